@@ -0,0 +1,90 @@
+use crate::config::Config;
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Audit fields of a client session, filled along its authentication
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct AuditSession {
+    /// Session uuid, also used to name the session socket
+    pub session: String,
+    pub client_addr: String,
+    /// Subject alternative name of the client certificate
+    pub tls_principal: Option<String>,
+    /// "tls", "kerberos" or "pam"
+    pub auth_type: Option<&'static str>,
+    pub username: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    /// Seconds since the epoch
+    timestamp: f64,
+    /// "auth" or "disconnect"
+    event: &'a str,
+    success: bool,
+    #[serde(flatten)]
+    session: &'a AuditSession,
+    /// Failure or disconnection reason
+    reason: Option<&'a str>,
+}
+
+/// Append only audit trail of the client sessions, as JSON lines
+///
+/// The file is opened in append mode for each record, written at once: the
+/// broker sons share it, and it can be rotated by renaming it.
+pub struct AuditLog {
+    path: Option<PathBuf>,
+}
+
+impl AuditLog {
+    pub fn new(config: &Config) -> Self {
+        AuditLog {
+            path: config.audit_log.as_ref().map(PathBuf::from),
+        }
+    }
+
+    fn write(&self, record: &AuditRecord) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut line = serde_json::to_string(record).context("Error in audit serialization")?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)
+            .context(format!("Error in open audit log {path:?}"))?;
+        file.write_all(line.as_bytes())
+            .context("Error in write audit log")
+    }
+
+    /// Record an event of `session`. Audit errors are logged, but do not
+    /// interrupt the session.
+    pub fn record(&self, event: &str, session: &AuditSession, success: bool, reason: Option<&str>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs_f64())
+            .unwrap_or(0.0);
+        let record = AuditRecord {
+            timestamp,
+            event,
+            success,
+            session,
+            reason,
+        };
+        if let Err(err) = self.write(&record) {
+            error!("Cannot write audit record");
+            err.chain().for_each(|cause| error!(" - due to {}", cause));
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use crate::config::{Backends, BalancePolicy};
+use anyhow::{Context, Result};
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::io::AsRawFd,
+    path::PathBuf,
+    str::FromStr,
+    thread,
+    time::Duration,
+};
+
+use uuid::Uuid;
+
+const DEFAULT_STATE_DIR: &str = "/tmp/sanzu_broker";
+/// Locked during a backend selection, holds the round robin counter
+const STATE_FILE: &str = "state";
+
+/// Connection attempts to a backend, while its server starts
+const CONNECT_ATTEMPTS: u32 = 50;
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Parse a "CID:PORT" vsock address
+pub fn parse_vsock_addr(value: &str) -> Result<(u32, u32)> {
+    let (cid, port) = value
+        .split_once(':')
+        .context(format!("Vsock address must be vsock:CID:PORT {value:?}"))?;
+    let cid = cid
+        .parse::<u32>()
+        .context(format!("Error in vsock cid parsing {cid:?}"))?;
+    let port = port
+        .parse::<u32>()
+        .context(format!("Error in vsock port parsing {port:?}"))?;
+    Ok((cid, port))
+}
+
+/// Address of a backend server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackendAddr {
+    /// "host:port"
+    Tcp(String),
+    /// Vsock context id and port
+    Vsock(u32, u32),
+}
+
+impl FromStr for BackendAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.strip_prefix("vsock:") {
+            Some(vsock_addr) => {
+                let (cid, port) = parse_vsock_addr(vsock_addr)?;
+                Ok(BackendAddr::Vsock(cid, port))
+            }
+            None if value.contains(':') => Ok(BackendAddr::Tcp(value.to_owned())),
+            None => Err(anyhow!("Backend address must be host:port {:?}", value)),
+        }
+    }
+}
+
+impl fmt::Display for BackendAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackendAddr::Tcp(addr) => write!(f, "{addr}"),
+            BackendAddr::Vsock(cid, port) => write!(f, "vsock:{cid}:{port}"),
+        }
+    }
+}
+
+/// Exclusive advisory lock on `file`
+fn lock(file: &File, blocking: bool) -> io::Result<()> {
+    let mut operation = libc::LOCK_EX;
+    if !blocking {
+        operation |= libc::LOCK_NB;
+    }
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Active session on a backend. Its file stays locked as long as the broker
+/// son runs, so that the sessions of crashed sons are not counted.
+pub struct BackendSession {
+    _file: File,
+    path: PathBuf,
+}
+
+impl Drop for BackendSession {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            warn!("Cannot remove session file {:?}: {}", self.path, err);
+        }
+    }
+}
+
+/// Pool of backends. Broker sons are forked processes: the sessions
+/// bookkeeping is shared through files in `state_dir`.
+pub struct BackendPool {
+    hosts: Vec<BackendAddr>,
+    policy: BalancePolicy,
+    state_dir: PathBuf,
+}
+
+impl BackendPool {
+    pub fn new(config: &Backends) -> Result<Self> {
+        let hosts = config
+            .hosts
+            .iter()
+            .map(|host| host.parse::<BackendAddr>())
+            .collect::<Result<Vec<_>>>()?;
+        if hosts.is_empty() {
+            return Err(anyhow!("Empty backend pool"));
+        }
+        Ok(BackendPool {
+            hosts,
+            policy: config.policy.unwrap_or_default(),
+            state_dir: PathBuf::from(config.state_dir.as_deref().unwrap_or(DEFAULT_STATE_DIR)),
+        })
+    }
+
+    fn sessions_dir(&self, host: &BackendAddr) -> PathBuf {
+        let name: String = host
+            .to_string()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        self.state_dir.join(name)
+    }
+
+    /// Number of active sessions on `host`. Stale session files are removed.
+    fn active_sessions(&self, host: &BackendAddr) -> Result<usize> {
+        let dir = self.sessions_dir(host);
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err).context(format!("Error in read_dir {dir:?}")),
+        };
+        let mut count = 0;
+        for entry in entries {
+            let path = entry.context("Error in read_dir entry")?.path();
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                // Session ended meanwhile
+                Err(_) => continue,
+            };
+            if lock(&file, false).is_ok() {
+                debug!("Remove stale session {:?}", path);
+                fs::remove_file(&path).context(format!("Error in remove_file {path:?}"))?;
+            } else {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Register a session on `host`. The file is locked before being moved to
+    /// the sessions directory, so that it is never seen unlocked.
+    fn register(&self, host: &BackendAddr) -> Result<BackendSession> {
+        let dir = self.sessions_dir(host);
+        fs::create_dir_all(&dir).context(format!("Error in create_dir_all {dir:?}"))?;
+        let uuid = Uuid::new_v4();
+        let tmp_path = self.state_dir.join(format!(".{uuid}"));
+        let file = File::create(&tmp_path).context(format!("Error in create {tmp_path:?}"))?;
+        lock(&file, true).context("Error in session lock")?;
+        let path = dir.join(uuid.to_string());
+        fs::rename(&tmp_path, &path).context(format!("Error in rename {tmp_path:?}"))?;
+        Ok(BackendSession { _file: file, path })
+    }
+
+    /// Select a backend following the policy and register a session on it
+    pub fn select(&self) -> Result<(BackendAddr, BackendSession)> {
+        fs::create_dir_all(&self.state_dir)
+            .context(format!("Error in create_dir_all {:?}", self.state_dir))?;
+        let state_path = self.state_dir.join(STATE_FILE);
+        let mut state = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&state_path)
+            .context(format!("Error in open {state_path:?}"))?;
+        // Serialize the selections of concurrent broker sons
+        lock(&state, true).context("Error in state lock")?;
+
+        let index = match self.policy {
+            BalancePolicy::RoundRobin => {
+                let mut content = String::new();
+                state
+                    .read_to_string(&mut content)
+                    .context("Error in read state")?;
+                let counter = content.trim().parse::<usize>().unwrap_or(0);
+                state.set_len(0).context("Error in truncate state")?;
+                state
+                    .seek(SeekFrom::Start(0))
+                    .context("Error in seek state")?;
+                write!(state, "{}", counter.wrapping_add(1)).context("Error in write state")?;
+                counter % self.hosts.len()
+            }
+            BalancePolicy::LeastConnections => {
+                let mut best = (0, usize::MAX);
+                for (index, host) in self.hosts.iter().enumerate() {
+                    let count = self.active_sessions(host)?;
+                    trace!("Backend {} sessions: {}", host, count);
+                    if count < best.1 {
+                        best = (index, count);
+                    }
+                }
+                best.0
+            }
+        };
+        let host = self.hosts[index].clone();
+        let session = self.register(&host)?;
+        Ok((host, session))
+    }
+}
+
+/// Connect to a backend, retrying while its server starts
+pub fn connect_retry<T>(addr: &BackendAddr, connect: impl Fn() -> io::Result<T>) -> Result<T> {
+    let mut attempt = 1;
+    loop {
+        match connect() {
+            Ok(stream) => return Ok(stream),
+            Err(err) if attempt < CONNECT_ATTEMPTS => {
+                trace!("Backend {} not ready: {}", addr, err);
+                thread::sleep(CONNECT_RETRY_DELAY);
+                attempt += 1;
+            }
+            Err(err) => return Err(err).context(format!("Cannot connect to backend {addr}")),
+        }
+    }
+}
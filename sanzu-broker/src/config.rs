@@ -1,5 +1,23 @@
+use crate::backend::BackendPool;
+use anyhow::Result;
+use sanzu_common::{
+    config_check::Problems, config_override::parse_config, config_reload::log_reload,
+    tls_helper::TlsPolicy,
+};
 use serde::{Deserialize, Serialize};
-use std::{fs::File, io, io::Read, path::Path};
+use std::{fs::File, io::Read, path::Path};
+
+/// Prefix of the environment variables overriding the configuration keys
+pub const CONFIG_ENV_PREFIX: &str = "SANZU_BROKER_";
+
+/// Keys applied to the next sessions on reload (SIGHUP)
+pub const HOT_KEYS: &[&str] = &[
+    "auth_timeout",
+    "max_sessions",
+    "max_msg_len",
+    "audit_log",
+    "cmd_callback",
+];
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Tls {
@@ -11,6 +29,16 @@ pub struct Tls {
     pub auth_key: String,
     /// List of domains to authenticate clients
     pub allowed_client_domains: Option<Vec<String>>,
+    /// Reject client certificates without a good and fresh OCSP status
+    pub require_ocsp: Option<bool>,
+    /// OCSP responder url (default: the one of the client certificate)
+    pub ocsp_responder: Option<String>,
+    /// OCSP request timeout, in seconds (default: 5)
+    pub ocsp_timeout: Option<u64>,
+    /// Minimum tls protocol version: "1.2" or "1.3" (default: 1.2)
+    pub min_version: Option<String>,
+    /// Allowed cipher suites (default: all the supported ones)
+    pub cipher_suites: Option<Vec<String>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -24,6 +52,20 @@ pub struct CmdCallBack {
     pub on_connect: Command,
 }
 
+/// OpenID Connect bearer token validation
+#[cfg(feature = "oidc")]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Oidc {
+    /// Url of the identity provider json web key set
+    pub jwks_url: String,
+    pub issuer: String,
+    pub audience: String,
+    /// Claim holding the username (default: preferred_username)
+    pub username_claim: Option<String>,
+    /// Key set refresh period, in seconds (default: 3600)
+    pub jwks_refresh: Option<u64>,
+}
+
 /// Support authentication mecanism
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "type", content = "args")]
@@ -33,6 +75,38 @@ pub enum AuthType {
     Kerberos(Vec<String>),
     // Pam name
     Pam(String),
+    #[cfg(feature = "oidc")]
+    // Signed token of an identity provider
+    Oidc(Oidc),
+}
+
+/// Backend selection policy
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BalancePolicy {
+    #[default]
+    RoundRobin,
+    /// Backend with the fewest active sessions
+    LeastConnections,
+}
+
+/// Pool of backend machines running the sanzu servers
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Backends {
+    /// Backend addresses: "host:port" or "vsock:CID:PORT"
+    pub hosts: Vec<String>,
+    /// Backend selection policy (default: round_robin)
+    pub policy: Option<BalancePolicy>,
+    /// Directory of the sessions bookkeeping (default: /tmp/sanzu_broker)
+    pub state_dir: Option<String>,
+}
+
+/// Prometheus metrics endpoint
+#[cfg(feature = "metrics")]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Metrics {
+    /// "ip:port" address of the http endpoint
+    pub listen: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -40,10 +114,119 @@ pub struct Config {
     pub tls: Tls,
     pub auth_type: Option<AuthType>,
     pub cmd_callback: CmdCallBack,
+    /// If set, users are forwarded to a backend of the pool instead of a
+    /// local unix socket
+    pub backends: Option<Backends>,
+    /// Maximum duration of the client tls handshake and authentication, in
+    /// seconds (default: 60, 0 disables the timeout)
+    pub auth_timeout: Option<u64>,
+    /// Maximum number of concurrent sessions (default: unlimited)
+    pub max_sessions: Option<usize>,
+    /// Maximum length of the messages received during the handshake, in bytes
+    /// (default: 1048576)
+    pub max_msg_len: Option<usize>,
+    /// If set, append the audit records of the client sessions to this file,
+    /// as JSON lines
+    pub audit_log: Option<String>,
+    /// If set, the clients carry the tunnel in WebSocket binary messages,
+    /// above the tls (wss): used by the browser clients
+    pub websocket: Option<bool>,
+    /// If set, expose the metrics on http
+    #[cfg(feature = "metrics")]
+    pub metrics: Option<Metrics>,
+}
+
+impl Tls {
+    fn validate(&self, problems: &mut Problems) {
+        problems.check_file("tls.ca_file", &self.ca_file);
+        problems.check_file("tls.auth_cert", &self.auth_cert);
+        problems.check_file("tls.auth_key", &self.auth_key);
+        if let Some(crl_file) = &self.crl_file {
+            problems.check_file("tls.crl_file", crl_file);
+        }
+        if let Some(ocsp_file) = &self.ocsp_file {
+            problems.check_file("tls.ocsp_file", ocsp_file);
+        }
+        problems.check(
+            "tls.ocsp_timeout",
+            self.ocsp_timeout != Some(0),
+            "must be at least 1",
+        );
+        problems.check_result(
+            "tls",
+            TlsPolicy::new(self.min_version.as_deref(), self.cipher_suites.as_deref()),
+        );
+    }
+}
+
+impl Config {
+    /// Take the hot settings (`HOT_KEYS`) of the reloaded `new` configuration,
+    /// logging the changes. Returns true if a hot setting has changed.
+    pub fn apply_reload(&mut self, new: Config) -> Result<bool> {
+        let changed = log_reload(self, &new, HOT_KEYS)?;
+        self.auth_timeout = new.auth_timeout;
+        self.max_sessions = new.max_sessions;
+        self.max_msg_len = new.max_msg_len;
+        self.audit_log = new.audit_log;
+        self.cmd_callback = new.cmd_callback;
+        Ok(changed)
+    }
+
+    /// Check the configuration, reporting all the problems at once
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Problems::new();
+        self.tls.validate(&mut problems);
+        match &self.auth_type {
+            Some(AuthType::Pam(name)) => {
+                problems.check("auth_type", !name.is_empty(), "empty pam name");
+            }
+            #[cfg(feature = "oidc")]
+            Some(AuthType::Oidc(oidc)) => {
+                problems.check(
+                    "auth_type.args.jwks_refresh",
+                    oidc.jwks_refresh != Some(0),
+                    "must be at least 1",
+                );
+            }
+            _ => {}
+        }
+        // Without a path, the command is searched in the PATH
+        let command_bin = &self.cmd_callback.on_connect.command_bin;
+        if command_bin.contains('/') {
+            problems.check_file("cmd_callback.on_connect.command_bin", command_bin);
+        }
+        if let Some(backends) = &self.backends {
+            problems.check_result("backends", BackendPool::new(backends));
+        }
+        problems.check(
+            "max_sessions",
+            self.max_sessions != Some(0),
+            "must be at least 1",
+        );
+        problems.check(
+            "max_msg_len",
+            self.max_msg_len != Some(0),
+            "must be at least 1",
+        );
+        if let Some(audit_log) = &self.audit_log {
+            let dir = match Path::new(audit_log).parent() {
+                Some(dir) if dir != Path::new("") => dir,
+                _ => Path::new("."),
+            };
+            problems.check(
+                "audit_log",
+                dir.is_dir(),
+                format!("directory {dir:?} does not exist"),
+            );
+        }
+        problems.into_result()
+    }
 }
 
-pub fn read_config<P: AsRef<Path>>(path: P) -> io::Result<Config> {
+/// Read configuration from a file, overridden by the environment and the
+/// `key=value` `sets`
+pub fn read_config<P: AsRef<Path>>(path: P, sets: &[String]) -> Result<Config> {
     let mut content = String::new();
     File::open(path)?.read_to_string(&mut content)?;
-    toml::from_str(&content).map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+    parse_config(&content, CONFIG_ENV_PREFIX, sets)
 }
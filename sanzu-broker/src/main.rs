@@ -8,26 +8,42 @@ use anyhow::{Context, Result};
 #[macro_use]
 extern crate log;
 
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command};
+mod audit;
+mod backend;
 mod config;
+mod metrics;
+#[cfg(feature = "oidc")]
+mod oidc;
+mod shutdown;
 
+use audit::{AuditLog, AuditSession};
+use backend::{connect_retry, parse_vsock_addr, BackendAddr, BackendPool};
 use config::{read_config, AuthType, Config};
-use mio::{
-    net::{TcpStream, UnixStream},
-    Events, Interest, Poll, Token,
-};
+use metrics::{AuthFailure, Direction};
+use mio::{unix::SourceFd, Events, Interest, Poll, Token};
+use shutdown::{install_sigterm_handler, reset_sigterm_handler, shutdown_requested, Sessions};
 
 use nix::{
+    errno::Errno,
     sys::wait::waitpid,
-    unistd::{fork, ForkResult},
+    unistd::{fork, ForkResult, Pid},
 };
 
 use sanzu_common::{
     auth_pam::do_pam_auth,
-    proto::{recv_client_msg_or_error, recv_server_msg_or_error, Tunnel, VERSION},
-    tls_helper::{get_subj_alt_names, make_server_config, tls_do_handshake},
+    callback::run_command,
+    config_reload::{install_sighup_handler, reload_requested},
+    ocsp::OcspConfig,
+    proto::{
+        check_version, recv_client_msg_or_error, recv_server_msg_or_error, send_server_err_event,
+        set_max_msg_len, Tunnel, VERSION,
+    },
+    tls_helper::{get_subj_alt_names, make_server_config, tls_do_handshake, TlsPolicy},
     tunnel,
     utils::get_username_from_principal,
+    websocket::{WebSocket, WebSocketStream},
+    ReadWrite,
 };
 
 #[cfg(all(unix, feature = "kerberos"))]
@@ -37,10 +53,11 @@ use rustls::ServerConnection;
 
 use std::{
     fs::remove_file,
-    io::{Read, Write},
+    io::{self, Read, Write},
     net::{IpAddr, SocketAddr},
-    process,
-    time::Duration,
+    os::unix::io::{AsRawFd, RawFd},
+    str::FromStr,
+    time::{Duration, Instant},
 };
 
 use uuid::Uuid;
@@ -49,8 +66,24 @@ use x509_parser::prelude::*;
 const DEFAULT_CONFIG: &str = "sanzu_broker.toml";
 const TOKEN_USERNAME: &str = "%USERNAME%";
 const TOKEN_CLIENT_ADDR: &str = "%CLIENT_ADDR%";
+const TOKEN_TARGET_ADDR: &str = "%TARGET_ADDR%";
+/// Former name of `TOKEN_TARGET_ADDR`
 const TOKEN_UNIX_SOCKET_PATH: &str = "%UNIX_SOCK_PATH%";
 
+/// Default maximum duration of the client authentication
+const DEFAULT_AUTH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Default maximum length of the messages received by the broker. It only
+/// handles the handshake messages: the session bytes are forwarded as is.
+const DEFAULT_MAX_MSG_LEN: usize = 1024 * 1024;
+
+/// Default timeout of the OCSP requests
+const DEFAULT_OCSP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum number of sons rejecting clients at the same time, once the
+/// sessions limit is reached
+const MAX_REJECTIONS: usize = 16;
+
 const SERVER: Token = Token(0);
 const CLIENT: Token = Token(1);
 
@@ -81,6 +114,122 @@ pub fn set_tcp_timeout(socket_ref: socket2::SockRef, timeout: Option<Duration>)
     Ok(())
 }
 
+/// Add vsock timeout
+/// Vsock has no keepalive: bound the blocking reads and writes instead
+pub fn set_vsock_timeout(socket: &vsock::VsockStream, timeout: Option<Duration>) -> Result<()> {
+    socket
+        .set_read_timeout(timeout)
+        .context("Cannot set read timeout")?;
+    socket
+        .set_write_timeout(timeout)
+        .context("Cannot set write timeout")
+}
+
+/// Read / write timeouts of blocking sockets
+pub trait HasTimeout {
+    fn timeouts(&self) -> io::Result<(Option<Duration>, Option<Duration>)>;
+    fn set_timeouts(&self, read: Option<Duration>, write: Option<Duration>) -> io::Result<()>;
+}
+
+macro_rules! impl_has_timeout {
+    (
+        $target: ty
+    ) => {
+        impl HasTimeout for $target {
+            fn timeouts(&self) -> io::Result<(Option<Duration>, Option<Duration>)> {
+                Ok((self.read_timeout()?, self.write_timeout()?))
+            }
+
+            fn set_timeouts(
+                &self,
+                read: Option<Duration>,
+                write: Option<Duration>,
+            ) -> io::Result<()> {
+                self.set_read_timeout(read)?;
+                self.set_write_timeout(write)
+            }
+        }
+    };
+}
+
+impl_has_timeout!(std::net::TcpStream);
+impl_has_timeout!(vsock::VsockStream);
+
+/// Returns true if the error comes from a socket timeout
+fn is_timeout(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|err| {
+            matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            )
+        })
+}
+
+/// Configured authentication timeout
+fn auth_timeout(config: &Config) -> Option<Duration> {
+    match config.auth_timeout {
+        Some(0) => None,
+        Some(timeout) => Some(Duration::from_secs(timeout)),
+        None => Some(DEFAULT_AUTH_TIMEOUT),
+    }
+}
+
+/// Authenticate the client within `config.auth_timeout`, so that stalled
+/// clients do not hold broker sons
+fn auth_client_timeout<S: Read + Write + Send + Sync + HasTimeout>(
+    config: &Config,
+    socket: &mut S,
+    audit: &mut AuditSession,
+) -> Result<(ClientConn, String, tunnel::Version)> {
+    let auth_timeout = auth_timeout(config);
+    let (read_timeout, write_timeout) = socket.timeouts().context("Cannot get timeouts")?;
+    socket
+        .set_timeouts(auth_timeout, auth_timeout)
+        .context("Cannot set authentication timeout")?;
+    let result = auth_client(config, socket, audit).map_err(|err| {
+        if is_timeout(&err) {
+            err.context(format!("Authentication timeout ({auth_timeout:?})"))
+        } else {
+            err
+        }
+    })?;
+    // Restore the session timeouts
+    socket
+        .set_timeouts(read_timeout, write_timeout)
+        .context("Cannot restore timeouts")?;
+    Ok(result)
+}
+
+/// Clients listening address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ListenAddr {
+    Tcp(IpAddr),
+    /// Vsock context id and port, for clients in virtual machines
+    Vsock(u32, u32),
+}
+
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+
+    /// Parse an ip address, or a "vsock:CID:PORT" address
+    fn from_str(value: &str) -> Result<Self> {
+        match value.strip_prefix("vsock:") {
+            Some(vsock_addr) => {
+                let (cid, port) = parse_vsock_addr(vsock_addr)?;
+                Ok(ListenAddr::Vsock(cid, port))
+            }
+            None => {
+                let address = value
+                    .parse::<IpAddr>()
+                    .context(format!("Error in ip parsing {value:?}"))?;
+                Ok(ListenAddr::Tcp(address))
+            }
+        }
+    }
+}
+
 /// Replace pattern tokens in list
 pub fn replace_source(args: &[String], needle: &str, new_str: &str) -> Vec<String> {
     args.iter()
@@ -94,14 +243,27 @@ pub fn replace_source(args: &[String], needle: &str, new_str: &str) -> Vec<Strin
         .collect()
 }
 
-/// Tls auth / Kerberos Auth
-fn auth_client(
-    config: &Config,
-    mut socket: &mut std::net::TcpStream,
-    addr: &SocketAddr,
-) -> Result<(ServerConnection, String, tunnel::Version)> {
-    socket.set_nodelay(true)?;
+/// Tls protocol versions and cipher suites allowed by the configuration
+fn tls_policy(config: &Config) -> Result<TlsPolicy> {
+    TlsPolicy::new(
+        config.tls.min_version.as_deref(),
+        config.tls.cipher_suites.as_deref(),
+    )
+}
 
+/// New tls connection to a client
+fn new_tls_conn(config: &Config) -> Result<ServerConnection> {
+    let require_ocsp = match config.tls.require_ocsp {
+        Some(true) => Some(OcspConfig {
+            responder_url: config.tls.ocsp_responder.clone(),
+            timeout: config
+                .tls
+                .ocsp_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_OCSP_TIMEOUT),
+        }),
+        _ => None,
+    };
     let tls_config = make_server_config(
         &config.tls.ca_file,
         config.tls.crl_file.as_deref(),
@@ -109,38 +271,123 @@ fn auth_client(
         &config.tls.auth_cert,
         &config.tls.auth_key,
         config.tls.allowed_client_domains.is_some(),
+        require_ocsp,
+        &tls_policy(config)?,
     )
     .context("Cannot make tls config")?;
     debug!("Using tls");
 
-    let mut tls_conn =
-        ServerConnection::new(tls_config).context("Error in new ServerConnection")?;
+    ServerConnection::new(tls_config).context("Error in new ServerConnection")
+}
+
+/// Client connection: tls session, and websocket framing if enabled
+pub struct ClientConn {
+    tls: ServerConnection,
+    websocket: Option<WebSocket>,
+}
+
+impl ClientConn {
+    /// Client connection on `tls`, upgraded to a websocket if configured
+    fn new<S: Read + Write>(
+        config: &Config,
+        mut tls: ServerConnection,
+        sock: &mut S,
+    ) -> Result<Self> {
+        let websocket = match config.websocket {
+            Some(true) => Some(
+                WebSocket::accept(&mut rustls::Stream::new(&mut tls, sock))
+                    .context("Error in websocket handshake")?,
+            ),
+            _ => None,
+        };
+        Ok(ClientConn { tls, websocket })
+    }
+
+    /// Stream of the tunnel bytes exchanged with the client on `sock`
+    fn stream<'a, S: Read + Write + Send + Sync>(
+        &'a mut self,
+        sock: &'a mut S,
+    ) -> Box<dyn ReadWrite + 'a> {
+        let tls = rustls::Stream::new(&mut self.tls, sock);
+        match self.websocket.as_mut() {
+            Some(websocket) => Box::new(WebSocketStream::new(websocket, tls)),
+            None => Box::new(tls),
+        }
+    }
+
+    /// True if client bytes are already received by the tls layer
+    fn has_pending_data(&mut self) -> Result<bool> {
+        let state = self
+            .tls
+            .process_new_packets()
+            .context("Error in tls packets")?;
+        Ok(state.plaintext_bytes_to_read() > 0)
+    }
+
+    /// Close the websocket, then the tls session
+    fn close<S: Read + Write>(&mut self, sock: &mut S) -> Result<()> {
+        if let Some(websocket) = self.websocket.as_mut() {
+            websocket
+                .send_close(&mut rustls::Stream::new(&mut self.tls, sock))
+                .context("Error in websocket close")?;
+        }
+        self.tls.send_close_notify();
+        self.tls
+            .complete_io(sock)
+            .context("Error in close notify")?;
+        Ok(())
+    }
+}
+
+/// Tls handshake and client certificate authentication
+fn tls_auth_client<S: Read + Write>(
+    tls_conn: &mut ServerConnection,
+    socket: &mut S,
+    allowed_client_domains: &[String],
+    audit: &mut AuditSession,
+) -> Result<String> {
+    tls_do_handshake(tls_conn, socket).context("Error in tls_do_handshake")?;
+    let certs = tls_conn.peer_certificates();
+    let certs = certs
+        .map(Ok)
+        .unwrap_or_else(|| Err(anyhow!("No cert from user")))?;
+    let cert = certs
+        .last()
+        .map(Ok)
+        .unwrap_or_else(|| Err(anyhow!("No cert from user")))?;
+    let (_, cert) = X509Certificate::from_der(cert).context("Error in X509Certificate from der")?;
+
+    let subj_alt_name =
+        get_subj_alt_names(&cert).context("Error in get subject alternative name")?;
+    debug!("Alt name: {:?}", subj_alt_name);
+    audit.tls_principal = Some(subj_alt_name.clone());
+
+    let tls_username = get_username_from_principal(&subj_alt_name, allowed_client_domains)
+        .context("Principal doesnt match realm pattern")?;
+    info!("TLS authentication ok for user: {}", tls_username);
+    Ok(tls_username)
+}
+
+/// Tls auth / Kerberos Auth
+///
+/// The authentication steps are recorded in `audit`
+fn auth_client<S: Read + Write + Send + Sync>(
+    config: &Config,
+    socket: &mut S,
+    audit: &mut AuditSession,
+) -> Result<(ClientConn, String, tunnel::Version)> {
+    let mut tls_conn = new_tls_conn(config)?;
 
     let mut username = None;
+    audit.auth_type = Some("tls");
     if let Some(ref allowed_client_domains) = config.tls.allowed_client_domains {
-        tls_do_handshake(&mut tls_conn, socket).context("Error in tls_do_handshake")?;
-        let certs = tls_conn.peer_certificates();
-        let certs = certs
-            .map(Ok)
-            .unwrap_or_else(|| Err(anyhow!("No cert from user")))?;
-        let cert = certs
-            .last()
-            .map(Ok)
-            .unwrap_or_else(|| Err(anyhow!("No cert from user")))?;
-        let (_, cert) =
-            X509Certificate::from_der(cert).context("Error in X509Certificate from der")?;
-
-        let subj_alt_name =
-            get_subj_alt_names(&cert).context("Error in get subject alternative name")?;
-        debug!("Alt name: {:?}", subj_alt_name);
-
-        let tls_username = get_username_from_principal(&subj_alt_name, allowed_client_domains)
-            .context("Principal doesnt match realm pattern")?;
-        info!("TLS authentication ok for user: {}", tls_username);
+        let tls_username = tls_auth_client(&mut tls_conn, socket, allowed_client_domains, audit)
+            .map_err(|err| metrics::auth_failure(AuthFailure::Tls, err))?;
         username = Some(tls_username);
     };
 
-    let mut conn = rustls::Stream::new(&mut tls_conn, &mut socket);
+    let mut client_conn = ClientConn::new(config, tls_conn, socket)?;
+    let mut conn = client_conn.stream(socket);
 
     // Send client version
     let server_version = tunnel::Version {
@@ -153,84 +400,126 @@ fn auth_client(
         recv_client_msg_type!(&mut conn, Version).context("Error in send client version")?;
 
     info!("Client version {:?}", client_version);
-    if client_version.version != VERSION {
-        return Err(anyhow!(
-            "Version mismatch server: {:?} client: {:?}",
-            VERSION,
-            client_version.version
-        ));
-    }
+    check_version(&client_version.version).context("Incompatible client")?;
 
     if let Some(auth_type) = &config.auth_type {
         match auth_type {
             #[cfg(all(unix, feature = "kerberos"))]
             AuthType::Kerberos(realms) => {
-                let krb_username = do_kerberos_client_auth(realms, &mut conn)?;
+                audit.auth_type = Some("kerberos");
+                let krb_username = do_kerberos_client_auth(realms, &mut conn)
+                    .map_err(|err| metrics::auth_failure(AuthFailure::Kerberos, err))?;
                 info!("Kerberos authentication ok for user: {}", krb_username);
                 if username.is_some() && username != Some(krb_username.to_owned()) {
-                    return Err(send_server_err_event(
+                    let err = send_server_err_event(
                         &mut conn,
                         anyhow!("Username mismatch between tls and kerberos"),
-                    ));
+                    );
+                    return Err(metrics::auth_failure(AuthFailure::Kerberos, err));
                 } else {
                     username = Some(krb_username);
                 }
             }
             AuthType::Pam(pam_name) => {
-                let final_user = do_pam_auth(&mut conn, pam_name)?;
+                audit.auth_type = Some("pam");
+                let final_user = do_pam_auth(&mut conn, pam_name)
+                    .map_err(|err| metrics::auth_failure(AuthFailure::Pam, err))?;
                 info!("Pam authentication ok for user: {}", final_user);
                 username = Some(final_user);
             }
+            #[cfg(feature = "oidc")]
+            AuthType::Oidc(oidc_config) => {
+                audit.auth_type = Some("oidc");
+                let token: tunnel::EventOidcToken = recv_client_msg_type!(&mut conn, Oidctoken)
+                    .context("Error in recv EventOidcToken")?;
+                let oidc_username = oidc::validate_token(oidc_config, &token.token)
+                    .map_err(|err| send_server_err_event(&mut conn, err))
+                    .map_err(|err| metrics::auth_failure(AuthFailure::Oidc, err))?;
+                info!("Oidc authentication ok for user: {}", oidc_username);
+                if username.is_some() && username.as_ref() != Some(&oidc_username) {
+                    let err = send_server_err_event(
+                        &mut conn,
+                        anyhow!("Username mismatch between tls and oidc"),
+                    );
+                    return Err(metrics::auth_failure(AuthFailure::Oidc, err));
+                }
+                username = Some(oidc_username);
+            }
         }
     }
 
+    drop(conn);
     let username = username.context("No username")?;
-    info!("Authenticated user: {:?} from {:?}", username, addr);
-    Ok((tls_conn, username, client_version))
+    info!(
+        "Authenticated user: {:?} from {:?}",
+        username, audit.client_addr
+    );
+    Ok((client_conn, username, client_version))
 }
 
-/// Forward connection between peers
-fn loop_fwd_conn(
-    server: std::os::unix::net::UnixStream,
-    client: std::net::TcpStream,
-    mut tls_conn: ServerConnection,
-) -> Result<()> {
+/// Number of bytes received on `fd` and not read yet, 0 if unknown
+fn pending_bytes(fd: RawFd) -> usize {
+    let mut len: libc::c_int = 0;
+    match unsafe { libc::ioctl(fd, libc::FIONREAD, &mut len) } {
+        0 => len as usize,
+        _ => 0,
+    }
+}
+
+/// Forward connection between peers, returns the disconnection reason
+///
+/// The sockets (unix, tcp or vsock) are polled through their file descriptors
+fn loop_fwd_conn<T, S>(
+    mut server: T,
+    mut client: S,
+    mut client_conn: ClientConn,
+) -> Result<&'static str>
+where
+    T: Read + Write + AsRawFd,
+    S: Read + Write + AsRawFd + Send + Sync,
+{
     let mut input_buffer = vec![0u8; 1024 * 1024];
     let mut output_buffer = vec![0u8; 1024 * 1024];
 
-    let mut server = UnixStream::from_std(server);
-    let mut client = TcpStream::from_std(client);
+    let server_fd = server.as_raw_fd();
+    let client_fd = client.as_raw_fd();
 
     let mut poll = Poll::new().context("Error in poll")?;
     let mut events = Events::with_capacity(128);
     poll.registry()
-        .register(&mut server, SERVER, Interest::READABLE)
+        .register(&mut SourceFd(&server_fd), SERVER, Interest::READABLE)
         .context("Error in register server")?;
     poll.registry()
-        .register(&mut client, CLIENT, Interest::READABLE)
+        .register(&mut SourceFd(&client_fd), CLIENT, Interest::READABLE)
         .context("Error in register client")?;
 
-    let mut client = rustls::Stream::new(&mut tls_conn, &mut client);
-
-    let mut stop = false;
-    while !stop {
+    let mut stop = None;
+    while stop.is_none() {
         poll.poll(&mut events, None).context("Error in poll")?;
         for event in events.iter() {
             match event.token() {
-                CLIENT => {
-                    let size = client
-                        .read(&mut input_buffer)
-                        .context("Error in client read")?;
-                    trace!("forward to server {:?}", size);
-                    if size == 0 {
-                        debug!("Client closed connexion");
-                        stop = true;
+                CLIENT => loop {
+                    match client_conn.stream(&mut client).read(&mut input_buffer) {
+                        Ok(0) => {
+                            debug!("Client closed connexion");
+                            stop = Some("client closed connection");
+                            break;
+                        }
+                        Ok(size) => {
+                            trace!("forward to server {:?}", size);
+                            server
+                                .write_all(&input_buffer[..size])
+                                .context("Error in server write")?;
+                            metrics::forwarded(Direction::ToServer, size);
+                        }
+                        Err(err) => return Err(err).context("Error in client read"),
+                    }
+                    // The poll is edge triggered: read the bytes already
+                    // received by the socket or by the tls layer
+                    if !client_conn.has_pending_data()? && pending_bytes(client_fd) == 0 {
                         break;
                     }
-                    server
-                        .write_all(&input_buffer[..size])
-                        .context("Error in server write")?;
-                }
+                },
                 SERVER => {
                     let size = server
                         .read(&mut output_buffer)
@@ -238,55 +527,50 @@ fn loop_fwd_conn(
                     trace!("forward to client {:?}", size);
                     if size == 0 {
                         debug!("Server closed connexion");
-                        stop = true;
+                        stop = Some("server closed connection");
                         break;
                     }
-                    client
+                    client_conn
+                        .stream(&mut client)
                         .write_all(&output_buffer[..size])
                         .context("Error in client write")?;
+                    metrics::forwarded(Direction::ToClient, size);
                 }
                 _ => unreachable!(),
             }
+            if stop.is_some() {
+                break;
+            }
         }
     }
-    Ok(())
+    Ok(stop.unwrap_or_default())
 }
 
-/// Run callback and forward connection between client and son
-pub fn connect_user(
-    config: &Config,
-    client: std::net::TcpStream,
-    tls_conn: ServerConnection,
-    username: &str,
-    addr: &SocketAddr,
-    client_version: tunnel::Version,
-) -> Result<()> {
-    // Create socket file
-    let uuid = Uuid::new_v4();
-    let socket_path = format!("/tmp/video_{uuid}");
-    debug!("Bind unix socket {:?}", socket_path);
-
+/// Run the connect callback, `target` being the address the son is reached at
+fn run_on_connect(config: &Config, username: &str, addr: &str, target: &str) -> Result<()> {
     let on_connect = &config.cmd_callback.on_connect;
     let args = replace_source(&on_connect.command_args, TOKEN_USERNAME, username);
-    let args = replace_source(&args, TOKEN_CLIENT_ADDR, &addr.to_string());
-    let args = replace_source(&args, TOKEN_UNIX_SOCKET_PATH, &socket_path);
+    let args = replace_source(&args, TOKEN_CLIENT_ADDR, addr);
+    let args = replace_source(&args, TOKEN_TARGET_ADDR, target);
+    let args = replace_source(&args, TOKEN_UNIX_SOCKET_PATH, target);
 
-    let listener = std::os::unix::net::UnixListener::bind(&socket_path)
-        .context(format!("Error in UnixListener bind {socket_path:?}"))?;
-
-    debug!("bin {} args {:?}", on_connect.command_bin, args);
-    let status = process::Command::new(&on_connect.command_bin)
-        .args(&args)
-        .status()
-        .context("Cannot exec connect callback")?;
-
-    if !status.success() {
-        return Err(anyhow!("Command execution failed"));
-    }
-
-    let (mut server, addr) = listener.accept().context("failed to accept connection")?;
-    info!("Client {:?}", addr);
+    run_command(&on_connect.command_bin, &args).context("Cannot exec connect callback")
+}
 
+/// Exchange versions with the son and forward connection between client and son
+///
+/// Returns the disconnection reason
+fn forward_user<T, S>(
+    mut server: T,
+    client: S,
+    client_conn: ClientConn,
+    username: &str,
+    client_version: tunnel::Version,
+) -> Result<String>
+where
+    T: Read + Write + AsRawFd + Send + Sync,
+    S: Read + Write + AsRawFd + Send + Sync,
+{
     // Forward client version to son
     send_client_msg_type!(&mut server, client_version, Version).context("Error in send Version")?;
 
@@ -295,28 +579,76 @@ pub fn connect_user(
         recv_server_msg_type!(&mut server, Version).context("Error in send server version")?;
 
     info!("Server version {:?}", server_version);
-    if server_version.version != VERSION {
-        return Err(anyhow!(
-            "Version mismatch server: {:?} client: {:?}",
-            server_version.version,
-            VERSION,
-        ));
-    }
+    check_version(&server_version.version).context("Incompatible server")?;
 
     // Link client & proxy
-    if let Err(err) = loop_fwd_conn(server, client, tls_conn) {
-        error!("Connection error: {:?}", err);
-    }
+    let reason = match loop_fwd_conn(server, client, client_conn) {
+        Ok(reason) => reason.to_owned(),
+        Err(err) => {
+            error!("Connection error: {:?}", err);
+            format!("connection error: {err:#}")
+        }
+    };
 
     info!("User disconnected: {:?}", username);
+    Ok(reason)
+}
+
+/// Run callback and forward connection between client and son
+///
+/// The son is reached through a local unix socket, named after the session
+/// `uuid`, or on a backend of the pool if configured. Returns the
+/// disconnection reason.
+pub fn connect_user<S: Read + Write + AsRawFd + Send + Sync>(
+    config: &Config,
+    client: S,
+    client_conn: ClientConn,
+    username: &str,
+    addr: &str,
+    uuid: &Uuid,
+    client_version: tunnel::Version,
+) -> Result<String> {
+    if let Some(backends) = &config.backends {
+        let pool = BackendPool::new(backends).context("Error in backends configuration")?;
+        // The session is accounted to the backend until the user disconnects
+        let (backend, _session) = pool.select().context("Cannot select a backend")?;
+        info!("User {:?} on backend {}", username, backend);
+        run_on_connect(config, username, addr, &backend.to_string())?;
+        return match &backend {
+            BackendAddr::Tcp(target) => {
+                let server = connect_retry(&backend, || std::net::TcpStream::connect(target))?;
+                server.set_nodelay(true)?;
+                forward_user(server, client, client_conn, username, client_version)
+            }
+            BackendAddr::Vsock(cid, port) => {
+                let server = connect_retry(&backend, || {
+                    vsock::VsockStream::connect(&vsock::VsockAddr::new(*cid, *port))
+                })?;
+                forward_user(server, client, client_conn, username, client_version)
+            }
+        };
+    }
+
+    // Create socket file
+    let socket_path = format!("/tmp/video_{uuid}");
+    debug!("Bind unix socket {:?}", socket_path);
+
+    let listener = std::os::unix::net::UnixListener::bind(&socket_path)
+        .context(format!("Error in UnixListener bind {socket_path:?}"))?;
+
+    run_on_connect(config, username, addr, &socket_path)?;
+
+    let (server, addr) = listener.accept().context("failed to accept connection")?;
+    info!("Client {:?}", addr);
+
+    let reason = forward_user(server, client, client_conn, username, client_version)?;
     remove_file(socket_path).context("Error in remove_file")?;
 
-    Ok(())
+    Ok(reason)
 }
 
-/// Authenticate client and forward connection to son
 /// Detach son from parent.
-fn auth_and_connect(config: &Config, mut sock: std::net::TcpStream, addr: SocketAddr) {
+fn detach_son() {
     match unsafe { fork() } {
         Ok(ForkResult::Parent { .. }) => {
             // kill parent to detach son
@@ -342,59 +674,292 @@ fn auth_and_connect(config: &Config, mut sock: std::net::TcpStream, addr: Socket
         error!("Cannot set to /");
         unsafe { libc::exit(1) };
     }
+}
+
+/// Authenticate client and forward connection to son
+fn auth_and_connect<S: Read + Write + AsRawFd + Send + Sync + HasTimeout>(
+    config: &Config,
+    mut sock: S,
+    addr: String,
+) -> ! {
+    detach_son();
 
-    let (tls_conn, username, client_version) = match auth_client(config, &mut sock, &addr) {
-        Ok((tls_conn, username, client_version)) => (tls_conn, username, client_version),
+    let uuid = Uuid::new_v4();
+    let audit_log = AuditLog::new(config);
+    let mut audit = AuditSession {
+        session: uuid.to_string(),
+        client_addr: addr.clone(),
+        ..Default::default()
+    };
+
+    let (client_conn, username, client_version) =
+        match auth_client_timeout(config, &mut sock, &mut audit) {
+            Ok((client_conn, username, client_version)) => (client_conn, username, client_version),
+            Err(err) => {
+                error!("Error in client auth {:?}", err);
+                audit_log.record("auth", &audit, false, Some(&format!("{err:#}")));
+                unsafe { libc::exit(1) };
+            }
+        };
+    audit.username = Some(username.clone());
+    audit_log.record("auth", &audit, true, None);
+
+    let start = Instant::now();
+    let reason = match connect_user(
+        config,
+        sock,
+        client_conn,
+        &username,
+        &addr,
+        &uuid,
+        client_version,
+    ) {
+        Ok(reason) => reason,
         Err(err) => {
-            error!("Error in client auth {:?}", err);
-            unsafe { libc::exit(1) };
+            error!("Error for client {}: {:?}", addr, err);
+            format!("{err:#}")
         }
     };
+    metrics::session_ended(start.elapsed());
+    audit_log.record("disconnect", &audit, true, Some(&reason));
+    unsafe { libc::exit(0) };
+}
 
-    if let Err(err) = connect_user(config, sock, tls_conn, &username, &addr, client_version) {
-        error!("Error for client {}: {:?}", addr, err);
+/// Tell the client that the sessions limit is reached
+///
+/// The error is sent in place of the server version, so that the client
+/// reports it to the user before the connection is closed
+fn reject_client<S: Read + Write + Send + Sync + HasTimeout>(
+    config: &Config,
+    mut sock: S,
+    addr: String,
+) -> ! {
+    detach_son();
+
+    let timeout = auth_timeout(config);
+    let result = sock
+        .set_timeouts(timeout, timeout)
+        .context("Cannot set rejection timeout")
+        .and_then(|_| {
+            let mut tls_conn = new_tls_conn(config)?;
+            if config.tls.allowed_client_domains.is_some() {
+                tls_do_handshake(&mut tls_conn, &mut sock).context("Error in tls_do_handshake")?;
+            }
+            let mut client_conn = ClientConn::new(config, tls_conn, &mut sock)?;
+            let mut conn = client_conn.stream(&mut sock);
+            let err = send_server_err_event(
+                &mut conn,
+                anyhow!("Too many sessions on the broker, retry later"),
+            );
+            conn.flush().context("Error in flush")?;
+            drop(conn);
+            client_conn.close(&mut sock)?;
+            info!("Client {} rejected: {}", addr, err);
+            Ok(())
+        });
+    if let Err(err) = result {
+        error!("Error in client {} rejection: {:?}", addr, err);
+        unsafe { libc::exit(1) };
     }
     unsafe { libc::exit(0) };
 }
 
-/// Accept and dispatch clients connections
+/// Reload the configuration with `load_config`. The hot settings are used by
+/// the next sessions, the running ones keep their configuration.
+fn reload_config(config: &mut Config, load_config: &dyn Fn() -> Result<Config>) {
+    info!("Reload configuration");
+    match load_config().and_then(|new_config| config.apply_reload(new_config)) {
+        Ok(_) => {
+            let max_msg_len = config.max_msg_len.unwrap_or(DEFAULT_MAX_MSG_LEN);
+            set_max_msg_len(max_msg_len, max_msg_len);
+        }
+        Err(err) => {
+            error!("Cannot reload configuration, keep the current one");
+            err.chain().for_each(|cause| error!(" - due to {}", cause));
+        }
+    }
+}
+
+/// Accept and dispatch clients connections. On SIGHUP, the configuration is
+/// reloaded with `load_config`.
 fn serve_user(
-    config: &Config,
-    address: IpAddr,
+    config: Config,
+    listen: ListenAddr,
     port: u16,
     connection_timeout: Option<u32>,
+    drain_timeout: Duration,
+    load_config: &dyn Fn() -> Result<Config>,
 ) -> Result<()> {
     info!("Server loop");
-    let listener = std::net::TcpListener::bind(SocketAddr::new(address, port))
-        .context(format!("Error in TcpListener bind {address} {port}"))?;
-
     let connection_timeout =
         connection_timeout.map(|timeout| std::time::Duration::from_secs(timeout as u64));
 
-    let socket_ref = socket2::SockRef::from(&listener);
-    set_tcp_timeout(socket_ref, connection_timeout).context("Cannot set keepalive")?;
+    match listen {
+        ListenAddr::Tcp(address) => {
+            let listener = std::net::TcpListener::bind(SocketAddr::new(address, port))
+                .context(format!("Error in TcpListener bind {address} {port}"))?;
+
+            let socket_ref = socket2::SockRef::from(&listener);
+            set_tcp_timeout(socket_ref, connection_timeout).context("Cannot set keepalive")?;
+
+            dispatch_clients(config, listener, drain_timeout, load_config, |listener| {
+                let (sock, addr) = listener.accept().context("Failed to accept connection")?;
+                sock.set_nodelay(true)?;
+                Ok((sock, addr.to_string()))
+            })
+        }
+        ListenAddr::Vsock(cid, port) => {
+            let listener = vsock::VsockListener::bind(&vsock::VsockAddr::new(cid, port))
+                .context(format!("Error in VsockListener bind {cid} {port}"))?;
+
+            dispatch_clients(config, listener, drain_timeout, load_config, |listener| {
+                let (sock, addr) = listener.accept().context("Failed to accept connection")?;
+                set_vsock_timeout(&sock, connection_timeout).context("Cannot set timeout")?;
+                Ok((sock, format!("vsock:{}:{}", addr.cid(), addr.port())))
+            })
+        }
+    }
+}
 
+/// Returns true if the error comes from a syscall interrupted by a signal
+fn is_interrupted(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|err| err.kind() == io::ErrorKind::Interrupted)
+}
+
+/// Wait for the intermediate son, which exits once the session son is detached
+fn wait_son(child: Pid) -> Result<()> {
     loop {
-        let (sock, addr) = listener.accept().context("Failed to accept connection")?;
+        match waitpid(child, None) {
+            Err(Errno::EINTR) => continue,
+            res => {
+                res.context("Error in waitpid")?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Fork a son for each accepted client connection
+///
+/// Once `config.max_sessions` sessions are active, new clients are rejected
+/// with an error message.
+///
+/// On SIGTERM, stop accepting clients and wait up to `drain_timeout` for the
+/// active sessions to end
+fn dispatch_clients<L, S, F>(
+    mut config: Config,
+    listener: L,
+    drain_timeout: Duration,
+    load_config: &dyn Fn() -> Result<Config>,
+    accept: F,
+) -> Result<()>
+where
+    L: AsRawFd,
+    S: Read + Write + AsRawFd + Send + Sync + HasTimeout,
+    F: Fn(&L) -> Result<(S, String)>,
+{
+    let mut sessions = Sessions::default();
+    // Sons sending the limit error to clients
+    let mut rejections = Sessions::default();
+    #[cfg(feature = "metrics")]
+    let metrics_server = match &config.metrics {
+        Some(metrics_config) => Some(metrics::MetricsServer::new(metrics_config)?),
+        None => None,
+    };
+    while !shutdown_requested() {
+        // SIGHUP interrupts the wait for clients (EINTR), which is restarted
+        // once the configuration is reloaded
+        if reload_requested() {
+            reload_config(&mut config, load_config);
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(metrics_server) = &metrics_server {
+            if !metrics_server.wait_client(listener.as_raw_fd(), || sessions.poll(0))? {
+                // Interrupted by a signal
+                continue;
+            }
+        }
+        let (sock, addr) = match accept(&listener) {
+            Ok(client) => client,
+            Err(err) if is_interrupted(&err) => continue,
+            Err(err) => return Err(err),
+        };
+
+        // A SIGHUP received just before the wait did not interrupt it
+        if reload_requested() {
+            reload_config(&mut config, load_config);
+        }
+        let max_sessions = config.max_sessions;
+        let limit = max_sessions.map_or_else(|| "unlimited".to_owned(), |max| max.to_string());
+
+        // Sons inherit the key set
+        #[cfg(feature = "oidc")]
+        if let Some(AuthType::Oidc(oidc_config)) = &config.auth_type {
+            oidc::refresh_jwks(oidc_config);
+        }
 
         info!("Client {:?}", addr);
 
+        // Forget the ended sessions
+        let active = sessions.poll(0)?;
+        let rejected = matches!(max_sessions, Some(max) if active >= max);
+        if rejected {
+            warn!(
+                "Sessions limit reached ({}/{}), rejecting client {:?}",
+                active, limit, addr
+            );
+            if rejections.poll(0)? >= MAX_REJECTIONS {
+                warn!("Too many pending rejections, dropping client {:?}", addr);
+                continue;
+            }
+        }
+
+        metrics::connection(rejected);
+
+        // Held by the son until it exits
+        let son_pipe = if rejected {
+            rejections.add()?
+        } else {
+            sessions.add()?
+        };
         match unsafe { fork() } {
             Ok(ForkResult::Parent { child, .. }) => {
                 // Force client sock drop
                 drop(sock);
-                waitpid(child, None).unwrap();
+                drop(son_pipe);
+                wait_son(child)?;
             }
             Ok(ForkResult::Child) => {
                 // Force listener drop to free port
                 drop(listener);
-                auth_and_connect(config, sock, addr);
-                break;
+                #[cfg(feature = "metrics")]
+                drop(metrics_server);
+                sessions.clear();
+                rejections.clear();
+                if let Err(err) = reset_sigterm_handler() {
+                    error!("Cannot reset SIGTERM handler: {:?}", err);
+                    unsafe { libc::exit(1) };
+                }
+                // Sons ignore the reloads, without interrupted calls
+                if let Err(err) = install_sighup_handler(false) {
+                    error!("Cannot set SIGHUP handler: {:?}", err);
+                    unsafe { libc::exit(1) };
+                }
+                if rejected {
+                    reject_client(&config, sock, addr);
+                }
+                auth_and_connect(&config, sock, addr);
             }
             Err(_) => error!("Fork failed"),
         }
+        info!("Active sessions: {}/{}", sessions.poll(0)?, limit);
     }
-    Ok(())
+
+    info!("Shutdown requested, stop accepting clients");
+    drop(listener);
+    sessions.drain(drain_timeout)
 }
 
 fn main() -> Result<()> {
@@ -418,14 +983,21 @@ Protocol version: {VERSION:?}
                 .default_value(DEFAULT_CONFIG)
                 .num_args(1),
         )
+        .arg(
+            Arg::new("set")
+                .long("set")
+                .value_name("KEY=VALUE")
+                .help("Override a configuration key, ex: max_sessions=10")
+                .num_args(1)
+                .action(ArgAction::Append),
+        )
         .arg(
             Arg::new("listen")
                 .short('l')
                 .long("listen")
                 .num_args(1)
                 .default_value("127.0.0.1")
-                .value_parser(clap::value_parser!(IpAddr))
-                .help("Listen address"),
+                .help("Listen address: ip address, or vsock:CID:PORT"),
         )
         .arg(
             Arg::new("port")
@@ -434,7 +1006,7 @@ Protocol version: {VERSION:?}
                 .num_args(1)
                 .default_value("1122")
                 .value_parser(clap::value_parser!(u16))
-                .help("Bind port number"),
+                .help("Bind port number (tcp)"),
         )
         .arg(
             Arg::new("connection_timeout")
@@ -443,10 +1015,20 @@ Protocol version: {VERSION:?}
                 .num_args(1)
                 .value_parser(clap::value_parser!(u32)),
         )
+        .arg(
+            Arg::new("drain_timeout")
+                .long("drain-timeout")
+                .help("On SIGTERM, maximum wait for the active sessions to end, in seconds")
+                .num_args(1)
+                .default_value("300")
+                .value_parser(clap::value_parser!(u64)),
+        )
         .get_matches();
 
-    let address = *matches
-        .get_one::<IpAddr>("listen")
+    let listen = matches
+        .get_one::<String>("listen")
+        .context("Cannot get listen address")?
+        .parse::<ListenAddr>()
         .context("Cannot parse listen address")?;
 
     let port = *matches
@@ -455,14 +1037,40 @@ Protocol version: {VERSION:?}
 
     let connection_timeout = matches.get_one::<u32>("connection-timeout").copied();
 
-    let config = read_config(
-        matches
-            .get_one::<String>("config")
-            .context("Error in config path")?,
-    )
-    .context("Error in read_config")?;
+    let sets: Vec<String> = matches
+        .get_many::<String>("set")
+        .map(|sets| sets.cloned().collect())
+        .unwrap_or_default();
+    let config_path = matches
+        .get_one::<String>("config")
+        .context("Error in config path")?;
+    let load_config = || -> Result<Config> {
+        let config = read_config(config_path, &sets).context("Error in read_config")?;
+        config.validate().context("Bad configuration file")?;
+        Ok(config)
+    };
+    let config = load_config()?;
+    // Messages are received before the client is authenticated
+    let max_msg_len = config.max_msg_len.unwrap_or(DEFAULT_MAX_MSG_LEN);
+    set_max_msg_len(max_msg_len, max_msg_len);
+
+    let drain_timeout = Duration::from_secs(
+        *matches
+            .get_one::<u64>("drain_timeout")
+            .context("Cannot parse drain timeout")?,
+    );
 
-    if let Err(err) = serve_user(&config, address, port, connection_timeout) {
+    install_sigterm_handler()?;
+    install_sighup_handler(true)?;
+
+    if let Err(err) = serve_user(
+        config,
+        listen,
+        port,
+        connection_timeout,
+        drain_timeout,
+        &load_config,
+    ) {
         error!("Server error");
         err.chain().for_each(|cause| error!(" - due to {}", cause));
     }
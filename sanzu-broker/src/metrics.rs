@@ -0,0 +1,314 @@
+use std::{
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+    time::Duration,
+};
+
+#[cfg(feature = "metrics")]
+use crate::config::Metrics as MetricsConfig;
+#[cfg(feature = "metrics")]
+use anyhow::{Context, Result};
+#[cfg(feature = "metrics")]
+use std::{
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::TcpListener,
+    os::unix::io::{AsRawFd, RawFd},
+};
+
+/// Upper bounds of the session duration histogram buckets, in seconds
+const DURATION_BUCKETS: [u64; 9] = [1, 10, 60, 300, 900, 3600, 14400, 43200, 86400];
+
+/// Timeout of the metrics requests, which are served by the broker loop
+#[cfg(feature = "metrics")]
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+#[cfg(feature = "metrics")]
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Authentication step which failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailure {
+    Tls = 0,
+    #[cfg(all(unix, feature = "kerberos"))]
+    Kerberos = 1,
+    Pam = 2,
+    #[cfg(feature = "oidc")]
+    Oidc = 3,
+}
+
+/// Forwarding direction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ToServer = 0,
+    ToClient = 1,
+}
+
+/// Broker counters
+///
+/// Clients are served by forked sons: the counters live in a shared mapping,
+/// created before the first fork, so that the sons update them.
+#[derive(Default)]
+struct Counters {
+    connections: AtomicU64,
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    auth_failures: [AtomicU64; 4],
+    forwarded_bytes: [AtomicU64; 2],
+    /// Sessions per duration bucket, the last one being +Inf
+    durations: [AtomicU64; DURATION_BUCKETS.len() + 1],
+    durations_sum_ms: AtomicU64,
+}
+
+/// Shared counters, null if metrics are not enabled
+static COUNTERS: AtomicPtr<Counters> = AtomicPtr::new(ptr::null_mut());
+
+fn counters() -> Option<&'static Counters> {
+    unsafe { COUNTERS.load(Ordering::Relaxed).as_ref() }
+}
+
+fn inc(counter: &AtomicU64, value: u64) {
+    counter.fetch_add(value, Ordering::Relaxed);
+}
+
+/// Record an accepted client connection, dispatched to a session son or
+/// rejected because of the sessions limit
+pub fn connection(rejected: bool) {
+    if let Some(counters) = counters() {
+        inc(&counters.connections, 1);
+        if rejected {
+            inc(&counters.rejected, 1);
+        } else {
+            inc(&counters.accepted, 1);
+        }
+    }
+}
+
+/// Record an authentication failure, returning the error
+pub fn auth_failure(failure: AuthFailure, err: anyhow::Error) -> anyhow::Error {
+    if let Some(counters) = counters() {
+        inc(&counters.auth_failures[failure as usize], 1);
+    }
+    err
+}
+
+/// Record forwarded bytes
+pub fn forwarded(direction: Direction, size: usize) {
+    if let Some(counters) = counters() {
+        inc(&counters.forwarded_bytes[direction as usize], size as u64);
+    }
+}
+
+/// Record the duration of an ended session
+pub fn session_ended(duration: Duration) {
+    if let Some(counters) = counters() {
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|bound| duration.as_secs_f64() <= *bound as f64)
+            .unwrap_or(DURATION_BUCKETS.len());
+        inc(&counters.durations[bucket], 1);
+        inc(&counters.durations_sum_ms, duration.as_millis() as u64);
+    }
+}
+
+/// Map the shared counters. Must be called before forking sons.
+#[cfg(feature = "metrics")]
+fn init() -> Result<()> {
+    if counters().is_some() {
+        return Ok(());
+    }
+    let size = std::mem::size_of::<Counters>();
+    let addr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error()).context("Cannot map metrics");
+    }
+    let addr = addr as *mut Counters;
+    unsafe { addr.write(Counters::default()) };
+    COUNTERS.store(addr, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(feature = "metrics")]
+fn metric_header(output: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(output, "# HELP {name} {help}");
+    let _ = writeln!(output, "# TYPE {name} {kind}");
+}
+
+/// Render the metrics in the Prometheus text format
+#[cfg(feature = "metrics")]
+fn render(active_sessions: usize) -> String {
+    let mut output = String::new();
+    let counters = match counters() {
+        Some(counters) => counters,
+        None => return output,
+    };
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+    for (name, help, counter) in [
+        (
+            "sanzu_broker_connections_total",
+            "Client connections",
+            &counters.connections,
+        ),
+        (
+            "sanzu_broker_connections_accepted_total",
+            "Client connections dispatched to a session",
+            &counters.accepted,
+        ),
+        (
+            "sanzu_broker_connections_rejected_total",
+            "Client connections rejected by the sessions limit",
+            &counters.rejected,
+        ),
+    ] {
+        metric_header(&mut output, name, "counter", help);
+        let _ = writeln!(output, "{name} {}", load(counter));
+    }
+
+    let name = "sanzu_broker_auth_failures_total";
+    metric_header(&mut output, name, "counter", "Authentication failures");
+    for (kind, failure) in [
+        ("tls", AuthFailure::Tls),
+        #[cfg(all(unix, feature = "kerberos"))]
+        ("kerberos", AuthFailure::Kerberos),
+        ("pam", AuthFailure::Pam),
+        #[cfg(feature = "oidc")]
+        ("oidc", AuthFailure::Oidc),
+    ] {
+        let value = load(&counters.auth_failures[failure as usize]);
+        let _ = writeln!(output, "{name}{{type=\"{kind}\"}} {value}");
+    }
+
+    let name = "sanzu_broker_active_sessions";
+    metric_header(&mut output, name, "gauge", "Active sessions");
+    let _ = writeln!(output, "{name} {active_sessions}");
+
+    let name = "sanzu_broker_forwarded_bytes_total";
+    metric_header(&mut output, name, "counter", "Bytes forwarded");
+    for (direction, index) in [
+        ("to_server", Direction::ToServer),
+        ("to_client", Direction::ToClient),
+    ] {
+        let value = load(&counters.forwarded_bytes[index as usize]);
+        let _ = writeln!(output, "{name}{{direction=\"{direction}\"}} {value}");
+    }
+
+    let name = "sanzu_broker_session_duration_seconds";
+    metric_header(
+        &mut output,
+        name,
+        "histogram",
+        "Duration of the ended sessions",
+    );
+    let mut count = 0;
+    for (index, counter) in counters.durations.iter().enumerate() {
+        count += load(counter);
+        let bound = match DURATION_BUCKETS.get(index) {
+            Some(bound) => bound.to_string(),
+            None => "+Inf".to_owned(),
+        };
+        let _ = writeln!(output, "{name}_bucket{{le=\"{bound}\"}} {count}");
+    }
+    let sum = load(&counters.durations_sum_ms) as f64 / 1000.0;
+    let _ = writeln!(output, "{name}_sum {sum}");
+    let _ = writeln!(output, "{name}_count {count}");
+    output
+}
+
+/// Http endpoint exposing the metrics on `/metrics`
+#[cfg(feature = "metrics")]
+pub struct MetricsServer {
+    listener: TcpListener,
+}
+
+#[cfg(feature = "metrics")]
+impl MetricsServer {
+    pub fn new(config: &MetricsConfig) -> Result<Self> {
+        init()?;
+        let listener = TcpListener::bind(&config.listen)
+            .context(format!("Error in metrics bind {:?}", config.listen))?;
+        info!("Metrics on http://{}/metrics", config.listen);
+        Ok(MetricsServer { listener })
+    }
+
+    /// Serve metrics requests until `client_fd` is readable. Returns false if
+    /// interrupted by a signal.
+    pub fn wait_client(
+        &self,
+        client_fd: RawFd,
+        mut active_sessions: impl FnMut() -> Result<usize>,
+    ) -> Result<bool> {
+        loop {
+            let mut fds = [client_fd, self.listener.as_raw_fd()].map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            });
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    return Ok(false);
+                }
+                return Err(err).context("Error in poll");
+            }
+            if fds[1].revents != 0 {
+                let active_sessions = active_sessions()?;
+                if let Err(err) = self.serve(active_sessions) {
+                    warn!("Error in metrics request: {:?}", err);
+                }
+            }
+            if fds[0].revents != 0 {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn serve(&self, active_sessions: usize) -> Result<()> {
+        let (mut stream, _) = self.listener.accept().context("Error in metrics accept")?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+        // Read the request head
+        let mut request = vec![];
+        let mut buffer = [0u8; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            let size = stream.read(&mut buffer).context("Error in metrics read")?;
+            if size == 0 {
+                return Err(anyhow!("Metrics client closed connection"));
+            }
+            request.extend_from_slice(&buffer[..size]);
+            if request.len() > MAX_REQUEST_SIZE {
+                return Err(anyhow!("Metrics request too large"));
+            }
+        }
+        let request = String::from_utf8_lossy(&request);
+        let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+
+        let response = match (request_line.next(), request_line.next()) {
+            (Some("GET"), Some("/metrics")) => {
+                let body = render(active_sessions);
+                format!(
+                    "HTTP/1.1 200 OK\r\n\
+                     Content-Type: text/plain; version=0.0.4\r\n\
+                     Content-Length: {}\r\n\
+                     Connection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            }
+            _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_owned(),
+        };
+        stream
+            .write_all(response.as_bytes())
+            .context("Error in metrics write")
+    }
+}
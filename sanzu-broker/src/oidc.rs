@@ -0,0 +1,264 @@
+use crate::config::Oidc;
+use anyhow::{anyhow, Context, Result};
+
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+
+use nix::{
+    fcntl::{fcntl, FcntlArg, OFlag},
+    unistd::{fork, pipe2, ForkResult},
+};
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{ErrorKind, Read},
+    os::unix::io::AsRawFd,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_USERNAME_CLAIM: &str = "preferred_username";
+const DEFAULT_JWKS_REFRESH: Duration = Duration::from_secs(3600);
+const JWKS_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delay before fetching again after a failed fetch
+const JWKS_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// Minimum age of the cached keys before an unknown key id triggers a fetch
+const JWKS_MIN_REFRESH: Duration = Duration::from_secs(30);
+/// Maximum size of the key set sent by the fetching son
+const MAX_JWKS_LEN: usize = 1024 * 1024;
+
+/// Key set fetch running in a detached son, which writes the key set to the
+/// pipe
+struct PendingFetch {
+    /// Non blocking read end of the pipe
+    pipe: File,
+    data: Vec<u8>,
+}
+
+impl PendingFetch {
+    /// Fork a son fetching the key set
+    fn spawn(config: &Oidc) -> Result<Self> {
+        let (read_end, write_end) = pipe2(OFlag::O_CLOEXEC).context("Error in pipe2")?;
+        fcntl(read_end.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+            .context("Error in fcntl")?;
+        match unsafe { fork() }.context("Error in fork")? {
+            ForkResult::Parent { child } => {
+                drop(write_end);
+                crate::wait_son(child)?;
+                Ok(PendingFetch {
+                    pipe: File::from(read_end),
+                    data: vec![],
+                })
+            }
+            ForkResult::Child => {
+                drop(read_end);
+                crate::detach_son();
+                // The name resolution has no timeout: bound the son lifetime
+                unsafe { libc::alarm(2 * JWKS_FETCH_TIMEOUT.as_secs() as libc::c_uint) };
+                let result = fetch_jwks(config).and_then(|keys| {
+                    serde_json::to_writer(File::from(write_end), &keys).context("Cannot send jwks")
+                });
+                if let Err(err) = result {
+                    error!("Cannot fetch jwks");
+                    err.chain().for_each(|cause| error!(" - due to {}", cause));
+                    unsafe { libc::exit(1) };
+                }
+                unsafe { libc::exit(0) };
+            }
+        }
+    }
+
+    /// Read the key set sent so far. Returns None while the son is running.
+    fn poll(&mut self) -> Option<Result<JwkSet>> {
+        let mut buffer = [0u8; 4096];
+        loop {
+            match self.pipe.read(&mut buffer) {
+                // A failed son sends nothing
+                Ok(0) => {
+                    return Some(
+                        serde_json::from_slice(&self.data).context("Error in jwks parsing"),
+                    )
+                }
+                Ok(size) => {
+                    self.data.extend_from_slice(&buffer[..size]);
+                    if self.data.len() > MAX_JWKS_LEN {
+                        return Some(Err(anyhow!("Jwks too large")));
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return None,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Some(Err(err).context("Cannot read jwks")),
+            }
+        }
+    }
+}
+
+/// Cached key set of the identity provider
+#[derive(Default)]
+struct JwksCache {
+    /// Key set, and its fetch time
+    keys: Option<(Instant, JwkSet)>,
+    /// Time of the last failed fetch
+    failed: Option<Instant>,
+    /// Fetch running in the background
+    pending: Option<PendingFetch>,
+}
+
+impl JwksCache {
+    /// Store the key set `result`. On error, the previous keys are kept.
+    fn update(&mut self, result: Result<JwkSet>) {
+        match result {
+            Ok(keys) => {
+                debug!("Jwks refreshed: {} keys", keys.keys.len());
+                self.keys = Some((Instant::now(), keys));
+                self.failed = None;
+            }
+            Err(err) => {
+                error!("Cannot refresh jwks, retry in {:?}", JWKS_RETRY_DELAY);
+                err.chain().for_each(|cause| error!(" - due to {}", cause));
+                self.failed = Some(Instant::now());
+            }
+        }
+    }
+
+    /// True if the last fetch failed less than `JWKS_RETRY_DELAY` ago
+    fn retry_delayed(&self) -> bool {
+        matches!(self.failed, Some(failed) if failed.elapsed() < JWKS_RETRY_DELAY)
+    }
+
+    /// Fetch the key set, unless the last fetch failed less than
+    /// `JWKS_RETRY_DELAY` ago
+    fn fetch(&mut self, config: &Oidc) {
+        if !self.retry_delayed() {
+            self.update(fetch_jwks(config));
+        }
+    }
+
+    /// Collect the key set of the pending fetch, and start a new fetch in the
+    /// background if the keys are older than `max_age`
+    fn fetch_background(&mut self, config: &Oidc, max_age: Duration) {
+        if let Some(pending) = self.pending.as_mut() {
+            match pending.poll() {
+                Some(result) => {
+                    self.pending = None;
+                    self.update(result);
+                }
+                None => return,
+            }
+        }
+        if self.is_fresh(max_age) || self.retry_delayed() {
+            return;
+        }
+        match PendingFetch::spawn(config) {
+            Ok(pending) => self.pending = Some(pending),
+            Err(err) => self.update(Err(err)),
+        }
+    }
+
+    /// True if the keys were fetched less than `max_age` ago
+    fn is_fresh(&self, max_age: Duration) -> bool {
+        matches!(&self.keys, Some((fetched, _)) if fetched.elapsed() < max_age)
+    }
+}
+
+/// Cached key set of the identity provider.
+///
+/// The broker refreshes it in the background, and the sons inherit a copy
+/// when they are forked.
+static JWKS: Mutex<Option<JwksCache>> = Mutex::new(None);
+
+fn fetch_jwks(config: &Oidc) -> Result<JwkSet> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(JWKS_FETCH_TIMEOUT)
+        .build();
+    agent
+        .get(&config.jwks_url)
+        .call()
+        .context(format!("Error in get {:?}", config.jwks_url))?
+        .into_json::<JwkSet>()
+        .context("Error in jwks parsing")
+}
+
+/// Update the cached key set with the fetch running in the background, and
+/// start a new one once the keys are older than the refresh period. This
+/// never waits for the identity provider: on error, the previous keys are
+/// kept, and the fetch is not retried before `JWKS_RETRY_DELAY`.
+pub fn refresh_jwks(config: &Oidc) {
+    let refresh = config
+        .jwks_refresh
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_JWKS_REFRESH);
+    let mut jwks = JWKS.lock().unwrap_or_else(|err| err.into_inner());
+    let jwks = jwks.get_or_insert_with(JwksCache::default);
+    jwks.fetch_background(config, refresh);
+}
+
+/// Validate the `token` signature, issuer, audience and expiry, and return
+/// the username claim
+pub fn validate_token(config: &Oidc, token: &str) -> Result<String> {
+    let header = decode_header(token).context("Error in token header")?;
+    let kid = header.kid.context("No key id in token")?;
+
+    let mut jwks = JWKS.lock().unwrap_or_else(|err| err.into_inner());
+    let jwks = jwks.get_or_insert_with(JwksCache::default);
+    // The identity provider may have rotated its keys: fetch them once. This
+    // runs in the son, without holding the other clients.
+    let known = matches!(&jwks.keys, Some((_, keys)) if keys.find(&kid).is_some());
+    if !known && !jwks.is_fresh(JWKS_MIN_REFRESH) {
+        info!("Unknown token key id {:?}, refresh jwks", kid);
+        jwks.fetch(config);
+    }
+    let (_, keys) = jwks.keys.as_ref().context("No jwks available")?;
+    let jwk = keys
+        .find(&kid)
+        .context(format!("Unknown token key id {kid:?}"))?;
+
+    // Only accept signatures by the identity provider keys
+    let key_type_ok = match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => matches!(
+            header.alg,
+            Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512
+        ),
+        AlgorithmParameters::EllipticCurve(_) => {
+            matches!(header.alg, Algorithm::ES256 | Algorithm::ES384)
+        }
+        AlgorithmParameters::OctetKeyPair(_) => header.alg == Algorithm::EdDSA,
+        AlgorithmParameters::OctetKey(_) => false,
+    };
+    if !key_type_ok {
+        return Err(anyhow!(
+            "Token algorithm {:?} does not match key {:?}",
+            header.alg,
+            kid
+        ));
+    }
+    let key = DecodingKey::from_jwk(jwk).context("Error in decoding key")?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&config.issuer]);
+    validation.set_audience(&[&config.audience]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+
+    let token = decode::<HashMap<String, serde_json::Value>>(token, &key, &validation)
+        .context("Invalid token")?;
+
+    let claim = config
+        .username_claim
+        .as_deref()
+        .unwrap_or(DEFAULT_USERNAME_CLAIM);
+    token
+        .claims
+        .get(claim)
+        .and_then(|value| value.as_str())
+        .map(|username| username.to_owned())
+        .context(format!("No {claim:?} claim in token"))
+}
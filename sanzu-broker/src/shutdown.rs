@@ -0,0 +1,123 @@
+use anyhow::{Context, Result};
+
+use nix::{
+    fcntl::OFlag,
+    sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+    unistd::pipe2,
+};
+
+use std::{
+    io,
+    os::unix::io::{AsRawFd, OwnedFd},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Set on SIGTERM: the broker stops accepting clients and drains the sessions
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sigterm(_signal: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Install the SIGTERM handler. Without SA_RESTART, the blocking accept is
+/// interrupted by the signal.
+pub fn install_sigterm_handler() -> Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(handle_sigterm),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGTERM, &action) }.context("Cannot set SIGTERM handler")?;
+    Ok(())
+}
+
+/// Restore the default SIGTERM behavior in broker sons
+pub fn reset_sigterm_handler() -> Result<()> {
+    let action = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+    unsafe { sigaction(Signal::SIGTERM, &action) }.context("Cannot reset SIGTERM handler")?;
+    Ok(())
+}
+
+/// Active sessions of the broker.
+///
+/// Sons are detached from the broker, which cannot wait for them. Instead,
+/// each son holds the write end of a pipe: the read end, kept by the broker,
+/// hangs up once the son exits. The pipes are close on exec, so that the
+/// processes run by the connect callback do not hold them.
+#[derive(Default)]
+pub struct Sessions {
+    pipes: Vec<OwnedFd>,
+}
+
+impl Sessions {
+    /// Create the pipe of a new session. Returns the write end, to be held by
+    /// the son.
+    pub fn add(&mut self) -> Result<OwnedFd> {
+        let (read_end, write_end) = pipe2(OFlag::O_CLOEXEC).context("Error in pipe2")?;
+        self.pipes.push(read_end);
+        Ok(write_end)
+    }
+
+    /// Release the pipes, in sons
+    pub fn clear(&mut self) {
+        self.pipes.clear();
+    }
+
+    /// Wait up to `timeout_ms` for sessions to end, and return the number of
+    /// active sessions
+    pub fn poll(&mut self, timeout_ms: i32) -> Result<usize> {
+        if self.pipes.is_empty() {
+            return Ok(0);
+        }
+        let mut fds: Vec<libc::pollfd> = self
+            .pipes
+            .iter()
+            .map(|pipe| libc::pollfd {
+                fd: pipe.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err).context("Error in poll");
+            }
+            return Ok(self.pipes.len());
+        }
+        // Nothing is written to the pipes: any event is a hang up
+        let mut ended = fds.iter().map(|fd| fd.revents != 0);
+        self.pipes.retain(|_| !ended.next().unwrap_or(false));
+        Ok(self.pipes.len())
+    }
+
+    /// Wait for the active sessions to end, up to `timeout`
+    pub fn drain(&mut self, timeout: Duration) -> Result<()> {
+        let active = self.poll(0)?;
+        info!("Draining {} active sessions", active);
+        let deadline = Instant::now() + timeout;
+        let mut remaining = active;
+        while remaining != 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            let timeout_ms = (deadline - now).as_millis().min(i32::MAX as u128) as i32;
+            remaining = self.poll(timeout_ms)?;
+        }
+        info!("Drained {} sessions", active - remaining);
+        if remaining != 0 {
+            warn!(
+                "{} sessions still active after {:?}, exiting anyway",
+                remaining, timeout
+            );
+        }
+        Ok(())
+    }
+}
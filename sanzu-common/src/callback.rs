@@ -0,0 +1,67 @@
+//! Execution of the commands set in the configuration (connection
+//! callbacks, catalog applications)
+//!
+//! The commands are executed without shell, with the environment of the
+//! caller.
+
+use anyhow::{Context, Result};
+use std::{
+    process::{Command, Stdio},
+    thread,
+};
+
+/// Run `bin` with `args` and wait for its end. Fails if it doesn't exit
+/// successfully.
+pub fn run_command(bin: &str, args: &[String]) -> Result<()> {
+    debug!("bin {} args {:?}", bin, args);
+    let status = Command::new(bin)
+        .args(args)
+        .status()
+        .context(format!("Cannot exec {bin:?}"))?;
+
+    if !status.success() {
+        return Err(anyhow!("Command execution failed ({})", status));
+    }
+    Ok(())
+}
+
+/// Start `bin` with `args` in the background, with the additional environment
+/// variables `envs`. The command is reaped, and its exit status logged, once
+/// it ends.
+pub fn spawn_command(bin: &str, args: &[String], envs: &[(&str, &str)]) -> Result<()> {
+    debug!("bin {} args {:?} envs {:?}", bin, args, envs);
+    let mut child = Command::new(bin)
+        .args(args)
+        .envs(envs.iter().copied())
+        .stdin(Stdio::null())
+        .spawn()
+        .context(format!("Cannot exec {bin:?}"))?;
+
+    let bin = bin.to_owned();
+    thread::spawn(move || match child.wait() {
+        Ok(status) if status.success() => debug!("Command {:?} ended", bin),
+        Ok(status) => warn!("Command {:?} failed ({})", bin, status),
+        Err(err) => warn!("Cannot wait command {:?}: {}", bin, err),
+    });
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_command() {
+        assert!(run_command("/bin/true", &[]).is_ok());
+        assert!(run_command("/bin/false", &[]).is_err());
+        assert!(run_command("/nonexistent/command", &[]).is_err());
+        let args = vec!["-c".to_owned(), "exit 0".to_owned()];
+        assert!(run_command("/bin/sh", &args).is_ok());
+    }
+
+    #[test]
+    fn test_spawn_command() {
+        assert!(spawn_command("/bin/false", &[], &[]).is_ok());
+        assert!(spawn_command("/nonexistent/command", &[], &[]).is_err());
+    }
+}
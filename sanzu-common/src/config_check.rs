@@ -0,0 +1,90 @@
+//! Validation of the configuration files
+//!
+//! The checks are run right after the parse, and all the problems are
+//! reported at once, each one prefixed by the key at fault.
+
+use anyhow::Result;
+use std::{fmt::Display, path::Path};
+
+/// Problems found in a configuration
+#[derive(Debug, Default)]
+pub struct Problems(Vec<String>);
+
+impl Problems {
+    pub fn new() -> Self {
+        Problems::default()
+    }
+
+    /// Report a `problem` of the configuration `key`
+    pub fn push(&mut self, key: &str, problem: impl Display) {
+        self.0.push(format!("{key}: {problem}"));
+    }
+
+    /// Report `problem` if `valid` is false
+    pub fn check(&mut self, key: &str, valid: bool, problem: impl Display) {
+        if !valid {
+            self.push(key, problem);
+        }
+    }
+
+    /// Report the error of `result`, with its causes
+    pub fn check_result<T>(&mut self, key: &str, result: Result<T>) {
+        if let Err(err) = result {
+            let causes: Vec<String> = err.chain().map(|cause| cause.to_string()).collect();
+            self.push(key, causes.join(": "));
+        }
+    }
+
+    /// Report `path` if it is not an existing file
+    pub fn check_file(&mut self, key: &str, path: &str) {
+        let path = Path::new(path);
+        if !path.exists() {
+            self.push(key, format!("{path:?} does not exist"));
+        } else if !path.is_file() {
+            self.push(key, format!("{path:?} is not a file"));
+        }
+    }
+
+    /// Error listing the problems, if any
+    pub fn into_result(self) -> Result<()> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+        let mut text = format!("{} configuration problem(s):", self.0.len());
+        for problem in self.0.iter() {
+            text.push_str("\n  - ");
+            text.push_str(problem);
+        }
+        Err(anyhow!(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_problems() {
+        let mut problems = Problems::new();
+        problems.check("video.tiles", true, "must be at least 1");
+        problems.check_file("tls.ca_file", "/");
+        // A directory is not a file
+        assert!(problems.into_result().is_err());
+
+        let mut problems = Problems::new();
+        problems.check("video.tiles", false, "must be at least 1");
+        problems.check_file("tls.ca_file", "/nonexistent/ca.pem");
+        problems.check_result::<()>(
+            "tls.min_version",
+            Err(anyhow!("Unknown version").context("Bad tls policy")),
+        );
+        let text = problems.into_result().unwrap_err().to_string();
+        assert_eq!(
+            text,
+            "3 configuration problem(s):\n  \
+             - video.tiles: must be at least 1\n  \
+             - tls.ca_file: \"/nonexistent/ca.pem\" does not exist\n  \
+             - tls.min_version: Bad tls policy: Unknown version"
+        );
+    }
+}
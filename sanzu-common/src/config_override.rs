@@ -0,0 +1,271 @@
+//! Overrides of the configuration file keys
+//!
+//! A key is a dotted path in the toml configuration, ex: `video.max_fps`. It
+//! can be overridden by an environment variable, the key in upper case with
+//! `_` separators after a prefix (ex: `SANZU_VIDEO_MAX_FPS`), and by a
+//! `key=value` command line argument. The priority is: command line,
+//! environment, file, default.
+//!
+//! Environment variables only override the keys of the sections present in
+//! the configuration, or of its top level keys: other variables, such as the
+//! command line arguments ones, are ignored. A `__` separator forces the
+//! section boundary (ex: `SANZU_PEN__DEVICE`).
+//!
+//! Values are toml values (ex: `30`, `true`, `"text"`). A value which is not
+//! valid toml, or which replaces a string, is taken as a string.
+
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use toml::{Table, Value};
+
+/// Override of a configuration key
+#[derive(Debug, PartialEq)]
+struct Override {
+    path: Vec<String>,
+    value: String,
+    /// Command line argument or environment variable, for the error messages
+    origin: String,
+}
+
+/// Parse a `key=value` command line override
+fn parse_set(set: &str) -> Result<Override> {
+    let (key, value) = set
+        .split_once('=')
+        .context(format!("Override {set:?} is not key=value"))?;
+    let path: Vec<String> = key.trim().split('.').map(str::to_owned).collect();
+    if path.iter().any(|name| name.is_empty()) {
+        return Err(anyhow!("Bad configuration key {:?}", key));
+    }
+    Ok(Override {
+        path,
+        value: value.to_owned(),
+        origin: format!("--set {set}"),
+    })
+}
+
+/// Resolve the lower case `name` of an environment variable to a key path of
+/// `table`
+///
+/// The words of `name` are joined with `_` until they name a section of
+/// `table`, which holds the rest of the key.
+fn resolve_path(table: &Table, name: &str) -> Vec<String> {
+    if let Some((section, rest)) = name.split_once("__") {
+        let mut path = vec![section.to_owned()];
+        match table.get(section).and_then(Value::as_table) {
+            Some(section) => path.extend(resolve_path(section, rest)),
+            None => path.extend(resolve_path(&Table::new(), rest)),
+        }
+        return path;
+    }
+    let words: Vec<&str> = name.split('_').collect();
+    for index in 1..words.len() {
+        let section = words[..index].join("_");
+        if let Some(sub_table) = table.get(&section).and_then(Value::as_table) {
+            let mut path = vec![section];
+            path.extend(resolve_path(sub_table, &words[index..].join("_")));
+            return path;
+        }
+    }
+    vec![name.to_owned()]
+}
+
+/// Get the value at `path` in `table`
+fn get_path<'a>(table: &'a Table, path: &[String]) -> Option<&'a Value> {
+    let (key, sections) = path.split_last()?;
+    let mut table = table;
+    for section in sections {
+        table = table.get(section)?.as_table()?;
+    }
+    table.get(key)
+}
+
+/// Get the overrides from the environment `vars` starting with `prefix`
+///
+/// `known` holds the keys of the configuration: a variable which is not in
+/// one of its sections, or which is not one of its top level keys, is
+/// ignored.
+fn env_overrides(
+    prefix: &str,
+    vars: impl Iterator<Item = (String, String)>,
+    known: &Table,
+) -> Vec<Override> {
+    let mut overrides = vec![];
+    for (var, value) in vars {
+        let name = match var.strip_prefix(prefix) {
+            Some(name) if !name.is_empty() => name.to_lowercase(),
+            _ => continue,
+        };
+        let path = resolve_path(known, &name);
+        let in_config = match known.get(&path[0]) {
+            Some(Value::Table(_)) => path.len() > 1,
+            Some(_) => path.len() == 1,
+            None => false,
+        };
+        if in_config {
+            overrides.push(Override {
+                path,
+                value,
+                origin: var,
+            });
+        }
+    }
+    // Environment order is not specified
+    overrides.sort_by(|a, b| a.origin.cmp(&b.origin));
+    overrides
+}
+
+/// Parse an override `value`, replacing the `current` one
+fn parse_value(value: &str, current: Option<&Value>) -> Value {
+    if let Some(Value::String(_)) = current {
+        return Value::String(value.to_owned());
+    }
+    match format!("value = {value}").parse::<Table>() {
+        Ok(mut table) => table
+            .remove("value")
+            .unwrap_or_else(|| Value::String(value.to_owned())),
+        Err(_) => Value::String(value.to_owned()),
+    }
+}
+
+/// Set the overridden value in `table`, creating the missing sections
+fn apply_override(table: &mut Table, over: &Override) -> Result<()> {
+    let (key, sections) = over.path.split_last().context("Empty key")?;
+    let mut table = table;
+    for section in sections {
+        table = table
+            .entry(section.to_owned())
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .context(format!("{}: {:?} is not a section", over.origin, section))?;
+    }
+    let value = parse_value(&over.value, table.get(key));
+    table.insert(key.to_owned(), value);
+    Ok(())
+}
+
+fn parse_config_with_env<T: DeserializeOwned + Serialize>(
+    content: &str,
+    env_prefix: &str,
+    vars: impl Iterator<Item = (String, String)>,
+    sets: &[String],
+) -> Result<T> {
+    let mut table: Table = content.parse().context("Cannot parse configuration")?;
+    let config: T = table.clone().try_into().context("Bad configuration")?;
+    let known = Table::try_from(&config).context("Cannot serialize configuration")?;
+
+    let mut overrides = env_overrides(env_prefix, vars, &known);
+    for set in sets {
+        overrides.push(parse_set(set)?);
+    }
+    if overrides.is_empty() {
+        return Ok(config);
+    }
+    for over in overrides.iter() {
+        info!(
+            "Configuration {} overridden by {}",
+            over.path.join("."),
+            over.origin
+        );
+        apply_override(&mut table, over)?;
+    }
+    let config: T = table.try_into().context("Bad configuration overrides")?;
+
+    // Unknown keys are ignored by the deserialization
+    let result = Table::try_from(&config).context("Cannot serialize configuration")?;
+    for over in overrides.iter() {
+        if get_path(&result, &over.path).is_none() {
+            return Err(anyhow!(
+                "Unknown configuration key {:?} ({})",
+                over.path.join("."),
+                over.origin
+            ));
+        }
+    }
+    Ok(config)
+}
+
+/// Parse the toml configuration `content`, overridden by the environment
+/// variables starting with `env_prefix` then by the `key=value` `sets`
+pub fn parse_config<T: DeserializeOwned + Serialize>(
+    content: &str,
+    env_prefix: &str,
+    sets: &[String],
+) -> Result<T> {
+    parse_config_with_env(content, env_prefix, std::env::vars(), sets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, Serialize, PartialEq)]
+    struct Video {
+        max_fps: u32,
+        encoder: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, PartialEq)]
+    struct Config {
+        video: Video,
+        export_video_pci: bool,
+        max_sessions: Option<usize>,
+    }
+
+    const CONF: &str = "export_video_pci = false\n[video]\nmax_fps = 25\n";
+
+    fn parse(vars: &[(&str, &str)], sets: &[&str]) -> Result<Config> {
+        let vars = vars
+            .iter()
+            .map(|(var, value)| (var.to_string(), value.to_string()));
+        let sets: Vec<String> = sets.iter().map(|set| set.to_string()).collect();
+        parse_config_with_env(CONF, "SANZU_", vars, &sets)
+    }
+
+    #[test]
+    fn test_config_override() {
+        let config = parse(&[], &[]).unwrap();
+        assert_eq!(config.video.max_fps, 25);
+
+        // Command line > environment > file
+        let config = parse(
+            &[
+                ("SANZU_VIDEO_MAX_FPS", "30"),
+                ("SANZU_EXPORT_VIDEO_PCI", "true"),
+            ],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(config.video.max_fps, 30);
+        assert!(config.export_video_pci);
+        let config = parse(&[("SANZU_VIDEO_MAX_FPS", "30")], &["video.max_fps=60"]).unwrap();
+        assert_eq!(config.video.max_fps, 60);
+
+        // Strings, and keys missing from the file
+        let config = parse(&[], &["video.encoder=libx264", "max_sessions=2"]).unwrap();
+        assert_eq!(config.video.encoder.as_deref(), Some("libx264"));
+        assert_eq!(config.max_sessions, Some(2));
+
+        // Variables outside of the configuration are ignored
+        let config = parse(&[("SANZU_ENCODER", "x"), ("SANZU_VIDEO", "x")], &[]).unwrap();
+        assert_eq!(config.video.max_fps, 25);
+
+        // Bad overrides
+        assert!(parse(&[("SANZU_VIDEO_MAX_FPSS", "30")], &[]).is_err());
+        assert!(parse(&[], &["video.max_fps=fast"]).is_err());
+        assert!(parse(&[], &["video.max_fps"]).is_err());
+        assert!(parse(&[], &["export_video_pci.enable=true"]).is_err());
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        let known: Table = "max_sessions = 1\n[video]\nmax_fps = 25\n[video_pci]\n"
+            .parse()
+            .unwrap();
+        assert_eq!(resolve_path(&known, "video_max_fps"), ["video", "max_fps"]);
+        assert_eq!(resolve_path(&known, "video_pci_bus"), ["video", "pci_bus"]);
+        assert_eq!(resolve_path(&known, "video_pci__bus"), ["video_pci", "bus"]);
+        assert_eq!(resolve_path(&known, "max_sessions"), ["max_sessions"]);
+        assert_eq!(resolve_path(&known, "pen__device"), ["pen", "device"]);
+    }
+}
@@ -0,0 +1,137 @@
+//! Reload of the configuration files on SIGHUP
+//!
+//! Only a subset of the keys, the hot ones, is applied to the running
+//! process: the changes of the other keys are reported as needing a restart.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use toml::{Table, Value};
+
+/// Set on SIGHUP, until the reload is done
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn handle_sighup(_signal: libc::c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+/// Install the SIGHUP handler. If `interrupt` is set, the blocking calls are
+/// interrupted by the signal (EINTR) instead of being restarted.
+#[cfg(unix)]
+pub fn install_sighup_handler(interrupt: bool) -> Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_sighup as *const () as libc::sighandler_t;
+        action.sa_flags = if interrupt { 0 } else { libc::SA_RESTART };
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error()).context("Cannot set SIGHUP handler");
+        }
+    }
+    Ok(())
+}
+
+/// Returns true once after each SIGHUP
+pub fn reload_requested() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
+
+/// Append to `changed` the dotted keys which differ between `old` and `new`
+fn diff_tables(prefix: &str, old: &Table, new: &Table, changed: &mut Vec<String>) {
+    let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        let path = match prefix {
+            "" => key.to_owned(),
+            _ => format!("{prefix}.{key}"),
+        };
+        match (old.get(key), new.get(key)) {
+            (Some(Value::Table(old)), Some(Value::Table(new))) => {
+                diff_tables(&path, old, new, changed)
+            }
+            (old, new) if old != new => changed.push(path),
+            _ => {}
+        }
+    }
+}
+
+/// Keys which differ between the `old` and `new` configurations
+pub fn changed_keys<T: Serialize>(old: &T, new: &T) -> Result<Vec<String>> {
+    let old = Table::try_from(old).context("Cannot serialize configuration")?;
+    let new = Table::try_from(new).context("Cannot serialize configuration")?;
+    let mut changed = vec![];
+    diff_tables("", &old, &new, &mut changed);
+    Ok(changed)
+}
+
+/// Returns true if `key` is one of the `hot_keys`, or is in one of their
+/// sections
+fn is_hot(key: &str, hot_keys: &[&str]) -> bool {
+    hot_keys.iter().any(|hot| {
+        key == *hot || matches!(key.strip_prefix(hot), Some(rest) if rest.starts_with('.'))
+    })
+}
+
+/// Log the changes between the `old` and `new` configurations: the ones of
+/// the `hot_keys` are applied, the others need a restart. Returns true if a
+/// hot key has changed.
+pub fn log_reload<T: Serialize>(old: &T, new: &T, hot_keys: &[&str]) -> Result<bool> {
+    let changed = changed_keys(old, new)?;
+    if changed.is_empty() {
+        info!("Configuration unchanged");
+    }
+    let mut hot_changed = false;
+    for key in changed {
+        if is_hot(&key, hot_keys) {
+            info!("Configuration {} reloaded", key);
+            hot_changed = true;
+        } else {
+            warn!("Configuration {} changed, ignored until restart", key);
+        }
+    }
+    Ok(hot_changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Video {
+        max_fps: u32,
+        encoder: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Config {
+        video: Video,
+        max_sessions: Option<usize>,
+    }
+
+    #[test]
+    fn test_changed_keys() {
+        let old: Config = toml::from_str("[video]\nmax_fps = 25\n").unwrap();
+        let new: Config =
+            toml::from_str("max_sessions = 2\n[video]\nmax_fps = 30\nencoder = \"x\"\n").unwrap();
+        assert!(changed_keys(&old, &old).unwrap().is_empty());
+        assert_eq!(
+            changed_keys(&old, &new).unwrap(),
+            ["max_sessions", "video.encoder", "video.max_fps"]
+        );
+
+        let hot = ["video.max_fps", "max_sessions"];
+        assert!(is_hot("video.max_fps", &hot));
+        assert!(is_hot("max_sessions", &hot));
+        assert!(!is_hot("video.max_fps_cap", &hot));
+        assert!(!is_hot("video", &hot));
+        assert!(is_hot(
+            "video.bitrate_control.max_kbps",
+            &["video.bitrate_control"]
+        ));
+        assert!(log_reload(&old, &new, &hot).unwrap());
+        assert!(!log_reload(&old, &new, &["video.encoder_name"]).unwrap());
+    }
+}
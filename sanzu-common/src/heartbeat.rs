@@ -0,0 +1,133 @@
+use crate::tunnel;
+use anyhow::Result;
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+/// Periodic pings sent to the peer, which echoes back their timestamps in an
+/// `EventPong`.
+///
+/// The peer is declared dead after `max_missed` consecutive unanswered pings.
+/// The echoed timestamps also give the round trip time.
+pub struct Heartbeat {
+    start: Instant,
+    interval: Duration,
+    max_missed: u32,
+    last_ping: Option<Instant>,
+    /// The last ping has not been answered yet
+    pending: bool,
+    /// Consecutive unanswered pings
+    missed: u32,
+    rtt: Option<Duration>,
+}
+
+impl Heartbeat {
+    pub fn new(start: Instant, interval: Duration, max_missed: u32) -> Self {
+        Heartbeat {
+            start,
+            interval,
+            max_missed: max_missed.max(1),
+            last_ping: None,
+            pending: false,
+            missed: 0,
+            rtt: None,
+        }
+    }
+
+    /// Returns a ping if the last one is older than the heartbeat interval
+    pub fn ping(&mut self, now: Instant) -> Option<tunnel::EventPing> {
+        if self
+            .last_ping
+            .is_some_and(|last_ping| now.duration_since(last_ping) < self.interval)
+        {
+            return None;
+        }
+        if self.pending {
+            self.missed += 1;
+        }
+        self.pending = true;
+        self.last_ping = Some(now);
+        let timestamp_us = now.duration_since(self.start).as_micros() as u64;
+        Some(tunnel::EventPing { timestamp_us })
+    }
+
+    /// Handles the peer reply: the peer is alive, and the round trip time is
+    /// updated
+    pub fn pong(&mut self, pong: &tunnel::EventPong, now: Instant) {
+        self.pending = false;
+        self.missed = 0;
+        let sent = self.start + Duration::from_micros(pong.timestamp_us);
+        if sent <= now {
+            self.rtt = Some(now - sent);
+        }
+    }
+
+    /// Returns an error if the peer is declared dead. The error is a timed out
+    /// io error, as the peer may be reached again by reconnecting.
+    pub fn check(&self) -> Result<()> {
+        if self.missed >= self.max_missed {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Peer is dead: {} pings were not answered", self.missed),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Last measured round trip time
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Time after which a silent peer is declared dead. Used to bound the
+    /// blocking reads and writes on the transport.
+    pub fn dead_timeout(&self) -> Duration {
+        self.interval * (self.max_missed + 1)
+    }
+}
+
+/// Answer to a peer ping
+pub fn pong(ping: &tunnel::EventPing) -> tunnel::EventPong {
+    tunnel::EventPong {
+        timestamp_us: ping.timestamp_us,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heartbeat() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(1);
+        let mut heartbeat = Heartbeat::new(start, interval, 3);
+        assert_eq!(heartbeat.rtt(), None);
+        let now = start + Duration::from_millis(100);
+        let ping = heartbeat.ping(now).expect("First ping");
+        assert_eq!(ping.timestamp_us, 100_000);
+        // One ping per interval
+        assert!(heartbeat.ping(now + Duration::from_millis(500)).is_none());
+        heartbeat.pong(&pong(&ping), now + Duration::from_millis(30));
+        assert_eq!(heartbeat.rtt(), Some(Duration::from_millis(30)));
+
+        // Peer declared dead after 3 unanswered pings
+        let mut now = now;
+        for _ in 0..3 {
+            assert!(heartbeat.check().is_ok());
+            now += interval;
+            assert!(heartbeat.ping(now).is_some());
+        }
+        assert!(heartbeat.check().is_ok());
+        now += interval;
+        assert!(heartbeat.ping(now).is_some());
+        assert!(heartbeat.check().is_err());
+
+        // A late answer revives the peer
+        heartbeat.pong(&tunnel::EventPong { timestamp_us: 0 }, now);
+        assert!(heartbeat.check().is_ok());
+        assert_eq!(heartbeat.dead_timeout(), Duration::from_secs(4));
+    }
+}
@@ -2,6 +2,11 @@
 extern crate anyhow;
 #[macro_use]
 extern crate log;
+pub mod callback;
+pub mod config_check;
+pub mod config_override;
+pub mod config_reload;
+pub mod heartbeat;
 pub mod utils;
 #[macro_use]
 pub mod proto;
@@ -10,6 +15,8 @@ pub use proto::{tunnel, ReadWrite, Stdio, Tunnel, VERSION};
 pub mod auth_kerberos;
 #[cfg(target_family = "unix")]
 pub mod auth_pam;
+pub mod ocsp;
 #[cfg(all(windows, feature = "kerberos"))]
 pub mod sspi;
 pub mod tls_helper;
+pub mod websocket;
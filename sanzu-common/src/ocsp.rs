@@ -0,0 +1,828 @@
+//! OCSP status check of the client certificates (RFC 6960)
+use anyhow::{Context, Result};
+use asn1_rs::{
+    oid, Any, Class, FromDer, GeneralizedTime, Integer, Null, OctetString, Oid, Tag, ToDer,
+};
+use ring::{digest, signature};
+use rustls::{
+    client::danger::HandshakeSignatureValid,
+    server::danger::{ClientCertVerified, ClientCertVerifier},
+    CertificateError, DigitallySignedStruct, DistinguishedName, SignatureScheme,
+};
+use rustls_pki_types::{CertificateDer, UnixTime};
+use std::{
+    borrow::Cow,
+    convert::TryFrom,
+    io::{self, Read},
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+use x509_parser::{
+    extensions::{GeneralName, ParsedExtension},
+    oid_registry::{
+        OID_HASH_SHA1, OID_PKCS1_SHA1WITHRSA, OID_PKCS1_SHA256WITHRSA, OID_PKCS1_SHA384WITHRSA,
+        OID_PKCS1_SHA512WITHRSA, OID_PKIX_ACCESS_DESCRIPTOR_OCSP, OID_SIG_ECDSA_WITH_SHA256,
+        OID_SIG_ECDSA_WITH_SHA384, OID_SIG_ED25519,
+    },
+    prelude::X509Certificate,
+    time::ASN1Time,
+};
+
+const OID_OCSP_BASIC: Oid<'static> = oid!(1.3.6 .1 .5 .5 .7 .48 .1 .1);
+
+/// Tolerated clock difference with the responder, in seconds
+const CLOCK_SKEW: u64 = 300;
+/// Maximum age of responses without next update, in seconds
+const MAX_RESPONSE_AGE: u64 = 24 * 3600;
+const MAX_RESPONSE_SIZE: u64 = 1024 * 1024;
+
+/// OCSP check configuration
+#[derive(Debug, Clone)]
+pub struct OcspConfig {
+    /// Responder url. If unset, the one of the certificate authority
+    /// information access is used
+    pub responder_url: Option<String>,
+    pub timeout: Duration,
+}
+
+/// Parse the der element `data`, which must not be followed by other data
+fn parse_der_single(data: &[u8]) -> Result<Any<'_>> {
+    let (rest, any) = Any::from_der(data).map_err(|err| anyhow!("Bad der: {}", err))?;
+    if !rest.is_empty() {
+        return Err(anyhow!("Trailing data after der"));
+    }
+    Ok(any)
+}
+
+/// Elements of a constructed der `content`, with their whole encoding
+fn der_elements(mut content: &[u8]) -> Result<Vec<(Any<'_>, &[u8])>> {
+    let mut elements = vec![];
+    while !content.is_empty() {
+        let (rest, any) = Any::from_der(content).map_err(|err| anyhow!("Bad der: {}", err))?;
+        elements.push((any, &content[..content.len() - rest.len()]));
+        content = rest;
+    }
+    Ok(elements)
+}
+
+/// Elements of the der sequence `any`
+fn sequence<'a>(any: &Any<'a>) -> Result<Vec<Any<'a>>> {
+    any.tag()
+        .assert_eq(Tag::Sequence)
+        .context("Expected a der sequence")?;
+    Ok(der_elements(any.data)?
+        .into_iter()
+        .map(|(any, _)| any)
+        .collect())
+}
+
+/// Inner element of the context specific explicit `tag`, if `any` has it
+fn explicit<'a>(any: &Any<'a>, tag: u32) -> Result<Option<Any<'a>>> {
+    if any.class() != Class::ContextSpecific || any.tag() != Tag(tag) {
+        return Ok(None);
+    }
+    parse_der_single(any.data).map(Some)
+}
+
+/// Der sequence of the encoded `elements`
+fn encode_sequence(elements: &[Vec<u8>]) -> Result<Vec<u8>> {
+    let content = elements.concat();
+    Any::from_tag_and_data(Tag::Sequence, &content)
+        .to_der_vec()
+        .context("Cannot encode der sequence")
+}
+
+/// Content of the bit string `any`, which must not have unused bits
+fn bit_string<'a>(any: &Any<'a>) -> Result<&'a [u8]> {
+    any.tag()
+        .assert_eq(Tag::BitString)
+        .context("Expected a bit string")?;
+    match any.data.split_first() {
+        Some((0, bits)) => Ok(bits),
+        _ => Err(anyhow!("Unsupported bit string")),
+    }
+}
+
+/// Algorithm identifier oid
+fn algorithm_oid<'a>(any: &Any<'a>) -> Result<Oid<'a>> {
+    let algorithm = sequence(any)?;
+    let oid = algorithm.first().context("Empty algorithm identifier")?;
+    Oid::try_from(oid.clone()).context("Bad algorithm identifier")
+}
+
+/// Seconds since the epoch of the der generalized time `any`
+fn generalized_time(any: &Any<'_>) -> Result<u64> {
+    let time = GeneralizedTime::try_from(any.clone()).context("Bad generalized time")?;
+    let timestamp = time
+        .utc_datetime()
+        .context("Bad generalized time")?
+        .unix_timestamp();
+    u64::try_from(timestamp).context("Time before the epoch")
+}
+
+/// Seconds since the epoch of a certificate validity bound, clamped to the
+/// epoch
+fn validity_time(time: ASN1Time) -> u64 {
+    u64::try_from(time.timestamp()).unwrap_or_default()
+}
+
+/// Fields of a certificate used in OCSP
+#[derive(Debug, Clone)]
+struct CertInfo<'a> {
+    /// Signed part of the certificate
+    tbs: &'a [u8],
+    serial: &'a [u8],
+    issuer: &'a [u8],
+    not_before: u64,
+    not_after: u64,
+    subject: &'a [u8],
+    public_key: Cow<'a, [u8]>,
+    signature_algorithm: Oid<'a>,
+    signature: Cow<'a, [u8]>,
+}
+
+fn parse_cert(data: &[u8]) -> Result<CertInfo<'_>> {
+    let (rest, cert) =
+        X509Certificate::from_der(data).context("Error in X509Certificate from der")?;
+    if !rest.is_empty() {
+        return Err(anyhow!("Trailing data after certificate"));
+    }
+    // The signed part is the first element of the certificate sequence
+    let (_, tbs) = *der_elements(parse_der_single(data)?.data)?
+        .first()
+        .context("Empty certificate")?;
+    let X509Certificate {
+        tbs_certificate,
+        signature_algorithm,
+        signature_value,
+    } = cert;
+    if tbs_certificate.subject_pki.subject_public_key.unused_bits != 0
+        || signature_value.unused_bits != 0
+    {
+        return Err(anyhow!("Unsupported bit string"));
+    }
+
+    Ok(CertInfo {
+        tbs,
+        serial: tbs_certificate.raw_serial(),
+        issuer: tbs_certificate.issuer.as_raw(),
+        not_before: validity_time(tbs_certificate.validity.not_before),
+        not_after: validity_time(tbs_certificate.validity.not_after),
+        subject: tbs_certificate.subject.as_raw(),
+        public_key: tbs_certificate.subject_pki.subject_public_key.data,
+        signature_algorithm: signature_algorithm.algorithm,
+        signature: signature_value.data,
+    })
+}
+
+/// Verify `signature` of `message` by `public_key`
+fn verify_signature(
+    algorithm: &Oid<'_>,
+    public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+) -> Result<()> {
+    let supported: &[(
+        Oid<'static>,
+        &[&'static dyn signature::VerificationAlgorithm],
+    )] = &[
+        (
+            OID_PKCS1_SHA1WITHRSA,
+            &[&signature::RSA_PKCS1_2048_8192_SHA1_FOR_LEGACY_USE_ONLY],
+        ),
+        (
+            OID_PKCS1_SHA256WITHRSA,
+            &[&signature::RSA_PKCS1_2048_8192_SHA256],
+        ),
+        (
+            OID_PKCS1_SHA384WITHRSA,
+            &[&signature::RSA_PKCS1_2048_8192_SHA384],
+        ),
+        (
+            OID_PKCS1_SHA512WITHRSA,
+            &[&signature::RSA_PKCS1_2048_8192_SHA512],
+        ),
+        (
+            OID_SIG_ECDSA_WITH_SHA256,
+            &[
+                &signature::ECDSA_P256_SHA256_ASN1,
+                &signature::ECDSA_P384_SHA256_ASN1,
+            ],
+        ),
+        (
+            OID_SIG_ECDSA_WITH_SHA384,
+            &[
+                &signature::ECDSA_P384_SHA384_ASN1,
+                &signature::ECDSA_P256_SHA384_ASN1,
+            ],
+        ),
+        (OID_SIG_ED25519, &[&signature::ED25519]),
+    ];
+    let (_, algorithms) = supported
+        .iter()
+        .find(|(oid, _)| oid == algorithm)
+        .context(format!("Unsupported signature algorithm {algorithm}"))?;
+    algorithms
+        .iter()
+        .any(|algorithm| {
+            signature::UnparsedPublicKey::new(*algorithm, public_key)
+                .verify(message, signature)
+                .is_ok()
+        })
+        .then_some(())
+        .context("Bad signature")
+}
+
+/// Returns true if the certificate is delegated to sign OCSP responses
+fn is_ocsp_signer(data: &[u8]) -> bool {
+    let cert = match X509Certificate::from_der(data) {
+        Ok((_, cert)) => cert,
+        Err(_) => return false,
+    };
+    cert.extensions().iter().any(|extension| {
+        matches!(
+            extension.parsed_extension(),
+            ParsedExtension::ExtendedKeyUsage(usage) if usage.ocsp_signing
+        )
+    })
+}
+
+/// OCSP responder url of the certificate authority information access
+fn responder_url(data: &[u8]) -> Result<String> {
+    let (_, cert) = X509Certificate::from_der(data).context("Error in X509Certificate from der")?;
+    for extension in cert.extensions() {
+        if let ParsedExtension::AuthorityInfoAccess(access) = extension.parsed_extension() {
+            for description in &access.accessdescs {
+                if description.access_method != OID_PKIX_ACCESS_DESCRIPTOR_OCSP {
+                    continue;
+                }
+                if let GeneralName::URI(url) = &description.access_location {
+                    return Ok(url.to_string());
+                }
+            }
+        }
+    }
+    Err(anyhow!("No OCSP responder in certificate"))
+}
+
+/// Certificate identifier in OCSP
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CertId {
+    issuer_name_hash: Vec<u8>,
+    issuer_key_hash: Vec<u8>,
+    serial: Vec<u8>,
+}
+
+impl CertId {
+    fn new(cert: &CertInfo<'_>, issuer: &CertInfo<'_>) -> Self {
+        let sha1 = |data| digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, data);
+        CertId {
+            issuer_name_hash: sha1(cert.issuer).as_ref().to_vec(),
+            issuer_key_hash: sha1(&issuer.public_key).as_ref().to_vec(),
+            serial: cert.serial.to_vec(),
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let algorithm =
+            encode_sequence(&[OID_HASH_SHA1.to_der_vec()?, Null::default().to_der_vec()?])?;
+        encode_sequence(&[
+            algorithm,
+            OctetString::new(&self.issuer_name_hash).to_der_vec()?,
+            OctetString::new(&self.issuer_key_hash).to_der_vec()?,
+            Integer::new(&self.serial).to_der_vec()?,
+        ])
+    }
+
+    /// Der OCSP request of the certificate status
+    fn request(&self) -> Result<Vec<u8>> {
+        let request = encode_sequence(&[self.encode()?])?;
+        let request_list = encode_sequence(&[request])?;
+        let tbs_request = encode_sequence(&[request_list])?;
+        encode_sequence(&[tbs_request])
+    }
+
+    fn matches(&self, any: &Any<'_>) -> Result<bool> {
+        let fields = sequence(any)?;
+        if fields.len() != 4 {
+            return Err(anyhow!("Bad cert id"));
+        }
+        fields[3].tag().assert_eq(Tag::Integer)?;
+        Ok(algorithm_oid(&fields[0])? == OID_HASH_SHA1
+            && <&[u8]>::try_from(fields[1].clone())? == self.issuer_name_hash
+            && <&[u8]>::try_from(fields[2].clone())? == self.issuer_key_hash
+            && fields[3].data == self.serial)
+    }
+}
+
+/// Status of a certificate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CertStatus {
+    Good,
+    Revoked,
+}
+
+/// Check the OCSP `response` signature, freshness and status for `cert_id`
+///
+/// The response must be signed by the certificate `issuer`, or by a
+/// responder certificate it delegated
+fn check_response(
+    response: &[u8],
+    cert_id: &CertId,
+    issuer: &CertInfo<'_>,
+    now: u64,
+) -> Result<CertStatus> {
+    let response = sequence(&parse_der_single(response)?)?;
+    let status = response
+        .first()
+        .context("Empty OCSP response")?
+        .as_enumerated()
+        .context("Bad OCSP response status")?;
+    if status.0 != 0 {
+        return Err(anyhow!("OCSP responder error status {}", status.0));
+    }
+    let response_bytes = match response.get(1) {
+        Some(response_bytes) => explicit(response_bytes, 0)?,
+        None => None,
+    };
+    let response_bytes = sequence(&response_bytes.context("No OCSP response bytes")?)?;
+    if response_bytes.len() != 2 || response_bytes[0].as_oid()? != OID_OCSP_BASIC {
+        return Err(anyhow!("Unsupported OCSP response type"));
+    }
+    let basic = OctetString::try_from(response_bytes[1].clone())?;
+    let basic = parse_der_single(basic.as_ref())?;
+    basic
+        .tag()
+        .assert_eq(Tag::Sequence)
+        .context("Bad OCSP basic response")?;
+    let basic = der_elements(basic.data)?;
+    if basic.len() < 3 {
+        return Err(anyhow!("Bad OCSP basic response"));
+    }
+    let (tbs, tbs_raw) = &basic[0];
+    let signature_algorithm = algorithm_oid(&basic[1].0)?;
+    let signature = bit_string(&basic[2].0)?;
+
+    let mut signed =
+        verify_signature(&signature_algorithm, &issuer.public_key, tbs_raw, signature).is_ok();
+    if !signed {
+        let certs = match basic.get(3) {
+            Some((certs, _)) => explicit(certs, 0)?,
+            None => None,
+        };
+        if let Some(certs) = certs {
+            certs
+                .tag()
+                .assert_eq(Tag::Sequence)
+                .context("Bad OCSP responder certificates")?;
+            for (_, cert) in der_elements(certs.data)? {
+                let responder = match parse_cert(cert) {
+                    Ok(responder) => responder,
+                    Err(_) => continue,
+                };
+                if responder.issuer == issuer.subject
+                    && responder.not_before <= now + CLOCK_SKEW
+                    && now <= responder.not_after + CLOCK_SKEW
+                    && is_ocsp_signer(cert)
+                    && verify_signature(
+                        &responder.signature_algorithm,
+                        &issuer.public_key,
+                        responder.tbs,
+                        &responder.signature,
+                    )
+                    .is_ok()
+                    && verify_signature(
+                        &signature_algorithm,
+                        &responder.public_key,
+                        tbs_raw,
+                        signature,
+                    )
+                    .is_ok()
+                {
+                    signed = true;
+                    break;
+                }
+            }
+        }
+    }
+    if !signed {
+        return Err(anyhow!("Bad OCSP response signature"));
+    }
+
+    // Skip the version, the responder id and the production time
+    let mut fields = sequence(tbs)?
+        .into_iter()
+        .skip_while(|field| field.class() == Class::ContextSpecific && field.tag() == Tag(0))
+        .skip(2);
+    let responses = fields.next().context("No OCSP single responses")?;
+    for single in sequence(&responses)? {
+        let single = sequence(&single)?;
+        if single.len() < 3 {
+            return Err(anyhow!("Bad OCSP single response"));
+        }
+        if !cert_id.matches(&single[0])? {
+            continue;
+        }
+        let this_update = generalized_time(&single[2])?;
+        let next_update = match single.get(3) {
+            Some(next_update) => match explicit(next_update, 0)? {
+                Some(next_update) => Some(generalized_time(&next_update)?),
+                None => None,
+            },
+            None => None,
+        };
+        if this_update > now + CLOCK_SKEW {
+            return Err(anyhow!("OCSP response not yet valid"));
+        }
+        let expiry = next_update.unwrap_or(this_update + MAX_RESPONSE_AGE);
+        if expiry + CLOCK_SKEW < now {
+            return Err(anyhow!("Stale OCSP response"));
+        }
+        // Implicitly tagged choice
+        let status = &single[1];
+        if status.class() != Class::ContextSpecific {
+            return Err(anyhow!("Bad OCSP certificate status"));
+        }
+        return match status.tag() {
+            Tag(0) => Ok(CertStatus::Good),
+            Tag(1) => Ok(CertStatus::Revoked),
+            Tag(2) => Err(anyhow!("Certificate unknown by the OCSP responder")),
+            tag => Err(anyhow!("Bad OCSP certificate status {}", tag)),
+        };
+    }
+    Err(anyhow!("No OCSP status for the certificate"))
+}
+
+/// Name resolution bounded by a timeout. On timeout, the resolution thread
+/// is left to finish on its own.
+fn resolve_timeout(netloc: &str, timeout: Duration) -> io::Result<Vec<SocketAddr>> {
+    let (sender, receiver) = mpsc::channel();
+    let netloc = netloc.to_owned();
+    thread::spawn(move || {
+        let _ = sender.send(netloc.to_socket_addrs().map(Iterator::collect));
+    });
+    receiver.recv_timeout(timeout).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            "OCSP responder name resolution timeout",
+        )
+    })?
+}
+
+/// Post the der `request` to an http(s) OCSP responder. The whole exchange,
+/// including the name resolution, is bounded by `timeout`.
+fn post_request(url: &str, request: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(timeout)
+        .resolver(move |netloc: &str| resolve_timeout(netloc, timeout))
+        .build();
+    let response = agent
+        .post(url)
+        .set("Content-Type", "application/ocsp-request")
+        .set("Accept", "application/ocsp-response")
+        .send_bytes(request)
+        .context("Error in OCSP request")?;
+    let mut body = vec![];
+    response
+        .into_reader()
+        .take(MAX_RESPONSE_SIZE)
+        .read_to_end(&mut body)
+        .context("Error in OCSP response")?;
+    Ok(body)
+}
+
+/// Issuer of `cert` among `candidates`: the first one with the issuer name
+/// whose key verifies the `cert` signature. The name alone may match a
+/// certificate with another key, which would give a wrong OCSP cert id.
+fn find_issuer<'a>(
+    cert: &CertInfo<'_>,
+    candidates: impl IntoIterator<Item = CertInfo<'a>>,
+) -> Option<CertInfo<'a>> {
+    candidates.into_iter().find(|candidate| {
+        candidate.subject == cert.issuer
+            && verify_signature(
+                &cert.signature_algorithm,
+                &candidate.public_key,
+                cert.tbs,
+                &cert.signature,
+            )
+            .is_ok()
+    })
+}
+
+/// Check the OCSP `response` stapled by the server to its certificate chain
+/// `certs`: it must give a good and fresh status of the server certificate.
+/// The issuer is searched in `roots`, then in the chain.
+pub fn check_stapled_response(
+    certs: &[CertificateDer<'_>],
+    roots: &[CertificateDer<'_>],
+    response: &[u8],
+    now: u64,
+) -> Result<()> {
+    let cert = parse_cert(certs.first().context("No server certificate")?)
+        .context("Error in server certificate parsing")?;
+    let candidates = roots
+        .iter()
+        .chain(certs.iter().skip(1))
+        .filter_map(|data| parse_cert(data).ok());
+    let issuer =
+        find_issuer(&cert, candidates).context("Cannot find the server certificate issuer")?;
+    match check_response(response, &CertId::new(&cert, &issuer), &issuer, now)? {
+        CertStatus::Good => Ok(()),
+        CertStatus::Revoked => Err(anyhow!("Server certificate revoked")),
+    }
+}
+
+/// Client certificate verifier which also requires a good and fresh OCSP
+/// status of the client certificate. Errors are fatal.
+#[derive(Debug)]
+pub struct OcspClientVerifier {
+    inner: Arc<dyn ClientCertVerifier>,
+    /// Certificate authorities, searched first for the client certificate
+    /// issuer
+    roots: Vec<CertificateDer<'static>>,
+    config: OcspConfig,
+}
+
+impl OcspClientVerifier {
+    pub fn new(
+        inner: Arc<dyn ClientCertVerifier>,
+        roots: Vec<CertificateDer<'static>>,
+        config: OcspConfig,
+    ) -> Self {
+        OcspClientVerifier {
+            inner,
+            roots,
+            config,
+        }
+    }
+
+    fn check(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: u64,
+    ) -> Result<CertStatus> {
+        let cert = parse_cert(end_entity).context("Error in client certificate parsing")?;
+        // The trusted roots first, then the intermediates sent by the client
+        let candidates = self
+            .roots
+            .iter()
+            .chain(intermediates)
+            .filter_map(|data| parse_cert(data).ok());
+        let issuer =
+            find_issuer(&cert, candidates).context("Cannot find the client certificate issuer")?;
+        let url = match &self.config.responder_url {
+            Some(url) => url.to_owned(),
+            None => responder_url(end_entity)?,
+        };
+        let cert_id = CertId::new(&cert, &issuer);
+        debug!("OCSP request to {}", url);
+        let response = post_request(&url, &cert_id.request()?, self.config.timeout)?;
+        check_response(&response, &cert_id, &issuer, now)
+    }
+}
+
+impl ClientCertVerifier for OcspClientVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.inner.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.inner.client_auth_mandatory()
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.inner.root_hint_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let verified = self
+            .inner
+            .verify_client_cert(end_entity, intermediates, now)?;
+        match self.check(end_entity, intermediates, now.as_secs()) {
+            Ok(CertStatus::Good) => Ok(verified),
+            Ok(CertStatus::Revoked) => {
+                error!("Client certificate revoked (OCSP)");
+                Err(rustls::Error::InvalidCertificate(CertificateError::Revoked))
+            }
+            Err(err) => {
+                error!("OCSP check failed");
+                err.chain().for_each(|cause| error!(" - due to {}", cause));
+                Err(rustls::Error::General(format!("OCSP check failed: {err}")))
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asn1_rs::{BitString, Enumerated, Header, Length};
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+
+    /// Der element with the context specific `tag`
+    fn tagged(tag: u32, constructed: bool, content: &[u8]) -> Vec<u8> {
+        let header = Header::new(
+            Class::ContextSpecific,
+            constructed,
+            Tag(tag),
+            Length::Definite(0),
+        );
+        Any::new(header, content).to_der_vec().unwrap()
+    }
+
+    fn generalized_time(time: &str) -> Vec<u8> {
+        GeneralizedTime::from_bytes(time.as_bytes())
+            .unwrap()
+            .to_der_vec()
+            .unwrap()
+    }
+
+    /// Ocsp response signed by `key` for `cert_id`, with the certificate
+    /// status `status` (der encoded)
+    fn make_response(key: &Ed25519KeyPair, cert_id: &CertId, status: &[u8]) -> Vec<u8> {
+        let single = encode_sequence(&[
+            cert_id.encode().unwrap(),
+            status.to_vec(),
+            generalized_time("20240101000000Z"),
+            tagged(0, true, &generalized_time("20240108000000Z")),
+        ])
+        .unwrap();
+        let responses = encode_sequence(&[single]).unwrap();
+        let responder_id = OctetString::new(&[0u8; 20]).to_der_vec().unwrap();
+        let tbs = encode_sequence(&[
+            tagged(2, true, &responder_id),
+            generalized_time("20240101000000Z"),
+            responses,
+        ])
+        .unwrap();
+        let signature = key.sign(&tbs);
+        let basic = encode_sequence(&[
+            tbs,
+            encode_sequence(&[OID_SIG_ED25519.to_der_vec().unwrap()]).unwrap(),
+            BitString::new(0, signature.as_ref()).to_der_vec().unwrap(),
+        ])
+        .unwrap();
+        let response_bytes = encode_sequence(&[
+            OID_OCSP_BASIC.to_der_vec().unwrap(),
+            OctetString::new(&basic).to_der_vec().unwrap(),
+        ])
+        .unwrap();
+        encode_sequence(&[
+            Enumerated(0).to_der_vec().unwrap(),
+            tagged(0, true, &response_bytes),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_ocsp_response() {
+        let rng = SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let other_pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let other_key = Ed25519KeyPair::from_pkcs8(other_pkcs8.as_ref()).unwrap();
+
+        let issuer = CertInfo {
+            tbs: &[],
+            serial: &[1],
+            issuer: b"issuer",
+            not_before: 0,
+            not_after: u64::MAX / 2,
+            subject: b"issuer",
+            public_key: key.public_key().as_ref().into(),
+            signature_algorithm: OID_SIG_ED25519,
+            signature: (&[][..]).into(),
+        };
+        let cert = CertInfo {
+            serial: &[0x01, 0x42],
+            ..issuer.clone()
+        };
+        let cert_id = CertId::new(&cert, &issuer);
+        let now = 1704240000; // 2024-01-03
+
+        let good = make_response(&key, &cert_id, &tagged(0, false, &[]));
+        assert_eq!(
+            check_response(&good, &cert_id, &issuer, now).unwrap(),
+            CertStatus::Good
+        );
+        // Expired
+        assert!(check_response(&good, &cert_id, &issuer, now + 7 * 86400).is_err());
+        // Other certificate
+        let other_id = CertId {
+            serial: vec![0x02],
+            ..cert_id.clone()
+        };
+        assert!(check_response(&good, &other_id, &issuer, now).is_err());
+
+        let revoked_info = tagged(1, true, &generalized_time("20231201000000Z"));
+        let revoked = make_response(&key, &cert_id, &revoked_info);
+        assert_eq!(
+            check_response(&revoked, &cert_id, &issuer, now).unwrap(),
+            CertStatus::Revoked
+        );
+
+        let unknown = make_response(&key, &cert_id, &tagged(2, false, &[]));
+        assert!(check_response(&unknown, &cert_id, &issuer, now).is_err());
+
+        let forged = make_response(&other_key, &cert_id, &tagged(0, false, &[]));
+        assert!(check_response(&forged, &cert_id, &issuer, now).is_err());
+        assert!(check_response(&good[..good.len() - 1], &cert_id, &issuer, now).is_err());
+    }
+
+    #[test]
+    fn test_find_issuer() {
+        let rng = SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let other_pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let other_key = Ed25519KeyPair::from_pkcs8(other_pkcs8.as_ref()).unwrap();
+
+        let issuer = CertInfo {
+            tbs: &[],
+            serial: &[1],
+            issuer: b"root",
+            not_before: 0,
+            not_after: u64::MAX / 2,
+            subject: b"intermediate",
+            public_key: key.public_key().as_ref().into(),
+            signature_algorithm: OID_SIG_ED25519,
+            signature: (&[][..]).into(),
+        };
+        // Same subject, other key
+        let impostor = CertInfo {
+            public_key: other_key.public_key().as_ref().into(),
+            ..issuer.clone()
+        };
+        let tbs = b"client certificate";
+        let signature = key.sign(tbs);
+        let cert = CertInfo {
+            tbs,
+            serial: &[2],
+            issuer: b"intermediate",
+            subject: b"client",
+            signature: signature.as_ref().into(),
+            ..issuer.clone()
+        };
+
+        let found = find_issuer(&cert, vec![impostor.clone(), issuer.clone()]).unwrap();
+        assert_eq!(found.public_key, issuer.public_key);
+        assert!(find_issuer(&cert, vec![impostor]).is_none());
+    }
+
+    #[test]
+    fn test_request() {
+        let cert_id = CertId {
+            issuer_name_hash: vec![0x11; 20],
+            issuer_key_hash: vec![0x22; 20],
+            serial: vec![0x01, 0x42],
+        };
+        // OCSPRequest / TBSRequest / requestList / Request / CertID
+        let request = cert_id.request().unwrap();
+        let mut any = parse_der_single(&request).unwrap();
+        for _ in 0..4 {
+            let elements = sequence(&any).unwrap();
+            assert_eq!(elements.len(), 1);
+            any = elements[0].clone();
+        }
+        assert!(cert_id.matches(&any).unwrap());
+        let other_id = CertId {
+            serial: vec![0x02],
+            ..cert_id.clone()
+        };
+        assert!(!other_id.matches(&any).unwrap());
+    }
+}
@@ -10,8 +10,33 @@ use byteorder::ByteOrder;
 use byteorder::{LittleEndian, ReadBytesExt};
 use std::io::Cursor;
 use std::io::{Read, Write};
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
 
-pub const VERSION: &str = "0.1.1";
+/// Protocol version. Peers are compatible if they share the wire format
+/// version: the major number, or the major and minor ones before 1.0. Optional
+/// features are negotiated with the `Capabilities` message.
+pub const VERSION: &str = "0.2.0";
+
+/// Wire format part of `version`
+pub(crate) fn wire_version(version: &str) -> (Option<&str>, Option<&str>) {
+    let mut parts = version.split('.');
+    match parts.next() {
+        Some("0") => (Some("0"), parts.next()),
+        major => (major, None),
+    }
+}
+
+/// Check that the `peer` version is compatible with ours
+pub fn check_version(peer: &str) -> Result<()> {
+    if wire_version(peer) != wire_version(VERSION) {
+        return Err(anyhow!(
+            "Version mismatch local: {:?} peer: {:?}",
+            VERSION,
+            peer
+        ));
+    }
+    Ok(())
+}
 
 /// Code generated by Tunnel
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -19,7 +44,65 @@ pub mod tunnel {
     include!(concat!(env!("OUT_DIR"), "/tunnel.rs"));
 }
 
-const MAX_PACKET_LEN: usize = 100 * 1024 * 1024; // 100 Mo
+/// Default maximum length of a received message
+pub const DEFAULT_MAX_MSG_LEN: usize = 16 * 1024 * 1024; // 16 Mo
+/// Default maximum length of a received message which may hold frames
+pub const DEFAULT_MAX_FRAME_MSG_LEN: usize = 100 * 1024 * 1024; // 100 Mo
+
+static MAX_MSG_LEN: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_MSG_LEN);
+static MAX_FRAME_MSG_LEN: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_FRAME_MSG_LEN);
+
+/// Length header flag of zstd compressed messages
+const COMPRESSED_FLAG: u64 = 1 << 63;
+/// Smaller messages are not worth compressing
+const MIN_COMPRESSED_LEN: usize = 256;
+const NO_COMPRESSION: i32 = i32::MIN;
+
+static COMPRESSION_LEVEL: AtomicI32 = AtomicI32::new(NO_COMPRESSION);
+
+/// Set the maximum length of received messages
+///
+/// `max_frame_msg_len` applies to server messages, which may hold raw frames
+pub fn set_max_msg_len(max_msg_len: usize, max_frame_msg_len: usize) {
+    MAX_MSG_LEN.store(max_msg_len, Ordering::Relaxed);
+    MAX_FRAME_MSG_LEN.store(max_frame_msg_len.max(max_msg_len), Ordering::Relaxed);
+}
+
+/// Compress the sent messages with zstd at `level`, or disable compression.
+///
+/// Must only be enabled once negotiated with the peer. Compressed messages
+/// are always accepted.
+pub fn set_compression(level: Option<i32>) {
+    COMPRESSION_LEVEL.store(level.unwrap_or(NO_COMPRESSION), Ordering::Relaxed);
+}
+
+/// Messages which may be compressed
+pub trait Compressible {
+    /// Returns false if the message mostly holds already compressed data
+    fn compressible(&self) -> bool {
+        true
+    }
+}
+
+impl Compressible for tunnel::ClientMsgOrErr {}
+
+impl Compressible for tunnel::Version {}
+
+impl Compressible for tunnel::ServerMsgOrErr {
+    /// Encoded video is not compressed
+    fn compressible(&self) -> bool {
+        let msgs = match &self.msg {
+            Some(tunnel::server_msg_or_err::Msg::Ok(tunnel::MessageServerOk {
+                msg: Some(tunnel::message_server_ok::Msg::Msgssrv(msgs)),
+            })) => msgs,
+            _ => return true,
+        };
+        !msgs
+            .msgs
+            .iter()
+            .any(|msg| matches!(msg.msg, Some(tunnel::message_srv::Msg::ImgEncoded(_))))
+    }
+}
 
 /// Read + Write trait used to send protobuf serialized messages
 pub trait ReadWrite: Read + Write + Send + Sync {}
@@ -48,17 +131,42 @@ impl Read for Stdio {
 pub struct Tunnel {}
 
 impl Tunnel {
-    /// Send a serialized message through `stream`
+    /// Send a serialized message through `stream`, compressed if enabled by
+    /// `set_compression`
     pub fn send<T>(stream: &mut dyn ReadWrite, req: T) -> Result<()>
     where
-        T: prost::Message,
+        T: prost::Message + Compressible,
+    {
+        let level = match COMPRESSION_LEVEL.load(Ordering::Relaxed) {
+            NO_COMPRESSION => None,
+            level => Some(level),
+        };
+        Tunnel::send_with_compression(stream, req, level)
+    }
+
+    /// Send a serialized message through `stream`, compressed with zstd at
+    /// `level` if set
+    pub fn send_with_compression<T>(
+        stream: &mut dyn ReadWrite,
+        req: T,
+        level: Option<i32>,
+    ) -> Result<()>
+    where
+        T: prost::Message + Compressible,
     {
-        // Send length, on 8 bytes
-        let mut buffer = vec![0u8; 8];
-        LittleEndian::write_u64(&mut buffer, req.encoded_len() as u64);
         // Encode request
         let mut req_buf = vec![];
         req.encode(&mut req_buf).context("Cannot encode pkt")?;
+        let mut len = req_buf.len() as u64;
+        if let Some(level) = level {
+            if req_buf.len() >= MIN_COMPRESSED_LEN && req.compressible() {
+                req_buf = zstd::bulk::compress(&req_buf, level).context("Cannot compress pkt")?;
+                len = req_buf.len() as u64 | COMPRESSED_FLAG;
+            }
+        }
+        // Send length, on 8 bytes
+        let mut buffer = vec![0u8; 8];
+        LittleEndian::write_u64(&mut buffer, len);
         buffer.append(&mut req_buf);
         // Send request
         stream.write_all(&buffer).context("Cannot write pkt")?;
@@ -67,6 +175,25 @@ impl Tunnel {
 
     /// Receive a serialized message from `stream`
     pub fn recv<T>(stream: &mut dyn ReadWrite) -> Result<T>
+    where
+        T: prost::Message + Default,
+    {
+        Tunnel::recv_with_max_len(stream, MAX_MSG_LEN.load(Ordering::Relaxed))
+    }
+
+    /// Receive a serialized message which may hold frames from `stream`
+    pub fn recv_frame_msg<T>(stream: &mut dyn ReadWrite) -> Result<T>
+    where
+        T: prost::Message + Default,
+    {
+        Tunnel::recv_with_max_len(stream, MAX_FRAME_MSG_LEN.load(Ordering::Relaxed))
+    }
+
+    /// Receive a serialized message from `stream`
+    ///
+    /// The message length is checked against `max_len` before allocation, and
+    /// during decompression
+    pub fn recv_with_max_len<T>(stream: &mut dyn ReadWrite, max_len: usize) -> Result<T>
     where
         T: prost::Message + Default,
     {
@@ -74,15 +201,38 @@ impl Tunnel {
         stream.read_exact(&mut buffer).context("Cannot read pkt")?;
 
         let mut rdr = Cursor::new(buffer);
-        let len =
-            ReadBytesExt::read_u64::<LittleEndian>(&mut rdr).context("Cannot read len")? as usize;
-        if len > MAX_PACKET_LEN {
-            return Err(anyhow!("Packet too big!"));
+        let len = ReadBytesExt::read_u64::<LittleEndian>(&mut rdr).context("Cannot read len")?;
+        let compressed = len & COMPRESSED_FLAG != 0;
+        let len = len & !COMPRESSED_FLAG;
+        if len > max_len as u64 {
+            return Err(anyhow!(
+                "Protocol error: packet length {} exceeds maximum {}",
+                len,
+                max_len
+            ));
         }
-        let mut req_buffer = vec![0u8; len];
+        let mut req_buffer = vec![0u8; len as usize];
         stream
             .read_exact(&mut req_buffer)
-            .context("Cannot read pkt")?;
+            .context("Protocol error: truncated pkt")?;
+        if compressed {
+            // Stream the decompression, so that memory is only allocated for
+            // the actual content, up to `max_len`
+            let mut decoder = zstd::stream::read::Decoder::with_buffer(req_buffer.as_slice())
+                .context("Protocol error: cannot decompress pkt")?
+                .take(max_len as u64 + 1);
+            let mut data = vec![];
+            decoder
+                .read_to_end(&mut data)
+                .context("Protocol error: cannot decompress pkt")?;
+            if data.len() > max_len {
+                return Err(anyhow!(
+                    "Protocol error: decompressed packet length exceeds maximum {}",
+                    max_len
+                ));
+            }
+            req_buffer = data;
+        }
         match prost::Message::decode(req_buffer.as_slice()) {
             Ok(pkt) => Ok(pkt),
             Err(_) => {
@@ -180,7 +330,8 @@ macro_rules! recv_client_msg_type {
 pub fn recv_server_msg_or_error(
     stream: &mut dyn ReadWrite,
 ) -> Result<tunnel::message_server_ok::Msg> {
-    let msg: tunnel::ServerMsgOrErr = Tunnel::recv(stream).context("Error in recv pkt")?;
+    let msg: tunnel::ServerMsgOrErr =
+        Tunnel::recv_frame_msg(stream).context("Error in recv pkt")?;
     match msg.msg {
         Some(tunnel::server_msg_or_err::Msg::Ok(msg_ok)) => {
             // Message is ok
@@ -255,3 +406,115 @@ macro_rules! send_client_msg_type {
             .map_err(|err| anyhow!("Error in send: Peer has closed connection? ({:?})", err,))
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    fn encode_version(version: &str) -> Vec<u8> {
+        let mut stream = Cursor::new(vec![]);
+        let msg = tunnel::Version {
+            version: version.to_owned(),
+        };
+        Tunnel::send(&mut stream, msg).unwrap();
+        stream.into_inner()
+    }
+
+    #[test]
+    fn test_recv_at_limit() {
+        let data = encode_version(VERSION);
+        let len = data.len() - 8;
+        let msg: tunnel::Version = Tunnel::recv_with_max_len(&mut Cursor::new(data), len).unwrap();
+        assert_eq!(msg.version, VERSION);
+    }
+
+    #[test]
+    fn test_recv_over_limit() {
+        let data = encode_version(VERSION);
+        let len = data.len() - 8;
+        let err = Tunnel::recv_with_max_len::<tunnel::Version>(&mut Cursor::new(data), len - 1)
+            .unwrap_err();
+        assert!(err.to_string().contains("exceeds maximum"));
+
+        // Huge length prefix must not be allocated
+        let mut data = u64::MAX.to_le_bytes().to_vec();
+        data.extend_from_slice(&[0u8; 16]);
+        let err = Tunnel::recv::<tunnel::Version>(&mut Cursor::new(data)).unwrap_err();
+        assert!(err.to_string().contains("exceeds maximum"));
+    }
+
+    #[test]
+    fn test_compressed_roundtrip() {
+        let text = tunnel::EventClipboard {
+            content: Some(tunnel::event_clipboard::Content::Text(
+                "sanzu ".repeat(1000),
+            )),
+        };
+        let msgs = |msg| tunnel::ServerMsgOrErr {
+            msg: Some(tunnel::server_msg_or_err::Msg::Ok(
+                tunnel::MessageServerOk {
+                    msg: Some(tunnel::message_server_ok::Msg::Msgssrv(
+                        tunnel::MessagesSrv {
+                            msgs: vec![tunnel::MessageSrv { msg: Some(msg) }],
+                        },
+                    )),
+                },
+            )),
+        };
+        let clipboard = msgs(tunnel::message_srv::Msg::Clipboard(text));
+        let video = msgs(tunnel::message_srv::Msg::ImgEncoded(tunnel::ImageEncoded {
+            data: vec![0; 1000],
+            ..Default::default()
+        }));
+        let version = tunnel::Version {
+            version: VERSION.to_owned(),
+        };
+
+        let mut stream = Cursor::new(vec![]);
+        Tunnel::send_with_compression(&mut stream, clipboard.clone(), Some(3)).unwrap();
+        let clipboard_len = stream.position();
+        Tunnel::send_with_compression(&mut stream, video.clone(), Some(3)).unwrap();
+        let video_len = stream.position() - clipboard_len;
+        Tunnel::send_with_compression(&mut stream, version.clone(), Some(3)).unwrap();
+        Tunnel::send_with_compression(&mut stream, clipboard.clone(), None).unwrap();
+        assert!((clipboard_len as usize) < clipboard.encoded_len() / 10);
+        assert_eq!(video_len as usize, video.encoded_len() + 8);
+
+        stream.set_position(0);
+        let msg: tunnel::ServerMsgOrErr = Tunnel::recv(&mut stream).unwrap();
+        assert_eq!(msg, clipboard);
+        let msg: tunnel::ServerMsgOrErr = Tunnel::recv(&mut stream).unwrap();
+        assert_eq!(msg, video);
+        let msg: tunnel::Version = Tunnel::recv(&mut stream).unwrap();
+        assert_eq!(msg, version);
+        let msg: tunnel::ServerMsgOrErr = Tunnel::recv(&mut stream).unwrap();
+        assert_eq!(msg, clipboard);
+
+        // The decompressed length is checked too
+        let mut stream = Cursor::new(vec![]);
+        Tunnel::send_with_compression(&mut stream, clipboard, Some(3)).unwrap();
+        stream.set_position(0);
+        let err =
+            Tunnel::recv_with_max_len::<tunnel::ServerMsgOrErr>(&mut stream, 1000).unwrap_err();
+        assert!(format!("{err:?}").contains("decompress"));
+    }
+
+    #[test]
+    fn test_check_version() {
+        assert!(check_version(VERSION).is_ok());
+        assert!(check_version("0.2.7").is_ok());
+        assert!(check_version("0.1.1").is_err());
+        assert!(check_version("1.2.0").is_err());
+        assert!(check_version("").is_err());
+        assert_eq!(wire_version("1.2.0"), wire_version("1.3"));
+    }
+
+    #[test]
+    fn test_recv_truncated() {
+        let mut data = encode_version(VERSION);
+        data.pop();
+        let err = Tunnel::recv::<tunnel::Version>(&mut Cursor::new(data)).unwrap_err();
+        assert!(err.to_string().contains("truncated"));
+    }
+}
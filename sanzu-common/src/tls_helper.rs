@@ -1,8 +1,13 @@
+use crate::ocsp::{check_stapled_response, OcspClientVerifier, OcspConfig};
 pub use crate::ReadWrite;
 use anyhow::{Context, Result};
 use rustls::ServerConnection;
-use rustls::{self, server::WebPkiClientVerifier, RootCertStore};
-use rustls_pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer};
+use rustls::{
+    self,
+    server::{danger::ClientCertVerifier, WebPkiClientVerifier},
+    RootCertStore, SupportedCipherSuite, SupportedProtocolVersion,
+};
+use rustls_pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer, UnixTime};
 use std::{
     fs,
     io::{BufReader, Read, Write},
@@ -52,6 +57,90 @@ fn load_ocsp(filename: &str) -> Result<Vec<u8>> {
         .context("Cannot read ocsp file")?;
     Ok(ret)
 }
+
+/// Restriction of the tls protocol versions and cipher suites
+///
+/// Unset fields keep the defaults of the server or client config.
+#[derive(Debug, Default, Clone)]
+pub struct TlsPolicy {
+    versions: Option<Vec<&'static SupportedProtocolVersion>>,
+    cipher_suites: Option<Vec<SupportedCipherSuite>>,
+}
+
+impl TlsPolicy {
+    /// Build a policy from a minimum protocol version ("1.2" or "1.3") and
+    /// cipher suite names (ex: "TLS13_AES_256_GCM_SHA384")
+    pub fn new(min_version: Option<&str>, cipher_suites: Option<&[String]>) -> Result<Self> {
+        let versions = match min_version {
+            Some("1.2") => Some(rustls::ALL_VERSIONS.to_vec()),
+            Some("1.3") => Some(vec![&rustls::version::TLS13]),
+            Some(version) => {
+                return Err(anyhow!(
+                    "Unknown tls version {:?} (supported: \"1.2\", \"1.3\")",
+                    version
+                ))
+            }
+            None => None,
+        };
+
+        let cipher_suites = match cipher_suites {
+            Some(names) => {
+                let mut suites = vec![];
+                for name in names {
+                    let suite = rustls::crypto::ring::ALL_CIPHER_SUITES
+                        .iter()
+                        .find(|suite| suite.suite().as_str() == Some(name.as_str()))
+                        .context(format!(
+                            "Unknown cipher suite {:?} (supported: {})",
+                            name,
+                            rustls::crypto::ring::ALL_CIPHER_SUITES
+                                .iter()
+                                .filter_map(|suite| suite.suite().as_str())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ))?;
+                    suites.push(*suite);
+                }
+                if suites.is_empty() {
+                    return Err(anyhow!("Empty cipher suites list"));
+                }
+                Some(suites)
+            }
+            None => None,
+        };
+
+        if let (Some(versions), Some(suites)) = (&versions, &cipher_suites) {
+            if !suites
+                .iter()
+                .any(|suite| versions.contains(&suite.version()))
+            {
+                return Err(anyhow!(
+                    "No cipher suite is usable with tls version {:?}",
+                    min_version.unwrap_or_default()
+                ));
+            }
+        }
+
+        Ok(TlsPolicy {
+            versions,
+            cipher_suites,
+        })
+    }
+
+    fn versions(
+        &self,
+        default: &[&'static SupportedProtocolVersion],
+    ) -> Vec<&'static SupportedProtocolVersion> {
+        self.versions.clone().unwrap_or_else(|| default.to_vec())
+    }
+
+    fn cipher_suites(&self, default: &[SupportedCipherSuite]) -> Vec<SupportedCipherSuite> {
+        self.cipher_suites
+            .clone()
+            .unwrap_or_else(|| default.to_vec())
+    }
+}
+
 /// Apply tls operation to socket
 fn tls_transfer<T: Read + Write>(server: &mut ServerConnection, socket: &mut T) -> Result<()> {
     if server.wants_write() {
@@ -79,6 +168,11 @@ pub fn tls_do_handshake<T: Read + Write>(
 }
 
 /// Make tls server config from config file
+///
+/// If `require_ocsp` is set, client certificates must also have a good OCSP
+/// status, and the OCSP response stapled from `ocsp_file` must give a good
+/// and fresh status of the server certificate
+#[allow(clippy::too_many_arguments)]
 pub fn make_server_config(
     ca_file: &str,
     crl_file: Option<&str>,
@@ -86,16 +180,28 @@ pub fn make_server_config(
     server_cert: &str,
     server_key: &str,
     auth_client: bool,
+    require_ocsp: Option<OcspConfig>,
+    policy: &TlsPolicy,
 ) -> Result<Arc<rustls::ServerConfig>> {
     let mut client_auth_roots = RootCertStore::empty();
     let roots = load_certs(ca_file).context("Cannot load ca certificates")?;
-    for root in roots {
+    for root in roots.iter().cloned() {
         client_auth_roots
             .add(root)
             .context("Cannot add root cert")?;
     }
     let certs = load_certs(server_cert).context("Cannot load server ceritifactes")?;
 
+    let ocsp = if let Some(ocsp_file) = ocsp_file {
+        load_ocsp(ocsp_file).context("Error in load ocsp file")?
+    } else {
+        vec![]
+    };
+    if require_ocsp.is_some() && !ocsp.is_empty() {
+        let now = UnixTime::now().as_secs();
+        check_stapled_response(&certs, &roots, &ocsp, now).context("Bad stapled OCSP response")?;
+    }
+
     let client_verifier = WebPkiClientVerifier::builder(client_auth_roots.into());
     let client_verifier = if let Some(crl_file) = crl_file {
         let mut crl_file = fs::File::open(crl_file).context("Cannot open crl file")?;
@@ -117,19 +223,17 @@ pub fn make_server_config(
     let client_auth = client_verifier
         .build()
         .context("Cannot build client verifier")?;
+    let client_auth: Arc<dyn ClientCertVerifier> = match require_ocsp {
+        Some(ocsp_config) => Arc::new(OcspClientVerifier::new(client_auth, roots, ocsp_config)),
+        None => client_auth,
+    };
 
-    let suites = rustls::crypto::ring::ALL_CIPHER_SUITES.to_vec();
+    let suites = policy.cipher_suites(rustls::crypto::ring::ALL_CIPHER_SUITES);
 
-    let versions = rustls::ALL_VERSIONS.to_vec();
+    let versions = policy.versions(rustls::ALL_VERSIONS);
 
     let privkey = load_private_key(server_key).context("Cannot load private key")?;
 
-    let ocsp = if let Some(ocsp_file) = ocsp_file {
-        load_ocsp(ocsp_file).context("Error in load ocsp file")?
-    } else {
-        vec![]
-    };
-
     let mut config = rustls::ServerConfig::builder_with_provider(
         rustls::crypto::CryptoProvider {
             cipher_suites: suites,
@@ -182,6 +286,7 @@ pub fn make_client_config(
     ca_file: Option<&str>,
     client_cert: Option<&str>,
     client_key: Option<&str>,
+    policy: &TlsPolicy,
 ) -> Result<Arc<rustls::ClientConfig>> {
     let mut root_store = RootCertStore::empty();
 
@@ -197,9 +302,9 @@ pub fn make_client_config(
         root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned())
     }
 
-    let suites = rustls::crypto::ring::DEFAULT_CIPHER_SUITES.to_vec();
+    let suites = policy.cipher_suites(rustls::crypto::ring::DEFAULT_CIPHER_SUITES);
 
-    let versions = rustls::DEFAULT_VERSIONS.to_vec();
+    let versions = policy.versions(rustls::DEFAULT_VERSIONS);
 
     let config = rustls::ClientConfig::builder_with_provider(
         rustls::crypto::CryptoProvider {
@@ -230,3 +335,33 @@ pub fn make_client_config(
     config.enable_sni = false;
     Ok(Arc::new(config))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tls_policy() {
+        let policy = TlsPolicy::default();
+        assert_eq!(policy.versions(rustls::ALL_VERSIONS).len(), 2);
+
+        let policy = TlsPolicy::new(Some("1.3"), None).unwrap();
+        assert_eq!(
+            policy.versions(rustls::ALL_VERSIONS),
+            vec![&rustls::version::TLS13]
+        );
+
+        let suites = vec!["TLS13_AES_256_GCM_SHA384".to_owned()];
+        let policy = TlsPolicy::new(Some("1.3"), Some(&suites)).unwrap();
+        assert_eq!(
+            policy.cipher_suites(rustls::crypto::ring::ALL_CIPHER_SUITES),
+            vec![rustls::crypto::ring::cipher_suite::TLS13_AES_256_GCM_SHA384]
+        );
+
+        assert!(TlsPolicy::new(Some("1.1"), None).is_err());
+        assert!(TlsPolicy::new(None, Some(&["TLS13_RC4".to_owned()])).is_err());
+        assert!(TlsPolicy::new(None, Some(&[])).is_err());
+        let suites = vec!["TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384".to_owned()];
+        assert!(TlsPolicy::new(Some("1.3"), Some(&suites)).is_err());
+    }
+}
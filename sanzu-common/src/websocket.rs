@@ -0,0 +1,689 @@
+//! WebSocket transport of the tunnel (RFC 6455)
+//!
+//! Browsers cannot open raw tcp connections: the tunnel byte stream (length
+//! headers and messages) is carried in WebSocket binary messages, above the
+//! tls layer (wss). A tunnel message may span several WebSocket messages.
+//!
+//! The frames are read straight from the stream, without read-ahead, so that
+//! the sockets can still be polled. The peers agree on the tunnel wire version
+//! with the `Sec-WebSocket-Protocol` header of the http upgrade.
+
+use crate::proto::{wire_version, VERSION};
+use anyhow::{Context, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use ring::{
+    digest,
+    rand::{SecureRandom, SystemRandom},
+};
+use std::io::{self, Read, Write};
+
+/// Suffix of the client key in the handshake accept value
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Only version of the protocol
+const WEBSOCKET_VERSION: &str = "13";
+/// Maximum length of the http upgrade request or response
+const MAX_HANDSHAKE_LEN: usize = 8192;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+/// Maximum payload length of the control frames
+const MAX_CONTROL_LEN: u64 = 125;
+/// Close status of a normal closure
+const CLOSE_NORMAL: u16 = 1000;
+
+/// WebSocket subprotocol of the tunnel: "sanzu-" followed by its wire format
+/// version
+pub fn subprotocol() -> String {
+    match wire_version(VERSION) {
+        (Some(major), Some(minor)) => format!("sanzu-{major}.{minor}"),
+        (major, _) => format!("sanzu-{}", major.unwrap_or_default()),
+    }
+}
+
+/// Handshake accept value of the client `key`
+fn accept_key(key: &str) -> String {
+    let key = format!("{key}{ACCEPT_GUID}");
+    BASE64_STANDARD.encode(digest::digest(
+        &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        key.as_bytes(),
+    ))
+}
+
+/// Read an http head, up to its empty line. It is read byte per byte, so that
+/// the following frames stay in the stream.
+pub fn read_http_head<S: Read>(stream: &mut S) -> Result<String> {
+    let mut head = vec![];
+    let mut byte = [0u8];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HANDSHAKE_LEN {
+            return Err(anyhow!("Http head too long"));
+        }
+        stream
+            .read_exact(&mut byte)
+            .context("Cannot read http head")?;
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).context("Http head is not utf8")
+}
+
+/// Http head: its first line, and its headers with lowercase names
+struct HttpHead<'a> {
+    first_line: &'a str,
+    headers: Vec<(String, &'a str)>,
+}
+
+impl<'a> HttpHead<'a> {
+    fn parse(head: &'a str) -> Result<Self> {
+        let mut lines = head.split("\r\n").filter(|line| !line.is_empty());
+        let first_line = lines.next().context("Empty http head")?;
+        let headers = lines
+            .map(|line| {
+                let (name, value) = line
+                    .split_once(':')
+                    .with_context(|| format!("Bad http header {line:?}"))?;
+                Ok((name.trim().to_ascii_lowercase(), value.trim()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(HttpHead {
+            first_line,
+            headers,
+        })
+    }
+
+    /// Value of the header `name`
+    fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| *value)
+    }
+
+    /// Comma separated values of the header `name`, which may be repeated
+    fn tokens(&self, name: &'a str) -> impl Iterator<Item = &'a str> + '_ {
+        self.headers
+            .iter()
+            .filter(move |(header, _)| header == name)
+            .flat_map(|(_, value)| value.split(','))
+            .map(str::trim)
+    }
+
+    /// True if the header `name` holds the case insensitive `token`
+    fn has_token(&self, name: &'a str, token: &str) -> bool {
+        self.tokens(name)
+            .any(|value| value.eq_ignore_ascii_case(token))
+    }
+}
+
+/// Check the client upgrade request, returns its key, or the http status of
+/// the refusal with its reason
+fn check_upgrade_request(head: &str) -> std::result::Result<String, (&'static str, String)> {
+    let bad_request = |reason: &str| ("400 Bad Request", reason.to_owned());
+    let head = HttpHead::parse(head).map_err(|err| bad_request(&err.to_string()))?;
+    let mut first_line = head.first_line.split(' ');
+    if first_line.next() != Some("GET") {
+        return Err(("405 Method Not Allowed", "Not a GET request".to_owned()));
+    }
+    if first_line.nth(1) != Some("HTTP/1.1") {
+        return Err(bad_request("Not a http/1.1 request"));
+    }
+    if !head.has_token("upgrade", "websocket") || !head.has_token("connection", "upgrade") {
+        return Err(bad_request("Not a websocket upgrade"));
+    }
+    if head.header("sec-websocket-version") != Some(WEBSOCKET_VERSION) {
+        return Err((
+            "426 Upgrade Required",
+            format!(
+                "Unsupported websocket version {:?}",
+                head.header("sec-websocket-version")
+            ),
+        ));
+    }
+    // Base64 of 16 bytes
+    let key = match head.header("sec-websocket-key") {
+        Some(key) if key.len() == 24 => key,
+        _ => return Err(bad_request("Bad websocket key")),
+    };
+    if !head
+        .tokens("sec-websocket-protocol")
+        .any(|protocol| protocol == subprotocol())
+    {
+        let protocols: Vec<&str> = head.tokens("sec-websocket-protocol").collect();
+        return Err(bad_request(&format!(
+            "Unsupported subprotocols {:?}, expected {:?}",
+            protocols,
+            subprotocol()
+        )));
+    }
+    Ok(key.to_owned())
+}
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Side of the connection: the client masks its frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Client,
+    Server,
+}
+
+/// WebSocket connection state
+///
+/// It is used with its stream through a `WebSocketStream`, as the
+/// `rustls::Stream` does with the tls connections.
+pub struct WebSocket {
+    role: Role,
+    /// Payload left in the current data frame
+    remaining: u64,
+    /// Mask of the current data frame, and offset of its next byte
+    mask: Option<([u8; 4], usize)>,
+    /// Set while a fragmented message waits for its continuation frames
+    fragmented: bool,
+    /// Set once a close frame is received
+    closed: bool,
+    /// Set once a close frame is sent
+    close_sent: bool,
+    rng: SystemRandom,
+}
+
+impl WebSocket {
+    fn new(role: Role) -> Self {
+        WebSocket {
+            role,
+            remaining: 0,
+            mask: None,
+            fragmented: false,
+            closed: false,
+            close_sent: false,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// Answer the client upgrade request received on `stream`
+    pub fn accept<S: Read + Write>(stream: &mut S) -> Result<Self> {
+        let head = read_http_head(stream)?;
+        let (status, reason) = match check_upgrade_request(&head) {
+            Ok(key) => {
+                let response = format!(
+                    "HTTP/1.1 101 Switching Protocols\r\n\
+                     Upgrade: websocket\r\n\
+                     Connection: Upgrade\r\n\
+                     Sec-WebSocket-Accept: {}\r\n\
+                     Sec-WebSocket-Protocol: {}\r\n\r\n",
+                    accept_key(&key),
+                    subprotocol()
+                );
+                stream
+                    .write_all(response.as_bytes())
+                    .and_then(|_| stream.flush())
+                    .context("Cannot send websocket handshake")?;
+                return Ok(WebSocket::new(Role::Server));
+            }
+            Err(refusal) => refusal,
+        };
+        let response = format!(
+            "HTTP/1.1 {status}\r\n\
+             Sec-WebSocket-Version: {WEBSOCKET_VERSION}\r\n\
+             Content-Length: 0\r\n\
+             Connection: close\r\n\r\n"
+        );
+        // The refusal is best effort, the reason is reported anyway
+        let _ = stream
+            .write_all(response.as_bytes())
+            .and_then(|_| stream.flush());
+        Err(anyhow!("Websocket handshake refused: {}", reason))
+    }
+
+    /// Upgrade the `stream` connected to `host` to a WebSocket on `path`
+    pub fn connect<S: Read + Write>(stream: &mut S, host: &str, path: &str) -> Result<Self> {
+        let mut nonce = [0u8; 16];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("Cannot generate websocket key"))?;
+        let key = BASE64_STANDARD.encode(nonce);
+        let request = format!(
+            "GET {path} HTTP/1.1\r\n\
+             Host: {host}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: {key}\r\n\
+             Sec-WebSocket-Version: {WEBSOCKET_VERSION}\r\n\
+             Sec-WebSocket-Protocol: {}\r\n\r\n",
+            subprotocol()
+        );
+        stream
+            .write_all(request.as_bytes())
+            .and_then(|_| stream.flush())
+            .context("Cannot send websocket handshake")?;
+
+        let head = read_http_head(stream)?;
+        let head = HttpHead::parse(&head)?;
+        if head.first_line.split(' ').nth(1) != Some("101") {
+            return Err(anyhow!("Websocket upgrade refused: {}", head.first_line));
+        }
+        if head.header("sec-websocket-accept") != Some(accept_key(&key).as_str()) {
+            return Err(anyhow!("Bad websocket accept key"));
+        }
+        if head.header("sec-websocket-protocol") != Some(subprotocol().as_str()) {
+            return Err(anyhow!(
+                "Unsupported server subprotocol {:?}",
+                head.header("sec-websocket-protocol")
+            ));
+        }
+        Ok(WebSocket::new(Role::Client))
+    }
+
+    /// Send a frame, masked on the client side
+    fn write_frame<S: Write>(&self, stream: &mut S, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 14);
+        // Single frame messages
+        frame.push(0x80 | opcode);
+        let mask_bit = match self.role {
+            Role::Client => 0x80,
+            Role::Server => 0,
+        };
+        match payload.len() {
+            len if len < 126 => frame.push(mask_bit | len as u8),
+            len if len <= 0xffff => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        match self.role {
+            Role::Client => {
+                let mut mask = [0u8; 4];
+                self.rng
+                    .fill(&mut mask)
+                    .map_err(|_| invalid_data("Cannot generate websocket mask"))?;
+                frame.extend_from_slice(&mask);
+                frame.extend(
+                    payload
+                        .iter()
+                        .enumerate()
+                        .map(|(index, byte)| byte ^ mask[index % 4]),
+                );
+            }
+            Role::Server => frame.extend_from_slice(payload),
+        }
+        stream.write_all(&frame)
+    }
+
+    /// Read a frame header: its final fragment bit, opcode, payload length and
+    /// mask
+    fn read_frame_header<S: Read>(
+        &self,
+        stream: &mut S,
+    ) -> io::Result<(bool, u8, u64, Option<[u8; 4]>)> {
+        let mut header = [0u8; 2];
+        stream.read_exact(&mut header)?;
+        if header[0] & 0x70 != 0 {
+            return Err(invalid_data("Websocket extensions are not supported"));
+        }
+        let fin = header[0] & 0x80 != 0;
+        let opcode = header[0] & 0x0f;
+        let len = match header[1] & 0x7f {
+            126 => {
+                let mut len = [0u8; 2];
+                stream.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0u8; 8];
+                stream.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            len => len as u64,
+        };
+        if opcode & 0x8 != 0 && (!fin || len > MAX_CONTROL_LEN) {
+            return Err(invalid_data("Bad websocket control frame"));
+        }
+        // Only the client frames are masked
+        let masked = header[1] & 0x80 != 0;
+        let mask = match (masked, self.role) {
+            (true, Role::Server) => {
+                let mut mask = [0u8; 4];
+                stream.read_exact(&mut mask)?;
+                Some(mask)
+            }
+            (false, Role::Client) => None,
+            (false, Role::Server) => return Err(invalid_data("Unmasked websocket client frame")),
+            (true, Role::Client) => return Err(invalid_data("Masked websocket server frame")),
+        };
+        Ok((fin, opcode, len, mask))
+    }
+
+    /// Read the payload of a control frame
+    fn read_control_payload<S: Read>(
+        stream: &mut S,
+        len: u64,
+        mask: Option<[u8; 4]>,
+    ) -> io::Result<Vec<u8>> {
+        let mut payload = vec![0u8; len as usize];
+        stream.read_exact(&mut payload)?;
+        if let Some(mask) = mask {
+            for (index, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[index % 4];
+            }
+        }
+        Ok(payload)
+    }
+
+    /// Read the tunnel bytes of the data frames into `buf`. The control frames
+    /// are handled on the way: pings are answered, and a close frame is
+    /// acknowledged and ends the stream.
+    ///
+    /// The frames are read until one holds tunnel bytes: the call blocks, or
+    /// gives a `WouldBlock` error on a non blocking stream.
+    fn read<S: Read + Write>(&mut self, stream: &mut S, buf: &mut [u8]) -> io::Result<usize> {
+        if self.closed || buf.is_empty() {
+            return Ok(0);
+        }
+        while self.remaining == 0 {
+            let (fin, opcode, len, mask) = self.read_frame_header(stream)?;
+            match opcode {
+                OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    match (opcode, self.fragmented) {
+                        (OPCODE_BINARY, true) => {
+                            return Err(invalid_data("Websocket message in a fragmented message"))
+                        }
+                        (OPCODE_CONTINUATION, false) => {
+                            return Err(invalid_data("Websocket continuation without a message"))
+                        }
+                        _ => {}
+                    }
+                    self.fragmented = !fin;
+                    self.remaining = len;
+                    self.mask = mask.map(|mask| (mask, 0));
+                }
+                OPCODE_PING => {
+                    let payload = Self::read_control_payload(stream, len, mask)?;
+                    self.write_frame(stream, OPCODE_PONG, &payload)?;
+                    stream.flush()?;
+                }
+                OPCODE_PONG => {
+                    Self::read_control_payload(stream, len, mask)?;
+                }
+                OPCODE_CLOSE => {
+                    let payload = Self::read_control_payload(stream, len, mask)?;
+                    self.closed = true;
+                    if !self.close_sent {
+                        // Echo the close status
+                        let status = payload.get(..2).unwrap_or_default();
+                        self.write_frame(stream, OPCODE_CLOSE, status)?;
+                        stream.flush()?;
+                        self.close_sent = true;
+                    }
+                    return Ok(0);
+                }
+                OPCODE_TEXT => return Err(invalid_data("Unexpected websocket text message")),
+                _ => return Err(invalid_data("Unknown websocket opcode")),
+            }
+        }
+        let len = self.remaining.min(buf.len() as u64) as usize;
+        let size = stream.read(&mut buf[..len])?;
+        if size == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if let Some((mask, offset)) = self.mask.as_mut() {
+            for byte in buf[..size].iter_mut() {
+                *byte ^= mask[*offset];
+                *offset = (*offset + 1) % 4;
+            }
+        }
+        self.remaining -= size as u64;
+        Ok(size)
+    }
+
+    /// Send `buf` in a binary message
+    fn write<S: Write>(&mut self, stream: &mut S, buf: &[u8]) -> io::Result<usize> {
+        if self.close_sent {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.write_frame(stream, OPCODE_BINARY, buf)?;
+        Ok(buf.len())
+    }
+
+    /// Send a close frame, for a normal closure
+    pub fn send_close<S: Write>(&mut self, stream: &mut S) -> io::Result<()> {
+        if self.close_sent {
+            return Ok(());
+        }
+        self.write_frame(stream, OPCODE_CLOSE, &CLOSE_NORMAL.to_be_bytes())?;
+        self.close_sent = true;
+        stream.flush()
+    }
+}
+
+/// Read + Write stream of the tunnel bytes, carried by the `websocket`
+/// messages on `stream`
+pub struct WebSocketStream<'a, S: Read + Write> {
+    websocket: &'a mut WebSocket,
+    stream: S,
+}
+
+impl<'a, S: Read + Write> WebSocketStream<'a, S> {
+    pub fn new(websocket: &'a mut WebSocket, stream: S) -> Self {
+        WebSocketStream { websocket, stream }
+    }
+}
+
+impl<'a, S: Read + Write> Read for WebSocketStream<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.websocket.read(&mut self.stream, buf)
+    }
+}
+
+impl<'a, S: Read + Write> Write for WebSocketStream<'a, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.websocket.write(&mut self.stream, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{proto::Tunnel, tunnel};
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    #[test]
+    fn test_handshake_values() {
+        // RFC 6455 example
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(subprotocol(), "sanzu-0.2");
+
+        let request = |version: &str, protocols: &str| {
+            format!(
+                "GET /tunnel HTTP/1.1\r\nHost: sanzu\r\nUpgrade: websocket\r\n\
+                 Connection: keep-alive, Upgrade\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                 Sec-WebSocket-Version: {version}\r\n\
+                 Sec-WebSocket-Protocol: {protocols}\r\n\r\n"
+            )
+        };
+        assert_eq!(
+            check_upgrade_request(&request("13", "chat, sanzu-0.2")).unwrap(),
+            "dGhlIHNhbXBsZSBub25jZQ=="
+        );
+        assert_eq!(
+            check_upgrade_request(&request("8", "sanzu-0.2"))
+                .unwrap_err()
+                .0,
+            "426 Upgrade Required"
+        );
+        assert_eq!(
+            check_upgrade_request(&request("13", "sanzu-0.1"))
+                .unwrap_err()
+                .0,
+            "400 Bad Request"
+        );
+        assert!(check_upgrade_request("GET / HTTP/1.1\r\nHost: sanzu\r\n\r\n").is_err());
+    }
+
+    /// Server frames read by a client websocket, and the client answers
+    struct Frames {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Frames {
+        /// Unmasked `frames`: their final fragment bit, opcode and payload
+        fn new(frames: &[(bool, u8, &[u8])]) -> Self {
+            let mut input = vec![];
+            for (fin, opcode, payload) in frames {
+                input.push((*fin as u8) << 7 | opcode);
+                input.push(payload.len() as u8);
+                input.extend_from_slice(payload);
+            }
+            Frames {
+                input: io::Cursor::new(input),
+                output: vec![],
+            }
+        }
+    }
+
+    impl Read for Frames {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Frames {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_websocket_fragments() {
+        let read = |frames: &[(bool, u8, &[u8])], len: usize| {
+            let mut frames = Frames::new(frames);
+            let mut websocket = WebSocket::new(Role::Client);
+            let mut buf = vec![0u8; len];
+            WebSocketStream::new(&mut websocket, &mut frames)
+                .read_exact(&mut buf)
+                .map(|_| (buf, frames.output))
+        };
+
+        // Control frames and empty frames are skipped within a single read
+        let mut frames = Frames::new(&[
+            (true, OPCODE_PONG, b""),
+            (true, OPCODE_BINARY, b""),
+            (true, OPCODE_BINARY, b"abc"),
+        ]);
+        let mut websocket = WebSocket::new(Role::Client);
+        let mut buf = [0u8; 8];
+        assert_eq!(websocket.read(&mut frames, &mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"abc");
+
+        // A ping is answered in the middle of a fragmented message
+        let (buf, output) = read(
+            &[
+                (false, OPCODE_BINARY, b"ab"),
+                (true, OPCODE_PING, b"ping"),
+                (false, OPCODE_CONTINUATION, b""),
+                (true, OPCODE_CONTINUATION, b"cd"),
+                (true, OPCODE_BINARY, b"e"),
+            ],
+            5,
+        )
+        .unwrap();
+        assert_eq!(buf, b"abcde");
+        // Masked pong
+        assert_eq!(&output[..2], &[0x80 | OPCODE_PONG, 0x80 | 4]);
+
+        let err = read(&[(true, OPCODE_CONTINUATION, b"ab")], 2).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = read(
+            &[(false, OPCODE_BINARY, b"ab"), (true, OPCODE_BINARY, b"cd")],
+            4,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_websocket_tunnel() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let large = tunnel::Version {
+            version: "x".repeat(100_000),
+        };
+        let expected = large.clone();
+        let server = thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut websocket = WebSocket::accept(&mut sock).unwrap();
+            let mut stream = WebSocketStream::new(&mut websocket, &mut sock);
+            let msg: tunnel::Version = Tunnel::recv(&mut stream).unwrap();
+            assert_eq!(msg.version, VERSION);
+            Tunnel::send(&mut stream, large).unwrap();
+            // The client closes the connection
+            let mut buf = [0u8; 16];
+            assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        });
+
+        let mut sock = TcpStream::connect(addr).unwrap();
+        let mut websocket = WebSocket::connect(&mut sock, "localhost", "/").unwrap();
+        // Pings are answered while reading
+        websocket
+            .write_frame(&mut sock, OPCODE_PING, b"ping")
+            .unwrap();
+        let mut stream = WebSocketStream::new(&mut websocket, &mut sock);
+        let version = tunnel::Version {
+            version: VERSION.to_owned(),
+        };
+        Tunnel::send(&mut stream, version).unwrap();
+        let msg: tunnel::Version = Tunnel::recv(&mut stream).unwrap();
+        assert_eq!(msg, expected);
+        websocket.send_close(&mut sock).unwrap();
+        // The server acknowledges the close
+        let mut buf = [0u8; 16];
+        assert_eq!(
+            WebSocketStream::new(&mut websocket, &mut sock)
+                .read(&mut buf)
+                .unwrap(),
+            0
+        );
+        server.join().unwrap();
+
+        // Plain tcp clients are refused
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            WebSocket::accept(&mut sock).is_err()
+        });
+        let mut sock = TcpStream::connect(addr).unwrap();
+        sock.write_all(b"GET / HTTP/1.1\r\nHost: sanzu\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        sock.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(server.join().unwrap());
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow::{Context, Result};
+use sanzu_common::{callback::spawn_command, tunnel};
+
+use std::path::{Component, Path};
+
+use crate::config::App;
+
+/// Applications the client is allowed to launch
+///
+/// The client only sends an application id: the command line is never
+/// received from the client, and is executed without shell.
+pub struct AppCatalog {
+    apps: Vec<App>,
+}
+
+/// Check an application entry from the configuration
+fn check_app(app: &App) -> Result<()> {
+    if app.name.is_empty() {
+        return Err(anyhow!("Empty application name"));
+    }
+    let path = Path::new(&app.exec);
+    if !path.is_absolute() {
+        return Err(anyhow!("Exec path must be absolute"));
+    }
+    if path
+        .components()
+        .any(|component| component == Component::ParentDir)
+    {
+        return Err(anyhow!("Exec path must not contain '..'"));
+    }
+    let has_nul =
+        app.exec.contains('\0') || app.args.iter().flatten().any(|arg| arg.contains('\0'));
+    if has_nul {
+        return Err(anyhow!("Exec path or arguments contain NUL char"));
+    }
+    Ok(())
+}
+
+impl AppCatalog {
+    pub fn new(apps: &[App]) -> Result<Self> {
+        for (index, app) in apps.iter().enumerate() {
+            check_app(app).context(format!("Bad application {:?}", app.name))?;
+            if apps[..index].iter().any(|other| other.name == app.name) {
+                return Err(anyhow!("Duplicate application {:?}", app.name));
+            }
+        }
+        Ok(AppCatalog {
+            apps: apps.to_vec(),
+        })
+    }
+
+    /// Build the catalog message sent to the client
+    pub fn to_msg(&self) -> tunnel::MessageSrv {
+        let apps = self
+            .apps
+            .iter()
+            .enumerate()
+            .map(|(id, app)| tunnel::AppEntry {
+                id: id as u32,
+                name: app.name.clone(),
+                icon: app.icon.clone().unwrap_or_default(),
+            })
+            .collect();
+        tunnel::MessageSrv {
+            msg: Some(tunnel::message_srv::Msg::AppCatalog(tunnel::AppCatalog {
+                apps,
+            })),
+        }
+    }
+
+    /// Launch the application `id`, on the X `display` if set (else, on the
+    /// one of the environment)
+    pub fn launch(&self, id: u32, display: Option<&str>) -> Result<()> {
+        let app = self
+            .apps
+            .get(id as usize)
+            .ok_or_else(|| anyhow!("Unknown application id {}", id))?;
+        info!("Launch application {:?}", app.name);
+        let envs: Vec<(&str, &str)> = display
+            .map(|display| ("DISPLAY", display))
+            .into_iter()
+            .collect();
+        spawn_command(&app.exec, app.args.as_deref().unwrap_or_default(), &envs)
+            .context(format!("Cannot launch application {:?}", app.name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app(name: &str, exec: &str) -> App {
+        App {
+            name: name.to_owned(),
+            exec: exec.to_owned(),
+            args: None,
+            icon: None,
+        }
+    }
+
+    #[test]
+    fn test_app_catalog_sanitize() {
+        assert!(AppCatalog::new(&[app("relative", "true")]).is_err());
+        assert!(AppCatalog::new(&[app("parent", "/usr/../bin/true")]).is_err());
+        assert!(AppCatalog::new(&[app("", "/bin/true")]).is_err());
+        assert!(AppCatalog::new(&[app("dup", "/bin/true"), app("dup", "/bin/false")]).is_err());
+        let mut bad_arg = app("arg", "/bin/true");
+        bad_arg.args = Some(vec!["a\0b".to_owned()]);
+        assert!(AppCatalog::new(&[bad_arg]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_app_catalog_launch() {
+        let catalog = AppCatalog::new(&[app("true", "/bin/true")]).unwrap();
+        catalog.launch(0, None).unwrap();
+        catalog.launch(0, Some(":1")).unwrap();
+        assert!(catalog.launch(1, None).is_err());
+        assert!(catalog.launch(u32::MAX, None).is_err());
+    }
+}
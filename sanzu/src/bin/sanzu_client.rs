@@ -1,5 +1,5 @@
 #![windows_subsystem = "windows"]
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::CommandFactory;
 
 #[macro_use]
@@ -8,7 +8,7 @@ extern crate log;
 use sanzu::{
     client,
     config::{read_client_config, ConfigClient},
-    utils::{init_logger, is_proto_arg, ClientArgs, ClientArgsConfig},
+    utils::{init_logger, init_max_msg_len, is_proto_arg, ClientArgs, ClientArgsConfig},
 };
 
 use sanzu_common::proto::VERSION;
@@ -36,6 +36,11 @@ fn main() -> Result<()> {
     let matches = ClientArgs::command().get_matches();
     let args_config = matches.get_one::<std::path::PathBuf>("args_config");
 
+    let sets: Vec<String> = matches
+        .get_many::<String>("set")
+        .map(|sets| sets.cloned().collect())
+        .unwrap_or_default();
+
     let mut layers = if let Some(args_config) = args_config {
         vec![Layer::Toml(args_config.into())]
     } else {
@@ -49,6 +54,7 @@ fn main() -> Result<()> {
     let client_config = ClientArgsConfig::with_layers(&layers).unwrap();
 
     init_logger(client_config.verbose);
+    init_max_msg_len(client_config.max_msg_len, client_config.max_frame_msg_len);
 
     if client_config.proto {
         println!("Protocol version: {VERSION}");
@@ -57,12 +63,18 @@ fn main() -> Result<()> {
 
     let conf = match client_config.config {
         Some(ref client_config) => {
-            read_client_config(client_config).expect("Cannot read configuration file")
+            read_client_config(client_config, &sets).expect("Cannot read configuration file")
         }
         None => ConfigClient {
             ffmpeg: HashMap::new(),
+            renderer: None,
+            frame_hold_ms: None,
+            hotkeys: None,
+            tls: None,
+            clipboard: None,
         },
     };
+    conf.validate().context("Bad configuration file")?;
     if let Err(err) = client::run(
         &conf,
         &client_config,
@@ -7,7 +7,7 @@ extern crate log;
 use sanzu::{
     config::read_server_config,
     proxy,
-    utils::{init_logger, is_proto_arg, ProxyArgs, ProxyArgsConfig},
+    utils::{init_logger, init_max_msg_len, is_proto_arg, ProxyArgs, ProxyArgsConfig},
 };
 
 use sanzu_common::proto::VERSION;
@@ -23,6 +23,11 @@ fn main() -> Result<()> {
     let matches = ProxyArgs::command().get_matches();
     let args_config = matches.get_one::<std::path::PathBuf>("args_config");
 
+    let sets: Vec<String> = matches
+        .get_many::<String>("set")
+        .map(|sets| sets.cloned().collect())
+        .unwrap_or_default();
+
     let mut layers = if let Some(args_config) = args_config {
         vec![Layer::Toml(args_config.into())]
     } else {
@@ -36,14 +41,16 @@ fn main() -> Result<()> {
     let proxy_config = ProxyArgsConfig::with_layers(&layers).unwrap();
 
     init_logger(proxy_config.verbose);
+    init_max_msg_len(proxy_config.max_msg_len, proxy_config.max_frame_msg_len);
 
     if proxy_config.proto {
         println!("Protocol version: {VERSION}");
         return Ok(());
     }
 
-    let conf =
-        read_server_config(&proxy_config.config).context("Cannot read configuration file")?;
+    let conf = read_server_config(&proxy_config.config, &sets)
+        .context("Cannot read configuration file")?;
+    conf.validate(false).context("Bad configuration file")?;
     if let Err(err) = proxy::run(&conf, &proxy_config) {
         error!("Proxy error");
         err.chain().for_each(|cause| error!(" - due to {}", cause));
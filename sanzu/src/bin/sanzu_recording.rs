@@ -0,0 +1,89 @@
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use sanzu::recording_crypto::{
+    decrypt_recording, generate_recording_keys, read_recording_key, RECORDING_KEY_LEN,
+};
+
+/// Keys and decryption of the encrypted session recordings
+#[derive(Parser, Debug)]
+#[clap(author, version, about)]
+struct RecordingArgs {
+    #[clap(subcommand)]
+    command: RecordingCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum RecordingCommand {
+    /// Generate a x25519 key pair. The public key is given to the server
+    /// (--record-key), the secret key decrypts the recordings.
+    Keygen {
+        /// Secret key file, created readable by its owner only
+        secret_key: PathBuf,
+        /// Public key file
+        public_key: PathBuf,
+    },
+    /// Decrypt a recording or an events log. A truncated recording (server
+    /// crash) is decrypted up to its last complete chunk, and reported.
+    Decrypt {
+        /// Secret key file
+        #[clap(long, short = 'k')]
+        secret_key: PathBuf,
+        /// Encrypted file
+        input: PathBuf,
+        /// Decrypted file
+        output: PathBuf,
+    },
+}
+
+/// Write `key` to the new file `path`, with the permissions `mode` (unix)
+fn write_key(path: &Path, key: &[u8; RECORDING_KEY_LEN], mode: u32) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    let mut file = options
+        .open(path)
+        .context(format!("Cannot create key file {path:?}"))?;
+    file.write_all(key)
+        .context(format!("Cannot write key file {path:?}"))
+}
+
+fn main() -> Result<()> {
+    let args = RecordingArgs::parse();
+    match args.command {
+        RecordingCommand::Keygen {
+            secret_key,
+            public_key,
+        } => {
+            let (secret, public) = generate_recording_keys()?;
+            write_key(&secret_key, &secret, 0o600)?;
+            write_key(&public_key, &public, 0o644)?;
+        }
+        RecordingCommand::Decrypt {
+            secret_key,
+            input,
+            output,
+        } => {
+            let secret = read_recording_key(&secret_key)?;
+            let reader = BufReader::new(
+                File::open(&input).context(format!("Cannot open recording {input:?}"))?,
+            );
+            let writer =
+                BufWriter::new(File::create(&output).context(format!("Cannot create {output:?}"))?);
+            decrypt_recording(reader, writer, &secret)
+                .context(format!("Cannot decrypt recording {input:?}"))?;
+        }
+    }
+    Ok(())
+}
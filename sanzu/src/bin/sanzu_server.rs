@@ -5,11 +5,13 @@ use clap::CommandFactory;
 extern crate log;
 
 use sanzu::{
-    config::read_server_config,
+    config::{read_server_config, ConfigServer},
     server,
-    utils::{init_logger, is_proto_arg, ServerArgs, ServerArgsConfig},
+    utils::{init_logger, init_max_msg_len, is_proto_arg, ServerArgs, ServerArgsConfig},
 };
 
+#[cfg(unix)]
+use sanzu_common::config_reload::install_sighup_handler;
 use sanzu_common::proto::VERSION;
 
 use twelf::Layer;
@@ -23,6 +25,11 @@ fn main() -> Result<()> {
     let matches = ServerArgs::command().get_matches();
     let args_config = matches.get_one::<std::path::PathBuf>("args_config");
 
+    let sets: Vec<String> = matches
+        .get_many::<String>("set")
+        .map(|sets| sets.cloned().collect())
+        .unwrap_or_default();
+
     let mut layers = if let Some(args_config) = args_config {
         vec![Layer::Toml(args_config.into())]
     } else {
@@ -36,15 +43,30 @@ fn main() -> Result<()> {
     let server_config = ServerArgsConfig::with_layers(&layers).unwrap();
 
     init_logger(server_config.verbose);
+    init_max_msg_len(server_config.max_msg_len, server_config.max_frame_msg_len);
 
     if server_config.proto {
         println!("Protocol version: {VERSION}");
         return Ok(());
     }
 
-    let conf =
-        read_server_config(&server_config.config).context("Cannot read configuration file")?;
-    if let Err(err) = server::run(&conf, &server_config) {
+    #[cfg(unix)]
+    if server_config.list_outputs {
+        return sanzu::server_x11::print_outputs();
+    }
+
+    let load_config = || -> Result<ConfigServer> {
+        let conf = read_server_config(&server_config.config, &sets)
+            .context("Cannot read configuration file")?;
+        conf.validate(server_config.export_video_pci)
+            .context("Bad configuration file")?;
+        Ok(conf)
+    };
+    let conf = load_config()?;
+    // Reload the hot settings of the configuration
+    #[cfg(unix)]
+    install_sighup_handler(false)?;
+    if let Err(err) = server::run(conf, &server_config, &load_config) {
         error!("Server error");
         err.chain().for_each(|cause| error!(" - due to {}", cause));
     }
@@ -1,7 +1,11 @@
 use anyhow::{Context, Result};
 extern crate libc;
-use memmap2::MmapOptions;
-use std::{collections::HashMap, fmt::Write as _, net::TcpStream, time::Instant};
+use memmap2::{Mmap, MmapOptions};
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    time::{Duration, Instant},
+};
 
 use std::{
     convert::TryFrom,
@@ -13,8 +17,11 @@ use std::{
 };
 
 use sanzu_common::{
-    proto::{recv_server_msg_or_error, send_client_err_event, VERSION},
-    tls_helper::make_client_config,
+    heartbeat::{self, Heartbeat},
+    proto::{
+        check_version, recv_server_msg_or_error, send_client_err_event, set_compression, VERSION,
+    },
+    tls_helper::{make_client_config, TlsPolicy},
     tunnel, ReadWrite, Tunnel,
 };
 
@@ -23,20 +30,32 @@ use sanzu_common::auth_kerberos::do_kerberos_server_auth;
 
 use crate::{
     client_graphics::*,
-    client_utils::Area,
+    client_utils::{
+        chunk_clipboard, connect_tcp, is_connection_error, reassemble_clipboard, Area, Client,
+        ConnectProxy, Hotkeys, HudMode, ReconnectBackoff,
+    },
     config::ConfigClient,
-    osd::{draw_text, TestDisplay},
+    osd::{audio_summary, draw_osd_lines, FrameStatsHistory, HudHistory, OsdNotice, TestDisplay},
     //proto::{Tunnel, ReadWrite},
-    sound::SoundDecoder,
+    sound::{OpusSettings, SoundDecoder, SoundEncoder, MICROPHONE_FORMAT},
     utils::{
-        get_xwd_data, set_tcp_timeout, ClientArgsConfig, MAX_BYTES_PER_LINE, MAX_CURSOR_HEIGHT,
-        MAX_CURSOR_WIDTH, MAX_WINDOW_HEIGHT, MAX_WINDOW_WIDTH,
+        blit_region, clipboard_allowed, get_xwd_data, keyboard_layout_mismatch, local_capabilities,
+        negotiated_capabilities, negotiated_chroma, negotiated_color_space,
+        server_clipboard_policy, set_tcp_timeout, ClientArgsConfig, ClipboardAssembler,
+        ClipboardChunker, CursorCache, HasTimeout, DEFAULT_DPI, MAX_BYTES_PER_LINE,
+        MAX_CURSOR_HEIGHT, MAX_CURSOR_WIDTH, MAX_WINDOW_HEIGHT, MAX_WINDOW_WIDTH,
     },
-    video_decoder::init_video_codec,
+    video_decoder::{init_video_codec, DecoderTiles, DecoderTimings, StreamRecovery},
+    video_encoder::Chroma,
 };
 
 #[cfg(target_family = "unix")]
-use crate::utils::HasTimeout;
+use crate::utils::stream_from_fd;
+#[cfg(feature = "printfile")]
+use crate::{client_utils::print_document, print_file::PrintAssembler};
+
+/// Period of the window events polling while waiting for a reconnection
+const RECONNECT_POLL_PERIOD: Duration = Duration::from_millis(50);
 
 struct ShellAttr {
     path: &'static str,
@@ -83,6 +102,23 @@ fn check_img_size(width: u32, height: u32) -> Result<(u32, u32)> {
     }
 }
 
+/// Size of the video and sound payloads of the server messages
+fn msgs_data_len(msgs: &tunnel::MessagesSrv) -> usize {
+    msgs.msgs
+        .iter()
+        .map(|msg| match &msg.msg {
+            Some(tunnel::message_srv::Msg::ImgEncoded(img)) => img.data.len(),
+            Some(tunnel::message_srv::Msg::ImgRaw(img)) => img.data.len(),
+            Some(tunnel::message_srv::Msg::ImgRawRegion(region)) => region.data.len(),
+            Some(tunnel::message_srv::Msg::SoundEncoded(sound)) => {
+                sound.data.iter().map(|pkt| pkt.len()).sum()
+            }
+            Some(tunnel::message_srv::Msg::SoundRaw(sound)) => sound.data.len(),
+            _ => 0,
+        })
+        .sum()
+}
+
 fn check_cusor_size(width: u32, height: u32, xhot: u32, yhot: u32) -> Result<(u32, u32, u32, u32)> {
     if width > MAX_CURSOR_WIDTH
         || height > MAX_CURSOR_HEIGHT
@@ -101,6 +137,39 @@ fn check_cusor_size(width: u32, height: u32, xhot: u32, yhot: u32) -> Result<(u3
     }
 }
 
+/// Apply the server `cursor` to the client window
+fn apply_cursor(client: &mut dyn Client, cursor: &tunnel::EventCursor) -> Result<()> {
+    let (width, height, xhot, yhot) =
+        check_cusor_size(cursor.width, cursor.height, cursor.xhot, cursor.yhot)
+            .context("Cursor size error")?;
+    client
+        .set_cursor(&cursor.data, (width, height), (xhot as u16, yhot as u16))
+        .context("Set cursor error")
+}
+
+/// Draw the `osd_lines` on the rgba frame `data_rgba` and display it
+fn display_frame(
+    client: &mut dyn Client,
+    data_rgba: &mut [u8],
+    (width, height): (u32, u32),
+    osd_lines: &[String],
+) -> Result<()> {
+    if !osd_lines.is_empty() {
+        let mut display = TestDisplay {
+            width,
+            height,
+            buffer: data_rgba,
+        };
+        draw_osd_lines(&mut display, osd_lines);
+    }
+    client
+        .set_img(
+            &data_rgba[0..width as usize * height as usize * 4],
+            (width, height),
+        )
+        .context("Error in set_img")
+}
+
 pub trait ClientInterface {
     fn pam_echo(&mut self, echo: String) -> Result<String>;
 
@@ -188,11 +257,18 @@ pub fn do_run(
     arguments: &ClientArgsConfig,
     client_interface: &mut impl ClientInterface,
 ) -> Result<()> {
+    if !arguments.audio_volume.is_finite() || arguments.audio_volume < 0.0 {
+        return Err(anyhow!(
+            "Bad audio volume {}: must be positive",
+            arguments.audio_volume
+        ));
+    }
     let mut sound_obj = if arguments.audio {
         Some(
             SoundDecoder::new(
                 "default",
                 arguments.audio_sample_rate,
+                None,
                 arguments.audio_buffer_ms,
             )
             .context("Error in new SoundDecoder")?,
@@ -200,101 +276,254 @@ pub fn do_run(
     } else {
         None
     };
+    if let Some(sound_obj) = sound_obj.as_ref() {
+        sound_obj.set_volume(arguments.audio_volume);
+    }
 
-    let (audio, audio_sample_rate) = match &sound_obj {
-        Some(ref sound_obj) => (true, sound_obj.sample_rate),
-        None => (false, 0),
-    };
+    if arguments.stdio && arguments.login {
+        return Err(anyhow!("Login prompt cannot be used with stdio transport"));
+    }
 
-    let connection_timeout = arguments
-        .connection_timeout
-        .map(|timeout| std::time::Duration::from_secs(timeout as u64));
+    if arguments.reconnect_attempts != 0 && (arguments.stdio || arguments.fd.is_some()) {
+        return Err(anyhow!(
+            "Reconnection cannot be used with stdio or file descriptor transport"
+        ));
+    }
 
-    let mut socket: Box<dyn ReadWrite> = match &arguments.proxycommand {
-        None => {
-            #[cfg(unix)]
-            if arguments.vsock {
-                let port = arguments.server_port as u32;
-                let address = arguments
-                    .server_addr
-                    .parse::<u32>()
-                    .expect("Not a vsock address");
-                let server = vsock::VsockStream::connect(&vsock::VsockAddr::new(address, port))
-                    .context(format!(
-                        "Error in vsock server connection {address:?} {port:?}"
-                    ))?;
-                server
-                    .set_connection_timeout(connection_timeout)
-                    .context("Cannot set timeout")?;
-                info!("Connected to server");
-                Box::new(server)
-            } else {
-                let port = arguments.server_port;
-                let destination = format!("{}:{}", arguments.server_addr, port);
-                let server = TcpStream::connect(&destination)
-                    .context(format!("Error in tcp server connection {destination:?}"))?;
+    if arguments.connect_proxy.is_some()
+        && (arguments.stdio || arguments.fd.is_some() || arguments.proxycommand.is_some())
+    {
+        return Err(anyhow!(
+            "Connect proxy cannot be used with stdio, file descriptor or proxycommand transport"
+        ));
+    }
+    #[cfg(unix)]
+    if arguments.connect_proxy.is_some() && arguments.vsock {
+        return Err(anyhow!("Connect proxy cannot be used with vsock transport"));
+    }
 
-                let socket_ref = socket2::SockRef::from(&server);
-                set_tcp_timeout(socket_ref, connection_timeout).context("Cannot set keepalive")?;
+    let hotkeys =
+        Hotkeys::new(client_config.hotkeys.as_ref()).context("Error in hotkeys configuration")?;
+    let tls_policy = match &client_config.tls {
+        Some(config_tls) => config_tls.policy().context("Error in tls configuration")?,
+        None => TlsPolicy::default(),
+    };
 
-                info!("Connected to server");
-                server.set_nodelay(true).expect("set_nodelay call failed");
-                Box::new(server)
-            }
-            #[cfg(windows)]
-            {
-                let port = arguments.server_port;
-                let destination = format!("{}:{}", arguments.server_addr, port);
-                let server = TcpStream::connect(&destination)
-                    .context(format!("Error in tcp server connection {destination:?}"))?;
-                let socket_ref = socket2::SockRef::from(&server);
-                set_tcp_timeout(socket_ref, connection_timeout).context("Cannot set keepalive")?;
-                info!("Connected to server");
-                server.set_nodelay(true).expect("set_nodelay call failed");
-                Box::new(server)
-            }
+    // The window is kept across reconnections
+    let mut client = None;
+    let mut backoff = ReconnectBackoff::new(
+        Duration::from_millis(arguments.reconnect_delay_ms),
+        Duration::from_millis(arguments.reconnect_max_delay_ms),
+        arguments.reconnect_attempts,
+    );
+    loop {
+        let mut established = false;
+        let err = match run_session(
+            client_config,
+            arguments,
+            client_interface,
+            &mut client,
+            &mut sound_obj,
+            &extern_img_source,
+            &hotkeys,
+            &tls_policy,
+            &mut established,
+        ) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        if arguments.reconnect_attempts == 0 || !is_connection_error(&err) {
+            return Err(err);
         }
-        Some(commandline) => {
-            /* Launch proxy command*/
-            let mut child = Command::new(SHELL_ATTR.path)
-                .arg(SHELL_ATTR.attr)
-                .arg(commandline)
-                .stdout(Stdio::piped())
-                .stdin(Stdio::piped())
-                .spawn()
-                .context("Error in launch proxycommand")?;
-            info!("Proxycommand {:?}", child);
-
-            let pipe_child_in = child.stdin.take().context("Error in get stdin")?;
-            let pipe_child_out = child.stdout.take().context("Error in get stdout")?;
-            let stream = StreamPipes {
-                pipe_in: pipe_child_out,
-                pipe_out: pipe_child_in,
-            };
+        if established {
+            backoff.reset();
+        } else if backoff.attempt() == 0 {
+            // The first connection never succeeded
+            return Err(err);
+        }
+        let delay = match backoff.next_delay() {
+            Some(delay) => delay,
+            None => return Err(err.context("No more reconnection attempts")),
+        };
+        warn!(
+            "Connection lost, reconnection attempt {} in {:?}",
+            backoff.attempt(),
+            delay
+        );
+        err.chain().for_each(|cause| warn!(" - due to {}", cause));
+        wait_reconnect(&mut client, delay, backoff.attempt())?;
+    }
+}
 
-            thread::spawn(move || {
-                debug!("Wait proxycommand");
-                child.wait().expect("Error in wait proxycommand");
-                debug!("End proxycommand");
-            });
-            Box::new(stream)
+/// Keep the window responsive, with a reconnection notice, until the next
+/// connection attempt. Events generated meanwhile are dropped.
+fn wait_reconnect(
+    client: &mut Option<Box<dyn Client>>,
+    delay: Duration,
+    attempt: u32,
+) -> Result<()> {
+    let deadline = Instant::now() + delay;
+    if let Some(client) = client.as_mut() {
+        let (width, height) = client.size();
+        let mut data = [0x20, 0x20, 0x20, 0xff].repeat(width as usize * height as usize);
+        let mut display = TestDisplay {
+            width: width as u32,
+            height: height as u32,
+            buffer: &mut data,
+        };
+        draw_osd_lines(
+            &mut display,
+            &[format!(
+                "Connection lost, reconnecting (attempt {attempt})..."
+            )],
+        );
+        client
+            .set_img(&data, (width as u32, height as u32))
+            .context("Error in set_img")?;
+    }
+    loop {
+        if let Some(client) = client.as_mut() {
+            client.poll_events().context("Error in poll_events")?;
         }
-    };
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(());
+        }
+        thread::sleep((deadline - now).min(RECONNECT_POLL_PERIOD));
+    }
+}
 
-    let extern_img_source = match arguments.extern_img_source.as_deref() {
-        Some(extern_img_source) => {
-            let file = fs::File::open(extern_img_source)
-                .context(format!("Error in open shared mem {extern_img_source:?}"))?;
-            unsafe {
-                Some(
-                    MmapOptions::new()
-                        .map(&file)
-                        .context("Error in map shared mem")?,
-                )
+/// Connect to the server and run a session until it ends
+///
+/// The client window is created during the first session and reused by the
+/// next ones. `established` is set once the session handshake is done.
+#[allow(clippy::too_many_arguments)]
+fn run_session(
+    client_config: &ConfigClient,
+    arguments: &ClientArgsConfig,
+    client_interface: &mut impl ClientInterface,
+    client: &mut Option<Box<dyn Client>>,
+    sound_obj: &mut Option<SoundDecoder>,
+    extern_img_source: &Option<Mmap>,
+    hotkeys: &Hotkeys,
+    tls_policy: &TlsPolicy,
+    established: &mut bool,
+) -> Result<()> {
+    let connection_timeout = arguments
+        .connection_timeout
+        .map(|timeout| std::time::Duration::from_secs(timeout as u64));
+    let connect_proxy = arguments
+        .connect_proxy
+        .as_deref()
+        .map(|proxy| proxy.parse::<ConnectProxy>())
+        .transpose()
+        .context("Error in connect proxy")?;
+
+    // Handle on the transport socket, used to bound the blocking reads once the
+    // session is established
+    let mut timeout_handle: Option<Box<dyn HasTimeout>> = None;
+    let mut socket: Box<dyn ReadWrite> =
+        match (&arguments.proxycommand, arguments.stdio, arguments.fd) {
+            (None, true, None) => {
+                info!("Use stdio transport");
+                Box::new(sanzu_common::Stdio {})
             }
-        }
-        None => None,
-    };
+            (None, false, Some(fd)) => {
+                #[cfg(unix)]
+                {
+                    info!("Use file descriptor {} transport", fd);
+                    Box::new(stream_from_fd(fd)?)
+                }
+                #[cfg(windows)]
+                {
+                    return Err(anyhow!(
+                        "File descriptor {} is not supported on windows",
+                        fd
+                    ));
+                }
+            }
+            (None, false, None) => {
+                #[cfg(unix)]
+                if arguments.vsock {
+                    let port = arguments.server_port as u32;
+                    let address = arguments
+                        .server_addr
+                        .parse::<u32>()
+                        .expect("Not a vsock address");
+                    let server = vsock::VsockStream::connect(&vsock::VsockAddr::new(address, port))
+                        .context(format!(
+                            "Error in vsock server connection {address:?} {port:?}"
+                        ))?;
+                    server
+                        .set_connection_timeout(connection_timeout)
+                        .context("Cannot set timeout")?;
+                    info!("Connected to server");
+                    timeout_handle =
+                        Some(Box::new(server.try_clone().context("Cannot clone socket")?));
+                    Box::new(server)
+                } else {
+                    let server = connect_tcp(
+                        &arguments.server_addr,
+                        arguments.server_port,
+                        connect_proxy.as_ref(),
+                    )?;
+
+                    let socket_ref = socket2::SockRef::from(&server);
+                    set_tcp_timeout(socket_ref, connection_timeout)
+                        .context("Cannot set keepalive")?;
+
+                    info!("Connected to server");
+                    server.set_nodelay(true).expect("set_nodelay call failed");
+                    timeout_handle =
+                        Some(Box::new(server.try_clone().context("Cannot clone socket")?));
+                    Box::new(server)
+                }
+                #[cfg(windows)]
+                {
+                    let server = connect_tcp(
+                        &arguments.server_addr,
+                        arguments.server_port,
+                        connect_proxy.as_ref(),
+                    )?;
+                    let socket_ref = socket2::SockRef::from(&server);
+                    set_tcp_timeout(socket_ref, connection_timeout)
+                        .context("Cannot set keepalive")?;
+                    info!("Connected to server");
+                    server.set_nodelay(true).expect("set_nodelay call failed");
+                    timeout_handle =
+                        Some(Box::new(server.try_clone().context("Cannot clone socket")?));
+                    Box::new(server)
+                }
+            }
+            (Some(commandline), false, None) => {
+                /* Launch proxy command*/
+                let mut child = Command::new(SHELL_ATTR.path)
+                    .arg(SHELL_ATTR.attr)
+                    .arg(commandline)
+                    .stdout(Stdio::piped())
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .context("Error in launch proxycommand")?;
+                info!("Proxycommand {:?}", child);
+
+                let pipe_child_in = child.stdin.take().context("Error in get stdin")?;
+                let pipe_child_out = child.stdout.take().context("Error in get stdout")?;
+                let stream = StreamPipes {
+                    pipe_in: pipe_child_out,
+                    pipe_out: pipe_child_in,
+                };
+
+                thread::spawn(move || {
+                    debug!("Wait proxycommand");
+                    child.wait().expect("Error in wait proxycommand");
+                    debug!("End proxycommand");
+                });
+                Box::new(stream)
+            }
+            _ => {
+                return Err(anyhow!("proxycommand / stdio / fd arguments error"));
+            }
+        };
 
     debug!("Connected");
 
@@ -309,6 +538,7 @@ pub fn do_run(
             arguments.tls_ca.as_deref(),
             arguments.client_cert.as_deref(),
             arguments.client_key.as_deref(),
+            tls_policy,
         )
         .context("Error in make client tls config")?;
         conn = rustls::ClientConnection::new(config, server_name)
@@ -319,6 +549,9 @@ pub fn do_run(
         &mut socket
     };
 
+    // Compression is only enabled once negotiated with this server
+    set_compression(None);
+
     // Send client version
     let client_version = tunnel::Version {
         version: VERSION.to_owned(),
@@ -330,13 +563,7 @@ pub fn do_run(
         recv_server_msg_type!(server, Version).context("Error in send server version")?;
 
     info!("Server version {:?}", server_version);
-    if server_version.version != VERSION {
-        return Err(anyhow!(
-            "Version mismatch server: {:?} client: {:?}",
-            server_version.version,
-            VERSION,
-        ));
-    }
+    check_version(&server_version.version).context("Incompatible server")?;
 
     #[cfg(feature = "kerberos")]
     if let Some(cname) = &arguments.server_cname {
@@ -347,6 +574,20 @@ pub fn do_run(
     #[cfg(not(feature = "kerberos"))]
     debug!("Skipping kerberos auth");
 
+    if let Some(token_file) = &arguments.oidc_token_file {
+        if arguments.tls_server_name.is_none() {
+            // Not on stdout, which may be the stdio transport
+            warn!("No tls, token will be sent in clear text");
+        }
+        // Read at each connection, to get the refreshed token
+        let token = std::fs::read_to_string(token_file)
+            .context(format!("Cannot read oidc token {token_file:?}"))?;
+        let token = tunnel::EventOidcToken {
+            token: token.trim().to_owned(),
+        };
+        send_client_msg_type!(server, token, Oidctoken).context("Error in send EventOidcToken")?;
+    }
+
     if arguments.login {
         if arguments.tls_server_name.is_none() {
             println!("WARNING: no tls, password will be sent in clear text");
@@ -390,6 +631,38 @@ pub fn do_run(
         }
     }
 
+    // Optional features are only used if both peers support them
+    let capabilities = tunnel::Capabilities {
+        audio: sound_obj.is_some(),
+        clipboard_image: arguments.max_clipboard_image != 0,
+        relative_mouse: true,
+        compression: arguments.compression_level.is_some(),
+        touch: cfg!(unix),
+        pen: cfg!(unix),
+        view_only: arguments.view_only,
+        // A requested chroma is kept for the whole session
+        adaptive_chroma: arguments.chroma.is_none(),
+        ..local_capabilities()
+    };
+    send_client_msg_type!(server, capabilities.clone(), Capabilities)
+        .context("Error in send Capabilities")?;
+    let server_capabilities: tunnel::Capabilities =
+        recv_server_msg_type!(server, Capabilities).context("Error in recv Capabilities")?;
+    let capabilities = negotiated_capabilities(&capabilities, &server_capabilities);
+    info!("Negotiated capabilities {:?}", capabilities);
+    if capabilities.view_only {
+        info!("View only session: the input is dropped by the server");
+    }
+    set_compression(
+        arguments
+            .compression_level
+            .filter(|_| capabilities.compression),
+    );
+    let max_clipboard_image = match capabilities.clipboard_image {
+        true => arguments.max_clipboard_image,
+        false => 0,
+    };
+
     /* Receive image info & codec name */
     let msg = recv_server_msg_type!(server, Hello).context("Error in recv ServerHello")?;
 
@@ -398,6 +671,24 @@ pub fn do_run(
         Some(decoder_name) => decoder_name.to_owned(),
         None => msg.codec_name.to_owned(),
     };
+    // Unknown color spaces are decoded as the default one, which is
+    // announced back so that the server uses it too
+    let (yuv_type, color_range) = negotiated_color_space(msg.color_space(), msg.color_range());
+    let color_space = tunnel::ColorSpace::from(yuv_type) as i32;
+    let color_range_msg = tunnel::ColorRange::from(color_range) as i32;
+    // The server proposal is kept unless the user asked for a chroma
+    let mut chroma = match arguments.chroma.as_deref() {
+        Some(chroma) => Some(chroma.parse::<Chroma>().context("Bad chroma argument")?),
+        None => negotiated_chroma(msg.chroma()),
+    };
+    let chroma_msg = chroma.map_or(tunnel::Chroma::Unspecified, tunnel::Chroma::from) as i32;
+    // Each peer applies its own clipboard policy, the user is shown what goes
+    // through
+    let clipboard_policy = client_config
+        .clipboard_policy()
+        .intersect(&server_clipboard_policy(&msg));
+    info!("Clipboard policy: {}", clipboard_policy);
+    let keyboard_layout = msg.keyboard_layout.clone();
     let (seamless, server_size) = match msg.msg {
         Some(tunnel::server_hello::Msg::AdaptScreen(adapt_screen)) => (adapt_screen.seamless, None),
         Some(tunnel::server_hello::Msg::Fullscreen(msg)) => {
@@ -407,15 +698,91 @@ pub fn do_run(
             panic!("Unknown Server hello");
         }
     };
+    // In seamless, the pointer cannot be confined to a single window
+    let relative_mouse = msg.relative_mouse && capabilities.relative_mouse && !seamless;
+    // Both audio codecs are decoded
+    let audio_codec = msg.audio_codec();
+    let microphone_accepted = msg.microphone;
+
+    // Play the sound in the format requested by the server
+    if let (Some(requested), Some(current)) = (msg.audio_format.as_ref(), sound_obj.as_ref()) {
+        let sample_rate = match requested.sample_rate {
+            0 => arguments.audio_sample_rate,
+            sample_rate => Some(sample_rate),
+        };
+        let channels = match requested.channels {
+            0 => None,
+            channels => Some(channels as u16),
+        };
+        let audio_buffer_ms = match requested.buffer_ms {
+            0 => arguments.audio_buffer_ms,
+            buffer_ms => buffer_ms,
+        };
+        if sample_rate.is_some_and(|rate| rate != current.format.sample_rate)
+            || channels.is_some_and(|channels| channels != current.format.channels)
+            || audio_buffer_ms != current.audio_buffer_ms
+        {
+            info!(
+                "Server audio format: {:?} Hz {:?} channels, buffer {} ms",
+                sample_rate, channels, audio_buffer_ms
+            );
+            let new_sound_obj =
+                SoundDecoder::new("default", sample_rate, channels, audio_buffer_ms)
+                    .context("Error in new SoundDecoder")
+                    .map_err(|err| send_client_err_event(server, err))?;
+            new_sound_obj.set_volume(arguments.audio_volume);
+            *sound_obj = Some(new_sound_obj);
+        }
+    }
+    let (audio, audio_sample_rate, audio_channels) =
+        match sound_obj.as_ref().filter(|_| capabilities.audio) {
+            Some(sound_obj) => (
+                true,
+                sound_obj.format.sample_rate,
+                sound_obj.format.channels as u32,
+            ),
+            None => (false, 0, 0),
+        };
 
-    #[cfg(unix)]
-    let mut client = init_x11rb(arguments, seamless, server_size)
+    if client.is_none() {
+        #[cfg(unix)]
+        let new_client = init_x11rb(
+            arguments,
+            seamless,
+            relative_mouse,
+            capabilities.touch,
+            capabilities.pen,
+            server_size,
+            hotkeys.clone(),
+            client_config.clipboard_policy(),
+        )
         .context("Error in init_x11rb")
         .map_err(|err| send_client_err_event(server, err))?;
-    #[cfg(windows)]
-    let mut client = init_wind3d(arguments, seamless, server_size)
+        #[cfg(windows)]
+        let new_client = init_wind3d(
+            arguments,
+            seamless,
+            relative_mouse,
+            server_size,
+            client_config.renderer.unwrap_or_default(),
+            client_config.frame_hold_ms.map(Duration::from_millis),
+            hotkeys.clone(),
+            client_config.clipboard_policy(),
+        )
         .context("Error in init_wind3d")
         .map_err(|err| send_client_err_event(server, err))?;
+        *client = Some(new_client);
+    }
+    let client = client.as_mut().context("No client window")?;
+
+    let dpi = client.dpi();
+    if dpi != 0 {
+        info!(
+            "Display dpi {} (scale {:.2})",
+            dpi,
+            dpi as f32 / DEFAULT_DPI as f32
+        );
+    }
 
     /* Send hello with audio bool */
     let (mut img_width, mut img_height) = match server_size {
@@ -423,6 +790,12 @@ pub fn do_run(
             let client_hello = tunnel::ClientHelloFullscreen {
                 audio,
                 audio_sample_rate,
+                color_space,
+                color_range: color_range_msg,
+                audio_codec: audio_codec as i32,
+                audio_channels,
+                dpi,
+                chroma: chroma_msg,
             };
             send_client_msg_type!(server, client_hello, Clienthellofullscreen)
                 .context("Error in send ClientHelloFullscreen")?;
@@ -437,6 +810,12 @@ pub fn do_run(
                 audio_sample_rate,
                 width: width_even,
                 height: height_event,
+                color_space,
+                color_range: color_range_msg,
+                audio_codec: audio_codec as i32,
+                audio_channels,
+                dpi,
+                chroma: chroma_msg,
             };
             send_client_msg_type!(server, client_hello, Clienthelloresolution)
                 .context("Error in send ClientHelloResolution")?;
@@ -444,25 +823,183 @@ pub fn do_run(
         }
     };
 
-    let mut decoder =
-        init_video_codec(client_config.ffmpeg_options(Some(&codec_name)), &codec_name)
-            .context("Cannot init video decoder")
-            .map_err(|err| send_client_err_event(server, err))?;
-
-    if let Some(ref mut sound_obj) = sound_obj {
+    let mut decoder = init_video_codec(
+        client_config.ffmpeg_options(Some(&codec_name)),
+        &codec_name,
+        yuv_type,
+        color_range,
+    )
+    .context("Cannot init video decoder")
+    .map_err(|err| send_client_err_event(server, err))?;
+    // Decoders of the frames split in tiles by the server
+    let mut tiles_decoder = DecoderTiles::default();
+
+    if let Some(sound_obj) = sound_obj.as_mut() {
         sound_obj
             .start()
             .context("Error in sound start")
             .map_err(|err| send_client_err_event(server, err))?;
     }
 
+    // A missing microphone does not prevent the session
+    let mut microphone = match (arguments.microphone, microphone_accepted) {
+        (true, true) => match SoundEncoder::new(
+            "default",
+            Some(OpusSettings::default()),
+            MICROPHONE_FORMAT,
+            arguments.audio_buffer_ms as u64,
+        )
+        .and_then(|mut encoder| encoder.start().map(|_| encoder))
+        {
+            Ok(encoder) => Some(encoder),
+            Err(err) => {
+                error!("Cannot open microphone, microphone disabled");
+                err.chain().for_each(|cause| error!(" - due to {}", cause));
+                None
+            }
+        },
+        (true, false) => {
+            warn!("Microphone not accepted by the server");
+            None
+        }
+        _ => None,
+    };
+
     let mut stats = "".to_owned();
     let mut img_bytes_per_line = None;
+    let mut launch_app = None;
+    // Ask for a keyframe on (re)connection, so the display doesn't wait for
+    // the next GOP boundary
+    let mut request_keyframe = true;
+    let mut link_stats = None;
+    let mut frame_stats = FrameStatsHistory::default();
+    let mut osd_notice = OsdNotice::default();
+    let mut notices = vec![];
+    if clipboard_policy.is_restricted() {
+        notices.push(format!("Clipboard policy: {}", clipboard_policy));
+    }
+    // The keys are sent as keycodes: they are typed with the server layout
+    let client_layout = client.keyboard_layout();
+    info!("Client keyboard layout: {:?}", client_layout);
+    if let Some(warning) = keyboard_layout_mismatch(client_layout.as_deref(), &keyboard_layout) {
+        warn!("{}", warning);
+        notices.push(warning);
+    }
+    if !notices.is_empty() {
+        osd_notice.set(notices.join(" - "), Instant::now());
+    }
+    let mut hud_history = HudHistory::default();
+    let mut heartbeat = Heartbeat::new(
+        Instant::now(),
+        Duration::from_millis(arguments.heartbeat_interval_ms),
+        arguments.heartbeat_missed,
+    );
+    // Don't wait forever for a silent server
+    if let (Some(handle), None) = (timeout_handle.as_ref(), connection_timeout) {
+        handle
+            .set_connection_timeout(Some(heartbeat.dead_timeout()))
+            .context("Cannot set heartbeat timeout")?;
+    }
+    let mut pong_msg = None;
+    let mut stream_recovery = StreamRecovery::default();
+    let mut clipboard_chunker = ClipboardChunker::default();
+    let mut clipboard_assembler = ClipboardAssembler::default();
+    #[cfg(feature = "printfile")]
+    let mut print_assembler = PrintAssembler::default();
+    let mut cursor_cache = CursorCache::default();
+    let mut audio_muted = false;
+    let mut chroma_toggled = false;
+    // Areas are kept until the server deletes them
+    let mut areas = HashMap::new();
+    *established = true;
     loop {
-        let mut areas = HashMap::new();
         let time_start = Instant::now();
 
-        let msgs = client.poll_events().context("Error in poll_events")?;
+        let mut msgs = client.poll_events().context("Error in poll_events")?;
+        msgs.msgs.retain(|msg| match &msg.msg {
+            Some(tunnel::message_client::Msg::Clipboard(event)) => {
+                clipboard_allowed(event, max_clipboard_image)
+            }
+            _ => true,
+        });
+        msgs.msgs = chunk_clipboard(&mut clipboard_chunker, std::mem::take(&mut msgs.msgs));
+        if let Some(id) = launch_app.take() {
+            msgs.msgs.push(tunnel::MessageClient {
+                msg: Some(tunnel::message_client::Msg::LaunchApp(tunnel::LaunchApp {
+                    id,
+                })),
+            });
+        }
+
+        if let Some(link_stats) = link_stats.take() {
+            msgs.msgs.push(tunnel::MessageClient {
+                msg: Some(tunnel::message_client::Msg::LinkStats(link_stats)),
+            });
+        }
+        if let Some(microphone) = microphone.as_mut() {
+            microphone.read_sound();
+            for event in microphone.recv_events() {
+                if let Some(tunnel::message_srv::Msg::SoundEncoded(sound)) = event.msg {
+                    msgs.msgs.push(tunnel::MessageClient {
+                        msg: Some(tunnel::message_client::Msg::Microphone(sound)),
+                    });
+                }
+            }
+        }
+        if sound_obj.is_some() && client.audio_muted() != audio_muted {
+            audio_muted = client.audio_muted();
+            info!("Audio muted: {}", audio_muted);
+            msgs.msgs.push(tunnel::MessageClient {
+                msg: Some(tunnel::message_client::Msg::AudioControl(
+                    tunnel::EventAudioControl { mute: audio_muted },
+                )),
+            });
+            if let Some(sound_obj) = sound_obj.as_ref() {
+                sound_obj.set_muted(audio_muted);
+            }
+            let text = if audio_muted {
+                "Audio muted"
+            } else {
+                "Audio unmuted"
+            };
+            osd_notice.set(text.to_owned(), time_start);
+        }
+        if client.chroma_toggled() != chroma_toggled {
+            chroma_toggled = client.chroma_toggled();
+            // Without a negotiated chroma, the encoder default is 4:2:0
+            let requested = chroma.map_or(Chroma::Yuv444, Chroma::toggled);
+            info!("Request chroma {}", requested);
+            chroma = Some(requested);
+            msgs.msgs.push(tunnel::MessageClient {
+                msg: Some(tunnel::message_client::Msg::RequestChroma(
+                    tunnel::RequestChroma {
+                        chroma: tunnel::Chroma::from(requested) as i32,
+                    },
+                )),
+            });
+        }
+        let hud_mode = client.hud_mode();
+        if let Some(ping) = heartbeat.ping(time_start) {
+            msgs.msgs.push(tunnel::MessageClient {
+                msg: Some(tunnel::message_client::Msg::Ping(ping)),
+            });
+        }
+        if let Some(pong) = pong_msg.take() {
+            msgs.msgs.push(tunnel::MessageClient {
+                msg: Some(tunnel::message_client::Msg::Pong(pong)),
+            });
+        }
+        if stream_recovery.need_keyframe(time_start) {
+            info!("Stream broken, request a keyframe");
+            request_keyframe = true;
+        }
+        if std::mem::take(&mut request_keyframe) {
+            msgs.msgs.push(tunnel::MessageClient {
+                msg: Some(tunnel::message_client::Msg::RequestKeyframe(
+                    tunnel::RequestKeyframe {},
+                )),
+            });
+        }
 
         let time_events = Instant::now();
 
@@ -476,18 +1013,36 @@ pub fn do_run(
 
         let time_recv = Instant::now();
 
+        // Report the link usage, used by the server to adapt the bitrate
+        let recv_time_ms = (time_recv - time_send).as_millis();
+        let recv_len = msgs_data_len(&msg);
+        link_stats = Some(tunnel::EventLinkStats {
+            throughput_kbps: (recv_len as u128 * 8 / recv_time_ms.max(1)) as u32,
+            recv_time_ms: recv_time_ms as u32,
+        });
+
         let mut img_todo = None;
+        let mut tiles_todo = vec![];
+        let mut tiles_size = None;
+        let mut regions_todo = vec![];
 
-        for msg in msg.msgs {
+        for msg in reassemble_clipboard(&mut clipboard_assembler, msg.msgs) {
             match msg.msg {
                 Some(tunnel::message_srv::Msg::ImgEncoded(img)) => {
                     let (width, height) = check_img_size(img.width, img.height)
                         .map_err(|err| send_client_err_event(server, err))?;
-                    img_todo = Some((img.data, width, height));
+                    stream_recovery.frame_received(img.sequence);
+                    match img.tile {
+                        Some(tile) => {
+                            tiles_size = Some((width, height, tile.count));
+                            tiles_todo.push((tile, img.data));
+                        }
+                        None => img_todo = Some((img.data, width, height)),
+                    }
                 }
                 Some(tunnel::message_srv::Msg::ImgRaw(img)) => {
-                    let (data, width, height, bytes_per_line) = match &extern_img_source {
-                        Some(ref video_shared_mem) => match arguments.source_is_xwd {
+                    let (data, width, height, bytes_per_line) = match extern_img_source {
+                        Some(video_shared_mem) => match arguments.source_is_xwd {
                             true => {
                                 let (data, _xwd_width, _xwd_height, bytes_per_line) =
                                     get_xwd_data(video_shared_mem)?;
@@ -510,35 +1065,59 @@ pub fn do_run(
                     }
                     img_bytes_per_line = Some(bytes_per_line as u16);
                 }
+                Some(tunnel::message_srv::Msg::ImgRawRegion(region)) => {
+                    regions_todo.push(region);
+                }
                 Some(tunnel::message_srv::Msg::SoundEncoded(sound)) => {
-                    if let Some(ref mut sound_obj) = sound_obj {
+                    if let Some(sound_obj) = sound_obj.as_mut() {
                         for pkt in sound.data {
                             sound_obj.push(pkt);
                         }
                     }
                 }
+                Some(tunnel::message_srv::Msg::SoundRaw(sound)) => {
+                    if let Some(sound_obj) = sound_obj.as_mut() {
+                        sound_obj.push_raw(sound.data);
+                    }
+                }
                 Some(tunnel::message_srv::Msg::Clipboard(clipboard)) => {
                     info!("Clipboard retrieved from server");
-                    if client.set_clipboard(&clipboard.data).is_err() {
-                        error!("Cannot set clipboard");
+                    if let (true, Some(content)) = (
+                        clipboard_allowed(&clipboard, max_clipboard_image),
+                        &clipboard.content,
+                    ) {
+                        if client.set_clipboard(content).is_err() {
+                            error!("Cannot set clipboard");
+                        }
                     }
                 }
                 Some(tunnel::message_srv::Msg::Cursor(cursor)) => {
-                    if let Err(err) =
-                        check_cusor_size(cursor.width, cursor.height, cursor.xhot, cursor.yhot)
-                            .map_err(|err| err.context("Cursor size error"))
-                            .map(|(width, height, xhot, yhot)| {
-                                client.set_cursor(
-                                    &cursor.data,
-                                    (width, height),
-                                    (xhot as u16, yhot as u16),
-                                )
-                            })
-                            .map_err(|err| err.context("Set cursor error"))
-                    {
+                    if let Err(err) = apply_cursor(client.as_mut(), &cursor) {
                         error!("Updt cursor error");
                         err.chain().for_each(|cause| error!(" - due to {}", cause));
                     }
+                    if cursor.id != 0 {
+                        cursor_cache.insert(cursor.id, cursor);
+                    }
+                }
+                Some(tunnel::message_srv::Msg::CursorRef(cursor_ref)) => {
+                    match cursor_cache.get(cursor_ref.id) {
+                        Some(cursor) => {
+                            if let Err(err) = apply_cursor(client.as_mut(), cursor) {
+                                error!("Updt cursor error");
+                                err.chain().for_each(|cause| error!(" - due to {}", cause));
+                            }
+                        }
+                        None => warn!("Unknown cursor {:x}", cursor_ref.id),
+                    }
+                }
+                Some(tunnel::message_srv::Msg::AreaUpdt(area_updt))
+                    if area_updt.width == 0 || area_updt.height == 0 =>
+                {
+                    // Empty areas cannot be displayed, drop the previous
+                    // geometry of the window
+                    trace!("empty area: {:?}", area_updt);
+                    areas.remove(&(area_updt.id as usize));
                 }
                 Some(tunnel::message_srv::Msg::AreaUpdt(area_updt)) => {
                     trace!("new updt: {:?}", area_updt);
@@ -549,19 +1128,56 @@ pub fn do_run(
                         mapped: area_updt.mapped,
                         is_app: area_updt.is_app,
                         name: area_updt.name.clone(),
+                        stacking: area_updt.stacking,
+                        topmost: area_updt.topmost,
                     };
                     areas.insert(area_updt.id as usize, area);
                 }
+                Some(tunnel::message_srv::Msg::WinIcon(icon)) => {
+                    trace!("icon: {} {}x{}", icon.id, icon.width, icon.height);
+                    if let Err(err) = client
+                        .set_area_icon(icon.id as usize, (icon.width, icon.height), &icon.data)
+                        .context("Error in set_area_icon")
+                    {
+                        err.chain().for_each(|cause| error!(" - due to {}", cause));
+                    }
+                }
+                Some(tunnel::message_srv::Msg::WindowList(window_list)) => {
+                    debug!("window list: {:?}", window_list);
+                    if let Err(err) = client
+                        .set_window_list(&window_list.windows)
+                        .context("Error in set_window_list")
+                    {
+                        err.chain().for_each(|cause| error!(" - due to {}", cause));
+                    }
+                }
+                Some(tunnel::message_srv::Msg::DeleteArea(delete_area)) => {
+                    trace!("delete area: {:?}", delete_area);
+                    areas.remove(&(delete_area.id as usize));
+                }
                 Some(tunnel::message_srv::Msg::Printfile(printfile)) => {
-                    trace!("printfile: {:?}", printfile);
+                    trace!(
+                        "printfile: {} {}/{}",
+                        printfile.id,
+                        printfile.seq,
+                        printfile.total
+                    );
                     #[cfg(feature = "printfile")]
-                    {
-                        info!("printfile: {:?}", printfile);
-                        if let Err(err) =
-                            client.printfile(&printfile.path).context("Error in print")
-                        {
-                            err.chain().for_each(|cause| error!(" - due to {}", cause));
+                    match print_assembler.push(printfile) {
+                        Ok(Some(document)) => {
+                            info!("printfile: {:?}", document.path);
+                            if let Err(err) = print_document(
+                                &**client,
+                                arguments.allow_print.as_deref(),
+                                &document,
+                            )
+                            .context("Error in print")
+                            {
+                                err.chain().for_each(|cause| error!(" - due to {}", cause));
+                            }
                         }
+                        Ok(None) => {}
+                        Err(err) => warn!("Print chunk dropped: {}", err),
                     }
                 }
                 Some(tunnel::message_srv::Msg::Notifications(notifications)) => {
@@ -616,10 +1232,63 @@ pub fn do_run(
                     trace!("server stats: {:?}", stats);
                     stats = msg_stats.stats
                 }
+                Some(tunnel::message_srv::Msg::FrameStats(msg_frame_stats)) => {
+                    frame_stats.push(msg_frame_stats, time_recv);
+                }
+                Some(tunnel::message_srv::Msg::Pong(pong)) => {
+                    heartbeat.pong(&pong, time_recv);
+                }
+                Some(tunnel::message_srv::Msg::Ping(ping)) => {
+                    pong_msg = Some(heartbeat::pong(&ping));
+                }
+                Some(tunnel::message_srv::Msg::OsdNotice(notice)) => {
+                    info!("Server notice: {}", notice.text);
+                    osd_notice.set(notice.text, time_recv);
+                }
+                Some(tunnel::message_srv::Msg::Chroma(event)) => {
+                    // Toggled from the chroma chosen by the server
+                    chroma = negotiated_chroma(event.chroma());
+                    info!("Server switched chroma to {:?}", chroma);
+                }
+                Some(tunnel::message_srv::Msg::AppCatalog(catalog)) => {
+                    info!("Server applications: {:?}", catalog.apps);
+                    if let Some(ref name) = arguments.launch_app {
+                        match catalog.apps.iter().find(|app| &app.name == name) {
+                            Some(app) => launch_app = Some(app.id),
+                            None => error!("Application {:?} not in server catalog", name),
+                        }
+                    }
+                }
                 _ => {}
             };
         }
+        heartbeat.check().context("Server is not responding")?;
 
+        let mut osd_lines = vec![];
+        if let Some(text) = osd_notice.text(time_recv) {
+            osd_lines.push(text.to_owned());
+        }
+        if audio_muted {
+            osd_lines.push("audio muted".to_owned());
+        }
+        match hud_mode {
+            HudMode::Off => {}
+            HudMode::Compact => {
+                osd_lines.push(hud_history.summary(heartbeat.rtt(), time_recv));
+            }
+            HudMode::Detailed => {
+                osd_lines.push(hud_history.summary(heartbeat.rtt(), time_recv));
+                osd_lines.push(stats.replace('µ', "u"));
+                osd_lines.push(frame_stats.summary(time_recv));
+                if let Some(sound_obj) = sound_obj.as_ref() {
+                    osd_lines.push(audio_summary(
+                        audio_codec,
+                        sound_obj.buffered(),
+                        heartbeat.rtt(),
+                    ));
+                }
+            }
+        }
         let time_decode_msgs = Instant::now();
         let mut time_decode = None;
 
@@ -631,30 +1300,60 @@ pub fn do_run(
                 ))?;
                 img_width = new_img_width as u16;
                 img_height = new_img_height as u16;
+                request_keyframe = true;
                 info!("New codec ok");
             }
 
-            if let (Some(_img_updated), Some(mut timings)) =
-                decoder.decode_img(&img_data, img_width, img_height, img_bytes_per_line)
-            {
+            let decoded = decoder.decode_img(&img_data, img_width, img_height, img_bytes_per_line);
+            if decoder.take_decode_error() {
+                stream_recovery.decode_error();
+            }
+            if let (Some(_img_updated), Some(mut timings)) = decoded {
                 let time_start = Instant::now();
                 if let Some(data_rgba) = decoder.data_rgba().as_mut() {
-                    if client.display_stats() {
-                        let mut display = TestDisplay {
-                            width: img_width as u32,
-                            height: img_height as u32,
-                            buffer: data_rgba,
-                        };
-                        let stats = stats.replace('µ', "u");
-                        draw_text(&mut display, &stats, 0, img_height as i32 - 50);
-                    }
+                    display_frame(
+                        client.as_mut(),
+                        data_rgba,
+                        (img_width as u32, img_height as u32),
+                        &osd_lines,
+                    )?;
+                }
+                let time_set_img = Instant::now();
+                timings.times.push(("set", time_set_img - time_start));
+                time_decode = Some(timings);
+            }
+        }
 
-                    client
-                        .set_img(
-                            &data_rgba[0..img_width as usize * img_height as usize * 4],
-                            (img_width as u32, img_height as u32),
-                        )
-                        .context("Error in set_img")?;
+        if let Some((width, height, count)) = tiles_size {
+            if img_width != width as u16 || img_height != height as u16 {
+                info!("New resolution {}x{}", width, height);
+                // Keep the frame decoder in sync if the server stops tiling
+                decoder = decoder
+                    .reload()
+                    .context(format!("Cannot reload decode with size {width}x{height}"))?;
+                img_width = width as u16;
+                img_height = height as u16;
+            }
+            if tiles_decoder
+                .set_layout(decoder.as_ref(), count, img_width, img_height)
+                .map_err(|err| send_client_err_event(server, err))?
+            {
+                info!("New tiles layout: {} tiles", count);
+                request_keyframe = true;
+            }
+            let decoded = tiles_decoder.decode_tiles(&tiles_todo);
+            if tiles_decoder.take_decode_error() {
+                stream_recovery.decode_error();
+            }
+            if let (Some(_img_updated), Some(mut timings)) = decoded {
+                let time_start = Instant::now();
+                if let Some(data_rgba) = tiles_decoder.data_rgba().as_mut() {
+                    display_frame(
+                        client.as_mut(),
+                        data_rgba,
+                        (img_width as u32, img_height as u32),
+                        &osd_lines,
+                    )?;
                 }
                 let time_set_img = Instant::now();
                 timings.times.push(("set", time_set_img - time_start));
@@ -662,10 +1361,43 @@ pub fn do_run(
             }
         }
 
+        if !regions_todo.is_empty() {
+            let time_start = Instant::now();
+            // Regions are composited on the last raw frame
+            if let Some(data_rgba) = decoder.data_rgba().as_mut() {
+                for region in regions_todo.iter() {
+                    if let Err(err) =
+                        blit_region(data_rgba, img_width as u32, img_height as u32, region)
+                    {
+                        error!("Cannot blit region");
+                        err.chain().for_each(|cause| error!(" - due to {}", cause));
+                    }
+                }
+                display_frame(
+                    client.as_mut(),
+                    data_rgba,
+                    (img_width as u32, img_height as u32),
+                    &osd_lines,
+                )?;
+                let time_set_img = Instant::now();
+                time_decode = Some(DecoderTimings {
+                    times: vec![("region", time_set_img - time_start)],
+                });
+            }
+        }
+
         client.update(&areas).context("Error in update")?;
 
         let time_stop = Instant::now();
 
+        hud_history.push(
+            recv_len,
+            time_decode
+                .as_ref()
+                .map(|timings| timings.times.iter().map(|(_, time)| *time).sum()),
+            time_recv,
+        );
+
         let mut timings_str = String::new();
         let times_img = if let Some(timings) = time_decode {
             for timing in timings.times {
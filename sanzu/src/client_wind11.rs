@@ -0,0 +1,303 @@
+use crate::{
+    client_utils::ScaleMode,
+    client_wind3d::{client_size, scale_mode, FrameRenderer},
+};
+use anyhow::{Context, Result};
+
+use std::ptr::null_mut;
+
+use winapi::{
+    ctypes::c_void,
+    shared::{
+        dxgi::{IDXGISwapChain, DXGI_SWAP_CHAIN_DESC, DXGI_SWAP_EFFECT_FLIP_SEQUENTIAL},
+        dxgiformat::{DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_UNKNOWN},
+        dxgitype::DXGI_USAGE_RENDER_TARGET_OUTPUT,
+        minwindef::TRUE,
+        windef::HWND,
+        winerror::SUCCEEDED,
+    },
+    um::{
+        d3d11::{
+            D3D11CreateDeviceAndSwapChain, ID3D11Device, ID3D11DeviceContext,
+            ID3D11RenderTargetView, ID3D11Resource, ID3D11Texture2D, D3D11_BIND_SHADER_RESOURCE,
+            D3D11_BOX, D3D11_CPU_ACCESS_WRITE, D3D11_CREATE_DEVICE_BGRA_SUPPORT,
+            D3D11_MAPPED_SUBRESOURCE, D3D11_MAP_WRITE_DISCARD, D3D11_SDK_VERSION,
+            D3D11_TEXTURE2D_DESC, D3D11_USAGE_DYNAMIC,
+        },
+        d3dcommon::{D3D_DRIVER_TYPE_HARDWARE, D3D_FEATURE_LEVEL_10_0, D3D_FEATURE_LEVEL_11_0},
+        unknwnbase::IUnknown,
+    },
+    Interface,
+};
+
+// Wrap a COM object to be sure we call Release on object deallocation
+struct ComObject<T: Interface> {
+    inner: *mut T,
+}
+
+impl<T: Interface> ComObject<T> {
+    fn new(inner: *mut T) -> Result<Self> {
+        if inner.is_null() {
+            return Err(anyhow!("Null {}", std::any::type_name::<T>()));
+        }
+        Ok(ComObject { inner })
+    }
+
+    fn get_inner(&self) -> *mut T {
+        self.inner
+    }
+
+    /// # Safety
+    ///
+    /// The wrapped pointer is not null and lives as long as self
+    unsafe fn get_ref(&self) -> &T {
+        &*self.inner
+    }
+}
+
+impl<T: Interface> Drop for ComObject<T> {
+    fn drop(&mut self) {
+        unsafe { (*(self.inner as *mut IUnknown)).Release() };
+    }
+}
+
+/// Frame texture and its size
+struct FrameTexture {
+    texture: ComObject<ID3D11Texture2D>,
+    width: u32,
+    height: u32,
+}
+
+/**
+Direct3D 11 renderer: frames are uploaded to a dynamic texture, which is
+copied to the swap chain back buffer.
+The fields' drop order is important here:
+The texture and swap chain must be freed before the device
+'The fields of a struct are dropped in declaration order.'
+**/
+pub struct SanzuDirect3D11 {
+    hwnd: HWND,
+    back_size: (u32, u32),
+    texture: Option<FrameTexture>,
+    swap_chain: ComObject<IDXGISwapChain>,
+    context: ComObject<ID3D11DeviceContext>,
+    device: ComObject<ID3D11Device>,
+}
+
+/// # Safety
+///
+/// Initialise Direct3D 11 by calling unsafe Windows API
+pub unsafe fn init_d3d11(hwnd: HWND) -> Result<SanzuDirect3D11> {
+    // The back buffer follows the window size
+    let mut desc = DXGI_SWAP_CHAIN_DESC::default();
+    desc.BufferDesc.Format = DXGI_FORMAT_B8G8R8A8_UNORM;
+    desc.SampleDesc.Count = 1;
+    desc.BufferUsage = DXGI_USAGE_RENDER_TARGET_OUTPUT;
+    desc.BufferCount = 2;
+    desc.OutputWindow = hwnd;
+    desc.Windowed = TRUE;
+    desc.SwapEffect = DXGI_SWAP_EFFECT_FLIP_SEQUENTIAL;
+
+    let feature_levels = [D3D_FEATURE_LEVEL_11_0, D3D_FEATURE_LEVEL_10_0];
+    let mut p_swap_chain: *mut IDXGISwapChain = null_mut();
+    let mut p_device: *mut ID3D11Device = null_mut();
+    let mut p_context: *mut ID3D11DeviceContext = null_mut();
+    let ret = D3D11CreateDeviceAndSwapChain(
+        null_mut(),
+        D3D_DRIVER_TYPE_HARDWARE,
+        null_mut(),
+        D3D11_CREATE_DEVICE_BGRA_SUPPORT,
+        feature_levels.as_ptr(),
+        feature_levels.len() as u32,
+        D3D11_SDK_VERSION,
+        &desc,
+        &mut p_swap_chain,
+        &mut p_device,
+        null_mut(),
+        &mut p_context,
+    );
+    if !SUCCEEDED(ret) {
+        return Err(anyhow!("Cannot create d3d11 device {:#X}", ret));
+    }
+
+    Ok(SanzuDirect3D11 {
+        hwnd,
+        back_size: client_size(hwnd),
+        texture: None,
+        swap_chain: ComObject::new(p_swap_chain)?,
+        context: ComObject::new(p_context)?,
+        device: ComObject::new(p_device)?,
+    })
+}
+
+/// Returns the back buffer size and the frame position in it, so that the
+/// presentation, which stretches the back buffer to the window, draws the
+/// frame following the scale mode. Returns None for an empty window.
+fn back_buffer_layout(
+    scale_mode: ScaleMode,
+    frame: (u32, u32),
+    window: (u32, u32),
+) -> Option<((u32, u32), (u32, u32))> {
+    let (x, y, width, height) = scale_mode.dest_rect(frame, window);
+    if width == 0 || height == 0 || window.0 == 0 || window.1 == 0 {
+        return None;
+    }
+    // Window pixels to back buffer pixels
+    let scale = |value: u32, frame_len: u32, rect_len: u32| {
+        (value as u64 * frame_len as u64 / rect_len as u64) as u32
+    };
+    Some((
+        (
+            scale(window.0, frame.0, width).max(1),
+            scale(window.1, frame.1, height).max(1),
+        ),
+        (scale(x, frame.0, width), scale(y, frame.1, height)),
+    ))
+}
+
+impl SanzuDirect3D11 {
+    /// Return the frame texture, (re)created if the frame size changed
+    unsafe fn frame_texture(&mut self, width: u32, height: u32) -> Result<*mut ID3D11Resource> {
+        if !matches!(&self.texture, Some(texture) if (texture.width, texture.height) == (width, height))
+        {
+            self.texture = None;
+
+            let mut desc = D3D11_TEXTURE2D_DESC {
+                Width: width,
+                Height: height,
+                MipLevels: 1,
+                ArraySize: 1,
+                Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+                Usage: D3D11_USAGE_DYNAMIC,
+                BindFlags: D3D11_BIND_SHADER_RESOURCE,
+                CPUAccessFlags: D3D11_CPU_ACCESS_WRITE,
+                ..Default::default()
+            };
+            desc.SampleDesc.Count = 1;
+
+            let mut p_texture: *mut ID3D11Texture2D = null_mut();
+            let ret = self
+                .device
+                .get_ref()
+                .CreateTexture2D(&desc, null_mut(), &mut p_texture);
+            if !SUCCEEDED(ret) {
+                return Err(anyhow!("Error in CreateTexture2D {:#X}", ret));
+            }
+            self.texture = Some(FrameTexture {
+                texture: ComObject::new(p_texture)?,
+                width,
+                height,
+            });
+        }
+        let texture = self.texture.as_ref().context("No frame texture")?;
+        Ok(texture.texture.get_inner() as *mut ID3D11Resource)
+    }
+
+    /// Render a frame to the Direct3D 11 context
+    /// # Safety
+    ///
+    /// `data` holds `height` rows of `width` bgra pixels
+    unsafe fn render_frame(&mut self, data: &[u8], width: u32, height: u32) -> Result<()> {
+        let pixel_w_size = width as usize * 4;
+        if data.len() < pixel_w_size * height as usize {
+            return Err(anyhow!("Frame too short"));
+        }
+        let ((back_width, back_height), (dst_x, dst_y)) =
+            match back_buffer_layout(scale_mode(), (width, height), client_size(self.hwnd)) {
+                Some(layout) => layout,
+                // Minimized window
+                None => return Ok(()),
+            };
+        if (back_width, back_height) != self.back_size {
+            let ret = self.swap_chain.get_ref().ResizeBuffers(
+                0,
+                back_width,
+                back_height,
+                DXGI_FORMAT_UNKNOWN,
+                0,
+            );
+            if !SUCCEEDED(ret) {
+                return Err(anyhow!("Error in ResizeBuffers {:#X}", ret));
+            }
+            self.back_size = (back_width, back_height);
+        }
+
+        let texture = self.frame_texture(width, height)?;
+        let context = self.context.get_ref();
+
+        let mut mapped = D3D11_MAPPED_SUBRESOURCE::default();
+        let ret = context.Map(texture, 0, D3D11_MAP_WRITE_DISCARD, 0, &mut mapped);
+        if !SUCCEEDED(ret) {
+            return Err(anyhow!("Error in Map {:#X}", ret));
+        }
+        if mapped.pData.is_null() {
+            context.Unmap(texture, 0);
+            return Err(anyhow!("Error: pData is null"));
+        }
+        for (i, row) in data
+            .chunks_exact(pixel_w_size)
+            .take(height as usize)
+            .enumerate()
+        {
+            let p_dest = (mapped.pData as *mut u8).add(i * mapped.RowPitch as usize);
+            std::ptr::copy_nonoverlapping(row.as_ptr(), p_dest, pixel_w_size);
+        }
+        context.Unmap(texture, 0);
+
+        let mut p_back_buffer: *mut ID3D11Texture2D = null_mut();
+        let ret = self.swap_chain.get_ref().GetBuffer(
+            0,
+            &ID3D11Texture2D::uuidof(),
+            &mut p_back_buffer as *mut _ as *mut *mut c_void,
+        );
+        if !SUCCEEDED(ret) {
+            return Err(anyhow!("Error in GetBuffer {:#X}", ret));
+        }
+        let back_buffer = ComObject::new(p_back_buffer)?;
+        let mut back_desc = D3D11_TEXTURE2D_DESC::default();
+        back_buffer.get_ref().GetDesc(&mut back_desc);
+
+        let mut p_view: *mut ID3D11RenderTargetView = null_mut();
+        let ret = self.device.get_ref().CreateRenderTargetView(
+            back_buffer.get_inner() as *mut ID3D11Resource,
+            null_mut(),
+            &mut p_view,
+        );
+        if !SUCCEEDED(ret) {
+            return Err(anyhow!("Error in CreateRenderTargetView {:#X}", ret));
+        }
+        let view = ComObject::new(p_view)?;
+        context.ClearRenderTargetView(view.get_inner(), &[0.0, 0.0, 0.0, 1.0]);
+
+        let src_box = D3D11_BOX {
+            left: 0,
+            top: 0,
+            front: 0,
+            right: width.min(back_desc.Width.saturating_sub(dst_x)),
+            bottom: height.min(back_desc.Height.saturating_sub(dst_y)),
+            back: 1,
+        };
+        context.CopySubresourceRegion(
+            back_buffer.get_inner() as *mut ID3D11Resource,
+            0,
+            dst_x,
+            dst_y,
+            0,
+            texture,
+            0,
+            &src_box,
+        );
+
+        let ret = self.swap_chain.get_ref().Present(1, 0);
+        if !SUCCEEDED(ret) {
+            return Err(anyhow!("Error in Present {:#X}", ret));
+        }
+        Ok(())
+    }
+}
+
+impl FrameRenderer for SanzuDirect3D11 {
+    fn render(&mut self, data: &[u8], width: u32, height: u32) -> Result<()> {
+        unsafe { self.render_frame(data, width, height) }
+    }
+}
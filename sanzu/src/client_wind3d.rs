@@ -1,25 +1,28 @@
 use crate::{
-    client_utils::{Area, Client},
-    utils::{ClientArgsConfig, ClipboardConfig},
+    client_utils::{stacking_order, Area, Client, HotkeyAction, Hotkeys, HudMode, ScaleMode},
+    client_wind11::init_d3d11,
+    config::{ClipboardPolicy, Renderer},
+    osd::FrameHold,
+    utils::{ClientArgsConfig, ClipboardConfig, ClipboardContent},
     utils_win,
 };
 use anyhow::{Context, Result};
 
-use clipboard_win::{formats, get_clipboard, set_clipboard};
 use lock_keys::LockKeyWrapper;
 use std::{
     cmp::Ordering,
     collections::{HashMap, HashSet},
     ffi::CString,
     iter::FromIterator,
+    path::Path,
     ptr::null_mut,
     sync::{
         atomic,
-        mpsc::{channel, sync_channel, Receiver, Sender, SyncSender},
+        mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender},
         Mutex,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use sanzu_common::tunnel;
@@ -34,31 +37,42 @@ use winapi::{
         d3d9types::{
             D3DBACKBUFFER_TYPE_MONO, D3DCLEAR_TARGET, D3DCOLOR_XRGB, D3DDEVTYPE_HAL,
             D3DFMT_UNKNOWN, D3DFMT_X8R8G8B8, D3DLOCKED_RECT, D3DPOOL_DEFAULT,
-            D3DPRESENT_PARAMETERS, D3DSWAPEFFECT_DISCARD, D3DTEXF_NONE,
+            D3DPRESENT_PARAMETERS, D3DSURFACE_DESC, D3DSWAPEFFECT_DISCARD, D3DTEXF_LINEAR,
+            D3DTEXF_NONE,
         },
         minwindef::{DWORD, LPARAM, LRESULT, TRUE, UINT, WPARAM},
-        windef::{HHOOK__, HWND, HWND__, POINT, RECT},
+        windef::{
+            DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE, HHOOK__, HICON, HWND, HWND__, POINT, RECT,
+        },
+        winerror::S_OK,
     },
     um::{
         libloaderapi::{GetModuleHandleA, GetProcAddress, LoadLibraryA},
         processthreadsapi::ExitProcess,
         shellapi::ShellExecuteA,
+        shellscalingapi::{GetDpiForMonitor, MDT_EFFECTIVE_DPI},
         wingdi::{CombineRgn, ExtCreateRegion},
         wingdi::{
             CreateBitmap, CreateCompatibleBitmap, DeleteObject, RDH_RECTANGLES, RGNDATA,
             RGNDATAHEADER, RGN_OR,
         },
         winuser::{
-            CallNextHookEx, CreateIconIndirect, CreateWindowExA, DefWindowProcA, DestroyIcon,
-            DestroyWindow, DispatchMessageA, GetCursorPos, GetDC, GetSystemMetrics, LoadImageA,
-            PeekMessageA, PostQuitMessage, RegisterClassExA, ReleaseDC, SendMessageA,
-            SetClipboardViewer, SetCursor, SetFocus, SetWindowRgn, SetWindowsHookExA,
-            TranslateMessage, UnhookWindowsHookEx, HC_ACTION, ICONINFO, ICON_BIG, IMAGE_ICON,
-            LPKBDLLHOOKSTRUCT, LR_DEFAULTSIZE, LR_LOADFROMFILE, MSG, PM_REMOVE, SM_CXSCREEN,
-            SM_CYSCREEN, SW_HIDE, WH_KEYBOARD_LL, WH_MOUSE_LL, WM_ACTIVATE, WM_CHANGECBCHAIN,
-            WM_CLOSE, WM_DESTROY, WM_DISPLAYCHANGE, WM_DRAWCLIPBOARD, WM_KEYDOWN, WM_KILLFOCUS,
-            WM_LBUTTONDOWN, WM_LBUTTONUP, WM_MBUTTONDOWN, WM_MBUTTONUP, WM_MOUSEMOVE,
-            WM_MOUSEWHEEL, WM_QUIT, WM_RBUTTONDOWN, WM_RBUTTONUP, WM_SETFOCUS, WM_SETICON, WM_SIZE,
+            CallNextHookEx, ClientToScreen, ClipCursor, CreateIconIndirect, CreateWindowExA,
+            DefWindowProcA, DestroyIcon, DestroyWindow, DispatchMessageA, GetClientRect,
+            GetCursorPos, GetDC, GetSystemMetrics, GetWindowLongPtrA, LoadImageA,
+            MonitorFromWindow, PeekMessageA, PostQuitMessage, RegisterClassExA, ReleaseDC,
+            SendMessageA, SetClipboardViewer, SetCursor, SetCursorPos, SetFocus,
+            SetThreadDpiAwarenessContext, SetWindowLongPtrA, SetWindowPos, SetWindowRgn,
+            SetWindowsHookExA, ShowWindow, TranslateMessage, UnhookWindowsHookEx, GWL_STYLE,
+            HC_ACTION, HWND_NOTOPMOST, HWND_TOP, HWND_TOPMOST, ICONINFO, ICON_BIG, ICON_SMALL,
+            IMAGE_ICON, LPKBDLLHOOKSTRUCT, LR_DEFAULTSIZE, LR_LOADFROMFILE,
+            MONITOR_DEFAULTTONEAREST, MSG, PM_REMOVE, SC_CLOSE, SC_MAXIMIZE, SC_MINIMIZE,
+            SC_RESTORE, SM_CXSCREEN, SM_CYSCREEN, SWP_FRAMECHANGED, SWP_NOACTIVATE, SWP_NOMOVE,
+            SWP_NOSIZE, SWP_NOZORDER, SW_HIDE, SW_MAXIMIZE, SW_RESTORE, WH_KEYBOARD_LL,
+            WH_MOUSE_LL, WM_ACTIVATE, WM_CHANGECBCHAIN, WM_CLOSE, WM_DESTROY, WM_DISPLAYCHANGE,
+            WM_DRAWCLIPBOARD, WM_KEYDOWN, WM_KILLFOCUS, WM_LBUTTONDOWN, WM_LBUTTONUP,
+            WM_MBUTTONDOWN, WM_MBUTTONUP, WM_MOUSEHWHEEL, WM_MOUSEMOVE, WM_MOUSEWHEEL, WM_QUIT,
+            WM_RBUTTONDOWN, WM_RBUTTONUP, WM_SETFOCUS, WM_SETICON, WM_SIZE, WM_SYSCOMMAND,
             WM_SYSKEYDOWN, WM_USER, WNDCLASSEXA, WS_CLIPCHILDREN, WS_CLIPSIBLINGS, WS_DLGFRAME,
             WS_MAXIMIZE, WS_OVERLAPPEDWINDOW, WS_POPUP, WS_VISIBLE,
         },
@@ -78,8 +92,10 @@ lazy_static! {
     static ref CURSOR_RECEIVER: Mutex<Option<CursorReceiver>> = Mutex::new(None);
     static ref SHAPE_RECEIVER: Mutex<Option<Receiver<Vec<Area>>>> = Mutex::new(None);
     static ref KEYS_STATE: Mutex<Vec<bool>> = Mutex::new(vec![false; 0x100]);
-    static ref DISPLAY_STATS: atomic::AtomicBool = atomic::AtomicBool::new(false);
+    static ref HUD_MODE: Mutex<HudMode> = Mutex::new(HudMode::Off);
     static ref CLIPBOARD_TRIG: atomic::AtomicBool = atomic::AtomicBool::new(false);
+    static ref AUDIO_MUTED: atomic::AtomicBool = atomic::AtomicBool::new(false);
+    static ref CHROMA_TOGGLED: atomic::AtomicBool = atomic::AtomicBool::new(false);
     static ref WINDOW_RECEIVER: Mutex<Option<Receiver<AreaManager>>> = Mutex::new(None);
     static ref WINDOW_SENDER: Mutex<Option<Sender<AreaManager>>> = Mutex::new(None);
     static ref MSG_SENDER: Mutex<Option<Sender<u64>>> = Mutex::new(None);
@@ -87,18 +103,13 @@ lazy_static! {
     static ref SYNC_KEY_LOCKS_NEEDED: Mutex<bool> = Mutex::new(false);
     static ref GRAB_KEYBOARD: Mutex<bool> = Mutex::new(false);
     static ref GRAB_KEYBOARD_ID: atomic::AtomicPtr<HHOOK__> = atomic::AtomicPtr::new(null_mut());
+    static ref RELATIVE_MOUSE_ALLOWED: atomic::AtomicBool = atomic::AtomicBool::new(false);
+    static ref RELATIVE_MOUSE: atomic::AtomicBool = atomic::AtomicBool::new(false);
+    static ref SCALING_ALLOWED: atomic::AtomicBool = atomic::AtomicBool::new(false);
+    static ref SCALE_MODE: Mutex<ScaleMode> = Mutex::new(ScaleMode::Native);
+    static ref HOTKEYS: Mutex<Hotkeys> = Mutex::new(Hotkeys::default());
 }
 
-/// Windows keycodes come from raw usb hid keycodes,
-/// then transformed to xkb keycodes
-/// xkbprint -color -kc :0 - | ps2pdf - > xkbprint.pdf
-const KEY_CTRL: usize = 37;
-const KEY_SHIFT: usize = 50;
-const KEY_ALT: usize = 64;
-const KEY_S: usize = 39;
-const KEY_C: usize = 54;
-const KEY_H: usize = 43;
-
 const WM_UPDATE_FRAME: UINT = WM_USER + 1;
 const WM_WTSSESSION_CHANGE: DWORD = 0x2B1;
 //const WTS_SESSION_LOCK: DWORD = 0x7;
@@ -114,6 +125,10 @@ const SANZU_CLASS: &str = "Sanzu-class";
 enum AreaManager {
     CreateArea(usize, String),
     DeleteArea(usize),
+    /// Area id, width, height, bgra data
+    SetIcon(usize, u32, u32, Vec<u8>),
+    /// Area ids with their topmost flag, from the bottom to the top
+    Restack(Vec<(usize, bool)>),
 }
 
 fn key_state_to_bool(state: lock_keys::LockKeyState) -> bool {
@@ -151,7 +166,8 @@ pub fn set_region_clipping(hwnd: HWND, zones: &[Area]) -> Result<()> {
     trace!("areas:");
     for area in zones {
         trace!("region {:?}", area);
-        if !area.mapped {
+        // Empty rectangles cannot be added to the region
+        if !area.mapped || area.size.0 == 0 || area.size.1 == 0 {
             continue;
         }
         let rect_bound = RECT {
@@ -235,8 +251,9 @@ pub struct ClientWindows {
     pub width: u16,
     pub height: u16,
     pub clipboard_config: ClipboardConfig,
-    pub clipboard_last_value: Option<String>,
-    pub printdir: Option<String>,
+    /// Allowed clipboard directions
+    pub clipboard_policy: ClipboardPolicy,
+    pub clipboard_last_value: Option<ClipboardContent>,
     /// Sync caps/num/scroll lock
     pub sync_key_locks: bool,
 }
@@ -245,7 +262,7 @@ impl ClientWindows {
     pub fn build(
         server_size: Option<(u16, u16)>,
         clipboard_config: ClipboardConfig,
-        printdir: Option<String>,
+        clipboard_policy: ClipboardPolicy,
         sync_key_locks: bool,
         window_mode: bool,
     ) -> (
@@ -293,8 +310,8 @@ impl ClientWindows {
             width,
             height,
             clipboard_config,
+            clipboard_policy,
             clipboard_last_value: None,
-            printdir,
             sync_key_locks,
         };
 
@@ -420,6 +437,34 @@ impl SanzuDirect3D {
     }
 }
 
+/// Draws the received frames in the main window
+pub trait FrameRenderer {
+    /// Render a bgra frame of `width` x `height` pixels
+    fn render(&mut self, data: &[u8], width: u32, height: u32) -> Result<()>;
+}
+
+impl FrameRenderer for SanzuDirect3D {
+    fn render(&mut self, data: &[u8], width: u32, height: u32) -> Result<()> {
+        unsafe { render(self, data, width, height) }
+    }
+}
+
+/// Initialise the configured rendering backend for frames of `width` x
+/// `height` pixels
+fn init_renderer(
+    renderer: Renderer,
+    hwnd: HWND,
+    width: u32,
+    height: u32,
+) -> Result<Box<dyn FrameRenderer>> {
+    *SCREEN_SIZE.lock().unwrap() = (width, height);
+    let frame_renderer: Box<dyn FrameRenderer> = match renderer {
+        Renderer::D3d9 => Box::new(unsafe { init_d3d9(hwnd, width, height) }?),
+        Renderer::D3d11 => Box::new(unsafe { init_d3d11(hwnd) }?),
+    };
+    Ok(frame_renderer)
+}
+
 /// # Safety
 ///
 /// Initialise Direct3D by calling unsafe Windows API
@@ -431,8 +476,6 @@ unsafe fn init_d3d9(hwnd: HWND, width: u32, height: u32) -> Result<SanzuDirect3D
 
     let mut direct3d = Direct3D::new(p_direct3d);
 
-    *SCREEN_SIZE.lock().unwrap() = (width, height);
-
     let mut d3dpp = D3DPRESENT_PARAMETERS {
         BackBufferWidth: 0,
         BackBufferHeight: 0,
@@ -498,7 +541,7 @@ unsafe fn init_d3d9(hwnd: HWND, width: u32, height: u32) -> Result<SanzuDirect3D
 /// - p_direct3d_surface must not be null
 unsafe fn render(
     sanzu_direct3d: &mut SanzuDirect3D,
-    data: Vec<u8>,
+    data: &[u8],
     width: u32,
     height: u32,
 ) -> Result<()> {
@@ -520,8 +563,7 @@ unsafe fn render(
         return Err(anyhow!("Error in lockrect {:#X}", ret));
     }
 
-    let mut p_src = data;
-    let mut raw_src = p_src.as_mut_ptr();
+    let mut raw_src = data.as_ptr();
     let mut p_dest = d3d_rect.pBits;
     let stride = d3d_rect.Pitch;
     let pixel_w_size = width * 4;
@@ -541,9 +583,6 @@ unsafe fn render(
         }
     }
 
-    // Use drop to keep lifetime of original object through unsafe call
-    drop(p_src);
-
     let ret = surface.UnlockRect();
     if ret != 0 {
         return Err(anyhow!("Error in UnlockRect {:#X}", ret));
@@ -574,12 +613,29 @@ unsafe fn render(
 
     let mut back_buffer = Direct3DSurface::new(p_back_buffer);
 
-    /* Use rect with img size to avoid stretching */
+    let mut back_desc = D3DSURFACE_DESC::default();
+    let ret = back_buffer
+        .get_inner()
+        .as_ref()
+        .context("Null back buffer")?
+        .GetDesc(&mut back_desc);
+    if ret != 0 {
+        return Err(anyhow!("Error in GetDesc: {:#X}", ret));
+    }
+
+    /* In native mode, use rect with img size to avoid stretching */
+    let scale_mode = scale_mode();
+    let (x, y, rect_width, rect_height) =
+        scale_mode.dest_rect((width, height), (back_desc.Width, back_desc.Height));
     let new_rect = RECT {
-        left: 0,
-        top: 0,
-        right: width as i32,
-        bottom: height as i32,
+        left: x as i32,
+        top: y as i32,
+        right: (x + rect_width) as i32,
+        bottom: (y + rect_height) as i32,
+    };
+    let filter = match scale_mode {
+        ScaleMode::Native => D3DTEXF_NONE,
+        _ => D3DTEXF_LINEAR,
     };
 
     let ret = device.StretchRect(
@@ -587,7 +643,7 @@ unsafe fn render(
         null_mut(),
         back_buffer.get_inner(),
         &new_rect as *const _,
-        D3DTEXF_NONE,
+        filter,
     );
     if ret != 0 {
         return Err(anyhow!(
@@ -647,6 +703,35 @@ extern "system" fn custom_wnd_proc_sub(
                 .send(0)
                 .expect("Error in send MsgSender");
         }
+        WM_SYSCOMMAND => {
+            // The low four bits are used internally by Windows
+            let action = match wparam & 0xFFF0 {
+                SC_MINIMIZE => tunnel::WinControl::Minimize,
+                SC_MAXIMIZE => tunnel::WinControl::Maximize,
+                SC_RESTORE => tunnel::WinControl::Restore,
+                SC_CLOSE => tunnel::WinControl::Close,
+                _ => return unsafe { DefWindowProcA(hwnd, msg, wparam, lparam) },
+            };
+            trace!("syscommand {:?} {:?}", hwnd, action);
+            if let Some(id) = HANDLE_TO_WIN_ID.lock().unwrap().get(&(hwnd as u64)) {
+                let eventwincontrol = tunnel::EventWinControl {
+                    id: *id as u32,
+                    action: action as i32,
+                };
+                let msg_event = tunnel::MessageClient {
+                    msg: Some(tunnel::message_client::Msg::WinControl(eventwincontrol)),
+                };
+                EVENT_SENDER
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .unwrap()
+                    .send(msg_event)
+                    .expect("Error in send EventWinControl");
+            }
+            // The local window follows the remote one through area updates
+            return 0;
+        }
         WM_DESTROY => {
             unsafe { DestroyWindow(hwnd) };
         }
@@ -700,6 +785,10 @@ extern "system" fn custom_wnd_proc(
             *GRAB_KEYBOARD.lock().unwrap() = false;
             ungrab_keyboard();
             release_keys();
+            // Don't keep the cursor clipped in the window while away
+            if RELATIVE_MOUSE.swap(false, atomic::Ordering::AcqRel) {
+                set_relative_mouse(false);
+            }
         }
 
         WM_UPDATE_FRAME => {}
@@ -708,9 +797,24 @@ extern "system" fn custom_wnd_proc(
             trace!("Move {:?} {:?} {:?} {:#X}", hwnd, msg, wparam, lparam);
             let x = lparam & 0xFFFF;
             let y = lparam >> 16;
+            if RELATIVE_MOUSE.load(atomic::Ordering::Acquire) {
+                if let Some(msg_event) = relative_motion(hwnd, x as i32, y as i32) {
+                    EVENT_SENDER
+                        .lock()
+                        .unwrap()
+                        .as_ref()
+                        .unwrap()
+                        .send(msg_event)
+                        .expect("Error in send EventMoveRelative");
+                }
+                return 0;
+            }
+            let (x, y) = window_to_frame(hwnd, x as i32, y as i32);
             let eventmove = tunnel::EventMove {
-                x: (x) as u32,
-                y: (y) as u32,
+                x,
+                y,
+                x_frac: 0,
+                y_frac: 0,
             };
             let msg_event = tunnel::MessageClient {
                 msg: Some(tunnel::message_client::Msg::Move(eventmove)),
@@ -725,8 +829,7 @@ extern "system" fn custom_wnd_proc(
         }
         WM_LBUTTONDOWN | WM_MBUTTONDOWN | WM_RBUTTONDOWN => {
             trace!("clickdown {:?} {:#X} {:?} {:#X}", hwnd, msg, wparam, lparam);
-            let x = lparam & 0xFFFF;
-            let y = lparam >> 16;
+            let (x, y) = window_to_frame(hwnd, (lparam & 0xFFFF) as i32, (lparam >> 16) as i32);
             if msg & 0x200 != 0 {
                 let button = msg & 0xF;
                 if let Some(button) = match button {
@@ -736,8 +839,8 @@ extern "system" fn custom_wnd_proc(
                     _ => None,
                 } {
                     let eventbutton = tunnel::EventButton {
-                        x: x as u32,
-                        y: y as u32,
+                        x,
+                        y,
                         button: button as u32,
                         updown: true,
                     };
@@ -756,8 +859,7 @@ extern "system" fn custom_wnd_proc(
         }
         WM_LBUTTONUP | WM_MBUTTONUP | WM_RBUTTONUP => {
             trace!("clickup {:?} {:#X} {:?} {:#X}", hwnd, msg, wparam, lparam);
-            let x = lparam & 0xFFFF;
-            let y = lparam >> 16;
+            let (x, y) = window_to_frame(hwnd, (lparam & 0xFFFF) as i32, (lparam >> 16) as i32);
             if msg & 0x200 != 0 {
                 let button = msg & 0xF;
                 if let Some(button) = match button {
@@ -767,8 +869,8 @@ extern "system" fn custom_wnd_proc(
                     _ => None,
                 } {
                     let eventbutton = tunnel::EventButton {
-                        x: x as u32,
-                        y: y as u32,
+                        x,
+                        y,
                         button: button as u32,
                         updown: false,
                     };
@@ -787,59 +889,33 @@ extern "system" fn custom_wnd_proc(
         }
         WM_MOUSEWHEEL => {
             trace!("wheel {:?} {:#X} {:#X} {:#X}", hwnd, msg, wparam, lparam);
-            let x = lparam & 0xFFFF;
-            let y = lparam >> 16;
+            let (x, y) = wheel_position(hwnd, lparam);
             let button = wparam as i32;
             let button = if button > 0 { 4 } else { 5 };
-
-            // Down
-            let eventbutton = tunnel::EventButton {
-                x: x as u32,
-                y: y as u32,
-                button: button as u32,
-                updown: true,
-            };
-            let msg_event = tunnel::MessageClient {
-                msg: Some(tunnel::message_client::Msg::Button(eventbutton)),
-            };
-            EVENT_SENDER
-                .lock()
-                .unwrap()
-                .as_ref()
-                .unwrap()
-                .send(msg_event)
-                .expect("Error in send EventButton");
-
-            // Up
-            let eventbutton = tunnel::EventButton {
-                x: x as u32,
-                y: y as u32,
-                button: button as u32,
-                updown: false,
-            };
-            let msg_event = tunnel::MessageClient {
-                msg: Some(tunnel::message_client::Msg::Button(eventbutton)),
-            };
-            EVENT_SENDER
-                .lock()
-                .unwrap()
-                .as_ref()
-                .unwrap()
-                .send(msg_event)
-                .expect("Error in send EventButton");
+            send_wheel(x, y, button);
+        }
+        WM_MOUSEHWHEEL => {
+            trace!("hwheel {:?} {:#X} {:#X} {:#X}", hwnd, msg, wparam, lparam);
+            let (x, y) = wheel_position(hwnd, lparam);
+            // Positive delta is a tilt to the right
+            let button = wparam as i32;
+            let button = if button > 0 { 7 } else { 6 };
+            send_wheel(x, y, button);
         }
 
         WM_DRAWCLIPBOARD => {
             info!("clipboard draw");
-            if let Ok(data) = get_clipboard(formats::Unicode) {
+            if let Some(data) = utils_win::get_clipboard_content() {
                 let mut skip_clipboard_guard = SKIP_CLIPBOARD.lock().unwrap();
                 if *skip_clipboard_guard > 0 {
                     *skip_clipboard_guard -= 1;
                     // The clipboard may be set by ourself, skip it
                 } else {
-                    trace!("Send clipboard {}", data);
+                    trace!("Send clipboard {:?}", data);
 
-                    let eventclipboard = tunnel::EventClipboard { data };
+                    let eventclipboard = tunnel::EventClipboard {
+                        content: Some(data),
+                    };
                     let msg_event = tunnel::MessageClient {
                         msg: Some(tunnel::message_client::Msg::Clipboard(eventclipboard)),
                     };
@@ -956,6 +1032,8 @@ extern "system" fn hook_callback_mouse(_code: i32, w_param: u64, _l_param: i64)
     let eventmove = tunnel::EventMove {
         x: (pos.x) as u32,
         y: (pos.y) as u32,
+        x_frac: 0,
+        y_frac: 0,
     };
     let msg_event = tunnel::MessageClient {
         msg: Some(tunnel::message_client::Msg::Move(eventmove)),
@@ -990,37 +1068,25 @@ extern "system" fn hook_callback_keyboard(code: i32, w_param: u64, l_param: i64)
                     keycode: keycode as u32,
                     updown,
                 };
-                let mut skip_key = false;
-                // If Ctrl alt shift s => Generate toggle server logs
-                if keycode == KEY_S as u16 && updown {
-                    // Ctrl Shift Alt
+                let action = if updown {
                     let keys_state = KEYS_STATE.lock().unwrap();
-                    if keys_state[KEY_CTRL] && keys_state[KEY_SHIFT] && keys_state[KEY_ALT] {
-                        let display_stats = DISPLAY_STATS.load(atomic::Ordering::Acquire);
-                        DISPLAY_STATS.store(!display_stats, atomic::Ordering::Release);
-                        info!("Toggle server logs");
-                        skip_key = true;
+                    HOTKEYS
+                        .lock()
+                        .unwrap()
+                        .action(keycode as usize, &keys_state)
+                } else {
+                    None
+                };
+                match action {
+                    Some(HotkeyAction::ToggleStats) => {
+                        let mut hud_mode = HUD_MODE.lock().unwrap();
+                        *hud_mode = hud_mode.next();
+                        info!("HUD mode {:?}", *hud_mode);
                     }
-                }
-
-                // If Ctrl alt shift c => Trig clipboard event
-                if keycode == KEY_C as u16 && updown {
-                    // Ctrl Shift Alt
-                    let keys_state = KEYS_STATE.lock().unwrap();
-                    if keys_state[KEY_CTRL] && keys_state[KEY_SHIFT] && keys_state[KEY_ALT] {
+                    Some(HotkeyAction::TriggerClipboard) => {
                         CLIPBOARD_TRIG.store(true, atomic::Ordering::Release);
-                        skip_key = true;
                     }
-                }
-
-                // If Ctrl alt shift h => toggle grab keyboard
-                if keycode == KEY_H as u16 && updown {
-                    // Ctrl Shift Alt
-                    let is_ctrl_alt_shift = {
-                        let keys_state = KEYS_STATE.lock().unwrap();
-                        keys_state[KEY_CTRL] && keys_state[KEY_SHIFT] && keys_state[KEY_ALT]
-                    };
-                    if is_ctrl_alt_shift {
+                    Some(HotkeyAction::ToggleGrab) => {
                         let mut grab_keyboard = GRAB_KEYBOARD.lock().unwrap();
                         *grab_keyboard = !*grab_keyboard;
 
@@ -1030,9 +1096,47 @@ extern "system" fn hook_callback_keyboard(code: i32, w_param: u64, l_param: i64)
                         }
 
                         info!("Toggle ungrab Keyboard {}", *grab_keyboard);
-                        skip_key = true;
                     }
+                    Some(HotkeyAction::ToggleRelative) => {
+                        if RELATIVE_MOUSE_ALLOWED.load(atomic::Ordering::Acquire) {
+                            let relative_mouse = !RELATIVE_MOUSE.load(atomic::Ordering::Acquire);
+                            RELATIVE_MOUSE.store(relative_mouse, atomic::Ordering::Release);
+                            set_relative_mouse(relative_mouse);
+                            info!("Toggle relative mouse {}", relative_mouse);
+                        } else {
+                            warn!("Relative mouse mode is not available");
+                        }
+                    }
+                    Some(HotkeyAction::CycleScale) => {
+                        if SCALING_ALLOWED.load(atomic::Ordering::Acquire) {
+                            let mut scale_mode = SCALE_MODE.lock().unwrap();
+                            *scale_mode = scale_mode.next();
+                            info!("Scale mode {:?}", *scale_mode);
+                        } else {
+                            warn!("Scaling is not available in seamless");
+                        }
+                    }
+                    Some(HotkeyAction::Fullscreen) => {
+                        // Seamless windows have no frame to toggle
+                        if SCALING_ALLOWED.load(atomic::Ordering::Acquire) {
+                            toggle_fullscreen(WINHANDLE.load(atomic::Ordering::Acquire));
+                        } else {
+                            warn!("Full screen is not available in seamless");
+                        }
+                    }
+                    Some(HotkeyAction::ToggleMute) => {
+                        let audio_muted = !AUDIO_MUTED.load(atomic::Ordering::Acquire);
+                        AUDIO_MUTED.store(audio_muted, atomic::Ordering::Release);
+                        info!("Audio muted {}", audio_muted);
+                    }
+                    Some(HotkeyAction::ToggleChroma) => {
+                        let chroma_toggled = !CHROMA_TOGGLED.load(atomic::Ordering::Acquire);
+                        CHROMA_TOGGLED.store(chroma_toggled, atomic::Ordering::Release);
+                        info!("Chroma toggled {}", chroma_toggled);
+                    }
+                    None => {}
                 }
+                let skip_key = action.is_some();
 
                 if *GRAB_KEYBOARD.lock().unwrap() {
                     if !skip_key {
@@ -1199,6 +1303,194 @@ fn set_window_cursor(cursor_data: &[u8], width: u32, height: u32, xhot: i32, yho
     }
 }
 
+/// Send a wheel notch as a press/release of the x11 `button` (4/5 vertical,
+/// 6/7 horizontal)
+/// Frame position of a wheel message, which holds screen coordinates
+fn wheel_position(hwnd: HWND, lparam: LPARAM) -> (u32, u32) {
+    let rect = client_screen_rect(hwnd);
+    let x = (lparam & 0xFFFF) as i16 as i32 - rect.left;
+    let y = (lparam >> 16) as i16 as i32 - rect.top;
+    window_to_frame(hwnd, x, y)
+}
+
+fn send_wheel(x: u32, y: u32, button: u32) {
+    for updown in [true, false] {
+        let eventbutton = tunnel::EventButton {
+            x,
+            y,
+            button,
+            updown,
+        };
+        let msg_event = tunnel::MessageClient {
+            msg: Some(tunnel::message_client::Msg::Button(eventbutton)),
+        };
+        EVENT_SENDER
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .send(msg_event)
+            .expect("Error in send EventButton");
+    }
+}
+
+/// Size of the client area of `hwnd`
+pub(crate) fn client_size(hwnd: HWND) -> (u32, u32) {
+    let mut rect = RECT::default();
+    unsafe { GetClientRect(hwnd, &mut rect) };
+    (
+        (rect.right - rect.left).max(0) as u32,
+        (rect.bottom - rect.top).max(0) as u32,
+    )
+}
+
+/// Current scaling of the frames in the main window
+pub(crate) fn scale_mode() -> ScaleMode {
+    *SCALE_MODE.lock().unwrap()
+}
+
+/// Converts client area coordinates of `hwnd` to remote frame coordinates
+fn window_to_frame(hwnd: HWND, x: i32, y: i32) -> (u32, u32) {
+    let frame_size = *SCREEN_SIZE.lock().unwrap();
+    let (x, y) = scale_mode().window_to_frame(frame_size, client_size(hwnd), (x as f64, y as f64));
+    (x as u32, y as u32)
+}
+
+/// Client area of `hwnd` in screen coordinates
+fn client_screen_rect(hwnd: HWND) -> RECT {
+    let mut rect = RECT::default();
+    unsafe { GetClientRect(hwnd, &mut rect) };
+    let mut top_left = POINT {
+        x: rect.left,
+        y: rect.top,
+    };
+    let mut bottom_right = POINT {
+        x: rect.right,
+        y: rect.bottom,
+    };
+    unsafe {
+        ClientToScreen(hwnd, &mut top_left);
+        ClientToScreen(hwnd, &mut bottom_right);
+    }
+    RECT {
+        left: top_left.x,
+        top: top_left.y,
+        right: bottom_right.x,
+        bottom: bottom_right.y,
+    }
+}
+
+/// Clip the cursor to the client window and park it at its center, or release
+/// it. The clip rectangle ignores the window region, which is why relative
+/// mode is refused in seamless.
+fn set_relative_mouse(enable: bool) {
+    if !enable {
+        unsafe { ClipCursor(null_mut()) };
+        return;
+    }
+    let rect = client_screen_rect(WINHANDLE.load(atomic::Ordering::Acquire));
+    unsafe {
+        ClipCursor(&rect);
+        SetCursorPos((rect.left + rect.right) / 2, (rect.top + rect.bottom) / 2);
+    }
+}
+
+/// Switch the client window between the full screen (maximized popup) and
+/// the framed window styles
+fn toggle_fullscreen(hwnd: HWND) {
+    let style = unsafe { GetWindowLongPtrA(hwnd, GWL_STYLE) } as DWORD;
+    let fullscreen = style & WS_POPUP == 0;
+    let (style, show) = if fullscreen {
+        (
+            (style & !WS_OVERLAPPEDWINDOW) | WS_POPUP | WS_DLGFRAME,
+            SW_MAXIMIZE,
+        )
+    } else {
+        (
+            (style & !(WS_POPUP | WS_DLGFRAME | WS_MAXIMIZE)) | WS_OVERLAPPEDWINDOW,
+            SW_RESTORE,
+        )
+    };
+    unsafe {
+        SetWindowLongPtrA(hwnd, GWL_STYLE, style as isize);
+        SetWindowPos(
+            hwnd,
+            null_mut(),
+            0,
+            0,
+            0,
+            0,
+            SWP_FRAMECHANGED | SWP_NOMOVE | SWP_NOSIZE | SWP_NOZORDER,
+        );
+        ShowWindow(hwnd, show);
+    }
+    info!("Toggle full screen {}", fullscreen);
+}
+
+/// Return the motion from the window center to (`x`, `y`), in client
+/// coordinates, and bring the cursor back to the center. The motion generated
+/// by SetCursorPos itself is null and is dropped.
+fn relative_motion(hwnd: HWND, x: i32, y: i32) -> Option<tunnel::MessageClient> {
+    let rect = client_screen_rect(hwnd);
+    let (width, height) = (rect.right - rect.left, rect.bottom - rect.top);
+    let (dx, dy) = (x - width / 2, y - height / 2);
+    if dx == 0 && dy == 0 {
+        return None;
+    }
+    unsafe { SetCursorPos(rect.left + width / 2, rect.top + height / 2) };
+    Some(tunnel::MessageClient {
+        msg: Some(tunnel::message_client::Msg::MoveRelative(
+            tunnel::EventMoveRelative { dx, dy },
+        )),
+    })
+}
+
+/// Create a window icon from `width`x`height` bgra `data`
+fn create_icon(data: &[u8], width: u32, height: u32) -> Option<HICON> {
+    let len = (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|len| len.checked_mul(4));
+    if width == 0 || height == 0 || len != Some(data.len()) {
+        error!("Strange icon size {}x{}", width, height);
+        return None;
+    }
+    let mut data = data.to_vec();
+    let hicon = unsafe {
+        let hdc = GetDC(null_mut());
+        let b_col = CreateBitmap(
+            width as i32,
+            height as i32,
+            1,
+            32,
+            data.as_mut_ptr() as *mut _,
+        );
+        let b_mask = CreateCompatibleBitmap(hdc, width as i32, height as i32);
+
+        let mut iconinfo = ICONINFO::default();
+        iconinfo.fIcon = TRUE;
+        iconinfo.hbmMask = b_mask;
+        iconinfo.hbmColor = b_col;
+
+        let hicon = CreateIconIndirect(&mut iconinfo);
+        DeleteObject(b_mask as *mut _);
+        DeleteObject(b_col as *mut _);
+        ReleaseDC(null_mut(), hdc);
+        hicon
+    };
+    if hicon.is_null() {
+        None
+    } else {
+        Some(hicon)
+    }
+}
+
+fn set_window_icon(window: HWND, icon: isize) {
+    unsafe {
+        SendMessageA(window, WM_SETICON as u32, ICON_BIG as usize, icon);
+        SendMessageA(window, WM_SETICON as u32, ICON_SMALL as usize, icon);
+    };
+}
+
 fn grab_keyboard() {
     let ptr = hook_callback_keyboard as *const ();
     let function: unsafe extern "system" fn(code: i32, wParam: usize, lParam: isize) -> isize =
@@ -1214,10 +1506,16 @@ fn ungrab_keyboard() {
     GRAB_KEYBOARD_ID.store(null_mut(), atomic::Ordering::Release);
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn init_wind3d(
     arguments: &ClientArgsConfig,
     mut seamless: bool,
+    relative_mouse: bool,
     server_size: Option<(u16, u16)>,
+    renderer: Renderer,
+    frame_hold: Option<Duration>,
+    hotkeys: Hotkeys,
+    clipboard_policy: ClipboardPolicy,
 ) -> Result<Box<dyn Client>> {
     let clipboard_config = match arguments.clipboard.as_str() {
         "allow" => ClipboardConfig::Allow,
@@ -1227,6 +1525,11 @@ pub fn init_wind3d(
             return Err(anyhow!("Unknown clipboard config: {}", arguments.clipboard));
         }
     };
+    // The local clipboard is not sent if the policy blocks it
+    let clipboard_config = match clipboard_policy.clipboard.to_server() {
+        true => clipboard_config,
+        false => ClipboardConfig::Deny,
+    };
 
     let (
         client_info,
@@ -1238,7 +1541,7 @@ pub fn init_wind3d(
     ) = ClientWindows::build(
         server_size,
         clipboard_config,
-        arguments.allow_print.clone(),
+        clipboard_policy,
         arguments.sync_key_locks,
         arguments.window_mode,
     );
@@ -1249,6 +1552,17 @@ pub fn init_wind3d(
     if window_mode {
         seamless = false;
     }
+    let scale_mode = arguments
+        .scale
+        .parse::<ScaleMode>()
+        .context("Error in scale argument")?;
+    // Seamless windows are placed at their remote position
+    SCALING_ALLOWED.store(!seamless, atomic::Ordering::Release);
+    if !seamless {
+        *SCALE_MODE.lock().unwrap() = scale_mode;
+    }
+    RELATIVE_MOUSE_ALLOWED.store(relative_mouse, atomic::Ordering::Release);
+    *HOTKEYS.lock().unwrap() = hotkeys;
     EVENT_SENDER.lock().unwrap().replace(event_sender);
 
     let (window_sender, window_receiver) = channel();
@@ -1376,48 +1690,69 @@ pub fn init_wind3d(
         // Only take one image from the queue. As it's a sync channel, this
         // will add a backpressure to the main thread.
         thread::spawn(move || {
-            let mut sanzu_direct3d = None;
+            let mut frame_renderer = None;
+            // The d3d9 back buffer keeps the window size of its creation
+            let mut renderer_window_size = (0, 0);
+            let mut frame_hold = frame_hold.map(FrameHold::new);
             loop {
-                if let Ok((data, width, height)) = frame_receiver.recv() {
+                // Without a new frame in time, the last one is presented again
+                let (frame, held) = match frame_hold.as_ref() {
+                    Some(hold) => match frame_receiver.recv_timeout(hold.interval()) {
+                        Ok(frame) => (Some(frame), false),
+                        Err(RecvTimeoutError::Timeout) => (hold.held_frame(Instant::now()), true),
+                        Err(RecvTimeoutError::Disconnected) => (None, false),
+                    },
+                    None => (frame_receiver.recv().ok(), false),
+                };
+                if let Some((data, width, height)) = frame {
+                    let window = WINHANDLE.load(atomic::Ordering::Acquire);
+                    let window_size = client_size(window);
                     if (width, height) != *SCREEN_SIZE.lock().unwrap()
                         || session_receiver.try_recv().is_ok()
+                        || (renderer == Renderer::D3d9 && window_size != renderer_window_size)
                     {
-                        let window = WINHANDLE.load(atomic::Ordering::Acquire);
-                        info!("Init d3d for new resolution {}x{}", width, height);
-                        match unsafe { init_d3d9(window, width as u32, height as u32) } {
-                            Ok(new_sanzu_direct3d) => {
-                                sanzu_direct3d = Some(new_sanzu_direct3d);
+                        renderer_window_size = window_size;
+                        info!(
+                            "Init {:?} for new resolution {}x{}",
+                            renderer, width, height
+                        );
+                        match init_renderer(renderer, window, width, height) {
+                            Ok(new_frame_renderer) => {
+                                frame_renderer = Some(new_frame_renderer);
                             }
                             Err(err) => {
-                                error!("Error in Init_d3d9: {:?}", err);
-                                sanzu_direct3d = None;
+                                error!("Error in init {:?}: {:?}", renderer, err);
+                                frame_renderer = None;
                             }
                         }
                     }
                     let result = {
-                        if let Some(sanzu_direct3d) = sanzu_direct3d.as_mut() {
-                            unsafe { render(sanzu_direct3d, data, width, height) }
+                        if let Some(frame_renderer) = frame_renderer.as_mut() {
+                            frame_renderer.render(&data, width, height)
                         } else {
-                            Err(anyhow!("No sanzu obj"))
+                            Err(anyhow!("No renderer"))
                         }
                     };
 
                     if let Err(err) = result {
-                        if sanzu_direct3d.is_some() {
+                        if frame_renderer.is_some() {
                             error!("Error during render: {:?}", err);
                         }
-                        info!("re-Init d3d for resolution {}x{}", width, height);
-                        let window = WINHANDLE.load(atomic::Ordering::Acquire);
-                        match unsafe { init_d3d9(window, width as u32, height as u32) } {
-                            Ok(new_sanzu_direct3d) => {
-                                sanzu_direct3d = Some(new_sanzu_direct3d);
+                        info!("re-Init {:?} for resolution {}x{}", renderer, width, height);
+                        renderer_window_size = window_size;
+                        match init_renderer(renderer, window, width, height) {
+                            Ok(new_frame_renderer) => {
+                                frame_renderer = Some(new_frame_renderer);
                             }
                             Err(err) => {
-                                warn!("Error in Init_d3d9: {:?}", err);
-                                sanzu_direct3d = None;
+                                warn!("Error in init {:?}: {:?}", renderer, err);
+                                frame_renderer = None;
                             }
                         }
                     }
+                    if let (Some(hold), false) = (frame_hold.as_mut(), held) {
+                        hold.set((data, width, height), Instant::now());
+                    }
                 }
             }
         });
@@ -1440,6 +1775,9 @@ pub fn init_wind3d(
             }
         });
 
+        // Icons received for the seamless windows, which may come before the
+        // window itself
+        let mut area_icons: HashMap<usize, HICON> = HashMap::new();
         let mut msg = MSG::default();
         let mut stopped = false;
         while msg.message != WM_QUIT && !stopped {
@@ -1488,14 +1826,11 @@ pub fn init_wind3d(
                         info!("New Window {:?}", window);
                         WIN_ID_TO_HANDLE.lock().unwrap().insert(id, window as u64);
                         HANDLE_TO_WIN_ID.lock().unwrap().insert(window as u64, id);
-                        unsafe {
-                            SendMessageA(
-                                (window) as *mut _,
-                                WM_SETICON as u32,
-                                ICON_BIG as usize,
-                                img as isize,
-                            )
+                        let icon = match area_icons.get(&id) {
+                            Some(icon) => *icon as isize,
+                            None => img as isize,
                         };
+                        set_window_icon(window, icon);
                     }
                     AreaManager::DeleteArea(id) => {
                         if let Some(window) = WIN_ID_TO_HANDLE.lock().unwrap().remove(&id) {
@@ -1504,6 +1839,40 @@ pub fn init_wind3d(
                             info!("Del Window {:#X}", window);
                             unsafe { SendMessageA((window) as *mut _, WM_CLOSE, 0, 0) };
                         }
+                        if let Some(icon) = area_icons.remove(&id) {
+                            unsafe { DestroyIcon(icon) };
+                        }
+                    }
+                    AreaManager::Restack(order) => {
+                        let handles = WIN_ID_TO_HANDLE.lock().unwrap();
+                        // Raise the windows from the bottom one, so that the
+                        // last raised is on top
+                        for (id, topmost) in order {
+                            let window = match handles.get(&id) {
+                                Some(window) => *window as HWND,
+                                None => continue,
+                            };
+                            let flags = SWP_NOMOVE | SWP_NOSIZE | SWP_NOACTIVATE;
+                            unsafe {
+                                if topmost {
+                                    SetWindowPos(window, HWND_TOPMOST, 0, 0, 0, 0, flags);
+                                } else {
+                                    // No effect if the window is not topmost
+                                    SetWindowPos(window, HWND_NOTOPMOST, 0, 0, 0, 0, flags);
+                                    SetWindowPos(window, HWND_TOP, 0, 0, 0, 0, flags);
+                                }
+                            }
+                        }
+                    }
+                    AreaManager::SetIcon(id, width, height, data) => {
+                        if let Some(icon) = create_icon(&data, width, height) {
+                            if let Some(window) = WIN_ID_TO_HANDLE.lock().unwrap().get(&id) {
+                                set_window_icon(*window as HWND, icon as isize);
+                            }
+                            if let Some(old_icon) = area_icons.insert(id, icon) {
+                                unsafe { DestroyIcon(old_icon) };
+                            }
+                        }
                     }
                 }
             }
@@ -1612,6 +1981,17 @@ impl Client for ClientWindows {
                     .send(AreaManager::DeleteArea(area.id))
                     .expect("Cannot receive window");
             }
+            let order = stacking_order(&areas_vec);
+            if order != stacking_order(&self.cur_areas) {
+                debug!("Restack windows {:?}", order);
+                WINDOW_SENDER
+                    .lock()
+                    .unwrap()
+                    .as_mut()
+                    .unwrap()
+                    .send(AreaManager::Restack(order))
+                    .expect("Cannot send window");
+            }
             self.shape_sender
                 .send(areas_vec.clone())
                 .context("Error in send shape")?;
@@ -1620,12 +2000,13 @@ impl Client for ClientWindows {
         Ok(())
     }
 
-    fn set_clipboard(&mut self, data: &str) -> Result<()> {
+    fn set_clipboard(&mut self, data: &ClipboardContent) -> Result<()> {
+        if !self.clipboard_policy.clipboard.to_client() {
+            debug!("Clipboard blocked by policy");
+            return Ok(());
+        }
         *SKIP_CLIPBOARD.lock().unwrap() += 1;
-        set_clipboard(formats::Unicode, data)
-            .map_err(|err| anyhow!("Err {:?}", err))
-            .context("Cannot set clipboard")?;
-        Ok(())
+        utils_win::set_clipboard_content(data)
     }
 
     fn poll_events(&mut self) -> Result<tunnel::MessagesClient> {
@@ -1665,7 +2046,9 @@ impl Client for ClientWindows {
                 } => last_move = Some(event),
                 tunnel::MessageClient {
                     msg:
-                        Some(tunnel::message_client::Msg::Clipboard(tunnel::EventClipboard { data })),
+                        Some(tunnel::message_client::Msg::Clipboard(tunnel::EventClipboard {
+                            content: Some(data),
+                        })),
                 } => {
                     self.clipboard_last_value = Some(data);
                 }
@@ -1682,7 +2065,9 @@ impl Client for ClientWindows {
 
             ClipboardConfig::Allow => {
                 if let Some(data) = self.clipboard_last_value.take() {
-                    let eventclipboard = tunnel::EventClipboard { data };
+                    let eventclipboard = tunnel::EventClipboard {
+                        content: Some(data),
+                    };
                     let clipboard_msg = tunnel::MessageClient {
                         msg: Some(tunnel::message_client::Msg::Clipboard(eventclipboard)),
                     };
@@ -1697,7 +2082,7 @@ impl Client for ClientWindows {
                 ) {
                     // If we triggered clipboard send and the clipboard is not empty
                     let eventclipboard = tunnel::EventClipboard {
-                        data: data.to_owned(),
+                        content: Some(data.to_owned()),
                     };
                     let clipboard_msg = tunnel::MessageClient {
                         msg: Some(tunnel::message_client::Msg::Clipboard(eventclipboard)),
@@ -1711,46 +2096,77 @@ impl Client for ClientWindows {
         Ok(tunnel::MessagesClient { msgs: events })
     }
 
-    fn display_stats(&self) -> bool {
-        DISPLAY_STATS.load(atomic::Ordering::Acquire)
+    fn hud_mode(&self) -> HudMode {
+        *HUD_MODE.lock().unwrap()
     }
 
-    fn printfile(&self, file: &str) -> Result<()> {
-        if let Some(ref printdir) = self.printdir {
-            info!("Request to print file {:?}", file);
-            if !file.chars().all(|c| {
-                (char::is_alphanumeric(c) || char::is_ascii_punctuation(&c))
-                    && (c != '/' && 'c' != '\\')
-            }) {
-                return Err(anyhow!("Bad filename {:?}", file));
-            }
+    fn audio_muted(&self) -> bool {
+        AUDIO_MUTED.load(atomic::Ordering::Acquire)
+    }
 
-            let path = std::path::Path::new(printdir);
-            let filepath = path.join(file);
-            info!("Print file path {:?}", filepath);
-            let filepath_str = filepath.to_str().context("Cannot get path str")?;
-            let print_str = CString::new("print").context("Error in create print str")?;
-            let print_str_ptr = print_str.as_ptr();
-            let filename = CString::new(filepath_str).context("Error in create file path str")?;
-            let filename_ptr = filename.as_ptr();
-            let ret = unsafe {
-                ShellExecuteA(
-                    null_mut(),
-                    print_str_ptr,
-                    filename_ptr,
-                    null_mut(),
-                    null_mut(),
-                    SW_HIDE,
-                )
-            };
-            debug!("ret {:?}", ret);
-            if ret as usize > 32 {
-                Ok(())
-            } else {
-                Err(anyhow!("Error during printing {:?}", ret))
-            }
+    fn chroma_toggled(&self) -> bool {
+        CHROMA_TOGGLED.load(atomic::Ordering::Acquire)
+    }
+
+    fn set_area_icon(&mut self, id: usize, size: (u32, u32), data: &[u8]) -> Result<()> {
+        WINDOW_SENDER
+            .lock()
+            .unwrap()
+            .as_mut()
+            .context("No window sender")?
+            .send(AreaManager::SetIcon(id, size.0, size.1, data.to_vec()))
+            .context("Cannot send window icon")
+    }
+
+    fn printfile(&self, path: &Path) -> Result<()> {
+        info!("Print file path {:?}", path);
+        let filepath_str = path.to_str().context("Cannot get path str")?;
+        let print_str = CString::new("print").context("Error in create print str")?;
+        let print_str_ptr = print_str.as_ptr();
+        let filename = CString::new(filepath_str).context("Error in create file path str")?;
+        let filename_ptr = filename.as_ptr();
+        let ret = unsafe {
+            ShellExecuteA(
+                null_mut(),
+                print_str_ptr,
+                filename_ptr,
+                null_mut(),
+                null_mut(),
+                SW_HIDE,
+            )
+        };
+        debug!("ret {:?}", ret);
+        if ret as usize > 32 {
+            Ok(())
         } else {
-            Err(anyhow!("Not configured to print"))
+            Err(anyhow!("Error during printing {:?}", ret))
         }
     }
+
+    fn dpi(&self) -> u32 {
+        let (mut dpi_x, mut dpi_y) = (0, 0);
+        // The client is not dpi aware: query the real monitor dpi from a per
+        // monitor aware context
+        let ret = unsafe {
+            let old_context = SetThreadDpiAwarenessContext(DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE);
+            let monitor = MonitorFromWindow(
+                WINHANDLE.load(atomic::Ordering::Acquire),
+                MONITOR_DEFAULTTONEAREST,
+            );
+            let ret = GetDpiForMonitor(monitor, MDT_EFFECTIVE_DPI, &mut dpi_x, &mut dpi_y);
+            if !old_context.is_null() {
+                SetThreadDpiAwarenessContext(old_context);
+            }
+            ret
+        };
+        if ret != S_OK {
+            warn!("Cannot get monitor dpi");
+            return 0;
+        }
+        dpi_x
+    }
+
+    fn keyboard_layout(&self) -> Option<String> {
+        utils_win::keyboard_layout()
+    }
 }
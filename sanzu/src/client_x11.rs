@@ -1,6 +1,10 @@
 use crate::{
-    client_utils::{Area, Client},
-    utils::{ClientArgsConfig, ClipboardConfig, ClipboardSelection},
+    client_utils::{Area, Client, HotkeyAction, Hotkeys, HudMode, ScaleMode},
+    config::ClipboardPolicy,
+    utils::{
+        xft_dpi, ClientArgsConfig, ClipboardConfig, ClipboardContent, ClipboardNotifier,
+        ClipboardSelection,
+    },
     utils_x11,
 };
 use anyhow::{Context, Result};
@@ -9,14 +13,13 @@ use sanzu_common::tunnel;
 
 use std::{
     collections::HashMap,
-    sync::{
-        mpsc::{channel, Receiver},
-        Arc, Mutex,
-    },
+    path::Path,
+    process::Command,
+    sync::{atomic::AtomicBool, Arc, Mutex},
     thread,
 };
 
-use utils_x11::{get_clipboard_events, listen_clipboard};
+use utils_x11::listen_clipboard;
 use x11_clipboard::Clipboard;
 
 use x11rb::{
@@ -26,6 +29,7 @@ use x11rb::{
         render,
         shape::{self, ConnectionExt as _},
         xfixes::ConnectionExt as _,
+        xinput::{self, ConnectionExt as _},
         xproto::ConnectionExt as _,
         xproto::*,
         Event,
@@ -35,14 +39,6 @@ use x11rb::{
     COPY_DEPTH_FROM_PARENT,
 };
 
-/// xkbprint -color -kc :0 - | ps2pdf - > xkbprint.pdf
-const KEY_CTRL: usize = 37;
-const KEY_SHIFT: usize = 50;
-const KEY_ALT: usize = 64;
-const KEY_S: usize = 39;
-const KEY_C: usize = 54;
-const KEY_H: usize = 43;
-
 /// Holds information on the local client graphic window
 pub struct WindowInfo {
     /// x11rb window handle
@@ -81,14 +77,18 @@ pub struct ClientInfo {
     pub clipboard: Clipboard,
     /// Clipboard behavior
     pub clipboard_config: ClipboardConfig,
-    /// Clipboard event receiver
-    pub clipboard_event_receiver: Receiver<String>,
+    /// Allowed clipboard directions, for each selection
+    pub clipboard_policy: ClipboardPolicy,
+    /// Clipboard events
+    pub clipboard_notifier: ClipboardNotifier,
     /// Last seen clipboard value
-    pub clipboard_last_value: Option<String>,
+    pub clipboard_last_value: Option<ClipboardContent>,
     /// store clipboard events to skip
     pub skip_clipboard_primary: Arc<Mutex<u32>>,
     pub skip_clipboard_clipboard: Arc<Mutex<u32>>,
-    pub display_stats: bool,
+    pub hud_mode: HudMode,
+    pub audio_muted: bool,
+    pub chroma_toggled: bool,
     /// Bool to trig clipboard send
     pub clipbard_trig: bool,
     /// Sync caps/num/scroll lock
@@ -101,6 +101,186 @@ pub struct ClientInfo {
     pub grab_keyboard: bool,
     /// Stores bgra format id for cursor picture
     pub bgra_format_id: u32,
+    /// Send every pointer motion, with sub-pixel coordinates
+    pub smooth_pointer: bool,
+    /// Server accepts relative pointer motion
+    pub relative_mouse_allowed: bool,
+    /// Pointer is grabbed and its motions are sent as deltas
+    pub relative_mouse: bool,
+    /// Scaling of the frame in the window (native if seamless)
+    pub scale_mode: ScaleMode,
+    /// Size of the last frame
+    pub frame_size: (u32, u32),
+    /// Picture format of the window visual, used to draw scaled frames
+    pub window_format_id: u32,
+    /// Client hotkeys
+    pub hotkeys: Hotkeys,
+    /// XInput2 pen devices, by device id
+    pub pen_devices: HashMap<u16, PenDevice>,
+    /// Last pen state, as the valuators are only sent when they change
+    pub pen_state: tunnel::EventPen,
+    /// The last motion came from a pen, whose buttons are sent in the pen
+    /// events
+    pub pen_active: bool,
+}
+
+/// Valuator of a XInput2 device
+#[derive(Debug, Clone, Copy)]
+struct Valuator {
+    number: u16,
+    min: f64,
+    max: f64,
+}
+
+/// XInput2 device reporting a pen pressure, with its optional tilt
+#[derive(Debug)]
+pub struct PenDevice {
+    pressure: Valuator,
+    tilt_x: Option<Valuator>,
+    tilt_y: Option<Valuator>,
+}
+
+fn fp3232_to_f64(value: &xinput::Fp3232) -> f64 {
+    value.integral as f64 + value.frac as f64 / 4294967296.0
+}
+
+/// List the pointer devices with a pressure valuator (pens, tablets)
+fn pen_devices<C: Connection>(conn: &C) -> Result<HashMap<u16, PenDevice>> {
+    let atom = |name: &[u8]| -> Result<Atom> {
+        Ok(conn
+            .intern_atom(true, name)
+            .context("Error in intern_atom")?
+            .reply()
+            .context("Error in intern_atom reply")?
+            .atom)
+    };
+    let pressure_label = atom(b"Abs Pressure")?;
+    let tilt_x_label = atom(b"Abs Tilt X")?;
+    let tilt_y_label = atom(b"Abs Tilt Y")?;
+    if pressure_label == x11rb::NONE {
+        return Ok(HashMap::new());
+    }
+
+    let devices = conn
+        .xinput_xi_query_device(xinput::Device::ALL)
+        .context("Error in xi_query_device")?
+        .reply()
+        .context("Error in xi_query_device reply")?;
+    let mut pen_devices = HashMap::new();
+    for device in devices.infos {
+        if device.type_ != xinput::DeviceType::SLAVE_POINTER {
+            continue;
+        }
+        let (mut pressure, mut tilt_x, mut tilt_y) = (None, None, None);
+        for class in device.classes.iter() {
+            if let xinput::DeviceClassData::Valuator(valuator) = &class.data {
+                let value = Some(Valuator {
+                    number: valuator.number,
+                    min: fp3232_to_f64(&valuator.min),
+                    max: fp3232_to_f64(&valuator.max),
+                });
+                match valuator.label {
+                    x11rb::NONE => {}
+                    label if label == pressure_label => pressure = value,
+                    label if label == tilt_x_label => tilt_x = value,
+                    label if label == tilt_y_label => tilt_y = value,
+                    _ => {}
+                }
+            }
+        }
+        if let Some(pressure) = pressure {
+            info!("Pen device {}", String::from_utf8_lossy(&device.name));
+            pen_devices.insert(
+                device.deviceid,
+                PenDevice {
+                    pressure,
+                    tilt_x,
+                    tilt_y,
+                },
+            );
+        }
+    }
+    Ok(pen_devices)
+}
+
+/// Value of the valuator `number` in a XInput2 event, if it is present
+fn valuator_value(mask: &[u32], values: &[xinput::Fp3232], number: u16) -> Option<f64> {
+    let (word, bit) = (number as usize / 32, number as u32 % 32);
+    let bits = *mask.get(word)?;
+    if bits & (1 << bit) == 0 {
+        return None;
+    }
+    // Values are only sent for the valuators set in the mask
+    let index = mask[..word]
+        .iter()
+        .map(|bits| bits.count_ones())
+        .sum::<u32>()
+        + (bits & ((1 << bit) - 1)).count_ones();
+    values.get(index as usize).map(fp3232_to_f64)
+}
+
+/// Update the pen state with a XInput2 motion event of the pen `device`
+fn pen_event(
+    client: &mut ClientInfo,
+    device: u16,
+    event: &xinput::MotionEvent,
+) -> Option<tunnel::MessageClient> {
+    let pen_device = client.pen_devices.get(&device)?;
+    let value = |valuator: Option<Valuator>| {
+        valuator.and_then(|valuator| {
+            valuator_value(&event.valuator_mask, &event.axisvalues, valuator.number)
+                .map(|value| (value, valuator))
+        })
+    };
+    let mut pen_state = client.pen_state.clone();
+    if let Some((value, valuator)) = value(Some(pen_device.pressure)) {
+        let range = (valuator.max - valuator.min).max(1.0);
+        pen_state.pressure = (((value - valuator.min) / range).clamp(0.0, 1.0) * 65535.0) as u32;
+    }
+    // Tilt is reported in degrees by the libinput driver
+    if let Some((value, _)) = value(pen_device.tilt_x) {
+        pen_state.tilt_x = value.round().clamp(-90.0, 90.0) as i32;
+    }
+    if let Some((value, _)) = value(pen_device.tilt_y) {
+        pen_state.tilt_y = value.round().clamp(-90.0, 90.0) as i32;
+    }
+    let buttons = event.button_mask.first().copied().unwrap_or(0);
+    pen_state.button = ((buttons >> 2) & 1) | (((buttons >> 3) & 1) << 1);
+    let (x, y) = window_to_frame(
+        client,
+        event.event_x as f64 / 65536.0,
+        event.event_y as f64 / 65536.0,
+    );
+    pen_state.x = x.max(0.0) as u32;
+    pen_state.y = y.max(0.0) as u32;
+    client.pen_state = pen_state.clone();
+    Some(tunnel::MessageClient {
+        msg: Some(tunnel::message_client::Msg::Pen(pen_state)),
+    })
+}
+
+/// Split a coordinate into its integer and 1/65536 parts
+fn split_coordinate(value: f64) -> (u32, u32) {
+    let value = value.max(0.0);
+    (value.trunc() as u32, (value.fract() * 65536.0) as u32)
+}
+
+/// Receive the pointer motions and the touches as XInput2 events, which hold
+/// sub-pixel coordinates
+///
+/// Selections on a window replace each other, so `mask` holds all the events
+fn select_xi2_events<C: Connection>(conn: &C, win_id: Window, mask: u32) -> Result<()> {
+    conn.xinput_xi_select_events(
+        win_id,
+        &[xinput::EventMask {
+            deviceid: xinput::Device::ALL_MASTER.into(),
+            mask: vec![mask.into()],
+        }],
+    )
+    .context("Error in xi_select_events")?
+    .check()
+    .context("Error in xi_select_events check")?;
+    Ok(())
 }
 
 fn create_gc<C: Connection>(
@@ -125,6 +305,7 @@ fn setup_window<C: Connection>(
     screen: &Screen,
     window_position: (i16, i16),
     window_size: (u16, u16),
+    xi2_mask: u32,
 ) -> Result<Window> {
     let win_id = conn.generate_id().context("Error in generate_id")?;
     let win_aux = CreateWindowAux::new()
@@ -200,6 +381,13 @@ fn setup_window<C: Connection>(
         }
     }
 
+    if xi2_mask != 0 {
+        if let Err(err) = select_xi2_events(conn, win_id, xi2_mask) {
+            warn!("Cannot select XInput2 events, sub-pixel motion, touch and pen disabled");
+            err.chain().for_each(|cause| warn!(" - due to {}", cause));
+        }
+    }
+
     conn.map_window(win_id)
         .context("Error in map_window")?
         .check()
@@ -215,9 +403,10 @@ fn new_area<C: Connection>(
     arguments: &ClientArgsConfig,
     screen: &Screen,
     size: (u16, u16),
+    xi2_mask: u32,
 ) -> Result<WindowInfo> {
-    let win_id =
-        setup_window(conn, arguments, screen, (0, 0), size).context("Error in setup_window")?;
+    let win_id = setup_window(conn, arguments, screen, (0, 0), size, xi2_mask)
+        .context("Error in setup_window")?;
 
     let pixmap = conn.generate_id().context("Error in x11rb generate_id")?;
 
@@ -243,10 +432,16 @@ fn new_area<C: Connection>(
 /// Initialize the x11 xfixes extension to support clipboard manipulations
 /// Initialize the x11 shape extension to support custom shaped windows (used in
 /// the seamless version)
+#[allow(clippy::too_many_arguments)]
 pub fn init_x11rb(
     arguments: &ClientArgsConfig,
     seamless: bool,
+    relative_mouse: bool,
+    touch: bool,
+    pen: bool,
     server_size: Option<(u16, u16)>,
+    hotkeys: Hotkeys,
+    clipboard_policy: ClipboardPolicy,
 ) -> Result<Box<dyn Client>> {
     debug!("Start client");
     let (conn, screen_num) =
@@ -282,8 +477,45 @@ pub fn init_x11rb(
     conn.xfixes_query_version(100, 0)
         .context("Error in xfixes_query_version")?;
 
-    let (selection_sender_primary, clipboard_event_receiver) = channel();
-    let selection_sender_clipboard = selection_sender_primary.clone();
+    if arguments.smooth_pointer || touch || pen {
+        // XInput2 events are only sent to clients announcing their version,
+        // touch events need the version 2.2
+        if let Err(err) = conn
+            .xinput_xi_query_version(2, if touch { 2 } else { 0 })
+            .context("Error in xi_query_version")
+            .and_then(|cookie| cookie.reply().context("Error in xi_query_version reply"))
+        {
+            warn!("XInput2 is not available");
+            err.chain().for_each(|cause| warn!(" - due to {}", cause));
+        }
+    }
+
+    let pen_devices = match pen {
+        true => pen_devices(&conn).unwrap_or_else(|err| {
+            warn!("Cannot list XInput2 devices, pen disabled");
+            err.chain().for_each(|cause| warn!(" - due to {}", cause));
+            HashMap::new()
+        }),
+        false => HashMap::new(),
+    };
+
+    // Pen valuators are only sent in XInput2 motion events. Touches are no
+    // longer emulated as pointer events once selected.
+    let mut xi2_mask = 0;
+    if arguments.smooth_pointer || !pen_devices.is_empty() {
+        xi2_mask |= u32::from(xinput::XIEventMask::MOTION);
+    }
+    if touch {
+        xi2_mask |= u32::from(
+            xinput::XIEventMask::TOUCH_BEGIN
+                | xinput::XIEventMask::TOUCH_UPDATE
+                | xinput::XIEventMask::TOUCH_END,
+        );
+    }
+
+    let clipboard_notifier = ClipboardNotifier::default();
+    let clipboard_notifier_primary = clipboard_notifier.clone();
+    let clipboard_notifier_clipboard = clipboard_notifier.clone();
 
     let skip_clipboard_primary = Arc::new(Mutex::new(0));
     let skip_clipboard_clipboard = Arc::new(Mutex::new(0));
@@ -301,23 +533,29 @@ pub fn init_x11rb(
 
     match clipboard_config {
         ClipboardConfig::Allow | ClipboardConfig::Trig => {
-            // Listen "primary" clipboard events
-            thread::spawn(move || {
-                listen_clipboard(
-                    ClipboardSelection::Primary,
-                    selection_sender_primary,
-                    skip_clipboard_primary_thread,
-                );
-            });
-
-            // Listen "clipboard" clipboard events
-            thread::spawn(move || {
-                listen_clipboard(
-                    ClipboardSelection::Clipboard,
-                    selection_sender_clipboard,
-                    skip_clipboard_clipboard_thread,
-                );
-            });
+            if clipboard_policy.primary.to_server() {
+                // Listen "primary" clipboard events
+                thread::spawn(move || {
+                    listen_clipboard(
+                        ClipboardSelection::Primary,
+                        clipboard_notifier_primary,
+                        skip_clipboard_primary_thread,
+                        Arc::new(AtomicBool::new(true)),
+                    );
+                });
+            }
+
+            if clipboard_policy.clipboard.to_server() {
+                // Listen "clipboard" clipboard events
+                thread::spawn(move || {
+                    listen_clipboard(
+                        ClipboardSelection::Clipboard,
+                        clipboard_notifier_clipboard,
+                        skip_clipboard_clipboard_thread,
+                        Arc::new(AtomicBool::new(true)),
+                    );
+                });
+            }
         }
         _ => {}
     };
@@ -332,8 +570,8 @@ pub fn init_x11rb(
         .check()
         .context("Error in randr_select_input check")?;
 
-    let window_info =
-        new_area(&conn, arguments, screen, (width, height)).context("Error in new_area")?;
+    let window_info = new_area(&conn, arguments, screen, (width, height), xi2_mask)
+        .context("Error in new_area")?;
 
     if arguments.grab_keyboard {
         conn.grab_keyboard(
@@ -378,6 +616,28 @@ pub fn init_x11rb(
         }
     }
 
+    let window_format_id = render_pict_format
+        .screens
+        .get(screen_num)
+        .and_then(|pict_screen| {
+            pict_screen
+                .depths
+                .iter()
+                .flat_map(|depth| depth.visuals.iter())
+                .find(|visual| visual.visual == screen.root_visual)
+        })
+        .map(|visual| visual.format)
+        .context("No picture format for the root visual")?;
+
+    let scale_mode = if seamless {
+        ScaleMode::Native
+    } else {
+        arguments
+            .scale
+            .parse::<ScaleMode>()
+            .context("Error in scale argument")?
+    };
+
     let clipboard = Clipboard::new().context("Error in clipboard creation")?;
     let root = screen.root;
     let client_info = ClientInfo {
@@ -394,17 +654,30 @@ pub fn init_x11rb(
         seamless,
         clipboard,
         clipboard_config,
-        clipboard_event_receiver,
+        clipboard_policy,
+        clipboard_notifier,
         clipboard_last_value: None,
         skip_clipboard_primary,
         skip_clipboard_clipboard,
-        display_stats: false,
+        hud_mode: HudMode::Off,
+        audio_muted: false,
+        chroma_toggled: false,
         clipbard_trig: false,
         sync_key_locks: arguments.sync_key_locks,
         sync_key_locks_needed: arguments.sync_key_locks,
         areas: vec![],
         grab_keyboard: arguments.grab_keyboard,
         bgra_format_id,
+        smooth_pointer: arguments.smooth_pointer,
+        relative_mouse_allowed: relative_mouse,
+        relative_mouse: false,
+        scale_mode,
+        frame_size: (width as u32, height as u32),
+        window_format_id,
+        hotkeys,
+        pen_devices,
+        pen_state: tunnel::EventPen::default(),
+        pen_active: false,
     };
 
     Ok(Box::new(client_info))
@@ -468,6 +741,92 @@ fn put_frame(client_info: &mut ClientInfo, img: &[u8], width: u32, height: u32)
     Ok(())
 }
 
+/// Draw the frame pixmap scaled in the window, following the scale mode, and
+/// paint the uncovered window parts in black
+fn put_scaled_frame(client_info: &ClientInfo) -> Result<()> {
+    let conn = &client_info.conn;
+    let window = (client_info.width as u32, client_info.height as u32);
+    let frame = client_info.frame_size;
+    let (x, y, width, height) = client_info.scale_mode.dest_rect(frame, window);
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+
+    let borders: Vec<Rectangle> = [
+        (0, 0, window.0, y),
+        (0, y + height, window.0, window.1.saturating_sub(y + height)),
+        (0, y, x, height),
+        (x + width, y, window.0.saturating_sub(x + width), height),
+    ]
+    .iter()
+    .filter(|(_, _, width, height)| *width != 0 && *height != 0)
+    .map(|(x, y, width, height)| Rectangle {
+        x: *x as i16,
+        y: *y as i16,
+        width: *width as u16,
+        height: *height as u16,
+    })
+    .collect();
+    if !borders.is_empty() {
+        conn.poly_fill_rectangle(
+            client_info.window_info.window,
+            client_info.black_gc,
+            &borders,
+        )
+        .context("Error in poly_fill_rectangle")?;
+    }
+
+    let src = render::PictureWrapper::create_picture(
+        conn,
+        client_info.window_info.pixmap,
+        client_info.window_format_id,
+        &Default::default(),
+    )
+    .context("Cannot create picture")?;
+    let dst = render::PictureWrapper::create_picture(
+        conn,
+        client_info.window_info.window,
+        client_info.window_format_id,
+        &Default::default(),
+    )
+    .context("Cannot create picture")?;
+
+    // The transform maps the window pixels to the frame pixels
+    let to_fixed = |value: f64| (value * 65536.0) as render::Fixed;
+    let transform = render::Transform {
+        matrix11: to_fixed(frame.0 as f64 / width as f64),
+        matrix12: 0,
+        matrix13: 0,
+        matrix21: 0,
+        matrix22: to_fixed(frame.1 as f64 / height as f64),
+        matrix23: 0,
+        matrix31: 0,
+        matrix32: 0,
+        matrix33: to_fixed(1.0),
+    };
+    render::set_picture_transform(conn, src.picture(), transform)
+        .context("Error in set_picture_transform")?;
+    render::set_picture_filter(conn, src.picture(), b"bilinear", &[])
+        .context("Error in set_picture_filter")?;
+    render::composite(
+        conn,
+        render::PictOp::SRC,
+        src.picture(),
+        x11rb::NONE,
+        dst.picture(),
+        0,
+        0,
+        0,
+        0,
+        x as i16,
+        y as i16,
+        width as u16,
+        height as u16,
+    )
+    .context("Error in composite")?;
+    Ok(())
+}
+
 fn create_gc_with_foreground<C: Connection>(
     conn: &C,
     win_id: Window,
@@ -635,6 +994,157 @@ fn focus_out(client: &mut ClientInfo) -> Result<Vec<tunnel::MessageClient>> {
     Ok(events)
 }
 
+/// Converts window coordinates to remote frame coordinates
+fn window_to_frame(client: &ClientInfo, x: f64, y: f64) -> (f64, f64) {
+    client.scale_mode.window_to_frame(
+        client.frame_size,
+        (client.width as u32, client.height as u32),
+        (x, y),
+    )
+}
+
+/// Touch point of a XInput2 touch event, converted to remote frame
+/// coordinates
+fn touch_event(
+    client: &ClientInfo,
+    id: u32,
+    event_x: i32,
+    event_y: i32,
+    phase: tunnel::TouchPhase,
+) -> tunnel::MessageClient {
+    trace!("Touch {} {:?} {:#x} {:#x}", id, phase, event_x, event_y);
+    let (x, y) = window_to_frame(client, event_x as f64 / 65536.0, event_y as f64 / 65536.0);
+    let mut event = tunnel::EventTouch {
+        id,
+        x: x.max(0.0) as u32,
+        y: y.max(0.0) as u32,
+        ..Default::default()
+    };
+    event.set_phase(phase);
+    tunnel::MessageClient {
+        msg: Some(tunnel::message_client::Msg::Touch(event)),
+    }
+}
+
+/// Center of the client window, where the pointer is parked in relative mode
+fn window_center(client: &ClientInfo) -> (i16, i16) {
+    (
+        (client.window_info.size.0 / 2) as i16,
+        (client.window_info.size.1 / 2) as i16,
+    )
+}
+
+/// Ask the window manager to toggle the full screen state of the client window
+fn toggle_fullscreen(client: &ClientInfo) -> Result<()> {
+    let wm_state = client
+        .conn
+        .intern_atom(false, b"_NET_WM_STATE")
+        .context("Error in intern_atom")?
+        .reply()
+        .context("Error in intern_atom reply")?
+        .atom;
+    let wm_full = client
+        .conn
+        .intern_atom(false, b"_NET_WM_STATE_FULLSCREEN")
+        .context("Error in intern_atom")?
+        .reply()
+        .context("Error in intern_atom reply")?
+        .atom;
+    // _NET_WM_STATE_TOGGLE, from a normal application
+    let event = ClientMessageEvent::new(
+        32,
+        client.window_info.window,
+        wm_state,
+        [2, wm_full, 0, 1, 0],
+    );
+    client
+        .conn
+        .send_event(
+            false,
+            client.root,
+            EventMask::SUBSTRUCTURE_REDIRECT | EventMask::SUBSTRUCTURE_NOTIFY,
+            event,
+        )
+        .context("Error in send_event")?
+        .check()
+        .context("Error in send_event check")?;
+    info!("Toggle full screen");
+    Ok(())
+}
+
+/// Grab the pointer into the client window, so that each of its motions can
+/// be sent as a delta from the window center
+fn relative_mouse_on(client: &mut ClientInfo) -> Result<()> {
+    let window = client.window_info.window;
+    let reply = client
+        .conn
+        .grab_pointer(
+            false,
+            window,
+            EventMask::POINTER_MOTION | EventMask::BUTTON_PRESS | EventMask::BUTTON_RELEASE,
+            GrabMode::ASYNC,
+            GrabMode::ASYNC,
+            window,
+            x11rb::NONE,
+            0u32,
+        )
+        .context("Error in grab pointer")?
+        .reply()
+        .context("Error in grab pointer reply")?;
+    if reply.status != GrabStatus::SUCCESS {
+        return Err(anyhow!("Cannot grab pointer: {:?}", reply.status));
+    }
+    let (center_x, center_y) = window_center(client);
+    client
+        .conn
+        .warp_pointer(x11rb::NONE, window, 0, 0, 0, 0, center_x, center_y)
+        .context("Error in warp pointer")?;
+    client.conn.flush().context("Error in x11rb flush")?;
+    Ok(())
+}
+
+fn relative_mouse_off(client: &mut ClientInfo) -> Result<()> {
+    client
+        .conn
+        .ungrab_pointer(0u32)
+        .context("Cannot ungrab pointer")?;
+    client.conn.flush().context("Error in x11rb flush")?;
+    Ok(())
+}
+
+/// Return the motion from the window center to (`x`, `y`) and bring the
+/// pointer back to the center. The motion generated by the warp itself is
+/// null and is dropped.
+fn relative_motion(
+    client: &mut ClientInfo,
+    x: i16,
+    y: i16,
+) -> Result<Option<tunnel::MessageClient>> {
+    let (center_x, center_y) = window_center(client);
+    let (dx, dy) = (x as i32 - center_x as i32, y as i32 - center_y as i32);
+    if dx == 0 && dy == 0 {
+        return Ok(None);
+    }
+    client
+        .conn
+        .warp_pointer(
+            x11rb::NONE,
+            client.window_info.window,
+            0,
+            0,
+            0,
+            0,
+            center_x,
+            center_y,
+        )
+        .context("Error in warp pointer")?;
+    Ok(Some(tunnel::MessageClient {
+        msg: Some(tunnel::message_client::Msg::MoveRelative(
+            tunnel::EventMoveRelative { dx, dy },
+        )),
+    }))
+}
+
 impl Client for ClientInfo {
     fn size(&self) -> (u16, u16) {
         (self.width, self.height)
@@ -706,6 +1216,7 @@ impl Client for ClientInfo {
 
     fn set_img(&mut self, img: &[u8], size: (u32, u32)) -> Result<()> {
         self.need_update = true;
+        self.frame_size = size;
         put_frame(self, img, size.0, size.1)
     }
 
@@ -723,32 +1234,40 @@ impl Client for ClientInfo {
                     self.areas = distant_areas;
                 }
             }
-            self.conn
-                .copy_area(
-                    self.window_info.pixmap,
-                    self.window_info.window,
-                    self.black_gc,
-                    0,
-                    0,
-                    0,
-                    0,
-                    self.window_info.size.0,
-                    self.window_info.size.1,
-                )
-                .context("Error in copy_area")?;
+            if self.scale_mode == ScaleMode::Native {
+                self.conn
+                    .copy_area(
+                        self.window_info.pixmap,
+                        self.window_info.window,
+                        self.black_gc,
+                        0,
+                        0,
+                        0,
+                        0,
+                        self.window_info.size.0,
+                        self.window_info.size.1,
+                    )
+                    .context("Error in copy_area")?;
+            } else {
+                put_scaled_frame(self).context("Error in put_scaled_frame")?;
+            }
             self.need_update = false;
             self.conn.flush().context("Error in x11rb flush")?;
         }
         Ok(())
     }
 
-    fn set_clipboard(&mut self, data: &str) -> Result<()> {
-        /* Set *both* clipboards (primary and clipboard) */
-        *self.skip_clipboard_clipboard.lock().unwrap() += 1;
-        utils_x11::set_clipboard(&self.clipboard, 0, data).context("Error in set_clipboard")?;
+    fn set_clipboard(&mut self, data: &ClipboardContent) -> Result<()> {
+        /* Set *both* clipboards (primary and clipboard), if allowed by the policy */
+        if self.clipboard_policy.clipboard.to_client() {
+            *self.skip_clipboard_clipboard.lock().unwrap() += 1;
+            utils_x11::set_clipboard(&self.clipboard, 0, data).context("Error in set_clipboard")?;
+        }
 
-        *self.skip_clipboard_primary.lock().unwrap() += 1;
-        utils_x11::set_clipboard(&self.clipboard, 1, data).context("Error in set_clipboard")?;
+        if self.clipboard_policy.primary.to_client() {
+            *self.skip_clipboard_primary.lock().unwrap() += 1;
+            utils_x11::set_clipboard(&self.clipboard, 1, data).context("Error in set_clipboard")?;
+        }
 
         self.conn.flush().context("Error in x11rb flush")?;
 
@@ -795,22 +1314,98 @@ impl Client for ClientInfo {
             match event {
                 Event::MotionNotify(event) => {
                     trace!("Mouse move");
+                    if self.relative_mouse {
+                        if let Some(msg_event) =
+                            relative_motion(self, event.event_x, event.event_y)?
+                        {
+                            events.push(msg_event);
+                        }
+                        continue;
+                    }
+                    let (x, y) = window_to_frame(self, event.event_x as f64, event.event_y as f64);
                     let eventmove = tunnel::EventMove {
-                        x: event.event_x as u32,
-                        y: event.event_y as u32,
+                        x: x as u32,
+                        y: y as u32,
+                        x_frac: 0,
+                        y_frac: 0,
+                    };
+                    let msg_event = tunnel::MessageClient {
+                        msg: Some(tunnel::message_client::Msg::Move(eventmove)),
                     };
 
                     /* If multiple mose moves, keep only last one */
-                    last_move = Some(tunnel::MessageClient {
+                    match self.smooth_pointer {
+                        true => events.push(msg_event),
+                        false => last_move = Some(msg_event),
+                    }
+                }
+
+                Event::XinputMotion(event) => {
+                    trace!("Mouse move {:#x} {:#x}", event.event_x, event.event_y);
+                    // Under the pointer grab, motions are handled as core events
+                    if self.relative_mouse {
+                        continue;
+                    }
+                    // The pen buttons are sent with its motions
+                    if let Some(msg_event) = pen_event(self, event.sourceid, &event) {
+                        self.pen_active = true;
+                        events.push(msg_event);
+                        continue;
+                    }
+                    self.pen_active = false;
+                    let (x, y) = window_to_frame(
+                        self,
+                        event.event_x as f64 / 65536.0,
+                        event.event_y as f64 / 65536.0,
+                    );
+                    let (x, x_frac) = split_coordinate(x);
+                    let (y, y_frac) = split_coordinate(y);
+                    let eventmove = tunnel::EventMove {
+                        x,
+                        y,
+                        x_frac,
+                        y_frac,
+                    };
+                    let msg_event = tunnel::MessageClient {
                         msg: Some(tunnel::message_client::Msg::Move(eventmove)),
-                    });
+                    };
+                    match self.smooth_pointer {
+                        true => events.push(msg_event),
+                        false => last_move = Some(msg_event),
+                    }
                 }
 
+                Event::XinputTouchBegin(event) => events.push(touch_event(
+                    self,
+                    event.detail,
+                    event.event_x,
+                    event.event_y,
+                    tunnel::TouchPhase::Begin,
+                )),
+                Event::XinputTouchUpdate(event) => events.push(touch_event(
+                    self,
+                    event.detail,
+                    event.event_x,
+                    event.event_y,
+                    tunnel::TouchPhase::Update,
+                )),
+                Event::XinputTouchEnd(event) => events.push(touch_event(
+                    self,
+                    event.detail,
+                    event.event_x,
+                    event.event_y,
+                    tunnel::TouchPhase::End,
+                )),
+
                 Event::ButtonPress(event) => {
                     trace!("Mouse button down {}", event.detail);
+                    if self.pen_active {
+                        continue;
+                    }
+                    let (x, y) = window_to_frame(self, event.event_x as f64, event.event_y as f64);
                     let eventbutton = tunnel::EventButton {
-                        x: event.event_x as u32,
-                        y: event.event_y as u32,
+                        x: x as u32,
+                        y: y as u32,
                         button: event.detail as u32,
                         updown: true,
                     };
@@ -821,9 +1416,13 @@ impl Client for ClientInfo {
                 }
                 Event::ButtonRelease(event) => {
                     trace!("Mouse button up {}", event.detail);
+                    if self.pen_active {
+                        continue;
+                    }
+                    let (x, y) = window_to_frame(self, event.event_x as f64, event.event_y as f64);
                     let eventbutton = tunnel::EventButton {
-                        x: event.event_x as u32,
-                        y: event.event_y as u32,
+                        x: x as u32,
+                        y: y as u32,
                         button: event.detail as u32,
                         updown: false,
                     };
@@ -837,39 +1436,16 @@ impl Client for ClientInfo {
                     trace!("Key down {:?}", event.detail as u32 & 0xFF);
                     self.keys_state[(event.detail as u32 & 0xFF) as usize] = true;
 
-                    let mut skip_key = false;
-                    // If Ctrl alt shift s => Generate toggle server logs
-                    if event.detail == KEY_S as u8 {
-                        // Ctrl Shift Alt
-                        if self.keys_state[KEY_CTRL]
-                            && self.keys_state[KEY_SHIFT]
-                            && self.keys_state[KEY_ALT]
-                        {
-                            self.display_stats = !self.display_stats;
-                            info!("Toggle server logs");
-                            skip_key = true;
+                    let action = self.hotkeys.action(event.detail as usize, &self.keys_state);
+                    match action {
+                        Some(HotkeyAction::ToggleStats) => {
+                            self.hud_mode = self.hud_mode.next();
+                            info!("HUD mode {:?}", self.hud_mode);
                         }
-                    }
-
-                    // If Ctrl alt shift c => Trig clipboard event
-                    if event.detail == KEY_C as u8 {
-                        // Ctrl Shift Alt
-                        if self.keys_state[KEY_CTRL]
-                            && self.keys_state[KEY_SHIFT]
-                            && self.keys_state[KEY_ALT]
-                        {
+                        Some(HotkeyAction::TriggerClipboard) => {
                             self.clipbard_trig = true;
-                            skip_key = true;
                         }
-                    }
-
-                    // If Ctrl alt shift h => toggle grab keyboard
-                    if event.detail == KEY_H as u8 {
-                        // Ctrl Shift Alt
-                        if self.keys_state[KEY_CTRL]
-                            && self.keys_state[KEY_SHIFT]
-                            && self.keys_state[KEY_ALT]
-                        {
+                        Some(HotkeyAction::ToggleGrab) => {
                             if self.grab_keyboard {
                                 let mut events_focus =
                                     focus_out(self).context("Cannot focus out")?;
@@ -880,9 +1456,50 @@ impl Client for ClientInfo {
 
                             self.grab_keyboard = !self.grab_keyboard;
                             info!("Toggle ungrab Keyboard {}", self.grab_keyboard);
-                            skip_key = true;
                         }
+                        Some(HotkeyAction::ToggleRelative) => {
+                            if !self.relative_mouse_allowed {
+                                warn!("Relative mouse mode is not available");
+                            } else if self.relative_mouse {
+                                relative_mouse_off(self).context("Cannot leave relative mode")?;
+                                self.relative_mouse = false;
+                            } else if let Err(err) = relative_mouse_on(self) {
+                                // Another client may hold the pointer grab
+                                warn!("Cannot enter relative mouse mode");
+                                err.chain().for_each(|cause| warn!(" - due to {}", cause));
+                            } else {
+                                self.relative_mouse = true;
+                            }
+                            info!("Toggle relative mouse {}", self.relative_mouse);
+                        }
+                        Some(HotkeyAction::CycleScale) => {
+                            if self.seamless {
+                                warn!("Scaling is not available in seamless");
+                            } else {
+                                self.scale_mode = self.scale_mode.next();
+                                self.need_update = true;
+                                info!("Scale mode {:?}", self.scale_mode);
+                            }
+                        }
+                        Some(HotkeyAction::Fullscreen) => {
+                            if self.seamless {
+                                warn!("Full screen is not available in seamless");
+                            } else if let Err(err) = toggle_fullscreen(self) {
+                                error!("Cannot toggle full screen");
+                                err.chain().for_each(|cause| error!(" - due to {}", cause));
+                            }
+                        }
+                        Some(HotkeyAction::ToggleMute) => {
+                            self.audio_muted = !self.audio_muted;
+                            info!("Audio muted {}", self.audio_muted);
+                        }
+                        Some(HotkeyAction::ToggleChroma) => {
+                            self.chroma_toggled = !self.chroma_toggled;
+                            info!("Chroma toggled {}", self.chroma_toggled);
+                        }
+                        None => {}
                     }
+                    let skip_key = action.is_some();
 
                     if !skip_key {
                         let eventkey = tunnel::EventKey {
@@ -971,7 +1588,7 @@ impl Client for ClientInfo {
         }
 
         /* Get clipboard events */
-        if let Some(data) = get_clipboard_events(&self.clipboard_event_receiver) {
+        if let Some(data) = self.clipboard_notifier.take() {
             self.clipboard_last_value = Some(data);
         }
 
@@ -983,7 +1600,9 @@ impl Client for ClientInfo {
 
             ClipboardConfig::Allow => {
                 if let Some(data) = self.clipboard_last_value.take() {
-                    let eventclipboard = tunnel::EventClipboard { data };
+                    let eventclipboard = tunnel::EventClipboard {
+                        content: Some(data),
+                    };
                     let clipboard_msg = tunnel::MessageClient {
                         msg: Some(tunnel::message_client::Msg::Clipboard(eventclipboard)),
                     };
@@ -995,7 +1614,7 @@ impl Client for ClientInfo {
                 if let (true, Some(ref data)) = (self.clipbard_trig, &self.clipboard_last_value) {
                     // If we triggered clipboard send and the clipboard is not empty
                     let eventclipboard = tunnel::EventClipboard {
-                        data: data.to_owned(),
+                        content: Some(data.to_owned()),
                     };
                     let clipboard_msg = tunnel::MessageClient {
                         msg: Some(tunnel::message_client::Msg::Clipboard(eventclipboard)),
@@ -1009,12 +1628,56 @@ impl Client for ClientInfo {
         Ok(tunnel::MessagesClient { msgs: events })
     }
 
-    fn display_stats(&self) -> bool {
-        self.display_stats
+    fn hud_mode(&self) -> HudMode {
+        self.hud_mode
+    }
+
+    fn audio_muted(&self) -> bool {
+        self.audio_muted
     }
 
-    fn printfile(&self, file: &str) -> Result<()> {
-        info!("Print file {:?}", file);
+    fn chroma_toggled(&self) -> bool {
+        self.chroma_toggled
+    }
+
+    fn printfile(&self, path: &Path) -> Result<()> {
+        info!("Open document {:?}", path);
+        let mut viewer = Command::new("xdg-open")
+            .arg(path)
+            .spawn()
+            .context("Cannot run xdg-open")?;
+        thread::spawn(move || viewer.wait());
         Ok(())
     }
+
+    fn dpi(&self) -> u32 {
+        // The Xft.dpi resource holds the desktop scaling, the screen physical
+        // size is often made up
+        let resources = self
+            .conn
+            .get_property(
+                false,
+                self.root,
+                AtomEnum::RESOURCE_MANAGER,
+                AtomEnum::STRING,
+                0,
+                0xFFFF,
+            )
+            .ok()
+            .and_then(|cookie| cookie.reply().ok());
+        if let Some(dpi) =
+            resources.and_then(|resources| xft_dpi(&String::from_utf8_lossy(&resources.value)))
+        {
+            return dpi;
+        }
+        let screen = &self.conn.setup().roots[self.screen_num];
+        if screen.width_in_millimeters == 0 {
+            return 0;
+        }
+        (screen.width_in_pixels as f32 * 25.4 / screen.width_in_millimeters as f32).round() as u32
+    }
+
+    fn keyboard_layout(&self) -> Option<String> {
+        utils_x11::keyboard_layout(&self.conn, self.root)
+    }
 }
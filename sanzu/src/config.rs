@@ -1,5 +1,31 @@
+use crate::{
+    app_catalog::AppCatalog,
+    client_utils::Hotkeys,
+    server_utils::InputFilter,
+    sound::{AudioFormat, OpusSettings},
+    utils::{ClipboardSelection, DEFAULT_NOTIFICATION_MAX_LENGTH},
+    video_encoder::{Chroma, GopSettings},
+    yuv_rgb_rs::{ColorRange, YuvType},
+};
+use anyhow::Result;
+use sanzu_common::{
+    config_check::Problems, config_override::parse_config, config_reload::log_reload,
+    tls_helper::TlsPolicy,
+};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs::File, io, io::Read, path::Path};
+use std::{collections::HashMap, fmt, fs::File, io::Read, path::Path};
+
+/// Prefix of the environment variables overriding the configuration keys
+pub const CONFIG_ENV_PREFIX: &str = "SANZU_";
+
+/// Keys of the server configuration applied to the running session on
+/// reload (SIGHUP)
+pub const SERVER_HOT_KEYS: &[&str] = &[
+    "video.max_fps",
+    "video.max_stall_img",
+    "video.bitrate_control",
+    "clipboard",
+];
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ConfigTls {
@@ -11,12 +37,51 @@ pub struct ConfigTls {
     pub auth_key: String,
     /// List of domains to authenticate clients
     pub allowed_client_domains: Option<Vec<String>>,
+    /// Minimum tls protocol version: "1.2" or "1.3" (default: 1.2)
+    pub min_version: Option<String>,
+    /// Allowed cipher suites (default: all the supported ones)
+    pub cipher_suites: Option<Vec<String>>,
+}
+
+impl ConfigTls {
+    pub fn policy(&self) -> anyhow::Result<TlsPolicy> {
+        TlsPolicy::new(self.min_version.as_deref(), self.cipher_suites.as_deref())
+    }
+
+    fn validate(&self, problems: &mut Problems) {
+        problems.check_file("tls.ca_file", &self.ca_file);
+        problems.check_file("tls.auth_cert", &self.auth_cert);
+        problems.check_file("tls.auth_key", &self.auth_key);
+        if let Some(crl_file) = &self.crl_file {
+            problems.check_file("tls.crl_file", crl_file);
+        }
+        if let Some(ocsp_file) = &self.ocsp_file {
+            problems.check_file("tls.ocsp_file", ocsp_file);
+        }
+        problems.check_result("tls", self.policy());
+    }
+}
+
+/// Tls settings of the client
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigClientTls {
+    /// Minimum tls protocol version: "1.2" or "1.3" (default: 1.2)
+    pub min_version: Option<String>,
+    /// Allowed cipher suites (default: the rustls default ones)
+    pub cipher_suites: Option<Vec<String>>,
+}
+
+impl ConfigClientTls {
+    pub fn policy(&self) -> anyhow::Result<TlsPolicy> {
+        TlsPolicy::new(self.min_version.as_deref(), self.cipher_suites.as_deref())
+    }
 }
 
 /// Holds configuration for the video frame behavior
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Video {
-    /// Max frame rate
+    /// Max frame rate (0 for no limit)
     pub max_fps: u64,
     /// Max identical frames to wait before stopping sending frame to the encoder / client.
     ///
@@ -38,6 +103,213 @@ pub struct Video {
     pub ffmpeg_options_cmd: Option<String>,
     /// Socket control path
     pub control_path: Option<String>,
+    /// Number of consecutive empty or failed frames before the encoder is
+    /// considered misconfigured (default: 25). The frames held by the encoder
+    /// (lookahead, b frames) are not counted.
+    pub max_encoder_failures: Option<u32>,
+    /// Encoders to try, in order, if the configured encoder stops producing
+    /// frames. Only encoders of the same category (h264, hevc, ...) as the
+    /// configured one are used, as the client decoder is already set up.
+    pub fallback_encoders: Option<Vec<String>>,
+    /// Color matrix used to convert frames to yuv (default: ItuT871). The
+    /// client falls back to ItuT871 if it doesn't support it.
+    pub color_space: Option<YuvType>,
+    /// Range of the yuv samples (default: the one defined by `color_space`,
+    /// which is Full for ItuT871 and Limited for the others)
+    pub color_range: Option<ColorRange>,
+    /// Pixel layout of the frames read from the external image source
+    /// (default: Rgba)
+    pub extern_img_format: Option<ImgSourceFormat>,
+    /// Adjust the encoder bitrate to the client link (disabled if not set)
+    pub bitrate_control: Option<BitrateControl>,
+    /// Encoders to try, in order, at startup. The first one which can be
+    /// opened is used. Overrides the encoder command line argument.
+    pub encoder: Option<Vec<String>>,
+    /// Device used by vaapi encoders (default: /dev/dri/renderD128)
+    pub vaapi_device: Option<String>,
+    /// Frames between two periodic keyframes, 0 for intra only (default: the
+    /// ffmpeg `g` option). Keyframes requested by the client are sent in
+    /// addition, so a long gop doesn't delay the recovery of a client.
+    pub gop_size: Option<u32>,
+    /// Max consecutive b frames (default: the ffmpeg `bf` option). Use 0 for
+    /// low latency, as b frames delay the output of the encoder.
+    pub max_b_frames: Option<u32>,
+    /// Policy when the grab, the encoder or the link can't keep up with
+    /// `max_fps` (default: Latest)
+    pub frame_drop: Option<FrameDrop>,
+    /// Split frames in horizontal tiles encoded in parallel, each by its own
+    /// encoder, to lower the encoding latency of large resolutions (default:
+    /// 1, no tiles)
+    pub tiles: Option<u32>,
+    /// Number of custom RandR modes rotated by the resolution changes, as the
+    /// mode in use cannot be modified (default and minimum: 2). More modes
+    /// keep fast successive changes from reusing a mode being deleted.
+    pub video_modes: Option<usize>,
+    /// Largest resolution (width, height) granted to the clients, below the
+    /// limits of the server screen (default: no other limit)
+    pub max_resolution: Option<(u32, u32)>,
+    /// Chroma subsampling proposed to the client: "420" saves bandwidth, "444"
+    /// keeps colored text legible (default: the encoder pixel format). The
+    /// client can request another one, and switch it during the session.
+    pub chroma: Option<Chroma>,
+    /// Switch the chroma subsampling from the frame content: 4:4:4 for text,
+    /// 4:2:0 for video (default: false). Only used by the x11 server, until
+    /// the client requests a chroma.
+    pub adaptive_chroma: Option<bool>,
+    /// Encode the areas changed since the previous frame with more bits than
+    /// the static ones (disabled if not set). Only used by the x11 server, and
+    /// by the encoders honoring the regions of interest.
+    pub roi: Option<RegionsOfInterest>,
+    /// Encode a discarded blank image at session start, so that the encoder
+    /// initialization doesn't delay the first frame of the client (default:
+    /// true)
+    pub warm_up: Option<bool>,
+}
+
+impl Video {
+    pub fn gop_settings(&self) -> GopSettings {
+        GopSettings {
+            gop_size: self.gop_size,
+            max_b_frames: self.max_b_frames,
+        }
+    }
+
+    fn validate(&self, problems: &mut Problems) {
+        problems.check(
+            "video.max_encoder_failures",
+            self.max_encoder_failures != Some(0),
+            "must be at least 1",
+        );
+        problems.check(
+            "video.encoder",
+            !matches!(&self.encoder, Some(encoders) if encoders.is_empty()),
+            "must list at least one encoder",
+        );
+        problems.check("video.tiles", self.tiles != Some(0), "must be at least 1");
+        problems.check(
+            "video.video_modes",
+            !matches!(self.video_modes, Some(modes) if modes < 2),
+            "must be at least 2",
+        );
+        if let Some((width, height)) = self.max_resolution {
+            problems.check(
+                "video.max_resolution",
+                width > 0 && height > 0,
+                format!("{width}x{height} is empty"),
+            );
+        }
+        if let Some(bitrate) = &self.bitrate_control {
+            problems.check(
+                "video.bitrate_control",
+                bitrate.min_kbps <= bitrate.max_kbps,
+                format!(
+                    "min_kbps ({}) is above max_kbps ({})",
+                    bitrate.min_kbps, bitrate.max_kbps
+                ),
+            );
+            problems.check(
+                "video.bitrate_control.step_kbps",
+                bitrate.step_kbps > 0,
+                "must be at least 1",
+            );
+        }
+        if let Some(roi) = &self.roi {
+            for (key, qoffset) in [
+                ("video.roi.active_qoffset", roi.active_qoffset),
+                ("video.roi.static_qoffset", roi.static_qoffset),
+            ] {
+                problems.check(
+                    key,
+                    qoffset.map_or(true, |qoffset| (-1.0..=1.0).contains(&qoffset)),
+                    "must be between -1 and 1",
+                );
+            }
+        }
+        if let Some(vaapi_device) = &self.vaapi_device {
+            problems.check(
+                "video.vaapi_device",
+                Path::new(vaapi_device).exists(),
+                format!("{vaapi_device:?} does not exist"),
+            );
+        }
+        if let Some(control_path) = &self.control_path {
+            let dir = match Path::new(control_path).parent() {
+                Some(dir) if dir != Path::new("") => dir,
+                _ => Path::new("."),
+            };
+            problems.check(
+                "video.control_path",
+                dir.is_dir(),
+                format!("directory {dir:?} does not exist"),
+            );
+        }
+    }
+}
+
+/// Holds configuration for the encoder bitrate controller
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BitrateControl {
+    /// Lowest target bitrate (kbps)
+    pub min_kbps: u32,
+    /// Highest target bitrate (kbps), also used as initial target
+    pub max_kbps: u32,
+    /// Bitrate change per adjustment (kbps)
+    pub step_kbps: u32,
+    /// Frame receive time over the frame period above which the link is
+    /// considered congested (ms)
+    pub max_latency_ms: u32,
+}
+
+/// Holds configuration for the regions of interest encoding
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegionsOfInterest {
+    /// Quantizer offset of the changed areas, from -1 (best quality) to 1
+    /// (lowest quality) (default: -0.1)
+    pub active_qoffset: Option<f32>,
+    /// Quantizer offset of the static areas (default: 0.1)
+    pub static_qoffset: Option<f32>,
+}
+
+impl RegionsOfInterest {
+    /// Quantizer offsets of the changed and static areas
+    pub fn qoffsets(&self) -> (f32, f32) {
+        (
+            self.active_qoffset.unwrap_or(-0.1),
+            self.static_qoffset.unwrap_or(0.1),
+        )
+    }
+}
+
+/// Frame dropping policy of the server loop, when it is late on the frame rate
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FrameDrop {
+    /// Drop the late frames, and encode the latest grab. Keeps the latency low
+    /// on slow links.
+    #[default]
+    Latest,
+    /// Encode a frame for each period, catching up the late ones. Keeps the
+    /// frame timing, for recording.
+    Never,
+}
+
+/// Pixel layout of the external image source frames
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImgSourceFormat {
+    /// 32 bits per pixel rgba
+    #[default]
+    Rgba,
+    /// Packed yuv 4:2:2, in the negotiated color space
+    Yuyv,
+}
+
+/// Audio codec proposed to the client
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioCodec {
+    #[default]
+    Opus,
+    /// 16 bits pcm, for local networks
+    Raw,
 }
 
 /// Holds configuration for the audio timings
@@ -45,6 +317,49 @@ pub struct Video {
 pub struct Audio {
     /// Server side buffer size
     pub max_buffer_ms: u64,
+    /// Codec proposed to the client (default: opus)
+    pub codec: Option<AudioCodec>,
+    /// Opus bitrate, in kbps (default: chosen by the encoder)
+    pub bitrate_kbps: Option<u32>,
+    /// Duration of the opus frames, in ms: 5, 10, 20 or 30 (default: 20)
+    pub frame_ms: Option<u32>,
+    /// Sample rate requested to the client (default: chosen by the client)
+    pub sample_rate: Option<u32>,
+    /// Channel count requested to the client (default: chosen by the client)
+    pub channels: Option<u16>,
+    /// Client playback buffer length, overriding the client one
+    pub buffer_ms: Option<u32>,
+}
+
+impl Audio {
+    pub fn opus_settings(&self) -> anyhow::Result<OpusSettings> {
+        OpusSettings::new(self.bitrate_kbps, self.frame_ms)
+    }
+
+    pub fn check_format(&self) -> anyhow::Result<()> {
+        AudioFormat::check(self.sample_rate, self.channels)?;
+        if self.buffer_ms == Some(0) {
+            return Err(anyhow!("Audio buffer length must not be null"));
+        }
+        Ok(())
+    }
+}
+
+/// Playback of the client microphone on the server
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Microphone {
+    /// Output device receiving the microphone sound, whose loopback is used
+    /// as a source by the applications (ex: a PulseAudio null sink)
+    pub device: String,
+    /// Playback buffer size (default: 150)
+    pub buffer_ms: Option<u32>,
+}
+
+/// Injection of the client pen events through a virtual tablet (linux only)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Pen {
+    /// Uinput device (default: /dev/uinput)
+    pub device: Option<String>,
 }
 
 /// Holds configuration for the shm video export
@@ -58,6 +373,231 @@ pub struct ExportVideoPci {
     pub device: String,
 }
 
+/// Holds configuration for the seamless windows (areas)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Seamless {
+    /// Windows with a width or height below this value are not sent to the
+    /// client (default: 2). Empty windows are never sent.
+    pub min_area_size: Option<u16>,
+    /// Don't send windows which are fully outside of the screen (default: true)
+    pub cull_offscreen: Option<bool>,
+}
+
+/// Application the client can launch
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct App {
+    pub name: String,
+    /// Absolute path of the executable
+    pub exec: String,
+    pub args: Option<Vec<String>>,
+    /// Icon name or path, sent as is to the client
+    pub icon: Option<String>,
+}
+
+/// X server spawned for the session
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VirtualDisplayServer {
+    /// Fixed resolution
+    #[default]
+    Xvfb,
+    /// Xorg with the dummy video driver, which follows the client resolution
+    Xorg,
+}
+
+/// Holds configuration for the virtual display
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VirtualDisplay {
+    /// X server (default: Xvfb)
+    pub server: Option<VirtualDisplayServer>,
+    /// Path of the X server executable (default: Xvfb or Xorg)
+    pub command: Option<String>,
+    /// Display name, ex ":99" (default: the first free one)
+    pub display: Option<String>,
+    /// Initial resolution
+    pub width: u16,
+    pub height: u16,
+    /// Color depth (default: 24)
+    pub depth: Option<u8>,
+    /// Largest resolution reachable with the Xorg dummy driver (default:
+    /// 3840x2160)
+    pub max_width: Option<u16>,
+    pub max_height: Option<u16>,
+}
+
+/// Direction in which a clipboard selection is exchanged
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClipboardDirection {
+    #[default]
+    Both,
+    /// Client to server only
+    ToServer,
+    /// Server to client only
+    ToClient,
+    None,
+}
+
+impl ClipboardDirection {
+    fn from_flags(to_server: bool, to_client: bool) -> Self {
+        match (to_server, to_client) {
+            (true, true) => ClipboardDirection::Both,
+            (true, false) => ClipboardDirection::ToServer,
+            (false, true) => ClipboardDirection::ToClient,
+            (false, false) => ClipboardDirection::None,
+        }
+    }
+
+    pub fn to_server(self) -> bool {
+        matches!(
+            self,
+            ClipboardDirection::Both | ClipboardDirection::ToServer
+        )
+    }
+
+    pub fn to_client(self) -> bool {
+        matches!(
+            self,
+            ClipboardDirection::Both | ClipboardDirection::ToClient
+        )
+    }
+
+    /// Direction allowed by both `self` and `other`
+    pub fn intersect(self, other: ClipboardDirection) -> Self {
+        ClipboardDirection::from_flags(
+            self.to_server() && other.to_server(),
+            self.to_client() && other.to_client(),
+        )
+    }
+}
+
+impl fmt::Display for ClipboardDirection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            ClipboardDirection::Both => "both ways",
+            ClipboardDirection::ToServer => "client to server only",
+            ClipboardDirection::ToClient => "server to client only",
+            ClipboardDirection::None => "disabled",
+        };
+        f.write_str(text)
+    }
+}
+
+/// Allowed clipboard directions, for each selection. Each peer applies its
+/// own policy to its selections: values in a blocked direction are silently
+/// dropped.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClipboardPolicy {
+    /// "Clipboard" selection (copy / paste), the only one on Windows
+    #[serde(default)]
+    pub clipboard: ClipboardDirection,
+    /// "Primary" selection (x11 mouse selection / middle click paste)
+    #[serde(default)]
+    pub primary: ClipboardDirection,
+}
+
+impl ClipboardPolicy {
+    pub fn direction(&self, selection: ClipboardSelection) -> ClipboardDirection {
+        match selection {
+            ClipboardSelection::Clipboard => self.clipboard,
+            ClipboardSelection::Primary => self.primary,
+        }
+    }
+
+    /// Directions allowed by both `self` and `other`
+    pub fn intersect(&self, other: &ClipboardPolicy) -> Self {
+        ClipboardPolicy {
+            clipboard: self.clipboard.intersect(other.clipboard),
+            primary: self.primary.intersect(other.primary),
+        }
+    }
+
+    /// True if a direction is blocked
+    pub fn is_restricted(&self) -> bool {
+        *self != ClipboardPolicy::default()
+    }
+
+    /// Remove the server to client directions
+    pub fn without_to_client(&self) -> Self {
+        self.intersect(&ClipboardPolicy {
+            clipboard: ClipboardDirection::ToServer,
+            primary: ClipboardDirection::ToServer,
+        })
+    }
+}
+
+impl fmt::Display for ClipboardPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.clipboard == self.primary {
+            write!(f, "{}", self.clipboard)
+        } else {
+            write!(f, "clipboard {}, primary {}", self.clipboard, self.primary)
+        }
+    }
+}
+
+/// Holds the clipboard configuration
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigClipboard {
+    /// Allowed directions (default: both ways for all selections)
+    pub policy: Option<ClipboardPolicy>,
+}
+
+/// Holds the forwarding configuration of the desktop notifications
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigNotifications {
+    /// Forward only the notifications of these applications (default: all)
+    pub allow: Option<Vec<String>>,
+    /// Never forward the notifications of these applications
+    pub deny: Option<Vec<String>>,
+    /// Maximum length of the title and message texts, in characters
+    /// (default: 1024)
+    pub max_length: Option<usize>,
+}
+
+impl ConfigNotifications {
+    /// True if the notifications of `app` are forwarded. Application names
+    /// are case insensitive
+    pub fn forwarded(&self, app: &str) -> bool {
+        let listed = |apps: &Option<Vec<String>>| {
+            apps.iter()
+                .flatten()
+                .any(|name| name.eq_ignore_ascii_case(app))
+        };
+        !listed(&self.deny) && (self.allow.is_none() || listed(&self.allow))
+    }
+
+    /// Maximum length of the notification texts, in characters
+    pub fn max_length(&self) -> usize {
+        self.max_length.unwrap_or(DEFAULT_NOTIFICATION_MAX_LENGTH)
+    }
+}
+
+/// Action of an input rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputAction {
+    /// The key is not injected
+    Block,
+    /// The key is injected as is, even if a later rule matches
+    Allow,
+    /// The `remap` key is injected instead
+    Remap,
+}
+
+/// Rule applied to the client keys before their injection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InputRule {
+    /// Key, with the modifiers held, in the hotkeys syntax (ex: "ctrl+alt+f1")
+    pub keys: String,
+    pub action: InputAction,
+    /// Key injected by the remap action (ex: "alt")
+    pub remap: Option<String>,
+}
+
 /// Support authentication mecanism
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "type", content = "args")]
@@ -75,9 +615,24 @@ pub enum AuthType {
 pub struct ConfigServer {
     pub video: Video,
     pub audio: Audio,
+    /// Client microphone forwarding, disabled if unset
+    pub microphone: Option<Microphone>,
+    /// Client pen forwarding, disabled if unset
+    pub pen: Option<Pen>,
     pub export_video_pci: Option<ExportVideoPci>,
+    pub seamless: Option<Seamless>,
     pub tls: Option<ConfigTls>,
     pub auth_type: Option<AuthType>,
+    /// Application catalog advertised to the client
+    pub apps: Option<Vec<App>>,
+    /// X server started if no display is reachable (x11 only)
+    pub virtual_display: Option<VirtualDisplay>,
+    /// Clipboard exchange policy
+    pub clipboard: Option<ConfigClipboard>,
+    /// Desktop notifications forwarded to the client (x11, "notify" feature)
+    pub notifications: Option<ConfigNotifications>,
+    /// Filter of the client keys, the first matching rule applies
+    pub input_rules: Option<Vec<InputRule>>,
     /// Holds codecs configuration.
     ///
     /// For each codec name, stores the HashMap which links codec property to
@@ -86,6 +641,79 @@ pub struct ConfigServer {
 }
 
 impl ConfigServer {
+    /// Check the configuration, reporting all the problems at once.
+    /// `export_video_pci` is the command line option, which needs the
+    /// `export_video_pci` section.
+    pub fn validate(&self, export_video_pci: bool) -> Result<()> {
+        let mut problems = Problems::new();
+        self.video.validate(&mut problems);
+        problems.check_result("audio", self.audio.opus_settings());
+        problems.check_result("audio", self.audio.check_format());
+        if let Some(microphone) = &self.microphone {
+            problems.check(
+                "microphone.buffer_ms",
+                microphone.buffer_ms != Some(0),
+                "must be at least 1",
+            );
+        }
+        problems.check(
+            "export_video_pci",
+            !export_video_pci || self.export_video_pci.is_some(),
+            "section required by --export-video-pci",
+        );
+        if let Some(tls) = &self.tls {
+            tls.validate(&mut problems);
+        }
+        if let Some(apps) = &self.apps {
+            problems.check_result("apps", AppCatalog::new(apps));
+        }
+        if let Some(display) = &self.virtual_display {
+            problems.check(
+                "virtual_display",
+                display.width > 0 && display.height > 0,
+                format!("{}x{} is empty", display.width, display.height),
+            );
+        }
+        if let Some(notifications) = &self.notifications {
+            problems.check(
+                "notifications.max_length",
+                notifications.max_length != Some(0),
+                "must be at least 1",
+            );
+        }
+        if let Some(input_rules) = &self.input_rules {
+            problems.check_result("input_rules", InputFilter::new(input_rules));
+        }
+        problems.into_result()
+    }
+
+    /// Take the hot settings (`SERVER_HOT_KEYS`) of the reloaded `new`
+    /// configuration, logging the changes. Returns true if a hot setting has
+    /// changed.
+    pub fn apply_reload(&mut self, new: ConfigServer) -> Result<bool> {
+        let changed = log_reload(self, &new, SERVER_HOT_KEYS)?;
+        self.video.max_fps = new.video.max_fps;
+        self.video.max_stall_img = new.video.max_stall_img;
+        self.video.bitrate_control = new.video.bitrate_control;
+        self.clipboard = new.clipboard;
+        Ok(changed)
+    }
+
+    /// Clipboard policy of the server. `restrict_clipboard` blocks the server
+    /// to client direction.
+    pub fn clipboard_policy(&self, restrict_clipboard: bool) -> ClipboardPolicy {
+        let policy = self
+            .clipboard
+            .as_ref()
+            .and_then(|clipboard| clipboard.policy)
+            .unwrap_or_default();
+        if restrict_clipboard {
+            policy.without_to_client()
+        } else {
+            policy
+        }
+    }
+
     pub fn ffmpeg_options(
         &self,
         codec: Option<&str>,
@@ -96,11 +724,12 @@ impl ConfigServer {
     }
 }
 
-/// Read configuration from a file
-pub fn read_server_config<P: AsRef<Path>>(path: P) -> io::Result<ConfigServer> {
+/// Read configuration from a file, overridden by the environment and the
+/// `key=value` `sets`
+pub fn read_server_config<P: AsRef<Path>>(path: P, sets: &[String]) -> Result<ConfigServer> {
     let mut content = String::new();
     File::open(path)?.read_to_string(&mut content)?;
-    toml::from_str(&content).map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+    parse_config(&content, CONFIG_ENV_PREFIX, sets)
 }
 
 /// Server configuration
@@ -111,9 +740,80 @@ pub struct ConfigClient {
     /// For each codec name, stores the HashMap which links codec property to
     /// its value
     pub ffmpeg: HashMap<String, HashMap<String, String>>,
+    /// Rendering backend of the Windows client (default: d3d9)
+    pub renderer: Option<Renderer>,
+    /// Windows client only: delay (ms) without a new frame after which the
+    /// last frame is presented again with a frozen indicator, at the same
+    /// interval (disabled if unset)
+    pub frame_hold_ms: Option<u64>,
+    /// Client hotkeys
+    pub hotkeys: Option<ConfigHotkeys>,
+    /// Tls protocol restrictions
+    pub tls: Option<ConfigClientTls>,
+    /// Clipboard exchange policy
+    pub clipboard: Option<ConfigClipboard>,
+}
+
+/// Client hotkeys: key names joined with "+", the last key triggers the
+/// action while the others are held (ex: "ctrl+alt+shift+s"). Unset actions
+/// keep their default hotkey, an empty string disables the action.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigHotkeys {
+    /// Cycle the HUD mode (default: ctrl+alt+shift+s)
+    pub toggle_stats: Option<String>,
+    /// Send the clipboard in trigger mode (default: ctrl+alt+shift+c)
+    pub trigger_clipboard: Option<String>,
+    /// Toggle the keyboard grab (default: ctrl+alt+shift+h)
+    pub toggle_grab: Option<String>,
+    /// Toggle the relative mouse mode (default: ctrl+alt+shift+r)
+    pub toggle_relative: Option<String>,
+    /// Cycle the frame scaling modes (default: ctrl+alt+shift+d)
+    pub cycle_scale: Option<String>,
+    /// Toggle the client window full screen (default: ctrl+alt+shift+f)
+    pub fullscreen: Option<String>,
+    /// Mute or unmute the audio (default: ctrl+alt+shift+m)
+    pub toggle_mute: Option<String>,
+    /// Switch the chroma subsampling between 4:2:0 and 4:4:4 (default:
+    /// ctrl+alt+shift+y)
+    pub toggle_chroma: Option<String>,
+}
+
+/// Rendering backend of the Windows client
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Renderer {
+    /// Direct3D 9 offscreen surface, stretched to the back buffer
+    #[default]
+    D3d9,
+    /// Direct3D 11 dynamic texture, copied to a flip model swap chain
+    D3d11,
 }
 
 impl ConfigClient {
+    /// Check the configuration, reporting all the problems at once
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Problems::new();
+        problems.check(
+            "frame_hold_ms",
+            self.frame_hold_ms != Some(0),
+            "must be at least 1",
+        );
+        problems.check_result("hotkeys", Hotkeys::new(self.hotkeys.as_ref()));
+        if let Some(tls) = &self.tls {
+            problems.check_result("tls", tls.policy());
+        }
+        problems.into_result()
+    }
+
+    /// Clipboard policy of the client
+    pub fn clipboard_policy(&self) -> ClipboardPolicy {
+        self.clipboard
+            .as_ref()
+            .and_then(|clipboard| clipboard.policy)
+            .unwrap_or_default()
+    }
+
     pub fn ffmpeg_options(
         &self,
         codec: Option<&str>,
@@ -124,11 +824,12 @@ impl ConfigClient {
     }
 }
 
-/// Read configuration from a file
-pub fn read_client_config<P: AsRef<Path>>(path: P) -> io::Result<ConfigClient> {
+/// Read configuration from a file, overridden by the environment and the
+/// `key=value` `sets`
+pub fn read_client_config<P: AsRef<Path>>(path: P, sets: &[String]) -> Result<ConfigClient> {
     let mut content = String::new();
     File::open(path)?.read_to_string(&mut content)?;
-    toml::from_str(&content).map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+    parse_config(&content, CONFIG_ENV_PREFIX, sets)
 }
 
 #[cfg(test)]
@@ -140,6 +841,14 @@ mod tests {
 [video]
 max_fps = 60
 max_stall_img = 30
+gop_size = 0
+max_b_frames = 0
+
+[video.bitrate_control]
+min_kbps = 500
+max_kbps = 4000
+step_kbps = 250
+max_latency_ms = 50
 
 [audio]
 sample_rate = 44100
@@ -158,5 +867,84 @@ tune = "zerolatency"
     fn test_conf() {
         let config: ConfigServer = toml::from_str(&CONF).unwrap();
         dbg!(&config);
+        assert_eq!(
+            config.video.gop_settings(),
+            GopSettings {
+                gop_size: Some(0),
+                max_b_frames: Some(0),
+            }
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config: ConfigServer = toml::from_str(CONF).unwrap();
+        config.validate(false).unwrap();
+
+        // All the problems are reported
+        config.video.tiles = Some(0);
+        config.video.bitrate_control.as_mut().unwrap().min_kbps = 8000;
+        config.video.roi = Some(RegionsOfInterest {
+            active_qoffset: Some(-2.0),
+            static_qoffset: None,
+        });
+        let err = config.validate(true).unwrap_err().to_string();
+        assert!(err.starts_with("4 configuration problem(s):"));
+        assert!(err.contains("video.roi.active_qoffset: must be between -1 and 1"));
+        assert!(err.contains("video.tiles: must be at least 1"));
+        assert!(err.contains("export_video_pci: section required by --export-video-pci"));
+    }
+
+    #[test]
+    fn test_clipboard_policy() {
+        let config: ConfigClient = toml::from_str(
+            "[ffmpeg]\n[clipboard.policy]\nclipboard = \"to_server\"\nprimary = \"none\"\n",
+        )
+        .unwrap();
+        let policy = config.clipboard_policy();
+        assert!(policy.direction(ClipboardSelection::Clipboard).to_server());
+        assert!(!policy.direction(ClipboardSelection::Clipboard).to_client());
+        assert_eq!(policy.primary, ClipboardDirection::None);
+        assert!(policy.is_restricted());
+
+        let config: ConfigClient = toml::from_str("[ffmpeg]\n").unwrap();
+        assert!(!config.clipboard_policy().is_restricted());
+        assert_eq!(
+            config.clipboard_policy().without_to_client(),
+            ClipboardPolicy {
+                clipboard: ClipboardDirection::ToServer,
+                primary: ClipboardDirection::ToServer,
+            }
+        );
+        assert_eq!(
+            ClipboardDirection::ToClient.intersect(ClipboardDirection::ToServer),
+            ClipboardDirection::None
+        );
+    }
+
+    #[test]
+    fn test_notifications_filter() {
+        let all = ConfigNotifications::default();
+        assert!(all.forwarded("Firefox"));
+        assert!(all.forwarded("Slack"));
+
+        let config: ConfigNotifications =
+            toml::from_str("allow = [\"firefox\", \"Thunderbird\"]\ndeny = [\"Thunderbird\"]\n")
+                .unwrap();
+        assert!(config.forwarded("Firefox"));
+        assert!(!config.forwarded("Thunderbird"));
+        assert!(!config.forwarded("Slack"));
+
+        let config: ConfigNotifications = toml::from_str("deny = [\"Slack\"]\n").unwrap();
+        assert!(config.forwarded("Teams"));
+        assert!(!config.forwarded("slack"));
+    }
+
+    #[test]
+    fn test_client_renderer() {
+        let config: ConfigClient = toml::from_str("[ffmpeg]\n").unwrap();
+        assert_eq!(config.renderer.unwrap_or_default(), Renderer::D3d9);
+        let config: ConfigClient = toml::from_str("renderer = \"d3d11\"\n[ffmpeg]\n").unwrap();
+        assert_eq!(config.renderer, Some(Renderer::D3d11));
     }
 }
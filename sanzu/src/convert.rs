@@ -0,0 +1,1133 @@
+//! Color space conversion entry points
+//!
+//! Those functions pick the fastest implementation available on the running
+//! cpu: NEON on aarch64, SSSE3 on x86 and scalar otherwise. Cpu features are
+//! detected at runtime on the first call, so a binary built on a recent
+//! machine won't use unsupported instructions on the target.
+//!
+//! Buffers are checked against the image geometry before the conversion, so
+//! a wrong stride is reported as a [`ConvertError`] instead of a panic.
+//!
+//! Red and blue samples of 32 bits images are placed according to the given
+//! [`ByteOrder`]: frames captured by the servers and displayed by the clients
+//! are [`FRAME_BYTE_ORDER`].
+//!
+//! [`convert`] wraps them behind a [`PixelFormat`] pair, for callers which
+//! only know the formats of their frames at runtime.
+use crate::yuv_rgb_rs::{self, ColorRange, YuvType};
+pub use crate::yuv_rgb_rs::{ByteOrder, ConvertError};
+use std::sync::OnceLock;
+
+/// Byte order of the frames grabbed by the servers and of the client
+/// framebuffers
+pub const FRAME_BYTE_ORDER: ByteOrder = ByteOrder::Bgra;
+
+/// Rgba to planar yuv conversion kernel
+pub type RgbaToYuvFn = fn(
+    usize,
+    usize,
+    &[u8],
+    usize,
+    &mut [u8],
+    &mut [u8],
+    &mut [u8],
+    usize,
+    usize,
+    usize,
+    YuvType,
+    ColorRange,
+    ByteOrder,
+);
+
+/// Rgba to semi planar yuv conversion kernel
+pub type RgbaToNv12Fn = fn(
+    usize,
+    usize,
+    &[u8],
+    usize,
+    &mut [u8],
+    &mut [u8],
+    usize,
+    usize,
+    YuvType,
+    ColorRange,
+    ByteOrder,
+);
+
+/// Planar yuv to rgba conversion kernel
+pub type YuvToRgbaFn = fn(
+    usize,
+    usize,
+    &[u8],
+    &[u8],
+    &[u8],
+    usize,
+    usize,
+    usize,
+    &mut [u8],
+    usize,
+    YuvType,
+    ColorRange,
+    ByteOrder,
+);
+
+/// Semi planar yuv to rgba conversion kernel
+pub type Nv12ToRgbaFn =
+    fn(usize, usize, &[u8], &[u8], usize, usize, &mut [u8], usize, YuvType, ColorRange, ByteOrder);
+
+/// Packed yuv 4:2:2 to rgba conversion kernel
+pub type YuyvToRgbaFn =
+    fn(usize, usize, &[u8], usize, &mut [u8], usize, YuvType, ColorRange, ByteOrder);
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn has_ssse3() -> bool {
+    is_x86_feature_detected!("ssse3")
+}
+
+fn select_rgba_to_yuv420() -> RgbaToYuvFn {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let kernel: RgbaToYuvFn = if has_ssse3() {
+        yuv_rgb_rs::rgba_to_yuv420_ssse3_rayon
+    } else {
+        yuv_rgb_rs::rgba_to_yuv420_std
+    };
+    #[cfg(target_arch = "aarch64")]
+    let kernel: RgbaToYuvFn = yuv_rgb_rs::rgba_to_yuv420_neon;
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    let kernel: RgbaToYuvFn = yuv_rgb_rs::rgba_to_yuv420_std_rayon;
+    kernel
+}
+
+fn select_rgba_to_yuv444() -> RgbaToYuvFn {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let kernel: RgbaToYuvFn = if has_ssse3() {
+        yuv_rgb_rs::rgba_to_yuv444_ssse3
+    } else {
+        yuv_rgb_rs::rgba_to_yuv444_std
+    };
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    let kernel: RgbaToYuvFn = yuv_rgb_rs::rgba_to_yuv444_std_rayon;
+    kernel
+}
+
+fn select_rgba_to_nv12() -> RgbaToNv12Fn {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let kernel: RgbaToNv12Fn = if has_ssse3() {
+        yuv_rgb_rs::rgba_to_nv12_ssse3
+    } else {
+        yuv_rgb_rs::rgba_to_nv12_std
+    };
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    let kernel: RgbaToNv12Fn = yuv_rgb_rs::rgba_to_nv12_std;
+    kernel
+}
+
+fn select_rgba_to_nv21() -> RgbaToNv12Fn {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let kernel: RgbaToNv12Fn = if has_ssse3() {
+        yuv_rgb_rs::rgba_to_nv21_ssse3
+    } else {
+        yuv_rgb_rs::rgba_to_nv21_std
+    };
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    let kernel: RgbaToNv12Fn = yuv_rgb_rs::rgba_to_nv21_std;
+    kernel
+}
+
+fn select_yuv420_to_rgba() -> YuvToRgbaFn {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let kernel: YuvToRgbaFn = if has_ssse3() {
+        yuv_rgb_rs::yuv420_to_rgba_ssse3
+    } else {
+        yuv_rgb_rs::yuv420_to_rgba_std
+    };
+    #[cfg(target_arch = "aarch64")]
+    let kernel: YuvToRgbaFn = yuv_rgb_rs::yuv420_to_rgba_neon;
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    let kernel: YuvToRgbaFn = yuv_rgb_rs::yuv420_to_rgba_std;
+    kernel
+}
+
+fn select_yuv444_to_rgba() -> YuvToRgbaFn {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let kernel: YuvToRgbaFn = if has_ssse3() {
+        yuv_rgb_rs::yuv444_to_rgba_ssse3
+    } else {
+        yuv_rgb_rs::yuv444_to_rgba_std
+    };
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    let kernel: YuvToRgbaFn = yuv_rgb_rs::yuv444_to_rgba_std;
+    kernel
+}
+
+fn select_nv12_to_rgba() -> Nv12ToRgbaFn {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let kernel: Nv12ToRgbaFn = if has_ssse3() {
+        yuv_rgb_rs::nv12_rgba_ssse3
+    } else {
+        yuv_rgb_rs::nv12_rgba_std
+    };
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    let kernel: Nv12ToRgbaFn = yuv_rgb_rs::nv12_rgba_std;
+    kernel
+}
+
+fn select_yuyv_to_rgba() -> YuyvToRgbaFn {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let kernel: YuyvToRgbaFn = if has_ssse3() {
+        yuv_rgb_rs::yuyv_to_rgba_ssse3
+    } else {
+        yuv_rgb_rs::yuyv_to_rgba_std
+    };
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    let kernel: YuyvToRgbaFn = yuv_rgb_rs::yuyv_to_rgba_std;
+    kernel
+}
+
+/// Convert a rgba image to yuv420
+#[allow(clippy::too_many_arguments)]
+pub fn rgba_to_yuv420(
+    width: usize,
+    height: usize,
+    buffer_rgba: &[u8],
+    rgba_stride: usize,
+    buffer_y: &mut [u8],
+    buffer_u: &mut [u8],
+    buffer_v: &mut [u8],
+    y_stride: usize,
+    u_stride: usize,
+    v_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) -> Result<(), ConvertError> {
+    yuv_rgb_rs::check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride)?;
+    yuv_rgb_rs::check_yuv420(
+        width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride,
+    )?;
+    // Kernels expect at least one pixel
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    static KERNEL: OnceLock<RgbaToYuvFn> = OnceLock::new();
+    let convert = KERNEL.get_or_init(select_rgba_to_yuv420);
+    convert(
+        width,
+        height,
+        buffer_rgba,
+        rgba_stride,
+        buffer_y,
+        buffer_u,
+        buffer_v,
+        y_stride,
+        u_stride,
+        v_stride,
+        yuv_type,
+        color_range,
+        byte_order,
+    );
+    Ok(())
+}
+
+/// Convert a rgba image to yuv444
+#[allow(clippy::too_many_arguments)]
+pub fn rgba_to_yuv444(
+    width: usize,
+    height: usize,
+    buffer_rgba: &[u8],
+    rgba_stride: usize,
+    buffer_y: &mut [u8],
+    buffer_u: &mut [u8],
+    buffer_v: &mut [u8],
+    y_stride: usize,
+    u_stride: usize,
+    v_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) -> Result<(), ConvertError> {
+    yuv_rgb_rs::check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride)?;
+    yuv_rgb_rs::check_yuv444(
+        width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride,
+    )?;
+    // Kernels expect at least one pixel
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    static KERNEL: OnceLock<RgbaToYuvFn> = OnceLock::new();
+    let convert = KERNEL.get_or_init(select_rgba_to_yuv444);
+    convert(
+        width,
+        height,
+        buffer_rgba,
+        rgba_stride,
+        buffer_y,
+        buffer_u,
+        buffer_v,
+        y_stride,
+        u_stride,
+        v_stride,
+        yuv_type,
+        color_range,
+        byte_order,
+    );
+    Ok(())
+}
+
+/// Convert a rgba image to nv12
+#[allow(clippy::too_many_arguments)]
+pub fn rgba_to_nv12(
+    width: usize,
+    height: usize,
+    buffer_rgba: &[u8],
+    rgba_stride: usize,
+    buffer_y: &mut [u8],
+    buffer_uv: &mut [u8],
+    y_stride: usize,
+    uv_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) -> Result<(), ConvertError> {
+    yuv_rgb_rs::check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride)?;
+    yuv_rgb_rs::check_semi_planar(width, height, buffer_y, buffer_uv, y_stride, uv_stride)?;
+    // Kernels expect at least one pixel
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    static KERNEL: OnceLock<RgbaToNv12Fn> = OnceLock::new();
+    let convert = KERNEL.get_or_init(select_rgba_to_nv12);
+    convert(
+        width,
+        height,
+        buffer_rgba,
+        rgba_stride,
+        buffer_y,
+        buffer_uv,
+        y_stride,
+        uv_stride,
+        yuv_type,
+        color_range,
+        byte_order,
+    );
+    Ok(())
+}
+
+/// Convert a rgba image to nv21
+#[allow(clippy::too_many_arguments)]
+pub fn rgba_to_nv21(
+    width: usize,
+    height: usize,
+    buffer_rgba: &[u8],
+    rgba_stride: usize,
+    buffer_y: &mut [u8],
+    buffer_vu: &mut [u8],
+    y_stride: usize,
+    vu_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) -> Result<(), ConvertError> {
+    yuv_rgb_rs::check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride)?;
+    yuv_rgb_rs::check_semi_planar(width, height, buffer_y, buffer_vu, y_stride, vu_stride)?;
+    // Kernels expect at least one pixel
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    static KERNEL: OnceLock<RgbaToNv12Fn> = OnceLock::new();
+    let convert = KERNEL.get_or_init(select_rgba_to_nv21);
+    convert(
+        width,
+        height,
+        buffer_rgba,
+        rgba_stride,
+        buffer_y,
+        buffer_vu,
+        y_stride,
+        vu_stride,
+        yuv_type,
+        color_range,
+        byte_order,
+    );
+    Ok(())
+}
+
+/// Convert a rgba image to p010, through the 10 bits rgba `buffer_rgba10`
+///
+/// The strides of the 10 bits buffers are counted in samples.
+#[allow(clippy::too_many_arguments)]
+pub fn rgba_to_p010(
+    width: usize,
+    height: usize,
+    buffer_rgba: &[u8],
+    rgba_stride: usize,
+    buffer_rgba10: &mut [u16],
+    buffer_y: &mut [u16],
+    buffer_uv: &mut [u16],
+    y_stride: usize,
+    uv_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) -> Result<(), ConvertError> {
+    let rgba10_stride = width * 4;
+    yuv_rgb_rs::check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride)?;
+    yuv_rgb_rs::check_plane("rgba10", buffer_rgba10, height, width * 4, rgba10_stride)?;
+    yuv_rgb_rs::check_semi_planar(width, height, buffer_y, buffer_uv, y_stride, uv_stride)?;
+    yuv_rgb_rs::rgba_to_rgba10_std(
+        width,
+        height,
+        buffer_rgba,
+        rgba_stride,
+        buffer_rgba10,
+        rgba10_stride,
+    );
+    yuv_rgb_rs::rgba10_to_p010_std(
+        width,
+        height,
+        buffer_rgba10,
+        rgba10_stride,
+        buffer_y,
+        buffer_uv,
+        y_stride,
+        uv_stride,
+        yuv_type,
+        color_range,
+        byte_order,
+    );
+    Ok(())
+}
+
+/// Convert a yuv420 image to rgba
+///
+/// The alpha channel of `buffer_rgba` is left untouched.
+#[allow(clippy::too_many_arguments)]
+pub fn yuv420_to_rgba(
+    width: usize,
+    height: usize,
+    buffer_y: &[u8],
+    buffer_u: &[u8],
+    buffer_v: &[u8],
+    y_stride: usize,
+    u_stride: usize,
+    v_stride: usize,
+    buffer_rgba: &mut [u8],
+    rgba_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) -> Result<(), ConvertError> {
+    yuv_rgb_rs::check_yuv420(
+        width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride,
+    )?;
+    yuv_rgb_rs::check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride)?;
+    // Kernels expect at least one pixel
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    static KERNEL: OnceLock<YuvToRgbaFn> = OnceLock::new();
+    let convert = KERNEL.get_or_init(select_yuv420_to_rgba);
+    convert(
+        width,
+        height,
+        buffer_y,
+        buffer_u,
+        buffer_v,
+        y_stride,
+        u_stride,
+        v_stride,
+        buffer_rgba,
+        rgba_stride,
+        yuv_type,
+        color_range,
+        byte_order,
+    );
+    Ok(())
+}
+
+/// Convert a yuv444 image to rgba
+#[allow(clippy::too_many_arguments)]
+pub fn yuv444_to_rgba(
+    width: usize,
+    height: usize,
+    buffer_y: &[u8],
+    buffer_u: &[u8],
+    buffer_v: &[u8],
+    y_stride: usize,
+    u_stride: usize,
+    v_stride: usize,
+    buffer_rgba: &mut [u8],
+    rgba_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) -> Result<(), ConvertError> {
+    yuv_rgb_rs::check_yuv444(
+        width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride,
+    )?;
+    yuv_rgb_rs::check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride)?;
+    // Kernels expect at least one pixel
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    static KERNEL: OnceLock<YuvToRgbaFn> = OnceLock::new();
+    let convert = KERNEL.get_or_init(select_yuv444_to_rgba);
+    convert(
+        width,
+        height,
+        buffer_y,
+        buffer_u,
+        buffer_v,
+        y_stride,
+        u_stride,
+        v_stride,
+        buffer_rgba,
+        rgba_stride,
+        yuv_type,
+        color_range,
+        byte_order,
+    );
+    Ok(())
+}
+
+/// Convert a nv12 image to rgba
+#[allow(clippy::too_many_arguments)]
+pub fn nv12_to_rgba(
+    width: usize,
+    height: usize,
+    buffer_y: &[u8],
+    buffer_uv: &[u8],
+    y_stride: usize,
+    uv_stride: usize,
+    buffer_rgba: &mut [u8],
+    rgba_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) -> Result<(), ConvertError> {
+    yuv_rgb_rs::check_semi_planar(width, height, buffer_y, buffer_uv, y_stride, uv_stride)?;
+    yuv_rgb_rs::check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride)?;
+    // Kernels expect at least one pixel
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    static KERNEL: OnceLock<Nv12ToRgbaFn> = OnceLock::new();
+    let convert = KERNEL.get_or_init(select_nv12_to_rgba);
+    convert(
+        width,
+        height,
+        buffer_y,
+        buffer_uv,
+        y_stride,
+        uv_stride,
+        buffer_rgba,
+        rgba_stride,
+        yuv_type,
+        color_range,
+        byte_order,
+    );
+    Ok(())
+}
+
+/// Convert a nv21 image to rgba
+#[allow(clippy::too_many_arguments)]
+pub fn nv21_to_rgba(
+    width: usize,
+    height: usize,
+    buffer_y: &[u8],
+    buffer_vu: &[u8],
+    y_stride: usize,
+    vu_stride: usize,
+    buffer_rgba: &mut [u8],
+    rgba_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) -> Result<(), ConvertError> {
+    yuv_rgb_rs::check_semi_planar(width, height, buffer_y, buffer_vu, y_stride, vu_stride)?;
+    yuv_rgb_rs::check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride)?;
+    // Kernels expect at least one pixel
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    yuv_rgb_rs::nv21_rgba_std(
+        width,
+        height,
+        buffer_y,
+        buffer_vu,
+        y_stride,
+        vu_stride,
+        buffer_rgba,
+        rgba_stride,
+        yuv_type,
+        color_range,
+        byte_order,
+    );
+    Ok(())
+}
+
+/// Convert a packed yuv 4:2:2 (yuyv) image to rgba
+#[allow(clippy::too_many_arguments)]
+pub fn yuyv_to_rgba(
+    width: usize,
+    height: usize,
+    buffer_yuyv: &[u8],
+    yuyv_stride: usize,
+    buffer_rgba: &mut [u8],
+    rgba_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) -> Result<(), ConvertError> {
+    yuv_rgb_rs::check_plane(
+        "yuyv",
+        buffer_yuyv,
+        height,
+        width.div_ceil(2) * 4,
+        yuyv_stride,
+    )?;
+    yuv_rgb_rs::check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride)?;
+    // Kernels expect at least one pixel
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    static KERNEL: OnceLock<YuyvToRgbaFn> = OnceLock::new();
+    let convert = KERNEL.get_or_init(select_yuyv_to_rgba);
+    convert(
+        width,
+        height,
+        buffer_yuyv,
+        yuyv_stride,
+        buffer_rgba,
+        rgba_stride,
+        yuv_type,
+        color_range,
+        byte_order,
+    );
+    Ok(())
+}
+
+/// Pixel layout of a [`FrameBuf`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 32 bits per pixel, red first
+    Rgba,
+    /// 32 bits per pixel, blue first (x11 and Windows captures)
+    Bgra,
+    /// Planar yuv 4:2:0: y, u and v planes
+    I420,
+    /// Planar yuv 4:4:4: y, u and v planes
+    I444,
+    /// Semi planar yuv 4:2:0: y plane and interleaved uv plane
+    Nv12,
+}
+
+impl PixelFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            PixelFormat::Rgba => "rgba",
+            PixelFormat::Bgra => "bgra",
+            PixelFormat::I420 => "i420",
+            PixelFormat::I444 => "i444",
+            PixelFormat::Nv12 => "nv12",
+        }
+    }
+
+    /// Row length (bytes) and row count of each plane of a `width`x`height`
+    /// image
+    fn plane_sizes(self, width: usize, height: usize) -> Vec<(usize, usize)> {
+        let (uv_width, uv_height) = (width.div_ceil(2), height.div_ceil(2));
+        match self {
+            PixelFormat::Rgba | PixelFormat::Bgra => vec![(width * 4, height)],
+            PixelFormat::I420 => vec![
+                (width, height),
+                (uv_width, uv_height),
+                (uv_width, uv_height),
+            ],
+            PixelFormat::I444 => vec![(width, height); 3],
+            PixelFormat::Nv12 => vec![(width, height), (uv_width * 2, uv_height)],
+        }
+    }
+
+    /// Order of the samples of the 32 bits formats, rgba for the others
+    fn byte_order(self) -> ByteOrder {
+        match self {
+            PixelFormat::Bgra => ByteOrder::Bgra,
+            _ => ByteOrder::Rgba,
+        }
+    }
+}
+
+/// Image made of planes, each one with its own stride (bytes)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FrameBuf {
+    pub width: usize,
+    pub height: usize,
+    /// Planes in the order of the pixel format
+    pub planes: Vec<Vec<u8>>,
+    pub strides: Vec<usize>,
+}
+
+impl FrameBuf {
+    /// Allocate a `width`x`height` image of `format`, without row padding
+    pub fn new(format: PixelFormat, width: usize, height: usize) -> Self {
+        let sizes = format.plane_sizes(width, height);
+        FrameBuf {
+            width,
+            height,
+            planes: sizes
+                .iter()
+                .map(|(row_len, rows)| vec![0; row_len * rows])
+                .collect(),
+            strides: sizes.iter().map(|(row_len, _)| *row_len).collect(),
+        }
+    }
+
+    /// Check the planes against `format`
+    fn check(&self, format: PixelFormat) -> Result<(), ConvertError> {
+        let sizes = format.plane_sizes(self.width, self.height);
+        if self.planes.len() != sizes.len() || self.strides.len() != sizes.len() {
+            return Err(ConvertError::PlaneCount {
+                format: format.name(),
+                planes: self.planes.len().min(self.strides.len()),
+                expected: sizes.len(),
+            });
+        }
+        for ((plane, stride), (row_len, rows)) in
+            self.planes.iter().zip(self.strides.iter()).zip(sizes)
+        {
+            yuv_rgb_rs::check_plane(format.name(), plane, rows, row_len, *stride)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copy the `row_len` first bytes of `rows` rows
+fn copy_rows(
+    rows: usize,
+    row_len: usize,
+    src: &[u8],
+    src_stride: usize,
+    dst: &mut [u8],
+    dst_stride: usize,
+) {
+    for row in 0..rows {
+        let (src_start, dst_start) = (row * src_stride, row * dst_stride);
+        dst[dst_start..dst_start + row_len].copy_from_slice(&src[src_start..src_start + row_len]);
+    }
+}
+
+/// Swap the red and blue samples of a 32 bits per pixel image, in place
+fn swap_red_blue(width: usize, height: usize, buffer: &mut [u8], stride: usize) {
+    for row in 0..height {
+        let start = row * stride;
+        for pixel in buffer[start..start + width * 4].chunks_exact_mut(4) {
+            pixel.swap(0, 2);
+        }
+    }
+}
+
+/// Convert the image `frame` from `src` to the `dst` pixel format, into `out`
+///
+/// Both images must have the same size. The fastest kernel available on the
+/// cpu is used. Conversions between two yuv formats are not supported, except
+/// plain copies.
+pub fn convert(
+    src: PixelFormat,
+    dst: PixelFormat,
+    frame: &FrameBuf,
+    out: &mut FrameBuf,
+    matrix: YuvType,
+    range: ColorRange,
+) -> Result<(), ConvertError> {
+    let (width, height) = (frame.width, frame.height);
+    if (out.width, out.height) != (width, height) {
+        return Err(ConvertError::SizeMismatch {
+            src: (width, height),
+            dst: (out.width, out.height),
+        });
+    }
+    frame.check(src)?;
+    out.check(dst)?;
+
+    match (src, dst) {
+        (src, dst) if src == dst => {
+            let planes = frame.planes.iter().zip(frame.strides.iter());
+            let out_planes = out.planes.iter_mut().zip(out.strides.iter());
+            for (((plane, stride), (out_plane, out_stride)), (row_len, rows)) in
+                planes.zip(out_planes).zip(src.plane_sizes(width, height))
+            {
+                copy_rows(rows, row_len, plane, *stride, out_plane, *out_stride);
+            }
+            Ok(())
+        }
+        (PixelFormat::Rgba, PixelFormat::Bgra) | (PixelFormat::Bgra, PixelFormat::Rgba) => {
+            copy_rows(
+                height,
+                width * 4,
+                &frame.planes[0],
+                frame.strides[0],
+                &mut out.planes[0],
+                out.strides[0],
+            );
+            swap_red_blue(width, height, &mut out.planes[0], out.strides[0]);
+            Ok(())
+        }
+        (PixelFormat::Rgba | PixelFormat::Bgra, PixelFormat::I420 | PixelFormat::I444) => {
+            let rgba_to_yuv = match dst {
+                PixelFormat::I420 => rgba_to_yuv420,
+                _ => rgba_to_yuv444,
+            };
+            match out.planes.as_mut_slice() {
+                [y, u, v] => rgba_to_yuv(
+                    width,
+                    height,
+                    &frame.planes[0],
+                    frame.strides[0],
+                    y,
+                    u,
+                    v,
+                    out.strides[0],
+                    out.strides[1],
+                    out.strides[2],
+                    matrix,
+                    range,
+                    src.byte_order(),
+                ),
+                _ => unreachable!("Planes are checked"),
+            }
+        }
+        (PixelFormat::Rgba | PixelFormat::Bgra, PixelFormat::Nv12) => {
+            match out.planes.as_mut_slice() {
+                [y, uv] => rgba_to_nv12(
+                    width,
+                    height,
+                    &frame.planes[0],
+                    frame.strides[0],
+                    y,
+                    uv,
+                    out.strides[0],
+                    out.strides[1],
+                    matrix,
+                    range,
+                    src.byte_order(),
+                ),
+                _ => unreachable!("Planes are checked"),
+            }
+        }
+        (PixelFormat::I420 | PixelFormat::I444, PixelFormat::Rgba | PixelFormat::Bgra) => {
+            let yuv_to_rgba = match src {
+                PixelFormat::I420 => yuv420_to_rgba,
+                _ => yuv444_to_rgba,
+            };
+            yuv_to_rgba(
+                width,
+                height,
+                &frame.planes[0],
+                &frame.planes[1],
+                &frame.planes[2],
+                frame.strides[0],
+                frame.strides[1],
+                frame.strides[2],
+                &mut out.planes[0],
+                out.strides[0],
+                matrix,
+                range,
+                dst.byte_order(),
+            )
+        }
+        (PixelFormat::Nv12, PixelFormat::Rgba | PixelFormat::Bgra) => nv12_to_rgba(
+            width,
+            height,
+            &frame.planes[0],
+            &frame.planes[1],
+            frame.strides[0],
+            frame.strides[1],
+            &mut out.planes[0],
+            out.strides[0],
+            matrix,
+            range,
+            dst.byte_order(),
+        ),
+        (src, dst) => Err(ConvertError::Unsupported {
+            src: src.name(),
+            dst: dst.name(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bad_buffers() {
+        let (width, height) = (33, 5);
+        let rgba = vec![0u8; width * 4 * height];
+        let mut buffer_y = vec![0u8; width * height];
+        let mut buffer_u = vec![0u8; 17 * 3];
+        let mut buffer_v = vec![0u8; 17 * 3 - 1];
+
+        let err = rgba_to_yuv420(
+            width,
+            height,
+            &rgba,
+            width * 4,
+            &mut buffer_y,
+            &mut buffer_u,
+            &mut buffer_v,
+            width,
+            17,
+            17,
+            YuvType::ItuT871,
+            ColorRange::Full,
+            ByteOrder::Rgba,
+        );
+        assert_eq!(
+            err,
+            Err(ConvertError::BufferTooSmall {
+                plane: "v",
+                len: 50,
+                needed: 51
+            })
+        );
+
+        let err = rgba_to_yuv420(
+            width,
+            height,
+            &rgba,
+            width * 4 - 4,
+            &mut buffer_y,
+            &mut buffer_u,
+            &mut buffer_v,
+            width,
+            17,
+            17,
+            YuvType::ItuT871,
+            ColorRange::Full,
+            ByteOrder::Rgba,
+        );
+        assert_eq!(
+            err,
+            Err(ConvertError::StrideTooSmall {
+                plane: "rgba",
+                stride: width * 4 - 4,
+                row_len: width * 4
+            })
+        );
+
+        // The last row doesn't need to be padded
+        let mut buffer_v = vec![0u8; 17 * 3];
+        let rgba = vec![0u8; (width * 4 + 16) * (height - 1) + width * 4];
+        assert_eq!(
+            rgba_to_yuv420(
+                width,
+                height,
+                &rgba,
+                width * 4 + 16,
+                &mut buffer_y,
+                &mut buffer_u,
+                &mut buffer_v,
+                width,
+                17,
+                17,
+                YuvType::ItuT871,
+                ColorRange::Full,
+                ByteOrder::Rgba,
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_bad_p010_buffers() {
+        let (width, height) = (33, 5);
+        let rgba = vec![0u8; width * 4 * height];
+        let mut rgba10 = vec![0u16; width * 4 * height];
+        let mut buffer_y = vec![0u16; width * height];
+        let mut buffer_uv = vec![0u16; 34 * 3];
+        assert_eq!(
+            rgba_to_p010(
+                width,
+                height,
+                &rgba,
+                width * 4,
+                &mut rgba10,
+                &mut buffer_y,
+                &mut buffer_uv,
+                width,
+                33,
+                YuvType::ItuT871,
+                ColorRange::Full,
+                ByteOrder::Rgba,
+            ),
+            Err(ConvertError::StrideTooSmall {
+                plane: "uv",
+                stride: 33,
+                row_len: 34
+            })
+        );
+        assert_eq!(
+            rgba_to_p010(
+                width,
+                height,
+                &rgba,
+                width * 4,
+                &mut rgba10[1..],
+                &mut buffer_y,
+                &mut buffer_uv,
+                width,
+                34,
+                YuvType::ItuT871,
+                ColorRange::Full,
+                ByteOrder::Rgba,
+            ),
+            Err(ConvertError::BufferTooSmall {
+                plane: "rgba10",
+                len: width * 4 * height - 1,
+                needed: width * 4 * height
+            })
+        );
+        assert_eq!(
+            rgba_to_p010(
+                width,
+                height,
+                &rgba,
+                width * 4,
+                &mut rgba10,
+                &mut buffer_y,
+                &mut buffer_uv,
+                width,
+                34,
+                YuvType::ItuT871,
+                ColorRange::Full,
+                ByteOrder::Rgba,
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_convert() {
+        let (width, height) = (38, 10);
+        let mut bgra = FrameBuf::new(PixelFormat::Bgra, width, height);
+        for pixel in bgra.planes[0].chunks_exact_mut(4) {
+            pixel.copy_from_slice(&[200, 100, 50, 255]);
+        }
+
+        let mut i420 = FrameBuf::new(PixelFormat::I420, width, height);
+        let mut rgba = FrameBuf::new(PixelFormat::Rgba, width, height);
+        for (yuv_format, yuv) in [
+            (PixelFormat::I420, &mut i420),
+            (
+                PixelFormat::I444,
+                &mut FrameBuf::new(PixelFormat::I444, width, height),
+            ),
+            (
+                PixelFormat::Nv12,
+                &mut FrameBuf::new(PixelFormat::Nv12, width, height),
+            ),
+        ] {
+            convert(
+                PixelFormat::Bgra,
+                yuv_format,
+                &bgra,
+                yuv,
+                YuvType::ItuR709,
+                ColorRange::Limited,
+            )
+            .unwrap();
+            convert(
+                yuv_format,
+                PixelFormat::Rgba,
+                yuv,
+                &mut rgba,
+                YuvType::ItuR709,
+                ColorRange::Limited,
+            )
+            .unwrap();
+            for pixel in rgba.planes[0].chunks_exact(4) {
+                for (value, expected) in pixel[..3].iter().zip([50u8, 100, 200]) {
+                    assert!(
+                        value.abs_diff(expected) <= 3,
+                        "{:?}: {:?}",
+                        yuv_format,
+                        pixel
+                    );
+                }
+            }
+        }
+
+        // Swapping red and blue is lossless, also with padded destination rows
+        convert(
+            PixelFormat::Bgra,
+            PixelFormat::Rgba,
+            &bgra,
+            &mut rgba,
+            YuvType::ItuR709,
+            ColorRange::Limited,
+        )
+        .unwrap();
+        assert_eq!(&rgba.planes[0][..4], &[50, 100, 200, 255]);
+        let mut padded = FrameBuf::new(PixelFormat::Rgba, width, height);
+        padded.strides[0] += 8;
+        padded.planes[0].resize(padded.strides[0] * height, 0);
+        convert(
+            PixelFormat::Rgba,
+            PixelFormat::Bgra,
+            &rgba,
+            &mut padded,
+            YuvType::ItuR709,
+            ColorRange::Limited,
+        )
+        .unwrap();
+        assert_eq!(&padded.planes[0][..4], &bgra.planes[0][..4]);
+        assert_eq!(&padded.planes[0][width * 4..padded.strides[0]], &[0; 8]);
+
+        let mut nv12 = FrameBuf::new(PixelFormat::Nv12, width, height);
+        assert_eq!(
+            convert(
+                PixelFormat::I420,
+                PixelFormat::Nv12,
+                &i420,
+                &mut nv12,
+                YuvType::ItuR709,
+                ColorRange::Limited,
+            ),
+            Err(ConvertError::Unsupported {
+                src: "i420",
+                dst: "nv12"
+            })
+        );
+        assert_eq!(
+            convert(
+                PixelFormat::Nv12,
+                PixelFormat::Rgba,
+                &i420,
+                &mut rgba,
+                YuvType::ItuR709,
+                ColorRange::Limited,
+            ),
+            Err(ConvertError::PlaneCount {
+                format: "nv12",
+                planes: 3,
+                expected: 2
+            })
+        );
+        let mut small = FrameBuf::new(PixelFormat::Rgba, width - 1, height);
+        assert_eq!(
+            convert(
+                PixelFormat::Bgra,
+                PixelFormat::Rgba,
+                &bgra,
+                &mut small,
+                YuvType::ItuR709,
+                ColorRange::Limited,
+            ),
+            Err(ConvertError::SizeMismatch {
+                src: (width, height),
+                dst: (width - 1, height)
+            })
+        );
+    }
+}
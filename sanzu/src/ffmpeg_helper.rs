@@ -1,6 +1,9 @@
 use anyhow::{Context, Result};
 use ffmpeg_sys_next as ffmpeg;
-use std::ffi::{CStr, CString};
+use std::{
+    ffi::{CStr, CString},
+    ptr::null_mut,
+};
 
 pub fn averror(msg: &str, num: i32) -> anyhow::Error {
     let mut buf = vec![0u8; 200];
@@ -31,6 +34,22 @@ pub unsafe fn set_option(obj: *mut libc::c_void, name: &str, val: &str) -> Resul
     Ok(())
 }
 
+/// Value of the integer option `name`, None if `obj` has no such option
+///
+/// # Safety
+/// obj must be a correct ffmpeg context
+pub unsafe fn get_int_option(obj: *mut libc::c_void, name: &str) -> Option<i64> {
+    let name_c = CString::new(name).ok()?;
+    let mut value = 0;
+    let retval: i32 = ffmpeg::av_opt_get_int(
+        obj,
+        name_c.as_ptr(),
+        ffmpeg::AV_OPT_SEARCH_CHILDREN,
+        &mut value,
+    );
+    (retval >= 0).then_some(value)
+}
+
 /// Hold information on the FFmpg codec context
 #[derive(Debug)]
 pub struct AVCodecContext {
@@ -200,3 +219,63 @@ impl Drop for AVParser {
         }
     }
 }
+
+/// Hold a reference on a FFmpeg buffer (hardware device or frames context)
+#[derive(Debug)]
+pub struct AVBufferRef {
+    /// Raw pointer on the AVBufferRef
+    ptr: *mut ffmpeg::AVBufferRef,
+}
+
+impl AVBufferRef {
+    pub fn as_ptr(&self) -> *mut ffmpeg::AVBufferRef {
+        self.ptr
+    }
+}
+
+impl Drop for AVBufferRef {
+    fn drop(&mut self) {
+        unsafe {
+            ffmpeg::av_buffer_unref(&mut self.ptr);
+        }
+    }
+}
+
+/// Open the vaapi device `path`, and create a frames context holding
+/// `width`x`height` nv12 surfaces
+pub fn vaapi_frames_context(path: &str, width: i32, height: i32) -> Result<AVBufferRef> {
+    let path_c = CString::new(path).context("Error in CString")?;
+    let mut device = null_mut();
+    let retval = unsafe {
+        ffmpeg::av_hwdevice_ctx_create(
+            &mut device,
+            ffmpeg::AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI,
+            path_c.as_ptr(),
+            null_mut(),
+            0,
+        )
+    };
+    if retval < 0 {
+        return Err(averror(&format!("av_hwdevice_ctx_create {path:?}"), retval));
+    }
+    // The frames context holds its own reference on the device
+    let device = AVBufferRef { ptr: device };
+    let frames = unsafe { ffmpeg::av_hwframe_ctx_alloc(device.as_ptr()) };
+    if frames.is_null() {
+        return Err(anyhow!("Error in av_hwframe_ctx_alloc"));
+    }
+    let frames = AVBufferRef { ptr: frames };
+    unsafe {
+        let frames_ctx = (*frames.as_ptr()).data as *mut ffmpeg::AVHWFramesContext;
+        (*frames_ctx).format = ffmpeg::AVPixelFormat::AV_PIX_FMT_VAAPI;
+        (*frames_ctx).sw_format = ffmpeg::AVPixelFormat::AV_PIX_FMT_NV12;
+        (*frames_ctx).width = width;
+        (*frames_ctx).height = height;
+        (*frames_ctx).initial_pool_size = 20;
+    }
+    let retval = unsafe { ffmpeg::av_hwframe_ctx_init(frames.as_ptr()) };
+    if retval < 0 {
+        return Err(averror("av_hwframe_ctx_init", retval));
+    }
+    Ok(frames)
+}
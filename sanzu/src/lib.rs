@@ -11,9 +11,12 @@ extern crate anyhow;
 #[macro_use]
 extern crate sanzu_common;
 
+pub mod app_catalog;
 pub mod client;
 pub mod client_utils;
 #[cfg(windows)]
+pub mod client_wind11;
+#[cfg(windows)]
 pub mod client_wind3d;
 #[cfg(unix)]
 pub mod client_x11;
@@ -26,17 +29,25 @@ pub mod utils;
 pub mod utils_win;
 #[cfg(unix)]
 pub mod utils_x11;
+#[cfg(unix)]
+pub mod virtual_display;
 #[cfg(windows)]
 pub use client_wind3d as client_graphics;
 #[cfg(unix)]
 pub use client_x11 as client_graphics;
 pub mod config;
+pub mod convert;
 //pub mod proto;
 #[cfg(unix)]
 pub mod proxy;
 #[cfg(windows)]
 pub mod proxy_windows;
+#[cfg(feature = "record")]
+pub mod recorder;
+pub mod recording_crypto;
 pub mod server;
+#[cfg(all(unix, feature = "wayland"))]
+pub mod server_wayland;
 #[cfg(windows)]
 pub mod server_windows;
 #[cfg(unix)]
@@ -44,6 +55,9 @@ pub mod server_x11;
 #[cfg(windows)]
 pub use proxy_windows as proxy;
 pub mod osd;
+#[cfg(target_os = "linux")]
+pub mod pen_tablet;
+pub mod print_file;
 pub mod sound;
 pub mod video_decoder;
 pub mod video_encoder;
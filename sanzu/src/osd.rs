@@ -10,6 +10,12 @@ use embedded_graphics::{
     text::Text,
 };
 
+use sanzu_common::tunnel;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
 pub struct TestDisplay<'a> {
     pub width: u32,
     pub height: u32,
@@ -137,3 +143,338 @@ pub fn draw_text(display: &mut TestDisplay, text: &str, x: i32, y: i32) {
     let text_front = Text::new(text, Point::new(x, y), style);
     text_front.draw(display).expect("Cannot draw text");
 }
+
+/// Draw `lines` at the bottom of the display, the last line being the lowest
+pub fn draw_osd_lines(display: &mut TestDisplay, lines: &[String]) {
+    let bottom = display.height as i32 - 22;
+    for (index, line) in lines.iter().enumerate() {
+        let y = bottom - 14 * (lines.len() - index) as i32;
+        draw_text(display, line, 0, y);
+    }
+}
+
+/// Duration during which a notice stays on the OSD
+const OSD_NOTICE_DURATION: Duration = Duration::from_secs(5);
+
+/// Transient message from the server displayed on the OSD
+#[derive(Debug, Default)]
+pub struct OsdNotice {
+    notice: Option<(Instant, String)>,
+}
+
+impl OsdNotice {
+    pub fn set(&mut self, text: String, now: Instant) {
+        self.notice = Some((now, text));
+    }
+
+    /// Current notice, if not expired
+    pub fn text(&mut self, now: Instant) -> Option<&str> {
+        if let Some((time, _)) = self.notice {
+            if now.duration_since(time) > OSD_NOTICE_DURATION {
+                self.notice = None;
+            }
+        }
+        self.notice.as_ref().map(|(_, text)| text.as_str())
+    }
+}
+
+/// Duration over which the frame statistics are computed
+const FRAME_STATS_WINDOW: Duration = Duration::from_secs(1);
+
+/// Rolling statistics of the encoded frames, displayed on the OSD
+#[derive(Debug, Default)]
+pub struct FrameStatsHistory {
+    frames: VecDeque<(Instant, tunnel::EventFrameStats)>,
+}
+
+impl FrameStatsHistory {
+    pub fn push(&mut self, stats: tunnel::EventFrameStats, now: Instant) {
+        self.frames.push_back((now, stats));
+        self.expire(now);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((time, _)) = self.frames.front() {
+            if now.duration_since(*time) <= FRAME_STATS_WINDOW {
+                break;
+            }
+            self.frames.pop_front();
+        }
+    }
+
+    /// Bitrate, frame type distribution and average quantizer of the last frames
+    pub fn summary(&mut self, now: Instant) -> String {
+        self.expire(now);
+        let mut bytes = 0u64;
+        let mut types = [0usize; 4];
+        let (mut qp_sum, mut qp_count) = (0f32, 0usize);
+        for (_, stats) in self.frames.iter() {
+            bytes += stats.size as u64;
+            types[stats.frame_type() as usize] += 1;
+            if stats.qp >= 0.0 {
+                qp_sum += stats.qp;
+                qp_count += 1;
+            }
+        }
+        let kbps = bytes * 8 / 1000 / FRAME_STATS_WINDOW.as_secs();
+        let qp = match qp_count {
+            0 => "-".to_owned(),
+            _ => format!("{:.1}", qp_sum / qp_count as f32),
+        };
+        format!(
+            "bitrate: {} kbps I/P/B: {}/{}/{} qp: {}",
+            kbps,
+            types[tunnel::FrameType::I as usize],
+            types[tunnel::FrameType::P as usize],
+            types[tunnel::FrameType::B as usize],
+            qp
+        )
+    }
+}
+
+/// Client side measures of one received server message
+#[derive(Debug)]
+struct HudSample {
+    time: Instant,
+    bytes: usize,
+    decode: Option<Duration>,
+}
+
+/// Rolling client side measures (frame rate, decode time and received
+/// bitrate), displayed on the HUD
+#[derive(Debug, Default)]
+pub struct HudHistory {
+    samples: VecDeque<HudSample>,
+}
+
+impl HudHistory {
+    /// Records a received message of `bytes` bytes, and its frame decode
+    /// duration if it updated the display
+    pub fn push(&mut self, bytes: usize, decode: Option<Duration>, now: Instant) {
+        self.samples.push_back(HudSample {
+            time: now,
+            bytes,
+            decode,
+        });
+        self.expire(now);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(sample) = self.samples.front() {
+            if now.duration_since(sample.time) <= FRAME_STATS_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// Round trip time, frame rate, average decode time and received bitrate
+    pub fn summary(&mut self, rtt: Option<Duration>, now: Instant) -> String {
+        self.expire(now);
+        let bytes: usize = self.samples.iter().map(|sample| sample.bytes).sum();
+        let decodes: Vec<Duration> = self
+            .samples
+            .iter()
+            .filter_map(|sample| sample.decode)
+            .collect();
+        let fps = decodes.len() as u64 / FRAME_STATS_WINDOW.as_secs();
+        let kbps = bytes as u64 * 8 / 1000 / FRAME_STATS_WINDOW.as_secs();
+        let rtt = match rtt {
+            Some(rtt) => format!("{:.1} ms", rtt.as_secs_f64() * 1000.0),
+            None => "-".to_owned(),
+        };
+        let decode = match decodes.len() {
+            0 => "-".to_owned(),
+            count => format!(
+                "{:.1} ms",
+                decodes.iter().sum::<Duration>().as_secs_f64() * 1000.0 / count as f64
+            ),
+        };
+        format!(
+            "rtt: {} fps: {} decode: {} recv: {} kbps",
+            rtt, fps, decode, kbps
+        )
+    }
+}
+
+/// Last rendered frame, presented again dimmed and with a frozen indicator
+/// when no new frame comes in time, so that the window does not look hung
+#[derive(Debug)]
+pub struct FrameHold {
+    /// Delay without a new frame before the held frame is presented, and
+    /// between its presentations
+    interval: Duration,
+    frame: Option<(Vec<u8>, u32, u32)>,
+    received: Instant,
+}
+
+impl FrameHold {
+    pub fn new(interval: Duration) -> Self {
+        FrameHold {
+            interval,
+            frame: None,
+            received: Instant::now(),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Keep the last rendered `frame`, of 4 bytes per pixel
+    pub fn set(&mut self, frame: (Vec<u8>, u32, u32), now: Instant) {
+        self.frame = Some(frame);
+        self.received = now;
+    }
+
+    /// Dimmed copy of the held frame with the time elapsed since its
+    /// reception, once the interval has elapsed
+    pub fn held_frame(&self, now: Instant) -> Option<(Vec<u8>, u32, u32)> {
+        let elapsed = now.duration_since(self.received);
+        if elapsed < self.interval {
+            return None;
+        }
+        let (data, width, height) = self.frame.as_ref()?;
+        let mut data = data.clone();
+        for pixel in data.chunks_exact_mut(4) {
+            for value in pixel[..3].iter_mut() {
+                *value -= *value / 4;
+            }
+        }
+        let mut display = TestDisplay {
+            width: *width,
+            height: *height,
+            buffer: &mut data,
+        };
+        draw_text(
+            &mut display,
+            &format!("Frozen {:.1}s", elapsed.as_secs_f64()),
+            4,
+            14,
+        );
+        Some((data, *width, *height))
+    }
+}
+
+/// Audio codec and estimated latency: half the round trip time, plus the
+/// sound waiting for playback on the client
+pub fn audio_summary(
+    codec: tunnel::AudioCodec,
+    buffered: Duration,
+    rtt: Option<Duration>,
+) -> String {
+    let codec = match codec {
+        tunnel::AudioCodec::Opus => "opus",
+        tunnel::AudioCodec::Raw => "raw",
+    };
+    let latency = match rtt {
+        Some(rtt) => format!("{:.1} ms", (rtt / 2 + buffered).as_secs_f64() * 1000.0),
+        None => "-".to_owned(),
+    };
+    format!(
+        "audio: {} latency: {} buffer: {:.1} ms",
+        codec,
+        latency,
+        buffered.as_secs_f64() * 1000.0
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audio_summary() {
+        let buffered = Duration::from_millis(80);
+        assert_eq!(
+            audio_summary(tunnel::AudioCodec::Opus, buffered, None),
+            "audio: opus latency: - buffer: 80.0 ms"
+        );
+        assert_eq!(
+            audio_summary(
+                tunnel::AudioCodec::Raw,
+                buffered,
+                Some(Duration::from_millis(30))
+            ),
+            "audio: raw latency: 95.0 ms buffer: 80.0 ms"
+        );
+    }
+
+    #[test]
+    fn test_frame_stats_history() {
+        let frame = |size, frame_type: tunnel::FrameType, qp| tunnel::EventFrameStats {
+            size,
+            frame_type: frame_type as i32,
+            qp,
+        };
+        let start = Instant::now();
+        let mut history = FrameStatsHistory::default();
+        assert_eq!(history.summary(start), "bitrate: 0 kbps I/P/B: 0/0/0 qp: -");
+        history.push(frame(50000, tunnel::FrameType::I, 20.0), start);
+        let now = start + Duration::from_millis(500);
+        history.push(frame(10000, tunnel::FrameType::P, 30.0), now);
+        history.push(frame(15000, tunnel::FrameType::B, -1.0), now);
+        assert_eq!(
+            history.summary(now),
+            "bitrate: 600 kbps I/P/B: 1/1/1 qp: 25.0"
+        );
+        // The keyframe leaves the window
+        let now = start + Duration::from_millis(1200);
+        assert_eq!(
+            history.summary(now),
+            "bitrate: 200 kbps I/P/B: 0/1/1 qp: 30.0"
+        );
+    }
+
+    #[test]
+    fn test_hud_history() {
+        let start = Instant::now();
+        let mut history = HudHistory::default();
+        assert_eq!(
+            history.summary(None, start),
+            "rtt: - fps: 0 decode: - recv: 0 kbps"
+        );
+        history.push(50000, Some(Duration::from_millis(4)), start);
+        let now = start + Duration::from_millis(500);
+        history.push(25000, Some(Duration::from_millis(2)), now);
+        history.push(100, None, now);
+        assert_eq!(
+            history.summary(Some(Duration::from_micros(12500)), now),
+            "rtt: 12.5 ms fps: 2 decode: 3.0 ms recv: 600 kbps"
+        );
+        // The first frame leaves the window
+        let now = start + Duration::from_millis(1200);
+        assert_eq!(
+            history.summary(None, now),
+            "rtt: - fps: 1 decode: 2.0 ms recv: 200 kbps"
+        );
+    }
+
+    #[test]
+    fn test_osd_notice() {
+        let start = Instant::now();
+        let mut notice = OsdNotice::default();
+        assert_eq!(notice.text(start), None);
+        notice.set("fell back".to_owned(), start);
+        assert_eq!(
+            notice.text(start + Duration::from_secs(1)),
+            Some("fell back")
+        );
+        assert_eq!(notice.text(start + Duration::from_secs(6)), None);
+    }
+
+    #[test]
+    fn test_frame_hold() {
+        let start = Instant::now();
+        let interval = Duration::from_millis(500);
+        let mut hold = FrameHold::new(interval);
+        assert!(hold.held_frame(start + interval).is_none());
+
+        hold.set((vec![0x80; 64 * 32 * 4], 64, 32), start);
+        assert!(hold.held_frame(start + interval / 2).is_none());
+        let (data, width, height) = hold.held_frame(start + interval).unwrap();
+        assert_eq!((data.len(), width, height), (64 * 32 * 4, 64, 32));
+        // Dimmed outside of the indicator, alpha is kept
+        assert_eq!(data[data.len() - 4..], [0x60, 0x60, 0x60, 0x80]);
+    }
+}
@@ -0,0 +1,206 @@
+//! Virtual pen tablet fed with the client pen events
+//!
+//! The tablet is a uinput device with absolute axes, pressure and tilt. It is
+//! flagged as a direct input device, so the input stack (libinput) maps it to
+//! the whole screen: positions are scaled from the frame size to the axis
+//! range.
+//!
+//! The server needs write access to the uinput device, ex: with a udev rule
+//! `KERNEL=="uinput", GROUP="input", MODE="0660"` and the server user in the
+//! `input` group.
+
+use anyhow::{Context, Result};
+use sanzu_common::tunnel;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    mem::size_of,
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+};
+
+pub const UINPUT_DEVICE: &str = "/dev/uinput";
+const DEVICE_NAME: &[u8] = b"Sanzu pen tablet";
+
+/// Range of the position axes
+const AXIS_MAX: i32 = 0xffff;
+const PRESSURE_MAX: i32 = 0xffff;
+/// Tilt range, in degrees
+const TILT_MAX: i32 = 90;
+
+/* linux/input-event-codes.h */
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_ABS: u16 = 0x03;
+const SYN_REPORT: u16 = 0x00;
+const BTN_TOOL_PEN: u16 = 0x140;
+const BTN_TOUCH: u16 = 0x14a;
+const BTN_STYLUS: u16 = 0x14b;
+const BTN_STYLUS2: u16 = 0x14c;
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+const ABS_PRESSURE: u16 = 0x18;
+const ABS_TILT_X: u16 = 0x1a;
+const ABS_TILT_Y: u16 = 0x1b;
+const BUS_VIRTUAL: u16 = 0x06;
+
+/// Encode a uinput ioctl request (generic layout used by x86 and arm)
+const fn uinput_ioctl(write: bool, nr: u32, size: usize) -> u32 {
+    ((write as u32) << 30) | ((size as u32) << 16) | ((b'U' as u32) << 8) | nr
+}
+
+const UI_DEV_CREATE: u32 = uinput_ioctl(false, 1, 0);
+const UI_DEV_SETUP: u32 = uinput_ioctl(true, 3, size_of::<libc::uinput_setup>());
+const UI_ABS_SETUP: u32 = uinput_ioctl(true, 4, size_of::<libc::uinput_abs_setup>());
+const UI_SET_EVBIT: u32 = uinput_ioctl(true, 100, size_of::<libc::c_int>());
+const UI_SET_KEYBIT: u32 = uinput_ioctl(true, 101, size_of::<libc::c_int>());
+const UI_SET_ABSBIT: u32 = uinput_ioctl(true, 103, size_of::<libc::c_int>());
+const UI_SET_PROPBIT: u32 = uinput_ioctl(true, 110, size_of::<libc::c_int>());
+
+/// Uinput tablet device, destroyed when its file is closed
+pub struct PenTablet {
+    file: File,
+    /// The pen has been reported in range of the tablet
+    in_range: bool,
+}
+
+/// Scale a frame coordinate to the tablet axis range
+fn axis_value(value: u32, size: u16) -> i32 {
+    let max = (size as i64 - 1).max(1);
+    (value as i64 * AXIS_MAX as i64 / max).clamp(0, AXIS_MAX as i64) as i32
+}
+
+impl PenTablet {
+    /// Create the tablet device on the uinput device at `path`
+    pub fn new(path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)
+            .context(format!("Cannot open {}", path))?;
+        let fd = file.as_raw_fd();
+        let ioctl = |request: u32, arg: usize, name: &str| -> Result<()> {
+            if unsafe { libc::ioctl(fd, request as _, arg) } < 0 {
+                return Err(std::io::Error::last_os_error())
+                    .context(format!("Error in uinput {}", name));
+            }
+            Ok(())
+        };
+
+        ioctl(
+            UI_SET_PROPBIT,
+            libc::INPUT_PROP_DIRECT as usize,
+            "set_propbit",
+        )?;
+        for event_type in [EV_SYN, EV_KEY, EV_ABS] {
+            ioctl(UI_SET_EVBIT, event_type as usize, "set_evbit")?;
+        }
+        for key in [BTN_TOOL_PEN, BTN_TOUCH, BTN_STYLUS, BTN_STYLUS2] {
+            ioctl(UI_SET_KEYBIT, key as usize, "set_keybit")?;
+        }
+        for (code, minimum, maximum) in [
+            (ABS_X, 0, AXIS_MAX),
+            (ABS_Y, 0, AXIS_MAX),
+            (ABS_PRESSURE, 0, PRESSURE_MAX),
+            (ABS_TILT_X, -TILT_MAX, TILT_MAX),
+            (ABS_TILT_Y, -TILT_MAX, TILT_MAX),
+        ] {
+            ioctl(UI_SET_ABSBIT, code as usize, "set_absbit")?;
+            let setup = libc::uinput_abs_setup {
+                code,
+                absinfo: libc::input_absinfo {
+                    value: 0,
+                    minimum,
+                    maximum,
+                    fuzz: 0,
+                    flat: 0,
+                    resolution: 0,
+                },
+            };
+            ioctl(UI_ABS_SETUP, &setup as *const _ as usize, "abs_setup")?;
+        }
+
+        let mut setup = libc::uinput_setup {
+            id: libc::input_id {
+                bustype: BUS_VIRTUAL,
+                vendor: 0,
+                product: 0,
+                version: 1,
+            },
+            name: [0; libc::UINPUT_MAX_NAME_SIZE],
+            ff_effects_max: 0,
+        };
+        for (dst, src) in setup.name.iter_mut().zip(DEVICE_NAME) {
+            *dst = *src as _;
+        }
+        ioctl(UI_DEV_SETUP, &setup as *const _ as usize, "dev_setup")?;
+        ioctl(UI_DEV_CREATE, 0, "dev_create")?;
+
+        Ok(PenTablet {
+            file,
+            in_range: false,
+        })
+    }
+
+    /// Inject the pen `event`, whose position is relative to a frame of `size`
+    pub fn send(&mut self, event: &tunnel::EventPen, size: (u16, u16)) -> Result<()> {
+        let mut values = vec![];
+        if !self.in_range {
+            values.push((EV_KEY, BTN_TOOL_PEN, 1));
+        }
+        let pressure = (event.pressure as i32).min(PRESSURE_MAX);
+        values.extend([
+            (EV_ABS, ABS_X, axis_value(event.x, size.0)),
+            (EV_ABS, ABS_Y, axis_value(event.y, size.1)),
+            (EV_ABS, ABS_PRESSURE, pressure),
+            (EV_ABS, ABS_TILT_X, event.tilt_x.clamp(-TILT_MAX, TILT_MAX)),
+            (EV_ABS, ABS_TILT_Y, event.tilt_y.clamp(-TILT_MAX, TILT_MAX)),
+            (EV_KEY, BTN_TOUCH, (pressure > 0) as i32),
+            (EV_KEY, BTN_STYLUS, (event.button & 1 != 0) as i32),
+            (EV_KEY, BTN_STYLUS2, (event.button & 2 != 0) as i32),
+            (EV_SYN, SYN_REPORT, 0),
+        ]);
+
+        let mut data = Vec::with_capacity(values.len() * size_of::<libc::input_event>());
+        for (type_, code, value) in values {
+            // The kernel sets the event time
+            let mut input_event: libc::input_event = unsafe { std::mem::zeroed() };
+            input_event.type_ = type_;
+            input_event.code = code;
+            input_event.value = value;
+            data.extend_from_slice(unsafe {
+                std::slice::from_raw_parts(
+                    &input_event as *const _ as *const u8,
+                    size_of::<libc::input_event>(),
+                )
+            });
+        }
+        self.file
+            .write_all(&data)
+            .context("Cannot write pen events")?;
+        self.in_range = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_axis_value() {
+        assert_eq!(axis_value(0, 1920), 0);
+        assert_eq!(axis_value(1919, 1920), AXIS_MAX);
+        assert_eq!(axis_value(5000, 1920), AXIS_MAX);
+        // Empty frame
+        assert_eq!(axis_value(0, 0), 0);
+    }
+
+    #[test]
+    fn test_uinput_ioctl() {
+        // Values of linux/uinput.h
+        assert_eq!(UI_DEV_CREATE, 0x5501);
+        assert_eq!(UI_DEV_SETUP, 0x405c5503);
+        assert_eq!(UI_ABS_SETUP, 0x401c5504);
+        assert_eq!(UI_SET_EVBIT, 0x40045564);
+    }
+}
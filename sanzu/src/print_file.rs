@@ -0,0 +1,322 @@
+//! Transfer of the documents printed on the server to the client
+//!
+//! The dbus print request gives the path of the document on the server: its
+//! content is sent in chunks, one per frame, the first one holding its path,
+//! size and type. The client saves the document in its print directory,
+//! under the name of the server file, and prints it if its type is known.
+
+use anyhow::{Context, Result};
+use sanzu_common::tunnel;
+use std::{
+    collections::VecDeque,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Documents are sent in chunks of this size
+pub const PRINT_CHUNK_SIZE: usize = 256 * 1024;
+/// Maximum size of a printed document
+pub const MAX_PRINT_FILE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Type of the documents which are not printed, only saved
+pub const UNKNOWN_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Printable content types, with their signature and file extensions
+const CONTENT_TYPES: &[(&str, &[u8], &[&str])] = &[
+    ("application/pdf", b"%PDF-", &["pdf"]),
+    ("application/postscript", b"%!PS", &["ps"]),
+    ("image/png", b"\x89PNG\r\n\x1a\n", &["png"]),
+    ("image/jpeg", b"\xff\xd8\xff", &["jpg", "jpeg"]),
+];
+
+/// Content type of a document, from its signature
+pub fn content_type(data: &[u8]) -> &'static str {
+    CONTENT_TYPES
+        .iter()
+        .find(|(_, signature, _)| data.starts_with(signature))
+        .map_or(UNKNOWN_CONTENT_TYPE, |(content_type, _, _)| content_type)
+}
+
+/// Extensions of a printable content type
+fn extensions(content_type: &str) -> Option<&'static [&'static str]> {
+    CONTENT_TYPES
+        .iter()
+        .find(|(known, _, _)| *known == content_type)
+        .map(|(_, _, extensions)| *extensions)
+}
+
+/// Document to print
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrintDocument {
+    /// Path of the document on the server
+    pub path: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+impl PrintDocument {
+    /// Read the document at `path`
+    pub fn read(path: &str) -> Result<Self> {
+        let size = std::fs::metadata(path)
+            .with_context(|| format!("Cannot stat {:?}", path))?
+            .len();
+        if size > MAX_PRINT_FILE_SIZE as u64 {
+            return Err(anyhow!("Document too large ({} bytes)", size));
+        }
+        let data = std::fs::read(path).with_context(|| format!("Cannot read {:?}", path))?;
+        // The file may have grown since the stat
+        if data.len() > MAX_PRINT_FILE_SIZE {
+            return Err(anyhow!("Document too large ({} bytes)", data.len()));
+        }
+        Ok(PrintDocument {
+            path: path.to_owned(),
+            content_type: content_type(&data).to_owned(),
+            data,
+        })
+    }
+
+    /// True if the client can print the document
+    pub fn printable(&self) -> bool {
+        extensions(&self.content_type).is_some()
+    }
+
+    /// Local file name: the server file name, restricted to safe characters,
+    /// with the extension of its content type
+    pub fn file_name(&self) -> String {
+        let name = Path::new(&self.path)
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let name: String = name
+            .chars()
+            .map(|c| match c {
+                c if c.is_alphanumeric() => c,
+                '-' | '_' | '.' | ' ' | '(' | ')' => c,
+                _ => '_',
+            })
+            .collect();
+        let mut name = name.trim_start_matches('.').to_owned();
+        if name.is_empty() {
+            name = "document".to_owned();
+        }
+        if let Some(extensions) = extensions(&self.content_type) {
+            let extension = Path::new(&name)
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_lowercase());
+            if !matches!(extension, Some(extension) if extensions.contains(&extension.as_str())) {
+                name = format!("{}.{}", name, extensions[0]);
+            }
+        }
+        name
+    }
+
+    /// Save the document in `print_dir`, without overwriting the existing
+    /// files. Returns the path of the saved document.
+    pub fn save(&self, print_dir: &Path) -> Result<PathBuf> {
+        let name = self.file_name();
+        let (stem, extension) = match name.rfind('.') {
+            Some(index) if index > 0 => name.split_at(index),
+            _ => (name.as_str(), ""),
+        };
+        for index in 0..100 {
+            let path = match index {
+                0 => print_dir.join(&name),
+                index => print_dir.join(format!("{stem} ({index}){extension}")),
+            };
+            let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => file,
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err).with_context(|| format!("Cannot create {:?}", path)),
+            };
+            file.write_all(&self.data)
+                .with_context(|| format!("Cannot write {:?}", path))?;
+            return Ok(path);
+        }
+        Err(anyhow!("Too many documents named {:?}", name))
+    }
+}
+
+/// Splits the documents to print in chunks
+///
+/// Chunks are sent one per frame, so a large document does not freeze the
+/// display. Documents are sent one after the other.
+#[derive(Default)]
+pub struct PrintChunker {
+    next_id: u32,
+    pending: VecDeque<tunnel::EventPrintFile>,
+}
+
+impl PrintChunker {
+    /// Queue a document
+    pub fn push(&mut self, document: PrintDocument) {
+        self.next_id = self.next_id.wrapping_add(1);
+        // An empty document is sent in a single empty chunk
+        let chunks: Vec<&[u8]> = match document.data.is_empty() {
+            true => vec![&[]],
+            false => document.data.chunks(PRINT_CHUNK_SIZE).collect(),
+        };
+        let total = chunks.len() as u32;
+        for (seq, chunk) in chunks.into_iter().enumerate() {
+            // The metadata is only in the first chunk
+            let (path, content_type) = match seq {
+                0 => (document.path.clone(), document.content_type.clone()),
+                _ => (String::new(), String::new()),
+            };
+            self.pending.push_back(tunnel::EventPrintFile {
+                path,
+                id: self.next_id,
+                seq: seq as u32,
+                total,
+                size: document.data.len() as u32,
+                content_type,
+                data: chunk.to_vec(),
+            });
+        }
+    }
+
+    /// Next chunk to send, if any
+    pub fn next_chunk(&mut self) -> Option<tunnel::EventPrintFile> {
+        self.pending.pop_front()
+    }
+}
+
+/// Rebuilds the documents to print from their chunks
+#[derive(Default)]
+pub struct PrintAssembler {
+    /// Current transfer id, next expected chunk and announced size
+    transfer: Option<(u32, u32, usize)>,
+    document: Option<PrintDocument>,
+}
+
+impl PrintAssembler {
+    /// Add a chunk, returns the document once all its chunks are received
+    ///
+    /// A chunk out of sequence drops the current transfer
+    pub fn push(&mut self, chunk: tunnel::EventPrintFile) -> Result<Option<PrintDocument>> {
+        if chunk.seq == 0 {
+            // Start of a new transfer, drop the incomplete one
+            self.document = None;
+            self.transfer = None;
+            if chunk.size as usize > MAX_PRINT_FILE_SIZE {
+                return Err(anyhow!("Document too large ({} bytes)", chunk.size));
+            }
+            self.transfer = Some((chunk.id, 0, chunk.size as usize));
+            self.document = Some(PrintDocument {
+                path: chunk.path.clone(),
+                content_type: chunk.content_type.clone(),
+                data: Vec::with_capacity(chunk.size as usize),
+            });
+        }
+        let (next_seq, size, mut document) = match (self.transfer.take(), self.document.take()) {
+            (Some((id, next_seq, size)), Some(document))
+                if id == chunk.id && next_seq == chunk.seq =>
+            {
+                (next_seq + 1, size, document)
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Unexpected print chunk {}/{} of transfer {}",
+                    chunk.seq,
+                    chunk.total,
+                    chunk.id
+                ));
+            }
+        };
+        if document.data.len() + chunk.data.len() > size {
+            return Err(anyhow!("Print chunks larger than announced"));
+        }
+        document.data.extend_from_slice(&chunk.data);
+        if next_seq < chunk.total {
+            self.transfer = Some((chunk.id, next_seq, size));
+            self.document = Some(document);
+            return Ok(None);
+        }
+        if document.data.len() != size {
+            return Err(anyhow!("Truncated print transfer"));
+        }
+        Ok(Some(document))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(path: &str, data: Vec<u8>) -> PrintDocument {
+        PrintDocument {
+            path: path.to_owned(),
+            content_type: content_type(&data).to_owned(),
+            data,
+        }
+    }
+
+    #[test]
+    fn test_print_chunks() {
+        let mut data = b"%PDF-1.7\n".to_vec();
+        data.extend((0..3 * PRINT_CHUNK_SIZE + 10).map(|index| index as u8));
+        let pdf = document("/tmp/report.pdf", data);
+        assert_eq!(pdf.content_type, "application/pdf");
+
+        let mut chunker = PrintChunker::default();
+        let mut assembler = PrintAssembler::default();
+        chunker.push(pdf.clone());
+        chunker.push(document("/tmp/empty", vec![]));
+        let mut documents = vec![];
+        while let Some(chunk) = chunker.next_chunk() {
+            assert!(chunk.data.len() <= PRINT_CHUNK_SIZE);
+            if let Some(document) = assembler.push(chunk).unwrap() {
+                documents.push(document);
+            }
+        }
+        assert_eq!(documents, [pdf.clone(), document("/tmp/empty", vec![])]);
+
+        // Missing chunk
+        chunker.push(pdf);
+        let first = chunker.next_chunk().unwrap();
+        chunker.next_chunk().unwrap();
+        let third = chunker.next_chunk().unwrap();
+        assert_eq!(assembler.push(first).unwrap(), None);
+        assert!(assembler.push(third).is_err());
+    }
+
+    #[test]
+    fn test_print_file_name() {
+        let pdf = b"%PDF-1.4".to_vec();
+        assert_eq!(
+            document("/tmp/report.pdf", pdf.clone()).file_name(),
+            "report.pdf"
+        );
+        assert_eq!(
+            document("/tmp/report", pdf.clone()).file_name(),
+            "report.pdf"
+        );
+        assert_eq!(
+            document("../../.bashrc", pdf.clone()).file_name(),
+            "bashrc.pdf"
+        );
+        assert_eq!(document("/", pdf).file_name(), "document.pdf");
+        let jpeg = b"\xff\xd8\xff\xe0".to_vec();
+        assert_eq!(document("a/b:c.JPEG", jpeg).file_name(), "b_c.JPEG");
+        let unknown = document("/tmp/run.sh", b"#!/bin/sh".to_vec());
+        assert!(!unknown.printable());
+        assert_eq!(unknown.file_name(), "run.sh");
+
+        let print_dir = std::env::temp_dir().join(format!("sanzu-print-{}", std::process::id()));
+        std::fs::create_dir(&print_dir).unwrap();
+        let report = document("/tmp/report.pdf", b"%PDF-1.4".to_vec());
+        assert_eq!(
+            report.save(&print_dir).unwrap(),
+            print_dir.join("report.pdf")
+        );
+        assert_eq!(
+            report.save(&print_dir).unwrap(),
+            print_dir.join("report (1).pdf")
+        );
+        assert_eq!(
+            std::fs::read(print_dir.join("report (1).pdf")).unwrap(),
+            report.data
+        );
+        std::fs::remove_dir_all(&print_dir).unwrap();
+    }
+}
@@ -2,17 +2,21 @@ use anyhow::{Context, Result};
 
 use crate::{
     config::ConfigServer,
-    sound::{encode_sound, SOUND_FREQ},
+    sound::encode_sound,
     utils::{
-        get_xwd_data, set_tcp_timeout, HasTimeout, ProxyArgsConfig, MAX_BYTES_PER_LINE,
+        blit_region, get_xwd_data, negotiated_capabilities, negotiated_chroma,
+        negotiated_color_space, set_tcp_timeout, HasTimeout, ProxyArgsConfig, MAX_BYTES_PER_LINE,
         MAX_WINDOW_HEIGHT, MAX_WINDOW_WIDTH,
     },
+    video_decoder::{init_video_codec, next_sequence, Decoder, DecoderTiles, StreamRecovery},
     video_encoder::{get_encoder_category, init_video_encoder},
+    yuv_rgb_rs::{ColorRange, YuvType},
 };
 use byteorder::{LittleEndian, ReadBytesExt};
 use memmap2::MmapOptions;
 use sanzu_common::{
-    proto::{recv_client_msg_or_error, recv_server_msg_or_error, VERSION},
+    heartbeat,
+    proto::{check_version, recv_client_msg_or_error, recv_server_msg_or_error},
     tunnel, ReadWrite, Tunnel,
 };
 
@@ -21,8 +25,11 @@ use std::{
     fs,
     io::Cursor,
     net::{SocketAddr, TcpListener, TcpStream},
-    os::unix::net::UnixStream,
-    sync::mpsc::channel,
+    os::unix::{
+        io::{AsRawFd, RawFd},
+        net::UnixStream,
+    },
+    sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError},
     thread::{self},
     time::Instant,
 };
@@ -66,7 +73,8 @@ fn send_client_err_event(sock: &mut Box<dyn ReadWrite>, err: anyhow::Error) -> a
 }
 
 fn recv_srv_msg_or_error(stream: &mut dyn ReadWrite) -> Result<tunnel::message_server_ok::Msg> {
-    let msg: tunnel::ServerMsgOrErr = Tunnel::recv(stream).context("Error in recv pkt")?;
+    let msg: tunnel::ServerMsgOrErr =
+        Tunnel::recv_frame_msg(stream).context("Error in recv pkt")?;
     match msg.msg {
         Some(tunnel::server_msg_or_err::Msg::Ok(msg_ok)) => {
             // Message is ok
@@ -137,9 +145,316 @@ macro_rules! send_srv_msg_type {
     }};
 }
 
+/// Forward the connection between the client and the server as is, returns
+/// the disconnection reason
+///
+/// The sockets (unix, tcp or vsock) are polled through their file descriptors
+fn loop_fwd_conn(
+    (client, client_fd): (&mut dyn ReadWrite, RawFd),
+    (server, server_fd): (&mut dyn ReadWrite, RawFd),
+) -> Result<&'static str> {
+    let mut buffer = vec![0u8; 1024 * 1024];
+    let mut fds = [client_fd, server_fd].map(|fd| libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    });
+    loop {
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err).context("Error in poll");
+        }
+        if fds[0].revents != 0 {
+            let size = client.read(&mut buffer).context("Error in client read")?;
+            trace!("forward to server {:?}", size);
+            if size == 0 {
+                return Ok("client closed connection");
+            }
+            server
+                .write_all(&buffer[..size])
+                .context("Error in server write")?;
+        }
+        if fds[1].revents != 0 {
+            let size = server.read(&mut buffer).context("Error in server read")?;
+            trace!("forward to client {:?}", size);
+            if size == 0 {
+                return Ok("server closed connection");
+            }
+            client
+                .write_all(&buffer[..size])
+                .context("Error in client write")?;
+        }
+    }
+}
+
+/// Decoder of the video sent by a relayed server, to encode it again
+struct Transcoder {
+    decoder: Box<dyn Decoder>,
+    /// Decoders of the frames split in tiles by the server
+    tiles_decoder: DecoderTiles,
+    size: (u16, u16),
+    stream_recovery: StreamRecovery,
+    /// The decoders have been reset and wait for a keyframe
+    request_keyframe: bool,
+}
+
+impl Transcoder {
+    fn new(codec_name: &str, yuv_type: YuvType, color_range: ColorRange) -> Result<Self> {
+        let decoder = init_video_codec(
+            None::<std::collections::hash_map::Iter<String, String>>,
+            codec_name,
+            yuv_type,
+            color_range,
+        )
+        .context("Cannot init video decoder")?;
+        Ok(Transcoder {
+            decoder,
+            tiles_decoder: DecoderTiles::default(),
+            size: (0, 0),
+            stream_recovery: StreamRecovery::default(),
+            request_keyframe: false,
+        })
+    }
+
+    /// Reload the decoder if the frame size changed
+    fn set_size(&mut self, width: u32, height: u32) -> Result<()> {
+        let size = (width as u16, height as u16);
+        if size != self.size {
+            debug!("Relayed resolution {}x{}", width, height);
+            self.decoder = self
+                .decoder
+                .reload()
+                .context(format!("Cannot reload decoder with size {width}x{height}"))?;
+            self.size = size;
+            self.request_keyframe = true;
+        }
+        Ok(())
+    }
+
+    /// Decode the encoded images of `msgs`
+    ///
+    /// The encoded images are replaced by the last decoded frame, as a raw
+    /// image.
+    fn decode(&mut self, msgs: Vec<tunnel::MessageSrv>) -> Result<Vec<tunnel::MessageSrv>> {
+        let mut events = vec![];
+        let mut tiles = vec![];
+        let mut frame = None;
+        for msg in msgs {
+            let img = match msg.msg {
+                Some(tunnel::message_srv::Msg::ImgEncoded(img)) => img,
+                msg => {
+                    events.push(tunnel::MessageSrv { msg });
+                    continue;
+                }
+            };
+            if img.width > MAX_WINDOW_WIDTH || img.height > MAX_WINDOW_HEIGHT {
+                return Err(anyhow!("Size too big {}x{}", img.width, img.height));
+            }
+            self.stream_recovery.frame_received(img.sequence);
+            self.set_size(img.width, img.height)?;
+            match img.tile {
+                Some(tile) => tiles.push((tile, img.data)),
+                None => {
+                    let (updated, _) =
+                        self.decoder
+                            .decode_img(&img.data, self.size.0, self.size.1, None);
+                    if self.decoder.take_decode_error() {
+                        self.stream_recovery.decode_error();
+                    }
+                    if updated.is_some() {
+                        frame = self.decoder.data_rgba().clone();
+                    }
+                }
+            }
+        }
+
+        if let Some((tile, _)) = tiles.first() {
+            if self.tiles_decoder.set_layout(
+                self.decoder.as_ref(),
+                tile.count,
+                self.size.0,
+                self.size.1,
+            )? {
+                self.request_keyframe = true;
+            }
+            let (updated, _) = self.tiles_decoder.decode_tiles(&tiles);
+            if self.tiles_decoder.take_decode_error() {
+                self.stream_recovery.decode_error();
+            }
+            if updated.is_some() {
+                frame = self.tiles_decoder.data_rgba().clone();
+            }
+        }
+
+        if let Some(data) = frame {
+            let (width, height) = (self.size.0 as u32, self.size.1 as u32);
+            let img = tunnel::ImageRaw {
+                data,
+                width,
+                height,
+                bytes_per_line: width * 4,
+            };
+            events.push(tunnel::MessageSrv {
+                msg: Some(tunnel::message_srv::Msg::ImgRaw(img)),
+            });
+        }
+        Ok(events)
+    }
+
+    /// Returns true if a keyframe must be requested to the relayed server
+    fn need_keyframe(&mut self, now: Instant) -> bool {
+        let reset = std::mem::take(&mut self.request_keyframe);
+        self.stream_recovery.need_keyframe(now) || reset
+    }
+}
+
+/// Max number of server messages batches waiting to be sent to a viewer. The
+/// batches are dropped for a late viewer, which then requests a keyframe.
+const VIEWER_QUEUE_LEN: usize = 8;
+
+/// Session handshake, replayed to the viewers
+#[derive(Clone)]
+struct ViewerHandshake {
+    version: tunnel::Version,
+    capabilities: tunnel::Capabilities,
+    hello: tunnel::ServerHello,
+}
+
+/// True if the server message is shared with the viewers: the video, cursor
+/// and windows of the session
+fn is_viewer_msg(msg: &tunnel::MessageSrv) -> bool {
+    matches!(
+        msg.msg,
+        Some(tunnel::message_srv::Msg::ImgEncoded(_))
+            | Some(tunnel::message_srv::Msg::FrameStats(_))
+            | Some(tunnel::message_srv::Msg::Display(_))
+            | Some(tunnel::message_srv::Msg::Cursor(_))
+            | Some(tunnel::message_srv::Msg::CursorRef(_))
+            | Some(tunnel::message_srv::Msg::AreaUpdt(_))
+            | Some(tunnel::message_srv::Msg::DeleteArea(_))
+            | Some(tunnel::message_srv::Msg::WinIcon(_))
+            | Some(tunnel::message_srv::Msg::WindowList(_))
+            | Some(tunnel::message_srv::Msg::SoundEncoded(_))
+            | Some(tunnel::message_srv::Msg::SoundRaw(_))
+    )
+}
+
+/// Serve a view only client, fed with the session messages from `queue`
+///
+/// The viewer input is dropped, except its keyframe requests, sent to
+/// `keyframe_sender`, and its heartbeat, answered by the proxy.
+fn run_viewer(
+    mut sock: Box<dyn ReadWrite>,
+    handshake: ViewerHandshake,
+    queue: Receiver<Vec<tunnel::MessageSrv>>,
+    keyframe_sender: Sender<()>,
+) -> Result<()> {
+    let version: tunnel::Version =
+        recv_client_msg_type!(&mut sock, Version).context("Error in recv viewer version")?;
+    check_version(&version.version).context("Incompatible viewer")?;
+    send_server_msg_type!(&mut sock, handshake.version, Version)
+        .context("Error in send Version")?;
+
+    let viewer_capabilities: tunnel::Capabilities = recv_client_msg_type!(&mut sock, Capabilities)
+        .context("Error in recv viewer Capabilities")?;
+    let mut capabilities = negotiated_capabilities(&handshake.capabilities, &viewer_capabilities);
+    capabilities.view_only = true;
+    send_server_msg_type!(&mut sock, capabilities, Capabilities)
+        .context("Error in send Capabilities")?;
+
+    /* The viewer follows the session resolution */
+    let fullscreen = matches!(
+        handshake.hello.msg,
+        Some(tunnel::server_hello::Msg::Fullscreen(_))
+    );
+    send_srv_msg_type!(&mut sock, handshake.hello, Hello).context("Error in send ServerHello")?;
+    if fullscreen {
+        recv_client_msg_type!(&mut sock, Clienthellofullscreen)
+            .context("Error in recv ClientHelloFullscreen")?;
+    } else {
+        recv_client_msg_type!(&mut sock, Clienthelloresolution)
+            .context("Error in recv ClientHelloResolution")?;
+    }
+
+    // The viewer starts decoding from a keyframe
+    let _ = keyframe_sender.send(());
+    let mut pong = None;
+    while let Ok(mut msgs) = queue.recv() {
+        if let Some(pong) = pong.take() {
+            msgs.push(tunnel::MessageSrv {
+                msg: Some(tunnel::message_srv::Msg::Pong(pong)),
+            });
+        }
+        let msgs = tunnel::MessagesSrv { msgs };
+        send_srv_msg_type!(&mut sock, msgs, Msgssrv).context("Error in send MessagesSrv")?;
+
+        let msgs =
+            recv_client_msg_type!(&mut sock, Msgsclient).context("Error in recv MessagesClient")?;
+        for msg in msgs.msgs {
+            match msg.msg {
+                Some(tunnel::message_client::Msg::RequestKeyframe(_)) => {
+                    let _ = keyframe_sender.send(());
+                }
+                Some(tunnel::message_client::Msg::Ping(ping)) => {
+                    pong = Some(heartbeat::pong(&ping));
+                }
+                /* View only: drop the input */
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Accept the pending viewers on `listener`, up to `max_viewers`
+fn accept_viewers(
+    listener: &TcpListener,
+    max_viewers: usize,
+    viewers: &mut Vec<SyncSender<Vec<tunnel::MessageSrv>>>,
+    handshake: &ViewerHandshake,
+    keyframe_sender: &Sender<()>,
+) -> Result<()> {
+    loop {
+        let (viewer, addr) = match listener.accept() {
+            Ok(viewer) => viewer,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+            Err(err) => return Err(err).context("failed to accept viewer"),
+        };
+        viewer
+            .set_nonblocking(false)
+            .context("Error in set_nonblocking")?;
+        viewer.set_nodelay(true).context("Error in set_nodelay")?;
+        let mut viewer: Box<dyn ReadWrite> = Box::new(viewer);
+        if viewers.len() >= max_viewers {
+            warn!("Viewer {:?} rejected: too many viewers", addr);
+            send_srv_err_event(&mut viewer, anyhow!("Too many viewers"));
+            continue;
+        }
+        info!("Viewer {:?}", addr);
+        let (sender, queue) = sync_channel(VIEWER_QUEUE_LEN);
+        viewers.push(sender);
+        let handshake = handshake.clone();
+        let keyframe_sender = keyframe_sender.clone();
+        thread::spawn(move || {
+            if let Err(err) = run_viewer(viewer, handshake, queue, keyframe_sender) {
+                error!("Viewer {:?} error", addr);
+                err.chain().for_each(|cause| error!(" - due to {}", cause));
+            }
+            info!("Viewer {:?} left", addr);
+        });
+    }
+}
+
 /// Exec main loop
 ///
 pub fn run(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<()> {
+    config
+        .audio
+        .opus_settings()
+        .context("Error in audio configuration")?;
     if arguments.keep_listening {
         loop {
             if let Err(err) = run_server(config, arguments) {
@@ -158,12 +473,9 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
         .connection_timeout
         .map(|timeout| std::time::Duration::from_secs(timeout as u64));
 
-    let mut sound_encoder = opus::Encoder::new(
-        SOUND_FREQ,
-        opus::Channels::Mono,
-        opus::Application::LowDelay,
-    )
-    .expect("Cannot create sound encoder");
+    // The server sends raw sound, encoded by the proxy
+    let opus_settings = config.audio.opus_settings()?;
+    let mut sound_encoder = opus_settings.new_encoder()?;
 
     let video_shared_mem = match arguments.extern_img_source.as_deref() {
         Some(extern_img_source) => {
@@ -181,7 +493,7 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
     };
 
     /* wait for client */
-    let mut client: Box<dyn ReadWrite> =
+    let (mut client, client_fd, listener): (Box<dyn ReadWrite>, RawFd, Option<TcpListener>) =
         match (arguments.listen_port, arguments.unix_socket.as_deref()) {
             (listen_port, None) => {
                 let listen_port = listen_port.unwrap_or(1122);
@@ -198,7 +510,8 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
                 let (client, addr) = listener.accept().context("failed to accept connection")?;
                 info!("Client {:?}", addr);
                 client.set_nodelay(true).context("Error in set_nodelay")?;
-                Box::new(client)
+                let fd = client.as_raw_fd();
+                (Box::new(client), fd, Some(listener))
             }
             (None, Some(unix_socket)) => {
                 let client = UnixStream::connect(unix_socket)
@@ -206,7 +519,8 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
                 client
                     .set_connection_timeout(connection_timeout)
                     .context("Cannot set timeout")?;
-                Box::new(client)
+                let fd = client.as_raw_fd();
+                (Box::new(client), fd, None)
             }
             _ => {
                 panic!("Choose between listen port and liten unix path");
@@ -214,7 +528,7 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
         };
 
     /* Connect to server */
-    let mut server: Box<dyn ReadWrite> = if arguments.vsock {
+    let (mut server, server_fd): (Box<dyn ReadWrite>, RawFd) = if arguments.vsock {
         let port = arguments
             .server_port
             .parse::<u32>()
@@ -230,7 +544,8 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
             .set_connection_timeout(connection_timeout)
             .context("Cannot set timeout")?;
         info!("Connected to server");
-        Box::new(server)
+        let fd = server.as_raw_fd();
+        (Box::new(server), fd)
     } else {
         let port = arguments
             .server_port
@@ -245,21 +560,26 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
 
         info!("Connected to server");
         server.set_nodelay(true).expect("set_nodelay call failed");
-        Box::new(server)
+        let fd = server.as_raw_fd();
+        (Box::new(server), fd)
     };
 
+    if arguments.relay && !arguments.transcode {
+        /* The relayed server video is not decoded: forward the connection */
+        if arguments.max_viewers.is_some() {
+            warn!("Viewers need the relayed video to be transcoded, they are disabled");
+        }
+        let reason = loop_fwd_conn((client.as_mut(), client_fd), (server.as_mut(), server_fd))?;
+        info!("End of relay: {}", reason);
+        return Ok(());
+    }
+
     /* Recv client version */
     let client_version: tunnel::Version =
         recv_client_msg_type!(&mut client, Version).context("Error in send client version")?;
 
     info!("Client version {:?}", client_version);
-    if client_version.version != VERSION {
-        return Err(anyhow!(
-            "Version mismatch server: {:?} client: {:?}",
-            VERSION,
-            client_version.version
-        ));
-    }
+    check_version(&client_version.version).context("Incompatible client")?;
 
     /* Forward version to server */
     send_client_msg_type!(&mut server, client_version, Version).context("Error in send Version")?;
@@ -269,17 +589,37 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
         recv_server_msg_type!(&mut server, Version).context("Error in recv server version")?;
 
     info!("Server version {:?}", server_version);
-    if server_version.version != VERSION {
-        return Err(anyhow!(
-            "Version mismatch server: {:?} client: {:?}",
-            server_version.version,
-            VERSION,
-        ));
-    }
+    check_version(&server_version.version).context("Incompatible server")?;
 
     /* Forward version to client */
+    let version = server_version.clone();
     send_server_msg_type!(&mut client, server_version, Version).context("Error in send Version")?;
 
+    /* Forward capabilities. Messages are not compressed. Unless relayed, the
+     * server sends raw sound, encoded with opus by the proxy. A relayed
+     * server negotiates its audio codec with the client */
+    let mut client_capabilities: tunnel::Capabilities =
+        recv_client_msg_type!(&mut client, Capabilities)
+            .context("Error in recv client Capabilities")?;
+    if !arguments.relay {
+        client_capabilities.audio_codecs = vec![tunnel::AudioCodec::Raw as i32];
+        // The client receives the chroma of the proxy encoder
+        client_capabilities.adaptive_chroma = false;
+    }
+    client_capabilities.compression = false;
+    send_client_msg_type!(&mut server, client_capabilities, Capabilities)
+        .context("Error in send Capabilities")?;
+    let mut server_capabilities: tunnel::Capabilities =
+        recv_server_msg_type!(&mut server, Capabilities)
+            .context("Error in recv server Capabilities")?;
+    if !arguments.relay {
+        server_capabilities.audio_codecs = vec![tunnel::AudioCodec::Opus as i32];
+    }
+    server_capabilities.compression = false;
+    let capabilities = server_capabilities.clone();
+    send_server_msg_type!(&mut client, server_capabilities, Capabilities)
+        .context("Error in send Capabilities")?;
+
     /* recv server hello */
     let msg = recv_srv_msg_type!(&mut server, Hello)
         .context("Error in recv ServerHello")
@@ -296,38 +636,90 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
     };
 
     /* Send server hello with image info & codec name */
+    let yuv_type = config.video.color_space.unwrap_or(YuvType::ItuT871);
+    let color_range = config
+        .video
+        .color_range
+        .unwrap_or_else(|| yuv_type.default_range());
+    // The relayed server encodes the video with its own codec
+    let relay_codec_name = arguments.relay.then(|| msg.codec_name.to_owned());
+    let (audio, audio_codec) = match arguments.relay {
+        true => (msg.audio, msg.audio_codec),
+        false => (arguments.audio, tunnel::AudioCodec::Opus as i32),
+    };
     let server_hello = tunnel::ServerHello {
         codec_name,
-        audio: arguments.audio,
+        audio,
         msg: msg.msg,
+        color_space: tunnel::ColorSpace::from(yuv_type) as i32,
+        color_range: tunnel::ColorRange::from(color_range) as i32,
+        relative_mouse: msg.relative_mouse,
+        audio_codec,
+        microphone: msg.microphone,
+        audio_format: msg.audio_format,
+        clipboard_policy: msg.clipboard_policy,
+        primary_policy: msg.primary_policy,
+        chroma: config
+            .video
+            .chroma
+            .map_or(tunnel::Chroma::Unspecified, tunnel::Chroma::from) as i32,
+        keyboard_layout: msg.keyboard_layout,
     };
 
+    let viewer_handshake = ViewerHandshake {
+        version,
+        capabilities,
+        hello: server_hello.clone(),
+    };
     send_srv_msg_type!(&mut client, server_hello, Hello)
         .context("Error in send ServerHello")
         .map_err(|err| send_client_err_event(&mut server, err))?;
 
-    let mut screen_size = if let Some((width, height)) = server_size {
+    let mut chroma;
+    let (mut screen_size, (yuv_type, color_range)) = if let Some((width, height)) = server_size {
         /* recv client hello with audio bool */
-        let msg = recv_client_msg_type!(&mut client, Clienthellofullscreen)
+        let mut msg = recv_client_msg_type!(&mut client, Clienthellofullscreen)
             .context("Error in recv ClientHelloFullscreen")
             .map_err(|err| send_client_err_event(&mut server, err))?;
         debug!("{:?}", msg);
+        if !arguments.relay {
+            msg.audio_codec = tunnel::AudioCodec::Raw as i32;
+        }
+        let color_space = negotiated_color_space(msg.color_space(), msg.color_range());
+        chroma = negotiated_chroma(msg.chroma());
         send_client_msg_type!(&mut server, msg, Clienthellofullscreen)
             .context("Error in send ClientHelloFullscreen")
             .map_err(|err| send_srv_err_event(&mut client, err))?;
-        (width, height)
+        ((width, height), color_space)
     } else {
         /* recv client hello with audio bool */
-        let msg = recv_client_msg_type!(&mut client, Clienthelloresolution)
+        let mut msg = recv_client_msg_type!(&mut client, Clienthelloresolution)
             .context("Error in recv ClientHelloResolution")
             .map_err(|err| send_client_err_event(&mut server, err))?;
 
         debug!("{:?}", msg);
+        if !arguments.relay {
+            msg.audio_codec = tunnel::AudioCodec::Raw as i32;
+        }
         let (width, height) = (msg.width as u16, msg.height as u16);
+        let color_space = negotiated_color_space(msg.color_space(), msg.color_range());
+        chroma = negotiated_chroma(msg.chroma());
         send_client_msg_type!(&mut server, msg, Clienthelloresolution)
             .context("Error in recv ClientHelloResolution")
             .map_err(|err| send_srv_err_event(&mut client, err))?;
-        (width, height)
+        ((width, height), color_space)
+    };
+
+    // The relayed server encodes with the color space requested by the client
+    let mut transcoder = match relay_codec_name {
+        Some(relay_codec_name) => {
+            info!("Relayed server codec {:?}", relay_codec_name);
+            Some(
+                Transcoder::new(&relay_codec_name, yuv_type, color_range)
+                    .map_err(|err| send_srv_err_event(&mut client, err))?,
+            )
+        }
+        None => None,
     };
 
     let mut video_encoder = init_video_encoder(
@@ -336,6 +728,11 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
         config.ffmpeg_options(Some(arguments.encoder.as_str())),
         &config.video.ffmpeg_options_cmd,
         (screen_size.0, screen_size.1),
+        yuv_type,
+        color_range,
+        config.video.gop_settings(),
+        config.video.vaapi_device.as_deref(),
+        chroma,
     )?;
 
     // Do socket control
@@ -364,8 +761,28 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
         });
     }
 
+    // View only clients, watching the session of the first client
+    let viewers_listener = match (arguments.max_viewers, listener) {
+        (Some(_), Some(listener)) => {
+            listener
+                .set_nonblocking(true)
+                .context("Error in set_nonblocking")?;
+            Some(listener)
+        }
+        (Some(_), None) => {
+            warn!("Viewers need a listen port, they are disabled");
+            None
+        }
+        _ => None,
+    };
+    let mut viewers = vec![];
+    let (keyframe_sender, keyframe_receiver) = channel();
+
     let mut count = 0;
     let mut sound_data = vec![];
+    // Last raw frame, on which damaged regions are composited
+    let mut last_frame: Option<Vec<u8>> = None;
+    let mut frame_sequence = 1;
     loop {
         // Test is we receiver control message
         let mut control_msg = false;
@@ -379,6 +796,25 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
                 .context("Cannot reload encoder in control management")?;
         }
 
+        if let (Some(listener), Some(max_viewers)) =
+            (viewers_listener.as_ref(), arguments.max_viewers)
+        {
+            if let Err(err) = accept_viewers(
+                listener,
+                max_viewers,
+                &mut viewers,
+                &viewer_handshake,
+                &keyframe_sender,
+            ) {
+                error!("Cannot accept viewer");
+                err.chain().for_each(|cause| error!(" - due to {}", cause));
+            }
+        }
+        if keyframe_receiver.try_iter().count() != 0 {
+            /* A viewer joined or lost the stream */
+            video_encoder.request_keyframe();
+        }
+
         /* Recv from server*/
         let msgs = recv_srv_msg_type!(&mut server, Msgssrv)
             .context("Error in recv MessagesSrv")
@@ -387,15 +823,35 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
         let mut time_encode_video: Option<String> = None;
         let mut time_encode_sound: Option<String> = None;
 
+        let msgs = match transcoder.as_mut() {
+            Some(transcoder) => {
+                let time_decode_start = Instant::now();
+                let msgs = transcoder
+                    .decode(msgs.msgs)
+                    .map_err(|err| send_srv_err_event(&mut client, err))?;
+                trace!(
+                    "Relayed video decoded in {:.1?}",
+                    time_decode_start.elapsed()
+                );
+                msgs
+            }
+            None => msgs.msgs,
+        };
+
         let mut events = vec![];
-        for msg in msgs.msgs {
+        for msg in msgs {
             match msg.msg {
                 /* Disallow encoded image form server to client*/
                 Some(tunnel::message_srv::Msg::ImgEncoded(_img)) => {
                     warn!("Filtering out encoded image");
                 }
-                Some(tunnel::message_srv::Msg::SoundEncoded(_sound)) => {
-                    warn!("Server sent encoded sound");
+                Some(tunnel::message_srv::Msg::SoundEncoded(sound)) => {
+                    if arguments.relay {
+                        let msg = tunnel::message_srv::Msg::SoundEncoded(sound);
+                        events.push(tunnel::MessageSrv { msg: Some(msg) });
+                    } else {
+                        warn!("Server sent encoded sound");
+                    }
                 }
 
                 Some(tunnel::message_srv::Msg::Stats(msg_stats)) => {
@@ -407,7 +863,8 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
                         events.push(msg);
                     }
                 }
-                event @ Some(tunnel::message_srv::Msg::Clipboard(_)) => {
+                event @ Some(tunnel::message_srv::Msg::Clipboard(_))
+                | event @ Some(tunnel::message_srv::Msg::ClipboardChunk(_)) => {
                     if !arguments.disable_server_clipboard {
                         events.push(tunnel::MessageSrv { msg: event });
                     }
@@ -460,6 +917,11 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
                             config.ffmpeg_options(Some(arguments.encoder.as_str())),
                             &config.video.ffmpeg_options_cmd,
                             (width as u16, height as u16),
+                            yuv_type,
+                            color_range,
+                            config.video.gop_settings(),
+                            config.video.vaapi_device.as_deref(),
+                            chroma,
                         )
                         .context("Error in init_encoder")?;
                         screen_size.0 = width as u16;
@@ -485,11 +947,59 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
                         data: encoded,
                         width: img.width,
                         height: img.height,
+                        sequence: frame_sequence,
+                        tile: None,
+                    });
+                    frame_sequence = next_sequence(frame_sequence);
+                    let msg_img = tunnel::MessageSrv { msg: Some(msg) };
+                    events.push(msg_img);
+                    if let Some(frame_stats) = timings.frame {
+                        events.push(frame_stats.to_msg());
+                    }
+                    let full_frame = img.width as usize * img.height as usize * 4;
+                    last_frame = if video_shared_mem.is_none()
+                        && img.bytes_per_line == img.width * 4
+                        && img.data.len() == full_frame
+                    {
+                        Some(img.data)
+                    } else {
+                        None
+                    };
+                }
+                Some(tunnel::message_srv::Msg::ImgRawRegion(region)) => {
+                    /* Composite region on the last raw frame and encode it */
+                    let frame = match last_frame.as_mut() {
+                        Some(frame) => frame,
+                        None => {
+                            warn!("Region without previous frame");
+                            continue;
+                        }
+                    };
+                    let time_encode_start = Instant::now();
+                    let (width, height) = (screen_size.0 as u32, screen_size.1 as u32);
+                    blit_region(frame, width, height, &region).context("Error in blit region")?;
+                    let (encoded, timings) = video_encoder
+                        .encode_image(frame, width, height, width * 4, count)
+                        .context("Error in encode region")?;
+                    let time_encode_stop = Instant::now();
+                    time_encode_video =
+                        Some(format!("{:.1?}", time_encode_stop - time_encode_start));
+                    count += 1;
+                    let msg = tunnel::message_srv::Msg::ImgEncoded(tunnel::ImageEncoded {
+                        data: encoded,
+                        width,
+                        height,
+                        sequence: frame_sequence,
+                        tile: None,
                     });
+                    frame_sequence = next_sequence(frame_sequence);
                     let msg_img = tunnel::MessageSrv { msg: Some(msg) };
                     events.push(msg_img);
+                    if let Some(frame_stats) = timings.frame {
+                        events.push(frame_stats.to_msg());
+                    }
                 }
-                Some(tunnel::message_srv::Msg::SoundRaw(sound_raw)) => {
+                Some(tunnel::message_srv::Msg::SoundRaw(sound_raw)) if !arguments.relay => {
                     /* Encode raw sound */
                     let mut rdr = Cursor::new(sound_raw.data);
                     let time_encode_start = Instant::now();
@@ -497,7 +1007,11 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
                         sound_data.push(sample);
                     }
 
-                    if let Some(sound_event) = encode_sound(&mut sound_encoder, &mut sound_data) {
+                    if let Some(sound_event) = encode_sound(
+                        &mut sound_encoder,
+                        opus_settings.frame_len(),
+                        &mut sound_data,
+                    ) {
                         events.push(sound_event);
                     }
                     let time_encode_stop = Instant::now();
@@ -512,6 +1026,22 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
             }
         }
 
+        if !viewers.is_empty() {
+            let shared: Vec<_> = events
+                .iter()
+                .filter(|msg| is_viewer_msg(msg))
+                .cloned()
+                .collect();
+            viewers.retain(|viewer| match viewer.try_send(shared.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    debug!("Late viewer, messages dropped");
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+        }
+
         let msgs = tunnel::MessagesSrv { msgs: events };
         send_srv_msg_type!(&mut client, msgs, Msgssrv)
             .context("Error in send MessagesSrv")
@@ -525,11 +1055,41 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
         let mut events = vec![];
         for msg in msgs.msgs {
             match msg.msg {
-                event @ Some(tunnel::message_client::Msg::Clipboard(_)) => {
+                event @ Some(tunnel::message_client::Msg::Clipboard(_))
+                | event @ Some(tunnel::message_client::Msg::ClipboardChunk(_)) => {
                     if !arguments.disable_client_clipboard {
                         events.push(tunnel::MessageClient { msg: event });
                     }
                 }
+                Some(tunnel::message_client::Msg::RequestKeyframe(_)) => {
+                    /* Video is encoded by the proxy */
+                    video_encoder.request_keyframe();
+                }
+                Some(tunnel::message_client::Msg::RequestChroma(request)) => {
+                    /* Video is encoded by the proxy */
+                    let requested = negotiated_chroma(request.chroma());
+                    match init_video_encoder(
+                        &arguments.encoder,
+                        config.ffmpeg_options(None),
+                        config.ffmpeg_options(Some(arguments.encoder.as_str())),
+                        &config.video.ffmpeg_options_cmd,
+                        screen_size,
+                        yuv_type,
+                        color_range,
+                        config.video.gop_settings(),
+                        config.video.vaapi_device.as_deref(),
+                        requested,
+                    ) {
+                        Ok(encoder) => {
+                            video_encoder = encoder;
+                            chroma = requested;
+                        }
+                        Err(err) => {
+                            error!("Cannot change the encoder chroma");
+                            err.chain().for_each(|cause| error!(" - due to {}", cause));
+                        }
+                    }
+                }
                 Some(msg) => {
                     /* Forward other events */
                     events.push(tunnel::MessageClient { msg: Some(msg) });
@@ -537,6 +1097,16 @@ pub fn run_server(config: &ConfigServer, arguments: &ProxyArgsConfig) -> Result<
                 _ => {}
             }
         }
+        if let Some(transcoder) = transcoder.as_mut() {
+            if transcoder.need_keyframe(Instant::now()) {
+                debug!("Request keyframe to the relayed server");
+                events.push(tunnel::MessageClient {
+                    msg: Some(tunnel::message_client::Msg::RequestKeyframe(
+                        tunnel::RequestKeyframe {},
+                    )),
+                });
+            }
+        }
         let msgs = tunnel::MessagesClient { msgs: events };
 
         send_client_msg_type!(&mut server, msgs, Msgsclient)
@@ -0,0 +1,761 @@
+//! Session recording
+//!
+//! The encoded frames sent to the client are muxed as is with the ffmpeg
+//! muxer guessed from the file name (mkv, ...): the video is not encoded a
+//! second time. The sound is stored as pcm: the opus packets are decoded, as
+//! they are only meaningful to the sanzu client.
+//!
+//! Packets are timestamped with the wall clock time since the recording start.
+//! The container header is written on the first keyframe, whose parameter sets
+//! (sps, pps, ...) are used as the codec extra data.
+//!
+//! With a recording key, the container and the events log are encrypted on the
+//! fly (see `recording_crypto`). The muxer output is not seekable then, so the
+//! container must be streamable (mkv, mpegts, ...).
+
+use crate::{
+    ffmpeg_helper::{averror, AVPacket},
+    recording_crypto::{EncryptedWriter, RECORDING_KEY_LEN},
+    sound::SOUND_FREQ,
+    utils::ClipboardContent,
+};
+use anyhow::{Context, Result};
+use ffmpeg_sys_next as ffmpeg;
+use sanzu_common::tunnel;
+use std::{
+    ffi::CString,
+    fs::File,
+    io::{self, BufWriter, Write},
+    os::raw::{c_int, c_void},
+    ptr::null_mut,
+    time::Instant,
+};
+
+/// Maximum number of decoded samples of an opus packet (120 ms at 48 kHz)
+const MAX_OPUS_SAMPLES: usize = 5760;
+/// Buffer size of the encrypted muxer output
+const AVIO_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Video codecs which can be recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VideoCodec {
+    H264,
+    Hevc,
+}
+
+impl VideoCodec {
+    fn new(codec_name: &str) -> Result<Self> {
+        match codec_name {
+            "h264" => Ok(VideoCodec::H264),
+            "hevc" => Ok(VideoCodec::Hevc),
+            _ => Err(anyhow!("Cannot record {:?} video", codec_name)),
+        }
+    }
+
+    fn codec_id(&self) -> ffmpeg::AVCodecID {
+        match self {
+            VideoCodec::H264 => ffmpeg::AVCodecID::AV_CODEC_ID_H264,
+            VideoCodec::Hevc => ffmpeg::AVCodecID::AV_CODEC_ID_HEVC,
+        }
+    }
+
+    /// Returns true if the nal unit is a parameter set
+    fn is_parameter_set(&self, nal: &[u8]) -> bool {
+        match (self, nal.first()) {
+            (VideoCodec::H264, Some(header)) => matches!(header & 0x1f, 7 | 8),
+            (VideoCodec::Hevc, Some(header)) => matches!((header >> 1) & 0x3f, 32..=34),
+            _ => false,
+        }
+    }
+
+    /// Returns true if the nal unit starts a keyframe
+    fn is_keyframe(&self, nal: &[u8]) -> bool {
+        match (self, nal.first()) {
+            (VideoCodec::H264, Some(header)) => header & 0x1f == 5,
+            (VideoCodec::Hevc, Some(header)) => matches!((header >> 1) & 0x3f, 16..=21),
+            _ => false,
+        }
+    }
+}
+
+/// Split an annex b bitstream in nal units, without their start codes
+fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = vec![];
+    let mut index = 0;
+    while index + 3 <= data.len() {
+        if data[index..index + 3] == [0, 0, 1] {
+            starts.push(index + 3);
+            index += 3;
+        } else {
+            index += 1;
+        }
+    }
+    let mut nals = vec![];
+    for (i, start) in starts.iter().enumerate() {
+        let mut end = starts.get(i + 1).map(|next| next - 3).unwrap_or(data.len());
+        // Trailing zero of a 4 bytes start code
+        while end > *start && data[end - 1] == 0 && starts.get(i + 1).is_some() {
+            end -= 1;
+        }
+        nals.push(&data[*start..end]);
+    }
+    nals
+}
+
+/// Parameter sets of a keyframe, as an annex b bitstream. Returns None if the
+/// frame is not a keyframe.
+fn keyframe_parameter_sets(codec: VideoCodec, data: &[u8]) -> Option<Vec<u8>> {
+    let nals = nal_units(data);
+    if !nals.iter().any(|nal| codec.is_keyframe(nal)) {
+        return None;
+    }
+    let mut parameter_sets = vec![];
+    for nal in nals.iter().filter(|nal| codec.is_parameter_set(nal)) {
+        parameter_sets.extend_from_slice(&[0, 0, 0, 1]);
+        parameter_sets.extend_from_slice(nal);
+    }
+    Some(parameter_sets)
+}
+
+/// Copy `data` to a buffer allocated by ffmpeg, as codec extra data
+unsafe fn set_extradata(par: *mut ffmpeg::AVCodecParameters, data: &[u8]) -> Result<()> {
+    let size = data.len() + ffmpeg::AV_INPUT_BUFFER_PADDING_SIZE as usize;
+    let extradata = ffmpeg::av_mallocz(size) as *mut u8;
+    if extradata.is_null() {
+        return Err(anyhow!("Error in av_mallocz"));
+    }
+    std::ptr::copy_nonoverlapping(data.as_ptr(), extradata, data.len());
+    (*par).extradata = extradata;
+    (*par).extradata_size = data.len() as i32;
+    Ok(())
+}
+
+/// Muxer output callback, `opaque` is the `EncryptedWriter` of the muxer
+unsafe extern "C" fn write_encrypted(
+    opaque: *mut c_void,
+    buf: *const u8,
+    buf_size: c_int,
+) -> c_int {
+    let writer = &mut *(opaque as *mut EncryptedWriter<File>);
+    let data = std::slice::from_raw_parts(buf, buf_size as usize);
+    match writer.write_all(data) {
+        Ok(()) => buf_size,
+        Err(err) => {
+            error!("Cannot write recording: {}", err);
+            -libc::EIO
+        }
+    }
+}
+
+/// Container output, created on the first keyframe
+struct Muxer {
+    context: *mut ffmpeg::AVFormatContext,
+    video_stream: i32,
+    audio_stream: Option<i32>,
+    packet: AVPacket,
+    /// Last written timestamp of each stream, in ms
+    last_pts: [i64; 2],
+    trailer_written: bool,
+    /// Destination of the custom io context, if the recording is encrypted
+    encrypted: Option<Box<EncryptedWriter<File>>>,
+}
+
+impl Muxer {
+    /// Create the container `path`, encrypted for `record_key` if set
+    fn new(
+        path: &str,
+        codec: VideoCodec,
+        (width, height): (u32, u32),
+        parameter_sets: &[u8],
+        audio_format: Option<&tunnel::AudioFormat>,
+        record_key: Option<&[u8; RECORDING_KEY_LEN]>,
+    ) -> Result<Self> {
+        let path_c = CString::new(path).context("Error in CString")?;
+        let packet = AVPacket::new()?;
+        let mut context = null_mut();
+        let retval = unsafe {
+            ffmpeg::avformat_alloc_output_context2(
+                &mut context,
+                null_mut(),
+                null_mut(),
+                path_c.as_ptr(),
+            )
+        };
+        if retval < 0 || context.is_null() {
+            return Err(averror(
+                &format!("avformat_alloc_output_context2 {path:?}"),
+                retval,
+            ));
+        }
+        // Freed on error from now on
+        let mut muxer = Muxer {
+            context,
+            video_stream: 0,
+            audio_stream: None,
+            packet,
+            last_pts: [-1; 2],
+            trailer_written: true,
+            encrypted: None,
+        };
+
+        unsafe {
+            let stream = ffmpeg::avformat_new_stream(context, null_mut());
+            if stream.is_null() {
+                return Err(anyhow!("Error in avformat_new_stream"));
+            }
+            let par = (*stream).codecpar;
+            (*par).codec_type = ffmpeg::AVMediaType::AVMEDIA_TYPE_VIDEO;
+            (*par).codec_id = codec.codec_id();
+            (*par).width = width as i32;
+            (*par).height = height as i32;
+            set_extradata(par, parameter_sets)?;
+            (*stream).time_base = ffmpeg::AVRational { num: 1, den: 1000 };
+            muxer.video_stream = (*stream).index;
+
+            if let Some(audio_format) = audio_format {
+                let stream = ffmpeg::avformat_new_stream(context, null_mut());
+                if stream.is_null() {
+                    return Err(anyhow!("Error in avformat_new_stream"));
+                }
+                let par = (*stream).codecpar;
+                (*par).codec_type = ffmpeg::AVMediaType::AVMEDIA_TYPE_AUDIO;
+                (*par).codec_id = ffmpeg::AVCodecID::AV_CODEC_ID_PCM_S16LE;
+                (*par).sample_rate = audio_format.sample_rate as i32;
+                ffmpeg::av_channel_layout_default(
+                    &mut (*par).ch_layout,
+                    audio_format.channels as i32,
+                );
+                (*par).bits_per_coded_sample = 16;
+                (*par).block_align = 2 * audio_format.channels as i32;
+                (*stream).time_base = ffmpeg::AVRational { num: 1, den: 1000 };
+                muxer.audio_stream = Some((*stream).index);
+            }
+
+            let has_file = (*(*context).oformat).flags & ffmpeg::AVFMT_NOFILE as i32 == 0;
+            match record_key {
+                Some(record_key) => {
+                    if !has_file {
+                        return Err(anyhow!("Cannot encrypt a {:?} recording", path));
+                    }
+                    let file =
+                        File::create(path).context(format!("Cannot create recording {path:?}"))?;
+                    let mut writer = Box::new(EncryptedWriter::new(file, record_key)?);
+                    let buffer = ffmpeg::av_malloc(AVIO_BUFFER_SIZE) as *mut u8;
+                    if buffer.is_null() {
+                        return Err(anyhow!("Error in av_malloc"));
+                    }
+                    // Not seekable: the muxer writes the container sequentially
+                    let pb = ffmpeg::avio_alloc_context(
+                        buffer,
+                        AVIO_BUFFER_SIZE as i32,
+                        1,
+                        writer.as_mut() as *mut EncryptedWriter<File> as *mut c_void,
+                        None,
+                        Some(write_encrypted),
+                        None,
+                    );
+                    if pb.is_null() {
+                        ffmpeg::av_free(buffer as *mut c_void);
+                        return Err(anyhow!("Error in avio_alloc_context"));
+                    }
+                    (*context).pb = pb;
+                    (*context).flags |= ffmpeg::AVFMT_FLAG_CUSTOM_IO as i32;
+                    muxer.encrypted = Some(writer);
+                }
+                None if has_file => {
+                    let retval = ffmpeg::avio_open(
+                        &mut (*context).pb,
+                        path_c.as_ptr(),
+                        ffmpeg::AVIO_FLAG_WRITE as i32,
+                    );
+                    if retval < 0 {
+                        return Err(averror(&format!("avio_open {path:?}"), retval));
+                    }
+                }
+                None => {}
+            }
+            let retval = ffmpeg::avformat_write_header(context, null_mut());
+            if retval < 0 {
+                return Err(averror("avformat_write_header", retval));
+            }
+        }
+        muxer.trailer_written = false;
+        Ok(muxer)
+    }
+
+    /// Write a packet of `stream_index`, timestamped in ms
+    fn write(&mut self, stream_index: i32, data: &[u8], pts_ms: i64, key: bool) -> Result<()> {
+        // Timestamps must be strictly increasing in each stream
+        let last_pts = &mut self.last_pts[(stream_index != self.video_stream) as usize];
+        let pts_ms = pts_ms.max(*last_pts + 1);
+        *last_pts = pts_ms;
+        unsafe {
+            let stream = *(*self.context).streams.add(stream_index as usize);
+            let pts = ffmpeg::av_rescale_q(
+                pts_ms,
+                ffmpeg::AVRational { num: 1, den: 1000 },
+                (*stream).time_base,
+            );
+            let packet = self.packet.as_mut_ptr();
+            // The packet is not reference counted: the muxer copies the data
+            (*packet).data = data.as_ptr() as *mut u8;
+            (*packet).size = data.len() as i32;
+            (*packet).stream_index = stream_index;
+            (*packet).pts = pts;
+            (*packet).dts = pts;
+            (*packet).flags = match key {
+                true => ffmpeg::AV_PKT_FLAG_KEY as i32,
+                false => 0,
+            };
+            let retval = ffmpeg::av_interleaved_write_frame(self.context, packet);
+            if retval < 0 {
+                return Err(averror("av_interleaved_write_frame", retval));
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush the interleaving queue and finalize the container, then
+    /// authenticate the end of the encrypted recording
+    fn finish(&mut self) -> Result<()> {
+        if self.trailer_written {
+            return Ok(());
+        }
+        self.trailer_written = true;
+        // Flushes the io context
+        let retval = unsafe { ffmpeg::av_write_trailer(self.context) };
+        if retval < 0 {
+            return Err(averror("av_write_trailer", retval));
+        }
+        if let Some(writer) = self.encrypted.as_mut() {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Muxer {
+    fn drop(&mut self) {
+        unsafe {
+            if self.encrypted.is_some() {
+                let pb = (*self.context).pb;
+                if !pb.is_null() {
+                    // The buffer may have been reallocated by ffmpeg
+                    ffmpeg::av_freep(&mut (*pb).buffer as *mut *mut u8 as *mut c_void);
+                }
+                ffmpeg::avio_context_free(&mut (*self.context).pb);
+            } else if (*(*self.context).oformat).flags & ffmpeg::AVFMT_NOFILE as i32 == 0 {
+                ffmpeg::avio_closep(&mut (*self.context).pb);
+            }
+            ffmpeg::avformat_free_context(self.context);
+        }
+    }
+}
+
+/// Input and clipboard events log, encrypted with the recording
+enum EventsLog {
+    Plain(BufWriter<File>),
+    Encrypted(EncryptedWriter<File>),
+}
+
+impl EventsLog {
+    fn finish(&mut self) -> Result<()> {
+        match self {
+            EventsLog::Plain(writer) => writer.flush().context("Cannot flush events log"),
+            EventsLog::Encrypted(writer) => writer.finish().context("Cannot finish events log"),
+        }
+    }
+}
+
+impl Write for EventsLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            EventsLog::Plain(writer) => writer.write(buf),
+            EventsLog::Encrypted(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            EventsLog::Plain(writer) => writer.flush(),
+            EventsLog::Encrypted(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Records the session video, sound and input events
+///
+/// `finish` must be called at the end of the session to finalize the
+/// recording. It is not done on drop: a recording interrupted by an error or a
+/// crash is left unfinalized, and encrypted ones are reported as truncated.
+pub struct Recorder {
+    path: String,
+    codec: VideoCodec,
+    start: Instant,
+    muxer: Option<Muxer>,
+    audio_format: Option<tunnel::AudioFormat>,
+    opus_decoder: Option<opus::Decoder>,
+    /// Recorded sound samples, per channel
+    audio_samples: u64,
+    /// Time of the first recorded sound sample, in ms
+    audio_start_ms: Option<i64>,
+    events: Option<EventsLog>,
+    /// X25519 public key the recording is encrypted for
+    record_key: Option<[u8; RECORDING_KEY_LEN]>,
+    finished: bool,
+}
+
+/// Clipboard event summary: images are not logged
+fn clipboard_summary(event: &tunnel::EventClipboard) -> String {
+    match &event.content {
+        Some(ClipboardContent::Text(text)) => format!("Clipboard {text:?}"),
+        Some(ClipboardContent::Image(image)) => {
+            format!("Clipboard image {}x{}", image.width, image.height)
+        }
+        None => "Clipboard empty".to_owned(),
+    }
+}
+
+impl Recorder {
+    /// Record the `codec_name` video to `path`, and the sound if
+    /// `audio_params` is set. The input and clipboard events are logged to
+    /// `events_path`, if set. Both are encrypted for the x25519 public
+    /// `record_key`, if set.
+    pub fn new(
+        path: &str,
+        codec_name: &str,
+        audio_params: Option<(tunnel::AudioFormat, tunnel::AudioCodec)>,
+        events_path: Option<&str>,
+        record_key: Option<&[u8; RECORDING_KEY_LEN]>,
+    ) -> Result<Self> {
+        let codec = VideoCodec::new(codec_name)?;
+        let opus_decoder = match audio_params {
+            Some((_, tunnel::AudioCodec::Opus)) => Some(
+                opus::Decoder::new(SOUND_FREQ, opus::Channels::Mono)
+                    .context("Cannot create sound decoder")?,
+            ),
+            _ => None,
+        };
+        let events = match events_path {
+            Some(events_path) => {
+                let file = File::create(events_path)
+                    .context(format!("Cannot create events log {events_path:?}"))?;
+                Some(match record_key {
+                    Some(record_key) => EventsLog::Encrypted(
+                        EncryptedWriter::new(file, record_key)
+                            .context("Cannot encrypt events log")?,
+                    ),
+                    None => EventsLog::Plain(BufWriter::new(file)),
+                })
+            }
+            None => None,
+        };
+        info!("Record session to {:?}", path);
+        Ok(Recorder {
+            path: path.to_owned(),
+            codec,
+            start: Instant::now(),
+            muxer: None,
+            audio_format: audio_params.map(|(audio_format, _)| audio_format),
+            opus_decoder,
+            audio_samples: 0,
+            audio_start_ms: None,
+            events,
+            record_key: record_key.copied(),
+            finished: false,
+        })
+    }
+
+    fn elapsed_ms(&self) -> i64 {
+        self.start.elapsed().as_millis() as i64
+    }
+
+    fn record_video(&mut self, img: &tunnel::ImageEncoded) -> Result<()> {
+        let pts_ms = self.elapsed_ms();
+        let key = if self.muxer.is_some() {
+            nal_units(&img.data)
+                .iter()
+                .any(|nal| self.codec.is_keyframe(nal))
+        } else {
+            // Frames are skipped until the first keyframe
+            let parameter_sets = match keyframe_parameter_sets(self.codec, &img.data) {
+                Some(parameter_sets) => parameter_sets,
+                None => return Ok(()),
+            };
+            let muxer = Muxer::new(
+                &self.path,
+                self.codec,
+                (img.width, img.height),
+                &parameter_sets,
+                self.audio_format.as_ref(),
+                self.record_key.as_ref(),
+            )
+            .context("Cannot create recording")?;
+            self.muxer = Some(muxer);
+            true
+        };
+        if let Some(muxer) = self.muxer.as_mut() {
+            let stream_index = muxer.video_stream;
+            muxer.write(stream_index, &img.data, pts_ms, key)?;
+        }
+        Ok(())
+    }
+
+    fn record_sound(&mut self, samples: &[u8]) -> Result<()> {
+        let (muxer, audio_format) = match (self.muxer.as_mut(), self.audio_format.as_ref()) {
+            (Some(muxer), Some(audio_format)) => (muxer, audio_format),
+            _ => return Ok(()),
+        };
+        let stream_index = match muxer.audio_stream {
+            Some(stream_index) => stream_index,
+            None => return Ok(()),
+        };
+        let elapsed_ms = self.start.elapsed().as_millis() as i64;
+        let audio_start_ms = *self.audio_start_ms.get_or_insert(elapsed_ms);
+        let pts_ms = audio_start_ms
+            + (self.audio_samples * 1000 / audio_format.sample_rate.max(1) as u64) as i64;
+        muxer.write(stream_index, samples, pts_ms, true)?;
+        self.audio_samples += (samples.len() / (2 * audio_format.channels.max(1) as usize)) as u64;
+        Ok(())
+    }
+
+    fn record_opus(&mut self, pkt: &[u8]) -> Result<()> {
+        let decoder = match self.opus_decoder.as_mut() {
+            Some(decoder) => decoder,
+            None => return Ok(()),
+        };
+        let mut output = vec![0i16; MAX_OPUS_SAMPLES];
+        let len = decoder
+            .decode(pkt, &mut output, false)
+            .context("Cannot decode sound")?;
+        let samples: Vec<u8> = output[..len]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        self.record_sound(&samples)
+    }
+
+    fn log_event(&mut self, direction: &str, event: &dyn std::fmt::Display) -> Result<()> {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if let Some(events) = self.events.as_mut() {
+            writeln!(events, "{elapsed:.3} {direction} {event}")
+                .context("Cannot write events log")?;
+        }
+        Ok(())
+    }
+
+    /// Record the video and sound of the messages sent to the client
+    pub fn record_media(&mut self, msgs: &[tunnel::MessageSrv]) -> Result<()> {
+        for msg in msgs {
+            match &msg.msg {
+                Some(tunnel::message_srv::Msg::ImgEncoded(img)) => self.record_video(img)?,
+                Some(tunnel::message_srv::Msg::SoundEncoded(sound)) => {
+                    for pkt in sound.data.iter() {
+                        self.record_opus(pkt)?;
+                    }
+                }
+                Some(tunnel::message_srv::Msg::SoundRaw(sound)) => {
+                    self.record_sound(&sound.data)?
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Log the clipboard events sent to the client
+    pub fn record_server_events(&mut self, msgs: &[tunnel::MessageSrv]) -> Result<()> {
+        for msg in msgs {
+            if let Some(tunnel::message_srv::Msg::Clipboard(event)) = &msg.msg {
+                self.log_event("server", &clipboard_summary(event))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Log the input and clipboard events of the client
+    pub fn record_client_events(&mut self, msgs: &[tunnel::MessageClient]) -> Result<()> {
+        for msg in msgs {
+            let event = match &msg.msg {
+                Some(tunnel::message_client::Msg::Move(event)) => format!("{event:?}"),
+                Some(tunnel::message_client::Msg::MoveRelative(event)) => format!("{event:?}"),
+                Some(tunnel::message_client::Msg::Key(event)) => format!("{event:?}"),
+                Some(tunnel::message_client::Msg::Button(event)) => format!("{event:?}"),
+                Some(tunnel::message_client::Msg::Clipboard(event)) => clipboard_summary(event),
+                _ => continue,
+            };
+            self.log_event("client", &event)?;
+        }
+        Ok(())
+    }
+
+    /// Finalize the container and the events log
+    pub fn finish(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        if let Some(events) = self.events.as_mut() {
+            events.finish()?;
+        }
+        match self.muxer.as_mut() {
+            Some(muxer) => muxer.finish().context("Cannot finalize recording"),
+            None => {
+                warn!("No keyframe recorded");
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        recording_crypto::{decrypt_recording, generate_recording_keys},
+        video_encoder::{init_video_encoder, GopSettings},
+        yuv_rgb_rs::{ColorRange, YuvType},
+    };
+
+    #[test]
+    fn test_keyframe_parameter_sets() {
+        // sps, pps, idr slice, with 4 and 3 bytes start codes
+        let keyframe = [
+            0, 0, 0, 1, 0x67, 1, 2, 0, 0, 0, 1, 0x68, 3, 0, 0, 1, 0x65, 4, 5, 0,
+        ];
+        assert_eq!(
+            nal_units(&keyframe),
+            vec![&[0x67, 1, 2][..], &[0x68, 3][..], &[0x65, 4, 5, 0][..]]
+        );
+        assert_eq!(
+            keyframe_parameter_sets(VideoCodec::H264, &keyframe),
+            Some(vec![0, 0, 0, 1, 0x67, 1, 2, 0, 0, 0, 1, 0x68, 3])
+        );
+        // non idr slice
+        let frame = [0, 0, 0, 1, 0x41, 6, 7];
+        assert_eq!(keyframe_parameter_sets(VideoCodec::H264, &frame), None);
+
+        // hevc vps, sps, pps, idr_w_radl
+        let keyframe = [
+            0, 0, 1, 0x40, 1, 0, 0, 1, 0x42, 1, 0, 0, 1, 0x44, 1, 0, 0, 1, 0x26, 1, 9,
+        ];
+        assert_eq!(
+            keyframe_parameter_sets(VideoCodec::Hevc, &keyframe),
+            Some(vec![
+                0, 0, 0, 1, 0x40, 1, 0, 0, 0, 1, 0x42, 1, 0, 0, 0, 1, 0x44, 1
+            ])
+        );
+        assert!(VideoCodec::new("av1").is_err());
+    }
+
+    #[test]
+    fn test_encrypted_recording() {
+        let (width, height) = (128u32, 128u32);
+        let options = [("tune".to_owned(), "zerolatency".to_owned())];
+        let mut encoder = match init_video_encoder(
+            "libx264",
+            None::<std::iter::Empty<(&String, &String)>>,
+            Some(options.iter().map(|(key, value)| (key, value))),
+            &None,
+            (width as u16, height as u16),
+            YuvType::ItuT871,
+            ColorRange::Full,
+            GopSettings {
+                gop_size: None,
+                max_b_frames: Some(0),
+            },
+            None,
+            None,
+        ) {
+            Ok(encoder) => encoder,
+            // Built without libx264
+            Err(_) => return,
+        };
+        let dir = std::env::temp_dir().join(format!("sanzu-record-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("session.mkv");
+        let events_path = dir.join("events.log");
+        let (secret_key, public_key) = generate_recording_keys().unwrap();
+
+        let mut recorder = Recorder::new(
+            path.to_str().unwrap(),
+            "h264",
+            None,
+            events_path.to_str(),
+            Some(&public_key),
+        )
+        .unwrap();
+        let mut recorded = 0;
+        for count in 0..10 {
+            let image = vec![(count * 20) as u8; (width * height * 4) as usize];
+            let (data, _) = encoder
+                .encode_image(&image, width, height, width * 4, count)
+                .unwrap();
+            if data.is_empty() {
+                continue;
+            }
+            let img = tunnel::ImageEncoded {
+                data,
+                width,
+                height,
+                ..Default::default()
+            };
+            recorder
+                .record_media(&[tunnel::MessageSrv {
+                    msg: Some(tunnel::message_srv::Msg::ImgEncoded(img)),
+                }])
+                .unwrap();
+            recorded += 1;
+        }
+        let clipboard = tunnel::EventClipboard {
+            content: Some(ClipboardContent::Text("sanzu secret".to_owned())),
+        };
+        recorder
+            .record_client_events(&[tunnel::MessageClient {
+                msg: Some(tunnel::message_client::Msg::Clipboard(clipboard)),
+            }])
+            .unwrap();
+        recorder.finish().unwrap();
+
+        // Nothing is written in clear
+        let encrypted = std::fs::read(&events_path).unwrap();
+        assert!(!encrypted
+            .windows(12)
+            .any(|window| window == b"sanzu secret"));
+        let mut events = vec![];
+        decrypt_recording(&encrypted[..], &mut events, &secret_key).unwrap();
+        assert!(String::from_utf8(events).unwrap().contains("sanzu secret"));
+
+        // The decrypted recording is a valid container, with all the frames
+        let decrypted_path = dir.join("decrypted.mkv");
+        decrypt_recording(
+            File::open(&path).unwrap(),
+            File::create(&decrypted_path).unwrap(),
+            &secret_key,
+        )
+        .unwrap();
+        let path_c = CString::new(decrypted_path.to_str().unwrap()).unwrap();
+        let packets = unsafe {
+            let mut context = null_mut();
+            let retval =
+                ffmpeg::avformat_open_input(&mut context, path_c.as_ptr(), null_mut(), null_mut());
+            assert_eq!(retval, 0);
+            assert_eq!((*context).nb_streams, 1);
+            let par = (**(*context).streams).codecpar;
+            assert_eq!((*par).codec_id, ffmpeg::AVCodecID::AV_CODEC_ID_H264);
+            assert_eq!(((*par).width, (*par).height), (width as i32, height as i32));
+            let packet = AVPacket::new().unwrap();
+            let mut packets = 0;
+            while ffmpeg::av_read_frame(context, packet.as_mut_ptr()) >= 0 {
+                packets += 1;
+                ffmpeg::av_packet_unref(packet.as_mut_ptr());
+            }
+            ffmpeg::avformat_close_input(&mut context);
+            packets
+        };
+        assert!(recorded > 0);
+        assert_eq!(packets, recorded);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
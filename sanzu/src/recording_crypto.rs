@@ -0,0 +1,370 @@
+//! Encryption of session recordings at rest
+//!
+//! Recordings are encrypted on the fly using AES-256-GCM, so plaintext never
+//! hits the disk. Each recording has its own random data key, wrapped for a
+//! x25519 public key: the server only holds the public key, and cannot decrypt
+//! the recordings. The matching secret key is only needed to decrypt them, and
+//! must be kept offline or in a KMS, never next to the recordings.
+//!
+//! File format:
+//! - header: magic "SANZUREC", format version (1 byte), ephemeral x25519
+//!   public key (32 bytes), wrapped data key (48 bytes), nonce prefix (7 bytes)
+//! - chunks: sealed chunk length (u32, little endian), sealed chunk
+//!
+//! The data key is sealed with a key derived (HKDF-SHA256) from the agreement
+//! between the ephemeral key and the recipient key. Each chunk nonce is built
+//! from the nonce prefix, the chunk counter and a "last chunk" flag, so chunks
+//! cannot be reordered, dropped or appended. The last chunk is only written by
+//! `EncryptedWriter::finish`, on clean shutdown. A recording interrupted by a
+//! crash has no last chunk: its content is recovered up to the last complete
+//! chunk, but it is reported as truncated.
+
+use anyhow::{Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use ring::{
+    aead, hkdf,
+    rand::{SecureRandom, SystemRandom},
+};
+use std::{
+    convert::TryInto,
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+};
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+
+const MAGIC: &[u8; 8] = b"SANZUREC";
+const FORMAT_VERSION: u8 = 1;
+/// Length of the x25519 keys, and of the data key
+pub const RECORDING_KEY_LEN: usize = 32;
+const WRAPPED_KEY_LEN: usize = RECORDING_KEY_LEN + aead::MAX_TAG_LEN;
+const NONCE_PREFIX_LEN: usize = 7;
+const EPHEMERAL_KEY_OFFSET: usize = MAGIC.len() + 1;
+const WRAPPED_KEY_OFFSET: usize = EPHEMERAL_KEY_OFFSET + RECORDING_KEY_LEN;
+const NONCE_PREFIX_OFFSET: usize = WRAPPED_KEY_OFFSET + WRAPPED_KEY_LEN;
+const HEADER_LEN: usize = NONCE_PREFIX_OFFSET + NONCE_PREFIX_LEN;
+/// Plaintext size of a chunk
+const CHUNK_SIZE: usize = 64 * 1024;
+const WRAPPING_INFO: &[u8] = b"sanzu recording key";
+
+/// Read a raw x25519 key (public or secret) from `path`
+pub fn read_recording_key<P: AsRef<Path>>(path: P) -> Result<[u8; RECORDING_KEY_LEN]> {
+    let key = fs::read(path).context("Cannot read recording key")?;
+    key.as_slice()
+        .try_into()
+        .map_err(|_| anyhow!("Recording key must be {} bytes long", RECORDING_KEY_LEN))
+}
+
+fn random_key() -> Result<[u8; RECORDING_KEY_LEN]> {
+    let mut key = [0u8; RECORDING_KEY_LEN];
+    SystemRandom::new()
+        .fill(&mut key)
+        .map_err(|_| anyhow!("Cannot generate key"))?;
+    Ok(key)
+}
+
+/// Generate a x25519 key pair for the recordings: returns the secret key, used
+/// to decrypt, and the public key, used by the server to encrypt
+pub fn generate_recording_keys() -> Result<([u8; RECORDING_KEY_LEN], [u8; RECORDING_KEY_LEN])> {
+    let secret = StaticSecret::from(random_key()?);
+    let public = PublicKey::from(&secret);
+    Ok((secret.to_bytes(), public.to_bytes()))
+}
+
+fn new_key(key: &[u8]) -> Result<aead::LessSafeKey> {
+    let key = aead::UnboundKey::new(&aead::AES_256_GCM, key)
+        .map_err(|_| anyhow!("Invalid recording key"))?;
+    Ok(aead::LessSafeKey::new(key))
+}
+
+/// Key sealing the data key, derived from the agreement `shared` between the
+/// ephemeral and the recipient keys
+fn wrapping_key(
+    shared: &SharedSecret,
+    ephemeral_public: &[u8],
+    recipient_public: &[u8],
+) -> Result<aead::LessSafeKey> {
+    if !shared.was_contributory() {
+        return Err(anyhow!("Invalid recording public key"));
+    }
+    let salt = [ephemeral_public, recipient_public].concat();
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &salt).extract(shared.as_bytes());
+    let okm = prk
+        .expand(&[WRAPPING_INFO], &aead::AES_256_GCM)
+        .map_err(|_| anyhow!("Cannot derive wrapping key"))?;
+    Ok(aead::LessSafeKey::new(aead::UnboundKey::from(okm)))
+}
+
+/// The wrapping key is used once, as the ephemeral key is random
+fn wrapping_nonce() -> aead::Nonce {
+    aead::Nonce::assume_unique_for_key([0u8; aead::NONCE_LEN])
+}
+
+fn chunk_nonce(nonce_prefix: &[u8], counter: u32, last: bool) -> aead::Nonce {
+    let mut nonce = [0u8; aead::NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(nonce_prefix);
+    nonce[NONCE_PREFIX_LEN..NONCE_PREFIX_LEN + 4].copy_from_slice(&counter.to_be_bytes());
+    nonce[aead::NONCE_LEN - 1] = last as u8;
+    aead::Nonce::assume_unique_for_key(nonce)
+}
+
+/// Writer which encrypts a recording on the fly, for a x25519 public key
+///
+/// `finish` must be called on clean shutdown to authenticate the end of the
+/// recording. If the writer is dropped before, the recording is left
+/// truncated.
+pub struct EncryptedWriter<W: Write> {
+    writer: W,
+    key: aead::LessSafeKey,
+    header: [u8; HEADER_LEN],
+    counter: u32,
+    buffer: Vec<u8>,
+    finished: bool,
+}
+
+impl<W: Write> EncryptedWriter<W> {
+    pub fn new(mut writer: W, public_key: &[u8; RECORDING_KEY_LEN]) -> Result<Self> {
+        let data_key = random_key()?;
+        let key = new_key(&data_key)?;
+
+        let ephemeral = StaticSecret::from(random_key()?);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(&PublicKey::from(*public_key));
+        let mut wrapped_key = data_key.to_vec();
+        wrapping_key(&shared, ephemeral_public.as_bytes(), public_key)?
+            .seal_in_place_append_tag(wrapping_nonce(), aead::Aad::empty(), &mut wrapped_key)
+            .map_err(|_| anyhow!("Cannot wrap recording key"))?;
+
+        let mut header = [0u8; HEADER_LEN];
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        header[MAGIC.len()] = FORMAT_VERSION;
+        header[EPHEMERAL_KEY_OFFSET..WRAPPED_KEY_OFFSET]
+            .copy_from_slice(ephemeral_public.as_bytes());
+        header[WRAPPED_KEY_OFFSET..NONCE_PREFIX_OFFSET].copy_from_slice(&wrapped_key);
+        SystemRandom::new()
+            .fill(&mut header[NONCE_PREFIX_OFFSET..])
+            .map_err(|_| anyhow!("Cannot generate nonce prefix"))?;
+        writer
+            .write_all(&header)
+            .context("Cannot write recording header")?;
+        Ok(EncryptedWriter {
+            writer,
+            key,
+            header,
+            counter: 0,
+            buffer: Vec::with_capacity(CHUNK_SIZE + aead::MAX_TAG_LEN),
+            finished: false,
+        })
+    }
+
+    /// Encrypt and write the buffered plaintext
+    fn seal_chunk(&mut self, last: bool) -> io::Result<()> {
+        if self.counter == u32::MAX {
+            return Err(io::Error::other("Recording too long"));
+        }
+        let nonce = chunk_nonce(&self.header[NONCE_PREFIX_OFFSET..], self.counter, last);
+        self.key
+            .seal_in_place_append_tag(nonce, aead::Aad::from(&self.header), &mut self.buffer)
+            .map_err(|_| io::Error::other("Cannot encrypt recording"))?;
+        self.writer
+            .write_u32::<LittleEndian>(self.buffer.len() as u32)?;
+        self.writer.write_all(&self.buffer)?;
+        self.buffer.clear();
+        self.counter += 1;
+        Ok(())
+    }
+
+    /// Write the last chunk and flush the underlying writer
+    pub fn finish(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.seal_chunk(true)
+            .context("Cannot write last recording chunk")?;
+        self.writer.flush().context("Cannot flush recording")
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+}
+
+impl<W: Write> Write for EncryptedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(io::Error::other("Recording already finished"));
+        }
+        let len = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == CHUNK_SIZE {
+            self.seal_chunk(false)?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Decrypt a recording from `reader` to `writer`, with the x25519
+/// `secret_key` of the recording public key
+///
+/// The plaintext is written chunk by chunk, so if the recording is truncated
+/// (server crash), `writer` holds the content up to the last complete chunk
+/// and an error is returned.
+pub fn decrypt_recording<R: Read, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    secret_key: &[u8; RECORDING_KEY_LEN],
+) -> Result<()> {
+    let mut header = [0u8; HEADER_LEN];
+    reader
+        .read_exact(&mut header)
+        .context("Cannot read recording header")?;
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(anyhow!("Not an encrypted recording"));
+    }
+    if header[MAGIC.len()] != FORMAT_VERSION {
+        return Err(anyhow!(
+            "Unsupported recording version {}",
+            header[MAGIC.len()]
+        ));
+    }
+
+    let secret = StaticSecret::from(*secret_key);
+    let ephemeral_public: [u8; RECORDING_KEY_LEN] = header
+        [EPHEMERAL_KEY_OFFSET..WRAPPED_KEY_OFFSET]
+        .try_into()
+        .context("Bad ephemeral key")?;
+    let shared = secret.diffie_hellman(&PublicKey::from(ephemeral_public));
+    let mut wrapped_key = header[WRAPPED_KEY_OFFSET..NONCE_PREFIX_OFFSET].to_vec();
+    let data_key = wrapping_key(
+        &shared,
+        &ephemeral_public,
+        PublicKey::from(&secret).as_bytes(),
+    )?
+    .open_in_place(wrapping_nonce(), aead::Aad::empty(), &mut wrapped_key)
+    .map_err(|_| anyhow!("Cannot unwrap recording key: not the recording secret key?"))?;
+    let key = new_key(data_key)?;
+    let nonce_prefix = &header[NONCE_PREFIX_OFFSET..];
+
+    let mut counter = 0u32;
+    let mut chunk = vec![];
+    loop {
+        let len = match reader.read_u32::<LittleEndian>() {
+            Ok(len) => len as usize,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(anyhow!("Recording is truncated"));
+            }
+            Err(err) => return Err(err).context("Cannot read chunk length"),
+        };
+        if len > CHUNK_SIZE + aead::MAX_TAG_LEN {
+            return Err(anyhow!("Bad chunk length {}", len));
+        }
+        chunk.resize(len, 0);
+        reader
+            .read_exact(&mut chunk)
+            .context("Recording is truncated")?;
+
+        // Try as a regular chunk, then as the last one
+        let mut plaintext = chunk.clone();
+        let mut last = false;
+        let plaintext_len = match key.open_in_place(
+            chunk_nonce(nonce_prefix, counter, false),
+            aead::Aad::from(&header),
+            &mut plaintext,
+        ) {
+            Ok(opened) => opened.len(),
+            Err(_) => {
+                last = true;
+                plaintext.copy_from_slice(&chunk);
+                key.open_in_place(
+                    chunk_nonce(nonce_prefix, counter, true),
+                    aead::Aad::from(&header),
+                    &mut plaintext,
+                )
+                .map_err(|_| anyhow!("Cannot authenticate chunk {}", counter))?
+                .len()
+            }
+        };
+        writer
+            .write_all(&plaintext[..plaintext_len])
+            .context("Cannot write plaintext")?;
+        if last {
+            break;
+        }
+        counter += 1;
+    }
+
+    let mut trailing = [0u8; 1];
+    if reader
+        .read(&mut trailing)
+        .context("Cannot read recording")?
+        != 0
+    {
+        return Err(anyhow!("Data after last chunk"));
+    }
+    writer.flush().context("Cannot flush plaintext")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt(data: &[u8], public_key: &[u8; RECORDING_KEY_LEN]) -> Vec<u8> {
+        let mut writer = EncryptedWriter::new(vec![], public_key).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap();
+        writer.get_ref().clone()
+    }
+
+    #[test]
+    fn test_recording_roundtrip() {
+        let (secret_key, public_key) = generate_recording_keys().unwrap();
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE + 123).map(|i| i as u8).collect();
+        let encrypted = encrypt(&data, &public_key);
+        assert!(!encrypted
+            .windows(16)
+            .any(|window| window == &data[CHUNK_SIZE..CHUNK_SIZE + 16]));
+
+        let mut decrypted = vec![];
+        decrypt_recording(&encrypted[..], &mut decrypted, &secret_key).unwrap();
+        assert_eq!(decrypted, data);
+    }
+
+    #[test]
+    fn test_recording_truncated() {
+        let (secret_key, public_key) = generate_recording_keys().unwrap();
+        let data = vec![0x55u8; 2 * CHUNK_SIZE];
+        // Not finished, as if the server had crashed
+        let mut writer = EncryptedWriter::new(vec![], &public_key).unwrap();
+        writer.write_all(&data).unwrap();
+        let truncated = writer.get_ref().clone();
+        drop(writer);
+
+        let mut decrypted = vec![];
+        let err = decrypt_recording(&truncated[..], &mut decrypted, &secret_key).unwrap_err();
+        assert_eq!(err.to_string(), "Recording is truncated");
+        assert_eq!(decrypted, data);
+    }
+
+    #[test]
+    fn test_recording_tampered() {
+        let (secret_key, public_key) = generate_recording_keys().unwrap();
+        let mut encrypted = encrypt(b"sanzu recording", &public_key);
+        let index = encrypted.len() - 1;
+        encrypted[index] ^= 1;
+        assert!(decrypt_recording(&encrypted[..], vec![], &secret_key).is_err());
+
+        // Tampered data key
+        let mut encrypted = encrypt(b"sanzu recording", &public_key);
+        encrypted[WRAPPED_KEY_OFFSET] ^= 1;
+        assert!(decrypt_recording(&encrypted[..], vec![], &secret_key).is_err());
+
+        let (other_secret_key, _) = generate_recording_keys().unwrap();
+        let encrypted = encrypt(b"sanzu recording", &public_key);
+        assert!(decrypt_recording(&encrypted[..], vec![], &other_secret_key).is_err());
+    }
+}
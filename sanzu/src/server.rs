@@ -7,17 +7,22 @@ use sanzu_common::auth_pam::do_pam_auth;
 #[cfg(target_family = "unix")]
 use sanzu_common::Stdio;
 use sanzu_common::{
-    proto::{recv_client_msg_or_error, send_server_err_event, VERSION},
+    config_reload::reload_requested,
+    heartbeat::{self, Heartbeat},
+    proto::{
+        check_version, recv_client_msg_or_error, send_server_err_event, set_compression, VERSION,
+    },
     tls_helper::{get_subj_alt_names, make_server_config, tls_do_handshake},
     tunnel,
     utils::get_username_from_principal,
+    websocket::{WebSocket, WebSocketStream},
     ReadWrite, Tunnel,
 };
 
-use spin_sleep_util;
+use spin_sleep_util::{self, Interval, MissedTickBehavior};
 use std::{
     net::{self, IpAddr, TcpListener},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 #[cfg(unix)]
@@ -31,26 +36,198 @@ use vsock;
 
 #[cfg(target_family = "unix")]
 use crate::config::AuthType;
+#[cfg(target_os = "linux")]
+use crate::pen_tablet::{PenTablet, UINPUT_DEVICE};
 use crate::{
-    config::{ConfigServer, ConfigTls},
-    sound::SoundEncoder,
-    utils::{set_tcp_timeout, ServerArgsConfig, ServerEvent},
-    video_encoder::{get_encoder_category, init_video_encoder, Encoder},
+    app_catalog::AppCatalog,
+    config::{ConfigServer, ConfigTls, FrameDrop},
+    server_utils::{
+        cache_cursors, chunk_clipboard, emulate_touch, filter_input, granted_resolution,
+        reassemble_clipboard, BitrateController, ChromaAdvisor, FpsCounter, FrameDropCounter,
+        FrameTimings, InputFilter, Server, ServerDisconnected, TouchEmulation, MIN_RESOLUTION,
+    },
+    sound::{SoundDecoder, SoundEncoder, DECODER_BUFFER_MS, MICROPHONE_FORMAT},
+    utils::{
+        client_audio_format, clipboard_allowed, local_capabilities, negotiated_audio_codec,
+        negotiated_capabilities, negotiated_chroma, negotiated_color_space, set_tcp_timeout,
+        ClipboardAssembler, ClipboardChunker, CursorCache, HasTimeout, ServerArgsConfig,
+        ServerEvent, MAX_WINDOW_HEIGHT, MAX_WINDOW_WIDTH,
+    },
+    video_decoder::next_sequence,
+    video_encoder::{
+        get_encoder_category, init_video_encoder, software_fallback_encoder, tile_bands, Chroma,
+        Encoder, EncoderHwFallback, EncoderTiled, EncoderWatchdog, DEFAULT_MAX_ENCODER_FAILURES,
+    },
+    yuv_rgb_rs::{ColorRange, YuvType},
 };
+#[cfg(feature = "record")]
+use crate::{recorder::Recorder, recording_crypto::read_recording_key};
 
 #[cfg(target_family = "unix")]
-use crate::utils::HasTimeout;
+use crate::utils::stream_from_fd;
 
 use rustls::ServerConnection;
 
 use x509_parser::prelude::*;
 
 #[cfg(unix)]
-use crate::server_x11::init_x11rb;
+use crate::{server_x11::init_x11rb, utils_x11::display_keyboard_layout};
+
+#[cfg(all(unix, feature = "wayland"))]
+use crate::server_wayland::init_wayland;
 
 #[cfg(windows)]
 use crate::server_windows::init_win;
 
+/// Open the graphic server selected by the arguments
+#[cfg(unix)]
+fn init_server(
+    arguments: &ServerArgsConfig,
+    config: &ConfigServer,
+    server_size: Option<(u16, u16)>,
+) -> Result<Box<dyn Server>> {
+    if arguments.wayland {
+        #[cfg(feature = "wayland")]
+        return init_wayland(arguments, config).context("Cannot init_wayland");
+        #[cfg(not(feature = "wayland"))]
+        return Err(anyhow!("Sanzu is built without the wayland feature"));
+    }
+    init_x11rb(arguments, config, server_size).context("Cannot init_x11rb")
+}
+
+/// Open the graphic server selected by the arguments
+#[cfg(windows)]
+fn init_server(
+    arguments: &ServerArgsConfig,
+    config: &ConfigServer,
+    server_size: Option<(u16, u16)>,
+) -> Result<Box<dyn Server>> {
+    init_win(arguments, config, server_size)
+}
+
+/// Keyboard layout of the session, reported to the client. Empty if unknown,
+/// as on wayland or when the virtual display is not started yet
+#[cfg(unix)]
+fn session_keyboard_layout(arguments: &ServerArgsConfig) -> String {
+    match arguments.wayland {
+        true => String::new(),
+        false => display_keyboard_layout().unwrap_or_default(),
+    }
+}
+
+/// Keyboard layout of the session, unknown on windows
+#[cfg(windows)]
+fn session_keyboard_layout(_arguments: &ServerArgsConfig) -> String {
+    String::new()
+}
+
+/// Graphic server of a session
+enum ServerSource {
+    /// Display selected by the arguments, reopened if its connection is lost
+    Display,
+    /// Server provided by an embedder, which cannot be reopened
+    Embedded(Option<Box<dyn Server>>),
+}
+
+impl ServerSource {
+    fn is_embedded(&self) -> bool {
+        matches!(self, ServerSource::Embedded(_))
+    }
+
+    /// Open the graphic server, with the `server_size` resolution if set
+    fn open(
+        &mut self,
+        arguments: &ServerArgsConfig,
+        config: &ConfigServer,
+        server_size: Option<(u16, u16)>,
+    ) -> Result<Box<dyn Server>> {
+        match self {
+            ServerSource::Display => init_server(arguments, config, server_size),
+            ServerSource::Embedded(server) => server
+                .take()
+                .context("Embedded server connection lost, it cannot be reopened"),
+        }
+    }
+
+    /// Keyboard layout reported to the client, unknown for embedded servers
+    fn keyboard_layout(&self, arguments: &ServerArgsConfig) -> String {
+        match self {
+            ServerSource::Display => session_keyboard_layout(arguments),
+            ServerSource::Embedded(_) => String::new(),
+        }
+    }
+}
+
+/// Time given to a lost display to come back before ending the session
+const DISPLAY_RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Replace the graphic server, whose connection was lost (`err`), by a
+/// placeholder until it is reopened. Returns the notice for the client
+fn disconnect_server(server_info: &mut Box<dyn Server>, err: &anyhow::Error) -> tunnel::MessageSrv {
+    warn!("Display connection lost");
+    err.chain().for_each(|cause| warn!(" - due to {}", cause));
+    *server_info = Box::new(ServerDisconnected {
+        size: server_info.size(),
+    });
+    let text = "Reconnecting to display".to_owned();
+    tunnel::MessageSrv {
+        msg: Some(tunnel::message_srv::Msg::OsdNotice(
+            tunnel::EventOsdNotice { text },
+        )),
+    }
+}
+
+/// Resolution granted to the client `requested` one, within the server limits
+/// and the configured ceiling. The client is told if it differs
+fn grant_resolution(
+    server_info: &dyn Server,
+    config: &ConfigServer,
+    requested: (u32, u32),
+    events: &mut Vec<tunnel::MessageSrv>,
+) -> (u32, u32) {
+    if requested.0 == 0 || requested.1 == 0 {
+        return requested;
+    }
+    let (min, max) = match server_info.resolution_range() {
+        Ok(range) => range,
+        Err(err) => {
+            warn!("Cannot get the server resolution range");
+            err.chain().for_each(|cause| warn!(" - due to {}", cause));
+            (MIN_RESOLUTION, (MAX_WINDOW_WIDTH, MAX_WINDOW_HEIGHT))
+        }
+    };
+    let max = match config.video.max_resolution {
+        Some((width, height)) => (max.0.min(width), max.1.min(height)),
+        None => max,
+    };
+    let granted = granted_resolution(requested, min, max);
+    info!(
+        "Resolution requested {}x{} granted {}x{}",
+        requested.0, requested.1, granted.0, granted.1
+    );
+    if granted != requested {
+        let text = format!(
+            "Resolution {}x{} out of range, using {}x{}",
+            requested.0, requested.1, granted.0, granted.1
+        );
+        let msg = tunnel::message_srv::Msg::OsdNotice(tunnel::EventOsdNotice { text });
+        events.push(tunnel::MessageSrv { msg: Some(msg) });
+    }
+    granted
+}
+
+/// Use the client display dpi if it is known and allowed by the arguments
+fn apply_client_dpi(server_info: &mut dyn Server, arguments: &ServerArgsConfig, dpi: u32) {
+    info!("Client dpi {}", dpi);
+    if !arguments.client_dpi || dpi == 0 {
+        return;
+    }
+    if let Err(err) = server_info.set_dpi(dpi) {
+        warn!("Cannot set server dpi");
+        err.chain().for_each(|cause| warn!(" - due to {}", cause));
+    }
+}
+
 /// Tls auth / Kerberos Auth
 fn auth_client(
     config_tls: &ConfigTls,
@@ -63,6 +240,8 @@ fn auth_client(
         &config_tls.auth_cert,
         &config_tls.auth_key,
         config_tls.allowed_client_domains.is_some(),
+        None,
+        &config_tls.policy()?,
     )
     .context("Cannot make tls config")?;
     debug!("Using tls");
@@ -103,18 +282,251 @@ fn auth_client(
     Ok((tls_conn, username))
 }
 
+/// Run `f` on the session recorder. On error, the recording is stopped
+/// without being finalized, but the session goes on.
+#[cfg(feature = "record")]
+fn record(recorder: &mut Option<Recorder>, f: impl FnOnce(&mut Recorder) -> Result<()>) {
+    if let Some(ref mut session_recorder) = recorder {
+        if let Err(err) = f(session_recorder) {
+            error!("Recording error, stop recording");
+            err.chain().for_each(|cause| error!(" - due to {}", cause));
+            *recorder = None;
+        }
+    }
+}
+
+/// Open the video encoder `name`, monitored by an `EncoderWatchdog`
+fn init_monitored_encoder(
+    config: &ConfigServer,
+    name: &str,
+    size: (u16, u16),
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    chroma: Option<Chroma>,
+) -> Result<Box<dyn Encoder>> {
+    let encoder = init_video_encoder(
+        name,
+        config.ffmpeg_options(None),
+        config.ffmpeg_options(Some(name)),
+        &config.video.ffmpeg_options_cmd,
+        size,
+        yuv_type,
+        color_range,
+        config.video.gop_settings(),
+        config.video.vaapi_device.as_deref(),
+        chroma,
+    )?;
+    // Hardware encoders can fail during the session, keep a software one ready
+    let encoder: Box<dyn Encoder> = match software_fallback_encoder(name) {
+        Some(software_name) => {
+            let software_options = config
+                .ffmpeg_options(None)
+                .into_iter()
+                .flatten()
+                .chain(
+                    config
+                        .ffmpeg_options(Some(software_name))
+                        .into_iter()
+                        .flatten(),
+                )
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect();
+            Box::new(EncoderHwFallback::new(
+                encoder,
+                software_name,
+                software_options,
+                (size.0 as u32, size.1 as u32),
+                config.video.gop_settings(),
+                chroma,
+            ))
+        }
+        None => encoder,
+    };
+    let max_failures = config
+        .video
+        .max_encoder_failures
+        .unwrap_or(DEFAULT_MAX_ENCODER_FAILURES);
+    Ok(Box::new(EncoderWatchdog::new(encoder, max_failures)))
+}
+
+/// Open the video encoder `name`, split in `video.tiles` horizontal tiles if
+/// configured
+fn init_session_encoder(
+    config: &ConfigServer,
+    name: &str,
+    size: (u16, u16),
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    chroma: Option<Chroma>,
+) -> Result<Box<dyn Encoder>> {
+    let tiles = config.video.tiles.unwrap_or(1);
+    if tiles <= 1 || name == "null" {
+        return init_monitored_encoder(config, name, size, yuv_type, color_range, chroma);
+    }
+    info!("Encode frames in {} tiles", tiles);
+    let encoders = tile_bands(size.1 as u32, tiles)
+        .into_iter()
+        .map(|(_, height)| {
+            let size = (size.0, height as u16);
+            init_monitored_encoder(config, name, size, yuv_type, color_range, chroma)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Box::new(EncoderTiled::new(encoders, size.1 as u32)?))
+}
+
+/// Frame size used to check that an encoder can be opened
+const ENCODER_PROBE_SIZE: (u16, u16) = (640, 480);
+
+/// Select the first of the `candidates` encoders which can be opened
+fn select_encoder(
+    config: &ConfigServer,
+    candidates: &[String],
+    yuv_type: YuvType,
+    color_range: ColorRange,
+) -> Result<String> {
+    for name in candidates {
+        let result = get_encoder_category(name).and_then(|_| {
+            init_video_encoder(
+                name,
+                config.ffmpeg_options(None),
+                config.ffmpeg_options(Some(name)),
+                &config.video.ffmpeg_options_cmd,
+                ENCODER_PROBE_SIZE,
+                yuv_type,
+                color_range,
+                config.video.gop_settings(),
+                config.video.vaapi_device.as_deref(),
+                None,
+            )
+        });
+        match result {
+            Ok(_) => {
+                info!("Selected encoder {:?}", name);
+                return Ok(name.to_owned());
+            }
+            Err(err) => {
+                warn!("Cannot use encoder {:?}", name);
+                err.chain().for_each(|cause| warn!(" - due to {}", cause));
+            }
+        }
+    }
+    Err(anyhow!(
+        "None of the configured encoders {:?} can be opened",
+        candidates
+    ))
+}
+
+/// Open the next working encoder from the configured fallback chain
+fn init_fallback_encoder(
+    config: &ConfigServer,
+    fallback_encoders: &mut Vec<String>,
+    size: (u16, u16),
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    chroma: Option<Chroma>,
+) -> Result<Box<dyn Encoder>> {
+    while !fallback_encoders.is_empty() {
+        let name = fallback_encoders.remove(0);
+        warn!("Fallback to encoder {:?}", name);
+        match init_session_encoder(config, &name, size, yuv_type, color_range, chroma) {
+            Ok(encoder) => return Ok(encoder),
+            Err(err) => {
+                error!("Cannot init fallback encoder {:?}", name);
+                err.chain().for_each(|cause| error!(" - due to {}", cause));
+            }
+        }
+    }
+    Err(anyhow!("No fallback encoder available"))
+}
+
+/// Reload the configuration with `load_config`, keeping the settings which
+/// need a restart. Returns true if a hot setting has changed.
+fn reload_server_config(
+    config: &mut ConfigServer,
+    load_config: &dyn Fn() -> Result<ConfigServer>,
+) -> bool {
+    info!("Reload configuration");
+    match load_config().and_then(|new_config| config.apply_reload(new_config)) {
+        Ok(changed) => changed,
+        Err(err) => {
+            error!("Cannot reload configuration, keep the current one");
+            err.chain().for_each(|cause| error!(" - due to {}", cause));
+            false
+        }
+    }
+}
+
+/// Frame period for `max_fps`, with the pacing of the loop and the dropped
+/// frames counter of the `frame_drop` policy
+fn frame_pacing(
+    max_fps: u64,
+    frame_drop: FrameDrop,
+) -> (Option<Duration>, Option<Interval>, Option<FrameDropCounter>) {
+    // A null max_fps leaves the frame rate uncapped
+    let frame_duration = match max_fps {
+        0 => None,
+        max_fps => Some(std::time::Duration::from_secs(1) / max_fps as u32),
+    };
+    // Late frames are either skipped, counted as dropped, or caught up
+    let loop_sleep = frame_duration.map(|frame_duration| {
+        let missed_tick_behavior = match frame_drop {
+            FrameDrop::Latest => MissedTickBehavior::Skip,
+            FrameDrop::Never => MissedTickBehavior::Burst,
+        };
+        spin_sleep_util::interval(frame_duration).with_missed_tick_behavior(missed_tick_behavior)
+    });
+    let frame_drop_counter = match (frame_drop, frame_duration) {
+        (FrameDrop::Latest, Some(frame_duration)) => Some(FrameDropCounter::new(frame_duration)),
+        _ => None,
+    };
+    (frame_duration, loop_sleep, frame_drop_counter)
+}
+
+/// Check the settings of `config` which are only used once a client is
+/// connected
+fn check_config(config: &ConfigServer) -> Result<()> {
+    if let Some(config_tls) = &config.tls {
+        config_tls.policy().context("Error in tls configuration")?;
+    }
+    config
+        .audio
+        .opus_settings()
+        .context("Error in audio configuration")?;
+    config
+        .audio
+        .check_format()
+        .context("Error in audio configuration")?;
+    Ok(())
+}
+
 /// Exec main loop
 ///
-pub fn run(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result<()> {
+/// `load_config` reads the configuration again, on SIGHUP
+pub fn run(
+    config: ConfigServer,
+    arguments: &ServerArgsConfig,
+    load_config: &dyn Fn() -> Result<ConfigServer>,
+) -> Result<()> {
+    run_with_observer(config, arguments, load_config, &mut |_| {})
+}
+
+/// Exec main loop, `observer` receives the timings of each loop iteration
+pub fn run_with_observer(
+    mut config: ConfigServer,
+    arguments: &ServerArgsConfig,
+    load_config: &dyn Fn() -> Result<ConfigServer>,
+    observer: &mut dyn FnMut(&FrameTimings),
+) -> Result<()> {
+    check_config(&config)?;
     if arguments.keep_listening {
         loop {
-            if let Err(err) = run_server(config, arguments) {
+            if let Err(err) = run_server(&mut config, arguments, load_config, observer) {
                 error!("Server error");
                 err.chain().for_each(|cause| error!(" - due to {}", cause));
             }
         }
     } else {
-        run_server(config, arguments)
+        run_server(&mut config, arguments, load_config, observer)
     }
 }
 
@@ -127,14 +539,32 @@ pub fn run(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result<()> {
 /// - encode image
 /// - serialize / send events to client
 /// - receive / handle client events
-pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result<()> {
+///
+/// The hot settings of `config` are reloaded with `load_config` on SIGHUP
+pub fn run_server(
+    config: &mut ConfigServer,
+    arguments: &ServerArgsConfig,
+    load_config: &dyn Fn() -> Result<ConfigServer>,
+    observer: &mut dyn FnMut(&FrameTimings),
+) -> Result<()> {
     info!("Start server");
     let connection_timeout = arguments
         .connection_timeout
         .map(|timeout| std::time::Duration::from_secs(timeout as u64));
-    let mut sock: Box<dyn ReadWrite> = match (arguments.vsock, arguments.stdio, arguments.unixsock)
-    {
-        (true, false, false) => {
+    let outer_transport = arguments.stdio || arguments.fd.is_some();
+    if arguments.skip_tls && !outer_transport {
+        return Err(anyhow!("Skip tls is only allowed with stdio / fd"));
+    }
+    // Handle on the transport socket, used to bound the blocking reads once the
+    // session is established
+    let mut timeout_handle: Option<Box<dyn HasTimeout>> = None;
+    let sock: Box<dyn ReadWrite> = match (
+        arguments.vsock,
+        arguments.stdio,
+        arguments.unixsock,
+        arguments.fd,
+    ) {
+        (true, false, false, None) => {
             #[cfg(unix)]
             {
                 let port = arguments
@@ -152,6 +582,7 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
                 socket
                     .set_connection_timeout(connection_timeout)
                     .context("Cannot set timeout")?;
+                timeout_handle = Some(Box::new(socket.try_clone().context("Cannot clone socket")?));
                 Box::new(socket)
             }
             #[cfg(windows)]
@@ -159,7 +590,7 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
                 return Err(anyhow!("Vsock not supported on windows"));
             }
         }
-        (false, false, true) => {
+        (false, false, true, None) => {
             #[cfg(unix)]
             {
                 let socket = if arguments.connect_unixsock {
@@ -174,6 +605,7 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
                 socket
                     .set_connection_timeout(connection_timeout)
                     .context("Cannot set timeout")?;
+                timeout_handle = Some(Box::new(socket.try_clone().context("Cannot clone socket")?));
                 Box::new(socket)
             }
             #[cfg(windows)]
@@ -181,7 +613,7 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
                 return Err(anyhow!("Unix sockets are not supported on windows"));
             }
         }
-        (false, true, false) => {
+        (false, true, false, None) => {
             #[cfg(unix)]
             {
                 Box::new(Stdio {})
@@ -191,7 +623,20 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
                 return Err(anyhow!("STDIO is not supported on windows"));
             }
         }
-        (false, false, false) => {
+        (false, false, false, Some(fd)) => {
+            #[cfg(unix)]
+            {
+                Box::new(stream_from_fd(fd)?)
+            }
+            #[cfg(windows)]
+            {
+                return Err(anyhow!(
+                    "File descriptor {} is not supported on windows",
+                    fd
+                ));
+            }
+        }
+        (false, false, false, None) => {
             let port = arguments
                 .port
                 .parse::<u16>()
@@ -213,14 +658,79 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
 
             socket.set_nodelay(true)?;
             info!("Client {:?}", addr);
+            timeout_handle = Some(Box::new(socket.try_clone().context("Cannot clone socket")?));
             Box::new(socket)
         }
         _ => {
-            return Err(anyhow!("vsock / stdio / unixsock arguments error"));
+            return Err(anyhow!("vsock / stdio / unixsock / fd arguments error"));
         }
     };
 
-    let (mut tls_conn, _tls_username) = match &config.tls {
+    serve_client(
+        sock,
+        timeout_handle,
+        ServerSource::Display,
+        config,
+        arguments,
+        load_config,
+        observer,
+    )
+}
+
+/// Serve one client on `conn` with the graphic `server` of an embedder, ex: a
+/// software framebuffer or a game engine, instead of a x11 or windows display
+///
+/// `conn` is an established connection, ex: an accepted tcp stream. The tls
+/// handshake (unless `skip_tls` is set), the websocket upgrade and the
+/// authentication configured in `config` are done on it, then the session runs
+/// as with the other servers: frames encoded through `server`, sound,
+/// clipboard, and client events dispatched to `server`. The resolution of
+/// `server` is kept, and the session ends if `server` reports a lost
+/// connection. Returns once the client is disconnected.
+pub fn run_embedded(
+    server: Box<dyn Server>,
+    config: &mut ConfigServer,
+    arguments: &ServerArgsConfig,
+    conn: Box<dyn ReadWrite>,
+) -> Result<()> {
+    check_config(config)?;
+    serve_client(
+        conn,
+        None,
+        ServerSource::Embedded(Some(server)),
+        config,
+        arguments,
+        &|| Err(anyhow!("Embedded servers don't reload their configuration")),
+        &mut |_| {},
+    )
+}
+
+/// Tls and websocket layers, authentication, then session of the client
+/// connected on `sock`, on the graphic server of `source`
+///
+/// `timeout_handle` bounds the blocking reads once the session is established
+fn serve_client(
+    mut sock: Box<dyn ReadWrite>,
+    timeout_handle: Option<Box<dyn HasTimeout>>,
+    mut source: ServerSource,
+    config: &mut ConfigServer,
+    arguments: &ServerArgsConfig,
+    load_config: &dyn Fn() -> Result<ConfigServer>,
+    observer: &mut dyn FnMut(&FrameTimings),
+) -> Result<()> {
+    let connection_timeout = arguments
+        .connection_timeout
+        .map(|timeout| std::time::Duration::from_secs(timeout as u64));
+    let keep_resolution = arguments.keep_server_resolution || source.is_embedded();
+
+    let config_tls = match arguments.skip_tls {
+        true => {
+            info!("Skip tls, connection secured by the outer transport");
+            None
+        }
+        false => config.tls.as_ref(),
+    };
+    let (mut tls_conn, _tls_username) = match config_tls {
         Some(config_tls) => {
             let (tls_conn, username) =
                 auth_client(config_tls, &mut sock).context("Error in auth client")?;
@@ -240,6 +750,19 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
     #[cfg(windows)]
     info!("Tls state: {}", has_tls);
 
+    // Browser clients carry the tunnel in websocket messages, above the tls
+    let mut websocket = match arguments.websocket {
+        true => Some(WebSocket::accept(&mut sock).context("Error in websocket handshake")?),
+        false => None,
+    };
+    let mut sock: Box<dyn ReadWrite> = match websocket.as_mut() {
+        Some(websocket) => Box::new(WebSocketStream::new(websocket, &mut sock)),
+        None => Box::new(sock),
+    };
+
+    // Compression is only enabled once negotiated with this client
+    set_compression(None);
+
     // Send client version
     let server_version = tunnel::Version {
         version: VERSION.to_owned(),
@@ -251,13 +774,7 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
         recv_client_msg_type!(&mut sock, Version).context("Error in send client version")?;
 
     info!("Client version {:?}", client_version);
-    if client_version.version != VERSION {
-        return Err(anyhow!(
-            "Version mismatch server: {:?} client: {:?}",
-            VERSION,
-            client_version.version
-        ));
-    }
+    check_version(&client_version.version).context("Incompatible client")?;
 
     #[cfg(target_family = "unix")]
     if let Some(auth_type) = &config.auth_type {
@@ -282,15 +799,121 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
             }
         }
     }
-    let codec_name = get_encoder_category(&arguments.encoder)?;
+
+    // Virtual tablet fed with the client pen events. Disabled in read only
+    // mode, or if the device cannot be created.
+    #[cfg(target_os = "linux")]
+    let mut pen_tablet = match (&config.pen, arguments.rdonly) {
+        (Some(pen), false) => {
+            match PenTablet::new(pen.device.as_deref().unwrap_or(UINPUT_DEVICE)) {
+                Ok(pen_tablet) => Some(pen_tablet),
+                Err(err) => {
+                    error!("Cannot create pen tablet, pen disabled");
+                    err.chain().for_each(|cause| error!(" - due to {}", cause));
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    #[cfg(target_os = "linux")]
+    let pen = pen_tablet.is_some();
+    #[cfg(not(target_os = "linux"))]
+    let pen = false;
+
+    // Optional features are only used if both peers support them
+    let capabilities = tunnel::Capabilities {
+        audio: arguments.audio,
+        clipboard_image: arguments.max_clipboard_image != 0,
+        relative_mouse: !arguments.rdonly,
+        compression: arguments.compression_level.is_some(),
+        touch: !arguments.rdonly,
+        pen,
+        view_only: arguments.rdonly,
+        adaptive_chroma: config.video.adaptive_chroma == Some(true),
+        ..local_capabilities()
+    };
+    send_server_msg_type!(&mut sock, capabilities.clone(), Capabilities)
+        .context("Error in send Capabilities")?;
+    let client_capabilities: tunnel::Capabilities =
+        recv_client_msg_type!(&mut sock, Capabilities).context("Error in recv Capabilities")?;
+    let capabilities = negotiated_capabilities(&capabilities, &client_capabilities);
+    info!("Negotiated capabilities {:?}", capabilities);
+    // A view only client is served as in read only mode
+    let rdonly = arguments.rdonly || capabilities.view_only;
+    set_compression(
+        arguments
+            .compression_level
+            .filter(|_| capabilities.compression),
+    );
+    let max_clipboard_image = match capabilities.clipboard_image {
+        true => arguments.max_clipboard_image,
+        false => 0,
+    };
+
+    let yuv_type = config.video.color_space.unwrap_or(YuvType::ItuT871);
+    let color_range = config
+        .video
+        .color_range
+        .unwrap_or_else(|| yuv_type.default_range());
+
+    let encoder_name = match config.video.encoder {
+        Some(ref candidates) => select_encoder(config, candidates, yuv_type, color_range)
+            .map_err(|err| send_server_err_event(&mut sock, err))?,
+        None => arguments.encoder.clone(),
+    };
+    let codec_name = get_encoder_category(&encoder_name)?;
+
+    let audio_codec = match arguments.raw_sound {
+        true => tunnel::AudioCodec::Raw,
+        false => tunnel::AudioCodec::from(config.audio.codec.unwrap_or_default()),
+    };
+    let audio_codec = match capabilities.audio_codecs.contains(&(audio_codec as i32)) {
+        true => audio_codec,
+        false => tunnel::AudioCodec::Opus,
+    };
+    let audio_format = tunnel::AudioFormat {
+        sample_rate: config.audio.sample_rate.unwrap_or(0),
+        channels: config.audio.channels.unwrap_or(0) as u32,
+        buffer_ms: config.audio.buffer_ms.unwrap_or(0),
+    };
+
+    // Client microphone, played on the configured device. Disabled in read
+    // only mode, or if the device is not available.
+    let mut microphone = match (&config.microphone, rdonly) {
+        (Some(microphone), false) => match SoundDecoder::new(
+            &microphone.device,
+            Some(MICROPHONE_FORMAT.sample_rate),
+            Some(MICROPHONE_FORMAT.channels),
+            microphone.buffer_ms.unwrap_or(DECODER_BUFFER_MS as u32),
+        )
+        .and_then(|mut decoder| decoder.start().map(|_| decoder))
+        {
+            Ok(decoder) => Some(decoder),
+            Err(err) => {
+                error!("Cannot open microphone device, microphone disabled");
+                err.chain().for_each(|cause| error!(" - due to {}", cause));
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let clipboard_policy = config.clipboard_policy(arguments.restrict_clipboard);
+    info!("Clipboard policy: {}", clipboard_policy);
+    let server_chroma = config
+        .video
+        .chroma
+        .map_or(tunnel::Chroma::Unspecified, tunnel::Chroma::from);
+
+    let keyboard_layout = source.keyboard_layout(arguments);
+    info!("Keyboard layout: {:?}", keyboard_layout);
 
     /* Send server hello with image info & codec name */
-    let (mut server_info, audio_sample_rate) =
-        if arguments.keep_server_resolution || arguments.rdonly {
-            #[cfg(unix)]
-            let server_info = init_x11rb(arguments, config, None).context("Cannot init_x11rb")?;
-            #[cfg(windows)]
-            let server_info = init_win(arguments, config, None)?;
+    let server_keeps_resolution = keep_resolution || rdonly || arguments.wayland;
+    let (mut server_info, audio_params, (yuv_type, color_range), mut chroma) =
+        if server_keeps_resolution {
+            let mut server_info = source.open(arguments, config, None)?;
 
             let (screen_width, screen_height) = server_info.size();
             let server_mode = tunnel::server_hello::Msg::Fullscreen(tunnel::ServerFullScreen {
@@ -299,9 +922,20 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
             });
 
             let server_hello = tunnel::ServerHello {
-                codec_name,
-                audio: arguments.audio,
+                codec_name: codec_name.clone(),
+                audio: capabilities.audio,
                 msg: Some(server_mode),
+                color_space: tunnel::ColorSpace::from(yuv_type) as i32,
+                color_range: tunnel::ColorRange::from(color_range) as i32,
+                relative_mouse: capabilities.relative_mouse,
+                audio_codec: audio_codec as i32,
+                microphone: microphone.is_some(),
+                audio_format: Some(audio_format.clone()),
+                clipboard_policy: tunnel::ClipboardDirection::from(clipboard_policy.clipboard)
+                    as i32,
+                primary_policy: tunnel::ClipboardDirection::from(clipboard_policy.primary) as i32,
+                chroma: server_chroma as i32,
+                keyboard_layout: keyboard_layout.clone(),
             };
 
             send_server_msg_type!(&mut sock, server_hello, Hello).context("Cannot send hello")?;
@@ -310,21 +944,41 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
             let msg: tunnel::ClientHelloFullscreen =
                 recv_client_msg_type!(&mut sock, Clienthellofullscreen)
                     .context("Error in send client hello full screen")?;
+            apply_client_dpi(server_info.as_mut(), arguments, msg.dpi);
 
-            let audio_sample_rate = match msg.audio {
-                true => Some(msg.audio_sample_rate),
+            let audio_params = match msg.audio {
+                true => Some((
+                    client_audio_format(msg.audio_sample_rate, msg.audio_channels),
+                    negotiated_audio_codec(audio_codec, msg.audio_codec()),
+                )),
                 false => None,
             };
-            (server_info, audio_sample_rate)
+            (
+                server_info,
+                audio_params,
+                negotiated_color_space(msg.color_space(), msg.color_range()),
+                negotiated_chroma(msg.chroma()),
+            )
         } else {
             let server_mode = tunnel::server_hello::Msg::AdaptScreen(tunnel::ServerAdaptScreen {
                 seamless: arguments.seamless,
             });
 
             let server_hello = tunnel::ServerHello {
-                codec_name,
-                audio: arguments.audio,
+                codec_name: codec_name.clone(),
+                audio: capabilities.audio,
                 msg: Some(server_mode),
+                color_space: tunnel::ColorSpace::from(yuv_type) as i32,
+                color_range: tunnel::ColorRange::from(color_range) as i32,
+                relative_mouse: capabilities.relative_mouse,
+                audio_codec: audio_codec as i32,
+                microphone: microphone.is_some(),
+                audio_format: Some(audio_format.clone()),
+                clipboard_policy: tunnel::ClipboardDirection::from(clipboard_policy.clipboard)
+                    as i32,
+                primary_policy: tunnel::ClipboardDirection::from(clipboard_policy.primary) as i32,
+                chroma: server_chroma as i32,
+                keyboard_layout: keyboard_layout.clone(),
             };
 
             send_server_msg_type!(&mut sock, server_hello, Hello).context("Cannot send hello")?;
@@ -336,11 +990,8 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
 
             info!("Client screen size {:?}x{:?}", msg.width, msg.height);
             let client_screen_size = Some((msg.width as u16, msg.height as u16));
-            #[cfg(unix)]
-            let mut server_info =
-                init_x11rb(arguments, config, client_screen_size).context("Cannot init_x11rb")?;
-            #[cfg(windows)]
-            let mut server_info = init_win(arguments, config, client_screen_size)?;
+            let mut server_info = source.open(arguments, config, client_screen_size)?;
+            apply_client_dpi(server_info.as_mut(), arguments, msg.dpi);
 
             // Force server resolution
             let (width, height) = server_info.size();
@@ -352,29 +1003,97 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
                 warn!("Cannot change server resolution");
             }
 
-            let audio_sample_rate = match msg.audio {
-                true => Some(msg.audio_sample_rate),
+            let audio_params = match msg.audio {
+                true => Some((
+                    client_audio_format(msg.audio_sample_rate, msg.audio_channels),
+                    negotiated_audio_codec(audio_codec, msg.audio_codec()),
+                )),
                 false => None,
             };
-            (server_info, audio_sample_rate)
+            (
+                server_info,
+                audio_params,
+                negotiated_color_space(msg.color_space(), msg.color_range()),
+                negotiated_chroma(msg.chroma()),
+            )
         };
 
-    let mut video_encoder: Box<dyn Encoder> = init_video_encoder(
-        arguments.encoder.as_str(),
-        config.ffmpeg_options(None),
-        config.ffmpeg_options(Some(arguments.encoder.as_str())),
-        &config.video.ffmpeg_options_cmd,
-        server_info.size(),
-    )
-    .context("Error in init video encoder")
-    .map_err(|err| send_server_err_event(&mut sock, err))?;
+    info!("Color space {:?} {:?}", yuv_type, color_range);
+    let size = server_info.size();
+    let mut video_encoder: Box<dyn Encoder> =
+        init_session_encoder(config, &encoder_name, size, yuv_type, color_range, chroma)
+            .or_else(|err| match chroma.take() {
+                // Some encoders don't support 4:4:4, keep their pixel format
+                Some(requested) => {
+                    warn!("Cannot encode with chroma {}", requested);
+                    err.chain().for_each(|cause| warn!(" - due to {}", cause));
+                    init_session_encoder(config, &encoder_name, size, yuv_type, color_range, None)
+                }
+                None => Err(err),
+            })
+            .context("Error in init video encoder")
+            .map_err(|err| send_server_err_event(&mut sock, err))?;
+
+    let mut bitrate_controller = config
+        .video
+        .bitrate_control
+        .as_ref()
+        .map(BitrateController::new);
+    if let Some(ref controller) = bitrate_controller {
+        video_encoder.set_bitrate(controller.target_kbps());
+    }
+    // Pay the first encode cost before the client waits for images
+    if config.video.warm_up.unwrap_or(true) {
+        if let Err(err) = video_encoder.warm_up() {
+            warn!("Cannot warm up encoder {:?}", video_encoder.name());
+            err.chain().for_each(|cause| warn!(" - due to {}", cause));
+        }
+    }
 
-    let mut sound_obj = match (audio_sample_rate, arguments.audio) {
-        (Some(audio_sample_rate), true) => {
+    let mut frame_sequence = 1;
+
+    // Fallback encoders must be compatible with the codec announced to the client
+    let mut fallback_encoders: Vec<String> = config
+        .video
+        .fallback_encoders
+        .iter()
+        .flatten()
+        .filter(|name| get_encoder_category(name).ok().as_ref() == Some(&codec_name))
+        .cloned()
+        .collect();
+
+    let app_catalog = config
+        .apps
+        .as_ref()
+        .map(|apps| AppCatalog::new(apps))
+        .transpose()
+        .context("Error in application catalog")
+        .map_err(|err| send_server_err_event(&mut sock, err))?;
+    let mut app_catalog_msg = app_catalog.as_ref().map(|catalog| catalog.to_msg());
+    let mut window_list_msg = None;
+    // Chroma subsampling requested by the client or by the frame content
+    // during the session
+    let mut chroma_request = None;
+    // Switches the chroma from the frame content, until the client requests one
+    let mut chroma_advisor = capabilities.adaptive_chroma.then(ChromaAdvisor::default);
+    let mut touch_emulation = TouchEmulation::default();
+    let mut input_filter = InputFilter::new(config.input_rules.as_deref().unwrap_or_default())
+        .context("Bad input rules")?;
+    let mut pong_msg = None;
+
+    #[cfg(feature = "record")]
+    let record_audio = audio_params.clone().filter(|_| capabilities.audio);
+    let mut sound_obj = match (audio_params, capabilities.audio) {
+        (Some((audio_format, audio_codec)), true) => {
+            info!("Audio codec {:?} {:?}", audio_codec, audio_format);
+            let opus_settings = match audio_codec {
+                tunnel::AudioCodec::Opus => Some(config.audio.opus_settings()?),
+                tunnel::AudioCodec::Raw => None,
+            };
             match SoundEncoder::new(
                 "default",
-                arguments.raw_sound,
-                audio_sample_rate,
+                opus_settings,
+                audio_format,
                 config.audio.max_buffer_ms,
             ) {
                 Ok(mut sound_obj) => {
@@ -390,13 +1109,48 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
         _ => None,
     };
 
+    #[cfg(feature = "record")]
+    let mut recorder = match arguments.record.as_deref() {
+        Some(path) => {
+            if config.video.tiles.unwrap_or(1) > 1 {
+                return Err(anyhow!("Tiled frames cannot be recorded"));
+            }
+            // The sound is only recorded if it is captured
+            let record_audio = record_audio.filter(|_| sound_obj.is_some());
+            let record_key = arguments
+                .record_key
+                .as_deref()
+                .map(read_recording_key)
+                .transpose()
+                .context("Cannot read recording public key")?;
+            let recorder = Recorder::new(
+                path,
+                &codec_name,
+                record_audio,
+                arguments.record_events.as_deref(),
+                record_key.as_ref(),
+            )
+            .context("Cannot create session recorder")?;
+            // The recording starts on a keyframe
+            video_encoder.request_keyframe();
+            Some(recorder)
+        }
+        None => None,
+    };
+
     let mut prev_time_start = Instant::now();
 
-    let mut loop_sleep =
-        spin_sleep_util::interval(std::time::Duration::from_secs(1) / config.video.max_fps as u32);
+    let frame_drop = config.video.frame_drop.unwrap_or_default();
+    let (mut frame_duration, mut loop_sleep, mut frame_drop_counter) =
+        frame_pacing(config.video.max_fps, frame_drop);
+    let mut fps_counter = FpsCounter::default();
 
     let mut new_size = None;
     let mut cur_size = None;
+    let mut clipboard_notifier = server_info.clipboard_notifier();
+    // Set while the display connection is lost, with the notice to send
+    let mut display_lost = None;
+    let mut display_msg = None;
 
     // Do socket control
     #[cfg(unix)]
@@ -429,20 +1183,154 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
     }
 
     let mut msg_stats = "".to_owned();
+    let mut clipboard_chunker = ClipboardChunker::default();
+    let mut cursor_cache = CursorCache::default();
+    let mut clipboard_assembler = ClipboardAssembler::default();
+    let mut heartbeat = Heartbeat::new(
+        Instant::now(),
+        Duration::from_millis(arguments.heartbeat_interval_ms),
+        arguments.heartbeat_missed,
+    );
+    // Don't wait forever for a silent client
+    if let (Some(handle), None) = (timeout_handle.as_ref(), connection_timeout) {
+        handle
+            .set_connection_timeout(Some(heartbeat.dead_timeout()))
+            .context("Cannot set heartbeat timeout")?;
+    }
     let err = loop {
         let time_start = Instant::now();
 
         let mut events = vec![];
-        if let Some((width, height)) = new_size.take() {
+        if reload_requested() {
+            let bitrate_control = config.video.bitrate_control.clone();
+            if reload_server_config(config, load_config) {
+                server_info.reload_config(config, arguments.restrict_clipboard);
+                (frame_duration, loop_sleep, frame_drop_counter) =
+                    frame_pacing(config.video.max_fps, frame_drop);
+                // The controller restarts from the max bitrate
+                if config.video.bitrate_control != bitrate_control {
+                    bitrate_controller = config
+                        .video
+                        .bitrate_control
+                        .as_ref()
+                        .map(BitrateController::new);
+                    if let Some(ref controller) = bitrate_controller {
+                        video_encoder.set_bitrate(controller.target_kbps());
+                    }
+                }
+                let text = "Server configuration reloaded".to_owned();
+                let msg = tunnel::message_srv::Msg::OsdNotice(tunnel::EventOsdNotice { text });
+                events.push(tunnel::MessageSrv { msg: Some(msg) });
+            }
+        }
+        if let Some(msg) = app_catalog_msg.take() {
+            events.push(msg);
+        }
+        if let Some(requested) = chroma_request.take() {
+            let size = cur_size
+                .map(|(width, height)| (width as u16, height as u16))
+                .unwrap_or_else(|| server_info.size());
+            let name = video_encoder.name();
+            let text =
+                match init_session_encoder(config, &name, size, yuv_type, color_range, requested) {
+                    Ok(mut encoder) => {
+                        if let Some(ref controller) = bitrate_controller {
+                            encoder.set_bitrate(controller.target_kbps());
+                        }
+                        video_encoder = encoder;
+                        chroma = requested;
+                        if chroma_advisor.is_some() {
+                            let chroma =
+                                chroma.map_or(tunnel::Chroma::Unspecified, tunnel::Chroma::from);
+                            let msg = tunnel::EventChroma {
+                                chroma: chroma as i32,
+                            };
+                            events.push(tunnel::MessageSrv {
+                                msg: Some(tunnel::message_srv::Msg::Chroma(msg)),
+                            });
+                        }
+                        match chroma {
+                            Some(chroma) => format!("Chroma {chroma}"),
+                            None => "Chroma of the encoder".to_owned(),
+                        }
+                    }
+                    Err(err) => {
+                        error!("Cannot change the encoder chroma");
+                        err.chain().for_each(|cause| error!(" - due to {}", cause));
+                        // Don't retry an unsupported chroma on each content change
+                        chroma_advisor = None;
+                        format!("Chroma not supported by {name}")
+                    }
+                };
+            let msg = tunnel::message_srv::Msg::OsdNotice(tunnel::EventOsdNotice { text });
+            events.push(tunnel::MessageSrv { msg: Some(msg) });
+        }
+        if let Some(msg) = window_list_msg.take() {
+            events.push(msg);
+        }
+        if let Some(msg) = pong_msg.take() {
+            events.push(msg);
+        }
+        // The lost display notice is sent before blocking in the reconnection
+        if let Some(msg) = display_msg.take() {
+            events.push(msg);
+        } else if let Some(lost_time) = display_lost {
+            let server_size = (!keep_resolution).then(|| server_info.size());
+            match source.open(arguments, config, server_size) {
+                Ok(server) => {
+                    info!("Display reconnected");
+                    let size = server_info.size();
+                    server_info = server;
+                    display_lost = None;
+                    clipboard_notifier = server_info.clipboard_notifier();
+                    if server_info.size() != size {
+                        let (width, height) = server_info.size();
+                        let (width, height) = (width as u32, height as u32);
+                        video_encoder = video_encoder
+                            .change_resolution(width, height)
+                            .context("Cannot change codec resolution")?;
+                        let msg = tunnel::EventDisplay { width, height };
+                        events.push(tunnel::MessageSrv {
+                            msg: Some(tunnel::message_srv::Msg::Display(msg)),
+                        });
+                    }
+                    // The restarted display has its default mode, apply the
+                    // client one again
+                    new_size = cur_size.take();
+                    video_encoder.request_keyframe();
+                    let text = "Display reconnected".to_owned();
+                    let msg = tunnel::message_srv::Msg::OsdNotice(tunnel::EventOsdNotice { text });
+                    events.push(tunnel::MessageSrv { msg: Some(msg) });
+                }
+                Err(err)
+                    if !source.is_embedded()
+                        && time_start - lost_time < DISPLAY_RECONNECT_TIMEOUT =>
+                {
+                    debug!("Cannot reconnect to display: {:?}", err);
+                }
+                Err(err) => {
+                    break send_server_err_event(
+                        &mut sock,
+                        err.context("Cannot reconnect to display"),
+                    );
+                }
+            }
+        }
+        if let Some(ping) = heartbeat.ping(time_start) {
+            events.push(tunnel::MessageSrv {
+                msg: Some(tunnel::message_srv::Msg::Ping(ping)),
+            });
+        }
+        if let Some(requested) = new_size.take() {
+            let (width, height) = match keep_resolution {
+                true => requested,
+                false => grant_resolution(server_info.as_ref(), config, requested, &mut events),
+            };
             // Change resolution if:
             // - requested resolution has really changed
             // - width or height is not null
             // - we are allowed to change resolution
-            if Some((width, height)) != cur_size
-                && width != 0
-                && height != 0
-                && !arguments.keep_server_resolution
-            {
+            if Some((width, height)) != cur_size && width != 0 && height != 0 && !keep_resolution {
                 match server_info.change_resolution(config, width, height) {
                     Ok(_) => {
                         cur_size = Some((width, height));
@@ -486,9 +1374,16 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
         }
 
         /* Grab frame */
-        if let Err(err) = server_info.grab_frame() {
-            error!("grab fail {:?}", err);
-            break anyhow!("Grab fail: {}", err);
+        match server_info.grab_frame() {
+            Err(err) if server_info.is_connection_lost(&err) => {
+                display_msg = Some(disconnect_server(&mut server_info, &err));
+                display_lost = Some(time_start);
+            }
+            Err(err) => {
+                error!("grab fail {:?}", err);
+                break anyhow!("Grab fail: {}", err);
+            }
+            Ok(()) => {}
         }
 
         let time_grab = Instant::now();
@@ -496,18 +1391,115 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
         /* Manage clipboard events */
         match server_info.poll_events() {
             Ok(mut new_events) => events.append(&mut new_events),
+            Err(err) if server_info.is_connection_lost(&err) => {
+                display_msg = Some(disconnect_server(&mut server_info, &err));
+                display_lost = Some(time_start);
+            }
             Err(err) => {
-                break anyhow!("Poll error: {}", err);
+                // Tell the client why the session ends (captured window
+                // destroyed, ...)
+                let err = anyhow!("Poll error: {}", err);
+                break send_server_err_event(&mut sock, err);
             }
         };
 
+        if let Some((width, height)) = server_info.take_resize() {
+            let (width, height) = (width as u32, height as u32);
+            debug!("Server resized to {}x{}", width, height);
+            cur_size = Some((width, height));
+            video_encoder = video_encoder
+                .change_resolution(width, height)
+                .context("Cannot change codec resolution")?;
+            let msg = tunnel::EventDisplay { width, height };
+            events.push(tunnel::MessageSrv {
+                msg: Some(tunnel::message_srv::Msg::Display(msg)),
+            });
+        }
+
+        if let Some(data) = clipboard_notifier
+            .as_ref()
+            .and_then(|notifier| notifier.take())
+        {
+            let eventclipboard = tunnel::EventClipboard {
+                content: Some(data),
+            };
+            events.push(tunnel::MessageSrv {
+                msg: Some(tunnel::message_srv::Msg::Clipboard(eventclipboard)),
+            });
+        }
+        events.retain(|msg| match &msg.msg {
+            Some(tunnel::message_srv::Msg::Clipboard(event)) => {
+                clipboard_allowed(event, max_clipboard_image)
+            }
+            _ => true,
+        });
+        #[cfg(feature = "record")]
+        record(&mut recorder, |recorder| {
+            recorder.record_server_events(&events)
+        });
+        events = chunk_clipboard(&mut clipboard_chunker, events);
+        events = cache_cursors(&mut cursor_cache, events);
+
+        if let (Some(advisor), Some(frame)) = (chroma_advisor.as_mut(), server_info.grabbed_frame())
+        {
+            if let Some(requested) = advisor.update(frame, chroma, time_start) {
+                info!("Frame content switches chroma to {}", requested);
+                chroma_request = Some(Some(requested));
+            }
+        }
+
         let time_event = Instant::now();
 
-        let (mut img_events, timings) = server_info
-            .generate_encoded_img(&mut video_encoder)
-            .context("Error in generate_encoded_img")?;
+        let (mut img_events, timings) = match server_info.generate_encoded_img(&mut video_encoder) {
+            Ok(result) => result,
+            Err(err) => {
+                let err = err.context("Error in generate_encoded_img");
+                error!("Encoder error");
+                err.chain().for_each(|cause| error!(" - due to {}", cause));
+                let size = cur_size
+                    .map(|(width, height)| (width as u16, height as u16))
+                    .unwrap_or_else(|| server_info.size());
+                video_encoder = match init_fallback_encoder(
+                    config,
+                    &mut fallback_encoders,
+                    size,
+                    yuv_type,
+                    color_range,
+                    chroma,
+                ) {
+                    Ok(mut encoder) => {
+                        if let Some(ref controller) = bitrate_controller {
+                            encoder.set_bitrate(controller.target_kbps());
+                        }
+                        encoder
+                    }
+                    Err(fallback_err) => {
+                        // Only report the encoder diagnostic to the client
+                        let diagnostic = anyhow!("{}", err.root_cause());
+                        send_server_err_event(&mut sock, diagnostic);
+                        return Err(err.context(fallback_err));
+                    }
+                };
+                (vec![], None)
+            }
+        };
         let time_encode = Instant::now();
 
+        if let Some(frame_stats) = timings.as_ref().and_then(|timings| timings.frame) {
+            img_events.push(frame_stats.to_msg());
+        }
+        // Number the frames so that the client detects lost ones
+        for msg in img_events.iter_mut() {
+            if let Some(tunnel::message_srv::Msg::ImgEncoded(img)) = msg.msg.as_mut() {
+                img.sequence = frame_sequence;
+                frame_sequence = next_sequence(frame_sequence);
+            }
+        }
+        if let Some(text) = video_encoder.take_notice() {
+            let msg = tunnel::message_srv::Msg::OsdNotice(tunnel::EventOsdNotice { text });
+            img_events.push(tunnel::MessageSrv { msg: Some(msg) });
+        }
+
         let mut sound_events = if let Some(ref mut sound_obj) = sound_obj {
             sound_obj.recv_events()
         } else {
@@ -524,6 +1516,9 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
         let msg = tunnel::MessageSrv { msg: Some(msg) };
         events.push(msg);
 
+        #[cfg(feature = "record")]
+        record(&mut recorder, |recorder| recorder.record_media(&events));
+
         /* Send events */
         send_server_msg_type!(&mut sock, tunnel::MessagesSrv { msgs: events }, Msgssrv)
             .context("Cannot send events")?;
@@ -533,10 +1528,133 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
         let msgs =
             recv_client_msg_type!(&mut sock, Msgsclient).context("Cannot recv client msgs")?;
 
-        if !arguments.rdonly {
-            let server_events = server_info
-                .handle_client_event(msgs)
-                .context("Error in client handle events")?;
+        // Keyframe requests and link stats only drive the video encoder, and
+        // the window list and heartbeat are read only: allow them in read only
+        // mode.
+        // The microphone is not accepted in read only mode, but the sound
+        // capture can be muted.
+        for msg in msgs.msgs.iter() {
+            match &msg.msg {
+                Some(tunnel::message_client::Msg::RequestKeyframe(_)) => {
+                    debug!("Client requested a keyframe");
+                    video_encoder.request_keyframe();
+                }
+                Some(tunnel::message_client::Msg::RequestChroma(request)) => {
+                    let requested = negotiated_chroma(request.chroma());
+                    debug!("Client requested chroma {:?}", requested);
+                    if chroma_advisor.take().is_some() {
+                        info!("Chroma set by the client, adaptive chroma disabled");
+                    }
+                    if requested != chroma {
+                        chroma_request = Some(requested);
+                    }
+                }
+                Some(tunnel::message_client::Msg::RequestWindowList(_)) => {
+                    match server_info.window_list() {
+                        Ok(windows) => {
+                            let msg = tunnel::EventWindowList { windows };
+                            window_list_msg = Some(tunnel::MessageSrv {
+                                msg: Some(tunnel::message_srv::Msg::WindowList(msg)),
+                            });
+                        }
+                        Err(err) => {
+                            error!("Cannot list windows");
+                            err.chain().for_each(|cause| error!(" - due to {}", cause));
+                        }
+                    }
+                }
+                Some(tunnel::message_client::Msg::Microphone(sound)) => {
+                    if let Some(microphone) = microphone.as_mut() {
+                        for pkt in sound.data.iter() {
+                            microphone.push(pkt.clone());
+                        }
+                    }
+                }
+                Some(tunnel::message_client::Msg::AudioControl(control)) => {
+                    if let Some(sound_obj) = sound_obj.as_mut() {
+                        debug!("Client audio muted: {}", control.mute);
+                        if let Err(err) = sound_obj.set_muted(control.mute) {
+                            error!("Cannot change audio mute state");
+                            err.chain().for_each(|cause| error!(" - due to {}", cause));
+                        }
+                    }
+                }
+                Some(tunnel::message_client::Msg::Ping(ping)) => {
+                    pong_msg = Some(tunnel::MessageSrv {
+                        msg: Some(tunnel::message_srv::Msg::Pong(heartbeat::pong(ping))),
+                    });
+                }
+                Some(tunnel::message_client::Msg::Pong(pong)) => {
+                    heartbeat.pong(pong, Instant::now());
+                }
+                Some(tunnel::message_client::Msg::LinkStats(stats)) => {
+                    if let Some(kbps) = bitrate_controller.as_mut().and_then(|controller| {
+                        let frame_duration = frame_duration.unwrap_or(time_start - prev_time_start);
+                        controller.update(stats, frame_duration)
+                    }) {
+                        debug!("Bitrate target {} kbps", kbps);
+                        video_encoder.set_bitrate(kbps);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Err(err) = heartbeat.check() {
+            break err.context("Client is not responding");
+        }
+
+        if !rdonly {
+            let msgs = reassemble_clipboard(&mut clipboard_assembler, msgs.msgs);
+            let msgs = emulate_touch(&mut touch_emulation, msgs);
+            let msgs = filter_input(&mut input_filter, msgs);
+            let (launch_events, mut msgs): (Vec<_>, Vec<_>) = msgs.into_iter().partition(|msg| {
+                matches!(msg.msg, Some(tunnel::message_client::Msg::LaunchApp(_)))
+            });
+            #[cfg(target_os = "linux")]
+            if let Some(pen_tablet) = pen_tablet.as_mut() {
+                let size = server_info.size();
+                for msg in msgs.iter() {
+                    if let Some(tunnel::message_client::Msg::Pen(event)) = &msg.msg {
+                        if let Err(err) = pen_tablet.send(event, size) {
+                            error!("Cannot inject pen event");
+                            err.chain().for_each(|cause| error!(" - due to {}", cause));
+                        }
+                    }
+                }
+            }
+            msgs.retain(|msg| match &msg.msg {
+                Some(tunnel::message_client::Msg::Clipboard(event)) => {
+                    clipboard_allowed(event, max_clipboard_image)
+                }
+                _ => true,
+            });
+            #[cfg(feature = "record")]
+            record(&mut recorder, |recorder| {
+                recorder.record_client_events(&msgs)
+            });
+            let msgs = tunnel::MessagesClient { msgs };
+            for msg in launch_events {
+                if let Some(tunnel::message_client::Msg::LaunchApp(launch)) = msg.msg {
+                    let result = match &app_catalog {
+                        Some(app_catalog) => app_catalog.launch(launch.id, server_info.display()),
+                        None => Err(anyhow!("No application catalog")),
+                    };
+                    if let Err(err) = result {
+                        error!("Cannot launch application");
+                        err.chain().for_each(|cause| error!(" - due to {}", cause));
+                    }
+                }
+            }
+
+            let server_events = match server_info.handle_client_event(msgs) {
+                Ok(server_events) => server_events,
+                Err(err) if server_info.is_connection_lost(&err) => {
+                    display_msg = Some(disconnect_server(&mut server_info, &err));
+                    display_lost = Some(time_start);
+                    vec![]
+                }
+                Err(err) => return Err(err.context("Error in client handle events")),
+            };
 
             for server_event in server_events {
                 match server_event {
@@ -550,44 +1668,140 @@ pub fn run_server(config: &ConfigServer, arguments: &ServerArgsConfig) -> Result
             }
         }
 
-        let mut timings_str = String::new();
-        if let Some(timings) = timings {
-            timings_str += &timings
-                .times
-                .iter()
-                .map(|(name, value)| format!("{name} {value:>7.1?}"))
-                .collect::<Vec<String>>()
-                .join(" ");
-        }
-
         let time_stop = Instant::now();
         let frame_time = time_start - prev_time_start;
+        let frame_timings = FrameTimings {
+            frame: frame_time,
+            total: time_stop - time_start,
+            grab: time_grab - time_start,
+            event: time_event - time_grab,
+            encode: time_encode - time_event,
+            encoder: timings.map(|timings| timings.times).unwrap_or_default(),
+            sound: time_sound - time_encode,
+            send: time_send - time_sound,
+            recv: time_stop - time_send,
+        };
+        observer(&frame_timings);
+
+        let timings_str = frame_timings
+            .encoder
+            .iter()
+            .map(|(name, value)| format!("{name} {value:>7.1?}"))
+            .collect::<Vec<String>>()
+            .join(" ");
         let frame_time_micro = frame_time.as_micros();
         let fps = if frame_time_micro == 0 {
             "-".to_owned()
         } else {
             format!("{:3}", 1_000_000 / frame_time_micro)
         };
+        let fps_avg = fps_counter.tick(time_start);
+        let fps_cap = match config.video.max_fps {
+            0 => "-".to_owned(),
+            max_fps => format!("{max_fps}"),
+        };
+        let dropped = match frame_drop_counter.as_mut() {
+            Some(counter) => counter.update(frame_time).to_string(),
+            None => "-".to_owned(),
+        };
 
         let msg = format!(
-                "Fps:{} Frame time: {:>7} Total: {:>7} grab: {:>7} event: {:>7} encode: {:>7} ({}) sound: {:>7} send: {:>7} recv: {:>7}",
+                "Fps:{} (avg {:5.1} max {} dropped {}) Frame time: {:>7} Total: {:>7} grab: {:>7} event: {:>7} encode: {:>7} ({}) sound: {:>7} send: {:>7} recv: {:>7}",
                 fps,
-                &format!("{:.1?}", (time_start - prev_time_start)),
-                &format!("{:.1?}", time_stop - time_start),
-                &format!("{:.1?}", time_grab - time_start),
-                &format!("{:.1?}", time_event - time_grab),
-                &format!("{:.1?}", time_encode - time_event),
+                fps_avg,
+                fps_cap,
+                dropped,
+                &format!("{:.1?}", frame_timings.frame),
+                &format!("{:.1?}", frame_timings.total),
+                &format!("{:.1?}", frame_timings.grab),
+                &format!("{:.1?}", frame_timings.event),
+                &format!("{:.1?}", frame_timings.encode),
                 &timings_str,
-                &format!("{:.1?}", time_sound - time_encode),
-                &format!("{:.1?}", time_send - time_sound),
-                &format!("{:.1?}", time_stop - time_send),
+                &format!("{:.1?}", frame_timings.sound),
+                &format!("{:.1?}", frame_timings.send),
+                &format!("{:.1?}", frame_timings.recv),
             );
         debug!("{}", msg);
         msg_stats = msg;
 
         prev_time_start = time_start;
-        loop_sleep.tick(); // sleeps to acheive target FPS rate
+        // Wake up early on clipboard change, so it is forwarded even if the
+        // frame rate is low
+        if let (Some(frame_duration), Some(loop_sleep)) = (frame_duration, loop_sleep.as_mut()) {
+            let clipboard_pending = clipboard_notifier
+                .as_ref()
+                .map(|notifier| notifier.wait_until(time_start + frame_duration))
+                .unwrap_or(false);
+            if !clipboard_pending {
+                loop_sleep.tick(); // sleeps to acheive target FPS rate
+            }
+        }
     };
 
+    #[cfg(feature = "record")]
+    if let Some(mut recorder) = recorder.take() {
+        if let Err(err) = recorder.finish() {
+            error!("Cannot finish recording");
+            err.chain().for_each(|cause| error!(" - due to {}", cause));
+        }
+    }
+
     Err(err)
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use sanzu_common::proto::recv_server_msg_or_error;
+    use std::os::unix::{io::IntoRawFd, net::UnixStream};
+
+    // The tls files don't exist: the session fails if tls isn't skipped
+    const CONF: &str = r#"
+[video]
+max_fps = 30
+max_stall_img = 30
+
+[audio]
+max_buffer_ms = 200
+
+[tls]
+server_name = "localhost"
+ca_file = "/nonexistent/rootCA.crt"
+auth_cert = "/nonexistent/localhost.crt"
+auth_key = "/nonexistent/localhost.key"
+
+[ffmpeg]
+"#;
+
+    #[test]
+    fn test_skip_tls() {
+        let mut config: ConfigServer = toml::from_str(CONF).unwrap();
+
+        // Tls is only skipped on an outer transport
+        let arguments = ServerArgsConfig::parse_from(["sanzu_server", "--skip-tls"]);
+        let err = run_server(
+            &mut config,
+            &arguments,
+            &|| Err(anyhow!("No reload")),
+            &mut |_| {},
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Skip tls is only allowed with stdio / fd");
+
+        let (local, mut peer) = UnixStream::pair().unwrap();
+        let conn: Box<dyn ReadWrite> = Box::new(stream_from_fd(local.into_raw_fd()).unwrap());
+        let handle = thread::spawn(move || {
+            let server = Box::new(ServerDisconnected { size: (640, 480) });
+            run_embedded(server, &mut config, &arguments, conn)
+        });
+
+        // The server version comes in clear, without a tls handshake
+        let version: tunnel::Version = recv_server_msg_type!(&mut peer, Version).unwrap();
+        assert_eq!(version.version, VERSION);
+
+        // The session ends with the connection
+        drop(peer);
+        assert!(handle.join().unwrap().is_err());
+    }
+}
@@ -1,10 +1,18 @@
 use crate::{
-    config::ConfigServer,
-    utils::ServerEvent,
-    video_encoder::{Encoder, EncoderTimings},
+    client_utils::{keycode_from_name, Hotkey},
+    config::{BitrateControl, ConfigServer, InputAction, InputRule},
+    utils::{
+        cursor_id, ClipboardAssembler, ClipboardChunker, ClipboardNotifier, CursorCache,
+        ServerEvent, MAX_WINDOW_HEIGHT, MAX_WINDOW_WIDTH,
+    },
+    video_encoder::{Chroma, Encoder, EncoderTimings},
 };
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
 
 use sanzu_common::tunnel;
 
@@ -20,6 +28,16 @@ pub trait Server {
     ///
     /// Graphic modifications are monitored using Damage x11 extension
     fn poll_events(&mut self) -> Result<Vec<tunnel::MessageSrv>>;
+    /// New frame size, if the grabbed surface was resized on the server side
+    /// since the last call
+    fn take_resize(&mut self) -> Option<(u16, u16)> {
+        None
+    }
+    /// Returns true if `err` comes from the loss of the connection to the
+    /// graphic server, which may come back
+    fn is_connection_lost(&self, _err: &anyhow::Error) -> bool {
+        false
+    }
     /// Encode image
     fn generate_encoded_img(
         &mut self,
@@ -35,5 +53,962 @@ pub trait Server {
     /// If everything is ok, we update the video index state, and recreate a new
     /// frame grabber according to the new resolution
     fn change_resolution(&mut self, config: &ConfigServer, width: u32, height: u32) -> Result<()>;
+    /// Smallest and largest resolutions accepted by `change_resolution`
+    fn resolution_range(&self) -> Result<((u32, u32), (u32, u32))> {
+        Ok((MIN_RESOLUTION, (MAX_WINDOW_WIDTH, MAX_WINDOW_HEIGHT)))
+    }
     fn activate_window(&self, win_id: u32) -> Result<()>;
+    /// Current windows, with the ids of the areas sent to the client
+    fn window_list(&self) -> Result<Vec<tunnel::WindowEntry>> {
+        Ok(vec![])
+    }
+    /// Server clipboard events, if they are not sent through `poll_events`
+    fn clipboard_notifier(&self) -> Option<ClipboardNotifier> {
+        None
+    }
+    /// Use `dpi` for the screen physical size (applied on the next resolution
+    /// change) and advertise it to the applications
+    fn set_dpi(&mut self, _dpi: u32) -> Result<()> {
+        Err(anyhow!("Dpi cannot be set on this server"))
+    }
+    /// Last grabbed 32 bits frame (data, width, height, bytes per line), if
+    /// the server exposes it for the content analysis
+    fn grabbed_frame(&self) -> Option<(&[u8], u32, u32, u32)> {
+        None
+    }
+    /// X display of the session, given to the launched applications, if it
+    /// differs from the one of the environment
+    fn display(&self) -> Option<&str> {
+        None
+    }
+    /// Apply the hot settings of the reloaded `config`: identical frames
+    /// before the encoder pause, and clipboard policy
+    fn reload_config(&mut self, _config: &ConfigServer, _restrict_clipboard: bool) {}
+}
+
+/// Smallest resolution granted to the clients
+pub const MIN_RESOLUTION: (u32, u32) = (64, 64);
+
+/// Clamp the client `requested` resolution between `min` and `max`, keeping
+/// even sizes
+pub fn granted_resolution(requested: (u32, u32), min: (u32, u32), max: (u32, u32)) -> (u32, u32) {
+    let clamp = |value: u32, min: u32, max: u32| value.min(max).max(min) & !1;
+    (
+        clamp(requested.0, min.0, max.0),
+        clamp(requested.1, min.1, max.1),
+    )
+}
+
+/// Stands for a graphic server whose connection was lost, until it is opened
+/// again: nothing is grabbed and the client events are dropped
+pub struct ServerDisconnected {
+    /// Size of the lost server
+    pub size: (u16, u16),
+}
+
+impl Server for ServerDisconnected {
+    fn size(&self) -> (u16, u16) {
+        self.size
+    }
+
+    fn grab_frame(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle_client_event(&mut self, _msgs: tunnel::MessagesClient) -> Result<Vec<ServerEvent>> {
+        Ok(vec![])
+    }
+
+    fn poll_events(&mut self) -> Result<Vec<tunnel::MessageSrv>> {
+        Ok(vec![])
+    }
+
+    fn generate_encoded_img(
+        &mut self,
+        _video_encoder: &mut Box<dyn Encoder>,
+    ) -> Result<(Vec<tunnel::MessageSrv>, Option<EncoderTimings>)> {
+        Ok((vec![], None))
+    }
+
+    fn change_resolution(
+        &mut self,
+        _config: &ConfigServer,
+        _width: u32,
+        _height: u32,
+    ) -> Result<()> {
+        Err(anyhow!("Display is disconnected"))
+    }
+
+    fn activate_window(&self, _win_id: u32) -> Result<()> {
+        Err(anyhow!("Display is disconnected"))
+    }
+}
+
+/// Number of consecutive uncongested frames before raising the bitrate
+const BITRATE_RAISE_FRAMES: u32 = 25;
+
+/// Adjusts the encoder target bitrate to the client link statistics
+///
+/// The target is lowered by `step_kbps` (or down to the reported throughput)
+/// on each congested frame, and raised by `step_kbps` after
+/// `BITRATE_RAISE_FRAMES` uncongested frames, between `min_kbps` and `max_kbps`.
+pub struct BitrateController {
+    config: BitrateControl,
+    target_kbps: u32,
+    good_frames: u32,
+}
+
+impl BitrateController {
+    pub fn new(config: &BitrateControl) -> Self {
+        BitrateController {
+            config: config.clone(),
+            target_kbps: config.max_kbps,
+            good_frames: 0,
+        }
+    }
+
+    /// Current target bitrate (kbps)
+    pub fn target_kbps(&self) -> u32 {
+        self.target_kbps
+    }
+
+    /// Update the target from the client `stats`, returns the new target if
+    /// it has changed
+    pub fn update(
+        &mut self,
+        stats: &tunnel::EventLinkStats,
+        frame_duration: Duration,
+    ) -> Option<u32> {
+        let max_recv_time = frame_duration.as_millis() as u32 + self.config.max_latency_ms;
+        let target_kbps = if stats.recv_time_ms > max_recv_time {
+            self.good_frames = 0;
+            let target_kbps = self.target_kbps.saturating_sub(self.config.step_kbps);
+            target_kbps.min(stats.throughput_kbps)
+        } else {
+            self.good_frames += 1;
+            if self.good_frames < BITRATE_RAISE_FRAMES {
+                return None;
+            }
+            self.good_frames = 0;
+            self.target_kbps.saturating_add(self.config.step_kbps)
+        };
+        let target_kbps = target_kbps.clamp(self.config.min_kbps, self.config.max_kbps);
+        if target_kbps == self.target_kbps {
+            return None;
+        }
+        self.target_kbps = target_kbps;
+        Some(target_kbps)
+    }
+}
+
+/// Rows skipped between two sampled rows of the analyzed frames
+const CONTENT_SAMPLE_ROWS: usize = 8;
+/// Distinct colors above which a frame is not considered as text
+const MAX_TEXT_COLORS: usize = 1024;
+/// Luma difference of neighbour pixels considered as a sharp edge
+const EDGE_LUMA_DIFF: i32 = 64;
+/// Luma difference of neighbour pixels considered as the same color
+const FLAT_LUMA_DIFF: i32 = 2;
+
+/// Kind of content of a frame, which sets the best chroma subsampling
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameContent {
+    /// Text and line art: sharp edges on flat areas, with few colors
+    Text,
+    /// Video and pictures: smooth gradients
+    Natural,
+    /// Neither of them dominates
+    Mixed,
+}
+
+/// Classify the content of a 32 bits `frame`, from a sample of its rows
+pub fn frame_content(frame: &[u8], width: u32, height: u32, bytes_per_line: u32) -> FrameContent {
+    let mut colors = HashSet::new();
+    let (mut pairs, mut flat, mut edges) = (0usize, 0usize, 0usize);
+    for row in (0..height as usize).step_by(CONTENT_SAMPLE_ROWS) {
+        let offset = row * bytes_per_line as usize;
+        let line = match frame.get(offset..offset + width as usize * 4) {
+            Some(line) => line,
+            None => break,
+        };
+        let mut previous: Option<i32> = None;
+        for pixel in line.chunks_exact(4) {
+            if colors.len() < MAX_TEXT_COLORS {
+                colors.insert([pixel[0], pixel[1], pixel[2]]);
+            }
+            // The luma weights don't depend on the byte order of the frame
+            let luma = (pixel[0] as i32 * 2 + pixel[1] as i32 * 5 + pixel[2] as i32) / 8;
+            if let Some(previous) = previous {
+                let diff = (luma - previous).abs();
+                pairs += 1;
+                if diff <= FLAT_LUMA_DIFF {
+                    flat += 1;
+                } else if diff >= EDGE_LUMA_DIFF {
+                    edges += 1;
+                }
+            }
+            previous = Some(luma);
+        }
+    }
+    let smooth = pairs - flat - edges;
+    if pairs == 0 {
+        FrameContent::Mixed
+    } else if smooth * 4 >= pairs {
+        FrameContent::Natural
+    } else if flat * 10 >= pairs * 7 && edges * 100 >= pairs && colors.len() < MAX_TEXT_COLORS {
+        FrameContent::Text
+    } else {
+        FrameContent::Mixed
+    }
+}
+
+/// Delay between two analyses of the frame content
+const CHROMA_ANALYSIS_PERIOD: Duration = Duration::from_millis(500);
+/// Consecutive analyses agreeing on the content before a chroma switch
+const CHROMA_SWITCH_ANALYSES: u32 = 3;
+/// Minimum delay between two chroma switches, each of them restarting the
+/// encoder on a keyframe
+const CHROMA_SWITCH_DELAY: Duration = Duration::from_secs(5);
+
+/// Selects the chroma subsampling of the frames from their content: 4:4:4 for
+/// text, 4:2:0 for video
+///
+/// A switch needs `CHROMA_SWITCH_ANALYSES` consecutive analyses agreeing on
+/// the content, and happens at most once per `CHROMA_SWITCH_DELAY`.
+#[derive(Default)]
+pub struct ChromaAdvisor {
+    last_analysis: Option<Instant>,
+    last_switch: Option<Instant>,
+    /// Chroma matching the last analyses, and their count
+    candidate: Option<(Chroma, u32)>,
+}
+
+impl ChromaAdvisor {
+    /// Analyze the 32 bits `frame` grabbed at `now`, returns the chroma to
+    /// switch to from the `current` one, if any
+    pub fn update(
+        &mut self,
+        (frame, width, height, bytes_per_line): (&[u8], u32, u32, u32),
+        current: Option<Chroma>,
+        now: Instant,
+    ) -> Option<Chroma> {
+        if self
+            .last_analysis
+            .is_some_and(|last| now.duration_since(last) < CHROMA_ANALYSIS_PERIOD)
+        {
+            return None;
+        }
+        self.last_analysis = Some(now);
+        let wanted = match frame_content(frame, width, height, bytes_per_line) {
+            FrameContent::Text => Chroma::Yuv444,
+            FrameContent::Natural => Chroma::Yuv420,
+            FrameContent::Mixed => {
+                self.candidate = None;
+                return None;
+            }
+        };
+        // Without a negotiated chroma, the encoder default is 4:2:0
+        if current.unwrap_or(Chroma::Yuv420) == wanted {
+            self.candidate = None;
+            return None;
+        }
+        let count = match self.candidate {
+            Some((chroma, count)) if chroma == wanted => count + 1,
+            _ => 1,
+        };
+        self.candidate = Some((wanted, count));
+        if count < CHROMA_SWITCH_ANALYSES
+            || self
+                .last_switch
+                .is_some_and(|last| now.duration_since(last) < CHROMA_SWITCH_DELAY)
+        {
+            return None;
+        }
+        self.candidate = None;
+        self.last_switch = Some(now);
+        Some(wanted)
+    }
+}
+
+/// Durations of the stages of a server loop iteration, reported to the
+/// observer given to `run_with_observer`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FrameTimings {
+    /// Time elapsed since the start of the previous iteration
+    pub frame: Duration,
+    /// Whole iteration, without the frame rate limiting sleep
+    pub total: Duration,
+    pub grab: Duration,
+    /// Poll of the graphic server events
+    pub event: Duration,
+    pub encode: Duration,
+    /// Stages of the encoding, labelled by the `TIMING_*` constants of the
+    /// video encoder
+    pub encoder: Vec<(&'static str, Duration)>,
+    pub sound: Duration,
+    pub send: Duration,
+    /// Reception and handling of the client events
+    pub recv: Duration,
+}
+
+impl FrameTimings {
+    /// Duration of the encoding stage `label`, if measured
+    pub fn encoder_stage(&self, label: &str) -> Option<Duration> {
+        self.encoder
+            .iter()
+            .find(|(name, _)| *name == label)
+            .map(|(_, duration)| *duration)
+    }
+}
+
+/// Frame rate actually achieved by the server loop, averaged over one second
+#[derive(Default)]
+pub struct FpsCounter {
+    start: Option<Instant>,
+    frames: u32,
+    fps: f64,
+}
+
+impl FpsCounter {
+    /// Count a frame started at `now`, returns the last averaged frame rate
+    pub fn tick(&mut self, now: Instant) -> f64 {
+        let start = *self.start.get_or_insert(now);
+        if now == start {
+            return self.fps;
+        }
+        self.frames += 1;
+        let elapsed = now - start;
+        if elapsed >= Duration::from_secs(1) {
+            self.fps = self.frames as f64 / elapsed.as_secs_f64();
+            self.start = Some(now);
+            self.frames = 0;
+        }
+        self.fps
+    }
+}
+
+/// Frames dropped by the server loop when it is late on the frame rate: the
+/// missed periods are skipped instead of being caught up
+pub struct FrameDropCounter {
+    frame_duration: Duration,
+    dropped: u64,
+}
+
+impl FrameDropCounter {
+    pub fn new(frame_duration: Duration) -> Self {
+        FrameDropCounter {
+            frame_duration,
+            dropped: 0,
+        }
+    }
+
+    /// Count the periods skipped by a loop of `loop_duration`, returns the
+    /// total of dropped frames
+    pub fn update(&mut self, loop_duration: Duration) -> u64 {
+        let periods = (loop_duration + self.frame_duration / 2).as_micros()
+            / self.frame_duration.as_micros().max(1);
+        self.dropped += (periods as u64).saturating_sub(1);
+        self.dropped
+    }
+}
+
+/// Split the large clipboard values sent to the client, and add the next
+/// pending clipboard chunk to `events`
+pub fn chunk_clipboard(
+    chunker: &mut ClipboardChunker,
+    events: Vec<tunnel::MessageSrv>,
+) -> Vec<tunnel::MessageSrv> {
+    let mut events: Vec<_> = events
+        .into_iter()
+        .filter_map(|event| match event.msg {
+            Some(tunnel::message_srv::Msg::Clipboard(clipboard)) => {
+                chunker.push(clipboard).map(|clipboard| tunnel::MessageSrv {
+                    msg: Some(tunnel::message_srv::Msg::Clipboard(clipboard)),
+                })
+            }
+            msg => Some(tunnel::MessageSrv { msg }),
+        })
+        .collect();
+    if let Some(chunk) = chunker.next_chunk() {
+        events.push(tunnel::MessageSrv {
+            msg: Some(tunnel::message_srv::Msg::ClipboardChunk(chunk)),
+        });
+    }
+    events
+}
+
+/// Replace the cursors already sent to the client by references to its
+/// cursor cache
+pub fn cache_cursors(
+    cache: &mut CursorCache<()>,
+    events: Vec<tunnel::MessageSrv>,
+) -> Vec<tunnel::MessageSrv> {
+    events
+        .into_iter()
+        .map(|event| match event.msg {
+            Some(tunnel::message_srv::Msg::Cursor(mut cursor)) => {
+                let id = cursor_id(&cursor);
+                let msg = if cache.get(id).is_some() {
+                    tunnel::message_srv::Msg::CursorRef(tunnel::EventCursorRef { id })
+                } else {
+                    cache.insert(id, ());
+                    cursor.id = id;
+                    tunnel::message_srv::Msg::Cursor(cursor)
+                };
+                tunnel::MessageSrv { msg: Some(msg) }
+            }
+            msg => tunnel::MessageSrv { msg },
+        })
+        .collect()
+}
+
+/// Replace the clipboard chunks received from the client by the rebuilt
+/// clipboard values
+pub fn reassemble_clipboard(
+    assembler: &mut ClipboardAssembler,
+    msgs: Vec<tunnel::MessageClient>,
+) -> Vec<tunnel::MessageClient> {
+    msgs.into_iter()
+        .filter_map(|msg| match msg.msg {
+            Some(tunnel::message_client::Msg::ClipboardChunk(chunk)) => {
+                match assembler.push(chunk) {
+                    Ok(clipboard) => clipboard.map(|clipboard| tunnel::MessageClient {
+                        msg: Some(tunnel::message_client::Msg::Clipboard(clipboard)),
+                    }),
+                    Err(err) => {
+                        warn!("Clipboard chunk dropped: {}", err);
+                        None
+                    }
+                }
+            }
+            msg => Some(tunnel::MessageClient { msg }),
+        })
+        .collect()
+}
+
+/// State of the pointer emulation of the client touch events: the first
+/// touch point drives the pointer, the other ones are ignored
+#[derive(Debug, Default)]
+pub struct TouchEmulation {
+    primary: Option<u32>,
+}
+
+/// Replace the touch events received from the client by pointer moves and
+/// left button presses
+pub fn emulate_touch(
+    touch: &mut TouchEmulation,
+    msgs: Vec<tunnel::MessageClient>,
+) -> Vec<tunnel::MessageClient> {
+    let mut events = vec![];
+    for msg in msgs {
+        let event = match msg.msg {
+            Some(tunnel::message_client::Msg::Touch(event)) => event,
+            msg => {
+                events.push(tunnel::MessageClient { msg });
+                continue;
+            }
+        };
+        let updown = match (event.phase(), touch.primary) {
+            (tunnel::TouchPhase::Begin, None) => {
+                touch.primary = Some(event.id);
+                Some(true)
+            }
+            (tunnel::TouchPhase::Update, Some(id)) if id == event.id => None,
+            (tunnel::TouchPhase::End | tunnel::TouchPhase::Cancel, Some(id)) if id == event.id => {
+                touch.primary = None;
+                Some(false)
+            }
+            _ => continue,
+        };
+        events.push(tunnel::MessageClient {
+            msg: Some(tunnel::message_client::Msg::Move(tunnel::EventMove {
+                x: event.x,
+                y: event.y,
+                ..Default::default()
+            })),
+        });
+        if let Some(updown) = updown {
+            events.push(tunnel::MessageClient {
+                msg: Some(tunnel::message_client::Msg::Button(tunnel::EventButton {
+                    x: event.x,
+                    y: event.y,
+                    button: 1,
+                    updown,
+                })),
+            });
+        }
+    }
+    events
+}
+
+/// Action of a parsed input rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyAction {
+    Block,
+    Allow,
+    Remap(u32),
+}
+
+/// Applies the configured rules to the client keys, before their injection
+///
+/// Without rules, all the keys are injected as is
+#[derive(Debug, Default)]
+pub struct InputFilter {
+    rules: Vec<(Hotkey, KeyAction)>,
+    /// Keys held on the client, including the blocked ones
+    keys_state: Vec<bool>,
+    /// Keycode injected for each held key, None if blocked, so that the
+    /// release matches the press
+    held: HashMap<u32, Option<u32>>,
+}
+
+impl InputFilter {
+    pub fn new(rules: &[InputRule]) -> Result<Self> {
+        let mut parsed = vec![];
+        for rule in rules {
+            let hotkey = rule
+                .keys
+                .parse::<Hotkey>()
+                .with_context(|| format!("Bad input rule keys {:?}", rule.keys))?;
+            let action = match (rule.action, &rule.remap) {
+                (InputAction::Block, None) => KeyAction::Block,
+                (InputAction::Allow, None) => KeyAction::Allow,
+                (InputAction::Remap, Some(remap)) => KeyAction::Remap(
+                    keycode_from_name(remap)
+                        .with_context(|| format!("Bad input rule remap {:?}", remap))?
+                        as u32,
+                ),
+                (InputAction::Remap, None) => {
+                    return Err(anyhow!("Input rule {:?} without remap key", rule.keys))
+                }
+                (_, Some(_)) => {
+                    return Err(anyhow!(
+                        "Input rule {:?}: remap needs the remap action",
+                        rule.keys
+                    ))
+                }
+            };
+            parsed.push((hotkey, action));
+        }
+        Ok(InputFilter {
+            rules: parsed,
+            keys_state: vec![false; 256],
+            held: HashMap::new(),
+        })
+    }
+
+    /// Keycode to inject for a client key event, None if the key is blocked
+    pub fn filter_key(&mut self, keycode: u32, updown: bool) -> Option<u32> {
+        if !updown {
+            if let Some(state) = self.keys_state.get_mut(keycode as usize) {
+                *state = false;
+            }
+            return self.held.remove(&keycode).unwrap_or(Some(keycode));
+        }
+        // Repeated presses keep the action of the first one
+        if let Some(injected) = self.held.get(&keycode) {
+            return *injected;
+        }
+        let action = self
+            .rules
+            .iter()
+            .find(|(hotkey, _)| hotkey.matches(keycode as usize, &self.keys_state))
+            .map_or(KeyAction::Allow, |(_, action)| *action);
+        let injected = match action {
+            KeyAction::Block => {
+                debug!("Key {} blocked", keycode);
+                None
+            }
+            KeyAction::Allow => Some(keycode),
+            KeyAction::Remap(remap) => Some(remap),
+        };
+        if let Some(state) = self.keys_state.get_mut(keycode as usize) {
+            *state = true;
+        }
+        self.held.insert(keycode, injected);
+        injected
+    }
+}
+
+/// Apply the input rules to the key events received from the client
+pub fn filter_input(
+    filter: &mut InputFilter,
+    msgs: Vec<tunnel::MessageClient>,
+) -> Vec<tunnel::MessageClient> {
+    if filter.rules.is_empty() {
+        return msgs;
+    }
+    msgs.into_iter()
+        .filter_map(|msg| match msg.msg {
+            Some(tunnel::message_client::Msg::Key(event)) => filter
+                .filter_key(event.keycode, event.updown)
+                .map(|keycode| tunnel::MessageClient {
+                    msg: Some(tunnel::message_client::Msg::Key(tunnel::EventKey {
+                        keycode,
+                        ..event
+                    })),
+                }),
+            msg => Some(tunnel::MessageClient { msg }),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 32 bits frame of `width`x`height` pixels, with the gray `level` of
+    /// each (x, y) pixel
+    fn gray_frame(width: u32, height: u32, level: impl Fn(u32, u32) -> u8) -> Vec<u8> {
+        let mut frame = vec![];
+        for y in 0..height {
+            for x in 0..width {
+                let level = level(x, y);
+                frame.extend_from_slice(&[level, level, level, 0xff]);
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn test_chroma_advisor() {
+        let (width, height) = (320, 64);
+        // Dark strokes on a white background
+        let text = gray_frame(width, height, |x, _| match x < width / 2 && x % 6 == 0 {
+            true => 0,
+            false => 0xff,
+        });
+        // Noisy gradient
+        let video = gray_frame(width, height, |x, y| {
+            ((x + y * 3 + (x * 7919 + y * 104729) % 23) % 256) as u8
+        });
+        let blank = gray_frame(width, height, |_, _| 0xff);
+        assert_eq!(
+            frame_content(&text, width, height, width * 4),
+            FrameContent::Text
+        );
+        assert_eq!(
+            frame_content(&video, width, height, width * 4),
+            FrameContent::Natural
+        );
+        assert_eq!(
+            frame_content(&blank, width, height, width * 4),
+            FrameContent::Mixed
+        );
+
+        let text = (text.as_slice(), width, height, width * 4);
+        let video = (video.as_slice(), width, height, width * 4);
+        let mut advisor = ChromaAdvisor::default();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        // Switched once the content is stable
+        assert_eq!(advisor.update(text, None, at(0)), None);
+        assert_eq!(advisor.update(text, None, at(100)), None);
+        assert_eq!(advisor.update(text, None, at(500)), None);
+        assert_eq!(advisor.update(text, None, at(1000)), Some(Chroma::Yuv444));
+        let current = Some(Chroma::Yuv444);
+        assert_eq!(advisor.update(text, current, at(1500)), None);
+
+        // Not switched back before the switch delay
+        for ms in [2000, 2500, 3000, 5500] {
+            assert_eq!(advisor.update(video, current, at(ms)), None);
+        }
+        assert_eq!(
+            advisor.update(video, current, at(6000)),
+            Some(Chroma::Yuv420)
+        );
+    }
+
+    #[test]
+    fn test_cache_cursors() {
+        let cursor = |xhot| tunnel::MessageSrv {
+            msg: Some(tunnel::message_srv::Msg::Cursor(tunnel::EventCursor {
+                data: vec![0xff; 16],
+                width: 2,
+                height: 2,
+                xhot,
+                ..Default::default()
+            })),
+        };
+        let mut cache = CursorCache::default();
+        let events = cache_cursors(&mut cache, vec![cursor(0), cursor(1), cursor(0)]);
+        let id = match &events[0].msg {
+            Some(tunnel::message_srv::Msg::Cursor(cursor)) => cursor.id,
+            msg => panic!("Unexpected message {:?}", msg),
+        };
+        assert_ne!(id, 0);
+        assert!(matches!(
+            events[1].msg,
+            Some(tunnel::message_srv::Msg::Cursor(_))
+        ));
+        assert_eq!(
+            events[2].msg,
+            Some(tunnel::message_srv::Msg::CursorRef(
+                tunnel::EventCursorRef { id }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_bitrate_controller() {
+        let config = BitrateControl {
+            min_kbps: 500,
+            max_kbps: 2000,
+            step_kbps: 250,
+            max_latency_ms: 50,
+        };
+        let frame_duration = Duration::from_millis(40);
+        let congested = tunnel::EventLinkStats {
+            throughput_kbps: 10000,
+            recv_time_ms: 200,
+        };
+        let uncongested = tunnel::EventLinkStats {
+            throughput_kbps: 10000,
+            recv_time_ms: 40,
+        };
+        let mut controller = BitrateController::new(&config);
+        assert_eq!(controller.target_kbps(), 2000);
+        assert_eq!(controller.update(&congested, frame_duration), Some(1750));
+        // Falls to the observed throughput, not below the minimum
+        let slow = tunnel::EventLinkStats {
+            throughput_kbps: 800,
+            ..congested
+        };
+        assert_eq!(controller.update(&slow, frame_duration), Some(800));
+        assert_eq!(controller.update(&slow, frame_duration), Some(550));
+        assert_eq!(controller.update(&slow, frame_duration), Some(500));
+        assert_eq!(controller.update(&slow, frame_duration), None);
+
+        // Raised slowly, up to the maximum
+        for _ in 0..BITRATE_RAISE_FRAMES - 1 {
+            assert_eq!(controller.update(&uncongested, frame_duration), None);
+        }
+        assert_eq!(controller.update(&uncongested, frame_duration), Some(750));
+        for _ in 0..10 * BITRATE_RAISE_FRAMES {
+            controller.update(&uncongested, frame_duration);
+        }
+        assert_eq!(controller.target_kbps(), 2000);
+    }
+
+    #[test]
+    fn test_chunk_clipboard() {
+        use crate::utils::{ClipboardContent, CLIPBOARD_CHUNK_SIZE};
+
+        let clipboard = tunnel::EventClipboard {
+            content: Some(ClipboardContent::Text("x".repeat(3 * CLIPBOARD_CHUNK_SIZE))),
+        };
+        let stats = tunnel::MessageSrv {
+            msg: Some(tunnel::message_srv::Msg::Stats(tunnel::EventStats {
+                stats: "".to_owned(),
+            })),
+        };
+        let mut chunker = ClipboardChunker::default();
+        let mut assembler = ClipboardAssembler::default();
+        let mut events = vec![
+            tunnel::MessageSrv {
+                msg: Some(tunnel::message_srv::Msg::Clipboard(clipboard.clone())),
+            },
+            stats.clone(),
+        ];
+        let mut received = None;
+        for _ in 0..10 {
+            let sent = chunk_clipboard(&mut chunker, std::mem::take(&mut events));
+            // One chunk per frame, interleaved with the other events
+            assert!(sent.len() <= 2);
+            for msg in crate::client_utils::reassemble_clipboard(&mut assembler, sent) {
+                if let Some(tunnel::message_srv::Msg::Clipboard(clipboard)) = msg.msg {
+                    received = Some(clipboard);
+                }
+            }
+        }
+        assert_eq!(received, Some(clipboard));
+    }
+
+    #[test]
+    fn test_granted_resolution() {
+        let (min, max) = ((64, 64), (4096, 2160));
+        assert_eq!(granted_resolution((1920, 1080), min, max), (1920, 1080));
+        assert_eq!(granted_resolution((100_000, 1080), min, max), (4096, 1080));
+        assert_eq!(granted_resolution((16, 4000), min, max), (64, 2160));
+        // Odd limits
+        assert_eq!(
+            granted_resolution((8000, 8000), min, (4095, 2161)),
+            (4094, 2160)
+        );
+    }
+
+    #[test]
+    fn test_frame_timings() {
+        let timings = FrameTimings {
+            encoder: vec![
+                ("yuv", Duration::from_millis(2)),
+                ("enc", Duration::from_millis(5)),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(timings.encoder_stage("enc"), Some(Duration::from_millis(5)));
+        assert_eq!(timings.encoder_stage("memcpy"), None);
+    }
+
+    #[test]
+    fn test_fps_counter() {
+        let start = Instant::now();
+        let mut counter = FpsCounter::default();
+        for i in 0..25 {
+            assert_eq!(counter.tick(start + Duration::from_millis(40) * i), 0.0);
+        }
+        assert_eq!(counter.tick(start + Duration::from_millis(1000)), 25.0);
+        // Kept until the next second is elapsed
+        assert_eq!(counter.tick(start + Duration::from_millis(1100)), 25.0);
+        for i in 2..=10 {
+            counter.tick(start + Duration::from_millis(1000) + Duration::from_millis(100) * i);
+        }
+        assert_eq!(counter.tick(start + Duration::from_millis(2500)), 10.0);
+    }
+
+    #[test]
+    fn test_frame_drop_counter() {
+        let mut counter = FrameDropCounter::new(Duration::from_millis(40));
+        assert_eq!(counter.update(Duration::from_millis(40)), 0);
+        // Scheduling jitter is not a drop
+        assert_eq!(counter.update(Duration::from_millis(55)), 0);
+        assert_eq!(counter.update(Duration::from_millis(79)), 1);
+        assert_eq!(counter.update(Duration::from_millis(160)), 4);
+        // Early wake up
+        assert_eq!(counter.update(Duration::from_millis(5)), 4);
+    }
+
+    #[test]
+    fn test_emulate_touch() {
+        let touch = |id, phase: tunnel::TouchPhase| tunnel::MessageClient {
+            msg: Some(tunnel::message_client::Msg::Touch(tunnel::EventTouch {
+                id,
+                x: id * 10,
+                y: 5,
+                phase: phase as i32,
+            })),
+        };
+        let pointer = |x, updown: Option<bool>| {
+            let mut events = vec![tunnel::message_client::Msg::Move(tunnel::EventMove {
+                x,
+                y: 5,
+                ..Default::default()
+            })];
+            if let Some(updown) = updown {
+                events.push(tunnel::message_client::Msg::Button(tunnel::EventButton {
+                    x,
+                    y: 5,
+                    button: 1,
+                    updown,
+                }));
+            }
+            events
+        };
+        let mut emulation = TouchEmulation::default();
+        let mut emulate = |msgs| -> Vec<_> {
+            emulate_touch(&mut emulation, msgs)
+                .into_iter()
+                .filter_map(|msg| msg.msg)
+                .collect()
+        };
+        assert_eq!(
+            emulate(vec![touch(1, tunnel::TouchPhase::Begin)]),
+            pointer(10, Some(true))
+        );
+        // Secondary touch points are ignored
+        assert_eq!(
+            emulate(vec![
+                touch(2, tunnel::TouchPhase::Begin),
+                touch(1, tunnel::TouchPhase::Update),
+                touch(2, tunnel::TouchPhase::End),
+            ]),
+            pointer(10, None)
+        );
+        assert_eq!(
+            emulate(vec![touch(1, tunnel::TouchPhase::Cancel)]),
+            pointer(10, Some(false))
+        );
+        assert_eq!(emulate(vec![touch(2, tunnel::TouchPhase::Update)]), vec![]);
+        assert_eq!(
+            emulate(vec![touch(2, tunnel::TouchPhase::Begin)]),
+            pointer(20, Some(true))
+        );
+    }
+
+    #[test]
+    fn test_input_filter() {
+        let rule = |keys: &str, action, remap: Option<&str>| InputRule {
+            keys: keys.to_owned(),
+            action,
+            remap: remap.map(|remap| remap.to_owned()),
+        };
+        let rules = [
+            rule("ctrl+alt+f2", InputAction::Allow, None),
+            rule("ctrl+alt+keycode:68", InputAction::Block, None),
+            rule("ctrl+alt+f1", InputAction::Block, None),
+            rule("super", InputAction::Remap, Some("alt")),
+        ];
+        let mut filter = InputFilter::new(&rules).unwrap();
+        let key = |keycode, updown| tunnel::MessageClient {
+            msg: Some(tunnel::message_client::Msg::Key(tunnel::EventKey {
+                keycode,
+                updown,
+            })),
+        };
+        let mut keys = |msgs: Vec<(u32, bool)>| -> Vec<(u32, bool)> {
+            let msgs = msgs
+                .into_iter()
+                .map(|(keycode, updown)| key(keycode, updown))
+                .collect();
+            filter_input(&mut filter, msgs)
+                .into_iter()
+                .filter_map(|msg| match msg.msg {
+                    Some(tunnel::message_client::Msg::Key(event)) => {
+                        Some((event.keycode, event.updown))
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+        // F1 alone is injected, blocked with ctrl+alt, release included
+        assert_eq!(
+            keys(vec![(67, true), (67, false)]),
+            [(67, true), (67, false)]
+        );
+        assert_eq!(
+            keys(vec![
+                (37, true),
+                (64, true),
+                (67, true),
+                (67, true),
+                (67, false)
+            ]),
+            [(37, true), (64, true)]
+        );
+        // The first matching rule applies
+        assert_eq!(
+            keys(vec![(68, true), (68, false)]),
+            [(68, true), (68, false)]
+        );
+        assert_eq!(
+            keys(vec![(37, false), (64, false)]),
+            [(37, false), (64, false)]
+        );
+        // Remapped key, released as remapped
+        assert_eq!(
+            keys(vec![(133, true), (133, false)]),
+            [(64, true), (64, false)]
+        );
+
+        assert!(InputFilter::new(&[rule("ctrl+foo", InputAction::Block, None)]).is_err());
+        assert!(InputFilter::new(&[rule("super", InputAction::Remap, None)]).is_err());
+        assert!(InputFilter::new(&[rule("super", InputAction::Block, Some("alt"))]).is_err());
+
+        // Without rules, the events are untouched
+        let mut filter = InputFilter::default();
+        assert_eq!(
+            filter_input(&mut filter, vec![key(67, true)]),
+            [key(67, true)]
+        );
+    }
 }
@@ -0,0 +1,667 @@
+//! Wayland server backend
+//!
+//! Wayland compositors do not allow clients to grab the screen or to inject
+//! inputs. The xdg-desktop-portal RemoteDesktop session gives both: the screen
+//! is streamed through PipeWire, and inputs are sent using the portal methods.
+use crate::{
+    config::ConfigServer,
+    server_utils::Server,
+    utils::{ServerArgsConfig, ServerEvent},
+    video_encoder::{EncodedTile, Encoder, EncoderTimings},
+};
+use anyhow::{Context, Result};
+use dbus::{
+    arg::{AppendAll, PropMap, RefArg, Variant},
+    blocking::LocalConnection,
+    message::MatchRule,
+    Path,
+};
+use pipewire as pw;
+use sanzu_common::tunnel;
+
+use std::{
+    cell::RefCell,
+    io::Cursor,
+    os::fd::{FromRawFd, OwnedFd},
+    rc::Rc,
+    sync::{Arc, Mutex},
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
+
+const PORTAL_BUS_NAME: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+const PORTAL_SCREENCAST: &str = "org.freedesktop.portal.ScreenCast";
+const PORTAL_REMOTE_DESKTOP: &str = "org.freedesktop.portal.RemoteDesktop";
+
+const DBUS_TIMEOUT: Duration = Duration::from_secs(5);
+/// The user may have to accept the session in a portal dialog
+const PORTAL_RESPONSE_TIMEOUT: Duration = Duration::from_secs(120);
+const FIRST_FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// RemoteDesktop device types
+const DEVICE_KEYBOARD: u32 = 1;
+const DEVICE_POINTER: u32 = 2;
+/// ScreenCast source type
+const SOURCE_MONITOR: u32 = 1;
+/// ScreenCast cursor mode: the cursor is drawn in the frames
+const CURSOR_MODE_EMBEDDED: u32 = 2;
+
+/// Linux input event codes of the mouse buttons
+const BTN_LEFT: i32 = 0x110;
+const BTN_RIGHT: i32 = 0x111;
+const BTN_MIDDLE: i32 = 0x112;
+/// RemoteDesktop scroll axis
+const AXIS_VERTICAL: u32 = 0;
+const AXIS_HORIZONTAL: u32 = 1;
+/// X11 keycodes are evdev keycodes shifted by 8
+const X11_KEYCODE_OFFSET: u32 = 8;
+
+/// Last frame received from PipeWire
+#[derive(Default)]
+struct SharedFrame {
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+    bytes_per_line: u32,
+    updated: bool,
+}
+
+/// RemoteDesktop portal session
+struct PortalSession {
+    conn: LocalConnection,
+    session: Path<'static>,
+    /// PipeWire node of the captured monitor
+    node_id: u32,
+    token_count: u32,
+}
+
+/// Object path of the portal request `token`
+///
+/// The response signal may be emitted before the method returns, so we have
+/// to subscribe to it before the call.
+fn request_path(conn: &LocalConnection, token: &str) -> Result<Path<'static>> {
+    let sender = conn.unique_name().trim_start_matches(':').replace('.', "_");
+    Path::new(format!("{}/request/{}/{}", PORTAL_PATH, sender, token))
+        .map_err(|err| anyhow!("Bad request path: {}", err))
+}
+
+/// Add a PropMap entry
+fn prop<T: RefArg + 'static>(options: &mut PropMap, key: &str, value: T) {
+    options.insert(key.to_owned(), Variant(Box::new(value)));
+}
+
+/// Node id of the first stream returned by the Start method
+fn first_stream_node(results: &PropMap) -> Option<u32> {
+    let streams = results.get("streams")?;
+    let stream = streams.0.as_iter()?.next()?;
+    let node_id = stream.as_iter()?.next()?.as_u64()?;
+    Some(node_id as u32)
+}
+
+impl PortalSession {
+    fn new_token(&mut self) -> String {
+        self.token_count += 1;
+        format!("sanzu{}_{}", std::process::id(), self.token_count)
+    }
+
+    /// Call a portal method returning a Request, and wait for its response
+    ///
+    /// `args` is built from the request token to pass in the method options
+    fn request<A: AppendAll>(
+        &mut self,
+        interface: &str,
+        method: &str,
+        args: impl FnOnce(String) -> A,
+    ) -> Result<PropMap> {
+        let token = self.new_token();
+        let path = request_path(&self.conn, &token)?;
+        let response: Rc<RefCell<Option<(u32, PropMap)>>> = Rc::default();
+        let response_cb = response.clone();
+        let rule =
+            MatchRule::new_signal("org.freedesktop.portal.Request", "Response").with_path(path);
+        let match_token = self
+            .conn
+            .add_match(rule, move |result: (u32, PropMap), _, _| {
+                *response_cb.borrow_mut() = Some(result);
+                true
+            })
+            .context("Error in add_match")?;
+
+        let proxy = self
+            .conn
+            .with_proxy(PORTAL_BUS_NAME, PORTAL_PATH, DBUS_TIMEOUT);
+        let _: (Path,) = proxy
+            .method_call(interface, method, args(token))
+            .context(format!("Error in {}", method))?;
+
+        let time_start = Instant::now();
+        let result = loop {
+            if let Some(result) = response.borrow_mut().take() {
+                break result;
+            }
+            if time_start.elapsed() > PORTAL_RESPONSE_TIMEOUT {
+                return Err(anyhow!("Timeout waiting for {} response", method));
+            }
+            self.conn
+                .process(Duration::from_millis(100))
+                .context("Error in dbus process")?;
+        };
+        self.conn
+            .remove_match(match_token)
+            .context("Error in remove_match")?;
+
+        match result {
+            (0, results) => Ok(results),
+            (1, _) => Err(anyhow!("{} cancelled by the user", method)),
+            (code, _) => Err(anyhow!("{} failed ({})", method, code)),
+        }
+    }
+
+    /// Open a RemoteDesktop session with keyboard, pointer and the screen
+    /// selected by the user
+    fn open() -> Result<PortalSession> {
+        let conn = LocalConnection::new_session().context("Cannot connect to session bus")?;
+        let mut portal = PortalSession {
+            conn,
+            session: Path::from("/"),
+            node_id: 0,
+            token_count: 0,
+        };
+
+        let session_token = portal.new_token();
+        let results = portal
+            .request(PORTAL_REMOTE_DESKTOP, "CreateSession", |token| {
+                let mut options = PropMap::new();
+                prop(&mut options, "handle_token", token);
+                prop(&mut options, "session_handle_token", session_token);
+                (options,)
+            })
+            .context("Cannot create portal session")?;
+        let session = results
+            .get("session_handle")
+            .and_then(|handle| handle.0.as_str())
+            .context("No session handle")?;
+        portal.session =
+            Path::new(session.to_owned()).map_err(|err| anyhow!("Bad session handle: {}", err))?;
+        debug!("Portal session {}", portal.session);
+
+        let session = portal.session.clone();
+        portal
+            .request(PORTAL_REMOTE_DESKTOP, "SelectDevices", |token| {
+                let mut options = PropMap::new();
+                prop(&mut options, "handle_token", token);
+                prop(&mut options, "types", DEVICE_KEYBOARD | DEVICE_POINTER);
+                (session, options)
+            })
+            .context("Cannot select input devices")?;
+
+        let session = portal.session.clone();
+        portal
+            .request(PORTAL_SCREENCAST, "SelectSources", |token| {
+                let mut options = PropMap::new();
+                prop(&mut options, "handle_token", token);
+                prop(&mut options, "types", SOURCE_MONITOR);
+                prop(&mut options, "multiple", false);
+                prop(&mut options, "cursor_mode", CURSOR_MODE_EMBEDDED);
+                (session, options)
+            })
+            .context("Cannot select screen")?;
+
+        let session = portal.session.clone();
+        let results = portal
+            .request(PORTAL_REMOTE_DESKTOP, "Start", |token| {
+                let mut options = PropMap::new();
+                prop(&mut options, "handle_token", token);
+                (session, "", options)
+            })
+            .context("Cannot start portal session")?;
+        portal.node_id = first_stream_node(&results).context("No screen stream")?;
+        debug!("PipeWire node {}", portal.node_id);
+
+        Ok(portal)
+    }
+
+    /// File descriptor of the PipeWire remote giving access to the stream
+    fn open_pipewire_remote(&self) -> Result<OwnedFd> {
+        let proxy = self
+            .conn
+            .with_proxy(PORTAL_BUS_NAME, PORTAL_PATH, DBUS_TIMEOUT);
+        let (fd,): (dbus::arg::OwnedFd,) = proxy
+            .method_call(
+                PORTAL_SCREENCAST,
+                "OpenPipeWireRemote",
+                (self.session.clone(), PropMap::new()),
+            )
+            .context("Error in OpenPipeWireRemote")?;
+        Ok(unsafe { OwnedFd::from_raw_fd(fd.into_fd()) })
+    }
+
+    fn notify<A: AppendAll>(&self, method: &str, args: A) -> Result<()> {
+        let proxy = self
+            .conn
+            .with_proxy(PORTAL_BUS_NAME, PORTAL_PATH, DBUS_TIMEOUT);
+        proxy
+            .method_call(PORTAL_REMOTE_DESKTOP, method, args)
+            .context(format!("Error in {}", method))
+    }
+
+    fn pointer_move(&self, x: f64, y: f64) -> Result<()> {
+        self.notify(
+            "NotifyPointerMotionAbsolute",
+            (self.session.clone(), PropMap::new(), self.node_id, x, y),
+        )
+    }
+
+    fn pointer_move_relative(&self, dx: f64, dy: f64) -> Result<()> {
+        self.notify(
+            "NotifyPointerMotion",
+            (self.session.clone(), PropMap::new(), dx, dy),
+        )
+    }
+
+    fn pointer_button(&self, button: u32, pressed: bool) -> Result<()> {
+        let (axis, steps) = match button {
+            1 => return self.pointer_evdev_button(BTN_LEFT, pressed),
+            2 => return self.pointer_evdev_button(BTN_MIDDLE, pressed),
+            3 => return self.pointer_evdev_button(BTN_RIGHT, pressed),
+            4 => (AXIS_VERTICAL, -1),
+            5 => (AXIS_VERTICAL, 1),
+            6 => (AXIS_HORIZONTAL, -1),
+            7 => (AXIS_HORIZONTAL, 1),
+            _ => {
+                trace!("Unsupported mouse button {}", button);
+                return Ok(());
+            }
+        };
+        // Wheel buttons are sent as press/release, scroll once
+        if !pressed {
+            return Ok(());
+        }
+        self.notify(
+            "NotifyPointerAxisDiscrete",
+            (self.session.clone(), PropMap::new(), axis, steps),
+        )
+    }
+
+    fn pointer_evdev_button(&self, button: i32, pressed: bool) -> Result<()> {
+        self.notify(
+            "NotifyPointerButton",
+            (self.session.clone(), PropMap::new(), button, pressed as u32),
+        )
+    }
+
+    fn key(&self, keycode: u32, pressed: bool) -> Result<()> {
+        if keycode < X11_KEYCODE_OFFSET {
+            return Ok(());
+        }
+        self.notify(
+            "NotifyKeyboardKeycode",
+            (
+                self.session.clone(),
+                PropMap::new(),
+                (keycode - X11_KEYCODE_OFFSET) as i32,
+                pressed as u32,
+            ),
+        )
+    }
+}
+
+/// Receive the frames of the PipeWire `node_id` into `frame`
+///
+/// Runs the PipeWire main loop, so it never returns on success
+fn pipewire_capture(fd: OwnedFd, node_id: u32, frame: Arc<Mutex<SharedFrame>>) -> Result<()> {
+    pw::init();
+    let mainloop = pw::main_loop::MainLoop::new(None).context("Error in PipeWire main loop")?;
+    let context = pw::context::Context::new(&mainloop).context("Error in PipeWire context")?;
+    let core = context
+        .connect_fd(fd, None)
+        .context("Cannot connect to PipeWire remote")?;
+
+    let stream = pw::stream::Stream::new(
+        &core,
+        "sanzu",
+        pw::properties::properties! {
+            *pw::keys::MEDIA_TYPE => "Video",
+            *pw::keys::MEDIA_CATEGORY => "Capture",
+            *pw::keys::MEDIA_ROLE => "Screen",
+        },
+    )
+    .context("Error in PipeWire stream")?;
+
+    let _listener = stream
+        .add_local_listener_with_user_data(pw::spa::param::video::VideoInfoRaw::default())
+        .param_changed(|_, format, id, param| {
+            let param = match param {
+                Some(param) => param,
+                None => return,
+            };
+            if id != pw::spa::param::ParamType::Format.as_raw() {
+                return;
+            }
+            if let Err(err) = format.parse(param) {
+                error!("Cannot parse PipeWire format: {}", err);
+                return;
+            }
+            info!(
+                "PipeWire stream {:?} {}x{}",
+                format.format(),
+                format.size().width,
+                format.size().height
+            );
+        })
+        .process(move |stream, format| {
+            let mut buffer = match stream.dequeue_buffer() {
+                Some(buffer) => buffer,
+                None => return,
+            };
+            let datas = buffer.datas_mut();
+            if datas.is_empty() {
+                return;
+            }
+            let data = &mut datas[0];
+            let bytes_per_line = data.chunk().stride() as u32;
+            let size = data.chunk().size() as usize;
+            let (width, height) = (format.size().width, format.size().height);
+            if let Some(bytes) = data.data() {
+                if size < (bytes_per_line * height) as usize || size > bytes.len() {
+                    trace!("Incomplete PipeWire buffer");
+                    return;
+                }
+                let mut frame = frame.lock().unwrap();
+                frame.data.clear();
+                frame.data.extend_from_slice(&bytes[..size]);
+                frame.width = width;
+                frame.height = height;
+                frame.bytes_per_line = bytes_per_line;
+                frame.updated = true;
+            }
+        })
+        .register()
+        .context("Error in PipeWire stream listener")?;
+
+    // Our encoders take 32 bits bgr frames, same layout as x11 frames
+    let format = pw::spa::pod::object!(
+        pw::spa::utils::SpaTypes::ObjectParamFormat,
+        pw::spa::param::ParamType::EnumFormat,
+        pw::spa::pod::property!(
+            pw::spa::param::format::FormatProperties::MediaType,
+            Id,
+            pw::spa::param::format::MediaType::Video
+        ),
+        pw::spa::pod::property!(
+            pw::spa::param::format::FormatProperties::MediaSubtype,
+            Id,
+            pw::spa::param::format::MediaSubtype::Raw
+        ),
+        pw::spa::pod::property!(
+            pw::spa::param::format::FormatProperties::VideoFormat,
+            Choice,
+            Enum,
+            Id,
+            pw::spa::param::video::VideoFormat::BGRx,
+            pw::spa::param::video::VideoFormat::BGRx,
+            pw::spa::param::video::VideoFormat::BGRA
+        ),
+        pw::spa::pod::property!(
+            pw::spa::param::format::FormatProperties::VideoSize,
+            Choice,
+            Range,
+            Rectangle,
+            pw::spa::utils::Rectangle {
+                width: 1920,
+                height: 1080
+            },
+            pw::spa::utils::Rectangle {
+                width: 1,
+                height: 1
+            },
+            pw::spa::utils::Rectangle {
+                width: 8192,
+                height: 8192
+            }
+        ),
+    );
+    let values: Vec<u8> = pw::spa::pod::serialize::PodSerializer::serialize(
+        Cursor::new(Vec::new()),
+        &pw::spa::pod::Value::Object(format),
+    )
+    .map_err(|err| anyhow!("Cannot serialize PipeWire format: {:?}", err))?
+    .0
+    .into_inner();
+    let mut params = [pw::spa::pod::Pod::from_bytes(&values).context("Bad PipeWire format")?];
+
+    stream
+        .connect(
+            pw::spa::utils::Direction::Input,
+            Some(node_id),
+            pw::stream::StreamFlags::AUTOCONNECT | pw::stream::StreamFlags::MAP_BUFFERS,
+            &mut params,
+        )
+        .context("Cannot connect PipeWire stream")?;
+
+    mainloop.run();
+    Ok(())
+}
+
+pub struct ServerWayland {
+    portal: PortalSession,
+    frame: Arc<Mutex<SharedFrame>>,
+    /// Copy of the last received frame
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+    bytes_per_line: u32,
+    new_frame: bool,
+    pending_resize: Option<(u16, u16)>,
+    /// Last pointer motion was relative: buttons are sent in place
+    relative_pointer: bool,
+    pub img_count: i64,
+    pub max_stall_img: u32,
+    pub frozen_frames_count: u32,
+}
+
+/// Open a portal session and start receiving its frames
+pub fn init_wayland(
+    _arguments: &ServerArgsConfig,
+    config: &ConfigServer,
+) -> Result<Box<dyn Server>> {
+    let portal = PortalSession::open().context("Cannot open portal session")?;
+    let fd = portal.open_pipewire_remote()?;
+    let node_id = portal.node_id;
+
+    let frame: Arc<Mutex<SharedFrame>> = Arc::default();
+    let frame_pipewire = frame.clone();
+    thread::spawn(move || {
+        if let Err(err) = pipewire_capture(fd, node_id, frame_pipewire) {
+            error!("PipeWire capture error");
+            err.chain().for_each(|cause| error!(" - due to {}", cause));
+        }
+    });
+
+    // The frame size is only known once PipeWire has negotiated the stream
+    let time_start = Instant::now();
+    while !frame.lock().unwrap().updated {
+        if time_start.elapsed() > FIRST_FRAME_TIMEOUT {
+            return Err(anyhow!("No frame received from PipeWire"));
+        }
+        sleep(Duration::from_millis(10));
+    }
+
+    let mut server = ServerWayland {
+        portal,
+        frame,
+        data: vec![],
+        width: 0,
+        height: 0,
+        bytes_per_line: 0,
+        new_frame: false,
+        pending_resize: None,
+        relative_pointer: false,
+        img_count: 0,
+        max_stall_img: config.video.max_stall_img,
+        frozen_frames_count: 0,
+    };
+    server.grab_frame()?;
+    server.pending_resize = None;
+    info!("Wayland screen {}x{}", server.width, server.height);
+    Ok(Box::new(server))
+}
+
+impl Server for ServerWayland {
+    fn size(&self) -> (u16, u16) {
+        (self.width as u16, self.height as u16)
+    }
+
+    fn grab_frame(&mut self) -> Result<()> {
+        let mut frame = self.frame.lock().unwrap();
+        if !frame.updated {
+            return Ok(());
+        }
+        frame.updated = false;
+        if (frame.width, frame.height) != (self.width, self.height) {
+            self.pending_resize = Some((frame.width as u16, frame.height as u16));
+        }
+        std::mem::swap(&mut self.data, &mut frame.data);
+        self.width = frame.width;
+        self.height = frame.height;
+        self.bytes_per_line = frame.bytes_per_line;
+        self.new_frame = true;
+        Ok(())
+    }
+
+    fn handle_client_event(&mut self, msgs: tunnel::MessagesClient) -> Result<Vec<ServerEvent>> {
+        for msg in msgs.msgs.iter() {
+            match &msg.msg {
+                Some(tunnel::message_client::Msg::Move(event)) => {
+                    trace!("Mouse move {} {}", event.x, event.y);
+                    let x = event.x as f64 + event.x_frac as f64 / 65536.0;
+                    let y = event.y as f64 + event.y_frac as f64 / 65536.0;
+                    self.relative_pointer = false;
+                    if let Err(err) = self.portal.pointer_move(x, y) {
+                        error!("Cannot send mouse move event: {}", err);
+                    }
+                }
+                Some(tunnel::message_client::Msg::MoveRelative(event)) => {
+                    trace!("Mouse move relative {} {}", event.dx, event.dy);
+                    self.relative_pointer = true;
+                    if let Err(err) = self
+                        .portal
+                        .pointer_move_relative(event.dx as f64, event.dy as f64)
+                    {
+                        error!("Cannot send mouse move event: {}", err);
+                    }
+                }
+                Some(tunnel::message_client::Msg::Button(event)) => {
+                    trace!(
+                        "Mouse button {} {} {} {}",
+                        event.x,
+                        event.y,
+                        event.button,
+                        event.updown
+                    );
+                    let result = match self.relative_pointer {
+                        true => Ok(()),
+                        false => self.portal.pointer_move(event.x as f64, event.y as f64),
+                    };
+                    if let Err(err) =
+                        result.and_then(|_| self.portal.pointer_button(event.button, event.updown))
+                    {
+                        error!("Cannot send mouse button event: {}", err);
+                    }
+                }
+                Some(tunnel::message_client::Msg::Key(event)) => {
+                    trace!("Key {} {}", event.keycode, event.updown);
+                    if let Err(err) = self.portal.key(event.keycode, event.updown) {
+                        error!("Cannot send key event: {}", err);
+                    }
+                }
+                Some(tunnel::message_client::Msg::Display(_)) => {
+                    /* Reset frames count to resend the current image */
+                    self.frozen_frames_count = 0;
+                }
+                msg => {
+                    trace!("Unsupported wayland event {:?}", msg);
+                }
+            }
+        }
+        Ok(vec![])
+    }
+
+    fn poll_events(&mut self) -> Result<Vec<tunnel::MessageSrv>> {
+        self.img_count += 1;
+        if std::mem::take(&mut self.new_frame) {
+            self.frozen_frames_count = 0;
+        } else {
+            self.frozen_frames_count += 1;
+        }
+        Ok(vec![])
+    }
+
+    fn take_resize(&mut self) -> Option<(u16, u16)> {
+        self.pending_resize.take()
+    }
+
+    fn generate_encoded_img(
+        &mut self,
+        video_encoder: &mut Box<dyn Encoder>,
+    ) -> Result<(Vec<tunnel::MessageSrv>, Option<EncoderTimings>)> {
+        let mut events = vec![];
+        let mut timings = None;
+        if self.frozen_frames_count > self.max_stall_img {
+            trace!("Frozen img");
+        } else {
+            let (width, height, bytes_per_line) = (self.width, self.height, self.bytes_per_line);
+            trace!(
+                "Encode {}x{} bytes per line {}",
+                width,
+                height,
+                bytes_per_line
+            );
+            let (tiles, encoder_timings) = video_encoder
+                .encode_image_tiles(&self.data, width, height, bytes_per_line, self.img_count)
+                .context("Error in encode image")?;
+            timings = Some(encoder_timings);
+
+            for EncodedTile { data, tile } in tiles {
+                let img = match video_encoder.is_raw() {
+                    true => tunnel::message_srv::Msg::ImgRaw(tunnel::ImageRaw {
+                        data,
+                        width,
+                        height,
+                        bytes_per_line,
+                    }),
+                    false => tunnel::message_srv::Msg::ImgEncoded(tunnel::ImageEncoded {
+                        data,
+                        width,
+                        height,
+                        // Numbered by the main loop
+                        sequence: 0,
+                        tile,
+                    }),
+                };
+                events.push(tunnel::MessageSrv { msg: Some(img) });
+            }
+        }
+        Ok((events, timings))
+    }
+
+    fn change_resolution(
+        &mut self,
+        _config: &ConfigServer,
+        _width: u32,
+        _height: u32,
+    ) -> Result<()> {
+        Err(anyhow!(
+            "The resolution of a wayland session cannot be changed"
+        ))
+    }
+
+    fn activate_window(&self, _win_id: u32) -> Result<()> {
+        Ok(())
+    }
+
+    fn reload_config(&mut self, config: &ConfigServer, _restrict_clipboard: bool) {
+        self.max_stall_img = config.video.max_stall_img;
+    }
+}
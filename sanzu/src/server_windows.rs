@@ -1,13 +1,12 @@
 use crate::{
-    config::ConfigServer,
+    config::{ClipboardPolicy, ConfigServer},
     server_utils::Server,
     utils::{ServerArgsConfig, ServerEvent},
     utils_win,
-    video_encoder::{Encoder, EncoderTimings},
+    video_encoder::{EncodedTile, Encoder, EncoderTimings},
 };
 use anyhow::{Context, Result};
 
-use clipboard_win::{formats, get_clipboard, set_clipboard};
 use lock_keys::LockKeyWrapper;
 use sanzu_common::tunnel;
 
@@ -44,11 +43,11 @@ use winapi::{
             GetWindowTextLengthA, IsIconic, IsWindowVisible, PeekMessageA, RegisterClassExA,
             SendInput, SetClipboardViewer, TranslateMessage, GWL_EXSTYLE, INPUT, INPUT_KEYBOARD,
             INPUT_MOUSE, KEYEVENTF_EXTENDEDKEY, KEYEVENTF_KEYUP, KEYEVENTF_SCANCODE,
-            MOUSEEVENTF_ABSOLUTE, MOUSEEVENTF_LEFTDOWN, MOUSEEVENTF_LEFTUP, MOUSEEVENTF_MIDDLEDOWN,
-            MOUSEEVENTF_MIDDLEUP, MOUSEEVENTF_MOVE, MOUSEEVENTF_RIGHTDOWN, MOUSEEVENTF_RIGHTUP,
-            MOUSEEVENTF_WHEEL, MSG, PM_REMOVE, SM_CXSIZEFRAME, WINDOWINFO, WM_DRAWCLIPBOARD,
-            WM_KILLFOCUS, WM_QUIT, WM_SETFOCUS, WNDCLASSEXA, WS_CLIPCHILDREN, WS_CLIPSIBLINGS,
-            WS_DLGFRAME, WS_EX_TOOLWINDOW, WS_POPUP,
+            MOUSEEVENTF_ABSOLUTE, MOUSEEVENTF_HWHEEL, MOUSEEVENTF_LEFTDOWN, MOUSEEVENTF_LEFTUP,
+            MOUSEEVENTF_MIDDLEDOWN, MOUSEEVENTF_MIDDLEUP, MOUSEEVENTF_MOVE, MOUSEEVENTF_RIGHTDOWN,
+            MOUSEEVENTF_RIGHTUP, MOUSEEVENTF_WHEEL, MSG, PM_REMOVE, SM_CXSIZEFRAME, WINDOWINFO,
+            WM_DRAWCLIPBOARD, WM_KILLFOCUS, WM_QUIT, WM_SETFOCUS, WNDCLASSEXA, WS_CLIPCHILDREN,
+            WS_CLIPSIBLINGS, WS_DLGFRAME, WS_EX_TOOLWINDOW, WS_POPUP,
         },
     },
 };
@@ -75,6 +74,10 @@ pub struct ServerInfo {
     /// Screen height
     pub height: u16,
     pub event_receiver: Receiver<tunnel::MessageSrv>,
+    /// Last mouse move was relative: clicks are done in place
+    pub relative_mouse: bool,
+    /// Allowed clipboard directions
+    pub clipboard_policy: ClipboardPolicy,
 }
 
 #[derive(Debug)]
@@ -83,6 +86,8 @@ pub struct Area {
     pub position: (i16, i16),
     pub size: (u16, u16),
     pub mapped: bool,
+    /// Enumeration index, 0 being the top window
+    pub z_index: usize,
 }
 
 impl Eq for Area {}
@@ -173,10 +178,12 @@ extern "system" fn custom_wnd_proc(
 
         WM_DRAWCLIPBOARD => {
             info!("clipboard!");
-            if let Ok(data) = get_clipboard(formats::Unicode) {
-                info!("Send clipboard {}", data);
+            if let Some(data) = utils_win::get_clipboard_content() {
+                info!("Send clipboard");
 
-                let eventclipboard = tunnel::EventClipboard { data };
+                let eventclipboard = tunnel::EventClipboard {
+                    content: Some(data),
+                };
                 let msg_event = tunnel::MessageSrv {
                     msg: Some(tunnel::message_srv::Msg::Clipboard(eventclipboard)),
                 };
@@ -252,14 +259,22 @@ extern "system" fn enum_window_callback(hwnd: HWND, _l_param: LPARAM) -> BOOL {
     let win_h = win_h - border_thickness as i32 + 1;
     let win_x = win_x + (border_thickness as i32) - 1;
 
+    // Skip windows without surface, usually seen while they are created
+    if win_w <= 0 || win_h <= 0 {
+        return 1;
+    }
+
+    // EnumWindows lists the windows from the top of the Z order
+    let mut areas = AREAS.lock().unwrap();
     let area = Area {
         drawable: hwnd as usize,
         position: (win_x as i16, win_y as i16),
         size: (win_w as u16, win_h as u16),
         mapped: true,
+        z_index: areas.len(),
     };
 
-    AREAS.lock().unwrap().insert(hwnd as usize, area);
+    areas.insert(hwnd as usize, area);
 
     1
 }
@@ -286,6 +301,27 @@ fn set_mouse_position(server_info: &mut ServerInfo, event_x: u32, event_y: u32)
     };
 }
 
+fn move_mouse_relative(dx: i32, dy: i32) {
+    let mut input = INPUT {
+        type_: INPUT_MOUSE,
+        ..Default::default()
+    };
+    {
+        // Without MOUSEEVENTF_ABSOLUTE, dx/dy are relative to the current position
+        let mouse = unsafe { input.u.mi_mut() };
+        mouse.dx = dx;
+        mouse.dy = dy;
+        mouse.dwFlags = MOUSEEVENTF_MOVE;
+        mouse.time = 0;
+        mouse.dwExtraInfo = 0;
+    }
+    let mut inputs = vec![input];
+    let inputs_ptr = inputs.as_mut_ptr();
+    unsafe {
+        SendInput(1, inputs_ptr, std::mem::size_of::<INPUT>() as i32);
+    };
+}
+
 // https://github.com/apitrace/apitrace/blob/master/lib/guids/guids_entries.h
 
 fn d3d11_get_device_idxgidevice(device: &d3d11::ID3D11Device) -> Result<&dxgi::IDXGIDevice> {
@@ -753,7 +789,7 @@ pub fn init_d3d11() -> Result<()> {
 }
 
 pub fn init_win(
-    _arguments: &ServerArgsConfig,
+    arguments: &ServerArgsConfig,
     config: &ConfigServer,
     _server_size: Option<(u16, u16)>,
 ) -> Result<Box<dyn Server>> {
@@ -833,6 +869,8 @@ pub fn init_win(
         width: screen_width,
         height: screen_height,
         event_receiver,
+        relative_mouse: false,
+        clipboard_policy: config.clipboard_policy(arguments.restrict_clipboard),
     };
     Ok(Box::new(server))
 }
@@ -874,15 +912,23 @@ impl Server for ServerInfo {
             match &msg.msg {
                 Some(tunnel::message_client::Msg::Move(event)) => {
                     info!("Mouse move {} {}", event.x, event.y);
+                    self.relative_mouse = false;
                     set_mouse_position(self, event.x, event.y);
                 }
+                Some(tunnel::message_client::Msg::MoveRelative(event)) => {
+                    trace!("Mouse move relative {} {}", event.dx, event.dy);
+                    self.relative_mouse = true;
+                    move_mouse_relative(event.dx, event.dy);
+                }
                 Some(tunnel::message_client::Msg::Button(event)) => {
                     info!(
                         "Mouse button {} {} {} {}",
                         event.x, event.y, event.button, event.updown
                     );
                     // First mouve
-                    set_mouse_position(self, event.x, event.y);
+                    if !self.relative_mouse {
+                        set_mouse_position(self, event.x, event.y);
+                    }
                     // Then click
                     let mut input = INPUT {
                         type_: INPUT_MOUSE,
@@ -938,6 +984,26 @@ impl Server for ServerInfo {
                                 mouse.mouseData = -40i32 as u32;
                                 mouse.dwFlags |= MOUSEEVENTF_WHEEL
                             }
+                            (6, true) => {
+                                // wheel left
+                                mouse.mouseData = -40i32 as u32;
+                                mouse.dwFlags |= MOUSEEVENTF_HWHEEL
+                            }
+                            (6, false) => {
+                                // wheel left end
+                                mouse.mouseData = -40i32 as u32;
+                                mouse.dwFlags |= MOUSEEVENTF_HWHEEL
+                            }
+                            (7, true) => {
+                                // wheel right
+                                mouse.mouseData = 40;
+                                mouse.dwFlags |= MOUSEEVENTF_HWHEEL
+                            }
+                            (7, false) => {
+                                // wheel right end
+                                mouse.mouseData = 40;
+                                mouse.dwFlags |= MOUSEEVENTF_HWHEEL
+                            }
                             (a, b) => {
                                 warn!("unhandlerd {:?} {:?}", a, b);
                             }
@@ -988,9 +1054,11 @@ impl Server for ServerInfo {
                 }
                 Some(tunnel::message_client::Msg::Clipboard(event)) => {
                     info!("Clipboard retrieved from client");
-                    set_clipboard(formats::Unicode, event.data.clone())
-                        .map_err(|err| anyhow!("Err {:?}", err))
-                        .context("Cannot set clipboard")?;
+                    if !self.clipboard_policy.clipboard.to_server() {
+                        debug!("Clipboard blocked by policy");
+                    } else if let Some(content) = &event.content {
+                        utils_win::set_clipboard_content(content)?;
+                    }
                 }
 
                 Some(tunnel::message_client::Msg::Keylocks(event)) => {
@@ -1021,6 +1089,11 @@ impl Server for ServerInfo {
     fn poll_events(&mut self) -> Result<Vec<tunnel::MessageSrv>> {
         let mut events = vec![];
         while let Ok(event) = self.event_receiver.try_recv() {
+            if let Some(tunnel::message_srv::Msg::Clipboard(_)) = event.msg {
+                if !self.clipboard_policy.clipboard.to_client() {
+                    continue;
+                }
+            }
             events.push(event);
         }
 
@@ -1028,7 +1101,8 @@ impl Server for ServerInfo {
 
         let _ = unsafe { EnumWindows(Some(enum_window_callback), 0) };
 
-        for (index, area) in AREAS.lock().unwrap().iter() {
+        let areas = AREAS.lock().unwrap();
+        for (index, area) in areas.iter() {
             let area_new = tunnel::EventAreaUpdt {
                 id: *index as u32,
                 x: area.position.0 as i32,
@@ -1038,6 +1112,8 @@ impl Server for ServerInfo {
                 mapped: area.mapped,
                 is_app: true,
                 name: "".to_string(),
+                stacking: (areas.len() - 1 - area.z_index) as u32,
+                topmost: false,
             };
             let event_area_updt = tunnel::message_srv::Msg::AreaUpdt(area_new);
             let event_area_updt = tunnel::MessageSrv {
@@ -1059,29 +1135,38 @@ impl Server for ServerInfo {
             if let Some(ref data) = &self.img {
                 let (width, height) = (self.width as u32, self.height as u32);
                 let result = video_encoder
-                    .encode_image(data, width, height, width * 4, self.img_count)
+                    .encode_image_tiles(data, width, height, width * 4, self.img_count)
                     .context("Error in encode image")?;
 
-                let encoded = result.0;
+                let tiles = result.0;
                 timings = Some(result.1);
 
-                /* Prepare encoded image */
-                let img = if video_encoder.is_raw() {
-                    tunnel::message_srv::Msg::ImgRaw(tunnel::ImageRaw {
-                        data: encoded,
-                        width,
-                        height,
-                        bytes_per_line: width * 4,
-                    })
-                } else {
-                    tunnel::message_srv::Msg::ImgEncoded(tunnel::ImageEncoded {
-                        data: encoded,
-                        width,
-                        height,
-                    })
-                };
-                let msg_img = tunnel::MessageSrv { msg: Some(img) };
-                events.push(msg_img);
+                /* Prepare encoded images, one per tile */
+                for EncodedTile {
+                    data: encoded,
+                    tile,
+                } in tiles
+                {
+                    let img = if video_encoder.is_raw() {
+                        tunnel::message_srv::Msg::ImgRaw(tunnel::ImageRaw {
+                            data: encoded,
+                            width,
+                            height,
+                            bytes_per_line: width * 4,
+                        })
+                    } else {
+                        tunnel::message_srv::Msg::ImgEncoded(tunnel::ImageEncoded {
+                            data: encoded,
+                            width,
+                            height,
+                            // Numbered by the main loop
+                            sequence: 0,
+                            tile,
+                        })
+                    };
+                    let msg_img = tunnel::MessageSrv { msg: Some(img) };
+                    events.push(msg_img);
+                }
             }
         }
 
@@ -1101,4 +1186,9 @@ impl Server for ServerInfo {
     fn activate_window(&self, _win_id: u32) -> Result<()> {
         Ok(())
     }
+
+    fn reload_config(&mut self, config: &ConfigServer, restrict_clipboard: bool) {
+        self.max_stall_img = config.video.max_stall_img;
+        self.clipboard_policy = config.clipboard_policy(restrict_clipboard);
+    }
 }
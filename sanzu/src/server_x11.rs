@@ -1,10 +1,19 @@
+#[cfg(feature = "printfile")]
+use crate::print_file::{PrintChunker, PrintDocument};
+#[cfg(feature = "notify")]
+use crate::{config::ConfigNotifications, utils::sanitize_notification};
 use crate::{
-    config::ConfigServer,
-    server_utils::Server,
-    utils::ClipboardSelection,
-    utils::{get_xwd_data, ServerArgsConfig, ServerEvent},
+    config::{ClipboardPolicy, ConfigServer, ImgSourceFormat, Seamless},
+    server_utils::{Server, MIN_RESOLUTION},
+    utils::{
+        get_xwd_data, with_xft_dpi, ClipboardContent, ClipboardNotifier, ClipboardSelection,
+        ServerArgsConfig, ServerEvent, MAX_WINDOW_HEIGHT, MAX_WINDOW_WIDTH,
+    },
     utils_x11,
-    video_encoder::{Encoder, EncoderTimings},
+    video_encoder::{
+        damage_regions_of_interest, EncodedTile, Encoder, EncoderTimings, TIMING_MEMCPY,
+    },
+    virtual_display::VirtualDisplay,
 };
 use anyhow::{Context, Result};
 use byteorder::{ByteOrder, LittleEndian};
@@ -24,6 +33,7 @@ use std::{
     io::Write,
     ptr::null_mut,
     sync::{
+        atomic::{self, AtomicBool},
         mpsc::{channel, Receiver},
         Arc, Mutex,
     },
@@ -34,7 +44,7 @@ use std::{
 #[cfg(any(feature = "notify", feature = "printfile"))]
 use std::sync::mpsc::Sender;
 
-use utils_x11::{get_clipboard_events, listen_clipboard};
+use utils_x11::listen_clipboard;
 
 use x11_clipboard::Clipboard;
 
@@ -42,15 +52,17 @@ const PATH_PCI_DEVICES: &str = "/sys/bus/pci/devices/";
 
 use x11rb::{
     connection::{Connection, RequestConnection},
+    errors::{ConnectionError, ReplyError, ReplyOrIdError},
     protocol::{
         damage::ConnectionExt as ConnectionExtXDamage,
         randr::{self, ConnectionExt as _},
         shm::{self, ConnectionExt as ConnectionExtShm},
         xfixes::{self, ConnectionExt as _},
+        xinput::{self, ConnectionExt as _},
         xproto::ConnectionExt as _,
         xproto::*,
         xtest::ConnectionExt as ConnectionExtXTest,
-        Event,
+        ErrorKind, Event,
     },
     rust_connection::RustConnection,
     COPY_DEPTH_FROM_PARENT,
@@ -60,8 +72,14 @@ x11rb::atom_manager! {
     pub AtomCollection: AtomCollectionCookie {
         _NET_CLIENT_LIST,
         _NET_WM_STATE,
+        _NET_WM_STATE_HIDDEN,
+        _NET_WM_STATE_MAXIMIZED_VERT,
+        _NET_WM_STATE_MAXIMIZED_HORZ,
         _NET_WM_NAME,
+        _NET_WM_ICON,
         _NET_ACTIVE_WINDOW,
+        _NET_CLOSE_WINDOW,
+        WM_CHANGE_STATE,
     }
 }
 
@@ -76,6 +94,10 @@ pub struct Area {
     pub mapped: bool,
     pub is_app: bool,
     pub name: String,
+    /// Position in the root children, 0 being the bottom
+    pub stacking: u32,
+    /// Override redirect window (menu, tooltip, ...)
+    pub topmost: bool,
 }
 
 impl Ord for Area {
@@ -101,7 +123,15 @@ impl Ord for Area {
         if ret != Ordering::Equal {
             return ret;
         }
-        self.name.cmp(&other.name)
+        let ret = self.name.cmp(&other.name);
+        if ret != Ordering::Equal {
+            return ret;
+        }
+        let ret = self.stacking.cmp(&other.stacking);
+        if ret != Ordering::Equal {
+            return ret;
+        }
+        self.topmost.cmp(&other.topmost)
     }
 }
 
@@ -111,6 +141,272 @@ impl PartialOrd for Area {
     }
 }
 
+/// Default minimum width / height of an area sent to the client
+const DEFAULT_MIN_AREA_SIZE: u16 = 2;
+
+/// Decides which windows are sent as areas to the client
+#[derive(Debug, Clone, Copy)]
+pub struct AreaPolicy {
+    /// Minimum width / height of an area
+    pub min_size: u16,
+    /// Drop areas which are fully outside of the screen
+    pub cull_offscreen: bool,
+}
+
+impl AreaPolicy {
+    pub fn new(config: Option<&Seamless>) -> Self {
+        AreaPolicy {
+            min_size: config
+                .and_then(|config| config.min_area_size)
+                .unwrap_or(DEFAULT_MIN_AREA_SIZE),
+            cull_offscreen: config
+                .and_then(|config| config.cull_offscreen)
+                .unwrap_or(true),
+        }
+    }
+
+    /// Returns true if `area` must be sent to the client
+    ///
+    /// Empty areas (often seen while applications start) are never sent, even
+    /// with a null `min_size`
+    pub fn is_visible(&self, area: &Area, screen_size: (u16, u16)) -> bool {
+        let min_size = self.min_size.max(1);
+        if area.size.0 < min_size || area.size.1 < min_size {
+            return false;
+        }
+        if self.cull_offscreen {
+            let (x, y) = (area.position.0 as i32, area.position.1 as i32);
+            let (width, height) = (area.size.0 as i32, area.size.1 as i32);
+            if x + width <= 0
+                || y + height <= 0
+                || x >= screen_size.0 as i32
+                || y >= screen_size.1 as i32
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Generate area events for the client
+///
+/// Areas filtered out by `policy` are not sent. Areas previously sent (stored
+/// in `sent_areas`) which are no longer valid are deleted on the client side.
+fn area_events(
+    areas: &HashMap<usize, Area>,
+    policy: &AreaPolicy,
+    screen_size: (u16, u16),
+    sent_areas: &mut HashSet<usize>,
+) -> Vec<tunnel::MessageSrv> {
+    let mut events = vec![];
+    let mut visible_areas = HashSet::new();
+    for (index, area) in areas.iter() {
+        if !policy.is_visible(area, screen_size) {
+            trace!("skip area {:x} {:?}", area.drawable, area);
+            continue;
+        }
+        visible_areas.insert(*index);
+        trace!("area {:x} {:?} {}", area.drawable, area.is_app, area.name);
+        let area_new = tunnel::EventAreaUpdt {
+            id: *index as u32,
+            x: area.position.0 as i32,
+            y: area.position.1 as i32,
+            width: area.size.0 as u32,
+            height: area.size.1 as u32,
+            mapped: area.mapped,
+            is_app: area.is_app,
+            name: area.name.clone(),
+            stacking: area.stacking,
+            topmost: area.topmost,
+        };
+        let event_area_updt = tunnel::message_srv::Msg::AreaUpdt(area_new);
+        let event_area_updt = tunnel::MessageSrv {
+            msg: Some(event_area_updt),
+        };
+        events.push(event_area_updt);
+    }
+    for index in sent_areas.difference(&visible_areas) {
+        trace!("delete area {}", index);
+        let event_delete_area = tunnel::EventDeleteArea { id: *index as u32 };
+        events.push(tunnel::MessageSrv {
+            msg: Some(tunnel::message_srv::Msg::DeleteArea(event_delete_area)),
+        });
+    }
+    *sent_areas = visible_areas;
+    events
+}
+
+/// Largest window icon side sent to the client
+const MAX_ICON_SIZE: u32 = 256;
+
+/// Max length (in 32 bits units) of the `_NET_WM_ICON` property read
+const MAX_ICON_PROPERTY_LEN: u32 = 0x100000;
+
+/// Select, in the `_NET_WM_ICON` `values` (width, height, argb pixels, ...),
+/// the largest icon which fits in `max_size`x`max_size`
+fn select_icon(values: &[u32], max_size: u32) -> Option<(u32, u32, &[u32])> {
+    let mut best: Option<(u32, u32, &[u32])> = None;
+    let mut values = values;
+    while let [width, height, pixels @ ..] = values {
+        let len = *width as usize * *height as usize;
+        if len == 0 || len > pixels.len() {
+            // Truncated or malformed property
+            break;
+        }
+        let fits = *width <= max_size && *height <= max_size;
+        let larger = best.map_or(true, |(best_width, best_height, _)| {
+            len > best_width as usize * best_height as usize
+        });
+        if fits && larger {
+            best = Some((*width, *height, &pixels[..len]));
+        }
+        values = &pixels[len..];
+    }
+    best
+}
+
+/// Extend the damaged bounding box `region` with `area`, clipped to the screen
+///
+/// Regions are stored as (x0, y0, x1, y1), with exclusive x1 / y1.
+fn extend_damaged_region(
+    region: Option<(u16, u16, u16, u16)>,
+    area: &Rectangle,
+    screen_size: (u16, u16),
+) -> Option<(u16, u16, u16, u16)> {
+    let clip = |value: i32, max: u16| value.clamp(0, max as i32) as u16;
+    let x0 = clip(area.x as i32, screen_size.0);
+    let y0 = clip(area.y as i32, screen_size.1);
+    let x1 = clip(area.x as i32 + area.width as i32, screen_size.0);
+    let y1 = clip(area.y as i32 + area.height as i32, screen_size.1);
+    if x0 >= x1 || y0 >= y1 {
+        return region;
+    }
+    Some(match region {
+        None => (x0, y0, x1, y1),
+        Some((rx0, ry0, rx1, ry1)) => (rx0.min(x0), ry0.min(y0), rx1.max(x1), ry1.max(y1)),
+    })
+}
+
+/// Extract the `region` (x0, y0, x1, y1) sub image from the rgba `data`
+fn extract_region(
+    data: &[u8],
+    bytes_per_line: usize,
+    region: (u16, u16, u16, u16),
+) -> tunnel::ImageRawRegion {
+    let (x0, y0, x1, y1) = region;
+    let row_len = (x1 - x0) as usize * 4;
+    let mut out = Vec::with_capacity(row_len * (y1 - y0) as usize);
+    for row in y0..y1 {
+        let offset = row as usize * bytes_per_line + x0 as usize * 4;
+        out.extend_from_slice(&data[offset..offset + row_len]);
+    }
+    tunnel::ImageRawRegion {
+        x: x0 as u32,
+        y: y0 as u32,
+        width: (x1 - x0) as u32,
+        height: (y1 - y0) as u32,
+        data: out,
+    }
+}
+
+/// Damaged `area` of the root window, relative to the grabbed rectangle at
+/// `origin`, or None if it is outside of it
+fn grabbed_area(area: &Rectangle, origin: (i16, i16), size: (u16, u16)) -> Option<Rectangle> {
+    let x = area.x as i32 - origin.0 as i32;
+    let y = area.y as i32 - origin.1 as i32;
+    if x >= size.0 as i32
+        || y >= size.1 as i32
+        || x + area.width as i32 <= 0
+        || y + area.height as i32 <= 0
+    {
+        return None;
+    }
+    Some(Rectangle {
+        x: x as i16,
+        y: y as i16,
+        width: area.width,
+        height: area.height,
+    })
+}
+
+/// Active RandR output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputInfo {
+    pub name: String,
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+}
+
+/// Outputs driven by a crtc, in server order
+pub fn get_outputs<C: Connection>(conn: &C, root: Window) -> Result<Vec<OutputInfo>> {
+    let resources = randr::get_screen_resources_current(conn, root)
+        .context("Error in get_screen_resources_current")?
+        .reply()
+        .context("Error in get_screen_resources_current reply")?;
+    let mut outputs = vec![];
+    for output in resources.outputs.iter() {
+        let output_info = randr::get_output_info(conn, *output, resources.config_timestamp)
+            .context("Error in get_output_info")?
+            .reply()
+            .context("Error in get_output_info reply")?;
+        if output_info.crtc == 0 {
+            continue;
+        }
+        let crtc = randr::get_crtc_info(conn, output_info.crtc, resources.config_timestamp)
+            .context("Error in get_crtc_info")?
+            .reply()
+            .context("Error in get_crtc_info reply")?;
+        outputs.push(OutputInfo {
+            name: String::from_utf8_lossy(&output_info.name).into_owned(),
+            x: crtc.x,
+            y: crtc.y,
+            width: crtc.width,
+            height: crtc.height,
+        });
+    }
+    Ok(outputs)
+}
+
+/// Find the output `spec`, by name or by index
+fn find_output<'a>(outputs: &'a [OutputInfo], spec: &str) -> Option<&'a OutputInfo> {
+    outputs
+        .iter()
+        .find(|output| output.name == spec)
+        .or_else(|| {
+            spec.parse::<usize>()
+                .ok()
+                .and_then(|index| outputs.get(index))
+        })
+}
+
+/// Print the active outputs of the x11 screen
+pub fn print_outputs() -> Result<()> {
+    let (conn, screen_num) =
+        RustConnection::connect(None).context("Cannot connect to X11 display")?;
+    let root = conn.setup().roots[screen_num].root;
+    let outputs = get_outputs(&conn, root)?;
+    for (index, output) in outputs.iter().enumerate() {
+        println!(
+            "{}: {} {}x{}+{}+{}",
+            index, output.name, output.width, output.height, output.x, output.y
+        );
+    }
+    Ok(())
+}
+
+/// Position of `window` in the `root` window
+fn window_origin<C: Connection>(conn: &C, window: Window, root: Window) -> Result<(i16, i16)> {
+    let reply = conn
+        .translate_coordinates(window, root, 0, 0)
+        .context("Error in translate_coordinates")?
+        .reply()
+        .context("Error in translate_coordinates reply")?;
+    Ok((reply.dst_x, reply.dst_y))
+}
+
 fn get_window_children<C: Connection>(conn: &C, window: Window) -> Result<Vec<Window>> {
     // Find children
     let response = conn
@@ -151,35 +447,63 @@ fn get_windows_parents<C: Connection>(conn: &C, window: Window) -> Result<HashMa
     Ok(out)
 }
 
+/// Shared memory segment receiving the grabbed frames
+///
+/// The local mapping is released on drop, so the frame data cannot outlive
+/// it. The segment must be detached from the X server by its owner.
+#[derive(Debug)]
+pub struct ShmSegment {
+    /// x11 shmseg
+    pub shmseg: u32,
+    /// x11 shared memory raw pointer
+    addr: *const u8,
+    /// Segment size
+    size: usize,
+}
+
+impl ShmSegment {
+    /// Content of the segment, the last grabbed frame
+    pub fn data(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.addr, self.size) }
+    }
+}
+
+impl Drop for ShmSegment {
+    fn drop(&mut self) {
+        // The X server may be gone, only release the local mapping
+        if unsafe { shmdt(self.addr as *const std::ffi::c_void) } != 0 {
+            warn!("Cannot detach shared memory");
+        }
+    }
+}
+
 /// Holds information on the server graphics
 #[derive(Debug)]
 pub struct GrabInfo {
     /// x11 handle
     pub drawable: u32,
-    /// Screen size
-    pub size: usize,
-    /// x11 shmseg
-    pub shmseg: u32,
+    /// Position of the grabbed rectangle in the drawable (output origin)
+    pub origin: (i16, i16),
     pub width: u16,
     pub height: u16,
-    /// x11 shared memory raw pointer
-    pub addr: *const u8,
+    /// Frames of `width` x `height` pixels
+    pub shm: ShmSegment,
     /// address of the pci video export
     pub export_video_mmap: Option<MmapMut>,
     pub extern_img_source_mmap: Option<Mmap>,
+    /// pixel layout of the external image source frames
+    pub extern_img_format: ImgSourceFormat,
 }
 
-fn init_grab<C: Connection>(
+/// Attach a shared memory segment of `width` x `height` pixels, and grab
+/// `drawable` from `origin` in it
+fn init_shm<C: Connection>(
     conn: &C,
-    screen: &Screen,
-    export_video_pci: bool,
-    extern_img_source: Option<String>,
-    config: &ConfigServer,
+    drawable: Window,
+    origin: (i16, i16),
     width: u16,
     height: u16,
-) -> Result<GrabInfo> {
-    let drawable = screen.root;
-
+) -> Result<ShmSegment> {
     let size = width as usize * height as usize * 4;
     let shmseg = conn.generate_id().context("Error in x11rb generate_id")?;
     debug!("shmget ok");
@@ -199,8 +523,8 @@ fn init_grab<C: Connection>(
 
     conn.shm_get_image(
         drawable,
-        0,
-        0,
+        origin.0,
+        origin.1,
         width,
         height,
         0xFFFFFFFF,
@@ -219,11 +543,29 @@ fn init_grab<C: Connection>(
     if addr == ptr_bad {
         return Err(anyhow!("ShmAt Error"));
     }
+    let shm = ShmSegment { shmseg, addr, size };
 
     if unsafe { shmctl(shmid, libc::IPC_RMID, null_mut()) } != 0 {
         return Err(anyhow!("shmctl error"));
     }
 
+    Ok(shm)
+}
+
+/// Grab `drawable` (the root window or a captured window), from `origin`
+#[allow(clippy::too_many_arguments)]
+fn init_grab<C: Connection>(
+    conn: &C,
+    drawable: Window,
+    origin: (i16, i16),
+    export_video_pci: bool,
+    extern_img_source: Option<String>,
+    config: &ConfigServer,
+    width: u16,
+    height: u16,
+) -> Result<GrabInfo> {
+    let shm = init_shm(conn, drawable, origin, width, height).context("Error in init_shm")?;
+
     let export_video_mmap = match (export_video_pci, &config.export_video_pci) {
         (true, Some(ref export_video_pci)) => {
             // Video is exported wia pci shared mem
@@ -258,16 +600,25 @@ fn init_grab<C: Connection>(
 
     Ok(GrabInfo {
         drawable,
-        size,
-        shmseg,
+        origin,
         width,
         height,
-        addr,
+        shm,
         export_video_mmap,
         extern_img_source_mmap,
+        extern_img_format: config.video.extern_img_format.unwrap_or_default(),
     })
 }
 
+/// Find the application window (listed in `app_list`) among the children of
+/// the `window` frame
+fn get_app_window<C: Connection>(conn: &C, window: Window, app_list: &[Window]) -> Option<Window> {
+    let windows_children = get_window_children(conn, window).ok()?;
+    windows_children
+        .into_iter()
+        .find(|child| app_list.contains(child))
+}
+
 /// Creates Area linked to a `window`
 pub fn init_area<C: Connection>(
     conn: &C,
@@ -281,11 +632,13 @@ pub fn init_area<C: Connection>(
         .reply()
         .context("Error in get geometry reply")?;
     let mut mapped = false;
+    let mut topmost = false;
     if let Ok(reply) = conn.get_window_attributes(window) {
         if let Ok(attributes) = reply.reply() {
             if attributes.map_state == MapState::VIEWABLE {
                 mapped = true;
             }
+            topmost = attributes.override_redirect;
         }
     }
     let app_list = get_client_list(conn, root).context("Error in get_client_list")?;
@@ -293,23 +646,14 @@ pub fn init_area<C: Connection>(
     trace!("init area {:x}", window);
 
     let mut app_name = "".to_string();
-    let is_app = if let Ok(windows_children) = get_window_children(conn, window) {
-        let mut found = false;
-
-        for child in windows_children.iter() {
-            if app_list.contains(child) {
-                if let Ok(name) = get_window_name(conn, *child, atoms) {
-                    app_name = name;
-                }
-                trace!("child {:x}: {}", *child, app_name);
-                found = true;
-                break;
-            }
+    let app_window = get_app_window(conn, window, &app_list);
+    if let Some(child) = app_window {
+        if let Ok(name) = get_window_name(conn, child, atoms) {
+            app_name = name;
         }
-        found
-    } else {
-        false
-    };
+        trace!("child {:x}: {}", child, app_name);
+    }
+    let is_app = app_window.is_some();
 
     Ok(Area {
         drawable: window,
@@ -318,9 +662,24 @@ pub fn init_area<C: Connection>(
         mapped,
         is_app,
         name: app_name,
+        // Set by `update_stacking`
+        stacking: 0,
+        topmost,
     })
 }
 
+/// Update the areas stacking order from the root children order (bottom to
+/// top)
+fn update_stacking(server: &mut ServerX11) -> Result<()> {
+    let children = get_window_children(&server.conn, server.root)?;
+    for area in server.areas.values_mut() {
+        if let Some(position) = children.iter().position(|window| *window == area.drawable) {
+            area.stacking = position as u32;
+        }
+    }
+    Ok(())
+}
+
 /// Holds information on the server
 pub struct ServerX11 {
     /// x11 connection handle
@@ -343,8 +702,17 @@ pub struct ServerX11 {
     pub frozen_frames_count: u32,
     /// Current graphic has changed
     pub modified_img: bool,
+    /// Bounding box (x0, y0, x1, y1) of the damaged rectangles since last poll
+    pub damaged_region: Option<(u16, u16, u16, u16)>,
+    /// Quantizer offsets of the damaged and static areas, if the regions of
+    /// interest are enabled
+    pub roi_qoffsets: Option<(f32, f32)>,
     /// Monitored areas have changed
     pub modified_area: bool,
+    /// Areas filtering
+    pub area_policy: AreaPolicy,
+    /// Areas sent to the client
+    pub sent_areas: HashSet<usize>,
     #[cfg(feature = "notify")]
     /// dbus handle
     pub dbus_conn: Option<dbus::blocking::Connection>,
@@ -363,20 +731,82 @@ pub struct ServerX11 {
     pub height: u16,
     /// Current video mode index
     pub video_mode_index: usize,
-    /// Allow to send clipboard to client
-    pub restrict_clipboard: bool,
-    /// Clipboard event receiver
-    pub clipboard_event_receiver: Receiver<String>,
+    /// Number of custom video modes, rotated by the resolution changes
+    pub video_modes: usize,
+    /// Allowed clipboard directions, for each selection
+    pub clipboard_policy: ClipboardPolicy,
+    /// Clipboard events
+    pub clipboard_notifier: ClipboardNotifier,
     /// store clipboard events to skip
     pub skip_clipboard_primary: Arc<Mutex<u32>>,
     pub skip_clipboard_clipboard: Arc<Mutex<u32>>,
+    /// Enable the server to client clipboard listeners, if started
+    pub primary_to_client: Option<Arc<AtomicBool>>,
+    pub clipboard_to_client: Option<Arc<AtomicBool>>,
     pub extern_img_source: Option<String>,
     pub avoid_img_extraction: bool,
     #[cfg(feature = "printfile")]
     /// dbus printfile receiver
-    pub dbus_printfile_receiver: Receiver<PrintFile>,
+    pub dbus_printfile_receiver: Receiver<PrintDocument>,
+    #[cfg(feature = "printfile")]
+    /// Documents being sent to the client
+    pub print_chunker: PrintChunker,
     /// simulated dpi
     pub dpi: u32,
+    /// Window captured instead of the root window
+    pub capture_window: Option<Window>,
+    /// RandR output captured instead of the whole screen (name or index)
+    pub output: Option<String>,
+    /// Root position of the captured surface, added to the mouse events
+    pub input_origin: (i16, i16),
+    /// New size of the captured window, not yet reported by `take_resize`
+    pub pending_resize: Option<(u16, u16)>,
+    /// XInput2 master pointer, used for sub-pixel pointer motion
+    pub xi2_pointer: Option<xinput::DeviceId>,
+    /// X server spawned for the session, stopped with the server
+    pub virtual_display: Option<VirtualDisplay>,
+}
+
+/// Returns true if the error comes from the X11 connection itself (X server
+/// stopped or restarted), as opposed to a request error
+pub fn is_x11_connection_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.downcast_ref::<ConnectionError>().is_some()
+            || matches!(
+                cause.downcast_ref::<ReplyError>(),
+                Some(ReplyError::ConnectionError(_))
+            )
+            || matches!(
+                cause.downcast_ref::<ReplyOrIdError>(),
+                Some(ReplyOrIdError::ConnectionError(_))
+            )
+    })
+}
+
+/// XInput2 master pointer, if the extension is available
+fn get_xi2_pointer(conn: &RustConnection) -> Result<xinput::DeviceId> {
+    conn.extension_information(xinput::X11_EXTENSION_NAME)
+        .context("Error in get xinput extension")?
+        .context("XInput is not supported")?;
+    let version = conn
+        .xinput_xi_query_version(2, 0)
+        .context("Error in xi_query_version")?
+        .reply()
+        .context("Error in xi_query_version reply")?;
+    if version.major_version < 2 {
+        return Err(anyhow!("XInput2 is not supported"));
+    }
+    let pointer = conn
+        .xinput_xi_get_client_pointer(x11rb::NONE)
+        .context("Error in xi_get_client_pointer")?
+        .reply()
+        .context("Error in xi_get_client_pointer reply")?;
+    Ok(pointer.deviceid)
+}
+
+/// XInput2 fixed point coordinate from its integer and 1/65536 parts
+fn coordinate_to_fp1616(value: i16, frac: u32) -> xinput::Fp1616 {
+    ((value as i32) << 16) | (frac & 0xffff) as i32
 }
 
 fn get_property32<C: Connection>(
@@ -430,12 +860,111 @@ pub fn get_window_name<C: Connection>(
     Ok(value)
 }
 
+/// Retrieve the icon of `window` (see `select_icon`), as (width, height, bgra
+/// data)
+pub fn get_window_icon<C: Connection>(
+    conn: &C,
+    window: Window,
+    atoms: &AtomCollection,
+) -> Result<Option<(u32, u32, Vec<u8>)>> {
+    let ret = conn
+        .get_property(
+            false,
+            window,
+            atoms._NET_WM_ICON,
+            AtomEnum::CARDINAL,
+            0,
+            MAX_ICON_PROPERTY_LEN,
+        )
+        .context("Error in get_property")?
+        .reply()
+        .context("Error in get_property reply")?;
+    if ret.length == 0 {
+        return Ok(None);
+    }
+    let values: Vec<u32> = ret
+        .value32()
+        .context("Incorrect format in GetProperty reply")?
+        .collect();
+    // Pixels are native argb values, stored as little endian bgra bytes
+    Ok(
+        select_icon(&values, MAX_ICON_SIZE).map(|(width, height, pixels)| {
+            let data = pixels
+                .iter()
+                .flat_map(|pixel| pixel.to_le_bytes())
+                .collect();
+            (width, height, data)
+        }),
+    )
+}
+
+/// Generate icon events for the app areas which were not sent in
+/// `previous_areas`
+fn icon_events(server: &ServerX11, previous_areas: &HashSet<usize>) -> Vec<tunnel::MessageSrv> {
+    let mut events = vec![];
+    let mut app_list = None;
+    for index in server.sent_areas.difference(previous_areas) {
+        let area = match server.areas.get(index) {
+            Some(area) if area.is_app => area,
+            _ => continue,
+        };
+        if app_list.is_none() {
+            match get_client_list(&server.conn, server.root) {
+                Ok(list) => app_list = Some(list),
+                Err(err) => {
+                    warn!("Cannot get client list");
+                    err.chain().for_each(|cause| warn!(" - due to {}", cause));
+                    break;
+                }
+            }
+        }
+        let app_window = match app_list
+            .as_ref()
+            .and_then(|list| get_app_window(&server.conn, area.drawable, list))
+        {
+            Some(app_window) => app_window,
+            None => continue,
+        };
+        match get_window_icon(&server.conn, app_window, &server.atoms) {
+            Ok(Some((width, height, data))) => {
+                trace!("icon {:x} {}x{}", app_window, width, height);
+                let icon = tunnel::EventWinIcon {
+                    id: *index as u32,
+                    width,
+                    height,
+                    data,
+                };
+                events.push(tunnel::MessageSrv {
+                    msg: Some(tunnel::message_srv::Msg::WinIcon(icon)),
+                });
+            }
+            Ok(None) => {}
+            Err(err) => {
+                warn!("Cannot get icon of {:x}", app_window);
+                err.chain().for_each(|cause| warn!(" - due to {}", cause));
+            }
+        }
+    }
+    events
+}
+
 #[cfg(feature = "notify")]
 /// Retrieve server notifications (messages and images)
 ///
-/// For now, only filter events generated by Firefox.
-fn notification_extract_message(msg: dbus::Message) -> Vec<Notification> {
+/// Only the notifications of the applications allowed by `config` are kept,
+/// their texts are sanitized
+fn notification_extract_message(
+    msg: dbus::Message,
+    config: &ConfigNotifications,
+) -> Vec<Notification> {
     let mut notifications = vec![];
+    // The monitor receives all the session bus messages
+    if msg.msg_type() != dbus::MessageType::MethodCall
+        || msg.interface().as_deref() != Some("org.freedesktop.Notifications")
+        || msg.member().as_deref() != Some("Notify")
+    {
+        return notifications;
+    }
     let items = msg.get_items();
     let mut items_iter = items.iter();
     let app = items_iter.next();
@@ -448,13 +977,18 @@ fn notification_extract_message(msg: dbus::Message) -> Vec<Notification> {
         Some(dbus::arg::messageitem::MessageItem::Str(ref title)),
     ) = (app, title)
     {
-        if app == "Firefox" {
-            notifications.push(Notification::Title(title.to_owned()));
+        if !config.forwarded(app) {
+            debug!("Notification of {:?} not forwarded", app);
+        } else {
+            let max_length = config.max_length();
+            notifications.push(Notification::Title(sanitize_notification(
+                title, max_length,
+            )));
             for item in items_iter {
                 match item {
-                    dbus::arg::messageitem::MessageItem::Str(ref string) => {
-                        notifications.push(Notification::Message(string.to_owned()))
-                    }
+                    dbus::arg::messageitem::MessageItem::Str(ref string) => notifications.push(
+                        Notification::Message(sanitize_notification(string, max_length)),
+                    ),
                     dbus::arg::messageitem::MessageItem::Dict(ref dict) => {
                         for (key, value) in dict.iter() {
                             if let (
@@ -462,7 +996,12 @@ fn notification_extract_message(msg: dbus::Message) -> Vec<Notification> {
                                 dbus::arg::messageitem::MessageItem::Variant(ref item_value),
                             ) = (key, value)
                             {
-                                if item_name == "icon_data" {
+                                // "icon_data" is the deprecated name of
+                                // the image hint
+                                if matches!(
+                                    item_name.as_str(),
+                                    "image-data" | "image_data" | "icon_data"
+                                ) {
                                     if let dbus::arg::messageitem::MessageItem::Struct(
                                         ref icon_struct,
                                     ) = **item_value
@@ -555,7 +1094,10 @@ fn notification_extract_message(msg: dbus::Message) -> Vec<Notification> {
 
 #[cfg(feature = "notify")]
 /// Create a dbus event monitor
-fn connect_to_dbus(notif_sender: Sender<Notifications>) -> Result<dbus::blocking::Connection> {
+fn connect_to_dbus(
+    notif_sender: Sender<Notifications>,
+    config: ConfigNotifications,
+) -> Result<dbus::blocking::Connection> {
     let dbus_conn = dbus::blocking::Connection::new_session().context("D-Bus connection failed")?;
 
     let rule = dbus::message::MatchRule::new();
@@ -575,8 +1117,10 @@ fn connect_to_dbus(notif_sender: Sender<Notifications>) -> Result<dbus::blocking
     dbus_conn.start_receive(
         rule,
         Box::new(move |msg, _| {
-            // TODO XXX we may want to filter special caracters ?
-            let notifications = notification_extract_message(msg);
+            let notifications = notification_extract_message(msg, &config);
+            if notifications.is_empty() {
+                return true;
+            }
             let notifications = Notifications { notifications };
             notif_sender
                 .send(notifications)
@@ -589,17 +1133,22 @@ fn connect_to_dbus(notif_sender: Sender<Notifications>) -> Result<dbus::blocking
 
 #[cfg(feature = "printfile")]
 /// Receive Printfile call and forward it to the server object
-/// The request embeds the path of the file to print
-/// This path will be used as hint on the client side to find the file to print
+/// The request embeds the path of the file to print, which is read and sent
+/// to the client. Its name is used as hint for the client file name
 fn print_entry(
     _: &mut dbus_crossroads::Context,
     dbus_info: &mut DBusInfo,
     (path,): (String,),
 ) -> Result<(), dbus_crossroads::MethodErr> {
     debug!("Print {}", path);
+    let document = PrintDocument::read(&path).map_err(|err| {
+        error!("Cannot print {:?}", path);
+        err.chain().for_each(|cause| error!(" - due to {}", cause));
+        dbus_crossroads::MethodErr::failed(&err)
+    })?;
     dbus_info
         .sender
-        .send(PrintFile { path })
+        .send(document)
         .expect("Cannot send print file");
     Ok(())
 }
@@ -607,14 +1156,14 @@ fn print_entry(
 #[cfg(feature = "printfile")]
 /// Struct used in dbus method callback
 struct DBusInfo {
-    sender: Sender<PrintFile>,
+    sender: Sender<PrintDocument>,
 }
 
 #[cfg(feature = "printfile")]
 /// Create the dbus print interface
 /// Can be called with dbus-send:
 /// dbus-send --type=method_call --dest=com.sanzu.dbus /print com.sanzu.dbus.print_entry string:"test"
-fn connect_to_dbus_print(printfile_sender: Sender<PrintFile>) -> Result<()> {
+fn connect_to_dbus_print(printfile_sender: Sender<PrintDocument>) -> Result<()> {
     let c = dbus::blocking::Connection::new_session()?;
     c.request_name("com.sanzu.dbus", false, true, false)?;
 
@@ -685,26 +1234,40 @@ fn find_pci_shared_memory(searched_device: &str, searched_vendor: &str) -> Resul
     Err(anyhow!("Cannot find PCI shared memory"))
 }
 
-const VIDEO_NAME_1: &str = "video_mode_1";
-const VIDEO_NAME_2: &str = "video_mode_2";
-const VIDEO_NAMES: &[&str; 2] = &[VIDEO_NAME_1, VIDEO_NAME_2];
+/// Default number of custom video modes, rotated by the resolution changes
+const DEFAULT_VIDEO_MODES: usize = 2;
 
-fn del_custom_video_mode<C: Connection>(conn: &C, window: Window) -> Result<()> {
-    if utils_x11::get_video_mode(conn, window, VIDEO_NAME_1)
-        .context("Error in get video mode1")?
-        .is_some()
-    {
-        utils_x11::delete_video_mode_by_name(conn, window, VIDEO_NAME_1)
-            .context("Error in del video mode1")?;
+/// Name of the custom video mode `index`
+fn video_mode_name(index: usize) -> String {
+    format!("video_mode_{}", index + 1)
+}
+
+/// Delete the custom video modes of the pool. The one in use cannot be
+/// deleted: returns its index, if any
+fn del_custom_video_mode<C: Connection>(
+    conn: &C,
+    window: Window,
+    video_modes: usize,
+) -> Result<Option<usize>> {
+    for index in 0..video_modes {
+        let name = video_mode_name(index);
+        if utils_x11::get_video_mode(conn, window, &name)
+            .context(format!("Error in get video mode {name:?}"))?
+            .is_some()
+        {
+            utils_x11::delete_video_mode_by_name(conn, window, &name)
+                .context(format!("Error in del video mode {name:?}"))?;
+        }
     }
-    if utils_x11::get_video_mode(conn, window, VIDEO_NAME_2)
-        .context("Error in get video mode2")?
-        .is_some()
-    {
-        utils_x11::delete_video_mode_by_name(conn, window, VIDEO_NAME_2)
-            .context("Error in del video mode2")?;
+    for index in 0..video_modes {
+        if utils_x11::get_video_mode(conn, window, &video_mode_name(index))
+            .context("Error in get_video_mode")?
+            .is_some()
+        {
+            return Ok(Some(index));
+        }
     }
-    Ok(())
+    Ok(None)
 }
 
 /// Initialize x11rb server handler
@@ -713,13 +1276,21 @@ pub fn init_x11rb(
     config: &ConfigServer,
     server_size: Option<(u16, u16)>,
 ) -> Result<Box<dyn Server>> {
+    // Start a virtual X server if the display is unreachable
+    let virtual_display = match &config.virtual_display {
+        Some(virtual_config) if RustConnection::connect(None).is_err() => {
+            Some(VirtualDisplay::spawn(virtual_config).context("Cannot start virtual display")?)
+        }
+        _ => None,
+    };
+    let display_name = virtual_display.as_ref().map(|display| display.display());
     let start = Instant::now();
     let (conn, screen_num) = loop {
         if Instant::now() - start > Duration::new(2, 0) {
             break Err(anyhow!("Time out connecting to X11 display"));
         }
-        if let Ok((conn, screen_num)) = x11rb::rust_connection::RustConnection::connect(None)
-            .map_err(|err| {
+        if let Ok((conn, screen_num)) =
+            x11rb::rust_connection::RustConnection::connect(display_name).map_err(|err| {
                 warn!("Attempt to connect to X11 server failed: {}", err);
                 err
             })
@@ -768,10 +1339,13 @@ pub fn init_x11rb(
         .reply()
         .context("Error in damage version reply")?;
 
+    // Capture a single window if requested, else the whole screen
+    let drawable = arguments.capture_window.unwrap_or(screen.root);
+
     let damage = conn.generate_id().context("Error in generate_id")?;
     conn.damage_create(
         damage,
-        screen.root,
+        drawable,
         x11rb::protocol::damage::ReportLevel::RAW_RECTANGLES,
     )
     .context("Error in damage create")?
@@ -781,22 +1355,74 @@ pub fn init_x11rb(
     conn.damage_subtract(damage, 0u32, 0u32)
         .context("Error in damage substract")?;
 
-    let screen_width = screen.width_in_pixels;
-    let screen_height = screen.height_in_pixels;
+    let xi2_pointer = match get_xi2_pointer(&conn) {
+        Ok(deviceid) => Some(deviceid),
+        Err(err) => {
+            warn!("Sub-pixel pointer motion disabled");
+            err.chain().for_each(|cause| warn!(" - due to {}", cause));
+            None
+        }
+    };
+
+    if arguments.capture_window.is_some() && arguments.output.is_some() {
+        return Err(anyhow!("Cannot capture both a window and an output"));
+    }
 
-    let (width, height) = if let Some((width, height)) = server_size {
-        (width, height)
+    let (origin, input_origin, width, height) = if let Some(window) = arguments.capture_window {
+        // Follow the captured window size
+        let geometry = conn
+            .get_geometry(window)
+            .context("Error in get_geometry")?
+            .reply()
+            .context(format!("Cannot get captured window {window:#x} geometry"))?;
+        let prop = ChangeWindowAttributesAux::default().event_mask(EventMask::STRUCTURE_NOTIFY);
+        conn.change_window_attributes(window, &prop)
+            .context("Error in change_window_attributes")?
+            .check()
+            .context("Error in change_window_attributes check")?;
+        info!(
+            "Capture window {:#x} ({}x{})",
+            window, geometry.width, geometry.height
+        );
+        let input_origin = window_origin(&conn, window, screen.root)
+            .context("Cannot get captured window position")?;
+        ((0, 0), input_origin, geometry.width, geometry.height)
+    } else if let Some(ref spec) = arguments.output {
+        let outputs = get_outputs(&conn, screen.root).context("Cannot get outputs")?;
+        let output = find_output(&outputs, spec).context(format!(
+            "Unknown output {:?}, available: {:?}",
+            spec,
+            outputs
+                .iter()
+                .map(|output| output.name.as_str())
+                .collect::<Vec<_>>()
+        ))?;
+        info!("Capture output {:?}", output);
+        let origin = (output.x, output.y);
+        (origin, origin, output.width, output.height)
     } else {
-        (screen_width, screen_height)
-    };
+        let screen_width = screen.width_in_pixels;
+        let screen_height = screen.height_in_pixels;
+
+        let (width, height) = if let Some((width, height)) = server_size {
+            (width, height)
+        } else {
+            (screen_width, screen_height)
+        };
 
-    // Force the resolution to be less thant the server side
-    let width = width.min(screen_width);
-    let height = height.min(screen_height);
+        // Force the resolution to be less thant the server side
+        (
+            (0, 0),
+            (0, 0),
+            width.min(screen_width),
+            height.min(screen_height),
+        )
+    };
 
     let grabinfo = init_grab(
         &conn,
-        screen,
+        drawable,
+        origin,
         arguments.export_video_pci,
         arguments.extern_img_source.clone(),
         config,
@@ -836,11 +1462,10 @@ pub fn init_x11rb(
             continue;
         }
         let area = init_area(&conn, root, target_window, &atoms).context("Error in init_area")?;
-        if area.size.0 > 1 && area.size.1 > 1 {
-            known_windows.insert(area.drawable);
-            areas.insert(index, area);
-            index += 1;
-        }
+        // Small windows are kept, but filtered out by the area policy
+        known_windows.insert(area.drawable);
+        areas.insert(index, area);
+        index += 1;
     }
 
     /* Add root's chidren windows */
@@ -880,7 +1505,8 @@ pub fn init_x11rb(
     #[cfg(feature = "notify")]
     let (dbus_conn, dbus_notif_receiver) = {
         let (dbus_notif_sender, dbus_notif_receiver) = channel();
-        let dbus_conn = match connect_to_dbus(dbus_notif_sender) {
+        let notifications_config = config.notifications.clone().unwrap_or_default();
+        let dbus_conn = match connect_to_dbus(dbus_notif_sender, notifications_config) {
             Err(err) => {
                 err.context("Cannot open dbus")
                     .chain()
@@ -910,16 +1536,19 @@ pub fn init_x11rb(
     let width = grabinfo.width;
     let height = grabinfo.height;
 
-    del_custom_video_mode(&conn, window_info).context("Error in del_custom_video_mode")?;
+    let video_modes = config
+        .video
+        .video_modes
+        .unwrap_or(DEFAULT_VIDEO_MODES)
+        .max(DEFAULT_VIDEO_MODES);
     // If we are already stucked in custom mode, update index accordingly
-    let video_mode_index = usize::from(
-        utils_x11::get_video_mode(&conn, window_info, VIDEO_NAME_2)
-            .context("Error in get_video_mode")?
-            .is_some(),
-    );
+    let video_mode_index = del_custom_video_mode(&conn, window_info, video_modes)
+        .context("Error in del_custom_video_mode")?
+        .unwrap_or(0);
 
-    let (selection_sender_primary, clipboard_event_receiver) = channel();
-    let selection_sender_clipboard = selection_sender_primary.clone();
+    let clipboard_notifier = ClipboardNotifier::default();
+    let clipboard_notifier_primary = clipboard_notifier.clone();
+    let clipboard_notifier_clipboard = clipboard_notifier.clone();
 
     let skip_clipboard_primary = Arc::new(Mutex::new(0));
     let skip_clipboard_clipboard = Arc::new(Mutex::new(0));
@@ -927,22 +1556,36 @@ pub fn init_x11rb(
     let skip_clipboard_primary_thread = skip_clipboard_primary.clone();
     let skip_clipboard_clipboard_thread = skip_clipboard_clipboard.clone();
 
-    if !arguments.restrict_clipboard {
+    let clipboard_policy = config.clipboard_policy(arguments.restrict_clipboard);
+    // Listeners are only started for the directions allowed at startup. A
+    // reloaded policy pauses them.
+    let primary_to_client = clipboard_policy
+        .primary
+        .to_client()
+        .then(|| Arc::new(AtomicBool::new(true)));
+    let clipboard_to_client = clipboard_policy
+        .clipboard
+        .to_client()
+        .then(|| Arc::new(AtomicBool::new(true)));
+    if let Some(enabled) = primary_to_client.clone() {
         // Listen "primary" clipboard events
         thread::spawn(move || {
             listen_clipboard(
                 ClipboardSelection::Primary,
-                selection_sender_primary,
+                clipboard_notifier_primary,
                 skip_clipboard_primary_thread,
+                enabled,
             );
         });
-
+    }
+    if let Some(enabled) = clipboard_to_client.clone() {
         // Listen "clipboard" clipboard events
         thread::spawn(move || {
             listen_clipboard(
                 ClipboardSelection::Clipboard,
-                selection_sender_clipboard,
+                clipboard_notifier_clipboard,
                 skip_clipboard_clipboard_thread,
+                enabled,
             );
         });
     }
@@ -959,7 +1602,11 @@ pub fn init_x11rb(
         screen_num,
         frozen_frames_count: 0,
         modified_img: true,
+        damaged_region: None,
+        roi_qoffsets: config.video.roi.as_ref().map(|roi| roi.qoffsets()),
         modified_area: true,
+        area_policy: AreaPolicy::new(config.seamless.as_ref()),
+        sent_areas: HashSet::new(),
         #[cfg(feature = "notify")]
         dbus_conn,
         #[cfg(feature = "notify")]
@@ -970,15 +1617,26 @@ pub fn init_x11rb(
         width,
         height,
         video_mode_index,
-        restrict_clipboard: arguments.restrict_clipboard,
-        clipboard_event_receiver,
+        video_modes,
+        clipboard_policy,
+        clipboard_notifier,
         skip_clipboard_primary,
         skip_clipboard_clipboard,
+        primary_to_client,
+        clipboard_to_client,
         extern_img_source,
         avoid_img_extraction: arguments.avoid_img_extraction,
         #[cfg(feature = "printfile")]
         dbus_printfile_receiver,
+        #[cfg(feature = "printfile")]
+        print_chunker: PrintChunker::default(),
         dpi: arguments.dpi,
+        capture_window: arguments.capture_window,
+        output: arguments.output.clone(),
+        input_origin,
+        pending_resize: None,
+        xi2_pointer,
+        virtual_display,
     };
 
     Ok(Box::new(server))
@@ -998,14 +1656,145 @@ pub struct Notifications {
     pub notifications: Vec<Notification>,
 }
 
-#[cfg(feature = "printfile")]
-/// Holds printfile messages
-#[derive(Debug)]
-pub struct PrintFile {
-    path: String,
-}
+/// EWMH _NET_WM_STATE actions
+const NET_WM_STATE_REMOVE: u32 = 0;
+const NET_WM_STATE_ADD: u32 = 1;
+/// EWMH source indication of requests coming from pagers / taskbars
+const EWMH_SOURCE_PAGER: u32 = 2;
+/// ICCCM iconic state
+const ICONIC_STATE: u32 = 3;
 
 /// Reparent known windows togethers, delete son
+impl ServerX11 {
+    /// Grab the captured window with its new size
+    fn resize_capture(&mut self, width: u16, height: u16) -> Result<()> {
+        info!("Captured window resized to {}x{}", width, height);
+        // The current segment is kept if the new one cannot be created
+        let shm = init_shm(
+            &self.conn,
+            self.grabinfo.drawable,
+            self.grabinfo.origin,
+            width,
+            height,
+        )
+        .context("Error in init_shm")?;
+        let old_shm = std::mem::replace(&mut self.grabinfo.shm, shm);
+        self.grabinfo.width = width;
+        self.grabinfo.height = height;
+        self.conn
+            .shm_detach(old_shm.shmseg)
+            .context("Error in shm_detach")?;
+        self.width = width;
+        self.height = height;
+        self.pending_resize = Some((width, height));
+        // The whole window must be sent again
+        self.modified_img = true;
+        self.damaged_region = None;
+        Ok(())
+    }
+
+    /// Damaged region to send instead of the full frame, if any
+    ///
+    /// Only raw frames grabbed from the x11 shm can be partially updated:
+    /// video codecs, external sources and video exports need full frames.
+    fn partial_damaged_region(&self, video_encoder: &dyn Encoder) -> Option<(u16, u16, u16, u16)> {
+        if !video_encoder.is_raw()
+            || self.grabinfo.export_video_mmap.is_some()
+            || self.grabinfo.extern_img_source_mmap.is_some()
+        {
+            return None;
+        }
+        self.damaged_region
+            .filter(|region| *region != (0, 0, self.grabinfo.width, self.grabinfo.height))
+    }
+
+    /// Send a `type_` client message about `window` to the window manager
+    fn send_wm_message(&self, window: Window, type_: Atom, data: [u32; 5]) -> Result<()> {
+        let event = ClientMessageEvent::new(32, window, type_, data);
+        self.conn
+            .send_event(
+                false,
+                self.root,
+                EventMask::SUBSTRUCTURE_NOTIFY | EventMask::SUBSTRUCTURE_REDIRECT,
+                event,
+            )
+            .context("Error in send_event")?;
+        self.conn.flush().context("Error in x11rb flush")?;
+        Ok(())
+    }
+
+    /// Minimize / maximize / restore / close the app window of area `win_id`
+    fn control_window(&self, win_id: u32, action: tunnel::WinControl) -> Result<()> {
+        let area = self
+            .areas
+            .get(&(win_id as usize))
+            .context("Unknown window")?;
+        // Only app windows are handled by the window manager
+        if !area.is_app {
+            return Err(anyhow!("Window {} is not an application", win_id));
+        }
+        let app_list =
+            get_client_list(&self.conn, self.root).context("Error in get_client_list")?;
+        let window = get_app_window(&self.conn, area.drawable, &app_list)
+            .context("Cannot find application window")?;
+        let maximized = [
+            self.atoms._NET_WM_STATE_MAXIMIZED_VERT,
+            self.atoms._NET_WM_STATE_MAXIMIZED_HORZ,
+        ];
+        match action {
+            tunnel::WinControl::Unspecified => Ok(()),
+            tunnel::WinControl::Minimize => {
+                // Window managers ignore _NET_WM_STATE_HIDDEN requests, the
+                // ICCCM way is used instead
+                self.send_wm_message(
+                    window,
+                    self.atoms.WM_CHANGE_STATE,
+                    [ICONIC_STATE, 0, 0, 0, 0],
+                )
+            }
+            tunnel::WinControl::Maximize => self.send_wm_message(
+                window,
+                self.atoms._NET_WM_STATE,
+                [
+                    NET_WM_STATE_ADD,
+                    maximized[0],
+                    maximized[1],
+                    EWMH_SOURCE_PAGER,
+                    0,
+                ],
+            ),
+            tunnel::WinControl::Restore => {
+                self.send_wm_message(
+                    window,
+                    self.atoms._NET_WM_STATE,
+                    [
+                        NET_WM_STATE_REMOVE,
+                        maximized[0],
+                        maximized[1],
+                        EWMH_SOURCE_PAGER,
+                        0,
+                    ],
+                )?;
+                let state = get_window_state(&self.conn, window).unwrap_or_default();
+                if state.contains(&self.atoms._NET_WM_STATE_HIDDEN) {
+                    // Activating a minimized window maps it back
+                    self.send_wm_message(
+                        window,
+                        self.atoms._NET_ACTIVE_WINDOW,
+                        [EWMH_SOURCE_PAGER, 0, 0, 0, 0],
+                    )?;
+                }
+                Ok(())
+            }
+            tunnel::WinControl::Close => self.send_wm_message(
+                window,
+                self.atoms._NET_CLOSE_WINDOW,
+                [0, EWMH_SOURCE_PAGER, 0, 0, 0],
+            ),
+        }
+    }
+}
+
 fn reparent_window(server: &mut ServerX11, window: Window, parent: Window) -> bool {
     trace!("Reparent");
     let mut found_window = None;
@@ -1111,13 +1900,17 @@ fn update_area(
     false
 }
 
-pub fn set_clipboard(server: &mut ServerX11, data: &str) -> Result<()> {
-    /* Set *both* clipboards (primary and clipboard) */
-    *server.skip_clipboard_clipboard.lock().unwrap() += 1;
-    utils_x11::set_clipboard(&server.clipboard, 0, data).context("Error in set_clipboard")?;
+pub fn set_clipboard(server: &mut ServerX11, data: &ClipboardContent) -> Result<()> {
+    /* Set *both* clipboards (primary and clipboard), if allowed by the policy */
+    if server.clipboard_policy.clipboard.to_server() {
+        *server.skip_clipboard_clipboard.lock().unwrap() += 1;
+        utils_x11::set_clipboard(&server.clipboard, 0, data).context("Error in set_clipboard")?;
+    }
 
-    *server.skip_clipboard_primary.lock().unwrap() += 1;
-    utils_x11::set_clipboard(&server.clipboard, 1, data).context("Error in set_clipboard")?;
+    if server.clipboard_policy.primary.to_server() {
+        *server.skip_clipboard_primary.lock().unwrap() += 1;
+        utils_x11::set_clipboard(&server.clipboard, 1, data).context("Error in set_clipboard")?;
+    }
 
     server.conn.flush().context("Error in x11rb flush")?;
 
@@ -1138,39 +1931,86 @@ impl Server for ServerX11 {
 
     fn grab_frame(&mut self) -> Result<()> {
         if self.extern_img_source.is_none() {
-            self.conn
+            let result = self
+                .conn
                 .shm_get_image(
                     self.grabinfo.drawable,
-                    0,
-                    0,
+                    self.grabinfo.origin.0,
+                    self.grabinfo.origin.1,
                     self.grabinfo.width,
                     self.grabinfo.height,
                     0xFFFFFFFF,
                     ImageFormat::Z_PIXMAP.into(),
-                    self.grabinfo.shmseg,
+                    self.grabinfo.shm.shmseg,
                     0,
                 )
                 .context("Error in shm_get_image")?
-                .reply()
-                .context("Error in shm_get_image reply")?;
+                .reply();
+            match result {
+                // An unmapped captured window cannot be grabbed, keep its last frame
+                Err(ReplyError::X11Error(ref err))
+                    if self.capture_window.is_some() && err.error_kind == ErrorKind::Match =>
+                {
+                    trace!("Captured window not viewable");
+                }
+                result => {
+                    result.context("Error in shm_get_image reply")?;
+                }
+            }
         }
 
         Ok(())
     }
 
+    fn take_resize(&mut self) -> Option<(u16, u16)> {
+        self.pending_resize.take()
+    }
+
+    fn is_connection_lost(&self, err: &anyhow::Error) -> bool {
+        is_x11_connection_error(err)
+    }
+
     fn handle_client_event(&mut self, msgs: tunnel::MessagesClient) -> Result<Vec<ServerEvent>> {
         let mut server_events = vec![];
         for msg in msgs.msgs.iter() {
             match &msg.msg {
                 Some(tunnel::message_client::Msg::Move(event)) => {
                     trace!("Mouse move {} {}", event.x, event.y);
+                    let (x, y) = (
+                        event.x as i16 + self.input_origin.0,
+                        event.y as i16 + self.input_origin.1,
+                    );
+                    let result = match self.xi2_pointer {
+                        // Xtest only moves to integer positions
+                        Some(deviceid) if event.x_frac != 0 || event.y_frac != 0 => {
+                            self.conn.xinput_xi_warp_pointer(
+                                x11rb::NONE,
+                                self.root,
+                                0,
+                                0,
+                                0,
+                                0,
+                                coordinate_to_fp1616(x, event.x_frac),
+                                coordinate_to_fp1616(y, event.y_frac),
+                                deviceid,
+                            )
+                        }
+                        _ => self.conn.xtest_fake_input(6, 0, 0, self.root, x, y, 0),
+                    };
+                    if let Err(err) = result {
+                        error!("Cannot send mouse move event: {}", err);
+                    };
+                }
+                Some(tunnel::message_client::Msg::MoveRelative(event)) => {
+                    trace!("Mouse move relative {} {}", event.dx, event.dy);
+                    // Detail 1 makes xtest move relative to the current position
                     if let Err(err) = self.conn.xtest_fake_input(
                         6,
+                        1,
                         0,
-                        0,
-                        self.root,
-                        event.x as i16,
-                        event.y as i16,
+                        x11rb::NONE,
+                        event.dx.clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+                        event.dy.clamp(i16::MIN as i32, i16::MAX as i32) as i16,
                         0,
                     ) {
                         error!("Cannot send mouse move event: {}", err);
@@ -1193,8 +2033,8 @@ impl Server for ServerX11 {
                         event.button as u8,
                         0,
                         self.root,
-                        event.x as i16,
-                        event.y as i16,
+                        event.x as i16 + self.input_origin.0,
+                        event.y as i16 + self.input_origin.1,
                         0,
                     ) {
                         error!("Cannot send mouse button event: {}", err);
@@ -1220,8 +2060,10 @@ impl Server for ServerX11 {
                 }
                 Some(tunnel::message_client::Msg::Clipboard(event)) => {
                     info!("Clipboard retrieved from client");
-                    if set_clipboard(self, &event.data).is_err() {
-                        error!("Cannot set clipboard");
+                    if let Some(content) = &event.content {
+                        if set_clipboard(self, content).is_err() {
+                            error!("Cannot set clipboard");
+                        }
                     }
                 }
                 Some(tunnel::message_client::Msg::Display(event)) => {
@@ -1237,6 +2079,14 @@ impl Server for ServerX11 {
                     }
                 }
 
+                Some(tunnel::message_client::Msg::WinControl(event)) => {
+                    debug!("Window control {:?}", event);
+                    if let Err(err) = self.control_window(event.id, event.action()) {
+                        error!("Cannot control window");
+                        err.chain().for_each(|cause| error!(" - due to {}", cause));
+                    }
+                }
+
                 Some(tunnel::message_client::Msg::Keylocks(event)) => {
                     info!("keyboard state {:?}", event);
                     let caps_lock = bool_to_key_state(event.caps_lock);
@@ -1264,6 +2114,7 @@ impl Server for ServerX11 {
     fn poll_events(&mut self) -> Result<Vec<tunnel::MessageSrv>> {
         self.img_count += 1;
         self.modified_img = false;
+        self.damaged_region = None;
         self.modified_area = false;
         let mut events = vec![];
 
@@ -1306,7 +2157,19 @@ impl Server for ServerX11 {
                 }
                 Event::DamageNotify(event) => {
                     trace!("Damage: {:?}", event);
-                    self.modified_img = true;
+                    // Ignore damages outside of the captured output
+                    if let Some(area) = grabbed_area(
+                        &event.area,
+                        self.grabinfo.origin,
+                        (self.grabinfo.width, self.grabinfo.height),
+                    ) {
+                        self.modified_img = true;
+                        self.damaged_region = extend_damaged_region(
+                            self.damaged_region,
+                            &area,
+                            (self.grabinfo.width, self.grabinfo.height),
+                        );
+                    }
                 }
                 Event::NoExposure(_event) => {}
 
@@ -1352,6 +2215,9 @@ impl Server for ServerX11 {
                 }
                 Event::DestroyNotify(event) => {
                     trace!("{:?}", event);
+                    if Some(event.window) == self.capture_window {
+                        return Err(anyhow!("Captured window {:#x} destroyed", event.window));
+                    }
                     if destroy_area(self, event.window) {
                         self.modified_area = true;
                     }
@@ -1364,6 +2230,17 @@ impl Server for ServerX11 {
                 }
                 Event::ConfigureNotify(event) => {
                     trace!("{:?}", event);
+                    if Some(event.window) == self.capture_window
+                        && (event.width, event.height)
+                            != (self.grabinfo.width, self.grabinfo.height)
+                    {
+                        self.resize_capture(event.width, event.height)
+                            .context("Cannot follow captured window size")?;
+                    }
+                    if Some(event.window) == self.capture_window {
+                        self.input_origin = window_origin(&self.conn, event.window, self.root)
+                            .context("Cannot get captured window position")?;
+                    }
                     if update_area(
                         self,
                         event.window,
@@ -1375,26 +2252,29 @@ impl Server for ServerX11 {
                         self.modified_area = true;
                     }
                 }
+                Event::CirculateNotify(event) => {
+                    trace!("{:?}", event);
+                    self.modified_area = true;
+                }
                 Event::ClientMessage(event) => {
                     trace!("{:?}", event);
                     self.modified_area = true;
                 }
-                Event::Error(_event) => {}
+                Event::Error(event) => {
+                    trace!("{:?}", event);
+                    // Drop area linked to an invalid window
+                    if matches!(event.error_kind, ErrorKind::Window | ErrorKind::Drawable)
+                        && destroy_area(self, event.bad_value)
+                    {
+                        self.modified_area = true;
+                    }
+                }
                 _ => {
                     warn!("Unknown event {:?}", event);
                 }
             }
         }
 
-        /* Get clipboard events */
-        if let Some(data) = get_clipboard_events(&self.clipboard_event_receiver) {
-            let eventclipboard = tunnel::EventClipboard { data };
-            let clipboard = tunnel::MessageSrv {
-                msg: Some(tunnel::message_srv::Msg::Clipboard(eventclipboard)),
-            };
-            events.push(clipboard);
-        }
-
         if self.modified_img {
             self.frozen_frames_count = 0;
         } else {
@@ -1402,33 +2282,33 @@ impl Server for ServerX11 {
         }
         /* Push areas infos */
         trace!("push areas");
-        for (index, area) in self.areas.iter() {
-            trace!("area {:x} {:?} {}", area.drawable, area.is_app, area.name);
-            let area_new = tunnel::EventAreaUpdt {
-                id: *index as u32,
-                x: area.position.0 as i32,
-                y: area.position.1 as i32,
-                width: area.size.0 as u32,
-                height: area.size.1 as u32,
-                mapped: area.mapped,
-                is_app: area.is_app,
-                name: area.name.clone(),
-            };
-            let event_area_updt = tunnel::message_srv::Msg::AreaUpdt(area_new);
-            let event_area_updt = tunnel::MessageSrv {
-                msg: Some(event_area_updt),
-            };
-            events.push(event_area_updt);
+        // Windows are restacked by ConfigureNotify / CirculateNotify events
+        if self.modified_area {
+            update_stacking(self).context("Cannot get windows stacking order")?;
         }
+        let previous_areas = self.sent_areas.clone();
+        events.append(&mut area_events(
+            &self.areas,
+            &self.area_policy,
+            (self.grabinfo.width, self.grabinfo.height),
+            &mut self.sent_areas,
+        ));
+        events.append(&mut icon_events(self, &previous_areas));
 
         // Get print file events
         #[cfg(feature = "printfile")]
         {
-            while let Ok(printfile) = self.dbus_printfile_receiver.try_recv() {
-                info!("Received printfile {:?}", printfile);
-                let print = tunnel::EventPrintFile {
-                    path: printfile.path,
-                };
+            while let Ok(document) = self.dbus_printfile_receiver.try_recv() {
+                info!(
+                    "Received printfile {:?} ({}, {} bytes)",
+                    document.path,
+                    document.content_type,
+                    document.data.len()
+                );
+                self.print_chunker.push(document);
+            }
+            // One chunk per frame
+            if let Some(print) = self.print_chunker.next_chunk() {
                 let event_printfile = tunnel::MessageSrv {
                     msg: Some(tunnel::message_srv::Msg::Printfile(print)),
                 };
@@ -1503,24 +2383,60 @@ impl Server for ServerX11 {
             });
             let msg_img = tunnel::MessageSrv { msg: Some(img) };
             events.push(msg_img);
+        } else if let Some(region) = self.partial_damaged_region(video_encoder.as_ref()) {
+            // Raw frames only need the damaged part of the screen
+            trace!("Grab region from x11 {:?}", region);
+            let time_start = Instant::now();
+            let data = self.grabinfo.shm.data();
+            let img = extract_region(data, width as usize * 4, region);
+            timings = Some(EncoderTimings {
+                times: vec![("region", Instant::now() - time_start)],
+                frame: None,
+            });
+            let img = tunnel::message_srv::Msg::ImgRawRegion(img);
+            let msg_img = tunnel::MessageSrv { msg: Some(img) };
+            events.push(msg_img);
         } else {
-            let (data, width, height, bytes_per_line) = if let Some(ref extern_img_source_mmap) =
-                self.grabinfo.extern_img_source_mmap
-            {
-                // Grab frame from external xwd image
-                let (data, _width, _height, bytes_per_line) = get_xwd_data(extern_img_source_mmap)?;
-                (data, width, height, bytes_per_line)
-            } else {
-                // Grab from from x11 shm
-                trace!("Grab from x11 {:?}", self.grabinfo.size);
-                let data = unsafe {
-                    std::slice::from_raw_parts(
-                        self.grabinfo.addr as *mut u8,
-                        self.grabinfo.size as _,
-                    )
+            let (data, width, height, bytes_per_line) =
+                if let Some(ref extern_img_source_mmap) = self.grabinfo.extern_img_source_mmap {
+                    // Grab frame from external xwd image, which must cover the
+                    // grabbed size
+                    let bytes_per_pixel = match self.grabinfo.extern_img_format {
+                        ImgSourceFormat::Rgba => 4,
+                        ImgSourceFormat::Yuyv => 2,
+                    };
+                    let source = get_xwd_data(extern_img_source_mmap).and_then(
+                        |(data, source_width, source_height, bytes_per_line)| {
+                            if source_width < width
+                                || source_height < height
+                                || bytes_per_line < width * bytes_per_pixel
+                            {
+                                return Err(anyhow!(
+                                    "Source {}x{} ({} bytes per line) smaller than {}x{}",
+                                    source_width,
+                                    source_height,
+                                    bytes_per_line,
+                                    width,
+                                    height
+                                ));
+                            }
+                            Ok((data, width, height, bytes_per_line))
+                        },
+                    );
+                    match source {
+                        Ok(source) => source,
+                        Err(err) => {
+                            warn!("Skip bad extern source frame");
+                            err.chain().for_each(|cause| warn!(" - due to {}", cause));
+                            return Ok((events, timings));
+                        }
+                    }
+                } else {
+                    // Grab from from x11 shm
+                    let data = self.grabinfo.shm.data();
+                    trace!("Grab from x11 {:?}", data.len());
+                    (data, width, height, width * 4)
                 };
-                (data, width, height, width * 4)
-            };
             trace!(
                 "data len {} {}x{} bytes per line {}",
                 data.len(),
@@ -1539,13 +2455,35 @@ impl Server for ServerX11 {
                 mmap.flush_range(0, data.len())
                     .context("Cannot flush video memory")?;
                 let time_stop = Instant::now();
-                time_memcpy = Some(("memcpy", time_stop - time_start));
+                time_memcpy = Some((TIMING_MEMCPY, time_stop - time_start));
+            }
+            // Damages only describe the x11 frames
+            if let (Some(qoffsets), None) =
+                (self.roi_qoffsets, &self.grabinfo.extern_img_source_mmap)
+            {
+                video_encoder.set_regions_of_interest(damage_regions_of_interest(
+                    self.damaged_region,
+                    (self.grabinfo.width, self.grabinfo.height),
+                    qoffsets,
+                ));
             }
             trace!("Encode");
-            let result = video_encoder
-                .encode_image(data, width, height, bytes_per_line, self.img_count)
-                .unwrap();
-            let encoded = result.0;
+            let yuyv_source = self.grabinfo.extern_img_source_mmap.is_some()
+                && self.grabinfo.extern_img_format == ImgSourceFormat::Yuyv;
+            let (result, bytes_per_line) = if yuyv_source {
+                // Packed 4:2:2 frames skip the rgba conversion when possible,
+                // raw encoders output rgba
+                let result = video_encoder
+                    .encode_yuyv_image_tiles(data, width, height, bytes_per_line, self.img_count)
+                    .context("Error in encode yuyv image")?;
+                (result, width * 4)
+            } else {
+                let result = video_encoder
+                    .encode_image_tiles(data, width, height, bytes_per_line, self.img_count)
+                    .context("Error in encode image")?;
+                (result, bytes_per_line)
+            };
+            let tiles = result.0;
             let mut encoder_timings = result.1;
             if let Some(time_memcpy) = time_memcpy {
                 encoder_timings.times.push(time_memcpy);
@@ -1553,41 +2491,70 @@ impl Server for ServerX11 {
 
             timings = Some(encoder_timings);
 
-            /* Prepare encoded image */
-            let img = match video_encoder.is_raw() {
-                true => match &self.grabinfo.export_video_mmap {
-                    None => tunnel::message_srv::Msg::ImgRaw(tunnel::ImageRaw {
+            /* Prepare encoded images, one per tile */
+            for EncodedTile {
+                data: encoded,
+                tile,
+            } in tiles
+            {
+                let img = match video_encoder.is_raw() {
+                    true => match &self.grabinfo.export_video_mmap {
+                        None => tunnel::message_srv::Msg::ImgRaw(tunnel::ImageRaw {
+                            data: encoded,
+                            width,
+                            height,
+                            bytes_per_line,
+                        }),
+                        Some(_) => tunnel::message_srv::Msg::ImgRaw(tunnel::ImageRaw {
+                            data: vec![],
+                            width,
+                            height,
+                            bytes_per_line,
+                        }),
+                    },
+                    false => tunnel::message_srv::Msg::ImgEncoded(tunnel::ImageEncoded {
                         data: encoded,
                         width,
                         height,
-                        bytes_per_line,
+                        // Numbered by the main loop
+                        sequence: 0,
+                        tile,
                     }),
-                    Some(_) => tunnel::message_srv::Msg::ImgRaw(tunnel::ImageRaw {
-                        data: vec![],
-                        width,
-                        height,
-                        bytes_per_line,
-                    }),
-                },
-                false => tunnel::message_srv::Msg::ImgEncoded(tunnel::ImageEncoded {
-                    data: encoded,
-                    width,
-                    height,
-                }),
-            };
-            let msg_img = tunnel::MessageSrv { msg: Some(img) };
-            events.push(msg_img);
+                };
+                let msg_img = tunnel::MessageSrv { msg: Some(img) };
+                events.push(msg_img);
+            }
         };
 
         Ok((events, timings))
     }
 
+    fn grabbed_frame(&self) -> Option<(&[u8], u32, u32, u32)> {
+        // External sources are only read on encoding
+        if self.grabinfo.extern_img_source_mmap.is_some() {
+            return None;
+        }
+        let (width, height) = (self.grabinfo.width as u32, self.grabinfo.height as u32);
+        Some((self.grabinfo.shm.data(), width, height, width * 4))
+    }
+
     fn change_resolution(&mut self, config: &ConfigServer, width: u32, height: u32) -> Result<()> {
-        let (old_video_name, new_video_name, new_video_index) = if self.video_mode_index == 0 {
-            (&VIDEO_NAMES[0], &VIDEO_NAMES[1], 1)
-        } else {
-            (&VIDEO_NAMES[1], &VIDEO_NAMES[0], 0)
-        };
+        if self.capture_window.is_some() {
+            return Err(anyhow!("Resolution follows the captured window size"));
+        }
+        let old_video_name = video_mode_name(self.video_mode_index);
+        let new_video_index = (self.video_mode_index + 1) % self.video_modes;
+        let new_video_name = video_mode_name(new_video_index);
+
+        // A previous deletion of this mode may have failed
+        if utils_x11::get_video_mode(&self.conn, self.window, &new_video_name)
+            .context("Error in get_video_mode")?
+            .is_some()
+        {
+            debug!("Delete stale video mode {:?}", new_video_name);
+            utils_x11::delete_video_mode_by_name(&self.conn, self.window, &new_video_name)
+                .context("Error in delete stale video mode")?;
+        }
 
         // Add video mode
         trace!("Add video mode {:?} {}x{}", new_video_name, width, height);
@@ -1596,44 +2563,172 @@ impl Server for ServerX11 {
             self.window,
             width as u16,
             height as u16,
-            new_video_name,
+            &new_video_name,
             new_video_index,
             self.dpi,
         )
         .context("Error in add_video_mode")?;
+        self.video_mode_index = new_video_index;
 
         // Create new grab info
         let setup = self.conn.setup();
         let screen = &setup.roots[self.screen_num];
 
-        self.conn
-            .shm_detach(self.grabinfo.shmseg)
-            .context("Error in shm_detach")?;
-        let ret = unsafe { shmdt(self.grabinfo.addr as *const std::ffi::c_void) };
-        if ret != 0 {
-            panic!("Cannot detach memory");
-        }
+        // The selected output may have moved with the new mode
+        let (origin, width, height) = match self.output {
+            Some(ref spec) => {
+                let outputs = get_outputs(&self.conn, screen.root).context("Cannot get outputs")?;
+                let output =
+                    find_output(&outputs, spec).context(format!("Cannot find output {spec:?}"))?;
+                (
+                    (output.x, output.y),
+                    (width as u16).min(output.width),
+                    (height as u16).min(output.height),
+                )
+            }
+            None => ((0, 0), width as u16, height as u16),
+        };
 
+        // The frame buffer is swapped as a whole: the current one is kept if
+        // the new one cannot be created
         let grabinfo = init_grab(
             &self.conn,
-            screen,
+            screen.root,
+            origin,
             self.grabinfo.export_video_mmap.is_some(),
             self.extern_img_source.clone(),
             config,
-            width as u16,
-            height as u16,
+            width,
+            height,
         )
         .context("Error in init_grab")?;
-        self.grabinfo = grabinfo;
+        let old_grabinfo = std::mem::replace(&mut self.grabinfo, grabinfo);
+        self.input_origin = origin;
+        self.conn
+            .shm_detach(old_grabinfo.shm.shmseg)
+            .context("Error in shm_detach")?;
 
-        // Delete old mode
-        utils_x11::delete_video_mode_by_name(&self.conn, self.window, old_video_name)
-            .context("Error in delete_video_mode_by_name")?;
-        self.video_mode_index = new_video_index;
+        // Delete old mode, retried when its name comes back in the rotation
+        if let Err(err) =
+            utils_x11::delete_video_mode_by_name(&self.conn, self.window, &old_video_name)
+        {
+            warn!("Cannot delete video mode {:?}", old_video_name);
+            err.chain().for_each(|cause| warn!(" - due to {}", cause));
+        }
+
+        Ok(())
+    }
+
+    fn resolution_range(&self) -> Result<((u32, u32), (u32, u32))> {
+        let range = self
+            .conn
+            .randr_get_screen_size_range(self.root)
+            .context("Error in randr_get_screen_size_range")?
+            .reply()
+            .context("Error in randr_get_screen_size_range reply")?;
+        let min = (
+            (range.min_width as u32).max(MIN_RESOLUTION.0),
+            (range.min_height as u32).max(MIN_RESOLUTION.1),
+        );
+        let max = (
+            (range.max_width as u32).min(MAX_WINDOW_WIDTH),
+            (range.max_height as u32).min(MAX_WINDOW_HEIGHT),
+        );
+        Ok((min, max))
+    }
+
+    fn clipboard_notifier(&self) -> Option<ClipboardNotifier> {
+        Some(self.clipboard_notifier.clone())
+    }
 
+    fn display(&self) -> Option<&str> {
+        self.virtual_display
+            .as_ref()
+            .map(|virtual_display| virtual_display.display())
+    }
+
+    fn reload_config(&mut self, config: &ConfigServer, restrict_clipboard: bool) {
+        self.max_stall_img = config.video.max_stall_img;
+        self.clipboard_policy = config.clipboard_policy(restrict_clipboard);
+        for (selection, listener) in [
+            (ClipboardSelection::Primary, &self.primary_to_client),
+            (ClipboardSelection::Clipboard, &self.clipboard_to_client),
+        ] {
+            let to_client = self.clipboard_policy.direction(selection).to_client();
+            match listener {
+                Some(enabled) => enabled.store(to_client, atomic::Ordering::Relaxed),
+                None if to_client => warn!(
+                    "Clipboard {:?} to client was disabled at startup, needs a restart",
+                    selection
+                ),
+                None => {}
+            }
+        }
+    }
+
+    fn set_dpi(&mut self, dpi: u32) -> Result<()> {
+        self.dpi = dpi;
+        // Advertise the dpi to the applications started from now on
+        let root = self.conn.setup().roots[self.screen_num].root;
+        let resources = self
+            .conn
+            .get_property(
+                false,
+                root,
+                AtomEnum::RESOURCE_MANAGER,
+                AtomEnum::STRING,
+                0,
+                0xFFFF,
+            )
+            .context("Error in get_property")?
+            .reply()
+            .context("Error in get_property reply")?;
+        let resources = with_xft_dpi(&String::from_utf8_lossy(&resources.value), dpi);
+        self.conn
+            .change_property8(
+                PropMode::REPLACE,
+                root,
+                AtomEnum::RESOURCE_MANAGER,
+                AtomEnum::STRING,
+                resources.as_bytes(),
+            )
+            .context("Error in change_property")?
+            .check()
+            .context("Error in change_property check")?;
+        self.conn.flush().context("Error in x11rb flush")?;
         Ok(())
     }
 
+    fn window_list(&self) -> Result<Vec<tunnel::WindowEntry>> {
+        let app_list =
+            get_client_list(&self.conn, self.root).context("Error in get_client_list")?;
+        let mut windows = vec![];
+        // Only list areas known by the client
+        for index in self.sent_areas.iter() {
+            let area = match self.areas.get(index) {
+                Some(area) => area,
+                None => continue,
+            };
+            let minimized = get_app_window(&self.conn, area.drawable, &app_list)
+                .and_then(|app_window| get_window_state(&self.conn, app_window).ok())
+                .map_or(false, |state| {
+                    state.contains(&self.atoms._NET_WM_STATE_HIDDEN)
+                });
+            windows.push(tunnel::WindowEntry {
+                id: *index as u32,
+                title: area.name.clone(),
+                x: area.position.0 as i32,
+                y: area.position.1 as i32,
+                width: area.size.0 as u32,
+                height: area.size.1 as u32,
+                is_app: area.is_app,
+                minimized,
+            });
+        }
+        windows.sort_by_key(|window| window.id);
+        Ok(windows)
+    }
+
     fn activate_window(&self, win_id: u32) -> Result<()> {
         let atom_active_a = self.atoms._NET_ACTIVE_WINDOW;
 
@@ -1664,3 +2759,242 @@ impl Server for ServerX11 {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn area(position: (i16, i16), size: (u16, u16)) -> Area {
+        Area {
+            drawable: 0x42,
+            position,
+            size,
+            mapped: true,
+            is_app: true,
+            name: "app".to_owned(),
+            stacking: 0,
+            topmost: false,
+        }
+    }
+
+    #[test]
+    fn test_x11_connection_error() {
+        let err = anyhow::Error::new(ReplyError::ConnectionError(ConnectionError::UnknownError))
+            .context("Error in shm_get_image reply");
+        assert!(is_x11_connection_error(&err));
+        let err = anyhow::Error::new(ConnectionError::UnknownError).context("Cannot flush");
+        assert!(is_x11_connection_error(&err));
+        let err = anyhow!("Captured window destroyed");
+        assert!(!is_x11_connection_error(&err));
+    }
+
+    #[test]
+    fn test_video_mode_name() {
+        // Names of the previous releases, cleaned up at startup
+        assert_eq!(video_mode_name(0), "video_mode_1");
+        assert_eq!(video_mode_name(1), "video_mode_2");
+    }
+
+    #[test]
+    fn test_coordinate_fp1616() {
+        assert_eq!(coordinate_to_fp1616(10, 0), 10 << 16);
+        assert_eq!(coordinate_to_fp1616(10, 0x8000), (10 << 16) + 0x8000);
+        // -0.75
+        assert_eq!(coordinate_to_fp1616(-1, 0x4000), -0xc000);
+    }
+
+    #[test]
+    fn test_area_policy_min_size() {
+        let screen = (1920, 1080);
+        let policy = AreaPolicy::new(None);
+        assert!(!policy.is_visible(&area((10, 10), (1, 1)), screen));
+        assert!(!policy.is_visible(&area((10, 10), (100, 1)), screen));
+        assert!(policy.is_visible(&area((10, 10), (2, 2)), screen));
+
+        let config = Seamless {
+            min_area_size: Some(32),
+            cull_offscreen: None,
+        };
+        let policy = AreaPolicy::new(Some(&config));
+        assert!(!policy.is_visible(&area((10, 10), (31, 100)), screen));
+        assert!(policy.is_visible(&area((10, 10), (32, 32)), screen));
+    }
+
+    #[test]
+    fn test_area_events_empty() {
+        let screen = (1920, 1080);
+        let config = Seamless {
+            min_area_size: Some(0),
+            cull_offscreen: None,
+        };
+        let policy = AreaPolicy::new(Some(&config));
+        let mut sent_areas = HashSet::new();
+        let mut areas = HashMap::new();
+        // Window created at 0x0, then resized
+        areas.insert(0, area((10, 10), (0, 0)));
+        assert!(area_events(&areas, &policy, screen, &mut sent_areas).is_empty());
+        areas.insert(0, area((10, 10), (200, 0)));
+        assert!(area_events(&areas, &policy, screen, &mut sent_areas).is_empty());
+
+        areas.insert(0, area((10, 10), (200, 100)));
+        let events = area_events(&areas, &policy, screen, &mut sent_areas);
+        assert!(matches!(
+            events[..],
+            [tunnel::MessageSrv {
+                msg: Some(tunnel::message_srv::Msg::AreaUpdt(tunnel::EventAreaUpdt {
+                    width: 200,
+                    height: 100,
+                    ..
+                }))
+            }]
+        ));
+
+        // Shrunk back to 0x0: area is deleted
+        areas.insert(0, area((10, 10), (0, 0)));
+        let events = area_events(&areas, &policy, screen, &mut sent_areas);
+        assert!(matches!(
+            events[..],
+            [tunnel::MessageSrv {
+                msg: Some(tunnel::message_srv::Msg::DeleteArea(
+                    tunnel::EventDeleteArea { id: 0 }
+                ))
+            }]
+        ));
+        assert!(sent_areas.is_empty());
+    }
+
+    #[test]
+    fn test_area_policy_offscreen() {
+        let screen = (1920, 1080);
+        let policy = AreaPolicy::new(None);
+        assert!(!policy.is_visible(&area((-200, 10), (200, 100)), screen));
+        assert!(!policy.is_visible(&area((1920, 10), (200, 100)), screen));
+        assert!(!policy.is_visible(&area((10, -32000), (200, 100)), screen));
+        // Partially visible
+        assert!(policy.is_visible(&area((-199, 10), (200, 100)), screen));
+        assert!(policy.is_visible(&area((1919, 1079), (200, 100)), screen));
+
+        let config = Seamless {
+            min_area_size: None,
+            cull_offscreen: Some(false),
+        };
+        let policy = AreaPolicy::new(Some(&config));
+        assert!(policy.is_visible(&area((-200, 10), (200, 100)), screen));
+    }
+
+    #[test]
+    fn test_area_events_offscreen() {
+        let screen = (1920, 1080);
+        let policy = AreaPolicy::new(None);
+        let mut sent_areas = HashSet::new();
+        let mut areas = HashMap::new();
+        areas.insert(0, area((-32000, -32000), (200, 100)));
+        assert!(area_events(&areas, &policy, screen, &mut sent_areas).is_empty());
+
+        // Window moved on screen, then offscreen: area is deleted
+        areas.insert(0, area((10, 10), (200, 100)));
+        let events = area_events(&areas, &policy, screen, &mut sent_areas);
+        assert!(matches!(
+            events[..],
+            [tunnel::MessageSrv {
+                msg: Some(tunnel::message_srv::Msg::AreaUpdt(_))
+            }]
+        ));
+        areas.insert(0, area((-32000, -32000), (200, 100)));
+        let events = area_events(&areas, &policy, screen, &mut sent_areas);
+        assert!(matches!(
+            events[..],
+            [tunnel::MessageSrv {
+                msg: Some(tunnel::message_srv::Msg::DeleteArea(
+                    tunnel::EventDeleteArea { id: 0 }
+                ))
+            }]
+        ));
+        assert!(area_events(&areas, &policy, screen, &mut sent_areas).is_empty());
+    }
+
+    #[test]
+    fn test_outputs() {
+        let output = |name: &str, x| OutputInfo {
+            name: name.to_owned(),
+            x,
+            y: 0,
+            width: 1920,
+            height: 1080,
+        };
+        let outputs = vec![output("DP-1", 0), output("HDMI-1", 1920)];
+        assert_eq!(find_output(&outputs, "HDMI-1"), Some(&outputs[1]));
+        assert_eq!(find_output(&outputs, "0"), Some(&outputs[0]));
+        assert_eq!(find_output(&outputs, "2"), None);
+        assert_eq!(find_output(&outputs, "DP-2"), None);
+
+        let damage = |x, width| Rectangle {
+            x,
+            y: 10,
+            width,
+            height: 10,
+        };
+        let size = (1920, 1080);
+        // Damages are moved to the output origin
+        assert_eq!(
+            grabbed_area(&damage(2000, 10), (1920, 0), size),
+            Some(damage(80, 10))
+        );
+        assert_eq!(
+            grabbed_area(&damage(1910, 20), (1920, 0), size),
+            Some(damage(-10, 20))
+        );
+        // Damages on other outputs are ignored
+        assert_eq!(grabbed_area(&damage(100, 10), (1920, 0), size), None);
+        assert_eq!(grabbed_area(&damage(1900, 20), (1920, 0), size), None);
+    }
+
+    #[test]
+    fn test_damaged_region() {
+        let screen = (100, 50);
+        let rect = |x, y, width, height| Rectangle {
+            x,
+            y,
+            width,
+            height,
+        };
+        let region = extend_damaged_region(None, &rect(10, 10, 5, 5), screen);
+        assert_eq!(region, Some((10, 10, 15, 15)));
+        let region = extend_damaged_region(region, &rect(40, 2, 10, 3), screen);
+        assert_eq!(region, Some((10, 2, 50, 15)));
+        // Clipped to the screen, empty and offscreen rectangles are ignored
+        let region = extend_damaged_region(region, &rect(-5, 40, 10, 20), screen);
+        assert_eq!(region, Some((0, 2, 50, 50)));
+        assert_eq!(
+            extend_damaged_region(region, &rect(120, 0, 10, 10), screen),
+            region
+        );
+        assert_eq!(
+            extend_damaged_region(None, &rect(5, 5, 0, 10), screen),
+            None
+        );
+
+        let data: Vec<u8> = (0..4 * 4 * 3).map(|i| i as u8).collect();
+        let img = extract_region(&data, 4 * 4, (1, 1, 3, 3));
+        assert_eq!((img.x, img.y, img.width, img.height), (1, 1, 2, 2));
+        let expected: Vec<u8> = (20..28).chain(36..44).collect();
+        assert_eq!(img.data, expected);
+    }
+
+    #[test]
+    fn test_select_icon() {
+        let mut values = vec![2, 2];
+        values.extend([1; 4]);
+        values.extend([4, 1]);
+        values.extend([2; 4]);
+        values.extend([300, 1]);
+        values.extend([3; 300]);
+        // Largest icon fitting in 256 pixels, first one on equal sizes
+        assert_eq!(select_icon(&values, 256), Some((2, 2, &[1u32; 4][..])));
+        assert_eq!(select_icon(&values, 512), Some((300, 1, &[3u32; 300][..])));
+        assert_eq!(select_icon(&values, 1), None);
+        // Truncated icon
+        assert_eq!(select_icon(&[16, 16, 0, 0], 256), None);
+        assert_eq!(select_icon(&[], 256), None);
+    }
+}
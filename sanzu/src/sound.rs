@@ -7,7 +7,10 @@ use sanzu_common::tunnel;
 use std::{
     collections::VecDeque,
     io::Cursor,
-    sync::{mpsc, Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc, Arc, Mutex,
+    },
     thread,
     time::Duration,
 };
@@ -16,10 +19,164 @@ use std::{
 pub const SOUND_FREQ: u32 = 48000;
 pub const DECODER_BUFFER_MS: usize = 150;
 pub const TARGET_SAMPLE_RATE: u32 = 48000;
+pub const DEFAULT_OPUS_FRAME_MS: u32 = 20;
+/// Supported opus frame durations, in ms
+const OPUS_FRAME_MS: [u32; 4] = [5, 10, 20, 30];
+/// Opus bitrate range, in kbps
+const OPUS_BITRATE_KBPS: std::ops::RangeInclusive<u32> = 6..=510;
+/// Accepted sample rates
+const SAMPLE_RATES: std::ops::RangeInclusive<u32> = 8000..=192000;
+/// Accepted channel counts
+const CHANNELS: std::ops::RangeInclusive<u16> = 1..=8;
+/// Format of the microphone stream, converted by the client if needed
+pub const MICROPHONE_FORMAT: AudioFormat = AudioFormat {
+    sample_rate: SOUND_FREQ,
+    channels: 2,
+};
+
+/// Pcm format of a sound stream, with interleaved channels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl AudioFormat {
+    /// Check a requested format. Unset values are chosen by the device.
+    pub fn check(sample_rate: Option<u32>, channels: Option<u16>) -> Result<()> {
+        if let Some(sample_rate) = sample_rate {
+            if !SAMPLE_RATES.contains(&sample_rate) {
+                return Err(anyhow!(
+                    "Sample rate {} out of range {:?}",
+                    sample_rate,
+                    SAMPLE_RATES
+                ));
+            }
+        }
+        if let Some(channels) = channels {
+            if !CHANNELS.contains(&channels) {
+                return Err(anyhow!(
+                    "Channel count {} out of range {:?}",
+                    channels,
+                    CHANNELS
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of samples of `duration_ms`
+    pub fn samples(&self, duration_ms: u64) -> usize {
+        (duration_ms as usize * self.sample_rate as usize * self.channels as usize) / 1000
+    }
+}
+
+/// Convert `samples` from the `from` format to the `to` format: channels are
+/// averaged into mono or repeated, and frames are linearly interpolated
+fn convert_format(samples: &[i16], from: AudioFormat, to: AudioFormat) -> Vec<i16> {
+    let from_channels = from.channels as usize;
+    let to_channels = to.channels as usize;
+    let frames: Vec<Vec<i16>> = samples
+        .chunks_exact(from_channels)
+        .map(|frame| {
+            if to_channels == 1 {
+                let sum: i32 = frame.iter().map(|sample| *sample as i32).sum();
+                vec![(sum / from_channels as i32) as i16]
+            } else {
+                (0..to_channels)
+                    .map(|channel| frame[channel % from_channels])
+                    .collect()
+            }
+        })
+        .collect();
+    if from.sample_rate == to.sample_rate || frames.is_empty() {
+        return frames.concat();
+    }
+
+    let ratio = from.sample_rate as f64 / to.sample_rate as f64;
+    let len = (frames.len() as f64 / ratio) as usize;
+    let mut output = Vec::with_capacity(len * to_channels);
+    for index in 0..len {
+        let position = index as f64 * ratio;
+        let prev = &frames[position as usize];
+        let next = frames.get(position as usize + 1).unwrap_or(prev);
+        for (prev, next) in prev.iter().zip(next.iter()) {
+            let value = *prev as f64 + (*next as f64 - *prev as f64) * position.fract();
+            output.push(value as i16);
+        }
+    }
+    output
+}
+
+/// Opus encoder settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpusSettings {
+    /// Bitrate in kbps, chosen by the encoder if None
+    pub bitrate_kbps: Option<u32>,
+    /// Duration of the encoded frames, in ms
+    pub frame_ms: u32,
+}
+
+impl OpusSettings {
+    pub fn new(bitrate_kbps: Option<u32>, frame_ms: Option<u32>) -> Result<Self> {
+        let frame_ms = frame_ms.unwrap_or(DEFAULT_OPUS_FRAME_MS);
+        if !OPUS_FRAME_MS.contains(&frame_ms) {
+            return Err(anyhow!(
+                "Unsupported opus frame duration {} ms (supported: {:?})",
+                frame_ms,
+                OPUS_FRAME_MS
+            ));
+        }
+        if let Some(bitrate_kbps) = bitrate_kbps {
+            if !OPUS_BITRATE_KBPS.contains(&bitrate_kbps) {
+                return Err(anyhow!(
+                    "Opus bitrate {} kbps out of range {:?}",
+                    bitrate_kbps,
+                    OPUS_BITRATE_KBPS
+                ));
+            }
+        }
+        Ok(OpusSettings {
+            bitrate_kbps,
+            frame_ms,
+        })
+    }
+
+    /// Number of samples of an encoded frame
+    pub fn frame_len(&self) -> usize {
+        SOUND_FREQ as usize * 2 * self.frame_ms as usize / 1000
+    }
+
+    pub fn new_encoder(&self) -> Result<opus::Encoder> {
+        let mut encoder = opus::Encoder::new(
+            SOUND_FREQ,
+            opus::Channels::Mono,
+            opus::Application::LowDelay,
+        )
+        .context("Cannot create sound encoder")?;
+        if let Some(bitrate_kbps) = self.bitrate_kbps {
+            encoder
+                .set_bitrate(opus::Bitrate::Bits(bitrate_kbps as i32 * 1000))
+                .context("Cannot set sound bitrate")?;
+        }
+        Ok(encoder)
+    }
+}
+
+impl Default for OpusSettings {
+    fn default() -> Self {
+        OpusSettings {
+            bitrate_kbps: None,
+            frame_ms: DEFAULT_OPUS_FRAME_MS,
+        }
+    }
+}
 
 fn enqueue_from_source<T, U>(
     input: &[T],
     buffering_queue: Arc<Mutex<(usize, VecDeque<Vec<i16>>)>>,
+    capture_format: AudioFormat,
+    format: AudioFormat,
     max_buffer_ms: u64,
 ) where
     T: cpal::Sample,
@@ -30,13 +187,16 @@ fn enqueue_from_source<T, U>(
         let sample: U = U::from_sample(sample);
         data.push(sample.as_i16());
     }
+    if capture_format != format {
+        data = convert_format(&data, capture_format, format);
+    }
 
     let mut buffering_queue_guard = buffering_queue.lock().unwrap();
 
     buffering_queue_guard.0 += data.len();
     buffering_queue_guard.1.push_back(data);
 
-    let sound_max_len: usize = (max_buffer_ms as usize * SOUND_FREQ as usize * 2) / 1000;
+    let sound_max_len = format.samples(max_buffer_ms);
 
     while buffering_queue_guard.0 > sound_max_len {
         let tmp = buffering_queue_guard
@@ -49,11 +209,12 @@ fn enqueue_from_source<T, U>(
 
 /// Initialize a sound encoder
 ///
-/// `sender` - a mpsc::Sender which will receive encoded sound samples
+/// The sound is captured in `format` if the device supports it, else in the
+/// device default format and converted.
 pub fn init_sound_encoder(
     device_name: &str,
     buffering_queue: Arc<Mutex<(usize, VecDeque<Vec<i16>>)>>,
-    sample_rate: u32,
+    format: AudioFormat,
     max_buffer_ms: u64,
 ) -> Result<cpal::Stream> {
     /* Sound */
@@ -77,12 +238,12 @@ pub fn init_sound_encoder(
     debug!("Default input config: {:?}", default_config);
 
     let configs = device
-        .supported_output_configs()
+        .supported_input_configs()
         .context("Error in get sound input config")?;
     let mut selected_config = None;
     for config in configs {
-        debug!("config {:?} {}", config, sample_rate);
-        if config.channels() != default_config.channels() {
+        debug!("config {:?} {:?}", config, format);
+        if config.channels() != format.channels {
             continue;
         }
 
@@ -90,18 +251,28 @@ pub fn init_sound_encoder(
             continue;
         }
 
-        if sample_rate < config.min_sample_rate().0 {
+        if format.sample_rate < config.min_sample_rate().0 {
             continue;
         }
 
-        if sample_rate > config.max_sample_rate().0 {
+        if format.sample_rate > config.max_sample_rate().0 {
             continue;
         }
-        let config = config.with_sample_rate(SampleRate(sample_rate));
+        let config = config.with_sample_rate(SampleRate(format.sample_rate));
         selected_config = Some(config);
         break;
     }
-    let config = selected_config.context("No suitable sound context")?;
+    let config = selected_config.unwrap_or_else(|| {
+        warn!(
+            "Sound input device does not support {:?}, using its default config",
+            format
+        );
+        default_config
+    });
+    let capture_format = AudioFormat {
+        sample_rate: config.sample_rate().0,
+        channels: config.channels(),
+    };
 
     // A flag to indicate that recording is in progress.
 
@@ -114,7 +285,13 @@ pub fn init_sound_encoder(
             .build_input_stream(
                 &config.into(),
                 move |data, _: &_| {
-                    enqueue_from_source::<f32, i16>(data, buffering_queue.clone(), max_buffer_ms)
+                    enqueue_from_source::<f32, i16>(
+                        data,
+                        buffering_queue.clone(),
+                        capture_format,
+                        format,
+                        max_buffer_ms,
+                    )
                 },
                 err_fn,
                 None,
@@ -124,7 +301,13 @@ pub fn init_sound_encoder(
             .build_input_stream(
                 &config.into(),
                 move |data, _: &_| {
-                    enqueue_from_source::<i16, i16>(data, buffering_queue.clone(), max_buffer_ms)
+                    enqueue_from_source::<i16, i16>(
+                        data,
+                        buffering_queue.clone(),
+                        capture_format,
+                        format,
+                        max_buffer_ms,
+                    )
                 },
                 err_fn,
                 None,
@@ -134,7 +317,13 @@ pub fn init_sound_encoder(
             .build_input_stream(
                 &config.into(),
                 move |data, _: &_| {
-                    enqueue_from_source::<u16, i16>(data, buffering_queue.clone(), max_buffer_ms)
+                    enqueue_from_source::<u16, i16>(
+                        data,
+                        buffering_queue.clone(),
+                        capture_format,
+                        format,
+                        max_buffer_ms,
+                    )
                 },
                 err_fn,
                 None,
@@ -150,57 +339,42 @@ pub fn init_sound_encoder(
     Ok(stream)
 }
 
+/// Sound packet received from the server
+enum SoundPacket {
+    Opus(Vec<u8>),
+    /// Little endian 16 bits pcm
+    Raw(Vec<u8>),
+}
+
 /// Holds SoundDecoder information
 pub struct SoundDecoder {
     /// Decoder stream
     stream: cpal::Stream,
-    /// Sample rate
-    pub sample_rate: u32,
+    /// Playback format
+    pub format: AudioFormat,
+    /// Playback buffer length
+    pub audio_buffer_ms: u32,
     /// Source of encoded packets
-    pkt_q: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    pkt_q: Arc<Mutex<VecDeque<SoundPacket>>>,
+    /// Decoded samples waiting for playback
+    sound_q: Arc<Mutex<VecDeque<i16>>>,
+    /// Playback gain, as f32 bits
+    volume: Arc<AtomicU32>,
+    /// Drop the received packets
+    muted: AtomicBool,
 }
 
 impl SoundDecoder {
+    /// Play the sound in the requested `sample_rate` and `channels` if the
+    /// device supports them, else in the device default format
     pub fn new(
         device_name: &str,
         sample_rate: Option<u32>,
+        channels: Option<u16>,
         audio_buffer_ms: u32,
     ) -> Result<SoundDecoder> {
         let mut decoder = opus::Decoder::new(SOUND_FREQ, opus::Channels::Mono)
-            .expect("Cannot create sound decoder");
-        let pkt_q: Arc<Mutex<VecDeque<Vec<u8>>>> = Arc::new(Mutex::new(VecDeque::new()));
-        let sound_q = Arc::new(Mutex::new(VecDeque::new()));
-
-        let sound_queue_cp = sound_q.clone();
-        let pkt_q_cp = pkt_q.clone();
-
-        thread::spawn(move || {
-            let mut output = vec![0i16; 100000];
-            loop {
-                while let Some(pkt) = pkt_q_cp.lock().unwrap().pop_front() {
-                    if let Ok(len) = decoder.decode(&pkt, &mut output, false) {
-                        trace!("Sound: decoded {:?} {:?}", pkt.len(), len);
-                        let real_output = output[0..len].to_owned();
-                        let mut data: std::collections::VecDeque<i16> =
-                            real_output.into_iter().collect();
-                        sound_q.lock().unwrap().append(&mut data);
-                        {
-                            // Forget old datas if we are lagging more than audio_buffer_ms
-                            let sound_max_len =
-                                (audio_buffer_ms as usize * SOUND_FREQ as usize * 2) / 1000;
-                            let mut sound_data = sound_q.lock().unwrap();
-                            if sound_data.len() > sound_max_len {
-                                debug!("Sound queue too big");
-                                let diff = sound_data.len() - sound_max_len;
-                                let _drained = sound_data.drain(0..diff).collect::<VecDeque<_>>();
-                            }
-                        }
-                    }
-                }
-                // Wait for buffer loading
-                std::thread::sleep(Duration::from_millis(10));
-            }
-        });
+            .context("Cannot create sound decoder")?;
 
         /* Sound */
         let host = cpal::default_host();
@@ -212,20 +386,23 @@ impl SoundDecoder {
                 .context("Error in sound output devices")?
                 .find(|x| x.name().map(|y| y == device_name).unwrap_or(false))
         }
-        .expect("failed to find output device");
+        .context(format!("Cannot find sound output device {device_name:?}"))?;
         debug!("Output device: {:?}", device.name()?);
 
-        let default_config = device.default_output_config().unwrap();
+        let default_config = device
+            .default_output_config()
+            .context("Error in get default sound output config")?;
 
         let configs = device
             .supported_output_configs()
-            .expect("Cannot get output config");
+            .context("Cannot get output config")?;
 
         debug!("Default config {:?}", default_config);
+        let requested_channels = channels.unwrap_or_else(|| default_config.channels());
         let mut selected_config = None;
         for config in configs {
             trace!("config {:?} {:?}", config, sample_rate);
-            if config.channels() != default_config.channels() {
+            if config.channels() != requested_channels {
                 continue;
             }
 
@@ -255,8 +432,17 @@ impl SoundDecoder {
             break;
         }
 
-        let config = selected_config.expect("No suitable sound config");
-        let sample_rate = config.sample_rate().0;
+        let config = selected_config.unwrap_or_else(|| {
+            warn!(
+                "Sound output device does not support {:?} Hz, {} channels, using its default",
+                sample_rate, requested_channels
+            );
+            default_config
+        });
+        let format = AudioFormat {
+            sample_rate: config.sample_rate().0,
+            channels: config.channels(),
+        };
 
         debug!("Default output config: {:?}", config);
 
@@ -265,14 +451,66 @@ impl SoundDecoder {
 
         let channels = config_in.channels as usize;
 
+        let pkt_q: Arc<Mutex<VecDeque<SoundPacket>>> = Arc::new(Mutex::new(VecDeque::new()));
+        let sound_q = Arc::new(Mutex::new(VecDeque::new()));
+
+        let sound_queue_cp = sound_q.clone();
+        let sound_queue_decoder = sound_q.clone();
+        let pkt_q_cp = pkt_q.clone();
+        let volume = Arc::new(AtomicU32::new(1.0f32.to_bits()));
+        let volume_cp = volume.clone();
+
+        thread::spawn(move || {
+            let sound_q = sound_queue_decoder;
+            let mut output = vec![0i16; 100000];
+            loop {
+                while let Some(pkt) = pkt_q_cp.lock().unwrap().pop_front() {
+                    let decoded = match pkt {
+                        SoundPacket::Opus(pkt) => match decoder.decode(&pkt, &mut output, false) {
+                            Ok(len) => {
+                                trace!("Sound: decoded {:?} {:?}", pkt.len(), len);
+                                output[0..len].to_owned()
+                            }
+                            Err(_) => continue,
+                        },
+                        SoundPacket::Raw(pkt) => pkt
+                            .chunks_exact(2)
+                            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+                            .collect(),
+                    };
+                    let gain = f32::from_bits(volume_cp.load(Ordering::Relaxed));
+                    let mut data: std::collections::VecDeque<i16> =
+                        apply_gain(decoded, gain).into_iter().collect();
+                    sound_q.lock().unwrap().append(&mut data);
+                    {
+                        // Forget old datas if we are lagging more than audio_buffer_ms
+                        let sound_max_len = format.samples(audio_buffer_ms as u64);
+                        let mut sound_data = sound_q.lock().unwrap();
+                        if sound_data.len() > sound_max_len {
+                            debug!("Sound queue too big");
+                            let diff = sound_data.len() - sound_max_len;
+                            let _drained = sound_data.drain(0..diff).collect::<VecDeque<_>>();
+                        }
+                    }
+                }
+                // The decoder has been dropped
+                if Arc::strong_count(&pkt_q_cp) == 1 {
+                    break;
+                }
+                // Wait for buffer loading
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        });
+
         let err_fn = |err| error!("An error occurred on stream: {}", err);
+        let prefill_len = format.samples(audio_buffer_ms as u64 / 4);
 
         let stream = match sample_format {
             cpal::SampleFormat::F32 => device
                 .build_output_stream(
                     &config_in,
                     move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                        dequeue_to_sink(data, channels, &sound_queue_cp, audio_buffer_ms)
+                        dequeue_to_sink(data, channels, &sound_queue_cp, prefill_len)
                     },
                     err_fn,
                     None,
@@ -282,7 +520,7 @@ impl SoundDecoder {
                 .build_output_stream(
                     &config_in,
                     move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
-                        dequeue_to_sink(data, channels, &sound_queue_cp, audio_buffer_ms)
+                        dequeue_to_sink(data, channels, &sound_queue_cp, prefill_len)
                     },
                     err_fn,
                     None,
@@ -292,7 +530,7 @@ impl SoundDecoder {
                 .build_output_stream(
                     &config_in,
                     move |data: &mut [u16], _: &cpal::OutputCallbackInfo| {
-                        dequeue_to_sink(data, channels, &sound_queue_cp, audio_buffer_ms)
+                        dequeue_to_sink(data, channels, &sound_queue_cp, prefill_len)
                     },
                     err_fn,
                     None,
@@ -307,8 +545,12 @@ impl SoundDecoder {
 
         Ok(SoundDecoder {
             stream,
-            sample_rate,
+            format,
+            audio_buffer_ms,
             pkt_q,
+            sound_q,
+            volume,
+            muted: AtomicBool::new(false),
         })
     }
 
@@ -316,16 +558,60 @@ impl SoundDecoder {
         self.stream.play().context("Error in stream play")
     }
 
+    /// Push an opus packet
     pub fn push(&mut self, data: Vec<u8>) {
-        self.pkt_q.lock().unwrap().push_back(data);
+        if !self.muted.load(Ordering::Relaxed) {
+            self.pkt_q
+                .lock()
+                .unwrap()
+                .push_back(SoundPacket::Opus(data));
+        }
+    }
+
+    /// Push raw pcm samples
+    pub fn push_raw(&mut self, data: Vec<u8>) {
+        if !self.muted.load(Ordering::Relaxed) {
+            self.pkt_q.lock().unwrap().push_back(SoundPacket::Raw(data));
+        }
+    }
+
+    /// Set the playback gain (1.0 keeps the original volume)
+    pub fn set_volume(&self, volume: f32) {
+        self.volume.store(volume.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Drop the pending sound and ignore the next packets while muted
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+        if muted {
+            self.pkt_q.lock().unwrap().clear();
+            self.sound_q.lock().unwrap().clear();
+        }
+    }
+
+    /// Duration of the decoded sound waiting for playback
+    pub fn buffered(&self) -> Duration {
+        let samples = self.sound_q.lock().unwrap().len();
+        let rate = self.format.sample_rate as f64 * self.format.channels as f64;
+        Duration::from_secs_f64(samples as f64 / rate)
     }
 }
 
+/// Scale `samples` by `gain`, saturating
+fn apply_gain(mut samples: Vec<i16>, gain: f32) -> Vec<i16> {
+    if gain != 1.0 {
+        for sample in samples.iter_mut() {
+            *sample = (*sample as f32 * gain).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+    }
+    samples
+}
+
 fn dequeue_to_sink<T>(
     output: &mut [T],
     channels: usize,
     sound_data: &Arc<Mutex<VecDeque<i16>>>,
-    audio_buffer_ms: u32,
+    prefill_len: usize,
 ) where
     T: cpal::Sample + cpal::FromSample<i16>,
 {
@@ -354,8 +640,7 @@ fn dequeue_to_sink<T>(
          * buffer. If the server finally send us data, it will be enqueued and
          * push out this dummy data.
          */
-        let initial_buf_len = (audio_buffer_ms as usize / 4 * SOUND_FREQ as usize * 2) / 1000;
-        for _ in 0..initial_buf_len {
+        for _ in 0..prefill_len {
             sound_data_in.push_back(0);
         }
     }
@@ -389,18 +674,18 @@ pub struct SoundEncoder {
     events_receiver: mpsc::Receiver<tunnel::MessageSrv>,
 }
 
-// Encode raw sound and serialize it.
+// Encode raw sound by frames of `frame_len` samples and serialize it.
 //
 // It may be possible that we don't have enough data to encode sound. In this
 // case, return None
 pub fn encode_sound(
     encoder: &mut opus::Encoder,
+    frame_len: usize,
     sound_data: &mut Vec<i16>,
 ) -> Option<tunnel::MessageSrv> {
-    const MONO_20MS: usize = SOUND_FREQ as usize * 2 * 20 / 1000;
     let mut sound_data_encoded = vec![];
-    while sound_data.len() > MONO_20MS {
-        let input: Vec<i16> = sound_data.drain(0..MONO_20MS).collect();
+    while sound_data.len() > frame_len {
+        let input: Vec<i16> = sound_data.drain(0..frame_len).collect();
         let mut output = vec![0u8; 10000];
         if let Ok(len) = encoder.encode(&input, &mut output) {
             let output = output[0..len].to_owned();
@@ -427,10 +712,11 @@ pub fn encode_sound(
 }
 
 impl SoundEncoder {
+    /// Encode the sound with `opus_settings`, or send it raw if None
     pub fn new(
         device_name: &str,
-        raw_sound: bool,
-        sample_rate: u32,
+        opus_settings: Option<OpusSettings>,
+        format: AudioFormat,
         max_buffer_ms: u64,
     ) -> Result<SoundEncoder> {
         let buffering_queue = Arc::new(Mutex::new((0, VecDeque::new())));
@@ -440,24 +726,13 @@ impl SoundEncoder {
             mpsc::Receiver<tunnel::MessageSrv>,
         ) = mpsc::channel();
 
-        let mut encoder = match raw_sound {
-            true => None,
-            false => Some(
-                opus::Encoder::new(
-                    SOUND_FREQ,
-                    opus::Channels::Mono,
-                    opus::Application::LowDelay,
-                )
-                .expect("Cannot create sound encoder"),
-            ),
+        let mut encoder = match opus_settings {
+            Some(settings) => Some((settings.new_encoder()?, settings.frame_len())),
+            None => None,
         };
 
-        let stream = init_sound_encoder(
-            device_name,
-            buffering_queue.clone(),
-            sample_rate,
-            max_buffer_ms,
-        )?;
+        let stream =
+            init_sound_encoder(device_name, buffering_queue.clone(), format, max_buffer_ms)?;
         let sound_buffer_cp = sound_buffer.clone();
         thread::spawn(move || {
             let mut sound_data = vec![];
@@ -477,11 +752,14 @@ impl SoundEncoder {
                     continue;
                 }
                 match &mut encoder {
-                    Some(ref mut encoder) => {
-                        if let Some(sound_event) = encode_sound(encoder, &mut sound_data) {
-                            events_sender
-                                .send(sound_event)
-                                .expect("Cannot send encoded data sound");
+                    Some((ref mut encoder, frame_len)) => {
+                        if let Some(sound_event) =
+                            encode_sound(encoder, *frame_len, &mut sound_data)
+                        {
+                            if events_sender.send(sound_event).is_err() {
+                                // The encoder has been dropped
+                                break;
+                            }
                         } else {
                             need_data = true;
                         }
@@ -506,9 +784,9 @@ impl SoundEncoder {
                                 msg: Some(sound_event),
                             };
 
-                            events_sender
-                                .send(sound_event)
-                                .expect("Cannot send encoded data sound");
+                            if events_sender.send(sound_event).is_err() {
+                                break;
+                            }
                         }
                     }
                 }
@@ -550,4 +828,50 @@ impl SoundEncoder {
     pub fn start(&mut self) -> Result<()> {
         self.stream.play().context("Error in stream play")
     }
+
+    /// Stop the sound recording and drop the pending sound, or restart it
+    pub fn set_muted(&mut self, muted: bool) -> Result<()> {
+        if !muted {
+            return self.start();
+        }
+        self.stream.pause().context("Error in stream pause")?;
+        {
+            let mut buffering_queue_guard = self.buffering_queue.lock().unwrap();
+            buffering_queue_guard.0 = 0;
+            buffering_queue_guard.1.clear();
+        }
+        self.sound_buffer.lock().unwrap().clear();
+        while self.events_receiver.try_recv().is_ok() {}
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_format() {
+        let stereo = AudioFormat {
+            sample_rate: 48000,
+            channels: 2,
+        };
+        let mono = AudioFormat {
+            sample_rate: 48000,
+            channels: 1,
+        };
+        assert_eq!(convert_format(&[10, 20, -4, 0], stereo, mono), [15, -2]);
+        assert_eq!(convert_format(&[15, -2], mono, stereo), [15, 15, -2, -2]);
+
+        let mono_24k = AudioFormat {
+            sample_rate: 24000,
+            channels: 1,
+        };
+        assert_eq!(convert_format(&[0, 10, 20, 30], mono, mono_24k), [0, 20]);
+        assert_eq!(convert_format(&[0, 10], mono_24k, mono), [0, 5, 10, 10]);
+
+        assert!(AudioFormat::check(Some(44100), Some(2)).is_ok());
+        assert!(AudioFormat::check(None, Some(0)).is_err());
+        assert!(AudioFormat::check(Some(1000), None).is_err());
+    }
 }
@@ -1,7 +1,18 @@
+use crate::{
+    config::{AudioCodec, ClipboardDirection, ClipboardPolicy},
+    sound::AudioFormat,
+    video_encoder::Chroma,
+    yuv_rgb_rs::{ColorRange, YuvType},
+};
 use anyhow::{Context, Result};
 use byteorder::{BigEndian, ByteOrder};
+use prost::Message;
+use sanzu_common::tunnel;
+use std::collections::{hash_map::DefaultHasher, VecDeque};
+use std::hash::{Hash, Hasher};
 use std::net::IpAddr;
-use std::time::Duration;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 use clap::{CommandFactory, Parser};
 use twelf::config;
@@ -16,6 +27,257 @@ pub enum ClipboardSelection {
     Primary,
 }
 
+/// Clipboard value exchanged between client and server
+pub type ClipboardContent = tunnel::event_clipboard::Content;
+
+/// Default maximum size of a clipboard image (bytes)
+pub const DEFAULT_MAX_CLIPBOARD_IMAGE: usize = 16 * 1024 * 1024;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Width and height of a png image, read from its header
+pub fn png_size(data: &[u8]) -> Option<(u32, u32)> {
+    if data.len() < 24 || !data.starts_with(PNG_SIGNATURE) || &data[12..16] != b"IHDR" {
+        return None;
+    }
+    Some((
+        BigEndian::read_u32(&data[16..20]),
+        BigEndian::read_u32(&data[20..24]),
+    ))
+}
+
+/// Build a clipboard image from png data
+pub fn clipboard_png(png: Vec<u8>) -> Option<ClipboardContent> {
+    let (width, height) = png_size(&png)?;
+    Some(ClipboardContent::Image(tunnel::ClipboardImage {
+        width,
+        height,
+        png,
+    }))
+}
+
+/// Returns true if the clipboard `event` may be sent or applied
+///
+/// Images are limited to `max_image_size` bytes, text is only limited by the
+/// maximum message length
+pub fn clipboard_allowed(event: &tunnel::EventClipboard, max_image_size: usize) -> bool {
+    match &event.content {
+        Some(ClipboardContent::Text(_)) => true,
+        Some(ClipboardContent::Image(image)) => {
+            if image.png.len() > max_image_size {
+                warn!(
+                    "Clipboard image too large ({}x{}, {} bytes)",
+                    image.width,
+                    image.height,
+                    image.png.len()
+                );
+                return false;
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Clipboard values larger than this are sent in several chunks
+pub const CLIPBOARD_CHUNK_SIZE: usize = 256 * 1024;
+/// Maximum size of a clipboard value received in chunks
+pub const MAX_CLIPBOARD_TRANSFER: usize = 64 * 1024 * 1024;
+
+/// Splits large clipboard values in chunks
+///
+/// Chunks are sent one per frame, so a big paste does not freeze the display
+#[derive(Default)]
+pub struct ClipboardChunker {
+    next_id: u32,
+    pending: VecDeque<tunnel::EventClipboardChunk>,
+}
+
+impl ClipboardChunker {
+    /// Queue a clipboard value
+    ///
+    /// Returns the event if it is small enough to be sent in one message
+    pub fn push(&mut self, event: tunnel::EventClipboard) -> Option<tunnel::EventClipboard> {
+        // The new value replaces the one being sent
+        self.pending.clear();
+        let data = event.encode_to_vec();
+        if data.len() <= CLIPBOARD_CHUNK_SIZE {
+            return Some(event);
+        }
+        self.next_id = self.next_id.wrapping_add(1);
+        let total = ((data.len() + CLIPBOARD_CHUNK_SIZE - 1) / CLIPBOARD_CHUNK_SIZE) as u32;
+        for (seq, chunk) in data.chunks(CLIPBOARD_CHUNK_SIZE).enumerate() {
+            self.pending.push_back(tunnel::EventClipboardChunk {
+                id: self.next_id,
+                seq: seq as u32,
+                total,
+                size: data.len() as u32,
+                data: chunk.to_vec(),
+            });
+        }
+        None
+    }
+
+    /// Next chunk to send, if any
+    pub fn next_chunk(&mut self) -> Option<tunnel::EventClipboardChunk> {
+        self.pending.pop_front()
+    }
+}
+
+/// Rebuilds clipboard values from their chunks
+#[derive(Default)]
+pub struct ClipboardAssembler {
+    /// Current transfer id, next expected chunk and announced size
+    transfer: Option<(u32, u32, usize)>,
+    data: Vec<u8>,
+}
+
+impl ClipboardAssembler {
+    /// Add a chunk, returns the clipboard event once all its chunks are received
+    ///
+    /// A chunk out of sequence drops the current transfer
+    pub fn push(
+        &mut self,
+        chunk: tunnel::EventClipboardChunk,
+    ) -> Result<Option<tunnel::EventClipboard>> {
+        if chunk.seq == 0 {
+            // Start of a new transfer, drop the incomplete one
+            self.data.clear();
+            self.transfer = None;
+            if chunk.size as usize > MAX_CLIPBOARD_TRANSFER {
+                return Err(anyhow!("Clipboard too large ({} bytes)", chunk.size));
+            }
+            self.transfer = Some((chunk.id, 0, chunk.size as usize));
+        }
+        let (next_seq, size) = match self.transfer.take() {
+            Some((id, next_seq, size)) if id == chunk.id && next_seq == chunk.seq => {
+                (next_seq + 1, size)
+            }
+            _ => {
+                self.data.clear();
+                return Err(anyhow!(
+                    "Unexpected clipboard chunk {}/{} of transfer {}",
+                    chunk.seq,
+                    chunk.total,
+                    chunk.id
+                ));
+            }
+        };
+        if self.data.len() + chunk.data.len() > size {
+            self.data.clear();
+            return Err(anyhow!("Clipboard chunks larger than announced"));
+        }
+        self.data.extend_from_slice(&chunk.data);
+        if next_seq < chunk.total {
+            self.transfer = Some((chunk.id, next_seq, size));
+            return Ok(None);
+        }
+
+        let data = std::mem::take(&mut self.data);
+        if data.len() != size {
+            return Err(anyhow!("Truncated clipboard transfer"));
+        }
+        let event =
+            tunnel::EventClipboard::decode(data.as_slice()).context("Cannot decode clipboard")?;
+        Ok(Some(event))
+    }
+}
+
+/// Holds the last clipboard value, shared between the clipboard listeners and
+/// the main loop
+///
+/// Listeners wake up the main loop on clipboard change, so the clipboard is
+/// forwarded without waiting for the next frame.
+#[derive(Clone, Default)]
+pub struct ClipboardNotifier {
+    inner: Arc<(Mutex<Option<ClipboardContent>>, Condvar)>,
+}
+
+impl ClipboardNotifier {
+    /// Store a new clipboard value and wake up the waiting loop
+    pub fn push(&self, data: ClipboardContent) {
+        let (lock, condvar) = &*self.inner;
+        *lock.lock().unwrap() = Some(data);
+        condvar.notify_all();
+    }
+
+    /// Retrieve the last clipboard value, if any
+    pub fn take(&self) -> Option<ClipboardContent> {
+        self.inner.0.lock().unwrap().take()
+    }
+
+    /// Wait until `deadline` or until a clipboard value is available
+    ///
+    /// Returns true if a clipboard value is available
+    pub fn wait_until(&self, deadline: Instant) -> bool {
+        let (lock, condvar) = &*self.inner;
+        let mut data = lock.lock().unwrap();
+        while data.is_none() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            data = condvar.wait_timeout(data, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+/// Number of cursors kept in the cursor caches
+pub const CURSOR_CACHE_SIZE: usize = 32;
+
+/// Cache id of a cursor image, never 0
+pub fn cursor_id(cursor: &tunnel::EventCursor) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (
+        &cursor.data,
+        cursor.width,
+        cursor.height,
+        cursor.xhot,
+        cursor.yhot,
+    )
+        .hash(&mut hasher);
+    hasher.finish().max(1)
+}
+
+/// Least recently used cursors, by cache id
+///
+/// The server and the client caches see the same insertions and lookups, so
+/// they evict the same cursors
+pub struct CursorCache<T> {
+    entries: VecDeque<(u64, T)>,
+}
+
+impl<T> Default for CursorCache<T> {
+    fn default() -> Self {
+        CursorCache {
+            entries: VecDeque::with_capacity(CURSOR_CACHE_SIZE),
+        }
+    }
+}
+
+impl<T> CursorCache<T> {
+    /// Cursor `id`, marked as the most recently used
+    pub fn get(&mut self, id: u64) -> Option<&T> {
+        let index = self
+            .entries
+            .iter()
+            .position(|(entry_id, _)| *entry_id == id)?;
+        let entry = self.entries.remove(index)?;
+        self.entries.push_back(entry);
+        self.entries.back().map(|(_, value)| value)
+    }
+
+    /// Add the cursor `id`, evicting the least recently used one if full
+    pub fn insert(&mut self, id: u64, value: T) {
+        self.entries.retain(|(entry_id, _)| *entry_id != id);
+        if self.entries.len() >= CURSOR_CACHE_SIZE {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((id, value));
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum ClipboardConfig {
     Allow,
@@ -37,6 +299,16 @@ Sanzu arguments can be set regarding this priority:
     )]
     pub args_config: Option<std::path::PathBuf>,
 
+    /// Configuration file overrides
+    #[clap(
+        long = "set",
+        value_name = "KEY=VALUE",
+        help = r"Override a key of the configuration file (ex: video.max_fps=30).
+Keys are also overridden by environment variables (ex: SANZU_VIDEO_MAX_FPS=30).
+Priority: command line, environment, configuration file"
+    )]
+    pub set: Vec<String>,
+
     /// Rest of arguments
     #[clap(flatten)]
     pub server_config: ServerArgsConfig,
@@ -66,6 +338,26 @@ pub struct ServerArgsConfig {
         help = "Use STDIO for communication layer"
     )]
     pub stdio: bool,
+    #[clap(
+        long,
+        help = "Use the already connected file descriptor for communication layer"
+    )]
+    pub fd: Option<i32>,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = r"Don't use tls, even if configured.
+Only allowed with --stdio / --fd, if the outer transport (ssh, ...)
+already secures the connection"
+    )]
+    pub skip_tls: bool,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = r"Carry the tunnel in WebSocket binary messages, for the browser
+clients (wss if tls is configured)"
+    )]
+    pub websocket: bool,
     #[clap(
         long,
         short = 'u',
@@ -140,6 +432,12 @@ tcp or vsock"
         help = "Disallow sending clipboard from server to client"
     )]
     pub restrict_clipboard: bool,
+    #[clap(
+        long,
+        default_value_t = DEFAULT_MAX_CLIPBOARD_IMAGE,
+        help = "Maximum size of clipboard images (bytes)"
+    )]
+    pub max_clipboard_image: usize,
     #[clap(
         long,
         short = 'z',
@@ -169,12 +467,92 @@ tcp or vsock"
     pub keep_listening: bool,
     #[clap(long, help = "Add connection timeout (seconds)")]
     pub connection_timeout: Option<u32>,
+    #[clap(long, help = "Maximum length of received messages (bytes)")]
+    pub max_msg_len: Option<usize>,
+    #[clap(
+        long,
+        help = "Maximum length of received messages holding frames (bytes)"
+    )]
+    pub max_frame_msg_len: Option<usize>,
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(i32).range(1..=22),
+        help = "Compress messages with zstd at this level (1-22), if the peer supports it"
+    )]
+    pub compression_level: Option<i32>,
+    #[clap(
+        long,
+        default_value_t = 1000,
+        value_parser = clap::value_parser!(u64).range(100..),
+        help = "Interval between two heartbeat pings (ms)"
+    )]
+    pub heartbeat_interval_ms: u64,
+    #[clap(
+        long,
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = r"Unanswered heartbeat pings after which the peer is declared dead
+and the session closed. Unless a connection timeout is set, this also bounds
+the time spent waiting for the peer"
+    )]
+    pub heartbeat_missed: u32,
     #[clap(long, help = "Displays protocol version")]
     pub proto: bool,
     #[clap(short='v', long, action = clap::ArgAction::Count)]
     pub verbose: u8,
     #[clap(long, default_value_t = 96, help = "Simulated dpi for server screen")]
     pub dpi: u32,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = r"Use the client display dpi, if known, instead of --dpi. It is also
+advertised to the applications through the Xft.dpi resource"
+    )]
+    pub client_dpi: bool,
+    #[clap(
+        long,
+        help = r"Capture only this x11 window (decimal id, see xwininfo -int)
+instead of the whole screen. The session ends when the window is destroyed"
+    )]
+    pub capture_window: Option<u32>,
+    #[clap(
+        long,
+        help = "Capture only this RandR output, by name (ex: DP-1) or index"
+    )]
+    pub output: Option<String>,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "Print the available RandR outputs and exit"
+    )]
+    pub list_outputs: bool,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = r"Capture a wayland session through xdg-desktop-portal and PipeWire
+(needs the wayland feature). The server keeps its resolution"
+    )]
+    pub wayland: bool,
+    #[cfg(feature = "record")]
+    #[clap(
+        long,
+        help = r"Record the session video and sound to this file (ex: session.mkv).
+The container is guessed from the extension. Only h264 and hevc are recorded"
+    )]
+    pub record: Option<String>,
+    #[cfg(feature = "record")]
+    #[clap(
+        long,
+        help = "Log the input and clipboard events of the recorded session to this file"
+    )]
+    pub record_events: Option<String>,
+    #[cfg(feature = "record")]
+    #[clap(
+        long,
+        help = r"Encrypt the recording and the events log for this x25519 public key file
+(generated by sanzu_recording keygen). The container must be streamable (ex: mkv)"
+    )]
+    pub record_key: Option<String>,
 }
 
 #[derive(Parser, Debug)]
@@ -191,6 +569,16 @@ Sanzu arguments can be set regarding this priority:
     )]
     pub args_config: Option<std::path::PathBuf>,
 
+    /// Configuration file overrides
+    #[clap(
+        long = "set",
+        value_name = "KEY=VALUE",
+        help = r"Override a key of the configuration file (ex: video.max_fps=30).
+Keys are also overridden by environment variables (ex: SANZU_VIDEO_MAX_FPS=30).
+Priority: command line, environment, configuration file"
+    )]
+    pub set: Vec<String>,
+
     /// Rest of arguments
     #[clap(flatten)]
     pub client_config: ClientArgsConfig,
@@ -217,6 +605,17 @@ pub struct ClientArgsConfig {
         help = "Use vsock for communication layer"
     )]
     pub vsock: bool,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "Use STDIO for communication layer"
+    )]
+    pub stdio: bool,
+    #[clap(
+        long,
+        help = "Use the already connected file descriptor for communication layer"
+    )]
+    pub fd: Option<i32>,
     #[clap(
         long,
         short = 'a',
@@ -233,6 +632,18 @@ pub struct ClientArgsConfig {
         help = "Audio buffer length (default 150ms)"
     )]
     pub audio_buffer_ms: u32,
+    #[clap(
+        long,
+        default_value_t = 1.0,
+        help = "Audio playback volume (default 1.0: unchanged)"
+    )]
+    pub audio_volume: f32,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "Forward the microphone to the server, if it accepts it"
+    )]
+    pub microphone: bool,
     #[cfg(feature = "kerberos")]
     #[clap(long, short = 'k', help = "Enable kerberos using server cname")]
     pub server_cname: Option<String>,
@@ -254,6 +665,11 @@ pub struct ClientArgsConfig {
     pub client_key: Option<String>,
     #[clap(long, short = 'l', help = "Use login/password to authenticate")]
     pub login: bool,
+    #[clap(
+        long,
+        help = "Authenticate with the OIDC token (JWT) read from this file"
+    )]
+    pub oidc_token_file: Option<String>,
     #[clap(
         long,
         short = 'q',
@@ -266,12 +682,51 @@ pub struct ClientArgsConfig {
 "#
     )]
     pub clipboard: String,
+    #[clap(
+        long,
+        default_value_t = DEFAULT_MAX_CLIPBOARD_IMAGE,
+        help = "Maximum size of clipboard images (bytes)"
+    )]
+    pub max_clipboard_image: usize,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "Request a view only session: the input is not forwarded to the server"
+    )]
+    pub view_only: bool,
     #[clap(
         long,
         short = 'w',
         help = "Client will be in window mode instead of fullscreen"
     )]
     pub window_mode: bool,
+    #[clap(
+        long,
+        default_value = "native",
+        help = r#"Frame scaling in the client window (ignored in seamless):
+ - native: one frame pixel per window pixel
+ - fit: scaled to the window, keeping the aspect ratio
+ - stretch: scaled to the window size
+         Shortcut to cycle the modes: Ctrl-Alt-Shift-D
+"#
+    )]
+    pub scale: String,
+    #[clap(
+        long,
+        help = r#"Chroma subsampling requested to the server (default: the server one, which
+may follow the frame content):
+ - 420: half resolution chroma, saves bandwidth (video playback)
+ - 444: full resolution chroma, keeps colored text legible
+         Shortcut to switch between them: Ctrl-Alt-Shift-Y
+"#
+    )]
+    pub chroma: Option<String>,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "Send every pointer motion with sub-pixel precision (XInput2)"
+    )]
+    pub smooth_pointer: bool,
     #[clap(
         long,
         short = 'd',
@@ -282,13 +737,23 @@ pub struct ClientArgsConfig {
         long,
         short = 'j',
         help = r#""Allow print order from serveur to local printing service.
-The argument is the local firectory base which contains files to print
+The argument is the local directory where the printed documents are saved
 Ex: -j c:\user\dupond\printdir\
 "#
     )]
     pub allow_print: Option<String>,
     #[clap(long, short = 'p', help = "Command to execute to establish connection")]
     pub proxycommand: Option<String>,
+    #[clap(
+        long,
+        help = r"Reach the server through a SOCKS5 or HTTP CONNECT proxy:
+socks5://[user:password@]host:port or http://[user:password@]host:port
+Set it in the environment (SANZU_CONNECT_PROXY) or the arguments file to keep
+the credentials out of the command line"
+    )]
+    pub connect_proxy: Option<String>,
+    #[clap(long, help = "Application to launch from the server catalog")]
+    pub launch_app: Option<String>,
     #[clap(long, short = 'g', help = "Synchronize caps/num/scroll lock")]
     pub sync_key_locks: bool,
     #[clap(
@@ -319,6 +784,55 @@ Shortcut to toggle grabbing: Ctrl-Alt-Shift-H"
     pub grab_keyboard: bool,
     #[clap(long, help = "Add connection timeout (seconds)")]
     pub connection_timeout: Option<u32>,
+    #[clap(
+        long,
+        default_value_t = 0,
+        help = r"Reconnection attempts after a connection loss (0: disabled).
+The window is kept during the attempts. Not available with stdio
+and file descriptor transports"
+    )]
+    pub reconnect_attempts: u32,
+    #[clap(
+        long,
+        default_value_t = 500,
+        help = "Delay before the first reconnection attempt, doubled at each attempt (ms)"
+    )]
+    pub reconnect_delay_ms: u64,
+    #[clap(
+        long,
+        default_value_t = 30000,
+        help = "Maximum delay between two reconnection attempts (ms)"
+    )]
+    pub reconnect_max_delay_ms: u64,
+    #[clap(long, help = "Maximum length of received messages (bytes)")]
+    pub max_msg_len: Option<usize>,
+    #[clap(
+        long,
+        help = "Maximum length of received messages holding frames (bytes)"
+    )]
+    pub max_frame_msg_len: Option<usize>,
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(i32).range(1..=22),
+        help = "Compress messages with zstd at this level (1-22), if the peer supports it"
+    )]
+    pub compression_level: Option<i32>,
+    #[clap(
+        long,
+        default_value_t = 1000,
+        value_parser = clap::value_parser!(u64).range(100..),
+        help = "Interval between two heartbeat pings (ms)"
+    )]
+    pub heartbeat_interval_ms: u64,
+    #[clap(
+        long,
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = r"Unanswered heartbeat pings after which the peer is declared dead
+and the session closed. Unless a connection timeout is set, this also bounds
+the time spent waiting for the peer"
+    )]
+    pub heartbeat_missed: u32,
     #[clap(long, help = "Displays protocol version")]
     pub proto: bool,
     #[clap(short='v', long, action = clap::ArgAction::Count)]
@@ -339,6 +853,16 @@ Sanzu arguments can be set regarding this priority:
     )]
     pub args_config: Option<std::path::PathBuf>,
 
+    /// Configuration file overrides
+    #[clap(
+        long = "set",
+        value_name = "KEY=VALUE",
+        help = r"Override a key of the configuration file (ex: video.max_fps=30).
+Keys are also overridden by environment variables (ex: SANZU_VIDEO_MAX_FPS=30).
+Priority: command line, environment, configuration file"
+    )]
+    pub set: Vec<String>,
+
     /// Rest of arguments
     #[clap(flatten)]
     pub proxy_config: ProxyArgsConfig,
@@ -398,6 +922,20 @@ pub struct ProxyArgsConfig {
     pub extern_img_source: Option<String>,
     #[clap(long, short = 'y', help = "Video source is xwd formated")]
     pub source_is_xwd: bool,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "Relay a server sending encoded video. The connection is forwarded as is"
+    )]
+    pub relay: bool,
+    #[clap(
+        long,
+        default_value_t = false,
+        requires = "relay",
+        conflicts_with = "extern_img_source",
+        help = "Decode the relayed video and encode it again with the proxy encoder"
+    )]
+    pub transcode: bool,
     #[clap(
         long,
         short = 'd',
@@ -419,6 +957,18 @@ pub struct ProxyArgsConfig {
     pub keep_listening: bool,
     #[clap(long, help = "Add connection timeout (seconds)")]
     pub connection_timeout: Option<u32>,
+    #[clap(
+        long,
+        help = "Accept up to N view only clients watching the session of the first client"
+    )]
+    pub max_viewers: Option<usize>,
+    #[clap(long, help = "Maximum length of received messages (bytes)")]
+    pub max_msg_len: Option<usize>,
+    #[clap(
+        long,
+        help = "Maximum length of received messages holding frames (bytes)"
+    )]
+    pub max_frame_msg_len: Option<usize>,
     #[clap(long, help = "Displays protocol version")]
     pub proto: bool,
     #[clap(short='v', long, action = clap::ArgAction::Count)]
@@ -440,8 +990,14 @@ const OFFSET_WINDOW_WIDTH: usize = 20;
 const OFFSET_WINDOW_HEIGHT: usize = 21;
 
 /// Get offset of pixels data from an Xwd image
+///
+/// The image is written by another process: the header values are checked
+/// against the `data` length before use.
 pub fn get_xwd_data(data: &[u8]) -> Result<(&[u8], u32, u32, u32)> {
-    let header = data.get(0..0x100).expect("Cannot get source header");
+    let header = data.get(0..0x100).context(format!(
+        "Source too small for a header ({} bytes)",
+        data.len()
+    ))?;
     let header_size = BigEndian::read_u32(&header[0..4]);
     if header_size > MAX_HEADER_SIZE {
         return Err(anyhow!(format!("Strange header size {header_size:x}")));
@@ -471,16 +1027,54 @@ pub fn get_xwd_data(data: &[u8]) -> Result<(&[u8], u32, u32, u32)> {
         return Err(anyhow!(format!("Strange window_y {window_y:x}")));
     }
 
-    let offset = header_size + ncolors * 0xc;
-    let size = bytes_per_line * window_y;
+    let offset = header_size as usize + ncolors as usize * 0xc;
+    let size = bytes_per_line as usize * window_y as usize;
     trace!("Grab from extern source {:?}", size);
-    Ok((
-        data.get(offset as usize..(offset + size) as usize)
-            .expect("Cannot get source"),
-        window_x,
+    let pixels = data.get(offset..offset + size).context(format!(
+        "Image of {} lines of {} bytes at {:#x} out of the source ({} bytes)",
         window_y,
         bytes_per_line,
-    ))
+        offset,
+        data.len()
+    ))?;
+    Ok((pixels, window_x, window_y, bytes_per_line))
+}
+
+/// Copy the rgba `region` into the `dst` rgba frame of size `dst_width`x`dst_height`
+pub fn blit_region(
+    dst: &mut [u8],
+    dst_width: u32,
+    dst_height: u32,
+    region: &tunnel::ImageRawRegion,
+) -> Result<()> {
+    let (x, y, width, height) = (
+        region.x as usize,
+        region.y as usize,
+        region.width as usize,
+        region.height as usize,
+    );
+    if x + width > dst_width as usize || y + height > dst_height as usize {
+        return Err(anyhow!(
+            "Region {}x{} at ({}, {}) out of frame {}x{}",
+            width,
+            height,
+            x,
+            y,
+            dst_width,
+            dst_height
+        ));
+    }
+    let row_len = width * 4;
+    if region.data.len() != row_len * height
+        || dst.len() < dst_width as usize * dst_height as usize * 4
+    {
+        return Err(anyhow!("Bad region data size"));
+    }
+    for (row, src) in region.data.chunks_exact(row_len).enumerate() {
+        let offset = ((y + row) * dst_width as usize + x) * 4;
+        dst[offset..offset + row_len].copy_from_slice(src);
+    }
+    Ok(())
 }
 
 /// Logger uses env var to set default log level
@@ -528,6 +1122,179 @@ pub fn is_proto_arg() -> bool {
     false
 }
 
+/// Set the maximum length of received messages, or use defaults
+pub fn init_max_msg_len(max_msg_len: Option<usize>, max_frame_msg_len: Option<usize>) {
+    sanzu_common::proto::set_max_msg_len(
+        max_msg_len.unwrap_or(sanzu_common::proto::DEFAULT_MAX_MSG_LEN),
+        max_frame_msg_len.unwrap_or(sanzu_common::proto::DEFAULT_MAX_FRAME_MSG_LEN),
+    );
+}
+
+impl From<sanzu_common::tunnel::ColorSpace> for YuvType {
+    fn from(color_space: sanzu_common::tunnel::ColorSpace) -> Self {
+        match color_space {
+            sanzu_common::tunnel::ColorSpace::ItuT871 => YuvType::ItuT871,
+            sanzu_common::tunnel::ColorSpace::ItuR601 => YuvType::ItuR601,
+            sanzu_common::tunnel::ColorSpace::ItuR709 => YuvType::ItuR709,
+            sanzu_common::tunnel::ColorSpace::ItuR2020 => YuvType::ItuR2020,
+        }
+    }
+}
+
+impl From<YuvType> for sanzu_common::tunnel::ColorSpace {
+    fn from(yuv_type: YuvType) -> Self {
+        match yuv_type {
+            YuvType::ItuT871 => sanzu_common::tunnel::ColorSpace::ItuT871,
+            YuvType::ItuR601 => sanzu_common::tunnel::ColorSpace::ItuR601,
+            YuvType::ItuR709 => sanzu_common::tunnel::ColorSpace::ItuR709,
+            YuvType::ItuR2020 => sanzu_common::tunnel::ColorSpace::ItuR2020,
+        }
+    }
+}
+
+impl From<ColorRange> for sanzu_common::tunnel::ColorRange {
+    fn from(color_range: ColorRange) -> Self {
+        match color_range {
+            ColorRange::Limited => sanzu_common::tunnel::ColorRange::Limited,
+            ColorRange::Full => sanzu_common::tunnel::ColorRange::Full,
+        }
+    }
+}
+
+/// Get the negotiated color matrix and range. An unspecified range is the
+/// one defined by the matrix.
+pub fn negotiated_color_space(
+    color_space: sanzu_common::tunnel::ColorSpace,
+    color_range: sanzu_common::tunnel::ColorRange,
+) -> (YuvType, ColorRange) {
+    let yuv_type = YuvType::from(color_space);
+    let color_range = match color_range {
+        sanzu_common::tunnel::ColorRange::Unspecified => yuv_type.default_range(),
+        sanzu_common::tunnel::ColorRange::Limited => ColorRange::Limited,
+        sanzu_common::tunnel::ColorRange::Full => ColorRange::Full,
+    };
+    (yuv_type, color_range)
+}
+
+impl From<Chroma> for tunnel::Chroma {
+    fn from(chroma: Chroma) -> Self {
+        match chroma {
+            Chroma::Yuv420 => tunnel::Chroma::Yuv420,
+            Chroma::Yuv444 => tunnel::Chroma::Yuv444,
+        }
+    }
+}
+
+/// Get the negotiated chroma subsampling, None leaving the encoder pixel
+/// format
+pub fn negotiated_chroma(chroma: tunnel::Chroma) -> Option<Chroma> {
+    match chroma {
+        tunnel::Chroma::Unspecified => None,
+        tunnel::Chroma::Yuv420 => Some(Chroma::Yuv420),
+        tunnel::Chroma::Yuv444 => Some(Chroma::Yuv444),
+    }
+}
+
+impl From<AudioCodec> for tunnel::AudioCodec {
+    fn from(codec: AudioCodec) -> Self {
+        match codec {
+            AudioCodec::Opus => tunnel::AudioCodec::Opus,
+            AudioCodec::Raw => tunnel::AudioCodec::Raw,
+        }
+    }
+}
+
+impl From<ClipboardDirection> for tunnel::ClipboardDirection {
+    fn from(direction: ClipboardDirection) -> Self {
+        match direction {
+            ClipboardDirection::Both => tunnel::ClipboardDirection::Both,
+            ClipboardDirection::ToServer => tunnel::ClipboardDirection::ToServer,
+            ClipboardDirection::ToClient => tunnel::ClipboardDirection::ToClient,
+            ClipboardDirection::None => tunnel::ClipboardDirection::None,
+        }
+    }
+}
+
+impl From<tunnel::ClipboardDirection> for ClipboardDirection {
+    fn from(direction: tunnel::ClipboardDirection) -> Self {
+        match direction {
+            tunnel::ClipboardDirection::Both => ClipboardDirection::Both,
+            tunnel::ClipboardDirection::ToServer => ClipboardDirection::ToServer,
+            tunnel::ClipboardDirection::ToClient => ClipboardDirection::ToClient,
+            tunnel::ClipboardDirection::None => ClipboardDirection::None,
+        }
+    }
+}
+
+/// Clipboard policy advertised by the server in its hello
+pub fn server_clipboard_policy(hello: &tunnel::ServerHello) -> ClipboardPolicy {
+    ClipboardPolicy {
+        clipboard: hello.clipboard_policy().into(),
+        primary: hello.primary_policy().into(),
+    }
+}
+
+/// Get the negotiated audio codec: the one proposed by the server if the
+/// client accepted it, else opus, which all clients decode.
+pub fn negotiated_audio_codec(
+    proposed: tunnel::AudioCodec,
+    accepted: tunnel::AudioCodec,
+) -> tunnel::AudioCodec {
+    if proposed == accepted {
+        accepted
+    } else {
+        tunnel::AudioCodec::Opus
+    }
+}
+
+/// Get the base capabilities of this peer: the supported audio codecs. The
+/// optional features are disabled, the caller enables the available ones.
+pub fn local_capabilities() -> tunnel::Capabilities {
+    tunnel::Capabilities {
+        audio_codecs: vec![
+            tunnel::AudioCodec::Opus as i32,
+            tunnel::AudioCodec::Raw as i32,
+        ],
+        ..Default::default()
+    }
+}
+
+/// Get the capabilities supported by both peers
+pub fn negotiated_capabilities(
+    local: &tunnel::Capabilities,
+    peer: &tunnel::Capabilities,
+) -> tunnel::Capabilities {
+    tunnel::Capabilities {
+        audio_codecs: local
+            .audio_codecs
+            .iter()
+            .filter(|codec| peer.audio_codecs.contains(codec))
+            .cloned()
+            .collect(),
+        audio: local.audio && peer.audio,
+        clipboard_image: local.clipboard_image && peer.clipboard_image,
+        relative_mouse: local.relative_mouse && peer.relative_mouse,
+        compression: local.compression && peer.compression,
+        touch: local.touch && peer.touch,
+        pen: local.pen && peer.pen,
+        adaptive_chroma: local.adaptive_chroma && peer.adaptive_chroma,
+        // Either peer can restrict the session
+        view_only: local.view_only || peer.view_only,
+    }
+}
+
+/// Get the sound format played by the client. Clients which do not send
+/// their channel count play stereo.
+pub fn client_audio_format(sample_rate: u32, channels: u32) -> AudioFormat {
+    AudioFormat {
+        sample_rate,
+        channels: match channels {
+            0 => 2,
+            channels => channels as u16,
+        },
+    }
+}
+
 pub trait HasTimeout {
     /// Set Read/Write timeout on socket
     fn set_connection_timeout(&self, timeout: Option<Duration>) -> Result<()>;
@@ -584,3 +1351,406 @@ pub fn set_tcp_timeout(socket_ref: socket2::SockRef, timeout: Option<Duration>)
     }
     Ok(())
 }
+
+/// Reference dpi of a display without scaling
+pub const DEFAULT_DPI: u32 = 96;
+
+/// Value of the `Xft.dpi` entry of the x11 resources database string
+pub fn xft_dpi(resources: &str) -> Option<u32> {
+    resources.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.trim() != "Xft.dpi" {
+            return None;
+        }
+        value
+            .trim()
+            .parse::<f32>()
+            .ok()
+            .map(|dpi| dpi.round() as u32)
+    })
+}
+
+/// Set the `Xft.dpi` entry of the x11 resources database string
+pub fn with_xft_dpi(resources: &str, dpi: u32) -> String {
+    let mut output: String = resources
+        .lines()
+        .filter(|line| {
+            line.split_once(':')
+                .map_or(true, |(name, _)| name.trim() != "Xft.dpi")
+        })
+        .map(|line| format!("{line}\n"))
+        .collect();
+    output += &format!("Xft.dpi:\t{dpi}\n");
+    output
+}
+
+/// Keyboard layout of the first xkb group, from the value of the
+/// `_XKB_RULES_NAMES` x11 root property (rules, model, layouts, variants and
+/// options, separated by nul bytes): "layout" or "layout(variant)"
+pub fn xkb_layout(rules_names: &[u8]) -> Option<String> {
+    let mut names = rules_names.split(|byte| *byte == 0).skip(2);
+    let layouts = String::from_utf8_lossy(names.next()?);
+    let variants = String::from_utf8_lossy(names.next().unwrap_or_default());
+    let layout = layouts.split(',').next()?.trim();
+    if layout.is_empty() {
+        return None;
+    }
+    match variants.split(',').next().map(str::trim) {
+        Some(variant) if !variant.is_empty() => Some(format!("{layout}({variant})")),
+        _ => Some(layout.to_owned()),
+    }
+}
+
+/// Returns a warning if the client and the server keyboard layouts differ:
+/// the keycodes sent by the client are then typed as other characters. Only
+/// the layouts are compared, the variants being often unknown.
+pub fn keyboard_layout_mismatch(client: Option<&str>, server: &str) -> Option<String> {
+    fn base(layout: &str) -> &str {
+        layout.split('(').next().unwrap_or(layout).trim()
+    }
+    match client {
+        Some(client) if !server.is_empty() && base(client) != base(server) => Some(format!(
+            "Keyboard layout: server {}, client {}",
+            server, client
+        )),
+        _ => None,
+    }
+}
+
+/// Default maximum length of the notification texts, in characters
+pub const DEFAULT_NOTIFICATION_MAX_LENGTH: usize = 1024;
+
+/// Make a notification text safe to display and log: line breaks and tabs
+/// become spaces, the other control characters (NUL, escape sequences, ...)
+/// and the bidirectional overrides are removed. The text is truncated to
+/// `max_length` characters, ellipsis included.
+pub fn sanitize_notification(text: &str, max_length: usize) -> String {
+    let mut chars = text.chars().filter_map(|c| match c {
+        '\n' | '\r' | '\t' => Some(' '),
+        c if c.is_control() => None,
+        '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}' => None,
+        c => Some(c),
+    });
+    let mut output: String = chars.by_ref().take(max_length).collect();
+    if chars.next().is_some() && output.pop().is_some() {
+        output.push('\u{2026}');
+    }
+    output
+}
+
+/// Use the already connected file descriptor `fd` (socket, pipe, ...) as
+/// communication layer
+#[cfg(unix)]
+pub fn stream_from_fd(fd: i32) -> Result<std::fs::File> {
+    use std::os::unix::io::FromRawFd;
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(std::io::Error::last_os_error()).context(format!("Bad file descriptor {fd}"));
+    }
+    // The fd is valid, and is owned by the returned stream from now on
+    Ok(unsafe { std::fs::File::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_cursor_cache() {
+        let mut cache = CursorCache::default();
+        for id in 1..=CURSOR_CACHE_SIZE as u64 {
+            cache.insert(id, id * 10);
+        }
+        // Using the oldest cursor keeps it in the cache
+        assert_eq!(cache.get(1), Some(&10));
+        cache.insert(100, 1000);
+        assert_eq!(cache.get(2), None);
+        assert_eq!(cache.get(1), Some(&10));
+        assert_eq!(cache.get(100), Some(&1000));
+
+        let cursor = tunnel::EventCursor {
+            data: vec![0xff; 16],
+            width: 2,
+            height: 2,
+            ..Default::default()
+        };
+        let moved = tunnel::EventCursor {
+            xhot: 1,
+            ..cursor.clone()
+        };
+        assert_eq!(cursor_id(&cursor), cursor_id(&cursor.clone()));
+        assert_ne!(cursor_id(&cursor), cursor_id(&moved));
+    }
+
+    #[test]
+    fn test_xft_dpi() {
+        let resources = "Xcursor.size:\t24\nXft.dpi:\t144\nXft.antialias:\t1\n";
+        assert_eq!(xft_dpi(resources), Some(144));
+        assert_eq!(xft_dpi("Xft.dpi: 120.5\n"), Some(121));
+        assert_eq!(xft_dpi("Xcursor.size:\t24\n"), None);
+        assert_eq!(
+            with_xft_dpi(resources, 192),
+            "Xcursor.size:\t24\nXft.antialias:\t1\nXft.dpi:\t192\n"
+        );
+        assert_eq!(with_xft_dpi("", 96), "Xft.dpi:\t96\n");
+    }
+
+    #[test]
+    fn test_xkb_layout() {
+        assert_eq!(
+            xkb_layout(b"evdev\0pc105\0fr\0azerty\0\0").as_deref(),
+            Some("fr(azerty)")
+        );
+        assert_eq!(
+            xkb_layout(b"evdev\0pc105\0us,ru\0,phonetic\0grp:alt_shift_toggle\0").as_deref(),
+            Some("us")
+        );
+        assert_eq!(xkb_layout(b"evdev\0pc105\0de").as_deref(), Some("de"));
+        assert_eq!(xkb_layout(b"evdev\0pc105\0\0\0\0"), None);
+        assert_eq!(xkb_layout(b""), None);
+
+        assert!(keyboard_layout_mismatch(Some("fr"), "fr(azerty)").is_none());
+        assert!(keyboard_layout_mismatch(None, "fr").is_none());
+        assert!(keyboard_layout_mismatch(Some("us"), "").is_none());
+        assert_eq!(
+            keyboard_layout_mismatch(Some("us"), "fr(azerty)").as_deref(),
+            Some("Keyboard layout: server fr(azerty), client us")
+        );
+    }
+
+    #[test]
+    fn test_sanitize_notification() {
+        assert_eq!(
+            sanitize_notification("New mail\nfrom\tBob", 100),
+            "New mail from Bob"
+        );
+        assert_eq!(
+            sanitize_notification("a\0b\x1b[31mred\x1b[0m\u{7}\u{9b}c", 100),
+            "ab[31mred[0mc"
+        );
+        assert_eq!(sanitize_notification("\u{202e}gpj.exe", 100), "gpj.exe");
+        assert_eq!(sanitize_notification("été", 3), "été");
+
+        let long = "x".repeat(100 * 1024);
+        let text = sanitize_notification(&long, DEFAULT_NOTIFICATION_MAX_LENGTH);
+        assert_eq!(text.chars().count(), DEFAULT_NOTIFICATION_MAX_LENGTH);
+        assert!(text.ends_with('\u{2026}'));
+        assert_eq!(sanitize_notification(&long, 0), "");
+    }
+
+    #[test]
+    fn test_clipboard_during_stall() {
+        let notifier = ClipboardNotifier::default();
+        let listener = notifier.clone();
+        // No new frame: the loop waits for the next frame deadline
+        let start = Instant::now();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            listener.push(ClipboardContent::Text("copied".to_owned()));
+        });
+        assert!(notifier.wait_until(start + Duration::from_secs(10)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            notifier.take(),
+            Some(ClipboardContent::Text("copied".to_owned()))
+        );
+        assert_eq!(notifier.take(), None);
+        handle.join().unwrap();
+
+        assert!(!notifier.wait_until(Instant::now() + Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn test_clipboard_image() {
+        let mut png = PNG_SIGNATURE.to_vec();
+        png.extend_from_slice(&[0, 0, 0, 13]);
+        png.extend_from_slice(b"IHDR");
+        png.extend_from_slice(&[0, 0, 1, 0, 0, 0, 0, 0x80]);
+        assert_eq!(png_size(&png), Some((256, 128)));
+        assert_eq!(png_size(&png[..20]), None);
+        assert_eq!(png_size(b"not a png image at all!!"), None);
+
+        let content = clipboard_png(png.clone()).unwrap();
+        let event = tunnel::EventClipboard {
+            content: Some(content),
+        };
+        assert!(clipboard_allowed(&event, png.len()));
+        assert!(!clipboard_allowed(&event, png.len() - 1));
+
+        let event = tunnel::EventClipboard {
+            content: Some(ClipboardContent::Text("text".to_owned())),
+        };
+        assert!(clipboard_allowed(&event, 0));
+        assert!(clipboard_png(b"text".to_vec()).is_none());
+    }
+
+    #[test]
+    fn test_clipboard_chunks() {
+        let text: String = (0..10 * 1024 * 1024)
+            .map(|index| (b'a' + (index % 26) as u8) as char)
+            .collect();
+        let event = tunnel::EventClipboard {
+            content: Some(ClipboardContent::Text(text)),
+        };
+        let mut chunker = ClipboardChunker::default();
+        let mut assembler = ClipboardAssembler::default();
+        assert!(chunker.push(event.clone()).is_none());
+
+        let mut chunks = vec![];
+        while let Some(chunk) = chunker.next_chunk() {
+            assert!(chunk.data.len() <= CLIPBOARD_CHUNK_SIZE);
+            chunks.push(chunk);
+        }
+        assert_eq!(chunks.len(), chunks[0].total as usize);
+        assert!(chunks.len() > 1);
+
+        let (last, chunks) = chunks.split_last().unwrap();
+        for chunk in chunks.iter() {
+            assert_eq!(assembler.push(chunk.clone()).unwrap(), None);
+        }
+        assert_eq!(assembler.push(last.clone()).unwrap(), Some(event.clone()));
+
+        // Missing chunk
+        chunker.push(event.clone());
+        let first = chunker.next_chunk().unwrap();
+        chunker.next_chunk().unwrap();
+        let third = chunker.next_chunk().unwrap();
+        assert_eq!(assembler.push(first).unwrap(), None);
+        assert!(assembler.push(third).is_err());
+
+        // Small values are not split
+        let small = tunnel::EventClipboard {
+            content: Some(ClipboardContent::Text("small".to_owned())),
+        };
+        assert_eq!(chunker.push(small.clone()), Some(small));
+        assert!(chunker.next_chunk().is_none());
+    }
+
+    #[test]
+    fn test_negotiated_capabilities() {
+        let server = tunnel::Capabilities {
+            audio: true,
+            relative_mouse: true,
+            touch: true,
+            adaptive_chroma: true,
+            ..local_capabilities()
+        };
+        let client = tunnel::Capabilities {
+            audio_codecs: vec![tunnel::AudioCodec::Opus as i32],
+            audio: true,
+            clipboard_image: true,
+            view_only: true,
+            ..Default::default()
+        };
+        let negotiated = negotiated_capabilities(&server, &client);
+        assert_eq!(negotiated, negotiated_capabilities(&client, &server));
+        assert_eq!(
+            negotiated,
+            tunnel::Capabilities {
+                audio_codecs: vec![tunnel::AudioCodec::Opus as i32],
+                audio: true,
+                view_only: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    /// Xwd image of `width`x`height` pixels with `bytes_per_line`, truncated
+    /// to `size` bytes
+    fn xwd_image(width: u32, height: u32, bytes_per_line: u32, size: usize) -> Vec<u8> {
+        let header_size = 0x64;
+        let mut data = vec![0u8; size];
+        let mut write = |index: usize, value: u32| {
+            if let Some(field) = data.get_mut(index * 4..(index + 1) * 4) {
+                BigEndian::write_u32(field, value);
+            }
+        };
+        write(0, header_size);
+        write(OFFSET_BYTES_PER_LINE, bytes_per_line);
+        write(OFFSET_NCOLORS, 0);
+        write(OFFSET_WINDOW_WIDTH, width);
+        write(OFFSET_WINDOW_HEIGHT, height);
+        data
+    }
+
+    #[test]
+    fn test_xwd_data() {
+        let data = xwd_image(16, 8, 64, 0x64 + 64 * 8);
+        let (pixels, width, height, bytes_per_line) = get_xwd_data(&data).unwrap();
+        assert_eq!(
+            (pixels.len(), width, height, bytes_per_line),
+            (64 * 8, 16, 8, 64)
+        );
+
+        // Truncated pixels
+        let data = xwd_image(16, 8, 64, 0x64 + 64 * 8 - 1);
+        assert!(get_xwd_data(&data).is_err());
+        // Truncated header
+        let data = xwd_image(16, 8, 64, 0x40);
+        assert!(get_xwd_data(&data).is_err());
+        // Oversized dimensions
+        let data = xwd_image(16, MAX_WINDOW_HEIGHT + 1, 64, 0x64 + 64 * 8);
+        assert!(get_xwd_data(&data).is_err());
+        let data = xwd_image(16, 8, MAX_BYTES_PER_LINE + 4, 0x64 + 64 * 8);
+        assert!(get_xwd_data(&data).is_err());
+        let data = xwd_image(16, MAX_WINDOW_HEIGHT, MAX_BYTES_PER_LINE, 0x64 + 64 * 8);
+        assert!(get_xwd_data(&data).is_err());
+    }
+
+    #[test]
+    fn test_blit_region() {
+        let mut frame = vec![0u8; 4 * 3 * 4];
+        let region = tunnel::ImageRawRegion {
+            x: 1,
+            y: 1,
+            width: 2,
+            height: 2,
+            data: vec![0xff; 2 * 2 * 4],
+        };
+        blit_region(&mut frame, 4, 3, &region).unwrap();
+        for (index, pixel) in frame.chunks_exact(4).enumerate() {
+            let (x, y) = (index % 4, index / 4);
+            let inside = (1..3).contains(&x) && (1..3).contains(&y);
+            assert_eq!(pixel[0] == 0xff, inside);
+        }
+
+        let region = tunnel::ImageRawRegion { x: 3, ..region };
+        assert!(blit_region(&mut frame, 4, 3, &region).is_err());
+        let region = tunnel::ImageRawRegion {
+            x: 0,
+            data: vec![0; 3],
+            ..region
+        };
+        assert!(blit_region(&mut frame, 4, 3, &region).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stream_from_fd() {
+        use sanzu_common::{
+            proto::{recv_client_msg_or_error, recv_server_msg_or_error, VERSION},
+            tunnel, ReadWrite, Tunnel,
+        };
+        use std::os::unix::{io::IntoRawFd, net::UnixStream};
+
+        let (local, mut peer) = UnixStream::pair().unwrap();
+        let mut stream: Box<dyn ReadWrite> = Box::new(stream_from_fd(local.into_raw_fd()).unwrap());
+        let version = tunnel::Version {
+            version: VERSION.to_owned(),
+        };
+
+        // The tunnel messages go both ways through the fd
+        send_client_msg_type!(&mut stream, version.clone(), Version).unwrap();
+        let received: tunnel::Version = recv_client_msg_type!(&mut peer, Version).unwrap();
+        assert_eq!(received, version);
+        send_server_msg_type!(&mut peer, version.clone(), Version).unwrap();
+        let received: tunnel::Version = recv_server_msg_type!(&mut stream, Version).unwrap();
+        assert_eq!(received, version);
+
+        // The stream owns the fd: the peer sees the connection closed
+        drop(stream);
+        assert!(recv_client_msg_type!(&mut peer, Version).is_err());
+
+        assert!(stream_from_fd(-1).is_err());
+    }
+}
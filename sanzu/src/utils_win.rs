@@ -1,3 +1,15 @@
+use crate::utils::{clipboard_png, ClipboardContent};
+use anyhow::{Context, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use clipboard_win::{formats, get_clipboard, set_clipboard};
+use std::{ffi::CStr, os::raw::c_char};
+use winapi::um::winuser::{GetKeyboardLayoutNameA, KL_NAMELENGTH};
+
+/// Size of a BITMAPINFOHEADER
+const BITMAPINFOHEADER_SIZE: usize = 40;
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+
 /// Transform PS2 code to hardware keycode
 /// PS2 codes:
 /// https://commons.wikimedia.org/wiki/File:Ps2_de_keyboard_scancode_set_1.svg
@@ -261,3 +273,202 @@ pub fn hardware_keycode_to_windows_scancode(hw_keycode: u32) -> Option<(u16, boo
     };
     Some((keycode as u16, extended))
 }
+
+/// Windows keyboard layout identifiers (KLID) and their xkb layout
+const KEYBOARD_LAYOUTS: &[(&str, &str)] = &[
+    ("00000409", "us"),
+    ("0000040C", "fr"),
+    ("00000407", "de"),
+    ("00000809", "gb"),
+    ("0000040A", "es"),
+    ("0000080A", "latam"),
+    ("00000410", "it"),
+    ("00000816", "pt"),
+    ("00000416", "br"),
+    ("0000080C", "be"),
+    ("00000807", "ch"),
+    ("0000100C", "ch(fr)"),
+    ("00000419", "ru"),
+    ("00000411", "jp"),
+];
+
+/// Xkb layout of a windows keyboard layout identifier, None if unknown
+pub fn klid_layout(klid: &str) -> Option<&'static str> {
+    KEYBOARD_LAYOUTS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(klid))
+        .map(|(_, layout)| *layout)
+}
+
+/// Keyboard layout of the calling thread, in the xkb syntax, None if unknown
+pub fn keyboard_layout() -> Option<String> {
+    let mut name = [0 as c_char; KL_NAMELENGTH as usize];
+    if unsafe { GetKeyboardLayoutNameA(name.as_mut_ptr()) } == 0 {
+        return None;
+    }
+    let klid = unsafe { CStr::from_ptr(name.as_ptr()) }.to_string_lossy();
+    klid_layout(&klid).map(str::to_owned)
+}
+
+/// Convert a CF_DIB clipboard bitmap (24 or 32 bits) to a png image
+pub fn dib_to_png(dib: &[u8]) -> Result<Vec<u8>> {
+    if dib.len() < BITMAPINFOHEADER_SIZE {
+        return Err(anyhow!("Bitmap too short"));
+    }
+    let header_size = LittleEndian::read_u32(&dib[0..4]) as usize;
+    let width = LittleEndian::read_i32(&dib[4..8]);
+    let height = LittleEndian::read_i32(&dib[8..12]);
+    let bit_count = LittleEndian::read_u16(&dib[14..16]);
+    let compression = LittleEndian::read_u32(&dib[16..20]);
+    let colors_used = LittleEndian::read_u32(&dib[32..36]) as usize;
+    if width <= 0 || height == 0 {
+        return Err(anyhow!("Bad bitmap size {}x{}", width, height));
+    }
+    if bit_count != 24 && bit_count != 32 {
+        return Err(anyhow!("Unsupported bitmap depth {}", bit_count));
+    }
+    let mut offset = header_size + colors_used * 4;
+    match compression {
+        BI_RGB => {}
+        // Color masks follow a BITMAPINFOHEADER
+        BI_BITFIELDS if header_size == BITMAPINFOHEADER_SIZE => offset += 12,
+        BI_BITFIELDS => {}
+        _ => return Err(anyhow!("Unsupported bitmap compression {}", compression)),
+    }
+
+    // Positive height means bottom-up rows
+    let bottom_up = height > 0;
+    let (width, height) = (width as usize, height.unsigned_abs() as usize);
+    let bytes_per_pixel = bit_count as usize / 8;
+    let stride = (width * bytes_per_pixel + 3) & !3;
+    let pixels = stride
+        .checked_mul(height)
+        .and_then(|size| dib.get(offset..offset.checked_add(size)?))
+        .context("Bitmap too short")?;
+
+    let mut rgba = Vec::with_capacity(width * height * 4);
+    for row in 0..height {
+        let row = if bottom_up { height - 1 - row } else { row };
+        let line = &pixels[row * stride..row * stride + width * bytes_per_pixel];
+        for pixel in line.chunks_exact(bytes_per_pixel) {
+            // Alpha is not reliable in clipboard bitmaps, output opaque pixels
+            rgba.extend_from_slice(&[pixel[2], pixel[1], pixel[0], 0xff]);
+        }
+    }
+
+    let mut png_data = vec![];
+    let mut encoder = png::Encoder::new(&mut png_data, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().context("Error in png header")?;
+    writer
+        .write_image_data(&rgba)
+        .context("Error in png encode")?;
+    writer.finish().context("Error in png finish")?;
+    Ok(png_data)
+}
+
+/// Convert a png image to a 32 bits CF_DIB clipboard bitmap
+pub fn png_to_dib(png_data: &[u8]) -> Result<Vec<u8>> {
+    let mut decoder = png::Decoder::new(png_data);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().context("Error in png header")?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader
+        .next_frame(&mut buffer)
+        .context("Error in png decode")?;
+    let (width, height) = (info.width as usize, info.height as usize);
+    let channels = info.color_type.samples();
+
+    let mut dib = vec![0u8; BITMAPINFOHEADER_SIZE];
+    LittleEndian::write_u32(&mut dib[0..4], BITMAPINFOHEADER_SIZE as u32);
+    LittleEndian::write_i32(&mut dib[4..8], info.width as i32);
+    LittleEndian::write_i32(&mut dib[8..12], info.height as i32);
+    LittleEndian::write_u16(&mut dib[12..14], 1);
+    LittleEndian::write_u16(&mut dib[14..16], 32);
+    LittleEndian::write_u32(&mut dib[16..20], BI_RGB);
+    LittleEndian::write_u32(&mut dib[20..24], (width * height * 4) as u32);
+
+    dib.reserve(width * height * 4);
+    // Bottom-up rows
+    for row in (0..height).rev() {
+        let line = &buffer[row * info.line_size..row * info.line_size + width * channels];
+        for pixel in line.chunks_exact(channels) {
+            let (r, g, b, a) = match channels {
+                1 => (pixel[0], pixel[0], pixel[0], 0xff),
+                2 => (pixel[0], pixel[0], pixel[0], pixel[1]),
+                3 => (pixel[0], pixel[1], pixel[2], 0xff),
+                _ => (pixel[0], pixel[1], pixel[2], pixel[3]),
+            };
+            dib.extend_from_slice(&[b, g, r, a]);
+        }
+    }
+    Ok(dib)
+}
+
+/// Current clipboard value: text, or bitmap converted to png
+pub fn get_clipboard_content() -> Option<ClipboardContent> {
+    if let Ok(text) = get_clipboard(formats::Unicode) {
+        return Some(ClipboardContent::Text(text));
+    }
+    let dib: Vec<u8> = get_clipboard(formats::RawData(formats::CF_DIB)).ok()?;
+    match dib_to_png(&dib) {
+        Ok(png_data) => clipboard_png(png_data),
+        Err(err) => {
+            warn!("Cannot convert clipboard bitmap: {}", err);
+            None
+        }
+    }
+}
+
+/// Set the clipboard to `content`, images are stored as CF_DIB bitmaps
+pub fn set_clipboard_content(content: &ClipboardContent) -> Result<()> {
+    let result = match content {
+        ClipboardContent::Text(text) => set_clipboard(formats::Unicode, text),
+        ClipboardContent::Image(image) => {
+            let dib = png_to_dib(&image.png).context("Cannot convert clipboard image")?;
+            set_clipboard(formats::RawData(formats::CF_DIB), dib)
+        }
+    };
+    result
+        .map_err(|err| anyhow!("Err {:?}", err))
+        .context("Cannot set clipboard")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_klid_layout() {
+        assert_eq!(klid_layout("0000040C"), Some("fr"));
+        assert_eq!(klid_layout("0000040c"), Some("fr"));
+        assert_eq!(klid_layout("00000409"), Some("us"));
+        assert_eq!(klid_layout("00010409"), None);
+    }
+
+    #[test]
+    fn test_dib_png() {
+        // 3x2 24 bits bottom-up bitmap, rows padded to 12 bytes
+        let mut dib = vec![0u8; BITMAPINFOHEADER_SIZE];
+        LittleEndian::write_u32(&mut dib[0..4], BITMAPINFOHEADER_SIZE as u32);
+        LittleEndian::write_i32(&mut dib[4..8], 3);
+        LittleEndian::write_i32(&mut dib[8..12], 2);
+        LittleEndian::write_u16(&mut dib[12..14], 1);
+        LittleEndian::write_u16(&mut dib[14..16], 24);
+        // Bottom row: blue pixels, top row: red pixels
+        dib.extend_from_slice(&[0xff, 0, 0, 0xff, 0, 0, 0xff, 0, 0, 0, 0, 0]);
+        dib.extend_from_slice(&[0, 0, 0xff, 0, 0, 0xff, 0, 0, 0xff, 0, 0, 0]);
+
+        let png_data = dib_to_png(&dib).unwrap();
+        assert_eq!(crate::utils::png_size(&png_data), Some((3, 2)));
+        let dib = png_to_dib(&png_data).unwrap();
+        assert_eq!(LittleEndian::read_i32(&dib[4..8]), 3);
+        assert_eq!(LittleEndian::read_i32(&dib[8..12]), 2);
+        let pixels = &dib[BITMAPINFOHEADER_SIZE..];
+        assert_eq!(&pixels[0..4], &[0xff, 0, 0, 0xff]);
+        assert_eq!(&pixels[12..16], &[0, 0, 0xff, 0xff]);
+
+        assert!(dib_to_png(&dib[..BITMAPINFOHEADER_SIZE + 8]).is_err());
+    }
+}
@@ -1,10 +1,15 @@
 use anyhow::{Context, Result};
-use std::sync::{
-    mpsc::{Receiver, Sender},
-    Arc, Mutex,
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
-use crate::utils::ClipboardSelection;
+use crate::utils::{
+    clipboard_png, xkb_layout, ClipboardContent, ClipboardNotifier, ClipboardSelection,
+};
 
 use x11rb::{
     self,
@@ -16,6 +21,37 @@ use x11_clipboard::Clipboard;
 
 use encoding_rs::mem::decode_latin1;
 
+/// Selection target used for clipboard images
+const CLIPBOARD_IMAGE_TARGET: &str = "image/png";
+const CLIPBOARD_LOAD_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Keyboard layout of the x11 display, from the `_XKB_RULES_NAMES` property
+/// of its `root` window, None if unknown
+pub fn keyboard_layout<C: Connection>(conn: &C, root: Window) -> Option<String> {
+    let atom = conn
+        .intern_atom(true, b"_XKB_RULES_NAMES")
+        .ok()?
+        .reply()
+        .ok()?
+        .atom;
+    if atom == x11rb::NONE {
+        return None;
+    }
+    let reply = conn
+        .get_property(false, root, atom, AtomEnum::STRING, 0, 1024)
+        .ok()?
+        .reply()
+        .ok()?;
+    xkb_layout(&reply.value)
+}
+
+/// Keyboard layout of the default x11 display, None if it is unreachable
+pub fn display_keyboard_layout() -> Option<String> {
+    let (conn, screen_num) = x11rb::rust_connection::RustConnection::connect(None).ok()?;
+    let root = conn.setup().roots.get(screen_num)?.root;
+    keyboard_layout(&conn, root)
+}
+
 /// Convert a xfixes event (for clipboard modification) into a x11 selection event
 pub fn convert_event<C: Connection>(conn: &C, window: Window, atom_selection: u32) -> Result<()> {
     let atom_property = conn
@@ -346,10 +382,13 @@ pub fn set_video_mode<C: Connection>(conn: &C, window: Window, mode: u32, dpi: u
     Ok(())
 }
 
+/// Forward the changes of the `selection` to `notifier`, while `enabled` is
+/// set
 pub fn listen_clipboard(
     selection: ClipboardSelection,
-    sender: Sender<String>,
+    notifier: ClipboardNotifier,
     skip_clipboard: Arc<Mutex<u32>>,
+    enabled: Arc<AtomicBool>,
 ) {
     let clipboard = Clipboard::new().unwrap();
     let selection_atom = match selection {
@@ -357,31 +396,63 @@ pub fn listen_clipboard(
         ClipboardSelection::Primary => clipboard.getter.atoms.primary,
     };
 
+    let image_png = clipboard
+        .getter
+        .get_atom(CLIPBOARD_IMAGE_TARGET)
+        .expect("Cannot get image/png atom");
+
     loop {
-        if let Ok(curr) = clipboard.load_wait(
+        // Stop with the server owning the skip counter (display reconnected)
+        if Arc::strong_count(&skip_clipboard) == 1 {
+            return;
+        }
+        let content = match clipboard.load_wait(
             selection_atom,
             clipboard.getter.atoms.utf8_string,
             clipboard.getter.atoms.property,
         ) {
-            let curr = String::from_utf8_lossy(&curr);
-            let curr = curr.trim_matches('\u{0}');
-
-            if curr.is_empty() {
-                continue;
+            Ok(curr) => {
+                let curr = String::from_utf8_lossy(&curr);
+                let curr = curr.trim_matches('\u{0}');
+
+                if curr.is_empty() {
+                    continue;
+                }
+                ClipboardContent::Text(curr.to_owned())
             }
-
-            let mut skip_clipboard_guard = skip_clipboard.lock().unwrap();
-            if *skip_clipboard_guard > 0 {
-                *skip_clipboard_guard -= 1;
-                // The clipboard may be set by ourself, skip it
-                continue;
-            }
-            sender.send(curr.to_owned()).expect("Cannot send clipboard");
+            // The selection owner has no text, try to get an image
+            Err(_) => match clipboard.load(
+                selection_atom,
+                image_png,
+                clipboard.getter.atoms.property,
+                CLIPBOARD_LOAD_TIMEOUT,
+            ) {
+                Ok(png) => match clipboard_png(png) {
+                    Some(content) => content,
+                    None => continue,
+                },
+                Err(_) => continue,
+            },
+        };
+
+        let mut skip_clipboard_guard = skip_clipboard.lock().unwrap();
+        if *skip_clipboard_guard > 0 {
+            *skip_clipboard_guard -= 1;
+            // The clipboard may be set by ourself, skip it
+            continue;
         }
+        if !enabled.load(Ordering::Relaxed) {
+            continue;
+        }
+        notifier.push(content);
     }
 }
 
-pub fn set_clipboard(clipboard: &Clipboard, selection: i32, value: &str) -> Result<()> {
+pub fn set_clipboard(
+    clipboard: &Clipboard,
+    selection: i32,
+    value: &ClipboardContent,
+) -> Result<()> {
     let selection_atom = match selection {
         0 /*ClipboardSelection::Clipboard*/ => clipboard.getter.atoms.clipboard,
         1 /*ClipboardSelection::Primary*/ => clipboard.getter.atoms.primary,
@@ -390,21 +461,18 @@ pub fn set_clipboard(clipboard: &Clipboard, selection: i32, value: &str) -> Resu
         }
     };
 
+    let (target, data) = match value {
+        ClipboardContent::Text(text) => (clipboard.getter.atoms.utf8_string, text.as_bytes()),
+        ClipboardContent::Image(image) => (
+            clipboard
+                .getter
+                .get_atom(CLIPBOARD_IMAGE_TARGET)
+                .context("Cannot get image/png atom")?,
+            image.png.as_slice(),
+        ),
+    };
     clipboard
-        .store(
-            selection_atom,
-            clipboard.getter.atoms.utf8_string,
-            value.as_bytes(),
-        )
+        .store(selection_atom, target, data)
         .context("Error in clipboard strore")?;
     Ok(())
 }
-
-pub fn get_clipboard_events(receiver: &Receiver<String>) -> Option<String> {
-    /* Pool clipboard events */
-    let mut message = None;
-    while let Ok(data) = receiver.try_recv() {
-        message = Some(data);
-    }
-    message
-}
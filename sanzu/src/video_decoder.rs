@@ -1,12 +1,11 @@
 use crate::ffmpeg_helper::{
     averror, set_option, AVCodec, AVCodecContext, AVFrame, AVPacket, AVParser,
 };
-use crate::yuv_rgb_rs;
+use crate::{convert, yuv_rgb_rs};
 use anyhow::{Context, Result};
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-use cpufeatures;
 //use ffmpeg::{AVCodecContext, AVCodecParserContext, AVFrame, AVPacket};
 use ffmpeg_sys_next as ffmpeg;
+use sanzu_common::tunnel;
 use std::{
     collections::HashMap,
     ptr::null_mut,
@@ -19,13 +18,18 @@ pub struct DecoderTimings {
     pub times: Vec<(&'static str, Duration)>,
 }
 
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-cpufeatures::new!(cpuid_ssse3, "ssse3");
+/// Software av1 decoders, in order of preference. The native `av1` decoder
+/// only decodes with hardware acceleration.
+const AV1_DECODERS: [&str; 3] = ["libdav1d", "libaom-av1", "av1"];
 
 /// Initialize a FFmpeg video decoder
+///
+/// The `av1` codec name selects the first available av1 decoder.
 pub fn init_video_codec<'a>(
     codec_options: Option<impl Iterator<Item = (&'a String, &'a String)>>,
     name: &str,
+    yuv_type: yuv_rgb_rs::YuvType,
+    color_range: yuv_rgb_rs::ColorRange,
 ) -> Result<Box<dyn Decoder>> {
     // Set log level to FATAL if building release
     #[cfg(not(debug_assertions))]
@@ -39,6 +43,15 @@ pub fn init_video_codec<'a>(
             data_rgba: None,
         }),
         name => {
+            let name = match name {
+                "av1" => AV1_DECODERS
+                    .iter()
+                    .copied()
+                    .find(|name| AVCodec::new_decoder(name).is_ok())
+                    .ok_or_else(|| anyhow!("No av1 decoder available"))?,
+                name => name,
+            };
+            info!("Decoder {:?}", name);
             let mut decoder = DecoderBuilder::new(name).context("Error in DecoderBuilder")?;
             // Set codec specific options
             if let Some(opts) = codec_options {
@@ -46,6 +59,7 @@ pub fn init_video_codec<'a>(
                     decoder.set_option(k, v).context("Error set option error")?;
                 }
             }
+            decoder.set_color_space(yuv_type, color_range);
             Box::new(decoder.open().context("Error in decoder open")?)
         }
     };
@@ -74,7 +88,10 @@ fn decode(
     let mut ret = unsafe { ffmpeg::avcodec_send_packet(context_ptr, packet_ptr) };
 
     if ret < 0 {
-        panic!("Error sending a packet for decoding ({:?})", ret);
+        // Corrupted or missing data on the link, the stream needs a keyframe
+        warn!("Error sending a packet for decoding ({:?})", ret);
+        decoder.decode_error = true;
+        return (None, None);
     }
     let mut duration_decode = Instant::now() - time_start;
 
@@ -87,7 +104,17 @@ fn decode(
             return (img_updated, Some(DecoderTimings { times: timings }));
         }
         if ret < 0 {
-            panic!("Error during decoding");
+            warn!("Error during decoding ({:?})", ret);
+            decoder.decode_error = true;
+            return (img_updated, None);
+        }
+        let corrupt = unsafe {
+            (*frame_ptr).flags & ffmpeg::AV_FRAME_FLAG_CORRUPT as i32 != 0
+                || (*frame_ptr).decode_error_flags != 0
+        };
+        if corrupt {
+            debug!("Corrupted frame");
+            decoder.decode_error = true;
         }
 
         if img_updated.is_some() {
@@ -124,7 +151,7 @@ fn decode(
         let final_width = img_width.min(img_out_width as u32);
         let final_height = img_height.min(img_out_height as u32);
 
-        match pixel_format {
+        let result = match pixel_format {
             0 => {
                 // yuv420
                 let p1size = unsafe { (*frame_ptr).linesize[0] * (*frame_ptr).height };
@@ -146,54 +173,21 @@ fn decode(
                 let u_lane = unsafe { (*frame_ptr).linesize[1] as u32 };
                 let v_lane = unsafe { (*frame_ptr).linesize[2] as u32 };
 
-                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-                {
-                    if decoder.has_ssse3 {
-                        yuv_rgb_rs::yuv420_to_rgba_ssse3(
-                            final_width as usize,
-                            final_height as usize,
-                            slice_y,
-                            slice_u,
-                            slice_v,
-                            y_lane as usize,
-                            u_lane as usize,
-                            v_lane as usize,
-                            data_rgba_ptr,
-                            img_out_width as usize * 4,
-                            yuv_rgb_rs::YuvType::ItuT871,
-                        );
-                    } else {
-                        yuv_rgb_rs::yuv420_to_rgba_std(
-                            final_width as usize,
-                            final_height as usize,
-                            slice_y,
-                            slice_u,
-                            slice_v,
-                            y_lane as usize,
-                            u_lane as usize,
-                            v_lane as usize,
-                            data_rgba_ptr,
-                            img_out_width as usize * 4,
-                            yuv_rgb_rs::YuvType::ItuT871,
-                        );
-                    }
-                }
-                #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
-                {
-                    yuv_rgb_rs::yuv420_to_rgba_std(
-                        final_width as usize,
-                        final_height as usize,
-                        slice_y,
-                        slice_u,
-                        slice_v,
-                        y_lane as usize,
-                        u_lane as usize,
-                        v_lane as usize,
-                        data_rgba_ptr,
-                        img_out_width as usize * 4,
-                        yuv_rgb_rs::YuvType::ItuT871,
-                    );
-                }
+                convert::yuv420_to_rgba(
+                    final_width as usize,
+                    final_height as usize,
+                    slice_y,
+                    slice_u,
+                    slice_v,
+                    y_lane as usize,
+                    u_lane as usize,
+                    v_lane as usize,
+                    data_rgba_ptr,
+                    img_out_width as usize * 4,
+                    decoder.yuv_type,
+                    decoder.color_range,
+                    convert::FRAME_BYTE_ORDER,
+                )
             }
             5 => {
                 // yuv444
@@ -216,54 +210,21 @@ fn decode(
                 let u_lane = unsafe { (*frame_ptr).linesize[1] as u32 };
                 let v_lane = unsafe { (*frame_ptr).linesize[2] as u32 };
 
-                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-                {
-                    if decoder.has_ssse3 {
-                        yuv_rgb_rs::yuv444_to_rgba_ssse3(
-                            final_width as usize,
-                            final_height as usize,
-                            slice_y,
-                            slice_u,
-                            slice_v,
-                            y_lane as usize,
-                            u_lane as usize,
-                            v_lane as usize,
-                            data_rgba_ptr,
-                            img_out_width as usize * 4,
-                            yuv_rgb_rs::YuvType::ItuT871,
-                        );
-                    } else {
-                        yuv_rgb_rs::yuv444_to_rgba_std(
-                            final_width as usize,
-                            final_height as usize,
-                            slice_y,
-                            slice_u,
-                            slice_v,
-                            y_lane as usize,
-                            u_lane as usize,
-                            v_lane as usize,
-                            data_rgba_ptr,
-                            img_out_width as usize * 4,
-                            yuv_rgb_rs::YuvType::ItuT871,
-                        );
-                    }
-                }
-                #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
-                {
-                    yuv_rgb_rs::yuv444_to_rgba_std(
-                        final_width as usize,
-                        final_height as usize,
-                        slice_y,
-                        slice_u,
-                        slice_v,
-                        y_lane as usize,
-                        u_lane as usize,
-                        v_lane as usize,
-                        data_rgba_ptr,
-                        img_out_width as usize * 4,
-                        yuv_rgb_rs::YuvType::ItuT871,
-                    );
-                }
+                convert::yuv444_to_rgba(
+                    final_width as usize,
+                    final_height as usize,
+                    slice_y,
+                    slice_u,
+                    slice_v,
+                    y_lane as usize,
+                    u_lane as usize,
+                    v_lane as usize,
+                    data_rgba_ptr,
+                    img_out_width as usize * 4,
+                    decoder.yuv_type,
+                    decoder.color_range,
+                    convert::FRAME_BYTE_ORDER,
+                )
             }
             23 => {
                 // nv12
@@ -281,56 +242,62 @@ fn decode(
                 let y_lane = unsafe { (*frame_ptr).linesize[0] as u32 };
                 let uv_lane = unsafe { (*frame_ptr).linesize[1] as u32 };
 
-                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-                {
-                    if decoder.has_ssse3 {
-                        yuv_rgb_rs::nv12_rgba_ssse3(
-                            final_width as usize,
-                            final_height as usize,
-                            slice_y,
-                            slice_uv,
-                            y_lane as usize,
-                            uv_lane as usize,
-                            data_rgba_ptr,
-                            img_out_width as usize * 4,
-                            yuv_rgb_rs::YuvType::ItuT871,
-                        );
-                    } else {
-                        yuv_rgb_rs::nv12_rgba_std(
-                            final_width as usize,
-                            final_height as usize,
-                            slice_y,
-                            slice_uv,
-                            y_lane as usize,
-                            uv_lane as usize,
-                            data_rgba_ptr,
-                            img_out_width as usize * 4,
-                            yuv_rgb_rs::YuvType::ItuT871,
-                        );
-                    }
-                }
-                #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
-                {
-                    yuv_rgb_rs::nv12_rgba_std(
-                        final_width as usize,
-                        final_height as usize,
-                        slice_y,
-                        slice_uv,
-                        y_lane as usize,
-                        uv_lane as usize,
-                        data_rgba_ptr,
-                        (img_out_width as usize * 4) as usize,
-                        yuv_rgb_rs::YuvType::ItuT871,
-                    );
-                }
+                convert::nv12_to_rgba(
+                    final_width as usize,
+                    final_height as usize,
+                    slice_y,
+                    slice_uv,
+                    y_lane as usize,
+                    uv_lane as usize,
+                    data_rgba_ptr,
+                    img_out_width as usize * 4,
+                    decoder.yuv_type,
+                    decoder.color_range,
+                    convert::FRAME_BYTE_ORDER,
+                )
+            }
+            24 => {
+                // nv21
+                let p1size = unsafe { (*frame_ptr).linesize[0] * (*frame_ptr).height };
+                let p2size = unsafe { (*frame_ptr).linesize[1] * (*frame_ptr).height };
+
+                /* Y part */
+                let slice_y =
+                    unsafe { std::slice::from_raw_parts_mut((*frame_ptr).data[0], p1size as _) };
+
+                /* VU part */
+                let slice_uv =
+                    unsafe { std::slice::from_raw_parts_mut((*frame_ptr).data[1], p2size as _) };
+
+                let y_lane = unsafe { (*frame_ptr).linesize[0] as u32 };
+                let uv_lane = unsafe { (*frame_ptr).linesize[1] as u32 };
+
+                convert::nv21_to_rgba(
+                    final_width as usize,
+                    final_height as usize,
+                    slice_y,
+                    slice_uv,
+                    y_lane as usize,
+                    uv_lane as usize,
+                    data_rgba_ptr,
+                    img_out_width as usize * 4,
+                    decoder.yuv_type,
+                    decoder.color_range,
+                    convert::FRAME_BYTE_ORDER,
+                )
             }
             _ => {
                 panic!("Unsupported pixel format {}", pixel_format);
             }
-        }
+        };
 
         duration_yuv += Instant::now() - time_yuv;
 
+        if let Err(err) = result {
+            error!("Cannot convert decoded frame: {}", err);
+            continue;
+        }
+
         img_updated = Some(());
     }
     let timings = vec![("dec", duration_decode), ("yuv", duration_yuv)];
@@ -347,6 +314,10 @@ pub struct DecoderBuilder {
     parser: AVParser,
     name: String,
     options: HashMap<String, String>,
+    /// Color matrix used to convert decoded images to rgb
+    yuv_type: yuv_rgb_rs::YuvType,
+    /// Range of the decoded yuv samples
+    color_range: yuv_rgb_rs::ColorRange,
 }
 
 /// Holds DecoderFFmpeg information
@@ -355,14 +326,18 @@ pub struct DecoderFFmpeg {
     name: String,
     options: HashMap<String, String>,
     context: AVCodecContext,
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    has_ssse3: bool,
     packet: AVPacket,
     frame: AVFrame,
     parser: AVParser,
+    /// Color matrix used to convert decoded images to rgb
+    yuv_type: yuv_rgb_rs::YuvType,
+    /// Range of the decoded yuv samples
+    color_range: yuv_rgb_rs::ColorRange,
     /// data_rgba & data_rgb are alloced once to avoid malloc / free / memset
     data_rgba: Option<Vec<u8>>,
     data_rgb: Option<Vec<u8>>,
+    /// Set when a packet cannot be decoded or a frame is corrupted
+    decode_error: bool,
 }
 
 impl DecoderBuilder {
@@ -381,6 +356,8 @@ impl DecoderBuilder {
             parser,
             name: name.to_owned(),
             options,
+            yuv_type: yuv_rgb_rs::YuvType::ItuT871,
+            color_range: yuv_rgb_rs::ColorRange::Full,
         })
     }
 
@@ -391,6 +368,17 @@ impl DecoderBuilder {
         unsafe { set_option(self.context.as_mut_ptr() as *mut libc::c_void, name, val) }
     }
 
+    /// Set the color matrix and range used to convert decoded images
+    fn set_color_space(
+        &mut self,
+        yuv_type: yuv_rgb_rs::YuvType,
+        color_range: yuv_rgb_rs::ColorRange,
+    ) {
+        debug!("set_color_space: {:?} {:?}", yuv_type, color_range);
+        self.yuv_type = yuv_type;
+        self.color_range = color_range;
+    }
+
     /// Generate FFmpeg decoder
     fn open(mut self) -> Result<DecoderFFmpeg> {
         let context_ptr = self.context.as_mut_ptr();
@@ -402,28 +390,25 @@ impl DecoderBuilder {
             return Err(averror("avcodec_open2", retval));
         }
         let frame = AVFrame::new()?;
-        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-        let has_ssse3 = { cpuid_ssse3::init().get() };
 
         Ok(DecoderFFmpeg {
             context: self.context,
             name: self.name.clone(),
             options: self.options.clone(),
-            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-            has_ssse3,
             packet: self.packet,
             parser: self.parser,
+            yuv_type: self.yuv_type,
+            color_range: self.color_range,
             frame,
             data_rgba: None,
             data_rgb: None,
+            decode_error: false,
         })
     }
 }
 
 pub trait Decoder {
     fn is_raw(&self) -> bool;
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    fn has_ssse3(&self) -> bool;
     fn name(&self) -> String;
     fn data_rgba(&mut self) -> &mut Option<Vec<u8>>;
     fn options(&self) -> HashMap<String, String>;
@@ -434,6 +419,10 @@ pub trait Decoder {
         img_out_height: u16,
         img_bytes_per_line: Option<u16>,
     ) -> (Option<()>, Option<DecoderTimings>);
+    /// True if a frame failed to decode since the last call
+    fn take_decode_error(&mut self) -> bool {
+        false
+    }
     fn reload(&self) -> Result<Box<dyn Decoder>>;
 }
 
@@ -441,10 +430,6 @@ impl Decoder for DecoderFFmpeg {
     fn is_raw(&self) -> bool {
         false
     }
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    fn has_ssse3(&self) -> bool {
-        self.has_ssse3
-    }
     fn name(&self) -> String {
         self.name.to_string()
     }
@@ -510,6 +495,10 @@ impl Decoder for DecoderFFmpeg {
         (img_updated, decode_timings)
     }
 
+    fn take_decode_error(&mut self) -> bool {
+        std::mem::take(&mut self.decode_error)
+    }
+
     fn reload(&self) -> Result<Box<dyn Decoder>> {
         let options = self.options();
         let name = self.name();
@@ -519,6 +508,7 @@ impl Decoder for DecoderFFmpeg {
                 .set_option(key, value)
                 .context(format!("Error in set_option {key:?} {value:?}"))?;
         }
+        builder.set_color_space(self.yuv_type, self.color_range);
         let decoder = builder.open().context("Error in decoder open")?;
         Ok(Box::new(decoder))
     }
@@ -536,10 +526,6 @@ impl Decoder for DecoderNull {
     fn is_raw(&self) -> bool {
         false
     }
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    fn has_ssse3(&self) -> bool {
-        false
-    }
     fn name(&self) -> String {
         "null".to_string()
     }
@@ -601,3 +587,292 @@ impl Decoder for DecoderNull {
         }))
     }
 }
+
+/// Max number of tiles of a frame
+const MAX_TILES: u32 = 64;
+
+/// Decoder of frames split in tiles
+///
+/// Each tile (`ImageEncoded.tile`) is a separate stream, decoded by its own
+/// decoder. The decoded tiles are composed in a rgba frame.
+#[derive(Default)]
+pub struct DecoderTiles {
+    decoders: Vec<Box<dyn Decoder>>,
+    size: (u16, u16),
+    data_rgba: Option<Vec<u8>>,
+    decode_error: bool,
+}
+
+impl DecoderTiles {
+    /// Set the layout of the frame: `count` tiles of a `width`x`height` frame
+    ///
+    /// On a layout change, the tile decoders are reloaded from `decoder` and
+    /// true is returned, as the tiles must restart from a keyframe.
+    pub fn set_layout(
+        &mut self,
+        decoder: &dyn Decoder,
+        count: u32,
+        width: u16,
+        height: u16,
+    ) -> Result<bool> {
+        if self.decoders.len() == count as usize && self.size == (width, height) {
+            return Ok(false);
+        }
+        if count == 0 || count > MAX_TILES {
+            return Err(anyhow!("Bad tiles count {}", count));
+        }
+        self.decoders = (0..count)
+            .map(|_| decoder.reload())
+            .collect::<Result<Vec<_>>>()
+            .context("Cannot reload tile decoders")?;
+        self.size = (width, height);
+        self.data_rgba = Some(vec![0u8; width as usize * height as usize * 4]);
+        Ok(true)
+    }
+
+    /// Decode the `tiles` and compose them in the frame
+    ///
+    /// Returns Some if a tile has been updated.
+    pub fn decode_tiles(
+        &mut self,
+        tiles: &[(tunnel::Tile, Vec<u8>)],
+    ) -> (Option<()>, Option<DecoderTimings>) {
+        let time_start = Instant::now();
+        let mut img_updated = None;
+        for (tile, data) in tiles {
+            match self.decode_tile(tile, data) {
+                Ok(updated) => img_updated = img_updated.or(updated),
+                Err(err) => {
+                    error!("Cannot decode tile {}", tile.index);
+                    err.chain().for_each(|cause| error!(" - due to {}", cause));
+                    self.decode_error = true;
+                }
+            }
+        }
+        let decode_timings = DecoderTimings {
+            times: vec![("tiles", Instant::now() - time_start)],
+        };
+        (img_updated, img_updated.map(|_| decode_timings))
+    }
+
+    fn decode_tile(&mut self, tile: &tunnel::Tile, data: &[u8]) -> Result<Option<()>> {
+        let (width, height) = (self.size.0 as usize, self.size.1 as usize);
+        let (x, y, tile_width, tile_height) = (
+            tile.x as usize,
+            tile.y as usize,
+            tile.width as usize,
+            tile.height as usize,
+        );
+        if x + tile_width > width || y + tile_height > height {
+            return Err(anyhow!(
+                "Tile {}x{} at ({}, {}) out of frame {}x{}",
+                tile_width,
+                tile_height,
+                x,
+                y,
+                width,
+                height
+            ));
+        }
+        let decoder = self
+            .decoders
+            .get_mut(tile.index as usize)
+            .ok_or_else(|| anyhow!("Bad tile index {}", tile.index))?;
+        let (img_updated, _) =
+            decoder.decode_img(data, tile_width as u16, tile_height as u16, None);
+        if decoder.take_decode_error() {
+            self.decode_error = true;
+        }
+        if img_updated.is_none() {
+            return Ok(None);
+        }
+        let row_len = tile_width * 4;
+        let src = decoder
+            .data_rgba()
+            .as_ref()
+            .filter(|src| src.len() >= row_len * tile_height)
+            .ok_or_else(|| anyhow!("Bad tile data size"))?;
+        let dst = self.data_rgba.as_mut().context("Tiles layout not set")?;
+        for (row, src) in src.chunks_exact(row_len).take(tile_height).enumerate() {
+            let offset = ((y + row) * width + x) * 4;
+            dst[offset..offset + row_len].copy_from_slice(src);
+        }
+        Ok(Some(()))
+    }
+
+    /// Composed rgba frame
+    pub fn data_rgba(&mut self) -> &mut Option<Vec<u8>> {
+        &mut self.data_rgba
+    }
+
+    /// True if a tile failed to decode since the last call
+    pub fn take_decode_error(&mut self) -> bool {
+        std::mem::take(&mut self.decode_error)
+    }
+}
+
+/// Minimum delay between two keyframe requests, as the keyframe needs a round
+/// trip to arrive
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Detects missing or corrupted frames in the encoded stream
+///
+/// Frames are numbered by the server (`ImageEncoded.sequence`, 0 if the
+/// server doesn't number them). A gap in the numbering or a decode error
+/// breaks the stream until the next keyframe, which is requested from the
+/// server.
+#[derive(Debug, Default)]
+pub struct StreamRecovery {
+    last_sequence: Option<u32>,
+    last_request: Option<Instant>,
+    broken: bool,
+}
+
+impl StreamRecovery {
+    /// Check the `sequence` number of a received frame
+    pub fn frame_received(&mut self, sequence: u32) {
+        if sequence == 0 {
+            return;
+        }
+        if let Some(last_sequence) = self.last_sequence {
+            if sequence != next_sequence(last_sequence) {
+                warn!("Missing frames: got {} after {}", sequence, last_sequence);
+                self.broken = true;
+            }
+        }
+        self.last_sequence = Some(sequence);
+    }
+
+    pub fn decode_error(&mut self) {
+        self.broken = true;
+    }
+
+    /// Returns true if a keyframe must be requested now
+    pub fn need_keyframe(&mut self, now: Instant) -> bool {
+        if !self.broken {
+            return false;
+        }
+        if let Some(last_request) = self.last_request {
+            if now.duration_since(last_request) < KEYFRAME_REQUEST_INTERVAL {
+                return false;
+            }
+        }
+        self.broken = false;
+        self.last_request = Some(now);
+        true
+    }
+}
+
+/// Sequence number following `sequence`, 0 being reserved for unnumbered
+/// frames
+pub fn next_sequence(sequence: u32) -> u32 {
+    sequence.checked_add(1).unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decoder filling its images with the first byte of the data
+    #[derive(Default)]
+    struct DecoderFill {
+        data_rgba: Option<Vec<u8>>,
+    }
+
+    impl Decoder for DecoderFill {
+        fn is_raw(&self) -> bool {
+            false
+        }
+        fn name(&self) -> String {
+            "fill".to_owned()
+        }
+        fn data_rgba(&mut self) -> &mut Option<Vec<u8>> {
+            &mut self.data_rgba
+        }
+        fn options(&self) -> HashMap<String, String> {
+            HashMap::new()
+        }
+        fn decode_img(
+            &mut self,
+            data_in: &[u8],
+            img_out_width: u16,
+            img_out_height: u16,
+            _img_bytes_per_line: Option<u16>,
+        ) -> (Option<()>, Option<DecoderTimings>) {
+            let value = match data_in.first() {
+                Some(value) => *value,
+                None => return (None, None),
+            };
+            let size = img_out_width as usize * img_out_height as usize * 4;
+            self.data_rgba = Some(vec![value; size]);
+            (Some(()), Some(DecoderTimings { times: vec![] }))
+        }
+        fn reload(&self) -> Result<Box<dyn Decoder>> {
+            Ok(Box::new(DecoderFill::default()))
+        }
+    }
+
+    #[test]
+    fn test_decoder_tiles() {
+        let tile = |index, y, height| tunnel::Tile {
+            index,
+            count: 2,
+            x: 0,
+            y,
+            width: 4,
+            height,
+        };
+        let mut tiles = DecoderTiles::default();
+        assert!(tiles.set_layout(&DecoderFill::default(), 2, 4, 6).unwrap());
+        assert!(!tiles.set_layout(&DecoderFill::default(), 2, 4, 6).unwrap());
+        assert!(tiles.set_layout(&DecoderFill::default(), 0, 4, 6).is_err());
+
+        let (updated, _) =
+            tiles.decode_tiles(&[(tile(0, 0, 4), vec![1]), (tile(1, 4, 2), vec![2])]);
+        assert!(updated.is_some());
+        let frame = tiles.data_rgba().clone().unwrap();
+        assert!(frame[..4 * 4 * 4].iter().all(|value| *value == 1));
+        assert!(frame[4 * 4 * 4..].iter().all(|value| *value == 2));
+        assert!(!tiles.take_decode_error());
+
+        // Empty tiles keep the previous content
+        let (updated, _) = tiles.decode_tiles(&[(tile(1, 4, 2), vec![])]);
+        assert!(updated.is_none());
+        assert_eq!(tiles.data_rgba().as_ref(), Some(&frame));
+
+        // Out of frame tiles and bad indexes are decode errors
+        let (updated, _) =
+            tiles.decode_tiles(&[(tile(1, 4, 4), vec![3]), (tile(2, 0, 2), vec![3])]);
+        assert!(updated.is_none());
+        assert!(tiles.take_decode_error());
+        assert_eq!(tiles.data_rgba().as_ref(), Some(&frame));
+    }
+
+    #[test]
+    fn test_stream_recovery() {
+        let start = Instant::now();
+        let mut recovery = StreamRecovery::default();
+        recovery.frame_received(1);
+        recovery.frame_received(2);
+        assert!(!recovery.need_keyframe(start));
+
+        // Frame 3 is lost
+        recovery.frame_received(4);
+        assert!(recovery.need_keyframe(start));
+        assert!(!recovery.need_keyframe(start));
+
+        // Requests are rate limited
+        recovery.decode_error();
+        assert!(!recovery.need_keyframe(start + Duration::from_millis(100)));
+        assert!(recovery.need_keyframe(start + KEYFRAME_REQUEST_INTERVAL));
+
+        // Unnumbered frames and wrapping are not gaps
+        recovery.frame_received(0);
+        recovery.frame_received(5);
+        assert_eq!(next_sequence(u32::MAX), 1);
+        let mut recovery = StreamRecovery::default();
+        recovery.frame_received(u32::MAX);
+        recovery.frame_received(1);
+        assert!(!recovery.need_keyframe(start));
+    }
+}
@@ -1,19 +1,23 @@
-use crate::ffmpeg_helper::{averror, set_option, AVCodec, AVCodecContext, AVFrame, AVPacket};
-use crate::yuv_rgb_rs;
+use crate::ffmpeg_helper::{
+    averror, get_int_option, set_option, vaapi_frames_context, AVBufferRef, AVCodec,
+    AVCodecContext, AVFrame, AVPacket,
+};
+use crate::{convert, yuv_rgb_rs};
 use anyhow::{Context, Result};
 use ffmpeg::AVPixelFormat;
 use ffmpeg_sys_next as ffmpeg;
+use rayon::prelude::*;
+use sanzu_common::tunnel;
+use serde::{Deserialize, Serialize};
 use std::{
     cmp::Ordering,
     collections::HashMap,
-    process,
+    fmt, process,
     ptr::null_mut,
+    str::FromStr,
     time::{Duration, Instant},
 };
 
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-cpufeatures::new!(cpuid_ssse3, "ssse3");
-
 /// Hold information to build an encoder
 #[derive(Debug)]
 pub struct EncoderBuilder {
@@ -24,8 +28,17 @@ pub struct EncoderBuilder {
     framerate: (i32, i32),
     /// Command to execute to get new options on encoder renewal
     command: Option<String>,
+    /// Color matrix used to convert images to yuv
+    yuv_type: yuv_rgb_rs::YuvType,
+    /// Range of the yuv samples
+    color_range: yuv_rgb_rs::ColorRange,
+    /// Vaapi device path, for hardware encoders
+    hw_device: Option<String>,
 }
 
+/// Default vaapi render node
+pub const DEFAULT_VAAPI_DEVICE: &str = "/dev/dri/renderD128";
+
 fn round_size_up(size: usize) -> usize {
     (size + 0x3F) & !0x3F
 }
@@ -44,6 +57,9 @@ impl EncoderBuilder {
             options,
             framerate: (25, 1),
             command: None,
+            yuv_type: yuv_rgb_rs::YuvType::ItuT871,
+            color_range: yuv_rgb_rs::ColorRange::Full,
+            hw_device: None,
         })
     }
 
@@ -70,6 +86,40 @@ impl EncoderBuilder {
         Ok(())
     }
 
+    /// Set the vaapi device used to upload frames
+    fn set_hw_device(&mut self, path: &str) {
+        debug!("set_hw_device: {:?}", path);
+        self.hw_device = Some(path.to_owned());
+    }
+
+    /// Set the color matrix and range used to convert images to yuv
+    ///
+    /// They are also signaled in the encoded stream.
+    fn set_color_space(
+        &mut self,
+        yuv_type: yuv_rgb_rs::YuvType,
+        color_range: yuv_rgb_rs::ColorRange,
+    ) {
+        debug!("set_color_space: {:?} {:?}", yuv_type, color_range);
+        self.yuv_type = yuv_type;
+        self.color_range = color_range;
+        let colorspace = match yuv_type {
+            yuv_rgb_rs::YuvType::ItuT871 | yuv_rgb_rs::YuvType::ItuR601 => {
+                ffmpeg::AVColorSpace::AVCOL_SPC_BT470BG
+            }
+            yuv_rgb_rs::YuvType::ItuR709 => ffmpeg::AVColorSpace::AVCOL_SPC_BT709,
+            yuv_rgb_rs::YuvType::ItuR2020 => ffmpeg::AVColorSpace::AVCOL_SPC_BT2020_NCL,
+        };
+        let color_range = match color_range {
+            yuv_rgb_rs::ColorRange::Limited => ffmpeg::AVColorRange::AVCOL_RANGE_MPEG,
+            yuv_rgb_rs::ColorRange::Full => ffmpeg::AVColorRange::AVCOL_RANGE_JPEG,
+        };
+        unsafe {
+            (*self.context.as_mut_ptr()).colorspace = colorspace;
+            (*self.context.as_mut_ptr()).color_range = color_range;
+        }
+    }
+
     /// Generate FFmpeg encoder
     fn open(mut self) -> Result<EncoderFFmpeg> {
         if let Some(ref command) = &self.command {
@@ -100,14 +150,35 @@ impl EncoderBuilder {
 
         let context_ptr = self.context.as_mut_ptr();
         let codec_ptr = self.codec.as_ptr();
+
+        // Hardware encoders take device surfaces, filled from nv12 frames
+        let hw_upload = match self.hw_device {
+            Some(ref path) => {
+                let (width, height) = unsafe { ((*context_ptr).width, (*context_ptr).height) };
+                let frames = vaapi_frames_context(path, width, height)
+                    .context("Error in vaapi frames context")?;
+                unsafe {
+                    (*context_ptr).pix_fmt = AVPixelFormat::AV_PIX_FMT_VAAPI;
+                    (*context_ptr).hw_frames_ctx = ffmpeg::av_buffer_ref(frames.as_ptr());
+                }
+                Some((frames, AVFrame::new()?))
+            }
+            None => None,
+        };
+
         let mut retval: i32 = unsafe { ffmpeg::avcodec_open2(context_ptr, codec_ptr, null_mut()) };
         if retval < 0 {
             return Err(averror("avcodec_open2", retval));
         }
+        let delay = unsafe { encoder_delay(context_ptr) };
+        debug!("Encoder delay: {} frames", delay);
         let frame = AVFrame::new()?;
         let frame_ptr = frame.get_ptr();
         unsafe {
-            (*frame_ptr).format = (*context_ptr).pix_fmt as i32;
+            (*frame_ptr).format = match hw_upload {
+                Some(_) => AVPixelFormat::AV_PIX_FMT_NV12 as i32,
+                None => (*context_ptr).pix_fmt as i32,
+            };
             (*frame_ptr).width = (*context_ptr).width;
             (*frame_ptr).height = (*context_ptr).height;
         }
@@ -116,14 +187,17 @@ impl EncoderBuilder {
         let height = round_size_up(unsafe { (*context_ptr).height } as usize);
 
         let image_size_y = width * height;
+        // 10 bits buffers are only used by p010
+        let image_size_10bit = match unsafe { (*context_ptr).pix_fmt } {
+            AVPixelFormat::AV_PIX_FMT_P010LE => image_size_y,
+            _ => 0,
+        };
 
         retval = unsafe { ffmpeg::av_frame_get_buffer(frame_ptr, 0) };
         if retval < 0 {
             return Err(averror("av_frame_get_buffer", retval));
         }
 
-        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-        let has_ssse3 = { cpuid_ssse3::init().get() };
         debug!("Encoder {}x{}", width, height);
 
         // Image size * 3 / 2 because img may have bigger lane
@@ -132,20 +206,54 @@ impl EncoderBuilder {
             name: self.name.clone(),
             options: self.options.clone(),
             framerate: self.framerate,
-            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-            has_ssse3,
             packet: AVPacket::new()?,
             frame,
             image_y: vec![0; image_size_y],
             image_u: vec![0; image_size_y],
             image_v: vec![0; image_size_y],
             image_uv: vec![0; image_size_y],
+            image_rgba10: vec![0; image_size_10bit * 4],
+            image_y10: vec![0; image_size_10bit],
+            image_uv10: vec![0; image_size_10bit],
             command: self.command.clone(),
+            yuv_type: self.yuv_type,
+            color_range: self.color_range,
             size: (width as u16, height as u16),
+            force_keyframe: false,
+            regions_of_interest: vec![],
+            hw_device: self.hw_device.clone(),
+            hw_upload,
+            delay,
         })
     }
 }
 
+/// Lookahead of the x264 / x265 encoders, if not set
+const DEFAULT_LOOKAHEAD: i64 = 40;
+
+/// Upper estimate of the number of frames the opened encoder `context` holds
+/// before outputting their packets: the codec delay, the b frames, the
+/// lookahead and the frame threads
+///
+/// # Safety
+/// context must be an opened encoder context
+unsafe fn encoder_delay(context: *mut ffmpeg::AVCodecContext) -> u32 {
+    let lookahead = match get_int_option(context as *mut libc::c_void, "rc-lookahead") {
+        Some(lookahead) if lookahead >= 0 => lookahead,
+        // Not set, or private to the encoder
+        _ => DEFAULT_LOOKAHEAD,
+    };
+    let threads = match (*context).thread_count {
+        0 => std::thread::available_parallelism().map_or(1, |count| count.get() as i64),
+        count => count as i64,
+    };
+    let delay = (*context).delay.max(0) as i64
+        + (*context).max_b_frames.max(0) as i64
+        + lookahead
+        + (threads - 1).max(0);
+    delay.min(u32::MAX as i64) as u32
+}
+
 /// Holds EncoderFFmpeg information
 #[derive(Debug)]
 pub struct EncoderFFmpeg {
@@ -155,8 +263,6 @@ pub struct EncoderFFmpeg {
     framerate: (i32, i32),
     // Codec runtime variables
     context: AVCodecContext,
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    has_ssse3: bool,
     packet: AVPacket,
     frame: AVFrame,
     /// Y yuv image part
@@ -167,20 +273,220 @@ pub struct EncoderFFmpeg {
     image_v: Vec<u8>,
     /// UV yuv image part for nv12
     image_uv: Vec<u8>,
+    /// 10 bits rgba image for p010
+    image_rgba10: Vec<u16>,
+    /// Y yuv image part for p010
+    image_y10: Vec<u16>,
+    /// UV yuv image part for p010
+    image_uv10: Vec<u16>,
     /// Command to execute to get new options on encoder renewal
     command: Option<String>,
+    /// Color matrix used to convert images to yuv
+    yuv_type: yuv_rgb_rs::YuvType,
+    /// Range of the yuv samples
+    color_range: yuv_rgb_rs::ColorRange,
     /// image size
     size: (u16, u16),
+    /// Encode the next frame as a keyframe
+    force_keyframe: bool,
+    /// Regions of interest of the next frame
+    regions_of_interest: Vec<RegionOfInterest>,
+    /// Vaapi device path, for hardware encoders
+    hw_device: Option<String>,
+    /// Hardware frames context and frame used to upload images to the device
+    hw_upload: Option<(AVBufferRef, AVFrame)>,
+    /// Frames held by the encoder before it outputs their packets
+    delay: u32,
 }
 
+// The ffmpeg contexts are owned by the encoder, which is only used by one
+// thread at a time
+unsafe impl Send for EncoderFFmpeg {}
+
+/// Label of the copy of the frame to the exported video memory
+pub const TIMING_MEMCPY: &str = "memcpy";
+/// Label of the conversion of the frame to the encoder pixel format
+pub const TIMING_CONVERT: &str = "yuv";
+/// Label of the encoding of the converted frame
+pub const TIMING_ENCODE: &str = "enc";
+
+/// Presentation timestamp of the warm-up image, before the first real image
+const WARM_UP_PTS: i64 = -1;
+
 pub struct EncoderTimings {
     pub times: Vec<(&'static str, Duration)>,
+    /// Statistics of the encoded frame, if known by the encoder
+    pub frame: Option<FrameStats>,
+}
+
+/// Picture type of an encoded frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    Unknown,
+    I,
+    P,
+    B,
+}
+
+/// Statistics of an encoded frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameStats {
+    /// Encoded size in bytes
+    pub size: usize,
+    pub frame_type: FrameType,
+    /// Quantizer, if exported by the encoder
+    pub qp: Option<f32>,
+}
+
+impl FrameStats {
+    pub fn to_msg(&self) -> tunnel::MessageSrv {
+        let frame_type = match self.frame_type {
+            FrameType::Unknown => tunnel::FrameType::Unknown,
+            FrameType::I => tunnel::FrameType::I,
+            FrameType::P => tunnel::FrameType::P,
+            FrameType::B => tunnel::FrameType::B,
+        };
+        let stats = tunnel::EventFrameStats {
+            size: self.size as u32,
+            frame_type: frame_type as i32,
+            qp: self.qp.unwrap_or(-1.0),
+        };
+        tunnel::MessageSrv {
+            msg: Some(tunnel::message_srv::Msg::FrameStats(stats)),
+        }
+    }
+}
+
+/// Picture type and quantizer of an encoded `packet`
+///
+/// They are read from the quality stats side data, if exported by the encoder.
+/// Otherwise, only key frames are detected.
+unsafe fn packet_frame_info(packet: *const ffmpeg::AVPacket) -> (FrameType, Option<f32>) {
+    let mut size = 0;
+    let data = ffmpeg::av_packet_get_side_data(
+        packet,
+        ffmpeg::AVPacketSideDataType::AV_PKT_DATA_QUALITY_STATS,
+        &mut size,
+    );
+    if !data.is_null() && size >= 5 {
+        // quality (le32, qp * FF_QP2LAMBDA), then picture type
+        let stats = std::slice::from_raw_parts(data, size);
+        let quality = i32::from_le_bytes([stats[0], stats[1], stats[2], stats[3]]);
+        let frame_type = match stats[4] as u32 {
+            x if x == ffmpeg::AVPictureType::AV_PICTURE_TYPE_I as u32 => FrameType::I,
+            x if x == ffmpeg::AVPictureType::AV_PICTURE_TYPE_P as u32 => FrameType::P,
+            x if x == ffmpeg::AVPictureType::AV_PICTURE_TYPE_B as u32 => FrameType::B,
+            _ => FrameType::Unknown,
+        };
+        return (
+            frame_type,
+            Some(quality as f32 / ffmpeg::FF_QP2LAMBDA as f32),
+        );
+    }
+    if (*packet).flags & ffmpeg::AV_PKT_FLAG_KEY as i32 != 0 {
+        (FrameType::I, None)
+    } else {
+        (FrameType::Unknown, None)
+    }
+}
+
+/// Rectangle of an image encoded with a quantizer offset
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegionOfInterest {
+    /// (x0, y0, x1, y1), with exclusive x1 / y1
+    pub region: (u32, u32, u32, u32),
+    /// Quantizer offset, from -1 (best quality) to 1 (lowest quality)
+    pub qoffset: f32,
+}
+
+impl RegionOfInterest {
+    /// Part of the region in the band of `height` rows starting at `y`, in the
+    /// band coordinates
+    fn crop_band(&self, y: u32, height: u32) -> Option<RegionOfInterest> {
+        let (x0, y0, x1, y1) = self.region;
+        let (y0, y1) = (y0.max(y), y1.min(y + height));
+        (y0 < y1).then_some(RegionOfInterest {
+            region: (x0, y0 - y, x1, y1 - y),
+            qoffset: self.qoffset,
+        })
+    }
+}
+
+/// Regions of interest of an image of `size`, whose `damaged` region (x0, y0,
+/// x1, y1) has changed: it is encoded with `active_qoffset`, the rest of the
+/// image with `static_qoffset`
+///
+/// No region is set if nothing or the whole image has changed: the encoder
+/// then spreads its bits as usual.
+pub fn damage_regions_of_interest(
+    damaged: Option<(u16, u16, u16, u16)>,
+    size: (u16, u16),
+    (active_qoffset, static_qoffset): (f32, f32),
+) -> Vec<RegionOfInterest> {
+    let (x0, y0, x1, y1) = match damaged {
+        Some(region) if region != (0, 0, size.0, size.1) => region,
+        _ => return vec![],
+    };
+    // The first region containing a pixel sets its quantizer offset
+    vec![
+        RegionOfInterest {
+            region: (x0 as u32, y0 as u32, x1 as u32, y1 as u32),
+            qoffset: active_qoffset,
+        },
+        RegionOfInterest {
+            region: (0, 0, size.0 as u32, size.1 as u32),
+            qoffset: static_qoffset,
+        },
+    ]
+}
+
+/// Attach the `regions` of interest side data to `frame`, replacing the
+/// previous ones
+unsafe fn set_frame_regions_of_interest(
+    frame: *mut ffmpeg::AVFrame,
+    regions: &[RegionOfInterest],
+) -> Result<()> {
+    let side_data_type = ffmpeg::AVFrameSideDataType::AV_FRAME_DATA_REGIONS_OF_INTEREST;
+    ffmpeg::av_frame_remove_side_data(frame, side_data_type);
+    if regions.is_empty() {
+        return Ok(());
+    }
+    let entry_size = std::mem::size_of::<ffmpeg::AVRegionOfInterest>();
+    let side_data =
+        ffmpeg::av_frame_new_side_data(frame, side_data_type, regions.len() * entry_size);
+    if side_data.is_null() {
+        return Err(anyhow!("Cannot allocate regions of interest side data"));
+    }
+    let entries = std::slice::from_raw_parts_mut(
+        (*side_data).data as *mut ffmpeg::AVRegionOfInterest,
+        regions.len(),
+    );
+    for (entry, roi) in entries.iter_mut().zip(regions) {
+        let (x0, y0, x1, y1) = roi.region;
+        *entry = ffmpeg::AVRegionOfInterest {
+            self_size: entry_size as u32,
+            top: y0 as i32,
+            bottom: y1 as i32,
+            left: x0 as i32,
+            right: x1 as i32,
+            qoffset: ffmpeg::AVRational {
+                num: (roi.qoffset.clamp(-1.0, 1.0) * 1000.0) as i32,
+                den: 1000,
+            },
+        };
+    }
+    Ok(())
 }
 
-pub trait Encoder {
+/// Encoded data of a tile
+pub struct EncodedTile {
+    pub data: Vec<u8>,
+    /// Rectangle of the tile, None if the image is not split
+    pub tile: Option<tunnel::Tile>,
+}
+
+pub trait Encoder: Send {
     fn is_raw(&self) -> bool;
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    fn has_ssse3(&self) -> bool;
     fn name(&self) -> String;
     fn options(&self) -> HashMap<String, String>;
     fn framerate(&self) -> (i32, i32);
@@ -192,18 +498,223 @@ pub trait Encoder {
         bytes_per_line: u32,
         count: i64,
     ) -> Result<(Vec<u8>, EncoderTimings)>;
+    /// Encode a packed yuv 4:2:2 (yuyv) image
+    ///
+    /// Samples are expected in the encoder color space. The default
+    /// implementation goes through an rgba image.
+    fn encode_yuyv_image(
+        &mut self,
+        image: &[u8],
+        width: u32,
+        height: u32,
+        bytes_per_line: u32,
+        count: i64,
+    ) -> Result<(Vec<u8>, EncoderTimings)> {
+        let (yuv_type, color_range) = self.color_space();
+        let (rgba, rgba_stride) =
+            yuyv_to_rgba_image(image, width, height, bytes_per_line, yuv_type, color_range)?;
+        self.encode_image(&rgba, width, height, rgba_stride, count)
+    }
+    /// Encode an image, split in tiles if the encoder does so
+    ///
+    /// The default implementation returns a single tile covering the image.
+    fn encode_image_tiles(
+        &mut self,
+        image: &[u8],
+        width: u32,
+        height: u32,
+        bytes_per_line: u32,
+        count: i64,
+    ) -> Result<(Vec<EncodedTile>, EncoderTimings)> {
+        let (data, timings) = self.encode_image(image, width, height, bytes_per_line, count)?;
+        Ok((vec![EncodedTile { data, tile: None }], timings))
+    }
+    /// Encode a packed yuv 4:2:2 (yuyv) image, split in tiles if the encoder
+    /// does so
+    fn encode_yuyv_image_tiles(
+        &mut self,
+        image: &[u8],
+        width: u32,
+        height: u32,
+        bytes_per_line: u32,
+        count: i64,
+    ) -> Result<(Vec<EncodedTile>, EncoderTimings)> {
+        let (data, timings) =
+            self.encode_yuyv_image(image, width, height, bytes_per_line, count)?;
+        Ok((vec![EncodedTile { data, tile: None }], timings))
+    }
+    /// Color matrix and range of the encoded images
+    fn color_space(&self) -> (yuv_rgb_rs::YuvType, yuv_rgb_rs::ColorRange) {
+        (yuv_rgb_rs::YuvType::ItuT871, yuv_rgb_rs::ColorRange::Full)
+    }
+    /// Encode the next image as a keyframe
+    ///
+    /// Used to recover quickly after a client reconnect or a decode error,
+    /// instead of waiting for the next GOP boundary.
+    fn request_keyframe(&mut self) {}
+    /// Encode and discard a blank image, so the first real image does not
+    /// pay for the buffers allocation and the hardware session start
+    ///
+    /// Encoders without a costly first frame keep the default no-op.
+    fn warm_up(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Max number of frames held by the encoder (lookahead, b frames, ...)
+    /// before it outputs their packets
+    fn delay(&self) -> u32 {
+        0
+    }
+    /// Change the target bitrate of the next images
+    fn set_bitrate(&mut self, _kbps: u32) {}
+    /// Quantizer offsets of the next image regions, ignored by the encoders
+    /// not honoring the regions of interest
+    fn set_regions_of_interest(&mut self, _regions: Vec<RegionOfInterest>) {}
+    /// Message to display to the user about an encoder change, if any
+    fn take_notice(&mut self) -> Option<String> {
+        None
+    }
     fn reload(&self) -> Result<Box<dyn Encoder>>;
     fn change_resolution(&mut self, width: u32, height: u32) -> Result<Box<dyn Encoder>>;
 }
 
+/// Convert a yuyv image to a tightly packed 32 bits one, in the byte order of
+/// the captured frames, returns the image and its bytes per line
+fn yuyv_to_rgba_image(
+    image: &[u8],
+    width: u32,
+    height: u32,
+    bytes_per_line: u32,
+    yuv_type: yuv_rgb_rs::YuvType,
+    color_range: yuv_rgb_rs::ColorRange,
+) -> Result<(Vec<u8>, u32)> {
+    let rgba_stride = width * 4;
+    let mut rgba = vec![0xFFu8; (rgba_stride * height) as usize];
+    convert::yuyv_to_rgba(
+        width as usize,
+        height as usize,
+        image,
+        bytes_per_line as usize,
+        &mut rgba,
+        rgba_stride as usize,
+        yuv_type,
+        color_range,
+        convert::FRAME_BYTE_ORDER,
+    )
+    .context("Error in yuyv_to_rgba")?;
+    Ok((rgba, rgba_stride))
+}
+
+/// Copy 16 bits samples to a little endian frame plane
+fn copy_samples_le(plane: &mut [u8], samples: &[u16]) {
+    for (dst, sample) in plane.chunks_exact_mut(2).zip(samples.iter()) {
+        dst.copy_from_slice(&sample.to_le_bytes());
+    }
+}
+
+impl EncoderFFmpeg {
+    /// Send the current frame to the codec and collect the output packets
+    fn encode_frame(
+        &mut self,
+        count: i64,
+        time_start: Instant,
+    ) -> Result<(Vec<u8>, EncoderTimings)> {
+        let frame_ptr = match self.hw_upload {
+            Some((ref hw_frames, ref hw_frame)) => {
+                // Upload the frame to a device surface
+                let hw_frame_ptr = hw_frame.get_ptr();
+                unsafe { ffmpeg::av_frame_unref(hw_frame_ptr) };
+                let retval =
+                    unsafe { ffmpeg::av_hwframe_get_buffer(hw_frames.as_ptr(), hw_frame_ptr, 0) };
+                if retval < 0 {
+                    return Err(averror("av_hwframe_get_buffer", retval));
+                }
+                let retval = unsafe {
+                    ffmpeg::av_hwframe_transfer_data(hw_frame_ptr, self.frame.get_ptr(), 0)
+                };
+                if retval < 0 {
+                    return Err(averror("av_hwframe_transfer_data", retval));
+                }
+                hw_frame_ptr
+            }
+            None => self.frame.get_ptr(),
+        };
+        // The frame is reused: reset the picture type once the keyframe is sent
+        let pict_type = match std::mem::take(&mut self.force_keyframe) {
+            true => ffmpeg::AVPictureType::AV_PICTURE_TYPE_I,
+            false => ffmpeg::AVPictureType::AV_PICTURE_TYPE_NONE,
+        };
+        unsafe {
+            (*frame_ptr).pts = count;
+            (*frame_ptr).pict_type = pict_type;
+            set_frame_regions_of_interest(
+                frame_ptr,
+                &std::mem::take(&mut self.regions_of_interest),
+            )
+            .context("Error in set regions of interest")?;
+        }
+        let time_yuv = Instant::now();
+
+        let mut retval =
+            unsafe { ffmpeg::avcodec_send_frame(self.context.as_mut_ptr(), frame_ptr) };
+        if retval < 0 {
+            return Err(averror("avcodec_send_frame", retval));
+        }
+        let mut buffer = Vec::new();
+        let mut frame_info = None;
+        while retval >= 0 {
+            retval = unsafe {
+                ffmpeg::avcodec_receive_packet(self.context.as_mut_ptr(), self.packet.as_mut_ptr())
+            };
+            if retval == ffmpeg::AVERROR(ffmpeg::EAGAIN) || retval == ffmpeg::AVERROR_EOF {
+                break;
+            }
+            if retval < 0 {
+                return Err(anyhow!("Error in avcodec_receive_packet"));
+            }
+            // Delayed encoders may output the warm-up frame with a later image
+            if unsafe { (*self.packet.as_mut_ptr()).pts } == WARM_UP_PTS {
+                unsafe { ffmpeg::av_packet_unref(self.packet.as_mut_ptr()) };
+                continue;
+            }
+            let slice = unsafe {
+                std::slice::from_raw_parts(
+                    (*self.packet.as_mut_ptr()).data as *const u8,
+                    (*self.packet.as_mut_ptr()).size as usize,
+                )
+            };
+            buffer.extend_from_slice(slice);
+            unsafe {
+                frame_info = Some(packet_frame_info(self.packet.as_mut_ptr()));
+                ffmpeg::av_packet_unref(self.packet.as_mut_ptr());
+            }
+        }
+        let time_encode = Instant::now();
+        let duration_yuv = time_yuv - time_start;
+        let duration_enc = time_encode - time_yuv;
+        let timings = vec![
+            (TIMING_CONVERT, duration_yuv),
+            (TIMING_ENCODE, duration_enc),
+        ];
+        let frame = frame_info.map(|(frame_type, qp)| FrameStats {
+            size: buffer.len(),
+            frame_type,
+            qp,
+        });
+
+        Ok((
+            buffer,
+            EncoderTimings {
+                times: timings,
+                frame,
+            },
+        ))
+    }
+}
+
 impl Encoder for EncoderFFmpeg {
     fn is_raw(&self) -> bool {
         false
     }
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    fn has_ssse3(&self) -> bool {
-        self.has_ssse3
-    }
     fn name(&self) -> String {
         self.name.clone()
     }
@@ -236,54 +747,22 @@ impl Encoder for EncoderFFmpeg {
                     (y_lane, u_lane, v_lane)
                 };
 
-                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-                {
-                    if self.has_ssse3() {
-                        yuv_rgb_rs::rgba_to_yuv420_ssse3(
-                            width as usize,
-                            height as usize,
-                            image,
-                            bytes_per_line as usize,
-                            &mut self.image_y,
-                            &mut self.image_u,
-                            &mut self.image_v,
-                            y_lane as usize,
-                            u_lane as usize,
-                            v_lane as usize,
-                            yuv_rgb_rs::YuvType::ItuT871,
-                        );
-                    } else {
-                        yuv_rgb_rs::rgba_to_yuv420_std(
-                            width as usize,
-                            height as usize,
-                            image,
-                            bytes_per_line as usize,
-                            &mut self.image_y,
-                            &mut self.image_u,
-                            &mut self.image_v,
-                            y_lane as usize,
-                            u_lane as usize,
-                            v_lane as usize,
-                            yuv_rgb_rs::YuvType::ItuT871,
-                        );
-                    }
-                }
-                #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
-                {
-                    yuv_rgb_rs::rgba_to_yuv420_std_rayon(
-                        width as usize,
-                        height as usize,
-                        image,
-                        bytes_per_line as usize,
-                        &mut self.image_y,
-                        &mut self.image_u,
-                        &mut self.image_v,
-                        y_lane as usize,
-                        u_lane as usize,
-                        v_lane as usize,
-                        yuv_rgb_rs::YuvType::ItuT871,
-                    );
-                }
+                convert::rgba_to_yuv420(
+                    width as usize,
+                    height as usize,
+                    image,
+                    bytes_per_line as usize,
+                    &mut self.image_y,
+                    &mut self.image_u,
+                    &mut self.image_v,
+                    y_lane as usize,
+                    u_lane as usize,
+                    v_lane as usize,
+                    self.yuv_type,
+                    self.color_range,
+                    convert::FRAME_BYTE_ORDER,
+                )
+                .context("Error in rgba_to_yuv420")?;
 
                 let y_size = (y_lane * height) as usize;
                 let uv_size = (u_lane * height / 2) as usize;
@@ -311,54 +790,22 @@ impl Encoder for EncoderFFmpeg {
                     (y_lane, u_lane, v_lane)
                 };
 
-                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-                {
-                    if self.has_ssse3() {
-                        yuv_rgb_rs::rgba_to_yuv444_ssse3(
-                            width as usize,
-                            height as usize,
-                            image,
-                            bytes_per_line as usize,
-                            &mut self.image_y,
-                            &mut self.image_u,
-                            &mut self.image_v,
-                            y_lane as usize,
-                            u_lane as usize,
-                            v_lane as usize,
-                            yuv_rgb_rs::YuvType::ItuT871,
-                        );
-                    } else {
-                        yuv_rgb_rs::rgba_to_yuv444_std(
-                            width as usize,
-                            height as usize,
-                            image,
-                            bytes_per_line as usize,
-                            &mut self.image_y,
-                            &mut self.image_u,
-                            &mut self.image_v,
-                            y_lane as usize,
-                            u_lane as usize,
-                            v_lane as usize,
-                            yuv_rgb_rs::YuvType::ItuT871,
-                        );
-                    }
-                }
-                #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
-                {
-                    yuv_rgb_rs::rgba_to_yuv444_std_rayon(
-                        width as usize,
-                        height as usize,
-                        image,
-                        bytes_per_line as usize,
-                        &mut self.image_y,
-                        &mut self.image_u,
-                        &mut self.image_v,
-                        y_lane as usize,
-                        u_lane as usize,
-                        v_lane as usize,
-                        yuv_rgb_rs::YuvType::ItuT871,
-                    );
-                }
+                convert::rgba_to_yuv444(
+                    width as usize,
+                    height as usize,
+                    image,
+                    bytes_per_line as usize,
+                    &mut self.image_y,
+                    &mut self.image_u,
+                    &mut self.image_v,
+                    y_lane as usize,
+                    u_lane as usize,
+                    v_lane as usize,
+                    self.yuv_type,
+                    self.color_range,
+                    convert::FRAME_BYTE_ORDER,
+                )
+                .context("Error in rgba_to_yuv444")?;
 
                 let y_size = (y_lane * height) as usize;
                 let uv_size = (u_lane * height) as usize;
@@ -385,48 +832,57 @@ impl Encoder for EncoderFFmpeg {
                     (y_lane, uv_lane)
                 };
 
-                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-                {
-                    if self.has_ssse3() {
-                        yuv_rgb_rs::rgba_to_nv12_ssse3(
-                            width as usize,
-                            height as usize,
-                            image,
-                            bytes_per_line as usize,
-                            &mut self.image_y,
-                            &mut self.image_uv,
-                            y_lane as usize,
-                            uv_lane as usize,
-                            yuv_rgb_rs::YuvType::ItuT871,
-                        );
-                    } else {
-                        yuv_rgb_rs::rgba_to_nv12_std(
-                            width as usize,
-                            height as usize,
-                            image,
-                            bytes_per_line as usize,
-                            &mut self.image_y,
-                            &mut self.image_uv,
-                            y_lane as usize,
-                            uv_lane as usize,
-                            yuv_rgb_rs::YuvType::ItuT871,
-                        );
-                    }
-                }
-                #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
-                {
-                    yuv_rgb_rs::rgba_to_nv12_std(
-                        width as usize,
-                        height as usize,
-                        image,
-                        bytes_per_line as usize,
-                        &mut self.image_y,
-                        &mut self.image_uv,
-                        y_lane as usize,
-                        uv_lane as usize,
-                        yuv_rgb_rs::YuvType::ItuT871,
-                    );
-                }
+                convert::rgba_to_nv12(
+                    width as usize,
+                    height as usize,
+                    image,
+                    bytes_per_line as usize,
+                    &mut self.image_y,
+                    &mut self.image_uv,
+                    y_lane as usize,
+                    uv_lane as usize,
+                    self.yuv_type,
+                    self.color_range,
+                    convert::FRAME_BYTE_ORDER,
+                )
+                .context("Error in rgba_to_nv12")?;
+
+                let y_size = (y_lane * height) as usize;
+                let uv_size = (uv_lane * height / 2) as usize;
+
+                self.frame
+                    .make_writable()
+                    .context("Error in make_writable")?;
+
+                self.frame
+                    .plane(0, y_size)
+                    .copy_from_slice(&self.image_y[0..y_size]);
+                self.frame
+                    .plane(1, uv_size)
+                    .copy_from_slice(&self.image_uv[0..uv_size]);
+            }
+            x if x == AVPixelFormat::AV_PIX_FMT_NV21 as i32 => {
+                // nv21, same as nv12 with swapped chroma samples
+                let (y_lane, uv_lane) = unsafe {
+                    let y_lane = (*self.frame.get_ptr()).linesize[0] as u32;
+                    let uv_lane = (*self.frame.get_ptr()).linesize[1] as u32;
+                    (y_lane, uv_lane)
+                };
+
+                convert::rgba_to_nv21(
+                    width as usize,
+                    height as usize,
+                    image,
+                    bytes_per_line as usize,
+                    &mut self.image_y,
+                    &mut self.image_uv,
+                    y_lane as usize,
+                    uv_lane as usize,
+                    self.yuv_type,
+                    self.color_range,
+                    convert::FRAME_BYTE_ORDER,
+                )
+                .context("Error in rgba_to_nv21")?;
 
                 let y_size = (y_lane * height) as usize;
                 let uv_size = (uv_lane * height / 2) as usize;
@@ -442,6 +898,41 @@ impl Encoder for EncoderFFmpeg {
                     .plane(1, uv_size)
                     .copy_from_slice(&self.image_uv[0..uv_size]);
             }
+            x if x == AVPixelFormat::AV_PIX_FMT_P010LE as i32 => {
+                // p010, from rgba expanded to 10 bits samples
+                let (y_lane, uv_lane) = unsafe {
+                    let y_lane = (*self.frame.get_ptr()).linesize[0] as u32;
+                    let uv_lane = (*self.frame.get_ptr()).linesize[1] as u32;
+                    (y_lane, uv_lane)
+                };
+
+                // Lanes are in bytes, p010 samples are 16 bits long
+                convert::rgba_to_p010(
+                    width as usize,
+                    height as usize,
+                    image,
+                    bytes_per_line as usize,
+                    &mut self.image_rgba10,
+                    &mut self.image_y10,
+                    &mut self.image_uv10,
+                    y_lane as usize / 2,
+                    uv_lane as usize / 2,
+                    self.yuv_type,
+                    self.color_range,
+                    convert::FRAME_BYTE_ORDER,
+                )
+                .context("Error in rgba_to_p010")?;
+
+                let y_size = (y_lane * height) as usize;
+                let uv_size = (uv_lane * height / 2) as usize;
+
+                self.frame
+                    .make_writable()
+                    .context("Error in make_writable")?;
+
+                copy_samples_le(self.frame.plane(0, y_size), &self.image_y10);
+                copy_samples_le(self.frame.plane(1, uv_size), &self.image_uv10);
+            }
             x if x == AVPixelFormat::AV_PIX_FMT_RGB0 as i32 => {
                 // rgb0
                 let image_bpl = bytes_per_line as usize;
@@ -480,44 +971,117 @@ impl Encoder for EncoderFFmpeg {
             }
         };
 
-        unsafe {
-            (*self.frame.get_ptr()).pts = count;
-        }
-        let time_yuv = Instant::now();
+        self.encode_frame(count, time_start)
+    }
 
-        let mut retval =
-            unsafe { ffmpeg::avcodec_send_frame(self.context.as_mut_ptr(), self.frame.get_ptr()) };
-        if retval < 0 {
-            return Err(averror("avcodec_send_frame", retval));
+    fn encode_yuyv_image(
+        &mut self,
+        image: &[u8],
+        width: u32,
+        height: u32,
+        bytes_per_line: u32,
+        count: i64,
+    ) -> Result<(Vec<u8>, EncoderTimings)> {
+        let time_start = Instant::now();
+
+        let pixel_format = unsafe { (*self.frame.get_ptr()).format };
+        if pixel_format != AVPixelFormat::AV_PIX_FMT_YUV420P as i32 {
+            // Other pixel formats need the rgba intermediate
+            let (rgba, rgba_stride) = yuyv_to_rgba_image(
+                image,
+                width,
+                height,
+                bytes_per_line,
+                self.yuv_type,
+                self.color_range,
+            )?;
+            return self.encode_image(&rgba, width, height, rgba_stride, count);
         }
-        let mut buffer = Vec::new();
-        while retval >= 0 {
-            retval = unsafe {
-                ffmpeg::avcodec_receive_packet(self.context.as_mut_ptr(), self.packet.as_mut_ptr())
-            };
-            if retval == ffmpeg::AVERROR(ffmpeg::EAGAIN) || retval == ffmpeg::AVERROR_EOF {
-                break;
-            }
-            if retval < 0 {
-                return Err(anyhow!("Error in avcodec_receive_packet"));
-            }
-            let slice = unsafe {
-                std::slice::from_raw_parts(
-                    (*self.packet.as_mut_ptr()).data as *const u8,
-                    (*self.packet.as_mut_ptr()).size as usize,
-                )
-            };
-            buffer.extend_from_slice(slice);
-            unsafe {
-                ffmpeg::av_packet_unref(self.packet.as_mut_ptr());
+
+        // yuv420, samples are only repacked
+        let (y_lane, u_lane, v_lane) = unsafe {
+            let y_lane = (*self.frame.get_ptr()).linesize[0] as u32;
+            let u_lane = (*self.frame.get_ptr()).linesize[1] as u32;
+            let v_lane = (*self.frame.get_ptr()).linesize[2] as u32;
+            (y_lane, u_lane, v_lane)
+        };
+
+        yuv_rgb_rs::yuyv_to_yuv420_std(
+            width as usize,
+            height as usize,
+            image,
+            bytes_per_line as usize,
+            &mut self.image_y,
+            &mut self.image_u,
+            &mut self.image_v,
+            y_lane as usize,
+            u_lane as usize,
+            v_lane as usize,
+        );
+
+        let y_size = (y_lane * height) as usize;
+        let uv_size = (u_lane * height / 2) as usize;
+
+        self.frame
+            .make_writable()
+            .context("Error in make_writable")?;
+
+        self.frame
+            .plane(0, y_size)
+            .copy_from_slice(&self.image_y[0..y_size]);
+        self.frame
+            .plane(1, uv_size)
+            .copy_from_slice(&self.image_u[0..uv_size]);
+        self.frame
+            .plane(2, uv_size)
+            .copy_from_slice(&self.image_v[0..uv_size]);
+
+        self.encode_frame(count, time_start)
+    }
+
+    fn color_space(&self) -> (yuv_rgb_rs::YuvType, yuv_rgb_rs::ColorRange) {
+        (self.yuv_type, self.color_range)
+    }
+
+    fn request_keyframe(&mut self) {
+        self.force_keyframe = true;
+    }
+
+    fn warm_up(&mut self) -> Result<()> {
+        let (width, height) = (self.size.0 as u32, self.size.1 as u32);
+        let image = vec![0u8; (width * height * 4) as usize];
+        self.encode_image(&image, width, height, width * 4, WARM_UP_PTS)
+            .context("Error in warm-up encode")?;
+        // The first real image must not reference the discarded one
+        self.request_keyframe();
+        Ok(())
+    }
+
+    fn delay(&self) -> u32 {
+        self.delay
+    }
+
+    fn set_regions_of_interest(&mut self, regions: Vec<RegionOfInterest>) {
+        self.regions_of_interest = regions;
+    }
+
+    fn set_bitrate(&mut self, kbps: u32) {
+        let bit_rate = kbps as i64 * 1000;
+        let context_ptr = self.context.as_mut_ptr();
+        let constrained = unsafe { (*context_ptr).rc_max_rate != 0 };
+        unsafe {
+            (*context_ptr).bit_rate = bit_rate;
+            // Only constrained rate control modes use a max rate
+            if constrained {
+                (*context_ptr).rc_max_rate = bit_rate;
             }
         }
-        let time_encode = Instant::now();
-        let duration_yuv = time_yuv - time_start;
-        let duration_enc = time_encode - time_yuv;
-        let timings = vec![("yuv", duration_yuv), ("enc", duration_enc)];
-
-        Ok((buffer, EncoderTimings { times: timings }))
+        // Keep the bitrate on encoder reload
+        self.options.insert("b".to_owned(), bit_rate.to_string());
+        if constrained {
+            self.options
+                .insert("maxrate".to_owned(), bit_rate.to_string());
+        }
     }
 
     fn reload(&self) -> Result<Box<dyn Encoder>> {
@@ -536,9 +1100,13 @@ impl Encoder for EncoderFFmpeg {
             .set_option("video_size", &video_size)
             .context(format!("Error in set_option video_size {video_size:?}"))?;
         builder.set_framerate(framerate.0, framerate.1);
+        builder.set_color_space(self.yuv_type, self.color_range);
         if let Some(ref command) = self.command {
             builder.set_command(command)?;
         }
+        if let Some(ref hw_device) = self.hw_device {
+            builder.set_hw_device(hw_device);
+        }
 
         let encoder = builder.open().context("Error in encoder open")?;
         Ok(Box::new(encoder))
@@ -566,10 +1134,6 @@ impl Encoder for EncoderNull {
     fn is_raw(&self) -> bool {
         true
     }
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    fn has_ssse3(&self) -> bool {
-        false
-    }
     fn name(&self) -> String {
         "".to_owned()
     }
@@ -587,7 +1151,13 @@ impl Encoder for EncoderNull {
         _bytes_per_line: u32,
         _count: i64,
     ) -> Result<(Vec<u8>, EncoderTimings)> {
-        Ok((image.to_owned(), EncoderTimings { times: vec![] }))
+        Ok((
+            image.to_owned(),
+            EncoderTimings {
+                times: vec![],
+                frame: None,
+            },
+        ))
     }
     fn reload(&self) -> Result<Box<dyn Encoder>> {
         Ok(Box::new(EncoderNull::new()))
@@ -598,12 +1168,764 @@ impl Encoder for EncoderNull {
     }
 }
 
+/// Default number of consecutive empty / failed frames tolerated, beyond the
+/// encoder delay, before reporting a misconfigured encoder
+pub const DEFAULT_MAX_ENCODER_FAILURES: u32 = 25;
+
+/// Encoder wrapper used to detect misconfigured codecs
+///
+/// Some codec configurations (bad pixel format, unsupported parameters, ...)
+/// are accepted at open time, but the encoder then fails or never outputs any
+/// packet. Each codec error is logged and an empty frame is returned. After
+/// `max_failures` consecutive empty or failed frames, an error is returned so
+/// that the caller can report it and switch to another encoder. The first
+/// empty frames, up to the encoder delay, are held by the encoder and are not
+/// failures.
+pub struct EncoderWatchdog {
+    encoder: Box<dyn Encoder>,
+    max_failures: u32,
+    failures: u32,
+    /// Consecutive empty frames
+    empty: u32,
+}
+
+impl EncoderWatchdog {
+    pub fn new(encoder: Box<dyn Encoder>, max_failures: u32) -> Self {
+        EncoderWatchdog {
+            encoder,
+            max_failures,
+            failures: 0,
+            empty: 0,
+        }
+    }
+
+    /// Number of consecutive empty or failed frames
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Count empty or failed frames, and fail once `max_failures` is reached
+    fn check_output(
+        &mut self,
+        result: Result<(Vec<u8>, EncoderTimings)>,
+    ) -> Result<(Vec<u8>, EncoderTimings)> {
+        let timings = match result {
+            Ok((buffer, timings)) if !buffer.is_empty() => {
+                self.failures = 0;
+                self.empty = 0;
+                return Ok((buffer, timings));
+            }
+            Ok((_, timings)) => {
+                self.empty += 1;
+                if self.empty <= self.encoder.delay() {
+                    // Frame held by the encoder
+                    return Ok((vec![], timings));
+                }
+                debug!("Encoder {:?} produced no output", self.encoder.name());
+                timings
+            }
+            Err(err) => {
+                error!("Encoder {:?} error", self.encoder.name());
+                err.chain().for_each(|cause| error!(" - due to {}", cause));
+                EncoderTimings {
+                    times: vec![],
+                    frame: None,
+                }
+            }
+        };
+        self.failures += 1;
+        if self.failures >= self.max_failures {
+            return Err(anyhow!(
+                "Encoder {:?} produced no output for {} frames, check pixel format/params",
+                self.encoder.name(),
+                self.failures
+            ));
+        }
+        Ok((vec![], timings))
+    }
+}
+
+impl Encoder for EncoderWatchdog {
+    fn is_raw(&self) -> bool {
+        self.encoder.is_raw()
+    }
+    fn name(&self) -> String {
+        self.encoder.name()
+    }
+    fn options(&self) -> HashMap<String, String> {
+        self.encoder.options()
+    }
+    fn framerate(&self) -> (i32, i32) {
+        self.encoder.framerate()
+    }
+    fn encode_image(
+        &mut self,
+        image: &[u8],
+        width: u32,
+        height: u32,
+        bytes_per_line: u32,
+        count: i64,
+    ) -> Result<(Vec<u8>, EncoderTimings)> {
+        let result = self
+            .encoder
+            .encode_image(image, width, height, bytes_per_line, count);
+        self.check_output(result)
+    }
+    fn encode_yuyv_image(
+        &mut self,
+        image: &[u8],
+        width: u32,
+        height: u32,
+        bytes_per_line: u32,
+        count: i64,
+    ) -> Result<(Vec<u8>, EncoderTimings)> {
+        let result = self
+            .encoder
+            .encode_yuyv_image(image, width, height, bytes_per_line, count);
+        self.check_output(result)
+    }
+    fn color_space(&self) -> (yuv_rgb_rs::YuvType, yuv_rgb_rs::ColorRange) {
+        self.encoder.color_space()
+    }
+    fn request_keyframe(&mut self) {
+        self.encoder.request_keyframe()
+    }
+    fn warm_up(&mut self) -> Result<()> {
+        // The discarded output is not a failed frame
+        self.encoder.warm_up()
+    }
+    fn delay(&self) -> u32 {
+        self.encoder.delay()
+    }
+    fn set_regions_of_interest(&mut self, regions: Vec<RegionOfInterest>) {
+        self.encoder.set_regions_of_interest(regions)
+    }
+    fn set_bitrate(&mut self, kbps: u32) {
+        self.encoder.set_bitrate(kbps)
+    }
+    fn take_notice(&mut self) -> Option<String> {
+        self.encoder.take_notice()
+    }
+    fn reload(&self) -> Result<Box<dyn Encoder>> {
+        let encoder = self.encoder.reload()?;
+        Ok(Box::new(EncoderWatchdog::new(encoder, self.max_failures)))
+    }
+
+    fn change_resolution(&mut self, width: u32, height: u32) -> Result<Box<dyn Encoder>> {
+        let encoder = self.encoder.change_resolution(width, height)?;
+        Ok(Box::new(EncoderWatchdog::new(encoder, self.max_failures)))
+    }
+}
+
+/// Software encoder replacing the hardware encoder `name`, if `name` is a
+/// hardware encoder
+pub fn software_fallback_encoder(name: &str) -> Option<&'static str> {
+    let hardware = ["_nvenc", "_qsv", "_amf", "_vaapi", "_v4l2m2m"]
+        .iter()
+        .any(|suffix| name.ends_with(suffix));
+    if !hardware {
+        return None;
+    }
+    match get_encoder_category(&name.to_owned()).ok()?.as_str() {
+        "h264" => Some("libx264"),
+        "hevc" => Some("libx265"),
+        "av1" => Some("libsvtav1"),
+        _ => None,
+    }
+}
+
+/// Hardware encoder wrapper switching to a software encoder on failure
+///
+/// A hardware encoder can be opened at startup and then fail during the
+/// session (GPU session limit reached on a resolution change, driver reset,
+/// ...). On such an error, the software encoder `software_name` is opened with
+/// the same resolution and bitrate, and the frame is encoded again with it. As
+/// the software encoder starts with a keyframe, the client decoder
+/// resynchronizes.
+pub struct EncoderHwFallback {
+    encoder: Box<dyn Encoder>,
+    software_name: String,
+    /// Software encoder options (global then codec specific)
+    software_options: Vec<(String, String)>,
+    size: (u32, u32),
+    color_space: (yuv_rgb_rs::YuvType, yuv_rgb_rs::ColorRange),
+    gop: GopSettings,
+    chroma: Option<Chroma>,
+    bitrate: Option<u32>,
+    /// True once the software encoder is used
+    fallen_back: bool,
+    notice: Option<String>,
+}
+
+impl EncoderHwFallback {
+    pub fn new(
+        encoder: Box<dyn Encoder>,
+        software_name: &str,
+        software_options: Vec<(String, String)>,
+        size: (u32, u32),
+        gop: GopSettings,
+        chroma: Option<Chroma>,
+    ) -> Self {
+        let color_space = encoder.color_space();
+        EncoderHwFallback {
+            encoder,
+            software_name: software_name.to_owned(),
+            software_options,
+            size,
+            color_space,
+            gop,
+            chroma,
+            bitrate: None,
+            fallen_back: false,
+            notice: None,
+        }
+    }
+
+    /// Same wrapper state around a new `encoder`
+    fn rewrap(
+        &self,
+        encoder: Box<dyn Encoder>,
+        size: (u32, u32),
+        fallen_back: bool,
+        notice: Option<String>,
+    ) -> Box<dyn Encoder> {
+        Box::new(EncoderHwFallback {
+            encoder,
+            software_name: self.software_name.clone(),
+            software_options: self.software_options.clone(),
+            size,
+            color_space: self.color_space,
+            gop: self.gop,
+            chroma: self.chroma,
+            bitrate: self.bitrate,
+            fallen_back,
+            notice,
+        })
+    }
+
+    fn fallback_notice(&self) -> String {
+        format!("Encoder fell back to software ({})", self.software_name)
+    }
+
+    /// Wrap the encoder re-created by `result`, or the software encoder if the
+    /// hardware one cannot be re-created
+    fn recreate(
+        &self,
+        result: Result<Box<dyn Encoder>>,
+        size: (u32, u32),
+    ) -> Result<Box<dyn Encoder>> {
+        let err = match result {
+            Ok(encoder) => return Ok(self.rewrap(encoder, size, self.fallen_back, None)),
+            Err(err) if self.fallen_back => return Err(err),
+            Err(err) => err,
+        };
+        warn!(
+            "Cannot re-create hardware encoder {:?}, fall back to {:?}",
+            self.encoder.name(),
+            self.software_name
+        );
+        err.chain().for_each(|cause| warn!(" - due to {}", cause));
+        let encoder = self.open_software(size).context(err)?;
+        Ok(self.rewrap(encoder, size, true, Some(self.fallback_notice())))
+    }
+
+    /// Open the software encoder with the current size and bitrate
+    fn open_software(&self, size: (u32, u32)) -> Result<Box<dyn Encoder>> {
+        let options = self.software_options.iter().map(|(k, v)| (k, v));
+        let mut encoder = init_video_encoder(
+            &self.software_name,
+            Some(options),
+            None::<std::iter::Empty<(&String, &String)>>,
+            &None,
+            (size.0 as u16, size.1 as u16),
+            self.color_space.0,
+            self.color_space.1,
+            self.gop,
+            None,
+            self.chroma,
+        )
+        .context(format!(
+            "Cannot open software encoder {:?}",
+            self.software_name
+        ))?;
+        if let Some(kbps) = self.bitrate {
+            encoder.set_bitrate(kbps);
+        }
+        encoder.request_keyframe();
+        Ok(encoder)
+    }
+
+    /// Replace the failing hardware encoder by the software one
+    fn fall_back(&mut self, err: anyhow::Error) -> Result<()> {
+        if self.fallen_back {
+            return Err(err);
+        }
+        warn!(
+            "Hardware encoder {:?} failed, fall back to {:?}",
+            self.encoder.name(),
+            self.software_name
+        );
+        err.chain().for_each(|cause| warn!(" - due to {}", cause));
+        self.encoder = self.open_software(self.size).context(err)?;
+        self.fallen_back = true;
+        self.notice = Some(self.fallback_notice());
+        Ok(())
+    }
+}
+
+impl Encoder for EncoderHwFallback {
+    fn is_raw(&self) -> bool {
+        self.encoder.is_raw()
+    }
+    fn name(&self) -> String {
+        self.encoder.name()
+    }
+    fn options(&self) -> HashMap<String, String> {
+        self.encoder.options()
+    }
+    fn framerate(&self) -> (i32, i32) {
+        self.encoder.framerate()
+    }
+    fn encode_image(
+        &mut self,
+        image: &[u8],
+        width: u32,
+        height: u32,
+        bytes_per_line: u32,
+        count: i64,
+    ) -> Result<(Vec<u8>, EncoderTimings)> {
+        match self
+            .encoder
+            .encode_image(image, width, height, bytes_per_line, count)
+        {
+            Err(err) => {
+                self.fall_back(err)?;
+                self.encoder
+                    .encode_image(image, width, height, bytes_per_line, count)
+            }
+            result => result,
+        }
+    }
+    fn encode_yuyv_image(
+        &mut self,
+        image: &[u8],
+        width: u32,
+        height: u32,
+        bytes_per_line: u32,
+        count: i64,
+    ) -> Result<(Vec<u8>, EncoderTimings)> {
+        match self
+            .encoder
+            .encode_yuyv_image(image, width, height, bytes_per_line, count)
+        {
+            Err(err) => {
+                self.fall_back(err)?;
+                self.encoder
+                    .encode_yuyv_image(image, width, height, bytes_per_line, count)
+            }
+            result => result,
+        }
+    }
+    fn color_space(&self) -> (yuv_rgb_rs::YuvType, yuv_rgb_rs::ColorRange) {
+        self.encoder.color_space()
+    }
+    fn request_keyframe(&mut self) {
+        self.encoder.request_keyframe()
+    }
+    fn warm_up(&mut self) -> Result<()> {
+        // A hardware session failing to start falls back before the stream
+        if let Err(err) = self.encoder.warm_up() {
+            self.fall_back(err)?;
+            self.encoder.warm_up()?;
+        }
+        Ok(())
+    }
+    fn delay(&self) -> u32 {
+        self.encoder.delay()
+    }
+    fn set_regions_of_interest(&mut self, regions: Vec<RegionOfInterest>) {
+        self.encoder.set_regions_of_interest(regions)
+    }
+    fn set_bitrate(&mut self, kbps: u32) {
+        self.bitrate = Some(kbps);
+        self.encoder.set_bitrate(kbps)
+    }
+    fn take_notice(&mut self) -> Option<String> {
+        self.notice.take()
+    }
+    fn reload(&self) -> Result<Box<dyn Encoder>> {
+        self.recreate(self.encoder.reload(), self.size)
+    }
+
+    fn change_resolution(&mut self, width: u32, height: u32) -> Result<Box<dyn Encoder>> {
+        let result = self.encoder.change_resolution(width, height);
+        self.recreate(result, (width, height))
+    }
+}
+
+/// Horizontal bands (y, height) splitting an image of `height` rows in
+/// `count` tiles
+///
+/// Bands have an even height, except the last one which takes the remaining
+/// rows. Small images may be split in less than `count` tiles.
+pub fn tile_bands(height: u32, count: u32) -> Vec<(u32, u32)> {
+    let count = count.max(1);
+    let band_height = (height.div_ceil(count) + 1) & !1;
+    let mut bands = vec![];
+    let mut y = 0;
+    while y < height && band_height != 0 {
+        let band = band_height.min(height - y);
+        bands.push((y, band));
+        y += band;
+    }
+    if bands.is_empty() {
+        bands.push((0, height));
+    }
+    bands
+}
+
+/// Encoder splitting images in horizontal tiles
+///
+/// Each tile is encoded as a separate stream by its own encoder, the tiles
+/// being encoded in parallel. This reduces the encoding latency of large
+/// images. The client decodes each tile and composes them back.
+pub struct EncoderTiled {
+    /// One encoder per band
+    encoders: Vec<Box<dyn Encoder>>,
+    bands: Vec<(u32, u32)>,
+    /// Requested number of tiles
+    count: u32,
+}
+
+impl EncoderTiled {
+    /// Build a tiled encoder of an image of `height` rows from the `encoders`
+    /// of each of its `tile_bands`
+    pub fn new(encoders: Vec<Box<dyn Encoder>>, height: u32) -> Result<Self> {
+        let bands = tile_bands(height, encoders.len() as u32);
+        if bands.len() != encoders.len() {
+            return Err(anyhow!(
+                "{} tiles encoders for {} tiles",
+                encoders.len(),
+                bands.len()
+            ));
+        }
+        let count = encoders.len() as u32;
+        Ok(EncoderTiled {
+            encoders,
+            bands,
+            count,
+        })
+    }
+
+    fn encode_tiles(
+        &mut self,
+        image: &[u8],
+        width: u32,
+        bytes_per_line: u32,
+        count: i64,
+        yuyv: bool,
+    ) -> Result<(Vec<EncodedTile>, EncoderTimings)> {
+        let time_start = Instant::now();
+        let tiles_count = self.bands.len() as u32;
+        let results: Vec<Result<(EncodedTile, EncoderTimings)>> = self
+            .encoders
+            .par_iter_mut()
+            .zip(self.bands.par_iter())
+            .enumerate()
+            .map(|(index, (encoder, &(y, height)))| {
+                let offset = y as usize * bytes_per_line as usize;
+                let band = image
+                    .get(offset..)
+                    .ok_or_else(|| anyhow!("Image too small for tile {}", index))?;
+                let (data, timings) = match yuyv {
+                    true => encoder.encode_yuyv_image(band, width, height, bytes_per_line, count),
+                    false => encoder.encode_image(band, width, height, bytes_per_line, count),
+                }
+                .context(format!("Error in encode tile {index}"))?;
+                let tile = tunnel::Tile {
+                    index: index as u32,
+                    count: tiles_count,
+                    x: 0,
+                    y,
+                    width,
+                    height,
+                };
+                Ok((
+                    EncodedTile {
+                        data,
+                        tile: Some(tile),
+                    },
+                    timings,
+                ))
+            })
+            .collect();
+
+        let mut tiles = vec![];
+        let mut frame: Option<FrameStats> = None;
+        for result in results {
+            let (tile, timings) = result?;
+            // Report the first tile picture type, with the size of all tiles
+            frame = match (frame, timings.frame) {
+                (Some(mut frame), Some(stats)) => {
+                    frame.size += stats.size;
+                    Some(frame)
+                }
+                (frame, stats) => frame.or(stats),
+            };
+            tiles.push(tile);
+        }
+        let timings = EncoderTimings {
+            times: vec![("tiles", Instant::now() - time_start)],
+            frame,
+        };
+        Ok((tiles, timings))
+    }
+}
+
+impl Encoder for EncoderTiled {
+    fn is_raw(&self) -> bool {
+        false
+    }
+    fn name(&self) -> String {
+        self.encoders[0].name()
+    }
+    fn options(&self) -> HashMap<String, String> {
+        self.encoders[0].options()
+    }
+    fn framerate(&self) -> (i32, i32) {
+        self.encoders[0].framerate()
+    }
+    fn encode_image(
+        &mut self,
+        _image: &[u8],
+        _width: u32,
+        _height: u32,
+        _bytes_per_line: u32,
+        _count: i64,
+    ) -> Result<(Vec<u8>, EncoderTimings)> {
+        Err(anyhow!("Tiled encoder only outputs tiles"))
+    }
+    fn encode_image_tiles(
+        &mut self,
+        image: &[u8],
+        width: u32,
+        _height: u32,
+        bytes_per_line: u32,
+        count: i64,
+    ) -> Result<(Vec<EncodedTile>, EncoderTimings)> {
+        self.encode_tiles(image, width, bytes_per_line, count, false)
+    }
+    fn encode_yuyv_image_tiles(
+        &mut self,
+        image: &[u8],
+        width: u32,
+        _height: u32,
+        bytes_per_line: u32,
+        count: i64,
+    ) -> Result<(Vec<EncodedTile>, EncoderTimings)> {
+        self.encode_tiles(image, width, bytes_per_line, count, true)
+    }
+    fn color_space(&self) -> (yuv_rgb_rs::YuvType, yuv_rgb_rs::ColorRange) {
+        self.encoders[0].color_space()
+    }
+    fn request_keyframe(&mut self) {
+        // Tiles are decoded independently, all of them must restart
+        for encoder in self.encoders.iter_mut() {
+            encoder.request_keyframe();
+        }
+    }
+    fn warm_up(&mut self) -> Result<()> {
+        self.encoders
+            .par_iter_mut()
+            .enumerate()
+            .try_for_each(|(index, encoder)| {
+                encoder
+                    .warm_up()
+                    .context(format!("Error in warm-up tile {index}"))
+            })
+    }
+    fn delay(&self) -> u32 {
+        self.encoders
+            .iter()
+            .map(|encoder| encoder.delay())
+            .max()
+            .unwrap_or(0)
+    }
+    fn set_bitrate(&mut self, kbps: u32) {
+        let kbps = (kbps / self.encoders.len() as u32).max(1);
+        for encoder in self.encoders.iter_mut() {
+            encoder.set_bitrate(kbps);
+        }
+    }
+    fn set_regions_of_interest(&mut self, regions: Vec<RegionOfInterest>) {
+        for (encoder, &(y, height)) in self.encoders.iter_mut().zip(self.bands.iter()) {
+            let band_regions = regions
+                .iter()
+                .filter_map(|roi| roi.crop_band(y, height))
+                .collect();
+            encoder.set_regions_of_interest(band_regions);
+        }
+    }
+    fn take_notice(&mut self) -> Option<String> {
+        self.encoders
+            .iter_mut()
+            .find_map(|encoder| encoder.take_notice())
+    }
+    fn reload(&self) -> Result<Box<dyn Encoder>> {
+        let encoders = self
+            .encoders
+            .iter()
+            .map(|encoder| encoder.reload())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Box::new(EncoderTiled {
+            encoders,
+            bands: self.bands.clone(),
+            count: self.count,
+        }))
+    }
+
+    fn change_resolution(&mut self, width: u32, height: u32) -> Result<Box<dyn Encoder>> {
+        // Small resolutions use less tiles, restore them on larger ones
+        let bands = tile_bands(height, self.count);
+        let mut encoders = vec![];
+        for (index, &(_, band_height)) in bands.iter().enumerate() {
+            let encoder = match self.encoders.get_mut(index) {
+                Some(encoder) => encoder.change_resolution(width, band_height)?,
+                None => self.encoders[0]
+                    .reload()?
+                    .change_resolution(width, band_height)?,
+            };
+            encoders.push(encoder);
+        }
+        Ok(Box::new(EncoderTiled {
+            encoders,
+            bands,
+            count: self.count,
+        }))
+    }
+}
+
+/// Default options needed by some encoders for real time encoding, overridden
+/// by the configured options
+fn realtime_options(name: &str) -> &'static [(&'static str, &'static str)] {
+    match name {
+        "libaom-av1" => &[
+            ("pixel_format", "yuv420p"),
+            ("usage", "realtime"),
+            ("cpu-used", "8"),
+            ("lag-in-frames", "0"),
+        ],
+        "libsvtav1" => &[
+            ("pixel_format", "yuv420p"),
+            ("preset", "10"),
+            // Low delay prediction structure
+            ("svtav1-params", "pred-struct=1"),
+        ],
+        "av1_nvenc" => &[("pixel_format", "yuv420p"), ("tune", "ull")],
+        _ => &[],
+    }
+}
+
+/// Chroma subsampling of the encoded frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Chroma {
+    /// Chroma at half resolution, saves bandwidth (video playback)
+    #[serde(rename = "420")]
+    Yuv420,
+    /// Chroma at full resolution, keeps colored text legible
+    #[serde(rename = "444")]
+    Yuv444,
+}
+
+impl Chroma {
+    /// Pixel format overriding the configured `pixel_format` of an encoder,
+    /// None if the configured one already has this subsampling
+    pub fn pixel_format(&self, configured: Option<&str>) -> Option<&'static str> {
+        let subsampled = matches!(configured, Some("yuv420p" | "nv12" | "nv21" | "p010le"));
+        let full = matches!(configured, Some("yuv444p"));
+        match self {
+            Chroma::Yuv420 if !subsampled => Some("yuv420p"),
+            Chroma::Yuv444 if !full => Some("yuv444p"),
+            _ => None,
+        }
+    }
+
+    /// Subsampling switched to by the chroma hotkey
+    pub fn toggled(self) -> Self {
+        match self {
+            Chroma::Yuv420 => Chroma::Yuv444,
+            Chroma::Yuv444 => Chroma::Yuv420,
+        }
+    }
+}
+
+impl FromStr for Chroma {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "420" => Ok(Chroma::Yuv420),
+            "444" => Ok(Chroma::Yuv444),
+            _ => Err(anyhow!("Unknown chroma subsampling: {}", value)),
+        }
+    }
+}
+
+impl fmt::Display for Chroma {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Chroma::Yuv420 => write!(f, "4:2:0"),
+            Chroma::Yuv444 => write!(f, "4:4:4"),
+        }
+    }
+}
+
+/// Keyframe interval settings, overriding the ffmpeg `g` and `bf` options
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GopSettings {
+    /// Frames between two keyframes (0: intra only)
+    pub gop_size: Option<u32>,
+    /// Max consecutive b frames (0: no b frames)
+    pub max_b_frames: Option<u32>,
+}
+
+impl GopSettings {
+    /// Codec options matching the settings
+    fn options(&self) -> Vec<(&'static str, String)> {
+        let mut options = vec![];
+        if let Some(gop_size) = self.gop_size {
+            options.push(("g", gop_size.to_string()));
+        }
+        if let Some(max_b_frames) = self.max_b_frames {
+            options.push(("bf", max_b_frames.to_string()));
+        }
+        options
+    }
+}
+
+/// Open the video encoder `name`
+///
+/// `gop` overrides the ffmpeg options, so that the keyframe interval doesn't
+/// depend on the codec section. Keyframes requested with `request_keyframe`
+/// are encoded in addition to the periodic ones.
+///
+/// `vaapi_device` is the device used by vaapi encoders (default:
+/// `DEFAULT_VAAPI_DEVICE`).
+///
+/// `chroma` overrides the configured pixel format subsampling. It is ignored
+/// by vaapi encoders, which are fed nv12 frames.
+#[allow(clippy::too_many_arguments)]
 pub fn init_video_encoder<'a>(
     name: &str,
     global_options: Option<impl Iterator<Item = (&'a String, &'a String)>>,
     codec_options: Option<impl Iterator<Item = (&'a String, &'a String)>>,
     command_options: &Option<String>,
     size: (u16, u16),
+    yuv_type: yuv_rgb_rs::YuvType,
+    color_range: yuv_rgb_rs::ColorRange,
+    gop: GopSettings,
+    vaapi_device: Option<&str>,
+    chroma: Option<Chroma>,
 ) -> Result<Box<dyn Encoder>> {
     // Set log level to FATAL if building release
     #[cfg(not(debug_assertions))]
@@ -615,6 +1937,11 @@ pub fn init_video_encoder<'a>(
         name => {
             let mut enc = EncoderBuilder::new(name).context("Error in EncoderBuilder")?;
 
+            for (k, v) in realtime_options(name) {
+                enc.set_option(k, v)
+                    .context("Error in set realtime option")?;
+            }
+
             // Set global options
             if let Some(opts) = global_options {
                 for (k, v) in opts {
@@ -629,6 +1956,18 @@ pub fn init_video_encoder<'a>(
                 }
             }
 
+            for (k, v) in gop.options() {
+                enc.set_option(k, &v).context("Error in set gop option")?;
+            }
+
+            if let Some(chroma) = chroma.filter(|_| !name.ends_with("_vaapi")) {
+                let configured = enc.options.get("pixel_format").map(String::as_str);
+                if let Some(pixel_format) = chroma.pixel_format(configured) {
+                    enc.set_option("pixel_format", pixel_format)
+                        .context("Error in set chroma pixel format")?;
+                }
+            }
+
             // Set option command line
             if let Some(ref command) = command_options {
                 info!("set ffmpeg options command");
@@ -645,6 +1984,10 @@ pub fn init_video_encoder<'a>(
             };
 
             enc.set_framerate(25, 1);
+            enc.set_color_space(yuv_type, color_range);
+            if name.ends_with("_vaapi") {
+                enc.set_hw_device(vaapi_device.unwrap_or(DEFAULT_VAAPI_DEVICE));
+            }
             Box::new(enc.open().context("Error in encoder open")?)
         }
     };
@@ -653,8 +1996,11 @@ pub fn init_video_encoder<'a>(
 
 pub fn get_encoder_category(encoder_name: &String) -> Result<String> {
     let codec_name = match encoder_name.as_str() {
-        "libx264" | "h264_nvenc" | "h264_qsv" | "h264_v4l2m2m" | "h264_amf" => "h264",
-        "libx265" | "hevc_nvenc" | "hevc_qsv" | "hevc_amf" => "hevc",
+        "libx264" | "h264_nvenc" | "h264_qsv" | "h264_v4l2m2m" | "h264_amf" | "h264_vaapi" => {
+            "h264"
+        }
+        "libx265" | "hevc_nvenc" | "hevc_qsv" | "hevc_amf" | "hevc_vaapi" => "hevc",
+        "libaom-av1" | "libsvtav1" | "av1_nvenc" | "av1_qsv" | "av1_amf" | "av1_vaapi" => "av1",
         "null" => "null",
         _ => {
             return Err(anyhow!("Unknown encoder category: {:?}", encoder_name));
@@ -662,3 +2008,421 @@ pub fn get_encoder_category(encoder_name: &String) -> Result<String> {
     };
     Ok(codec_name.to_owned())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encoder which always fails
+    struct EncoderStub {}
+
+    impl Encoder for EncoderStub {
+        fn is_raw(&self) -> bool {
+            false
+        }
+        fn name(&self) -> String {
+            "stub".to_owned()
+        }
+        fn options(&self) -> HashMap<String, String> {
+            HashMap::new()
+        }
+        fn framerate(&self) -> (i32, i32) {
+            (25, 1)
+        }
+        fn encode_image(
+            &mut self,
+            _image: &[u8],
+            _width: u32,
+            _height: u32,
+            _bytes_per_line: u32,
+            _count: i64,
+        ) -> Result<(Vec<u8>, EncoderTimings)> {
+            Err(anyhow!("Invalid pixel format"))
+        }
+        fn reload(&self) -> Result<Box<dyn Encoder>> {
+            Ok(Box::new(EncoderStub {}))
+        }
+        fn change_resolution(&mut self, _width: u32, _height: u32) -> Result<Box<dyn Encoder>> {
+            Ok(Box::new(EncoderStub {}))
+        }
+    }
+
+    #[test]
+    fn test_encoder_watchdog() {
+        let image = vec![0u8; 16 * 16 * 4];
+        let mut encoder = EncoderWatchdog::new(Box::new(EncoderStub {}), 3);
+        for count in 0..2 {
+            let (data, _) = encoder.encode_image(&image, 16, 16, 16 * 4, count).unwrap();
+            assert!(data.is_empty());
+        }
+        let err = encoder
+            .encode_image(&image, 16, 16, 16 * 4, 2)
+            .err()
+            .expect("Watchdog should report the failing encoder");
+        assert!(format!("{err}").contains("\"stub\" produced no output for 3 frames"));
+        assert_eq!(encoder.failures(), 3);
+    }
+
+    #[test]
+    fn test_encoder_watchdog_reset() {
+        let image = vec![0u8; 16 * 16 * 4];
+        let mut encoder = EncoderWatchdog::new(Box::new(EncoderNull::new()), 2);
+        for count in 0..4 {
+            let (data, _) = encoder.encode_image(&image, 16, 16, 16 * 4, count).unwrap();
+            assert_eq!(data.len(), image.len());
+        }
+        assert_eq!(encoder.failures(), 0);
+    }
+
+    /// Encoder holding `delay` frames before outputting them, or never
+    /// outputting anything if `stalled`
+    struct EncoderDelayed {
+        delay: u32,
+        held: u32,
+        stalled: bool,
+    }
+
+    impl Encoder for EncoderDelayed {
+        fn is_raw(&self) -> bool {
+            false
+        }
+        fn name(&self) -> String {
+            "delayed".to_owned()
+        }
+        fn options(&self) -> HashMap<String, String> {
+            HashMap::new()
+        }
+        fn framerate(&self) -> (i32, i32) {
+            (25, 1)
+        }
+        fn encode_image(
+            &mut self,
+            image: &[u8],
+            _width: u32,
+            _height: u32,
+            _bytes_per_line: u32,
+            _count: i64,
+        ) -> Result<(Vec<u8>, EncoderTimings)> {
+            let timings = EncoderTimings {
+                times: vec![],
+                frame: None,
+            };
+            if self.stalled || self.held < self.delay {
+                self.held += 1;
+                return Ok((vec![], timings));
+            }
+            Ok((image.to_owned(), timings))
+        }
+        fn delay(&self) -> u32 {
+            self.delay
+        }
+        fn reload(&self) -> Result<Box<dyn Encoder>> {
+            Err(anyhow!("Not supported"))
+        }
+        fn change_resolution(&mut self, _width: u32, _height: u32) -> Result<Box<dyn Encoder>> {
+            Err(anyhow!("Not supported"))
+        }
+    }
+
+    #[test]
+    fn test_encoder_watchdog_delay() {
+        let image = vec![0u8; 16 * 16 * 4];
+        // The frames held by a lookahead encoder are not failures
+        let delayed = EncoderDelayed {
+            delay: 40,
+            held: 0,
+            stalled: false,
+        };
+        let mut encoder = EncoderWatchdog::new(Box::new(delayed), 3);
+        for count in 0..40 {
+            let (data, _) = encoder.encode_image(&image, 16, 16, 16 * 4, count).unwrap();
+            assert!(data.is_empty());
+        }
+        assert_eq!(encoder.failures(), 0);
+        let (data, _) = encoder.encode_image(&image, 16, 16, 16 * 4, 40).unwrap();
+        assert_eq!(data.len(), image.len());
+
+        // Past its delay, an encoder without output is reported
+        let stalled = EncoderDelayed {
+            delay: 40,
+            held: 0,
+            stalled: true,
+        };
+        let mut encoder = EncoderWatchdog::new(Box::new(stalled), 3);
+        for count in 0..42 {
+            encoder.encode_image(&image, 16, 16, 16 * 4, count).unwrap();
+        }
+        assert_eq!(encoder.failures(), 2);
+        let err = encoder
+            .encode_image(&image, 16, 16, 16 * 4, 42)
+            .err()
+            .expect("Watchdog should report the stalled encoder");
+        assert!(format!("{err}").contains("\"delayed\" produced no output for 3 frames"));
+    }
+
+    /// Encoder outputting its image rows, counting the requested keyframes
+    struct EncoderRows {
+        keyframes: std::sync::Arc<std::sync::atomic::AtomicU32>,
+    }
+
+    impl Encoder for EncoderRows {
+        fn is_raw(&self) -> bool {
+            false
+        }
+        fn name(&self) -> String {
+            "rows".to_owned()
+        }
+        fn options(&self) -> HashMap<String, String> {
+            HashMap::new()
+        }
+        fn framerate(&self) -> (i32, i32) {
+            (25, 1)
+        }
+        fn encode_image(
+            &mut self,
+            image: &[u8],
+            _width: u32,
+            height: u32,
+            bytes_per_line: u32,
+            _count: i64,
+        ) -> Result<(Vec<u8>, EncoderTimings)> {
+            let data = image[..(bytes_per_line * height) as usize].to_owned();
+            let timings = EncoderTimings {
+                times: vec![],
+                frame: None,
+            };
+            Ok((data, timings))
+        }
+        fn request_keyframe(&mut self) {
+            self.keyframes
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+        fn reload(&self) -> Result<Box<dyn Encoder>> {
+            Ok(Box::new(EncoderRows {
+                keyframes: self.keyframes.clone(),
+            }))
+        }
+        fn change_resolution(&mut self, _width: u32, _height: u32) -> Result<Box<dyn Encoder>> {
+            self.reload()
+        }
+    }
+
+    #[test]
+    fn test_tile_bands() {
+        assert_eq!(
+            tile_bands(2160, 4),
+            vec![(0, 540), (540, 540), (1080, 540), (1620, 540)]
+        );
+        assert_eq!(
+            tile_bands(1081, 4),
+            vec![(0, 272), (272, 272), (544, 272), (816, 265)]
+        );
+        assert_eq!(tile_bands(6, 4), vec![(0, 2), (2, 2), (4, 2)]);
+        assert_eq!(tile_bands(480, 1), vec![(0, 480)]);
+    }
+
+    #[test]
+    fn test_regions_of_interest() {
+        let size = (1920, 1080);
+        let qoffsets = (-0.2, 0.1);
+        assert!(damage_regions_of_interest(None, size, qoffsets).is_empty());
+        assert!(damage_regions_of_interest(Some((0, 0, 1920, 1080)), size, qoffsets).is_empty());
+
+        let regions = damage_regions_of_interest(Some((100, 500, 300, 600)), size, qoffsets);
+        assert_eq!(
+            regions,
+            vec![
+                RegionOfInterest {
+                    region: (100, 500, 300, 600),
+                    qoffset: -0.2,
+                },
+                RegionOfInterest {
+                    region: (0, 0, 1920, 1080),
+                    qoffset: 0.1,
+                },
+            ]
+        );
+
+        // Regions are split on the tiles, in the tile coordinates
+        assert_eq!(
+            regions[0].crop_band(540, 540),
+            Some(RegionOfInterest {
+                region: (100, 0, 300, 60),
+                qoffset: -0.2,
+            })
+        );
+        assert_eq!(regions[0].crop_band(0, 500), None);
+        assert_eq!(
+            regions[1].crop_band(540, 540).unwrap().region,
+            (0, 0, 1920, 540)
+        );
+    }
+
+    #[test]
+    fn test_encoder_tiled() {
+        let (width, height) = (4u32, 10u32);
+        let image: Vec<u8> = (0..width * height * 4).map(|i| i as u8).collect();
+        let keyframes = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let encoders: Vec<Box<dyn Encoder>> = (0..2)
+            .map(|_| {
+                Box::new(EncoderRows {
+                    keyframes: keyframes.clone(),
+                }) as Box<dyn Encoder>
+            })
+            .collect();
+        let mut encoder = EncoderTiled::new(encoders, height).unwrap();
+        assert!(encoder
+            .encode_image(&image, width, height, width * 4, 0)
+            .is_err());
+
+        let (tiles, _) = encoder
+            .encode_image_tiles(&image, width, height, width * 4, 0)
+            .unwrap();
+        assert_eq!(tiles.len(), 2);
+        for (index, encoded) in tiles.iter().enumerate() {
+            let tile = encoded.tile.clone().unwrap();
+            assert_eq!(tile.index, index as u32);
+            assert_eq!(tile.count, 2);
+            assert_eq!((tile.x, tile.width), (0, width));
+            let offset = (tile.y * width * 4) as usize;
+            let size = (tile.height * width * 4) as usize;
+            assert_eq!(encoded.data, image[offset..offset + size]);
+        }
+
+        encoder.request_keyframe();
+        assert_eq!(keyframes.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        // Keep the requested tiles count across resolutions
+        let mut encoder = encoder.change_resolution(width, 2).unwrap();
+        let (tiles, _) = encoder
+            .encode_image_tiles(&image, width, 2, width * 4, 1)
+            .unwrap();
+        assert_eq!(tiles.len(), 1);
+        let mut encoder = encoder.change_resolution(width, height).unwrap();
+        let (tiles, _) = encoder
+            .encode_image_tiles(&image, width, height, width * 4, 2)
+            .unwrap();
+        assert_eq!(tiles.len(), 2);
+    }
+
+    #[test]
+    fn test_chroma() {
+        assert_eq!(Chroma::Yuv444.pixel_format(None), Some("yuv444p"));
+        assert_eq!(Chroma::Yuv444.pixel_format(Some("nv12")), Some("yuv444p"));
+        assert_eq!(Chroma::Yuv444.pixel_format(Some("yuv444p")), None);
+        assert_eq!(
+            Chroma::Yuv420.pixel_format(Some("yuv444p")),
+            Some("yuv420p")
+        );
+        assert_eq!(Chroma::Yuv420.pixel_format(Some("nv12")), None);
+        assert_eq!("444".parse::<Chroma>().unwrap(), Chroma::Yuv444);
+        assert!("422".parse::<Chroma>().is_err());
+        assert_eq!(Chroma::Yuv420.toggled(), Chroma::Yuv444);
+    }
+
+    #[test]
+    fn test_gop_options() {
+        assert!(GopSettings::default().options().is_empty());
+        let gop = GopSettings {
+            gop_size: Some(0),
+            max_b_frames: Some(0),
+        };
+        assert_eq!(
+            gop.options(),
+            vec![("g", "0".to_owned()), ("bf", "0".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_software_fallback_encoder() {
+        assert_eq!(software_fallback_encoder("h264_nvenc"), Some("libx264"));
+        assert_eq!(software_fallback_encoder("hevc_vaapi"), Some("libx265"));
+        assert_eq!(software_fallback_encoder("av1_qsv"), Some("libsvtav1"));
+        assert_eq!(software_fallback_encoder("libx264"), None);
+        assert_eq!(software_fallback_encoder("null"), None);
+    }
+
+    #[test]
+    fn test_warm_up() {
+        let (width, height) = (128u32, 128u32);
+        let mut encoder = match init_video_encoder(
+            "libx264",
+            None::<std::iter::Empty<(&String, &String)>>,
+            None::<std::iter::Empty<(&String, &String)>>,
+            &None,
+            (width as u16, height as u16),
+            yuv_rgb_rs::YuvType::ItuT871,
+            yuv_rgb_rs::ColorRange::Full,
+            GopSettings::default(),
+            None,
+            None,
+        ) {
+            Ok(encoder) => encoder,
+            // Built without libx264
+            Err(_) => return,
+        };
+        encoder.warm_up().unwrap();
+
+        // The warm-up image is never output, the stream starts with a keyframe
+        let image = vec![0x80u8; (width * height * 4) as usize];
+        let frame = (0..10).find_map(|count| {
+            let (_, timings) = encoder
+                .encode_image(&image, width, height, width * 4, count)
+                .unwrap();
+            timings.frame
+        });
+        assert_eq!(frame.map(|frame| frame.frame_type), Some(FrameType::I));
+    }
+
+    #[test]
+    fn test_av1_smoke() {
+        let (width, height) = (128u32, 128u32);
+        let encoder = ["libsvtav1", "libaom-av1"].iter().find_map(|name| {
+            init_video_encoder(
+                name,
+                None::<std::iter::Empty<(&String, &String)>>,
+                None::<std::iter::Empty<(&String, &String)>>,
+                &None,
+                (width as u16, height as u16),
+                yuv_rgb_rs::YuvType::ItuT871,
+                yuv_rgb_rs::ColorRange::Full,
+                GopSettings::default(),
+                None,
+                None,
+            )
+            .ok()
+        });
+        let mut encoder = match encoder {
+            Some(encoder) => encoder,
+            // Built without av1 encoder
+            None => return,
+        };
+        assert_eq!(get_encoder_category(&encoder.name()).unwrap(), "av1");
+
+        let image = vec![0x80u8; (width * height * 4) as usize];
+        let mut encoded = vec![];
+        for count in 0..10 {
+            let (data, _) = encoder
+                .encode_image(&image, width, height, width * 4, count)
+                .unwrap();
+            if !data.is_empty() {
+                encoded = data;
+                break;
+            }
+        }
+        assert!(!encoded.is_empty());
+
+        let mut decoder = match crate::video_decoder::init_video_codec(
+            None::<std::iter::Empty<(&String, &String)>>,
+            "av1",
+            yuv_rgb_rs::YuvType::ItuT871,
+            yuv_rgb_rs::ColorRange::Full,
+        ) {
+            Ok(decoder) => decoder,
+            // Built without av1 decoder
+            Err(_) => return,
+        };
+        let (img, _) = decoder.decode_img(&encoded, width as u16, height as u16, None);
+        assert!(img.is_some());
+    }
+}
@@ -0,0 +1,244 @@
+//! Virtual X server spawned by the server when no display is reachable
+//!
+//! The X server (Xvfb, or Xorg with the dummy driver) reports its display
+//! number through `-displayfd` once it accepts connections. `DISPLAY` is then
+//! set for the server and the processes it spawns (applications, clipboard
+//! listeners).
+//!
+//! The dummy driver accepts the RandR modes created on client resolution
+//! changes, Xvfb keeps its initial resolution.
+
+use crate::config::{VirtualDisplay as VirtualDisplayConfig, VirtualDisplayServer};
+use anyhow::{Context, Result};
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    os::unix::{
+        io::{FromRawFd, RawFd},
+        process::CommandExt,
+    },
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+/// Time allowed to the X server to start
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time allowed to the X server to exit before it is killed
+const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_DEPTH: u8 = 24;
+/// Default largest resolution of the Xorg dummy driver
+const DEFAULT_MAX_SIZE: (u16, u16) = (3840, 2160);
+
+/// Spawned X server, stopped on drop
+pub struct VirtualDisplay {
+    child: Child,
+    display: String,
+    /// Generated Xorg configuration
+    config_path: Option<PathBuf>,
+}
+
+/// Xorg dummy driver configuration. The initial mode timings only need to be
+/// in the monitor ranges, at 60 Hz.
+fn xorg_dummy_config(config: &VirtualDisplayConfig) -> String {
+    let depth = config.depth.unwrap_or(DEFAULT_DEPTH);
+    let (max_width, max_height) = (
+        config
+            .max_width
+            .unwrap_or(DEFAULT_MAX_SIZE.0)
+            .max(config.width),
+        config
+            .max_height
+            .unwrap_or(DEFAULT_MAX_SIZE.1)
+            .max(config.height),
+    );
+    let video_ram_kb = max_width as u64 * max_height as u64 * 4 / 1024 + 1024;
+    let (width, height) = (config.width as u64, config.height as u64);
+    let (htotal, vtotal) = (width + 160, height + 30);
+    let clock_mhz = (htotal * vtotal * 60) as f64 / 1_000_000.0;
+    format!(
+        r#"Section "Device"
+    Identifier "sanzu"
+    Driver "dummy"
+    VideoRam {video_ram_kb}
+EndSection
+
+Section "Monitor"
+    Identifier "sanzu"
+    HorizSync 5.0 - 1000.0
+    VertRefresh 5.0 - 200.0
+    Modeline "{width}x{height}" {clock_mhz:.2} {width} {} {} {htotal} {height} {} {} {vtotal}
+EndSection
+
+Section "Screen"
+    Identifier "sanzu"
+    Device "sanzu"
+    Monitor "sanzu"
+    DefaultDepth {depth}
+    SubSection "Display"
+        Depth {depth}
+        Modes "{width}x{height}"
+        Virtual {max_width} {max_height}
+    EndSubSection
+EndSection
+"#,
+        width + 48,
+        width + 80,
+        height + 3,
+        height + 8,
+    )
+}
+
+/// Read the display number written by the X server on `fd`, until the X
+/// server exits or `START_TIMEOUT` expires
+fn read_display_number(fd: RawFd, child: &mut Child) -> Result<String> {
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    let start = Instant::now();
+    let mut output = vec![];
+    while !output.contains(&b'\n') {
+        let timeout = START_TIMEOUT
+            .checked_sub(start.elapsed())
+            .context("Timeout waiting for the X server")?;
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ret = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as i32) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err).context("Error in poll");
+        }
+        if ret == 0 {
+            continue;
+        }
+        let mut buffer = [0u8; 16];
+        let size = file.read(&mut buffer).context("Error in displayfd read")?;
+        if size == 0 {
+            let status = child.wait().context("Error in X server wait")?;
+            return Err(anyhow!("X server exited: {}", status));
+        }
+        output.extend_from_slice(&buffer[..size]);
+    }
+    let number = String::from_utf8_lossy(&output).trim().to_owned();
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+        return Err(anyhow!("Bad display number {:?}", number));
+    }
+    Ok(format!(":{number}"))
+}
+
+impl VirtualDisplay {
+    /// Spawn the X server and wait until it accepts connections. Its display
+    /// name is given by `display`.
+    pub fn spawn(config: &VirtualDisplayConfig) -> Result<Self> {
+        let server = config.server.unwrap_or_default();
+        let depth = config.depth.unwrap_or(DEFAULT_DEPTH);
+        let mut command = match (&config.command, server) {
+            (Some(path), _) => Command::new(path),
+            (None, VirtualDisplayServer::Xvfb) => Command::new("Xvfb"),
+            (None, VirtualDisplayServer::Xorg) => Command::new("Xorg"),
+        };
+        if let Some(display) = &config.display {
+            command.arg(display);
+        }
+
+        let mut config_path = None;
+        match server {
+            VirtualDisplayServer::Xvfb => {
+                command
+                    .arg("-screen")
+                    .arg("0")
+                    .arg(format!("{}x{}x{}", config.width, config.height, depth));
+            }
+            VirtualDisplayServer::Xorg => {
+                let path =
+                    std::env::temp_dir().join(format!("sanzu-xorg-{}.conf", std::process::id()));
+                fs::write(&path, xorg_dummy_config(config))
+                    .context(format!("Cannot write Xorg configuration {path:?}"))?;
+                command
+                    .arg("-config")
+                    .arg(&path)
+                    .arg("-logfile")
+                    .arg(path.with_extension("log"))
+                    .arg("-noreset");
+                config_path = Some(path);
+            }
+        }
+
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error()).context("Error in pipe");
+        }
+        let (read_fd, write_fd) = (fds[0], fds[1]);
+        command
+            .args(["-nolisten", "tcp", "-displayfd"])
+            .arg(write_fd.to_string())
+            .stdin(Stdio::null());
+        unsafe {
+            command.pre_exec(move || {
+                libc::close(read_fd);
+                // Don't outlive the sanzu server, even if it is killed
+                #[cfg(target_os = "linux")]
+                libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+                Ok(())
+            });
+        }
+        info!("Start virtual display {:?}", command);
+        let child = command.spawn();
+        unsafe { libc::close(write_fd) };
+        let child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                unsafe { libc::close(read_fd) };
+                if let Some(path) = config_path {
+                    let _ = fs::remove_file(path);
+                }
+                return Err(err).context("Cannot start X server");
+            }
+        };
+        // From now on, the X server is stopped on error
+        let mut virtual_display = VirtualDisplay {
+            child,
+            display: String::new(),
+            config_path,
+        };
+        virtual_display.display = read_display_number(read_fd, &mut virtual_display.child)
+            .context("Cannot get the virtual display")?;
+        info!("Virtual display {} started", virtual_display.display);
+        Ok(virtual_display)
+    }
+
+    /// Display name of the X server (ex: ":1")
+    pub fn display(&self) -> &str {
+        &self.display
+    }
+}
+
+impl Drop for VirtualDisplay {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            info!("Stop virtual display {}", self.display);
+            // Let the X server clean its lock and socket files
+            unsafe { libc::kill(self.child.id() as libc::pid_t, libc::SIGTERM) };
+        }
+        let start = Instant::now();
+        while start.elapsed() < STOP_TIMEOUT {
+            match self.child.try_wait() {
+                Ok(Some(_)) | Err(_) => break,
+                Ok(None) => sleep(Duration::from_millis(50)),
+            }
+        }
+        if let Ok(None) = self.child.try_wait() {
+            warn!("X server did not exit, kill it");
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+        if let Some(path) = self.config_path.take() {
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 /// Sourced from from https://github.com/descampsa/yuv2rgb
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use std::arch::x86_64::{
@@ -7,6 +8,16 @@ use std::arch::x86_64::{
     _mm_srai_epi16, _mm_srli_epi16, _mm_storeu_si128, _mm_sub_epi16, _mm_sub_epi8,
     _mm_unpackhi_epi16, _mm_unpackhi_epi8, _mm_unpacklo_epi16, _mm_unpacklo_epi8,
 };
+use std::fmt;
+
+#[cfg(target_arch = "aarch64")]
+use std::arch::aarch64::{
+    int16x8_t, uint16x8_t, uint8x16_t, uint8x8_t, vaddq_s16, vaddq_u16, vcombine_u8, vdupq_n_s16,
+    vdupq_n_u16, vget_high_u8, vget_low_u8, vld1_u8, vld1q_u8, vld4q_u8, vmlaq_s16, vmlaq_u16,
+    vmovl_u8, vmovn_u16, vmulq_s16, vmulq_u16, vpaddq_u16, vqmovun_s16, vreinterpretq_s16_u16,
+    vshrq_n_s16, vshrq_n_u16, vst1_u8, vst1q_u8, vst4q_u8, vsubq_s16, vsubq_u16, vzip1q_s16,
+    vzip2q_s16,
+};
 
 fn f32_to_fixed_point(value: f32, precision: u32) -> u8 {
     ((value * ((1 << precision) as f32)) + 0.5) as u8
@@ -32,13 +43,22 @@ pub struct YuvToRgbParam {
     y_offset: u8,    // YMin
 }
 
+/// Chroma factor, bounded so that the chroma difference of a pure red or blue
+/// pixel multiplied by it still fits in an i16
+fn chroma_factor(value: f32, luma_factor: u8) -> u8 {
+    let max_diff = 255 - ((luma_factor as i32 * 255) >> 8);
+    f32_to_fixed_point(value, 8).min((i16::MAX as i32 / max_diff) as u8)
+}
+
 fn gen_rgb_to_yuv_param(rf: f32, bf: f32, ymin: f32, ymax: f32, cbcrrange: f32) -> RgbToYuvParam {
+    let r_factor = f32_to_fixed_point(rf, 8);
+    let b_factor = f32_to_fixed_point(bf, 8);
     RgbToYuvParam {
-        r_factor: f32_to_fixed_point(rf, 8),
-        g_factor: 255 - f32_to_fixed_point(rf, 8) - f32_to_fixed_point(bf, 8) + 1,
-        b_factor: f32_to_fixed_point(bf, 8),
-        cb_factor: f32_to_fixed_point((cbcrrange / 255.0) / (2.0 * (1.0 - bf)), 8),
-        cr_factor: f32_to_fixed_point((cbcrrange / 255.0) / (2.0 * (1.0 - rf)), 8),
+        r_factor,
+        g_factor: 255 - r_factor - b_factor + 1,
+        b_factor,
+        cb_factor: chroma_factor((cbcrrange / 255.0) / (2.0 * (1.0 - bf)), b_factor),
+        cr_factor: chroma_factor((cbcrrange / 255.0) / (2.0 * (1.0 - rf)), r_factor),
         y_factor: f32_to_fixed_point((ymax - ymin) / 255.0, 7),
         y_offset: ymin as u8,
     }
@@ -61,46 +81,286 @@ fn gen_yuv_to_rgb_param(rf: f32, bf: f32, ymin: f32, ymax: f32, cbcrrange: f32)
     }
 }
 
+/// Color matrix used to convert between rgb and yuv
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum YuvType {
+    /// ITU-T T.871 (JPEG), full range by default
     ItuT871,
+    /// ITU-R BT.601, limited range by default
     ItuR601,
+    /// ITU-R BT.709, limited range by default
     ItuR709,
+    /// ITU-R BT.2020 (non constant luminance), limited range by default
+    ItuR2020,
 }
 
-fn get_rgb_to_yuv_param(param: YuvType) -> RgbToYuvParam {
-    match param {
-        YuvType::ItuT871 => {
-            // ITU-T T.871 (JPEG)
-            gen_rgb_to_yuv_param(0.299, 0.114, 0.0, 255.0, 255.0)
+/// Range of the yuv samples
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColorRange {
+    /// Luma in [16, 235], chroma in [16, 240]
+    Limited,
+    /// Luma and chroma in [0, 255]
+    Full,
+}
+
+impl YuvType {
+    /// Range defined by the matrix standard
+    pub fn default_range(self) -> ColorRange {
+        match self {
+            YuvType::ItuT871 => ColorRange::Full,
+            YuvType::ItuR601 | YuvType::ItuR709 | YuvType::ItuR2020 => ColorRange::Limited,
         }
-        YuvType::ItuR601 => {
+    }
+
+    /// Red and blue luma coefficients (Kr, Kb)
+    fn coefficients(self) -> (f32, f32) {
+        match self {
+            // ITU-T T.871 (JPEG)
+            YuvType::ItuT871 => (0.299, 0.114),
             // ITU-R BT.601-7
-            gen_rgb_to_yuv_param(0.299, 0.114, 16.0, 235.0, 224.0)
-        }
-        YuvType::ItuR709 => {
+            YuvType::ItuR601 => (0.299, 0.114),
             // ITU-R BT.709-6
-            gen_rgb_to_yuv_param(0.2126, 0.0722, 16.0, 235.0, 224.0)
+            YuvType::ItuR709 => (0.2126, 0.0722),
+            // ITU-R BT.2020-2
+            YuvType::ItuR2020 => (0.2627, 0.0593),
         }
     }
 }
 
-fn get_yuv_to_rgb_param(param: YuvType) -> YuvToRgbParam {
-    match param {
-        YuvType::ItuT871 => {
-            // ITU-T T.871 (JPEG
-            gen_yuv_to_rgb_param(0.299, 0.114, 0.0, 255.0, 255.0)
+impl ColorRange {
+    /// Luma min, luma max and chroma range
+    fn bounds(self) -> (f32, f32, f32) {
+        match self {
+            ColorRange::Limited => (16.0, 235.0, 224.0),
+            ColorRange::Full => (0.0, 255.0, 255.0),
         }
-        YuvType::ItuR601 => {
-            // ITU-R BT.601-7
-            gen_yuv_to_rgb_param(0.299, 0.114, 16.0, 235.0, 224.0)
+    }
+
+    /// Luma min, luma max and chroma range of 10 bits samples
+    fn bounds_10bit(self) -> (f32, f32, f32) {
+        match self {
+            ColorRange::Limited => (64.0, 940.0, 896.0),
+            ColorRange::Full => (0.0, 1023.0, 1023.0),
         }
-        YuvType::ItuR709 => {
-            // ITU-R BT.709-6
-            gen_yuv_to_rgb_param(0.2126, 0.0722, 16.0, 235.0, 224.0)
+    }
+}
+
+/// Order of the interleaved chroma samples of semi planar images
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaOrder {
+    /// U then V (nv12)
+    Uv,
+    /// V then U (nv21)
+    Vu,
+}
+
+impl ChromaOrder {
+    /// Offsets of the U and V samples in a chroma pair
+    fn offsets(self) -> (usize, usize) {
+        match self {
+            ChromaOrder::Uv => (0, 1),
+            ChromaOrder::Vu => (1, 0),
+        }
+    }
+}
+
+/// Order of the samples of 32 bits pixels
+///
+/// Green is always the second sample and the fourth one is left untouched by
+/// the conversions to rgb.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    /// Red first
+    Rgba,
+    /// Blue first: x11, Wayland and Windows frames
+    Bgra,
+}
+
+impl ByteOrder {
+    /// Offsets of the red and blue samples in a pixel
+    fn offsets(self) -> (usize, usize) {
+        match self {
+            ByteOrder::Rgba => (0, 2),
+            ByteOrder::Bgra => (2, 0),
+        }
+    }
+
+    /// Map the (first, third) samples of a pixel to (red, blue), and back
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64"))]
+    fn red_blue<T>(self, first: T, third: T) -> (T, T) {
+        match self {
+            ByteOrder::Rgba => (first, third),
+            ByteOrder::Bgra => (third, first),
+        }
+    }
+}
+
+fn get_rgb_to_yuv_param(param: YuvType, range: ColorRange) -> RgbToYuvParam {
+    let (rf, bf) = param.coefficients();
+    let (ymin, ymax, cbcrrange) = range.bounds();
+    gen_rgb_to_yuv_param(rf, bf, ymin, ymax, cbcrrange)
+}
+
+fn get_yuv_to_rgb_param(param: YuvType, range: ColorRange) -> YuvToRgbParam {
+    let (rf, bf) = param.coefficients();
+    let (ymin, ymax, cbcrrange) = range.bounds();
+    gen_yuv_to_rgb_param(rf, bf, ymin, ymax, cbcrrange)
+}
+
+/// Buffer not matching the geometry of the image to convert
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConvertError {
+    /// Rows of the plane overlap
+    StrideTooSmall {
+        plane: &'static str,
+        stride: usize,
+        row_len: usize,
+    },
+    /// The buffer doesn't hold all the rows of the plane
+    BufferTooSmall {
+        plane: &'static str,
+        len: usize,
+        needed: usize,
+    },
+    /// The image doesn't have the planes of its pixel format
+    PlaneCount {
+        format: &'static str,
+        planes: usize,
+        expected: usize,
+    },
+    /// Source and destination images have different sizes
+    SizeMismatch {
+        src: (usize, usize),
+        dst: (usize, usize),
+    },
+    /// No kernel converts between those pixel formats
+    Unsupported {
+        src: &'static str,
+        dst: &'static str,
+    },
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConvertError::StrideTooSmall {
+                plane,
+                stride,
+                row_len,
+            } => write!(
+                f,
+                "{plane} stride {stride} is smaller than a row of {row_len} samples"
+            ),
+            ConvertError::BufferTooSmall { plane, len, needed } => {
+                write!(f, "{plane} buffer holds {len} samples, {needed} are needed")
+            }
+            ConvertError::PlaneCount {
+                format,
+                planes,
+                expected,
+            } => write!(
+                f,
+                "{format} image has {planes} planes instead of {expected}"
+            ),
+            ConvertError::SizeMismatch { src, dst } => write!(
+                f,
+                "Source is {}x{}, destination is {}x{}",
+                src.0, src.1, dst.0, dst.1
+            ),
+            ConvertError::Unsupported { src, dst } => {
+                write!(f, "Conversion from {src} to {dst} is not supported")
+            }
         }
     }
 }
 
+impl std::error::Error for ConvertError {}
+
+/// Check that `buffer` holds `rows` rows of `row_len` samples, each one
+/// starting `stride` samples after the previous one
+pub fn check_plane<T>(
+    plane: &'static str,
+    buffer: &[T],
+    rows: usize,
+    row_len: usize,
+    stride: usize,
+) -> Result<(), ConvertError> {
+    if rows > 1 && stride < row_len {
+        return Err(ConvertError::StrideTooSmall {
+            plane,
+            stride,
+            row_len,
+        });
+    }
+    // The last row doesn't need its padding
+    let needed = match rows {
+        0 => 0,
+        rows => (rows - 1).saturating_mul(stride).saturating_add(row_len),
+    };
+    if buffer.len() < needed {
+        return Err(ConvertError::BufferTooSmall {
+            plane,
+            len: buffer.len(),
+            needed,
+        });
+    }
+    Ok(())
+}
+
+/// Check the planes of a yuv420 image
+#[allow(clippy::too_many_arguments)]
+pub fn check_yuv420(
+    width: usize,
+    height: usize,
+    buffer_y: &[u8],
+    buffer_u: &[u8],
+    buffer_v: &[u8],
+    y_stride: usize,
+    u_stride: usize,
+    v_stride: usize,
+) -> Result<(), ConvertError> {
+    let (uv_width, uv_height) = (width.div_ceil(2), height.div_ceil(2));
+    check_plane("y", buffer_y, height, width, y_stride)?;
+    check_plane("u", buffer_u, uv_height, uv_width, u_stride)?;
+    check_plane("v", buffer_v, uv_height, uv_width, v_stride)
+}
+
+/// Check the planes of a yuv444 image
+#[allow(clippy::too_many_arguments)]
+pub fn check_yuv444(
+    width: usize,
+    height: usize,
+    buffer_y: &[u8],
+    buffer_u: &[u8],
+    buffer_v: &[u8],
+    y_stride: usize,
+    u_stride: usize,
+    v_stride: usize,
+) -> Result<(), ConvertError> {
+    check_plane("y", buffer_y, height, width, y_stride)?;
+    check_plane("u", buffer_u, height, width, u_stride)?;
+    check_plane("v", buffer_v, height, width, v_stride)
+}
+
+/// Check the planes of a nv12 / nv21 / p010 image
+pub fn check_semi_planar<T>(
+    width: usize,
+    height: usize,
+    buffer_y: &[T],
+    buffer_uv: &[T],
+    y_stride: usize,
+    uv_stride: usize,
+) -> Result<(), ConvertError> {
+    check_plane("y", buffer_y, height, width, y_stride)?;
+    check_plane(
+        "uv",
+        buffer_uv,
+        height.div_ceil(2),
+        width.div_ceil(2) * 2,
+        uv_stride,
+    )
+}
+
 fn clamp(value: i16) -> u8 {
     match value {
         value if value < 0 => 0,
@@ -122,60 +382,73 @@ pub fn rgb24_yuv420_std(
     u_stride: usize,
     v_stride: usize,
     yuv_type: YuvType,
+    color_range: ColorRange,
 ) {
-    let param = get_rgb_to_yuv_param(yuv_type);
+    debug_assert_eq!(
+        check_plane("rgb", buffer_rgb, height, width * 3, rgb_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_yuv420(width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride),
+        Ok(())
+    );
+    let param = get_rgb_to_yuv_param(yuv_type, color_range);
 
-    for y in (0..height - 1).step_by(2) {
+    for y in (0..height).step_by(2) {
+        // The last row of an odd height image is sampled alone
+        let next_row = if y + 1 < height { 1 } else { 0 };
         let mut y_index1 = y * y_stride;
-        let mut y_index2 = (y + 1) * y_stride;
+        let mut y_index2 = (y + next_row) * y_stride;
 
         let mut rgb_index1 = y * rgb_stride;
-        let mut rgb_index2 = (y + 1) * rgb_stride;
+        let mut rgb_index2 = (y + next_row) * rgb_stride;
 
         let mut u_index = (y / 2) * u_stride;
         let mut v_index = (y / 2) * v_stride;
 
-        for _ in (0..width - 1).step_by(2) {
+        for x in (0..width).step_by(2) {
+            // The last column of an odd width image is sampled alone
+            let next_col = if x + 1 < width { 1 } else { 0 };
             // compute yuv for the four pixels, u and v values are summed
 
             let mut y_tmp = (param.r_factor as u16 * buffer_rgb[rgb_index1] as u16
                 + param.g_factor as u16 * buffer_rgb[rgb_index1 + 1] as u16
                 + param.b_factor as u16 * buffer_rgb[rgb_index1 + 2] as u16)
                 >> 8;
-            let mut u_tmp: u16 = buffer_rgb[rgb_index1 + 2] as u16 - y_tmp;
-            let mut v_tmp: u16 = buffer_rgb[rgb_index1] as u16 - y_tmp;
+            let mut u_tmp = buffer_rgb[rgb_index1 + 2] as i16 - y_tmp as i16;
+            let mut v_tmp = buffer_rgb[rgb_index1] as i16 - y_tmp as i16;
             buffer_y[y_index1] =
                 (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
 
-            y_tmp = (param.r_factor as u16 * buffer_rgb[rgb_index1 + 3] as u16
-                + param.g_factor as u16 * buffer_rgb[rgb_index1 + 4] as u16
-                + param.b_factor as u16 * buffer_rgb[rgb_index1 + 5] as u16)
+            y_tmp = (param.r_factor as u16 * buffer_rgb[rgb_index1 + next_col * 3] as u16
+                + param.g_factor as u16 * buffer_rgb[rgb_index1 + next_col * 3 + 1] as u16
+                + param.b_factor as u16 * buffer_rgb[rgb_index1 + next_col * 3 + 2] as u16)
                 >> 8;
-            u_tmp += buffer_rgb[rgb_index1 + 5] as u16 - y_tmp;
-            v_tmp += buffer_rgb[rgb_index1 + 3] as u16 - y_tmp;
-            buffer_y[y_index1 + 1] =
+            u_tmp += buffer_rgb[rgb_index1 + next_col * 3 + 2] as i16 - y_tmp as i16;
+            v_tmp += buffer_rgb[rgb_index1 + next_col * 3] as i16 - y_tmp as i16;
+            buffer_y[y_index1 + next_col] =
                 (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
 
             y_tmp = (param.r_factor as u16 * buffer_rgb[rgb_index2] as u16
                 + param.g_factor as u16 * buffer_rgb[rgb_index2 + 1] as u16
                 + param.b_factor as u16 * buffer_rgb[rgb_index2 + 2] as u16)
                 >> 8;
-            u_tmp += buffer_rgb[rgb_index2 + 2] as u16 - y_tmp;
-            v_tmp += buffer_rgb[rgb_index2] as u16 - y_tmp;
+            u_tmp += buffer_rgb[rgb_index2 + 2] as i16 - y_tmp as i16;
+            v_tmp += buffer_rgb[rgb_index2] as i16 - y_tmp as i16;
             buffer_y[y_index2] =
                 (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
 
-            y_tmp = (param.r_factor as u16 * buffer_rgb[rgb_index2 + 3] as u16
-                + param.g_factor as u16 * buffer_rgb[rgb_index2 + 4] as u16
-                + param.b_factor as u16 * buffer_rgb[rgb_index2 + 5] as u16)
+            y_tmp = (param.r_factor as u16 * buffer_rgb[rgb_index2 + next_col * 3] as u16
+                + param.g_factor as u16 * buffer_rgb[rgb_index2 + next_col * 3 + 1] as u16
+                + param.b_factor as u16 * buffer_rgb[rgb_index2 + next_col * 3 + 2] as u16)
                 >> 8;
-            u_tmp += buffer_rgb[rgb_index2 + 5] as u16 - y_tmp;
-            v_tmp += buffer_rgb[rgb_index2 + 3] as u16 - y_tmp;
-            buffer_y[y_index2 + 1] =
+            u_tmp += buffer_rgb[rgb_index2 + next_col * 3 + 2] as i16 - y_tmp as i16;
+            v_tmp += buffer_rgb[rgb_index2 + next_col * 3] as i16 - y_tmp as i16;
+            buffer_y[y_index2 + next_col] =
                 (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
 
-            buffer_u[u_index] = ((((u_tmp >> 2) * param.cb_factor as u16) >> 8) + 128) as u8;
-            buffer_v[v_index] = ((((v_tmp >> 2) * param.cb_factor as u16) >> 8) + 128) as u8;
+            buffer_u[u_index] = clamp((((u_tmp >> 2) * param.cb_factor as i16) >> 8) + 128);
+            buffer_v[v_index] = clamp((((v_tmp >> 2) * param.cr_factor as i16) >> 8) + 128);
 
             rgb_index1 += 6;
             rgb_index2 += 6;
@@ -200,58 +473,77 @@ pub fn rgba_to_yuv420_std(
     u_stride: usize,
     v_stride: usize,
     yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
 ) {
-    let param = get_rgb_to_yuv_param(yuv_type);
-    for y in (0..height - 1).step_by(2) {
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_yuv420(width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride),
+        Ok(())
+    );
+    let param = get_rgb_to_yuv_param(yuv_type, color_range);
+    let (r_offset, b_offset) = byte_order.offsets();
+    for y in (0..height).step_by(2) {
+        // The last row of an odd height image is sampled alone
+        let next_row = if y + 1 < height { 1 } else { 0 };
         let mut y_index1 = y * y_stride;
-        let mut y_index2 = (y + 1) * y_stride;
+        let mut y_index2 = (y + next_row) * y_stride;
 
         let mut rgba_index1 = y * rgba_stride;
-        let mut rgba_index2 = (y + 1) * rgba_stride;
+        let mut rgba_index2 = (y + next_row) * rgba_stride;
 
         let mut u_index = (y / 2) * u_stride;
         let mut v_index = (y / 2) * v_stride;
 
-        for _ in (0..width - 1).step_by(2) {
+        for x in (0..width).step_by(2) {
+            // The last column of an odd width image is sampled alone
+            let next_col = if x + 1 < width { 1 } else { 0 };
             // compute yuv for the four pixels, u and v values are summed
-            let mut y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index1] as u16
+            let mut y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index1 + r_offset] as u16
                 + param.g_factor as u16 * buffer_rgba[rgba_index1 + 1] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index1 + 2] as u16)
+                + param.b_factor as u16 * buffer_rgba[rgba_index1 + b_offset] as u16)
                 >> 8;
-            let mut u_tmp = buffer_rgba[rgba_index1 + 2] as u16 - y_tmp;
-            let mut v_tmp = buffer_rgba[rgba_index1] as u16 - y_tmp;
+            let mut u_tmp = buffer_rgba[rgba_index1 + b_offset] as i16 - y_tmp as i16;
+            let mut v_tmp = buffer_rgba[rgba_index1 + r_offset] as i16 - y_tmp as i16;
             buffer_y[y_index1] =
                 (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
 
-            y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index1 + 4] as u16
-                + param.g_factor as u16 * buffer_rgba[rgba_index1 + 5] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index1 + 6] as u16)
+            y_tmp = (param.r_factor as u16
+                * buffer_rgba[rgba_index1 + next_col * 4 + r_offset] as u16
+                + param.g_factor as u16 * buffer_rgba[rgba_index1 + next_col * 4 + 1] as u16
+                + param.b_factor as u16
+                    * buffer_rgba[rgba_index1 + next_col * 4 + b_offset] as u16)
                 >> 8;
-            u_tmp += buffer_rgba[rgba_index1 + 6] as u16 - y_tmp;
-            v_tmp += buffer_rgba[rgba_index1 + 4] as u16 - y_tmp;
-            buffer_y[y_index1 + 1] =
+            u_tmp += buffer_rgba[rgba_index1 + next_col * 4 + b_offset] as i16 - y_tmp as i16;
+            v_tmp += buffer_rgba[rgba_index1 + next_col * 4 + r_offset] as i16 - y_tmp as i16;
+            buffer_y[y_index1 + next_col] =
                 (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
 
-            y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index2] as u16
+            y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index2 + r_offset] as u16
                 + param.g_factor as u16 * buffer_rgba[rgba_index2 + 1] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index2 + 2] as u16)
+                + param.b_factor as u16 * buffer_rgba[rgba_index2 + b_offset] as u16)
                 >> 8;
-            u_tmp += buffer_rgba[rgba_index2 + 2] as u16 - y_tmp;
-            v_tmp += buffer_rgba[rgba_index2] as u16 - y_tmp;
+            u_tmp += buffer_rgba[rgba_index2 + b_offset] as i16 - y_tmp as i16;
+            v_tmp += buffer_rgba[rgba_index2 + r_offset] as i16 - y_tmp as i16;
             buffer_y[y_index2] =
                 (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
 
-            y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index2 + 4] as u16
-                + param.g_factor as u16 * buffer_rgba[rgba_index2 + 5] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index2 + 6] as u16)
+            y_tmp = (param.r_factor as u16
+                * buffer_rgba[rgba_index2 + next_col * 4 + r_offset] as u16
+                + param.g_factor as u16 * buffer_rgba[rgba_index2 + next_col * 4 + 1] as u16
+                + param.b_factor as u16
+                    * buffer_rgba[rgba_index2 + next_col * 4 + b_offset] as u16)
                 >> 8;
-            u_tmp += buffer_rgba[rgba_index2 + 6] as u16 - y_tmp;
-            v_tmp += buffer_rgba[rgba_index2 + 4] as u16 - y_tmp;
-            buffer_y[y_index2 + 1] =
+            u_tmp += buffer_rgba[rgba_index2 + next_col * 4 + b_offset] as i16 - y_tmp as i16;
+            v_tmp += buffer_rgba[rgba_index2 + next_col * 4 + r_offset] as i16 - y_tmp as i16;
+            buffer_y[y_index2 + next_col] =
                 (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
 
-            buffer_u[u_index] = ((((u_tmp >> 2) * param.cb_factor as u16) >> 8) + 128) as u8;
-            buffer_v[v_index] = ((((v_tmp >> 2) * param.cb_factor as u16) >> 8) + 128) as u8;
+            buffer_u[u_index] = clamp((((u_tmp >> 2) * param.cb_factor as i16) >> 8) + 128);
+            buffer_v[v_index] = clamp((((v_tmp >> 2) * param.cr_factor as i16) >> 8) + 128);
 
             rgba_index1 += 8;
             rgba_index2 += 8;
@@ -276,8 +568,19 @@ pub fn rgba_to_yuv444_std(
     u_stride: usize,
     v_stride: usize,
     yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
 ) {
-    let param = get_rgb_to_yuv_param(yuv_type);
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_yuv444(width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride),
+        Ok(())
+    );
+    let param = get_rgb_to_yuv_param(yuv_type, color_range);
+    let (r_offset, b_offset) = byte_order.offsets();
     for y in 0..height {
         let mut y_index1 = y * y_stride;
 
@@ -287,17 +590,17 @@ pub fn rgba_to_yuv444_std(
         let mut v_index = y * v_stride;
         for _ in 0..width {
             // compute yuv for the four pixels, u and v values are summed
-            let y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index] as u16
+            let y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index + r_offset] as u16
                 + param.g_factor as u16 * buffer_rgba[rgba_index + 1] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index + 2] as u16)
+                + param.b_factor as u16 * buffer_rgba[rgba_index + b_offset] as u16)
                 >> 8;
-            let u_tmp = buffer_rgba[rgba_index + 2] as u16 - y_tmp;
-            let v_tmp = buffer_rgba[rgba_index] as u16 - y_tmp;
+            let u_tmp = buffer_rgba[rgba_index + b_offset] as i16 - y_tmp as i16;
+            let v_tmp = buffer_rgba[rgba_index + r_offset] as i16 - y_tmp as i16;
             buffer_y[y_index1] =
                 (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
 
-            buffer_u[u_index] = (((u_tmp * param.cb_factor as u16) >> 8) + 128) as u8;
-            buffer_v[v_index] = (((v_tmp * param.cb_factor as u16) >> 8) + 128) as u8;
+            buffer_u[u_index] = clamp(((u_tmp * param.cb_factor as i16) >> 8) + 128);
+            buffer_v[v_index] = clamp(((v_tmp * param.cr_factor as i16) >> 8) + 128);
 
             rgba_index += 4;
             y_index1 += 1;
@@ -320,8 +623,19 @@ pub fn yuv420_to_rgba_std(
     buffer_rgba: &mut [u8],
     rgba_stride: usize,
     yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
 ) {
-    let param = get_yuv_to_rgb_param(yuv_type);
+    debug_assert_eq!(
+        check_yuv420(width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    let param = get_yuv_to_rgb_param(yuv_type, color_range);
+    let (r_offset, b_offset) = byte_order.offsets();
     for y in (0..height - 1).step_by(2) {
         let mut rgba_index1 = y * rgba_stride;
         let mut rgba_index2 = (y + 1) * rgba_stride;
@@ -343,29 +657,29 @@ pub fn yuv420_to_rgba_std(
 
             let y_tmp =
                 (param.y_factor as i16 * (buffer_y[y_index1] as i16 - param.y_offset as i16)) >> 7;
-            buffer_rgba[rgba_index1] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index1 + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index1 + 1] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index1 + 2] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index1 + b_offset] = clamp(y_tmp + b_cb_offset);
 
             let y_tmp = (param.y_factor as i16
                 * (buffer_y[y_index1 + 1] as i16 - param.y_offset as i16))
                 >> 7;
-            buffer_rgba[rgba_index1 + 4] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index1 + 4 + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index1 + 5] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index1 + 6] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index1 + 4 + b_offset] = clamp(y_tmp + b_cb_offset);
 
             let y_tmp =
                 (param.y_factor as i16 * (buffer_y[y_index2] as i16 - param.y_offset as i16)) >> 7;
-            buffer_rgba[rgba_index2] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index2 + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index2 + 1] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index2 + 2] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index2 + b_offset] = clamp(y_tmp + b_cb_offset);
 
             let y_tmp = (param.y_factor as i16
                 * (buffer_y[y_index2 + 1] as i16 - param.y_offset as i16))
                 >> 7;
-            buffer_rgba[rgba_index2 + 4] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index2 + 4 + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index2 + 5] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index2 + 6] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index2 + 4 + b_offset] = clamp(y_tmp + b_cb_offset);
 
             rgba_index1 += 8;
             rgba_index2 += 8;
@@ -390,8 +704,19 @@ pub fn yuv444_to_rgba_std(
     buffer_rgba: &mut [u8],
     rgba_stride: usize,
     yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
 ) {
-    let param = get_yuv_to_rgb_param(yuv_type);
+    debug_assert_eq!(
+        check_yuv444(width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    let param = get_yuv_to_rgb_param(yuv_type, color_range);
+    let (r_offset, b_offset) = byte_order.offsets();
     for y in 0..height {
         let mut rgba_index = y * rgba_stride;
         let mut y_index = y * y_stride;
@@ -410,9 +735,9 @@ pub fn yuv444_to_rgba_std(
 
             let y_tmp =
                 (param.y_factor as i16 * (buffer_y[y_index] as i16 - param.y_offset as i16)) >> 7;
-            buffer_rgba[rgba_index] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index + 1] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index + 2] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index + b_offset] = clamp(y_tmp + b_cb_offset);
 
             rgba_index += 4;
             y_index += 1;
@@ -618,6 +943,7 @@ unsafe fn rgba_to_yuv420_step(
     y_index2: usize,
     u_index: usize,
     v_index: usize,
+    byte_order: ByteOrder,
 ) {
     let (rgba1, rgba2, rgba3, rgba4, rgba5, rgba6, rgba7, rgba8) =
         load_rgba_4_x_2!(buffer_rgba, rgba_index1, rgba_index2);
@@ -628,6 +954,8 @@ unsafe fn rgba_to_yuv420_step(
 
     let (col_r_1, col_g_1, col_b_1, _alpha1, col_r_2, col_g_2, col_b_2, _alpha2) =
         rgb32_to_r_g_b_a!(rgba1, rgba2, rgba3, rgba4, rgba5, rgba6, rgba7, rgba8);
+    let (col_r_1, col_b_1) = byte_order.red_blue(col_r_1, col_b_1);
+    let (col_r_2, col_b_2) = byte_order.red_blue(col_r_2, col_b_2);
 
     let (y1_16, cb1_16, cr1_16) = r_g_b_lo_to_y16_u16_v16!(param, col_r_1, col_g_1, col_b_1);
     let (y2_16, cb2_16, cr2_16) = r_g_b_lo_to_y16_u16_v16!(param, col_r_2, col_g_2, col_b_2);
@@ -689,6 +1017,8 @@ unsafe fn rgba_to_yuv420_step(
 
     let (col_r_1, col_g_1, col_b_1, _alpha1, col_r_2, col_g_2, col_b_2, _alpha2) =
         rgb32_to_r_g_b_a!(rgba1, rgba2, rgba3, rgba4, rgba5, rgba6, rgba7, rgba8);
+    let (col_r_1, col_b_1) = byte_order.red_blue(col_r_1, col_b_1);
+    let (col_r_2, col_b_2) = byte_order.red_blue(col_r_2, col_b_2);
 
     /* first compute Y', (B-Y') and (R-Y'), in 16bits values, for the first line
       Y is saved for each pixel, while only sums of (B-Y') and (R-Y') for pairs of adjacents
@@ -765,10 +1095,20 @@ pub fn rgba_to_yuv420_ssse3(
     u_stride: usize,
     v_stride: usize,
     yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
 ) {
-    let param = get_rgb_to_yuv_param(yuv_type);
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_yuv420(width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride),
+        Ok(())
+    );
+    let param = get_rgb_to_yuv_param(yuv_type, color_range);
 
-    for y in (0..height - 1).step_by(2) {
+    for y in (0..height - height % 2).step_by(2) {
         let mut rgba_index1 = y * rgba_stride;
         let mut rgba_index2 = (y + 1) * rgba_stride;
 
@@ -778,7 +1118,7 @@ pub fn rgba_to_yuv420_ssse3(
         let mut u_index = (y / 2) * u_stride;
         let mut v_index = (y / 2) * v_stride;
 
-        for _ in (0..width - 31).step_by(32) {
+        for _ in 0..width / 32 {
             unsafe {
                 rgba_to_yuv420_step(
                     &param,
@@ -792,6 +1132,7 @@ pub fn rgba_to_yuv420_ssse3(
                     y_index2,
                     u_index,
                     v_index,
+                    byte_order,
                 );
             }
             rgba_index1 += 128;
@@ -801,57 +1142,144 @@ pub fn rgba_to_yuv420_ssse3(
             u_index += 16;
             v_index += 16;
         }
+    }
 
-        // Complete image width
-        let cur_width = (width / 32) * 32;
-        for _ in (cur_width..width - 1).step_by(2) {
-            // compute yuv for the four pixels, u and v values are summed
-            let mut y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index1] as u16
-                + param.g_factor as u16 * buffer_rgba[rgba_index1 + 1] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index1 + 2] as u16)
-                >> 8;
-            let mut u_tmp = buffer_rgba[rgba_index1 + 2] as u16 - y_tmp;
-            let mut v_tmp = buffer_rgba[rgba_index1] as u16 - y_tmp;
-            buffer_y[y_index1] =
-                (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
-
-            y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index1 + 4] as u16
-                + param.g_factor as u16 * buffer_rgba[rgba_index1 + 5] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index1 + 6] as u16)
-                >> 8;
-            u_tmp += buffer_rgba[rgba_index1 + 6] as u16 - y_tmp;
-            v_tmp += buffer_rgba[rgba_index1 + 4] as u16 - y_tmp;
-            buffer_y[y_index1 + 1] =
-                (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
-
-            y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index2] as u16
-                + param.g_factor as u16 * buffer_rgba[rgba_index2 + 1] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index2 + 2] as u16)
-                >> 8;
-            u_tmp += buffer_rgba[rgba_index2 + 2] as u16 - y_tmp;
-            v_tmp += buffer_rgba[rgba_index2] as u16 - y_tmp;
-            buffer_y[y_index2] =
-                (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
-
-            y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index2 + 4] as u16
-                + param.g_factor as u16 * buffer_rgba[rgba_index2 + 5] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index2 + 6] as u16)
-                >> 8;
-            u_tmp += buffer_rgba[rgba_index2 + 6] as u16 - y_tmp;
-            v_tmp += buffer_rgba[rgba_index2 + 4] as u16 - y_tmp;
-            buffer_y[y_index2 + 1] =
-                (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
+    // Complete image width and the last row of odd height images
+    let cur_width = (width / 32) * 32;
+    if cur_width < width {
+        rgba_to_yuv420_std(
+            width - cur_width,
+            height,
+            &buffer_rgba[cur_width * 4..],
+            rgba_stride,
+            &mut buffer_y[cur_width..],
+            &mut buffer_u[cur_width / 2..],
+            &mut buffer_v[cur_width / 2..],
+            y_stride,
+            u_stride,
+            v_stride,
+            yuv_type,
+            color_range,
+            byte_order,
+        );
+    }
+    if height % 2 == 1 && cur_width > 0 {
+        let y = height - 1;
+        rgba_to_yuv420_std(
+            cur_width,
+            1,
+            &buffer_rgba[y * rgba_stride..],
+            rgba_stride,
+            &mut buffer_y[y * y_stride..],
+            &mut buffer_u[(y / 2) * u_stride..],
+            &mut buffer_v[(y / 2) * v_stride..],
+            y_stride,
+            u_stride,
+            v_stride,
+            yuv_type,
+            color_range,
+            byte_order,
+        );
+    }
+}
 
-            buffer_u[u_index] = ((((u_tmp >> 2) * param.cb_factor as u16) >> 8) + 128) as u8;
-            buffer_v[v_index] = ((((v_tmp >> 2) * param.cb_factor as u16) >> 8) + 128) as u8;
+/// Convert a rgba image to yuv420, using the SSSE3 kernel on row pairs
+/// processed in parallel
+///
+/// Each rayon task owns the Y rows, U row and V row of its row pair, split
+/// with `par_chunks_mut`, so tasks never share mutable memory.
+#[allow(clippy::too_many_arguments)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn rgba_to_yuv420_ssse3_rayon(
+    width: usize,
+    height: usize,
+    buffer_rgba: &[u8],
+    rgba_stride: usize,
+    buffer_y: &mut [u8],
+    buffer_u: &mut [u8],
+    buffer_v: &mut [u8],
+    y_stride: usize,
+    u_stride: usize,
+    v_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) {
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_yuv420(width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride),
+        Ok(())
+    );
+    let param = get_rgb_to_yuv_param(yuv_type, color_range);
+    let cur_width = (width / 32) * 32;
+
+    buffer_rgba
+        .par_chunks(2 * rgba_stride)
+        .zip(buffer_y.par_chunks_mut(2 * y_stride))
+        .zip(buffer_u.par_chunks_mut(u_stride))
+        .zip(buffer_v.par_chunks_mut(v_stride))
+        .take(height / 2)
+        .for_each(|(((rows_rgba, rows_y), row_u), row_v)| {
+            for block in 0..width / 32 {
+                unsafe {
+                    rgba_to_yuv420_step(
+                        &param,
+                        rows_rgba,
+                        rows_y,
+                        row_u,
+                        row_v,
+                        block * 128,
+                        rgba_stride + block * 128,
+                        block * 32,
+                        y_stride + block * 32,
+                        block * 16,
+                        block * 16,
+                        byte_order,
+                    );
+                }
+            }
 
-            rgba_index1 += 8;
-            rgba_index2 += 8;
-            y_index1 += 2;
-            y_index2 += 2;
-            u_index += 1;
-            v_index += 1;
-        }
+            // Complete image width
+            if cur_width < width {
+                rgba_to_yuv420_std(
+                    width - cur_width,
+                    2,
+                    &rows_rgba[cur_width * 4..],
+                    rgba_stride,
+                    &mut rows_y[cur_width..],
+                    &mut row_u[cur_width / 2..],
+                    &mut row_v[cur_width / 2..],
+                    y_stride,
+                    u_stride,
+                    v_stride,
+                    yuv_type,
+                    color_range,
+                    byte_order,
+                );
+            }
+        });
+
+    // Last row of odd height images
+    if height % 2 == 1 {
+        let y = height - 1;
+        rgba_to_yuv420_std(
+            width,
+            1,
+            &buffer_rgba[y * rgba_stride..],
+            rgba_stride,
+            &mut buffer_y[y * y_stride..],
+            &mut buffer_u[(y / 2) * u_stride..],
+            &mut buffer_v[(y / 2) * v_stride..],
+            y_stride,
+            u_stride,
+            v_stride,
+            yuv_type,
+            color_range,
+            byte_order,
+        );
     }
 }
 
@@ -871,6 +1299,7 @@ unsafe fn rgba_to_yuv444_step(
     u_index2: usize,
     v_index1: usize,
     v_index2: usize,
+    byte_order: ByteOrder,
 ) {
     let (rgba1, rgba2, rgba3, rgba4, rgba5, rgba6, rgba7, rgba8) =
         load_rgba_4_x_2!(buffer_rgba, rgba_index1, rgba_index2);
@@ -884,6 +1313,8 @@ unsafe fn rgba_to_yuv444_step(
 
     let (col_r_1, col_g_1, col_b_1, _alpha1, col_r_2, col_g_2, col_b_2, _alpha2) =
         rgb32_to_r_g_b_a!(rgba1, rgba2, rgba3, rgba4, rgba5, rgba6, rgba7, rgba8);
+    let (col_r_1, col_b_1) = byte_order.red_blue(col_r_1, col_b_1);
+    let (col_r_2, col_b_2) = byte_order.red_blue(col_r_2, col_b_2);
 
     /*
     first compute Y', (B-Y') and (R-Y'), in 16bits values, for the first line
@@ -959,6 +1390,8 @@ unsafe fn rgba_to_yuv444_step(
 
     let (col_r_1, col_g_1, col_b_1, _alpha1, col_r_2, col_g_2, col_b_2, _alpha2) =
         rgb32_to_r_g_b_a!(rgba1, rgba2, rgba3, rgba4, rgba5, rgba6, rgba7, rgba8);
+    let (col_r_1, col_b_1) = byte_order.red_blue(col_r_1, col_b_1);
+    let (col_r_2, col_b_2) = byte_order.red_blue(col_r_2, col_b_2);
 
     /* first compute Y', (B-Y') and (R-Y'), in 16bits values, for the first line */
     /* Y is saved for each pixel, while only sums of (B-Y') and (R-Y') for pairs of adjacents
@@ -1036,10 +1469,21 @@ pub fn rgba_to_yuv444_ssse3(
     u_stride: usize,
     v_stride: usize,
     yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
 ) {
-    let param = get_rgb_to_yuv_param(yuv_type);
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_yuv444(width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride),
+        Ok(())
+    );
+    let param = get_rgb_to_yuv_param(yuv_type, color_range);
+    let (r_offset, b_offset) = byte_order.offsets();
 
-    for y in (0..height - 1).step_by(2) {
+    for y in (0..height - height % 2).step_by(2) {
         let mut rgba_index1 = y * rgba_stride;
         let mut rgba_index2 = (y + 1) * rgba_stride;
 
@@ -1052,7 +1496,7 @@ pub fn rgba_to_yuv444_ssse3(
         let mut u_index2 = (y + 1) * u_stride;
         let mut v_index2 = (y + 1) * v_stride;
 
-        for _ in (0..width - 31).step_by(32) {
+        for _ in 0..width / 32 {
             unsafe {
                 rgba_to_yuv444_step(
                     &param,
@@ -1068,6 +1512,7 @@ pub fn rgba_to_yuv444_ssse3(
                     u_index2,
                     v_index1,
                     v_index2,
+                    byte_order,
                 );
             }
             rgba_index1 += 128;
@@ -1084,30 +1529,30 @@ pub fn rgba_to_yuv444_ssse3(
         let cur_width = (width / 32) * 32;
         for _ in cur_width..width {
             // line 1
-            let y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index1] as u16
+            let y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index1 + r_offset] as u16
                 + param.g_factor as u16 * buffer_rgba[rgba_index1 + 1] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index1 + 2] as u16)
+                + param.b_factor as u16 * buffer_rgba[rgba_index1 + b_offset] as u16)
                 >> 8;
-            let u_tmp = buffer_rgba[rgba_index1 + 2] as u16 - y_tmp;
-            let v_tmp = buffer_rgba[rgba_index1] as u16 - y_tmp;
+            let u_tmp = buffer_rgba[rgba_index1 + b_offset] as i16 - y_tmp as i16;
+            let v_tmp = buffer_rgba[rgba_index1 + r_offset] as i16 - y_tmp as i16;
             buffer_y[y_index1] =
                 (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
 
-            buffer_u[u_index1] = (((u_tmp * param.cb_factor as u16) >> 8) + 128) as u8;
-            buffer_v[v_index1] = (((v_tmp * param.cb_factor as u16) >> 8) + 128) as u8;
+            buffer_u[u_index1] = clamp(((u_tmp * param.cb_factor as i16) >> 8) + 128);
+            buffer_v[v_index1] = clamp(((v_tmp * param.cr_factor as i16) >> 8) + 128);
 
             // line 1
-            let y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index2] as u16
+            let y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index2 + r_offset] as u16
                 + param.g_factor as u16 * buffer_rgba[rgba_index2 + 1] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index2 + 2] as u16)
+                + param.b_factor as u16 * buffer_rgba[rgba_index2 + b_offset] as u16)
                 >> 8;
-            let u_tmp = buffer_rgba[rgba_index2 + 2] as u16 - y_tmp;
-            let v_tmp = buffer_rgba[rgba_index2] as u16 - y_tmp;
+            let u_tmp = buffer_rgba[rgba_index2 + b_offset] as i16 - y_tmp as i16;
+            let v_tmp = buffer_rgba[rgba_index2 + r_offset] as i16 - y_tmp as i16;
             buffer_y[y_index2] =
                 (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
 
-            buffer_u[u_index2] = (((u_tmp * param.cb_factor as u16) >> 8) + 128) as u8;
-            buffer_v[v_index2] = (((v_tmp * param.cb_factor as u16) >> 8) + 128) as u8;
+            buffer_u[u_index2] = clamp(((u_tmp * param.cb_factor as i16) >> 8) + 128);
+            buffer_v[v_index2] = clamp(((v_tmp * param.cr_factor as i16) >> 8) + 128);
 
             rgba_index1 += 4;
             rgba_index2 += 4;
@@ -1119,6 +1564,26 @@ pub fn rgba_to_yuv444_ssse3(
             v_index2 += 1;
         }
     }
+
+    // Last row of odd height images
+    if height % 2 == 1 {
+        let y = height - 1;
+        rgba_to_yuv444_std(
+            width,
+            1,
+            &buffer_rgba[y * rgba_stride..],
+            rgba_stride,
+            &mut buffer_y[y * y_stride..],
+            &mut buffer_u[y * u_stride..],
+            &mut buffer_v[y * v_stride..],
+            y_stride,
+            u_stride,
+            v_stride,
+            yuv_type,
+            color_range,
+            byte_order,
+        );
+    }
 }
 
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -1439,6 +1904,7 @@ unsafe fn yuv420_to_rgba_step(
     y_index2: usize,
     u_index1: usize,
     v_index1: usize,
+    byte_order: ByteOrder,
 ) {
     let y_ptr1_0 = &buffer_y[y_index1] as *const u8 as *const std::arch::x86_64::__m128i;
     let y_ptr2_0 = &buffer_y[y_index2] as *const u8 as *const std::arch::x86_64::__m128i;
@@ -1557,9 +2023,11 @@ unsafe fn yuv420_to_rgba_step(
     let rgb_ptr7 = &mut buffer_rgb[rgb_index1 + 96] as *mut u8 as *mut std::arch::x86_64::__m128i;
     let rgb_ptr8 = &mut buffer_rgb[rgb_index1 + 112] as *mut u8 as *mut std::arch::x86_64::__m128i;
 
-    let a_8_11 = _mm_set1_epi16(0xFF);
-    let a_8_12 = _mm_set1_epi16(0xFF);
+    let a_8_11 = _mm_set1_epi8(-1);
+    let a_8_12 = _mm_set1_epi8(-1);
 
+    let (r_8_11, b_8_11) = byte_order.red_blue(r_8_11, b_8_11);
+    let (r_8_12, b_8_12) = byte_order.red_blue(r_8_12, b_8_12);
     let (rgb_1, rgb_2, rgb_3, rgb_4, rgb_5, rgb_6, rgb_7, rgb_8) =
         pack_r_g_b_a_to_rgb32!(r_8_11, r_8_12, g_8_11, g_8_12, b_8_11, b_8_12, a_8_11, a_8_12);
     _mm_storeu_si128(rgb_ptr1, rgb_1);
@@ -1580,9 +2048,11 @@ unsafe fn yuv420_to_rgba_step(
     let rgb_ptr7 = &mut buffer_rgb[rgb_index2 + 96] as *mut u8 as *mut std::arch::x86_64::__m128i;
     let rgb_ptr8 = &mut buffer_rgb[rgb_index2 + 112] as *mut u8 as *mut std::arch::x86_64::__m128i;
 
-    let a_8_21 = _mm_set1_epi16(0xFF);
-    let a_8_22 = _mm_set1_epi16(0xFF);
+    let a_8_21 = _mm_set1_epi8(-1);
+    let a_8_22 = _mm_set1_epi8(-1);
 
+    let (r_8_21, b_8_21) = byte_order.red_blue(r_8_21, b_8_21);
+    let (r_8_22, b_8_22) = byte_order.red_blue(r_8_22, b_8_22);
     let (rgb_1, rgb_2, rgb_3, rgb_4, rgb_5, rgb_6, rgb_7, rgb_8) =
         pack_r_g_b_a_to_rgb32!(r_8_21, r_8_22, g_8_21, g_8_22, b_8_21, b_8_22, a_8_21, a_8_22);
     _mm_storeu_si128(rgb_ptr1, rgb_1);
@@ -1608,8 +2078,17 @@ pub fn yuv420_to_rgb_ssse3(
     buffer_rgb: &mut [u8],
     rgb_stride: usize,
     yuv_type: YuvType,
+    color_range: ColorRange,
 ) {
-    let param = get_yuv_to_rgb_param(yuv_type);
+    debug_assert_eq!(
+        check_yuv420(width, height, buffer_y, buffer_u, buffer_v, y_stride, uv_stride, uv_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_plane("rgb", buffer_rgb, height, width * 3, rgb_stride),
+        Ok(())
+    );
+    let param = get_yuv_to_rgb_param(yuv_type, color_range);
     for y in (0..height - 1).step_by(2) {
         let mut rgb_index1 = y * rgb_stride;
         let mut rgb_index2 = (y + 1) * rgb_stride;
@@ -1620,7 +2099,7 @@ pub fn yuv420_to_rgb_ssse3(
         let mut u_index = (y / 2) * uv_stride;
         let mut v_index = (y / 2) * uv_stride;
 
-        for _ in (0..width - 31).step_by(32) {
+        for _ in 0..width / 32 {
             unsafe {
                 yuv420_to_rgb_step(
                     &param, buffer_rgb, buffer_y, buffer_u, buffer_v, rgb_index1, rgb_index2,
@@ -1651,8 +2130,19 @@ pub fn yuv420_to_rgba_ssse3(
     buffer_rgba: &mut [u8],
     rgba_stride: usize,
     yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
 ) {
-    let param = get_yuv_to_rgb_param(yuv_type);
+    debug_assert_eq!(
+        check_yuv420(width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    let param = get_yuv_to_rgb_param(yuv_type, color_range);
+    let (r_offset, b_offset) = byte_order.offsets();
     for y in (0..height - 1).step_by(2) {
         let mut rgba_index1 = y * rgba_stride;
         let mut rgba_index2 = (y + 1) * rgba_stride;
@@ -1663,7 +2153,7 @@ pub fn yuv420_to_rgba_ssse3(
         let mut u_index = (y / 2) * u_stride;
         let mut v_index = (y / 2) * v_stride;
 
-        for _ in (0..width - 31).step_by(32) {
+        for _ in 0..width / 32 {
             unsafe {
                 yuv420_to_rgba_step(
                     &param,
@@ -1677,6 +2167,7 @@ pub fn yuv420_to_rgba_ssse3(
                     y_index2,
                     u_index,
                     v_index,
+                    byte_order,
                 );
             }
             rgba_index1 += 128;
@@ -1700,29 +2191,29 @@ pub fn yuv420_to_rgba_ssse3(
 
             let y_tmp =
                 (param.y_factor as i16 * (buffer_y[y_index1] as i16 - param.y_offset as i16)) >> 7;
-            buffer_rgba[rgba_index1] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index1 + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index1 + 1] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index1 + 2] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index1 + b_offset] = clamp(y_tmp + b_cb_offset);
 
             let y_tmp = (param.y_factor as i16
                 * (buffer_y[y_index1 + 1] as i16 - param.y_offset as i16))
                 >> 7;
-            buffer_rgba[rgba_index1 + 4] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index1 + 4 + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index1 + 5] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index1 + 6] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index1 + 4 + b_offset] = clamp(y_tmp + b_cb_offset);
 
             let y_tmp =
                 (param.y_factor as i16 * (buffer_y[y_index2] as i16 - param.y_offset as i16)) >> 7;
-            buffer_rgba[rgba_index2] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index2 + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index2 + 1] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index2 + 2] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index2 + b_offset] = clamp(y_tmp + b_cb_offset);
 
             let y_tmp = (param.y_factor as i16
                 * (buffer_y[y_index2 + 1] as i16 - param.y_offset as i16))
                 >> 7;
-            buffer_rgba[rgba_index2 + 4] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index2 + 4 + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index2 + 5] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index2 + 6] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index2 + 4 + b_offset] = clamp(y_tmp + b_cb_offset);
 
             rgba_index1 += 8;
             rgba_index2 += 8;
@@ -1995,8 +2486,17 @@ pub fn yuv444_rgb_ssse3(
     buffer_rgb: &mut [u8],
     rgb_stride: usize,
     yuv_type: YuvType,
+    color_range: ColorRange,
 ) {
-    let param = get_yuv_to_rgb_param(yuv_type);
+    debug_assert_eq!(
+        check_yuv444(width, height, buffer_y, buffer_u, buffer_v, y_stride, uv_stride, uv_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_plane("rgb", buffer_rgb, height, width * 3, rgb_stride),
+        Ok(())
+    );
+    let param = get_yuv_to_rgb_param(yuv_type, color_range);
     for y in (0..height - 1).step_by(2) {
         let mut rgb_index1 = y * rgb_stride;
         let mut rgb_index2 = (y + 1) * rgb_stride;
@@ -2009,7 +2509,7 @@ pub fn yuv444_rgb_ssse3(
         let mut u_index2 = (y + 1) * uv_stride;
         let mut v_index2 = (y + 1) * uv_stride;
 
-        for _ in (0..width - 31).step_by(32) {
+        for _ in 0..width / 32 {
             unsafe {
                 yuv444_to_rgb_step(
                     &param, buffer_rgb, buffer_y, buffer_u, buffer_v, rgb_index1, rgb_index2,
@@ -2044,6 +2544,7 @@ unsafe fn yuv444_to_rgba_step(
     u_index2: usize,
     v_index1: usize,
     v_index2: usize,
+    byte_order: ByteOrder,
 ) {
     let u_ptr1_0 = &buffer_u[u_index1] as *const u8 as *const std::arch::x86_64::__m128i;
     let v_ptr1_0 = &buffer_v[v_index1] as *const u8 as *const std::arch::x86_64::__m128i;
@@ -2193,9 +2694,11 @@ unsafe fn yuv444_to_rgba_step(
     let g_8_22 = _mm_packus_epi16(g_16_1, g_16_2);
     let b_8_22 = _mm_packus_epi16(b_16_1, b_16_2);
 
-    let a_8_11 = _mm_set1_epi16(0xFF);
-    let a_8_12 = _mm_set1_epi16(0xFF);
+    let a_8_11 = _mm_set1_epi8(-1);
+    let a_8_12 = _mm_set1_epi8(-1);
 
+    let (r_8_11, b_8_11) = byte_order.red_blue(r_8_11, b_8_11);
+    let (r_8_12, b_8_12) = byte_order.red_blue(r_8_12, b_8_12);
     let (rgb_1, rgb_2, rgb_3, rgb_4, rgb_5, rgb_6, rgb_7, rgb_8) =
         pack_r_g_b_a_to_rgb32!(r_8_11, r_8_12, g_8_11, g_8_12, b_8_11, b_8_12, a_8_11, a_8_12);
 
@@ -2217,9 +2720,11 @@ unsafe fn yuv444_to_rgba_step(
     _mm_storeu_si128(rgb_ptr7, rgb_7);
     _mm_storeu_si128(rgb_ptr8, rgb_8);
 
-    let a_8_11 = _mm_set1_epi16(0xFF);
-    let a_8_12 = _mm_set1_epi16(0xFF);
+    let a_8_11 = _mm_set1_epi8(-1);
+    let a_8_12 = _mm_set1_epi8(-1);
 
+    let (r_8_21, b_8_21) = byte_order.red_blue(r_8_21, b_8_21);
+    let (r_8_22, b_8_22) = byte_order.red_blue(r_8_22, b_8_22);
     let (rgb_1, rgb_2, rgb_3, rgb_4, rgb_5, rgb_6, rgb_7, rgb_8) =
         pack_r_g_b_a_to_rgb32!(r_8_21, r_8_22, g_8_21, g_8_22, b_8_21, b_8_22, a_8_11, a_8_12);
 
@@ -2256,9 +2761,20 @@ pub fn yuv444_to_rgba_ssse3(
     buffer_rgba: &mut [u8],
     rgba_stride: usize,
     yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
 ) {
-    let param = get_yuv_to_rgb_param(yuv_type);
-    for y in (0..height - 1).step_by(2) {
+    debug_assert_eq!(
+        check_yuv444(width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    let param = get_yuv_to_rgb_param(yuv_type, color_range);
+    let (r_offset, b_offset) = byte_order.offsets();
+    for y in (0..height - height % 2).step_by(2) {
         let mut rgba_index1 = y * rgba_stride;
         let mut rgba_index2 = (y + 1) * rgba_stride;
 
@@ -2270,7 +2786,7 @@ pub fn yuv444_to_rgba_ssse3(
         let mut u_index2 = (y + 1) * u_stride;
         let mut v_index2 = (y + 1) * v_stride;
 
-        for _ in (0..width - 31).step_by(32) {
+        for _ in 0..width / 32 {
             unsafe {
                 yuv444_to_rgba_step(
                     &param,
@@ -2286,6 +2802,7 @@ pub fn yuv444_to_rgba_ssse3(
                     u_index2,
                     v_index1,
                     v_index2,
+                    byte_order,
                 );
             }
             rgba_index1 += 128;
@@ -2312,9 +2829,9 @@ pub fn yuv444_to_rgba_ssse3(
 
             let y_tmp =
                 (param.y_factor as i16 * (buffer_y[y_index1] as i16 - param.y_offset as i16)) >> 7;
-            buffer_rgba[rgba_index1] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index1 + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index1 + 1] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index1 + 2] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index1 + b_offset] = clamp(y_tmp + b_cb_offset);
 
             // line 1
             let u_tmp = buffer_u[u_index2] as i16 - 128;
@@ -2327,9 +2844,9 @@ pub fn yuv444_to_rgba_ssse3(
 
             let y_tmp =
                 (param.y_factor as i16 * (buffer_y[y_index2] as i16 - param.y_offset as i16)) >> 7;
-            buffer_rgba[rgba_index2] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index2 + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index2 + 1] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index2 + 2] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index2 + b_offset] = clamp(y_tmp + b_cb_offset);
 
             rgba_index1 += 4;
             rgba_index2 += 4;
@@ -2341,74 +2858,583 @@ pub fn yuv444_to_rgba_ssse3(
             v_index2 += 1;
         }
     }
+
+    // Last row of odd height images
+    if height % 2 == 1 {
+        let y = height - 1;
+        yuv444_to_rgba_std(
+            width,
+            1,
+            &buffer_y[y * y_stride..],
+            &buffer_u[y * u_stride..],
+            &buffer_v[y * v_stride..],
+            y_stride,
+            u_stride,
+            v_stride,
+            &mut buffer_rgba[y * rgba_stride..],
+            rgba_stride,
+            yuv_type,
+            color_range,
+            byte_order,
+        );
+    }
+}
+
+/// Compute y (not rescaled), b - y and r - y for 8 pixels
+#[cfg(target_arch = "aarch64")]
+unsafe fn rgb_to_y_u_v_16_neon(
+    param: &RgbToYuvParam,
+    r: uint8x8_t,
+    g: uint8x8_t,
+    b: uint8x8_t,
+) -> (uint16x8_t, uint16x8_t, uint16x8_t) {
+    let r = vmovl_u8(r);
+    let g = vmovl_u8(g);
+    let b = vmovl_u8(b);
+    let y_tmp = vmulq_u16(r, vdupq_n_u16(param.r_factor as u16));
+    let y_tmp = vmlaq_u16(y_tmp, g, vdupq_n_u16(param.g_factor as u16));
+    let y_tmp = vmlaq_u16(y_tmp, b, vdupq_n_u16(param.b_factor as u16));
+    let y_tmp = vshrq_n_u16::<8>(y_tmp);
+    (y_tmp, vsubq_u16(b, y_tmp), vsubq_u16(r, y_tmp))
+}
+
+/// Rescale 16 y values and pack them to u8
+#[cfg(target_arch = "aarch64")]
+unsafe fn rescale_y_neon(param: &RgbToYuvParam, y_lo: uint16x8_t, y_hi: uint16x8_t) -> uint8x16_t {
+    let y_factor = vdupq_n_u16(param.y_factor as u16);
+    let y_offset = vdupq_n_u16(param.y_offset as u16);
+    let y_lo = vaddq_u16(vshrq_n_u16::<7>(vmulq_u16(y_lo, y_factor)), y_offset);
+    let y_hi = vaddq_u16(vshrq_n_u16::<7>(vmulq_u16(y_hi, y_factor)), y_offset);
+    vcombine_u8(vmovn_u16(y_lo), vmovn_u16(y_hi))
+}
+
+/// Rescale 8 summed chroma values and pack them to u8
+#[cfg(target_arch = "aarch64")]
+unsafe fn rescale_uv_neon(uv: uint16x8_t, factor: u8) -> uint8x8_t {
+    // Wrapped unsigned sums are the two's complement of the signed sums
+    let uv = vshrq_n_s16::<2>(vreinterpretq_s16_u16(uv));
+    let uv = vmulq_s16(uv, vdupq_n_s16(factor as i16));
+    vqmovun_s16(vaddq_s16(vshrq_n_s16::<8>(uv), vdupq_n_s16(128)))
 }
 
 #[allow(clippy::too_many_arguments)]
-pub fn rgba_to_nv12_std(
+#[cfg(target_arch = "aarch64")]
+unsafe fn rgba_to_yuv420_neon_step(
+    param: &RgbToYuvParam,
+    buffer_rgba: &[u8],
+    buffer_y: &mut [u8],
+    buffer_u: &mut [u8],
+    buffer_v: &mut [u8],
+    rgba_index1: usize,
+    rgba_index2: usize,
+    y_index1: usize,
+    y_index2: usize,
+    u_index: usize,
+    v_index: usize,
+    byte_order: ByteOrder,
+) {
+    let rgba1 = vld4q_u8(buffer_rgba[rgba_index1..rgba_index1 + 64].as_ptr());
+    let rgba2 = vld4q_u8(buffer_rgba[rgba_index2..rgba_index2 + 64].as_ptr());
+    let (r1, b1) = byte_order.red_blue(rgba1.0, rgba1.2);
+    let (r2, b2) = byte_order.red_blue(rgba2.0, rgba2.2);
+
+    let (y1_lo, u1_lo, v1_lo) = rgb_to_y_u_v_16_neon(
+        param,
+        vget_low_u8(r1),
+        vget_low_u8(rgba1.1),
+        vget_low_u8(b1),
+    );
+    let (y1_hi, u1_hi, v1_hi) = rgb_to_y_u_v_16_neon(
+        param,
+        vget_high_u8(r1),
+        vget_high_u8(rgba1.1),
+        vget_high_u8(b1),
+    );
+    let (y2_lo, u2_lo, v2_lo) = rgb_to_y_u_v_16_neon(
+        param,
+        vget_low_u8(r2),
+        vget_low_u8(rgba2.1),
+        vget_low_u8(b2),
+    );
+    let (y2_hi, u2_hi, v2_hi) = rgb_to_y_u_v_16_neon(
+        param,
+        vget_high_u8(r2),
+        vget_high_u8(rgba2.1),
+        vget_high_u8(b2),
+    );
+
+    vst1q_u8(
+        buffer_y[y_index1..y_index1 + 16].as_mut_ptr(),
+        rescale_y_neon(param, y1_lo, y1_hi),
+    );
+    vst1q_u8(
+        buffer_y[y_index2..y_index2 + 16].as_mut_ptr(),
+        rescale_y_neon(param, y2_lo, y2_hi),
+    );
+
+    // Sum chroma of each 2x2 block
+    let u = vaddq_u16(vpaddq_u16(u1_lo, u1_hi), vpaddq_u16(u2_lo, u2_hi));
+    let v = vaddq_u16(vpaddq_u16(v1_lo, v1_hi), vpaddq_u16(v2_lo, v2_hi));
+    vst1_u8(
+        buffer_u[u_index..u_index + 8].as_mut_ptr(),
+        rescale_uv_neon(u, param.cb_factor),
+    );
+    vst1_u8(
+        buffer_v[v_index..v_index + 8].as_mut_ptr(),
+        rescale_uv_neon(v, param.cr_factor),
+    );
+}
+
+#[allow(clippy::too_many_arguments)]
+#[cfg(target_arch = "aarch64")]
+pub fn rgba_to_yuv420_neon(
     width: usize,
     height: usize,
     buffer_rgba: &[u8],
     rgba_stride: usize,
     buffer_y: &mut [u8],
-    buffer_uv: &mut [u8],
+    buffer_u: &mut [u8],
+    buffer_v: &mut [u8],
     y_stride: usize,
-    uv_stride: usize,
+    u_stride: usize,
+    v_stride: usize,
     yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
 ) {
-    let param = get_rgb_to_yuv_param(yuv_type);
-    for y in (0..height - 1).step_by(2) {
-        let mut y_index1 = y * y_stride;
-        let mut y_index2 = (y + 1) * y_stride;
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_yuv420(width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride),
+        Ok(())
+    );
+    let param = get_rgb_to_yuv_param(yuv_type, color_range);
 
+    for y in (0..height - height % 2).step_by(2) {
         let mut rgba_index1 = y * rgba_stride;
         let mut rgba_index2 = (y + 1) * rgba_stride;
-        let mut uv_index = (y / 2) * uv_stride;
-        for _ in (0..width - 1).step_by(2) {
-            // compute yuv for the four pixels, u and v values are summed
-            let mut y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index1] as u16
-                + param.g_factor as u16 * buffer_rgba[rgba_index1 + 1] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index1 + 2] as u16)
-                >> 8;
-            let mut u_tmp = buffer_rgba[rgba_index1 + 2] as u16 - y_tmp;
-            let mut v_tmp = buffer_rgba[rgba_index1] as u16 - y_tmp;
-            buffer_y[y_index1] =
-                (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
-
-            y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index1 + 4] as u16
-                + param.g_factor as u16 * buffer_rgba[rgba_index1 + 5] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index1 + 6] as u16)
-                >> 8;
-            u_tmp += buffer_rgba[rgba_index1 + 6] as u16 - y_tmp;
-            v_tmp += buffer_rgba[rgba_index1 + 4] as u16 - y_tmp;
-            buffer_y[y_index1 + 1] =
-                (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
-
-            y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index2] as u16
-                + param.g_factor as u16 * buffer_rgba[rgba_index2 + 1] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index2 + 2] as u16)
-                >> 8;
-            u_tmp += buffer_rgba[rgba_index2 + 2] as u16 - y_tmp;
-            v_tmp += buffer_rgba[rgba_index2] as u16 - y_tmp;
-            buffer_y[y_index2] =
-                (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
 
-            y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index2 + 4] as u16
-                + param.g_factor as u16 * buffer_rgba[rgba_index2 + 5] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index2 + 6] as u16)
-                >> 8;
-            u_tmp += buffer_rgba[rgba_index2 + 6] as u16 - y_tmp;
-            v_tmp += buffer_rgba[rgba_index2 + 4] as u16 - y_tmp;
-            buffer_y[y_index2 + 1] =
-                (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
+        let mut y_index1 = y * y_stride;
+        let mut y_index2 = (y + 1) * y_stride;
 
-            buffer_uv[uv_index] = ((((u_tmp >> 2) * param.cb_factor as u16) >> 8) + 128) as u8;
-            buffer_uv[uv_index + 1] = ((((v_tmp >> 2) * param.cb_factor as u16) >> 8) + 128) as u8;
+        let mut u_index = (y / 2) * u_stride;
+        let mut v_index = (y / 2) * v_stride;
 
-            rgba_index1 += 8;
-            rgba_index2 += 8;
-            y_index1 += 2;
-            y_index2 += 2;
-            uv_index += 2;
+        for _ in 0..width / 16 {
+            unsafe {
+                rgba_to_yuv420_neon_step(
+                    &param,
+                    buffer_rgba,
+                    buffer_y,
+                    buffer_u,
+                    buffer_v,
+                    rgba_index1,
+                    rgba_index2,
+                    y_index1,
+                    y_index2,
+                    u_index,
+                    v_index,
+                    byte_order,
+                );
+            }
+            rgba_index1 += 64;
+            rgba_index2 += 64;
+            y_index1 += 16;
+            y_index2 += 16;
+            u_index += 8;
+            v_index += 8;
+        }
+    }
+
+    // Complete image width and the last row of odd height images
+    let cur_width = (width / 16) * 16;
+    if cur_width < width {
+        rgba_to_yuv420_std(
+            width - cur_width,
+            height,
+            &buffer_rgba[cur_width * 4..],
+            rgba_stride,
+            &mut buffer_y[cur_width..],
+            &mut buffer_u[cur_width / 2..],
+            &mut buffer_v[cur_width / 2..],
+            y_stride,
+            u_stride,
+            v_stride,
+            yuv_type,
+            color_range,
+            byte_order,
+        );
+    }
+    if height % 2 == 1 && cur_width > 0 {
+        let y = height - 1;
+        rgba_to_yuv420_std(
+            cur_width,
+            1,
+            &buffer_rgba[y * rgba_stride..],
+            rgba_stride,
+            &mut buffer_y[y * y_stride..],
+            &mut buffer_u[(y / 2) * u_stride..],
+            &mut buffer_v[(y / 2) * v_stride..],
+            y_stride,
+            u_stride,
+            v_stride,
+            yuv_type,
+            color_range,
+            byte_order,
+        );
+    }
+}
+
+/// Compute r, g, b for 8 pixels from y and the chroma offsets
+#[cfg(target_arch = "aarch64")]
+unsafe fn y_to_rgb_16_neon(
+    param: &YuvToRgbParam,
+    y: uint8x8_t,
+    r_cr_offset: int16x8_t,
+    g_cbcr_offset: int16x8_t,
+    b_cb_offset: int16x8_t,
+) -> (uint8x8_t, uint8x8_t, uint8x8_t) {
+    let y_tmp = vsubq_s16(
+        vreinterpretq_s16_u16(vmovl_u8(y)),
+        vdupq_n_s16(param.y_offset as i16),
+    );
+    let y_tmp = vshrq_n_s16::<7>(vmulq_s16(y_tmp, vdupq_n_s16(param.y_factor as i16)));
+    (
+        vqmovun_s16(vaddq_s16(y_tmp, r_cr_offset)),
+        vqmovun_s16(vsubq_s16(y_tmp, g_cbcr_offset)),
+        vqmovun_s16(vaddq_s16(y_tmp, b_cb_offset)),
+    )
+}
+
+/// Convert 16 pixels of y to rgba, keeping the alpha channel
+#[cfg(target_arch = "aarch64")]
+#[allow(clippy::too_many_arguments)]
+unsafe fn yuv_to_rgba_16_neon(
+    param: &YuvToRgbParam,
+    buffer_rgba: &mut [u8],
+    rgba_index: usize,
+    y: uint8x16_t,
+    r_cr_offset: int16x8_t,
+    g_cbcr_offset: int16x8_t,
+    b_cb_offset: int16x8_t,
+    byte_order: ByteOrder,
+) {
+    // Each chroma value is shared by two consecutive pixels
+    let (r_lo, g_lo, b_lo) = y_to_rgb_16_neon(
+        param,
+        vget_low_u8(y),
+        vzip1q_s16(r_cr_offset, r_cr_offset),
+        vzip1q_s16(g_cbcr_offset, g_cbcr_offset),
+        vzip1q_s16(b_cb_offset, b_cb_offset),
+    );
+    let (r_hi, g_hi, b_hi) = y_to_rgb_16_neon(
+        param,
+        vget_high_u8(y),
+        vzip2q_s16(r_cr_offset, r_cr_offset),
+        vzip2q_s16(g_cbcr_offset, g_cbcr_offset),
+        vzip2q_s16(b_cb_offset, b_cb_offset),
+    );
+
+    let rgba = &mut buffer_rgba[rgba_index..rgba_index + 64];
+    let mut pixels = vld4q_u8(rgba.as_ptr());
+    (pixels.0, pixels.2) = byte_order.red_blue(vcombine_u8(r_lo, r_hi), vcombine_u8(b_lo, b_hi));
+    pixels.1 = vcombine_u8(g_lo, g_hi);
+    vst4q_u8(rgba.as_mut_ptr(), pixels);
+}
+
+#[allow(clippy::too_many_arguments)]
+#[cfg(target_arch = "aarch64")]
+unsafe fn yuv420_to_rgba_neon_step(
+    param: &YuvToRgbParam,
+    buffer_rgba: &mut [u8],
+    buffer_y: &[u8],
+    buffer_u: &[u8],
+    buffer_v: &[u8],
+    rgba_index1: usize,
+    rgba_index2: usize,
+    y_index1: usize,
+    y_index2: usize,
+    u_index: usize,
+    v_index: usize,
+    byte_order: ByteOrder,
+) {
+    let u = vreinterpretq_s16_u16(vmovl_u8(vld1_u8(buffer_u[u_index..u_index + 8].as_ptr())));
+    let v = vreinterpretq_s16_u16(vmovl_u8(vld1_u8(buffer_v[v_index..v_index + 8].as_ptr())));
+    let u = vsubq_s16(u, vdupq_n_s16(128));
+    let v = vsubq_s16(v, vdupq_n_s16(128));
+
+    let b_cb_offset = vshrq_n_s16::<6>(vmulq_s16(u, vdupq_n_s16(param.cb_factor as i16)));
+    let r_cr_offset = vshrq_n_s16::<6>(vmulq_s16(v, vdupq_n_s16(param.cr_factor as i16)));
+    let g_cbcr_offset = vmulq_s16(u, vdupq_n_s16(param.g_cb_factor as i16));
+    let g_cbcr_offset = vmlaq_s16(g_cbcr_offset, v, vdupq_n_s16(param.g_cr_factor as i16));
+    let g_cbcr_offset = vshrq_n_s16::<7>(g_cbcr_offset);
+
+    let y1 = vld1q_u8(buffer_y[y_index1..y_index1 + 16].as_ptr());
+    let y2 = vld1q_u8(buffer_y[y_index2..y_index2 + 16].as_ptr());
+    yuv_to_rgba_16_neon(
+        param,
+        buffer_rgba,
+        rgba_index1,
+        y1,
+        r_cr_offset,
+        g_cbcr_offset,
+        b_cb_offset,
+        byte_order,
+    );
+    yuv_to_rgba_16_neon(
+        param,
+        buffer_rgba,
+        rgba_index2,
+        y2,
+        r_cr_offset,
+        g_cbcr_offset,
+        b_cb_offset,
+        byte_order,
+    );
+}
+
+#[allow(clippy::too_many_arguments)]
+#[cfg(target_arch = "aarch64")]
+pub fn yuv420_to_rgba_neon(
+    width: usize,
+    height: usize,
+    buffer_y: &[u8],
+    buffer_u: &[u8],
+    buffer_v: &[u8],
+    y_stride: usize,
+    u_stride: usize,
+    v_stride: usize,
+    buffer_rgba: &mut [u8],
+    rgba_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) {
+    debug_assert_eq!(
+        check_yuv420(width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    let param = get_yuv_to_rgb_param(yuv_type, color_range);
+    for y in (0..height - 1).step_by(2) {
+        let mut rgba_index1 = y * rgba_stride;
+        let mut rgba_index2 = (y + 1) * rgba_stride;
+
+        let mut y_index1 = y * y_stride;
+        let mut y_index2 = (y + 1) * y_stride;
+
+        let mut u_index = (y / 2) * u_stride;
+        let mut v_index = (y / 2) * v_stride;
+
+        for _ in 0..width / 16 {
+            unsafe {
+                yuv420_to_rgba_neon_step(
+                    &param,
+                    buffer_rgba,
+                    buffer_y,
+                    buffer_u,
+                    buffer_v,
+                    rgba_index1,
+                    rgba_index2,
+                    y_index1,
+                    y_index2,
+                    u_index,
+                    v_index,
+                    byte_order,
+                );
+            }
+            rgba_index1 += 64;
+            rgba_index2 += 64;
+            y_index1 += 16;
+            y_index2 += 16;
+            u_index += 8;
+            v_index += 8;
+        }
+    }
+
+    // Complete image width
+    let cur_width = (width / 16) * 16;
+    if cur_width < width {
+        yuv420_to_rgba_std(
+            width - cur_width,
+            height,
+            &buffer_y[cur_width..],
+            &buffer_u[cur_width / 2..],
+            &buffer_v[cur_width / 2..],
+            y_stride,
+            u_stride,
+            v_stride,
+            &mut buffer_rgba[cur_width * 4..],
+            rgba_stride,
+            yuv_type,
+            color_range,
+            byte_order,
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn rgba_to_nv12_std(
+    width: usize,
+    height: usize,
+    buffer_rgba: &[u8],
+    rgba_stride: usize,
+    buffer_y: &mut [u8],
+    buffer_uv: &mut [u8],
+    y_stride: usize,
+    uv_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) {
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_semi_planar(width, height, buffer_y, buffer_uv, y_stride, uv_stride),
+        Ok(())
+    );
+    rgba_to_semi_planar_std(
+        width,
+        height,
+        buffer_rgba,
+        rgba_stride,
+        buffer_y,
+        buffer_uv,
+        y_stride,
+        uv_stride,
+        yuv_type,
+        color_range,
+        ChromaOrder::Uv,
+        byte_order,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn rgba_to_nv21_std(
+    width: usize,
+    height: usize,
+    buffer_rgba: &[u8],
+    rgba_stride: usize,
+    buffer_y: &mut [u8],
+    buffer_vu: &mut [u8],
+    y_stride: usize,
+    vu_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) {
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_semi_planar(width, height, buffer_y, buffer_vu, y_stride, vu_stride),
+        Ok(())
+    );
+    rgba_to_semi_planar_std(
+        width,
+        height,
+        buffer_rgba,
+        rgba_stride,
+        buffer_y,
+        buffer_vu,
+        y_stride,
+        vu_stride,
+        yuv_type,
+        color_range,
+        ChromaOrder::Vu,
+        byte_order,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn rgba_to_semi_planar_std(
+    width: usize,
+    height: usize,
+    buffer_rgba: &[u8],
+    rgba_stride: usize,
+    buffer_y: &mut [u8],
+    buffer_uv: &mut [u8],
+    y_stride: usize,
+    uv_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    order: ChromaOrder,
+    byte_order: ByteOrder,
+) {
+    let param = get_rgb_to_yuv_param(yuv_type, color_range);
+    let (r_offset, b_offset) = byte_order.offsets();
+    let (u_offset, v_offset) = order.offsets();
+    for y in (0..height).step_by(2) {
+        // The last row of an odd height image is sampled alone
+        let next_row = if y + 1 < height { 1 } else { 0 };
+        let mut y_index1 = y * y_stride;
+        let mut y_index2 = (y + next_row) * y_stride;
+
+        let mut rgba_index1 = y * rgba_stride;
+        let mut rgba_index2 = (y + next_row) * rgba_stride;
+        let mut uv_index = (y / 2) * uv_stride;
+        for x in (0..width).step_by(2) {
+            // The last column of an odd width image is sampled alone
+            let next_col = if x + 1 < width { 1 } else { 0 };
+            // compute yuv for the four pixels, u and v values are summed
+            let mut y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index1 + r_offset] as u16
+                + param.g_factor as u16 * buffer_rgba[rgba_index1 + 1] as u16
+                + param.b_factor as u16 * buffer_rgba[rgba_index1 + b_offset] as u16)
+                >> 8;
+            let mut u_tmp = buffer_rgba[rgba_index1 + b_offset] as i16 - y_tmp as i16;
+            let mut v_tmp = buffer_rgba[rgba_index1 + r_offset] as i16 - y_tmp as i16;
+            buffer_y[y_index1] =
+                (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
+
+            y_tmp = (param.r_factor as u16
+                * buffer_rgba[rgba_index1 + next_col * 4 + r_offset] as u16
+                + param.g_factor as u16 * buffer_rgba[rgba_index1 + next_col * 4 + 1] as u16
+                + param.b_factor as u16
+                    * buffer_rgba[rgba_index1 + next_col * 4 + b_offset] as u16)
+                >> 8;
+            u_tmp += buffer_rgba[rgba_index1 + next_col * 4 + b_offset] as i16 - y_tmp as i16;
+            v_tmp += buffer_rgba[rgba_index1 + next_col * 4 + r_offset] as i16 - y_tmp as i16;
+            buffer_y[y_index1 + next_col] =
+                (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
+
+            y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index2 + r_offset] as u16
+                + param.g_factor as u16 * buffer_rgba[rgba_index2 + 1] as u16
+                + param.b_factor as u16 * buffer_rgba[rgba_index2 + b_offset] as u16)
+                >> 8;
+            u_tmp += buffer_rgba[rgba_index2 + b_offset] as i16 - y_tmp as i16;
+            v_tmp += buffer_rgba[rgba_index2 + r_offset] as i16 - y_tmp as i16;
+            buffer_y[y_index2] =
+                (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
+
+            y_tmp = (param.r_factor as u16
+                * buffer_rgba[rgba_index2 + next_col * 4 + r_offset] as u16
+                + param.g_factor as u16 * buffer_rgba[rgba_index2 + next_col * 4 + 1] as u16
+                + param.b_factor as u16
+                    * buffer_rgba[rgba_index2 + next_col * 4 + b_offset] as u16)
+                >> 8;
+            u_tmp += buffer_rgba[rgba_index2 + next_col * 4 + b_offset] as i16 - y_tmp as i16;
+            v_tmp += buffer_rgba[rgba_index2 + next_col * 4 + r_offset] as i16 - y_tmp as i16;
+            buffer_y[y_index2 + next_col] =
+                (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
+
+            buffer_uv[uv_index + u_offset] =
+                clamp((((u_tmp >> 2) * param.cb_factor as i16) >> 8) + 128);
+            buffer_uv[uv_index + v_offset] =
+                clamp((((v_tmp >> 2) * param.cr_factor as i16) >> 8) + 128);
+
+            rgba_index1 += 8;
+            rgba_index2 += 8;
+            y_index1 += 2;
+            y_index2 += 2;
+            uv_index += 2;
         }
     }
 }
@@ -2425,6 +3451,8 @@ unsafe fn rgba_to_nv12_step(
     y_index1: usize,
     y_index2: usize,
     uv_index: usize,
+    order: ChromaOrder,
+    byte_order: ByteOrder,
 ) {
     let (rgba1, rgba2, rgba3, rgba4, rgba5, rgba6, rgba7, rgba8) =
         load_rgba_4_x_2!(buffer_rgba, rgba_index1, rgba_index2);
@@ -2435,6 +3463,8 @@ unsafe fn rgba_to_nv12_step(
 
     let (col_r_1, col_g_1, col_b_1, _alpha1, col_r_2, col_g_2, col_b_2, _alpha2) =
         rgb32_to_r_g_b_a!(rgba1, rgba2, rgba3, rgba4, rgba5, rgba6, rgba7, rgba8);
+    let (col_r_1, col_b_1) = byte_order.red_blue(col_r_1, col_b_1);
+    let (col_r_2, col_b_2) = byte_order.red_blue(col_r_2, col_b_2);
 
     let (y1_16, cb1_16, cr1_16) = r_g_b_lo_to_y16_u16_v16!(param, col_r_1, col_g_1, col_b_1);
     let (y2_16, cb2_16, cr2_16) = r_g_b_lo_to_y16_u16_v16!(param, col_r_2, col_g_2, col_b_2);
@@ -2496,6 +3526,8 @@ unsafe fn rgba_to_nv12_step(
 
     let (col_r_1, col_g_1, col_b_1, _alpha1, col_r_2, col_g_2, col_b_2, _alpha2) =
         rgb32_to_r_g_b_a!(rgba1, rgba2, rgba3, rgba4, rgba5, rgba6, rgba7, rgba8);
+    let (col_r_1, col_b_1) = byte_order.red_blue(col_r_1, col_b_1);
+    let (col_r_2, col_b_2) = byte_order.red_blue(col_r_2, col_b_2);
 
     /* first compute Y', (B-Y') and (R-Y'), in 16bits values, for the first line
       Y is saved for each pixel, while only sums of (B-Y') and (R-Y') for pairs of adjacents
@@ -2551,8 +3583,13 @@ unsafe fn rgba_to_nv12_step(
     let cb = _mm_packus_epi16(cb1_16, cb2_16);
     let cr = _mm_packus_epi16(cr1_16, cr2_16);
 
-    let cbcr1 = _mm_unpacklo_epi8(cb, cr);
-    let cbcr2 = _mm_unpackhi_epi8(cb, cr);
+    /* Interleave them in the requested order */
+    let (first, second) = match order {
+        ChromaOrder::Uv => (cb, cr),
+        ChromaOrder::Vu => (cr, cb),
+    };
+    let cbcr1 = _mm_unpacklo_epi8(first, second);
+    let cbcr2 = _mm_unpackhi_epi8(first, second);
 
     let uv_ptr = &mut buffer_uv[uv_index] as *mut u8 as *mut std::arch::x86_64::__m128i;
     _mm_storeu_si128(uv_ptr, cbcr1);
@@ -2573,10 +3610,91 @@ pub fn rgba_to_nv12_ssse3(
     y_stride: usize,
     uv_stride: usize,
     yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
 ) {
-    let param = get_rgb_to_yuv_param(yuv_type);
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_semi_planar(width, height, buffer_y, buffer_uv, y_stride, uv_stride),
+        Ok(())
+    );
+    rgba_to_semi_planar_ssse3(
+        width,
+        height,
+        buffer_rgba,
+        rgba_stride,
+        buffer_y,
+        buffer_uv,
+        y_stride,
+        uv_stride,
+        yuv_type,
+        color_range,
+        ChromaOrder::Uv,
+        byte_order,
+    )
+}
 
-    for y in (0..height - 1).step_by(2) {
+#[allow(clippy::too_many_arguments)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn rgba_to_nv21_ssse3(
+    width: usize,
+    height: usize,
+    buffer_rgba: &[u8],
+    rgba_stride: usize,
+    buffer_y: &mut [u8],
+    buffer_vu: &mut [u8],
+    y_stride: usize,
+    vu_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) {
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_semi_planar(width, height, buffer_y, buffer_vu, y_stride, vu_stride),
+        Ok(())
+    );
+    rgba_to_semi_planar_ssse3(
+        width,
+        height,
+        buffer_rgba,
+        rgba_stride,
+        buffer_y,
+        buffer_vu,
+        y_stride,
+        vu_stride,
+        yuv_type,
+        color_range,
+        ChromaOrder::Vu,
+        byte_order,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn rgba_to_semi_planar_ssse3(
+    width: usize,
+    height: usize,
+    buffer_rgba: &[u8],
+    rgba_stride: usize,
+    buffer_y: &mut [u8],
+    buffer_uv: &mut [u8],
+    y_stride: usize,
+    uv_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    order: ChromaOrder,
+    byte_order: ByteOrder,
+) {
+    let param = get_rgb_to_yuv_param(yuv_type, color_range);
+
+    for y in (0..height - height % 2).step_by(2) {
         let mut rgba_index1 = y * rgba_stride;
         let mut rgba_index2 = (y + 1) * rgba_stride;
 
@@ -2585,7 +3703,7 @@ pub fn rgba_to_nv12_ssse3(
 
         let mut uv_index = (y / 2) * uv_stride;
 
-        for _ in (0..width - 31).step_by(32) {
+        for _ in 0..width / 32 {
             unsafe {
                 rgba_to_nv12_step(
                     &param,
@@ -2597,6 +3715,8 @@ pub fn rgba_to_nv12_ssse3(
                     y_index1,
                     y_index2,
                     uv_index,
+                    order,
+                    byte_order,
                 );
             }
             rgba_index1 += 128;
@@ -2605,55 +3725,42 @@ pub fn rgba_to_nv12_ssse3(
             y_index2 += 32;
             uv_index += 32;
         }
+    }
 
-        // Complete image width
-        let cur_width = (width / 32) * 32;
-        for _ in (cur_width..width - 1).step_by(2) {
-            let mut y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index1] as u16
-                + param.g_factor as u16 * buffer_rgba[rgba_index1 + 1] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index1 + 2] as u16)
-                >> 8;
-            let mut u_tmp = buffer_rgba[rgba_index1 + 2] as u16 - y_tmp;
-            let mut v_tmp = buffer_rgba[rgba_index1] as u16 - y_tmp;
-            buffer_y[y_index1] =
-                (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
-
-            y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index1 + 4] as u16
-                + param.g_factor as u16 * buffer_rgba[rgba_index1 + 5] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index1 + 6] as u16)
-                >> 8;
-            u_tmp += buffer_rgba[rgba_index1 + 6] as u16 - y_tmp;
-            v_tmp += buffer_rgba[rgba_index1 + 4] as u16 - y_tmp;
-            buffer_y[y_index1 + 1] =
-                (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
-
-            y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index2] as u16
-                + param.g_factor as u16 * buffer_rgba[rgba_index2 + 1] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index2 + 2] as u16)
-                >> 8;
-            u_tmp += buffer_rgba[rgba_index2 + 2] as u16 - y_tmp;
-            v_tmp += buffer_rgba[rgba_index2] as u16 - y_tmp;
-            buffer_y[y_index2] =
-                (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
-
-            y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index2 + 4] as u16
-                + param.g_factor as u16 * buffer_rgba[rgba_index2 + 5] as u16
-                + param.b_factor as u16 * buffer_rgba[rgba_index2 + 6] as u16)
-                >> 8;
-            u_tmp += buffer_rgba[rgba_index2 + 6] as u16 - y_tmp;
-            v_tmp += buffer_rgba[rgba_index2 + 4] as u16 - y_tmp;
-            buffer_y[y_index2 + 1] =
-                (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
-
-            buffer_uv[uv_index] = ((((u_tmp >> 2) * param.cb_factor as u16) >> 8) + 128) as u8;
-            buffer_uv[uv_index + 1] = ((((v_tmp >> 2) * param.cb_factor as u16) >> 8) + 128) as u8;
-
-            rgba_index1 += 8;
-            rgba_index2 += 8;
-            y_index1 += 2;
-            y_index2 += 2;
-            uv_index += 2;
-        }
+    // Complete image width and the last row of odd height images
+    let cur_width = (width / 32) * 32;
+    if cur_width < width {
+        rgba_to_semi_planar_std(
+            width - cur_width,
+            height,
+            &buffer_rgba[cur_width * 4..],
+            rgba_stride,
+            &mut buffer_y[cur_width..],
+            &mut buffer_uv[cur_width..],
+            y_stride,
+            uv_stride,
+            yuv_type,
+            color_range,
+            order,
+            byte_order,
+        );
+    }
+    if height % 2 == 1 && cur_width > 0 {
+        let y = height - 1;
+        rgba_to_semi_planar_std(
+            cur_width,
+            1,
+            &buffer_rgba[y * rgba_stride..],
+            rgba_stride,
+            &mut buffer_y[y * y_stride..],
+            &mut buffer_uv[(y / 2) * uv_stride..],
+            y_stride,
+            uv_stride,
+            yuv_type,
+            color_range,
+            order,
+            byte_order,
+        );
     }
 }
 
@@ -2668,12 +3775,93 @@ pub fn nv12_rgba_std(
     buffer_rgba: &mut [u8],
     rgba_stride: usize,
     yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) {
+    debug_assert_eq!(
+        check_semi_planar(width, height, buffer_y, buffer_uv, y_stride, uv_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    semi_planar_rgba_std(
+        width,
+        height,
+        buffer_y,
+        buffer_uv,
+        y_stride,
+        uv_stride,
+        buffer_rgba,
+        rgba_stride,
+        yuv_type,
+        color_range,
+        ChromaOrder::Uv,
+        byte_order,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn nv21_rgba_std(
+    width: usize,
+    height: usize,
+    buffer_y: &[u8],
+    buffer_vu: &[u8],
+    y_stride: usize,
+    vu_stride: usize,
+    buffer_rgba: &mut [u8],
+    rgba_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) {
+    debug_assert_eq!(
+        check_semi_planar(width, height, buffer_y, buffer_vu, y_stride, vu_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    semi_planar_rgba_std(
+        width,
+        height,
+        buffer_y,
+        buffer_vu,
+        y_stride,
+        vu_stride,
+        buffer_rgba,
+        rgba_stride,
+        yuv_type,
+        color_range,
+        ChromaOrder::Vu,
+        byte_order,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn semi_planar_rgba_std(
+    width: usize,
+    height: usize,
+    buffer_y: &[u8],
+    buffer_uv: &[u8],
+    y_stride: usize,
+    uv_stride: usize,
+    buffer_rgba: &mut [u8],
+    rgba_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    order: ChromaOrder,
+    byte_order: ByteOrder,
 ) {
     debug!(
         "tttt {}x{} {} {} {}",
         width, height, y_stride, uv_stride, rgba_stride
     );
-    let param = get_yuv_to_rgb_param(yuv_type);
+    let param = get_yuv_to_rgb_param(yuv_type, color_range);
+    let (r_offset, b_offset) = byte_order.offsets();
+    let (u_offset, v_offset) = order.offsets();
     for y in (0..height - 1).step_by(2) {
         let mut rgba_index1 = y * rgba_stride;
         let mut rgba_index2 = (y + 1) * rgba_stride;
@@ -2682,8 +3870,8 @@ pub fn nv12_rgba_std(
         let mut y_index1 = y * y_stride;
         let mut y_index2 = (y + 1) * y_stride;
         for _ in (0..width - 1).step_by(2) {
-            let u_tmp = buffer_uv[uv_index] as i16 - 128;
-            let v_tmp = buffer_uv[uv_index + 1] as i16 - 128;
+            let u_tmp = buffer_uv[uv_index + u_offset] as i16 - 128;
+            let v_tmp = buffer_uv[uv_index + v_offset] as i16 - 128;
 
             let b_cb_offset = (param.cb_factor as i16 * u_tmp) >> 6;
             let r_cr_offset = (param.cr_factor as i16 * v_tmp) >> 6;
@@ -2692,29 +3880,29 @@ pub fn nv12_rgba_std(
 
             let y_tmp =
                 (param.y_factor as i16 * (buffer_y[y_index1] as i16 - param.y_offset as i16)) >> 7;
-            buffer_rgba[rgba_index1] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index1 + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index1 + 1] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index1 + 2] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index1 + b_offset] = clamp(y_tmp + b_cb_offset);
 
             let y_tmp = (param.y_factor as i16
                 * (buffer_y[y_index1 + 1] as i16 - param.y_offset as i16))
                 >> 7;
-            buffer_rgba[rgba_index1 + 4] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index1 + 4 + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index1 + 5] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index1 + 6] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index1 + 4 + b_offset] = clamp(y_tmp + b_cb_offset);
 
             let y_tmp =
                 (param.y_factor as i16 * (buffer_y[y_index2] as i16 - param.y_offset as i16)) >> 7;
-            buffer_rgba[rgba_index2] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index2 + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index2 + 1] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index2 + 2] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index2 + b_offset] = clamp(y_tmp + b_cb_offset);
 
             let y_tmp = (param.y_factor as i16
                 * (buffer_y[y_index2 + 1] as i16 - param.y_offset as i16))
                 >> 7;
-            buffer_rgba[rgba_index2 + 4] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index2 + 4 + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index2 + 5] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index2 + 6] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index2 + 4 + b_offset] = clamp(y_tmp + b_cb_offset);
 
             rgba_index1 += 8;
             rgba_index2 += 8;
@@ -2737,6 +3925,7 @@ unsafe fn nv12_to_rgba_step(
     y_index1: usize,
     y_index2: usize,
     uv_index: usize,
+    byte_order: ByteOrder,
 ) {
     let y_ptr1_0 = &buffer_y[y_index1] as *const u8 as *const std::arch::x86_64::__m128i;
     let y_ptr2_0 = &buffer_y[y_index2] as *const u8 as *const std::arch::x86_64::__m128i;
@@ -2867,9 +4056,11 @@ unsafe fn nv12_to_rgba_step(
     let rgb_ptr8 =
         &mut buffer_rgba[rgba_index1 + 112] as *mut u8 as *mut std::arch::x86_64::__m128i;
 
-    let a_8_11 = _mm_set1_epi16(0xFF);
-    let a_8_12 = _mm_set1_epi16(0xFF);
+    let a_8_11 = _mm_set1_epi8(-1);
+    let a_8_12 = _mm_set1_epi8(-1);
 
+    let (r_8_11, b_8_11) = byte_order.red_blue(r_8_11, b_8_11);
+    let (r_8_12, b_8_12) = byte_order.red_blue(r_8_12, b_8_12);
     let (rgb_1, rgb_2, rgb_3, rgb_4, rgb_5, rgb_6, rgb_7, rgb_8) =
         pack_r_g_b_a_to_rgb32!(r_8_11, r_8_12, g_8_11, g_8_12, b_8_11, b_8_12, a_8_11, a_8_12);
     _mm_storeu_si128(rgb_ptr1, rgb_1);
@@ -2891,9 +4082,11 @@ unsafe fn nv12_to_rgba_step(
     let rgb_ptr8 =
         &mut buffer_rgba[rgba_index2 + 112] as *mut u8 as *mut std::arch::x86_64::__m128i;
 
-    let a_8_21 = _mm_set1_epi16(0xFF);
-    let a_8_22 = _mm_set1_epi16(0xFF);
+    let a_8_21 = _mm_set1_epi8(-1);
+    let a_8_22 = _mm_set1_epi8(-1);
 
+    let (r_8_21, b_8_21) = byte_order.red_blue(r_8_21, b_8_21);
+    let (r_8_22, b_8_22) = byte_order.red_blue(r_8_22, b_8_22);
     let (rgb_1, rgb_2, rgb_3, rgb_4, rgb_5, rgb_6, rgb_7, rgb_8) =
         pack_r_g_b_a_to_rgb32!(r_8_21, r_8_22, g_8_21, g_8_22, b_8_21, b_8_22, a_8_21, a_8_22);
     _mm_storeu_si128(rgb_ptr1, rgb_1);
@@ -2918,8 +4111,19 @@ pub fn nv12_rgba_ssse3(
     buffer_rgba: &mut [u8],
     rgba_stride: usize,
     yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
 ) {
-    let param = get_yuv_to_rgb_param(yuv_type);
+    debug_assert_eq!(
+        check_semi_planar(width, height, buffer_y, buffer_uv, y_stride, uv_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    let param = get_yuv_to_rgb_param(yuv_type, color_range);
+    let (r_offset, b_offset) = byte_order.offsets();
     for y in (0..height - 1).step_by(2) {
         let mut rgba_index1 = y * rgba_stride;
         let mut rgba_index2 = (y + 1) * rgba_stride;
@@ -2929,7 +4133,7 @@ pub fn nv12_rgba_ssse3(
 
         let mut uv_index = (y / 2) * uv_stride;
 
-        for _ in (0..width - 31).step_by(32) {
+        for _ in 0..width / 32 {
             unsafe {
                 nv12_to_rgba_step(
                     &param,
@@ -2941,6 +4145,7 @@ pub fn nv12_rgba_ssse3(
                     y_index1,
                     y_index2,
                     uv_index,
+                    byte_order,
                 );
             }
             rgba_index1 += 128;
@@ -2962,29 +4167,29 @@ pub fn nv12_rgba_ssse3(
 
             let y_tmp =
                 (param.y_factor as i16 * (buffer_y[y_index1] as i16 - param.y_offset as i16)) >> 7;
-            buffer_rgba[rgba_index1] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index1 + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index1 + 1] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index1 + 2] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index1 + b_offset] = clamp(y_tmp + b_cb_offset);
 
             let y_tmp = (param.y_factor as i16
                 * (buffer_y[y_index1 + 1] as i16 - param.y_offset as i16))
                 >> 7;
-            buffer_rgba[rgba_index1 + 4] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index1 + 4 + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index1 + 5] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index1 + 6] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index1 + 4 + b_offset] = clamp(y_tmp + b_cb_offset);
 
             let y_tmp =
                 (param.y_factor as i16 * (buffer_y[y_index2] as i16 - param.y_offset as i16)) >> 7;
-            buffer_rgba[rgba_index2] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index2 + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index2 + 1] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index2 + 2] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index2 + b_offset] = clamp(y_tmp + b_cb_offset);
 
             let y_tmp = (param.y_factor as i16
                 * (buffer_y[y_index2 + 1] as i16 - param.y_offset as i16))
                 >> 7;
-            buffer_rgba[rgba_index2 + 4] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index2 + 4 + r_offset] = clamp(y_tmp + r_cr_offset);
             buffer_rgba[rgba_index2 + 5] = clamp(y_tmp - g_cbcr_offset);
-            buffer_rgba[rgba_index2 + 6] = clamp(y_tmp + b_cb_offset);
+            buffer_rgba[rgba_index2 + 4 + b_offset] = clamp(y_tmp + b_cb_offset);
 
             rgba_index1 += 8;
             rgba_index2 += 8;
@@ -2995,7 +4200,12 @@ pub fn nv12_rgba_ssse3(
     }
 }
 
-#[allow(clippy::explicit_counter_loop)]
+/// Convert a rgba image to yuv420, using the scalar kernel on row pairs
+/// processed in parallel
+///
+/// As in `rgba_to_yuv420_ssse3_rayon`, each rayon task owns the rows of its
+/// row pair, which also keeps the chroma of odd sized images consistent with
+/// `rgba_to_yuv420_std`.
 #[allow(clippy::too_many_arguments)]
 pub fn rgba_to_yuv420_std_rayon(
     width: usize,
@@ -3009,100 +4219,42 @@ pub fn rgba_to_yuv420_std_rayon(
     u_stride: usize,
     v_stride: usize,
     yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
 ) {
-    let buffer_y_raw = buffer_y.as_mut_ptr();
-    let buffer_u_raw = buffer_u.as_mut_ptr();
-    let buffer_v_raw = buffer_v.as_mut_ptr();
-
-    let slice_y_len = y_stride;
-    let slice_u_len = u_stride;
-    let slice_v_len = v_stride;
-
-    let mut slices = vec![];
-
-    for y in (0..height - 1).step_by(2) {
-        let y_index1 = y * y_stride;
-        let y_index2 = (y + 1) * y_stride;
-
-        let u_index = (y / 2) * u_stride;
-        let v_index = (y / 2) * v_stride;
-
-        let cur_y_ptr_1 = unsafe { buffer_y_raw.add(y_index1) };
-        let cur_y_ptr_2 = unsafe { buffer_y_raw.add(y_index2) };
-        let cur_y_slice_1 = unsafe { std::slice::from_raw_parts_mut(cur_y_ptr_1, slice_y_len) };
-        let cur_y_slice_2 = unsafe { std::slice::from_raw_parts_mut(cur_y_ptr_2, slice_y_len) };
-
-        let cur_u_ptr = unsafe { buffer_u_raw.add(u_index) };
-        let cur_v_ptr = unsafe { buffer_v_raw.add(v_index) };
-        let cur_u_slice = unsafe { std::slice::from_raw_parts_mut(cur_u_ptr, slice_u_len) };
-        let cur_v_slice = unsafe { std::slice::from_raw_parts_mut(cur_v_ptr, slice_v_len) };
-
-        slices.push((y, cur_y_slice_1, cur_y_slice_2, cur_u_slice, cur_v_slice));
-    }
-
-    let param = get_rgb_to_yuv_param(yuv_type);
-    slices.par_iter_mut().for_each(
-        |(y, cur_y_slice_1, cur_y_slice_2, cur_u_slice, cur_v_slice)| {
-            let mut y_index1 = 0;
-            let mut y_index2 = 0;
-
-            let mut rgba_index1 = *y * rgba_stride;
-            let mut rgba_index2 = (*y + 1) * rgba_stride;
-
-            let mut u_index = 0;
-            let mut v_index = 0;
-
-            for _ in (0..width - 1).step_by(2) {
-                // compute yuv for the four pixels, u and v values are summed
-                let mut y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index1] as u16
-                    + param.g_factor as u16 * buffer_rgba[rgba_index1 + 1] as u16
-                    + param.b_factor as u16 * buffer_rgba[rgba_index1 + 2] as u16)
-                    >> 8;
-                let mut u_tmp = buffer_rgba[rgba_index1 + 2] as u16 - y_tmp;
-                let mut v_tmp = buffer_rgba[rgba_index1] as u16 - y_tmp;
-                cur_y_slice_1[y_index1] =
-                    (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
-
-                y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index1 + 4] as u16
-                    + param.g_factor as u16 * buffer_rgba[rgba_index1 + 5] as u16
-                    + param.b_factor as u16 * buffer_rgba[rgba_index1 + 6] as u16)
-                    >> 8;
-                u_tmp += buffer_rgba[rgba_index1 + 6] as u16 - y_tmp;
-                v_tmp += buffer_rgba[rgba_index1 + 4] as u16 - y_tmp;
-                cur_y_slice_1[y_index1 + 1] =
-                    (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
-
-                y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index2] as u16
-                    + param.g_factor as u16 * buffer_rgba[rgba_index2 + 1] as u16
-                    + param.b_factor as u16 * buffer_rgba[rgba_index2 + 2] as u16)
-                    >> 8;
-                u_tmp += buffer_rgba[rgba_index2 + 2] as u16 - y_tmp;
-                v_tmp += buffer_rgba[rgba_index2] as u16 - y_tmp;
-                cur_y_slice_2[y_index2] =
-                    (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
-
-                y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index2 + 4] as u16
-                    + param.g_factor as u16 * buffer_rgba[rgba_index2 + 5] as u16
-                    + param.b_factor as u16 * buffer_rgba[rgba_index2 + 6] as u16)
-                    >> 8;
-                u_tmp += buffer_rgba[rgba_index2 + 6] as u16 - y_tmp;
-                v_tmp += buffer_rgba[rgba_index2 + 4] as u16 - y_tmp;
-                cur_y_slice_2[y_index2 + 1] =
-                    (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
-
-                cur_u_slice[u_index] = ((((u_tmp >> 2) * param.cb_factor as u16) >> 8) + 128) as u8;
-                cur_v_slice[v_index] = ((((v_tmp >> 2) * param.cb_factor as u16) >> 8) + 128) as u8;
-
-                rgba_index1 += 8;
-                rgba_index2 += 8;
-                y_index1 += 2;
-                y_index2 += 2;
-                u_index += 1;
-                v_index += 1;
-            }
-        },
-    )
-}
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_yuv420(width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride),
+        Ok(())
+    );
+    buffer_rgba
+        .par_chunks(2 * rgba_stride)
+        .zip(buffer_y.par_chunks_mut(2 * y_stride))
+        .zip(buffer_u.par_chunks_mut(u_stride))
+        .zip(buffer_v.par_chunks_mut(v_stride))
+        .take(height.div_ceil(2))
+        .enumerate()
+        .for_each(|(pair, (((rows_rgba, rows_y), row_u), row_v))| {
+            rgba_to_yuv420_std(
+                width,
+                (height - 2 * pair).min(2),
+                rows_rgba,
+                rgba_stride,
+                rows_y,
+                row_u,
+                row_v,
+                y_stride,
+                u_stride,
+                v_stride,
+                yuv_type,
+                color_range,
+                byte_order,
+            );
+        });
+}
 
 #[allow(clippy::explicit_counter_loop)]
 #[allow(clippy::too_many_arguments)]
@@ -3118,7 +4270,17 @@ pub fn rgba_to_yuv444_std_rayon(
     u_stride: usize,
     v_stride: usize,
     yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
 ) {
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_yuv444(width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride),
+        Ok(())
+    );
     let buffer_y_raw = buffer_y.as_mut_ptr();
     let buffer_u_raw = buffer_u.as_mut_ptr();
     let buffer_v_raw = buffer_v.as_mut_ptr();
@@ -3146,7 +4308,9 @@ pub fn rgba_to_yuv444_std_rayon(
         slices.push((y, cur_y_slice, cur_u_slice, cur_v_slice));
     }
 
-    let param = get_rgb_to_yuv_param(yuv_type);
+    let param = get_rgb_to_yuv_param(yuv_type, color_range);
+
+    let (r_offset, b_offset) = byte_order.offsets();
     slices
         .par_iter_mut()
         .for_each(|(y, cur_y_slice, cur_u_slice, cur_v_slice)| {
@@ -3158,17 +4322,17 @@ pub fn rgba_to_yuv444_std_rayon(
             let mut v_index = 0;
             for _ in 0..width {
                 // compute yuv for the four pixels, u and v values are summed
-                let y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index] as u16
+                let y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index + r_offset] as u16
                     + param.g_factor as u16 * buffer_rgba[rgba_index + 1] as u16
-                    + param.b_factor as u16 * buffer_rgba[rgba_index + 2] as u16)
+                    + param.b_factor as u16 * buffer_rgba[rgba_index + b_offset] as u16)
                     >> 8;
-                let u_tmp = buffer_rgba[rgba_index + 2] as u16 - y_tmp;
-                let v_tmp = buffer_rgba[rgba_index] as u16 - y_tmp;
+                let u_tmp = buffer_rgba[rgba_index + b_offset] as i16 - y_tmp as i16;
+                let v_tmp = buffer_rgba[rgba_index + r_offset] as i16 - y_tmp as i16;
                 cur_y_slice[y_index1] =
                     (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
 
-                cur_u_slice[u_index] = (((u_tmp * param.cb_factor as u16) >> 8) + 128) as u8;
-                cur_v_slice[v_index] = (((v_tmp * param.cb_factor as u16) >> 8) + 128) as u8;
+                cur_u_slice[u_index] = clamp(((u_tmp * param.cb_factor as i16) >> 8) + 128);
+                cur_v_slice[v_index] = clamp(((v_tmp * param.cr_factor as i16) >> 8) + 128);
 
                 rgba_index += 4;
                 y_index1 += 1;
@@ -3177,3 +4341,1738 @@ pub fn rgba_to_yuv444_std_rayon(
             }
         })
 }
+
+/// Convert a packed yuv 4:2:2 (yuyv) image to rgba
+///
+/// Each pair of pixels is stored as `y0 u y1 v`, so a row holds at least
+/// `width * 2` bytes. Alpha is left untouched.
+#[allow(clippy::too_many_arguments)]
+pub fn yuyv_to_rgba_std(
+    width: usize,
+    height: usize,
+    buffer_yuyv: &[u8],
+    yuyv_stride: usize,
+    buffer_rgba: &mut [u8],
+    rgba_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) {
+    debug_assert_eq!(
+        check_plane(
+            "yuyv",
+            buffer_yuyv,
+            height,
+            width.div_ceil(2) * 4,
+            yuyv_stride
+        ),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    let param = get_yuv_to_rgb_param(yuv_type, color_range);
+    let (r_offset, b_offset) = byte_order.offsets();
+    for y in 0..height {
+        let mut yuyv_index = y * yuyv_stride;
+        let mut rgba_index = y * rgba_stride;
+        for x in (0..width).step_by(2) {
+            let u_tmp = buffer_yuyv[yuyv_index + 1] as i16 - 128;
+            let v_tmp = buffer_yuyv[yuyv_index + 3] as i16 - 128;
+
+            let b_cb_offset = (param.cb_factor as i16 * u_tmp) >> 6;
+            let r_cr_offset = (param.cr_factor as i16 * v_tmp) >> 6;
+            let g_cbcr_offset =
+                (param.g_cb_factor as i16 * u_tmp + param.g_cr_factor as i16 * v_tmp) >> 7;
+
+            let y_tmp = (param.y_factor as i16
+                * (buffer_yuyv[yuyv_index] as i16 - param.y_offset as i16))
+                >> 7;
+            buffer_rgba[rgba_index + r_offset] = clamp(y_tmp + r_cr_offset);
+            buffer_rgba[rgba_index + 1] = clamp(y_tmp - g_cbcr_offset);
+            buffer_rgba[rgba_index + b_offset] = clamp(y_tmp + b_cb_offset);
+
+            // The last column of an odd width image only uses the first sample
+            if x + 1 < width {
+                let y_tmp = (param.y_factor as i16
+                    * (buffer_yuyv[yuyv_index + 2] as i16 - param.y_offset as i16))
+                    >> 7;
+                buffer_rgba[rgba_index + 4 + r_offset] = clamp(y_tmp + r_cr_offset);
+                buffer_rgba[rgba_index + 5] = clamp(y_tmp - g_cbcr_offset);
+                buffer_rgba[rgba_index + 4 + b_offset] = clamp(y_tmp + b_cb_offset);
+            }
+
+            yuyv_index += 4;
+            rgba_index += 8;
+        }
+    }
+}
+
+/// Convert a rgba image to packed yuv 4:2:2 (yuyv)
+#[allow(clippy::too_many_arguments)]
+pub fn rgba_to_yuyv_std(
+    width: usize,
+    height: usize,
+    buffer_rgba: &[u8],
+    rgba_stride: usize,
+    buffer_yuyv: &mut [u8],
+    yuyv_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) {
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_plane(
+            "yuyv",
+            buffer_yuyv,
+            height,
+            width.div_ceil(2) * 4,
+            yuyv_stride
+        ),
+        Ok(())
+    );
+    let param = get_rgb_to_yuv_param(yuv_type, color_range);
+    let (r_offset, b_offset) = byte_order.offsets();
+    for y in 0..height {
+        let mut rgba_index = y * rgba_stride;
+        let mut yuyv_index = y * yuyv_stride;
+        for x in (0..width).step_by(2) {
+            // The last column of an odd width image is sampled alone
+            let next_col = if x + 1 < width { 1 } else { 0 };
+            // compute yuv for the two pixels, u and v values are summed
+            let mut y_tmp = (param.r_factor as u16 * buffer_rgba[rgba_index + r_offset] as u16
+                + param.g_factor as u16 * buffer_rgba[rgba_index + 1] as u16
+                + param.b_factor as u16 * buffer_rgba[rgba_index + b_offset] as u16)
+                >> 8;
+            let mut u_tmp = buffer_rgba[rgba_index + b_offset] as i16 - y_tmp as i16;
+            let mut v_tmp = buffer_rgba[rgba_index + r_offset] as i16 - y_tmp as i16;
+            buffer_yuyv[yuyv_index] =
+                (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
+
+            y_tmp = (param.r_factor as u16
+                * buffer_rgba[rgba_index + next_col * 4 + r_offset] as u16
+                + param.g_factor as u16 * buffer_rgba[rgba_index + next_col * 4 + 1] as u16
+                + param.b_factor as u16 * buffer_rgba[rgba_index + next_col * 4 + b_offset] as u16)
+                >> 8;
+            u_tmp += buffer_rgba[rgba_index + next_col * 4 + b_offset] as i16 - y_tmp as i16;
+            v_tmp += buffer_rgba[rgba_index + next_col * 4 + r_offset] as i16 - y_tmp as i16;
+            buffer_yuyv[yuyv_index + 2] =
+                (((y_tmp * param.y_factor as u16) >> 7) + param.y_offset as u16) as u8;
+
+            buffer_yuyv[yuyv_index + 1] =
+                clamp((((u_tmp >> 1) * param.cb_factor as i16) >> 8) + 128);
+            buffer_yuyv[yuyv_index + 3] =
+                clamp((((v_tmp >> 1) * param.cr_factor as i16) >> 8) + 128);
+
+            rgba_index += 8;
+            yuyv_index += 4;
+        }
+    }
+}
+
+/// Repack a packed yuv 4:2:2 (yuyv) image to planar yuv420
+///
+/// Luma samples are copied and the chroma samples of each pair of rows are
+/// averaged: no color conversion is involved, so the output keeps the color
+/// matrix and range of the input.
+#[allow(clippy::too_many_arguments)]
+pub fn yuyv_to_yuv420_std(
+    width: usize,
+    height: usize,
+    buffer_yuyv: &[u8],
+    yuyv_stride: usize,
+    buffer_y: &mut [u8],
+    buffer_u: &mut [u8],
+    buffer_v: &mut [u8],
+    y_stride: usize,
+    u_stride: usize,
+    v_stride: usize,
+) {
+    debug_assert_eq!(
+        check_plane(
+            "yuyv",
+            buffer_yuyv,
+            height,
+            width.div_ceil(2) * 4,
+            yuyv_stride
+        ),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_yuv420(width, height, buffer_y, buffer_u, buffer_v, y_stride, u_stride, v_stride),
+        Ok(())
+    );
+    for y in (0..height).step_by(2) {
+        // The last row of an odd height image is sampled alone
+        let next_row = if y + 1 < height { 1 } else { 0 };
+        let mut yuyv_index1 = y * yuyv_stride;
+        let mut yuyv_index2 = (y + next_row) * yuyv_stride;
+
+        let mut y_index1 = y * y_stride;
+        let mut y_index2 = (y + next_row) * y_stride;
+        for x in (0..width).step_by(2) {
+            let next_col = if x + 1 < width { 1 } else { 0 };
+            let u_index = (y / 2) * u_stride + x / 2;
+            let v_index = (y / 2) * v_stride + x / 2;
+            buffer_y[y_index1] = buffer_yuyv[yuyv_index1];
+            buffer_y[y_index1 + next_col] = buffer_yuyv[yuyv_index1 + next_col * 2];
+            buffer_y[y_index2] = buffer_yuyv[yuyv_index2];
+            buffer_y[y_index2 + next_col] = buffer_yuyv[yuyv_index2 + next_col * 2];
+
+            buffer_u[u_index] =
+                ((buffer_yuyv[yuyv_index1 + 1] as u16 + buffer_yuyv[yuyv_index2 + 1] as u16 + 1)
+                    >> 1) as u8;
+            buffer_v[v_index] =
+                ((buffer_yuyv[yuyv_index1 + 3] as u16 + buffer_yuyv[yuyv_index2 + 3] as u16 + 1)
+                    >> 1) as u8;
+
+            yuyv_index1 += 4;
+            yuyv_index2 += 4;
+            y_index1 += 2;
+            y_index2 += 2;
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+unsafe fn yuyv_to_rgba_step(
+    param: &YuvToRgbParam,
+    buffer_yuyv: &[u8],
+    buffer_rgba: &mut [u8],
+    yuyv_index: usize,
+    rgba_index: usize,
+    byte_order: ByteOrder,
+) {
+    let yuyv_ptr1 = &buffer_yuyv[yuyv_index] as *const u8 as *const __m128i;
+    let yuyv_ptr2 = &buffer_yuyv[yuyv_index + 16] as *const u8 as *const __m128i;
+    let yuyv_ptr3 = &buffer_yuyv[yuyv_index + 32] as *const u8 as *const __m128i;
+    let yuyv_ptr4 = &buffer_yuyv[yuyv_index + 48] as *const u8 as *const __m128i;
+
+    let yuyv_1 = _mm_loadu_si128(yuyv_ptr1);
+    let yuyv_2 = _mm_loadu_si128(yuyv_ptr2);
+    let yuyv_3 = _mm_loadu_si128(yuyv_ptr3);
+    let yuyv_4 = _mm_loadu_si128(yuyv_ptr4);
+
+    /* split luma (even bytes) and chroma (odd bytes) samples */
+    let mask = _mm_set1_epi16(0xFF);
+    let y_1 = _mm_packus_epi16(_mm_and_si128(yuyv_1, mask), _mm_and_si128(yuyv_2, mask));
+    let y_2 = _mm_packus_epi16(_mm_and_si128(yuyv_3, mask), _mm_and_si128(yuyv_4, mask));
+    let uv_1 = _mm_packus_epi16(_mm_srli_epi16(yuyv_1, 8), _mm_srli_epi16(yuyv_2, 8));
+    let uv_2 = _mm_packus_epi16(_mm_srli_epi16(yuyv_3, 8), _mm_srli_epi16(yuyv_4, 8));
+    let u = _mm_packus_epi16(_mm_and_si128(uv_1, mask), _mm_and_si128(uv_2, mask));
+    let v = _mm_packus_epi16(_mm_srli_epi16(uv_1, 8), _mm_srli_epi16(uv_2, 8));
+
+    let u = _mm_add_epi8(u, _mm_set1_epi8(-128));
+    let v = _mm_add_epi8(v, _mm_set1_epi8(-128));
+
+    /* process first 16 pixels */
+    let u_16 = _mm_srai_epi16(_mm_unpacklo_epi8(u, u), 8);
+    let v_16 = _mm_srai_epi16(_mm_unpacklo_epi8(v, v), 8);
+
+    let (r_16_1, g_16_1, b_16_1, r_16_2, g_16_2, b_16_2) = uv_to_rgb_16(param, u_16, v_16);
+
+    let y = _mm_sub_epi8(y_1, _mm_set1_epi8(param.y_offset as i8));
+    let y_16_1 = _mm_unpacklo_epi8(y, _mm_setzero_si128());
+    let y_16_2 = _mm_unpackhi_epi8(y, _mm_setzero_si128());
+
+    let (r_16_1, g_16_1, b_16_1, r_16_2, g_16_2, b_16_2) = add_y_to_rgb_16(
+        param, y_16_1, y_16_2, r_16_1, g_16_1, b_16_1, r_16_2, g_16_2, b_16_2,
+    );
+
+    let r_8_1 = _mm_packus_epi16(r_16_1, r_16_2);
+    let g_8_1 = _mm_packus_epi16(g_16_1, g_16_2);
+    let b_8_1 = _mm_packus_epi16(b_16_1, b_16_2);
+
+    /* process last 16 pixels */
+    let u_16 = _mm_srai_epi16(_mm_unpackhi_epi8(u, u), 8);
+    let v_16 = _mm_srai_epi16(_mm_unpackhi_epi8(v, v), 8);
+
+    let (r_16_1, g_16_1, b_16_1, r_16_2, g_16_2, b_16_2) = uv_to_rgb_16(param, u_16, v_16);
+
+    let y = _mm_sub_epi8(y_2, _mm_set1_epi8(param.y_offset as i8));
+    let y_16_1 = _mm_unpacklo_epi8(y, _mm_setzero_si128());
+    let y_16_2 = _mm_unpackhi_epi8(y, _mm_setzero_si128());
+
+    let (r_16_1, g_16_1, b_16_1, r_16_2, g_16_2, b_16_2) = add_y_to_rgb_16(
+        param, y_16_1, y_16_2, r_16_1, g_16_1, b_16_1, r_16_2, g_16_2, b_16_2,
+    );
+
+    let r_8_2 = _mm_packus_epi16(r_16_1, r_16_2);
+    let g_8_2 = _mm_packus_epi16(g_16_1, g_16_2);
+    let b_8_2 = _mm_packus_epi16(b_16_1, b_16_2);
+
+    let rgba_ptr1 = &mut buffer_rgba[rgba_index] as *mut u8 as *mut __m128i;
+    let rgba_ptr2 = &mut buffer_rgba[rgba_index + 16] as *mut u8 as *mut __m128i;
+    let rgba_ptr3 = &mut buffer_rgba[rgba_index + 32] as *mut u8 as *mut __m128i;
+    let rgba_ptr4 = &mut buffer_rgba[rgba_index + 48] as *mut u8 as *mut __m128i;
+    let rgba_ptr5 = &mut buffer_rgba[rgba_index + 64] as *mut u8 as *mut __m128i;
+    let rgba_ptr6 = &mut buffer_rgba[rgba_index + 80] as *mut u8 as *mut __m128i;
+    let rgba_ptr7 = &mut buffer_rgba[rgba_index + 96] as *mut u8 as *mut __m128i;
+    let rgba_ptr8 = &mut buffer_rgba[rgba_index + 112] as *mut u8 as *mut __m128i;
+
+    let a_8_1 = _mm_set1_epi8(-1);
+    let a_8_2 = _mm_set1_epi8(-1);
+
+    let (r_8_1, b_8_1) = byte_order.red_blue(r_8_1, b_8_1);
+    let (r_8_2, b_8_2) = byte_order.red_blue(r_8_2, b_8_2);
+    let (rgba_1, rgba_2, rgba_3, rgba_4, rgba_5, rgba_6, rgba_7, rgba_8) =
+        pack_r_g_b_a_to_rgb32!(r_8_1, r_8_2, g_8_1, g_8_2, b_8_1, b_8_2, a_8_1, a_8_2);
+    _mm_storeu_si128(rgba_ptr1, rgba_1);
+    _mm_storeu_si128(rgba_ptr2, rgba_2);
+    _mm_storeu_si128(rgba_ptr3, rgba_3);
+    _mm_storeu_si128(rgba_ptr4, rgba_4);
+    _mm_storeu_si128(rgba_ptr5, rgba_5);
+    _mm_storeu_si128(rgba_ptr6, rgba_6);
+    _mm_storeu_si128(rgba_ptr7, rgba_7);
+    _mm_storeu_si128(rgba_ptr8, rgba_8);
+}
+
+/// Convert a packed yuv 4:2:2 (yuyv) image to rgba
+///
+/// Rows are processed by blocks of 32 pixels, which are written with an
+/// opaque alpha. The remaining pixels of each row go through the scalar
+/// version.
+#[allow(clippy::too_many_arguments)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn yuyv_to_rgba_ssse3(
+    width: usize,
+    height: usize,
+    buffer_yuyv: &[u8],
+    yuyv_stride: usize,
+    buffer_rgba: &mut [u8],
+    rgba_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) {
+    debug_assert_eq!(
+        check_plane(
+            "yuyv",
+            buffer_yuyv,
+            height,
+            width.div_ceil(2) * 4,
+            yuyv_stride
+        ),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    let param = get_yuv_to_rgb_param(yuv_type, color_range);
+    let cur_width = (width / 32) * 32;
+    for y in 0..height {
+        let mut yuyv_index = y * yuyv_stride;
+        let mut rgba_index = y * rgba_stride;
+        for _ in (0..cur_width).step_by(32) {
+            unsafe {
+                yuyv_to_rgba_step(
+                    &param,
+                    buffer_yuyv,
+                    buffer_rgba,
+                    yuyv_index,
+                    rgba_index,
+                    byte_order,
+                );
+            }
+            yuyv_index += 64;
+            rgba_index += 128;
+        }
+
+        // Complete image width
+        if cur_width < width {
+            yuyv_to_rgba_std(
+                width - cur_width,
+                1,
+                &buffer_yuyv[yuyv_index..],
+                yuyv_stride,
+                &mut buffer_rgba[rgba_index..],
+                rgba_stride,
+                yuv_type,
+                color_range,
+                byte_order,
+            );
+        }
+    }
+}
+
+/// Rgb to yuv factors for 10 bits samples, 16 bits fixed point
+struct Rgb10ToYuvParam {
+    r_factor: i64,
+    g_factor: i64,
+    b_factor: i64,
+    cb_factor: i64,
+    cr_factor: i64,
+    y_factor: i64,
+    y_offset: i64,
+}
+
+/// Yuv to rgb factors for 10 bits samples, 16 bits fixed point
+struct Yuv10ToRgbParam {
+    cb_factor: i64,
+    cr_factor: i64,
+    g_cb_factor: i64,
+    g_cr_factor: i64,
+    y_factor: i64,
+    y_offset: i64,
+}
+
+fn f32_to_fixed_point_16(value: f32) -> i64 {
+    (value as f64 * 65536.0).round() as i64
+}
+
+fn get_rgb10_to_yuv_param(param: YuvType, range: ColorRange) -> Rgb10ToYuvParam {
+    let (rf, bf) = param.coefficients();
+    let (ymin, ymax, cbcrrange) = range.bounds_10bit();
+    let r_factor = f32_to_fixed_point_16(rf);
+    let b_factor = f32_to_fixed_point_16(bf);
+    Rgb10ToYuvParam {
+        r_factor,
+        g_factor: 65536 - r_factor - b_factor,
+        b_factor,
+        cb_factor: f32_to_fixed_point_16((cbcrrange / 1023.0) / (2.0 * (1.0 - bf))),
+        cr_factor: f32_to_fixed_point_16((cbcrrange / 1023.0) / (2.0 * (1.0 - rf))),
+        y_factor: f32_to_fixed_point_16((ymax - ymin) / 1023.0),
+        y_offset: ymin as i64,
+    }
+}
+
+fn get_yuv10_to_rgb_param(param: YuvType, range: ColorRange) -> Yuv10ToRgbParam {
+    let (rf, bf) = param.coefficients();
+    let (ymin, ymax, cbcrrange) = range.bounds_10bit();
+    Yuv10ToRgbParam {
+        cb_factor: f32_to_fixed_point_16(1023.0 * (2.0 * (1.0 - bf)) / cbcrrange),
+        cr_factor: f32_to_fixed_point_16(1023.0 * (2.0 * (1.0 - rf)) / cbcrrange),
+        g_cb_factor: f32_to_fixed_point_16(
+            bf / (1.0 - bf - rf) * 1023.0 * (2.0 * (1.0 - bf)) / cbcrrange,
+        ),
+        g_cr_factor: f32_to_fixed_point_16(
+            rf / (1.0 - bf - rf) * 1023.0 * (2.0 * (1.0 - rf)) / cbcrrange,
+        ),
+        y_factor: f32_to_fixed_point_16(1023.0 / (ymax - ymin)),
+        y_offset: ymin as i64,
+    }
+}
+
+fn clamp_10bit(value: i64) -> u16 {
+    value.clamp(0, 1023) as u16
+}
+
+/// Expand a 8 bits rgba image to 10 bits samples
+///
+/// Strides of the 10 bits image are counted in samples.
+pub fn rgba_to_rgba10_std(
+    width: usize,
+    height: usize,
+    buffer_rgba: &[u8],
+    rgba_stride: usize,
+    buffer_rgba10: &mut [u16],
+    rgba10_stride: usize,
+) {
+    debug_assert_eq!(
+        check_plane("rgba", buffer_rgba, height, width * 4, rgba_stride),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_plane("rgba10", buffer_rgba10, height, width * 4, rgba10_stride),
+        Ok(())
+    );
+    for y in 0..height {
+        let src = &buffer_rgba[y * rgba_stride..][..width * 4];
+        let dst = &mut buffer_rgba10[y * rgba10_stride..][..width * 4];
+        for (dst, &src) in dst.iter_mut().zip(src.iter()) {
+            // Replicate the high bits so that 0xFF maps to 0x3FF
+            *dst = ((src as u16) << 2) | ((src as u16) >> 6);
+        }
+    }
+}
+
+/// Convert a 10 bits rgba image to p010
+///
+/// The source holds 10 bits samples in the low bits of each word. P010 is a
+/// semi planar yuv420 format storing 10 bits samples in the high bits of 16
+/// bits words. Strides are counted in samples.
+#[allow(clippy::too_many_arguments)]
+pub fn rgba10_to_p010_std(
+    width: usize,
+    height: usize,
+    buffer_rgba10: &[u16],
+    rgba10_stride: usize,
+    buffer_y: &mut [u16],
+    buffer_uv: &mut [u16],
+    y_stride: usize,
+    uv_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) {
+    debug_assert_eq!(
+        check_plane("rgba10", buffer_rgba10, height, width * 4, rgba10_stride),
+        Ok(())
+    );
+    debug_assert_eq!(check_plane("y", buffer_y, height, width, y_stride), Ok(()));
+    debug_assert_eq!(
+        check_plane(
+            "uv",
+            buffer_uv,
+            height.div_ceil(2),
+            width.div_ceil(2) * 2,
+            uv_stride
+        ),
+        Ok(())
+    );
+    let param = get_rgb10_to_yuv_param(yuv_type, color_range);
+    let (r_offset, b_offset) = byte_order.offsets();
+    for y in (0..height).step_by(2) {
+        // The last row of an odd height image is sampled alone
+        let next_row = if y + 1 < height { 1 } else { 0 };
+        for x in (0..width).step_by(2) {
+            // The last column of an odd width image is sampled alone
+            let next_col = if x + 1 < width { 1 } else { 0 };
+            // compute yuv for the four pixels, u and v differences are summed
+            let mut u_tmp = 0;
+            let mut v_tmp = 0;
+            for (row, col) in [(0, 0), (0, next_col), (next_row, 0), (next_row, next_col)] {
+                let rgba_index = (y + row) * rgba10_stride + (x + col) * 4;
+                let r = buffer_rgba10[rgba_index + r_offset] as i64;
+                let g = buffer_rgba10[rgba_index + 1] as i64;
+                let b = buffer_rgba10[rgba_index + b_offset] as i64;
+                let y_tmp = param.r_factor * r + param.g_factor * g + param.b_factor * b;
+                u_tmp += (b << 16) - y_tmp;
+                v_tmp += (r << 16) - y_tmp;
+                let luma = ((y_tmp * param.y_factor + (1 << 31)) >> 32) + param.y_offset;
+                buffer_y[(y + row) * y_stride + x + col] = clamp_10bit(luma) << 6;
+            }
+            let uv_index = (y / 2) * uv_stride + x;
+            let u = ((u_tmp * param.cb_factor + (1 << 33)) >> 34) + 512;
+            let v = ((v_tmp * param.cr_factor + (1 << 33)) >> 34) + 512;
+            buffer_uv[uv_index] = clamp_10bit(u) << 6;
+            buffer_uv[uv_index + 1] = clamp_10bit(v) << 6;
+        }
+    }
+}
+
+/// Convert a p010 image to 10 bits rgba
+///
+/// Alpha is left untouched. Strides are counted in samples.
+#[allow(clippy::too_many_arguments)]
+pub fn p010_to_rgba10_std(
+    width: usize,
+    height: usize,
+    buffer_y: &[u16],
+    buffer_uv: &[u16],
+    y_stride: usize,
+    uv_stride: usize,
+    buffer_rgba10: &mut [u16],
+    rgba10_stride: usize,
+    yuv_type: YuvType,
+    color_range: ColorRange,
+    byte_order: ByteOrder,
+) {
+    debug_assert_eq!(check_plane("y", buffer_y, height, width, y_stride), Ok(()));
+    debug_assert_eq!(
+        check_plane(
+            "uv",
+            buffer_uv,
+            height.div_ceil(2),
+            width.div_ceil(2) * 2,
+            uv_stride
+        ),
+        Ok(())
+    );
+    debug_assert_eq!(
+        check_plane("rgba10", buffer_rgba10, height, width * 4, rgba10_stride),
+        Ok(())
+    );
+    let param = get_yuv10_to_rgb_param(yuv_type, color_range);
+    let (r_offset, b_offset) = byte_order.offsets();
+    for y in 0..height {
+        for x in 0..width {
+            let uv_index = (y / 2) * uv_stride + (x / 2) * 2;
+            let u_tmp = (buffer_uv[uv_index] >> 6) as i64 - 512;
+            let v_tmp = (buffer_uv[uv_index + 1] >> 6) as i64 - 512;
+            let y_tmp =
+                ((buffer_y[y * y_stride + x] >> 6) as i64 - param.y_offset) * param.y_factor;
+
+            let rgba_index = y * rgba10_stride + x * 4;
+            let r = y_tmp + param.cr_factor * v_tmp;
+            let g = y_tmp - param.g_cb_factor * u_tmp - param.g_cr_factor * v_tmp;
+            let b = y_tmp + param.cb_factor * u_tmp;
+            buffer_rgba10[rgba_index + r_offset] = clamp_10bit((r + (1 << 15)) >> 16);
+            buffer_rgba10[rgba_index + 1] = clamp_10bit((g + (1 << 15)) >> 16);
+            buffer_rgba10[rgba_index + b_offset] = clamp_10bit((b + (1 << 15)) >> 16);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pseudo random bytes, reproducible between runs
+    fn gen_bytes(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    /// Convert a `width`x`height` image filled with `border`, except its
+    /// bottom right pixel set to `corner`, with all the 4:2:0 converters
+    fn convert_odd(width: usize, height: usize, border: [u8; 3], corner: [u8; 3]) -> Vec<Vec<u8>> {
+        let mut rgba: Vec<u8> = (0..width * height)
+            .flat_map(|_| [border[0], border[1], border[2], 0xFF])
+            .collect();
+        rgba[(width * height - 1) * 4..][..3].copy_from_slice(&corner);
+        let rgb: Vec<u8> = rgba
+            .chunks_exact(4)
+            .flat_map(|pixel| pixel[..3].to_vec())
+            .collect();
+
+        let (y_stride, uv_stride) = (width, width.div_ceil(2));
+        let uv_height = height.div_ceil(2);
+        let mut results = vec![];
+        for rgb24 in [false, true] {
+            let mut buffer_y = vec![0u8; y_stride * height];
+            let mut buffer_u = vec![0u8; uv_stride * uv_height];
+            let mut buffer_v = vec![0u8; uv_stride * uv_height];
+            if rgb24 {
+                rgb24_yuv420_std(
+                    width,
+                    height,
+                    &rgb,
+                    width * 3,
+                    &mut buffer_y,
+                    &mut buffer_u,
+                    &mut buffer_v,
+                    y_stride,
+                    uv_stride,
+                    uv_stride,
+                    YuvType::ItuT871,
+                    ColorRange::Full,
+                );
+            } else {
+                rgba_to_yuv420_std(
+                    width,
+                    height,
+                    &rgba,
+                    width * 4,
+                    &mut buffer_y,
+                    &mut buffer_u,
+                    &mut buffer_v,
+                    y_stride,
+                    uv_stride,
+                    uv_stride,
+                    YuvType::ItuT871,
+                    ColorRange::Full,
+                    ByteOrder::Rgba,
+                );
+            }
+            results.push([buffer_y, buffer_u, buffer_v].concat());
+        }
+
+        let mut buffer_y = vec![0u8; y_stride * height];
+        let mut buffer_uv = vec![0u8; uv_stride * 2 * uv_height];
+        rgba_to_nv12_std(
+            width,
+            height,
+            &rgba,
+            width * 4,
+            &mut buffer_y,
+            &mut buffer_uv,
+            y_stride,
+            uv_stride * 2,
+            YuvType::ItuT871,
+            ColorRange::Full,
+            ByteOrder::Rgba,
+        );
+        // Deinterleave uv to compare with the planar results
+        let (buffer_u, buffer_v): (Vec<u8>, Vec<u8>) =
+            buffer_uv.chunks_exact(2).map(|uv| (uv[0], uv[1])).unzip();
+        results.push([buffer_y, buffer_u, buffer_v].concat());
+        results
+    }
+
+    #[test]
+    fn test_odd_dimensions() {
+        let gray = [100, 100, 100];
+        let color = [200, 50, 150];
+
+        // Reference values for each color, from an even sized image
+        let gray_yuv = &convert_odd(2, 2, gray, gray)[0];
+        let color_yuv = &convert_odd(2, 2, color, color)[0];
+        let (gray_y, gray_u, gray_v) = (gray_yuv[0], gray_yuv[4], gray_yuv[5]);
+        let (color_y, color_u, color_v) = (color_yuv[0], color_yuv[4], color_yuv[5]);
+
+        let (width, height) = (5, 3);
+        let (y_len, uv_len) = (width * height, 3 * 2);
+        for result in convert_odd(width, height, gray, color) {
+            let (buffer_y, buffer_uv) = result.split_at(y_len);
+            let (buffer_u, buffer_v) = buffer_uv.split_at(uv_len);
+            // Every pixel is converted, including the last column and row
+            assert!(buffer_y[..y_len - 1].iter().all(|&y| y == gray_y));
+            assert_eq!(buffer_y[y_len - 1], color_y);
+            // The bottom right chroma sample only covers the corner pixel
+            assert!(buffer_u[..uv_len - 1].iter().all(|&u| u == gray_u));
+            assert!(buffer_v[..uv_len - 1].iter().all(|&v| v == gray_v));
+            assert_eq!(buffer_u[uv_len - 1], color_u);
+            assert_eq!(buffer_v[uv_len - 1], color_v);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // Grid of colors covering each channel range
+        let levels = [0u8, 1, 17, 64, 100, 128, 160, 200, 240, 254, 255];
+        let mut rgba = vec![];
+        for &r in levels.iter() {
+            for &g in levels.iter() {
+                for &b in levels.iter() {
+                    rgba.extend_from_slice(&[r, g, b, 0xFF]);
+                }
+            }
+        }
+        let (width, height) = (levels.len() * levels.len(), levels.len());
+
+        for (yuv_type, color_range) in [
+            YuvType::ItuT871,
+            YuvType::ItuR601,
+            YuvType::ItuR709,
+            YuvType::ItuR2020,
+        ]
+        .iter()
+        .flat_map(|&yuv_type| {
+            [
+                (yuv_type, ColorRange::Limited),
+                (yuv_type, ColorRange::Full),
+            ]
+        }) {
+            let mut buffer_y = vec![0u8; width * height];
+            let mut buffer_u = vec![0u8; width * height];
+            let mut buffer_v = vec![0u8; width * height];
+            rgba_to_yuv444_std(
+                width,
+                height,
+                &rgba,
+                width * 4,
+                &mut buffer_y,
+                &mut buffer_u,
+                &mut buffer_v,
+                width,
+                width,
+                width,
+                yuv_type,
+                color_range,
+                ByteOrder::Rgba,
+            );
+            let mut result = vec![0u8; width * height * 4];
+            yuv444_to_rgba_std(
+                width,
+                height,
+                &buffer_y,
+                &buffer_u,
+                &buffer_v,
+                width,
+                width,
+                width,
+                &mut result,
+                width * 4,
+                yuv_type,
+                color_range,
+                ByteOrder::Rgba,
+            );
+            for (src, dst) in rgba.chunks_exact(4).zip(result.chunks_exact(4)) {
+                for channel in 0..3 {
+                    let error = (src[channel] as i16 - dst[channel] as i16).abs();
+                    // Fixed point factors lose a few levels on saturated colors
+                    assert!(
+                        error <= 6,
+                        "{:?} {:?}: {:?} converted back to {:?}",
+                        yuv_type,
+                        color_range,
+                        &src[..3],
+                        &dst[..3]
+                    );
+                }
+            }
+        }
+    }
+
+    /// Swap the samples of each chroma pair
+    fn swap_chroma(buffer: &[u8]) -> Vec<u8> {
+        buffer
+            .chunks_exact(2)
+            .flat_map(|pair| [pair[1], pair[0]])
+            .collect()
+    }
+
+    #[test]
+    fn test_nv21() {
+        // Width is not a multiple of the vector size to test the scalar tail
+        let (width, height) = (70, 4);
+        let rgba = gen_bytes(width * 4 * height, 6);
+        let (y_stride, uv_stride) = (width, width);
+
+        type Kernel = crate::convert::RgbaToNv12Fn;
+        let kernels: Vec<(Kernel, Kernel)> = vec![
+            (rgba_to_nv12_std, rgba_to_nv21_std),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            (rgba_to_nv12_ssse3, rgba_to_nv21_ssse3),
+        ];
+
+        for (nv12_kernel, nv21_kernel) in kernels {
+            let mut nv12 = (
+                vec![0u8; y_stride * height],
+                vec![0u8; uv_stride * height / 2],
+            );
+            let mut nv21 = nv12.clone();
+            nv12_kernel(
+                width,
+                height,
+                &rgba,
+                width * 4,
+                &mut nv12.0,
+                &mut nv12.1,
+                y_stride,
+                uv_stride,
+                YuvType::ItuT871,
+                ColorRange::Full,
+                ByteOrder::Rgba,
+            );
+            nv21_kernel(
+                width,
+                height,
+                &rgba,
+                width * 4,
+                &mut nv21.0,
+                &mut nv21.1,
+                y_stride,
+                uv_stride,
+                YuvType::ItuT871,
+                ColorRange::Full,
+                ByteOrder::Rgba,
+            );
+            assert_eq!(nv12.0, nv21.0);
+            assert_eq!(swap_chroma(&nv12.1), nv21.1);
+
+            let mut nv12_rgba = vec![0u8; width * 4 * height];
+            let mut nv21_rgba = vec![0u8; width * 4 * height];
+            nv12_rgba_std(
+                width,
+                height,
+                &nv12.0,
+                &nv12.1,
+                y_stride,
+                uv_stride,
+                &mut nv12_rgba,
+                width * 4,
+                YuvType::ItuT871,
+                ColorRange::Full,
+                ByteOrder::Rgba,
+            );
+            nv21_rgba_std(
+                width,
+                height,
+                &nv21.0,
+                &nv21.1,
+                y_stride,
+                uv_stride,
+                &mut nv21_rgba,
+                width * 4,
+                YuvType::ItuT871,
+                ColorRange::Full,
+                ByteOrder::Rgba,
+            );
+            assert_eq!(nv12_rgba, nv21_rgba);
+        }
+    }
+
+    #[test]
+    fn test_yuyv() {
+        // Odd width and padded rows: yuyv rows are width * 2 bytes long, but
+        // the strides must be honored
+        let (width, height): (usize, usize) = (71, 3);
+        let (yuyv_stride, rgba_stride) = (width.div_ceil(2) * 4 + 12, width * 4 + 20);
+
+        // Pixels of each pair share the same color so that the chroma
+        // subsampling is lossless
+        let colors = gen_bytes(width.div_ceil(2) * height * 3, 7);
+        let mut rgba = vec![0u8; rgba_stride * height];
+        for y in 0..height {
+            for x in 0..width {
+                let color = &colors[(y * width.div_ceil(2) + x / 2) * 3..][..3];
+                rgba[y * rgba_stride + x * 4..][..3].copy_from_slice(color);
+            }
+        }
+
+        for (yuv_type, color_range) in [
+            (YuvType::ItuT871, ColorRange::Full),
+            (YuvType::ItuR709, ColorRange::Limited),
+        ] {
+            let mut yuyv = vec![0u8; yuyv_stride * height];
+            rgba_to_yuyv_std(
+                width,
+                height,
+                &rgba,
+                rgba_stride,
+                &mut yuyv,
+                yuyv_stride,
+                yuv_type,
+                color_range,
+                ByteOrder::Rgba,
+            );
+            let mut result = vec![0xFFu8; rgba_stride * height];
+            yuyv_to_rgba_std(
+                width,
+                height,
+                &yuyv,
+                yuyv_stride,
+                &mut result,
+                rgba_stride,
+                yuv_type,
+                color_range,
+                ByteOrder::Rgba,
+            );
+            for y in 0..height {
+                let row = &result[y * rgba_stride..(y + 1) * rgba_stride];
+                // Row padding is left untouched
+                assert!(row[width * 4..].iter().all(|&value| value == 0xFF));
+                let src_row = &rgba[y * rgba_stride..][..width * 4];
+                for (src, dst) in src_row.chunks_exact(4).zip(row.chunks_exact(4)) {
+                    for channel in 0..3 {
+                        let error = (src[channel] as i16 - dst[channel] as i16).abs();
+                        assert!(
+                            error <= 6,
+                            "{:?} {:?}: {:?} converted back to {:?}",
+                            yuv_type,
+                            color_range,
+                            &src[..3],
+                            &dst[..3]
+                        );
+                    }
+                }
+            }
+
+            // Repacking to yuv420 keeps the luma samples
+            let (y_stride, uv_stride) = (width + 3, width.div_ceil(2) + 5);
+            let mut buffer_y = vec![0u8; y_stride * height];
+            let mut buffer_u = vec![0u8; uv_stride * height.div_ceil(2)];
+            let mut buffer_v = vec![0u8; uv_stride * height.div_ceil(2)];
+            yuyv_to_yuv420_std(
+                width,
+                height,
+                &yuyv,
+                yuyv_stride,
+                &mut buffer_y,
+                &mut buffer_u,
+                &mut buffer_v,
+                y_stride,
+                uv_stride,
+                uv_stride,
+            );
+            for y in 0..height {
+                for x in 0..width {
+                    assert_eq!(buffer_y[y * y_stride + x], yuyv[y * yuyv_stride + x * 2]);
+                }
+            }
+        }
+
+        // The vector version must match the scalar one. Use full range,
+        // where luma has no offset to saturate
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            let yuyv = gen_bytes(yuyv_stride * height, 8);
+            let mut result_std = vec![0xFFu8; rgba_stride * height];
+            let mut result_ssse3 = vec![0xFFu8; rgba_stride * height];
+            for (kernel, result) in [
+                (
+                    yuyv_to_rgba_std as crate::convert::YuyvToRgbaFn,
+                    &mut result_std,
+                ),
+                (yuyv_to_rgba_ssse3, &mut result_ssse3),
+            ] {
+                kernel(
+                    width,
+                    height,
+                    &yuyv,
+                    yuyv_stride,
+                    result,
+                    rgba_stride,
+                    YuvType::ItuT871,
+                    ColorRange::Full,
+                    ByteOrder::Rgba,
+                );
+            }
+            assert_eq!(result_std, result_ssse3);
+        }
+    }
+
+    #[test]
+    fn test_p010() {
+        // Grid of 10 bits colors, each one filling a 2x2 block so that the
+        // chroma subsampling is lossless
+        let levels = [0u16, 1, 64, 255, 512, 800, 1000, 1022, 1023];
+        let mut colors = vec![];
+        for &r in levels.iter() {
+            for &g in levels.iter() {
+                for &b in levels.iter() {
+                    colors.push([r, g, b]);
+                }
+            }
+        }
+        let blocks = 27;
+        let (width, height) = (blocks * 2, blocks * 2);
+        let (rgba10_stride, y_stride, uv_stride) = (width * 4 + 8, width + 2, width + 4);
+        let mut rgba10 = vec![0u16; rgba10_stride * height];
+        for y in 0..height {
+            for x in 0..width {
+                let color = colors[(y / 2) * blocks + x / 2];
+                rgba10[y * rgba10_stride + x * 4..][..3].copy_from_slice(&color);
+            }
+        }
+
+        for yuv_type in [
+            YuvType::ItuT871,
+            YuvType::ItuR601,
+            YuvType::ItuR709,
+            YuvType::ItuR2020,
+        ] {
+            for color_range in [ColorRange::Limited, ColorRange::Full] {
+                let mut buffer_y = vec![0u16; y_stride * height];
+                let mut buffer_uv = vec![0u16; uv_stride * height / 2];
+                rgba10_to_p010_std(
+                    width,
+                    height,
+                    &rgba10,
+                    rgba10_stride,
+                    &mut buffer_y,
+                    &mut buffer_uv,
+                    y_stride,
+                    uv_stride,
+                    yuv_type,
+                    color_range,
+                    ByteOrder::Rgba,
+                );
+                // Samples are stored in the high bits
+                assert!(buffer_y.iter().all(|sample| sample & 0x3F == 0));
+                assert!(buffer_uv.iter().all(|sample| sample & 0x3F == 0));
+
+                // Black and white
+                let (black, white) = match color_range {
+                    ColorRange::Limited => (64, 940),
+                    ColorRange::Full => (0, 1023),
+                };
+                assert_eq!(buffer_y[0] >> 6, black);
+                assert_eq!(buffer_y[(height - 1) * y_stride + width - 1] >> 6, white);
+                assert_eq!(buffer_uv[0] >> 6, 512);
+                assert_eq!(buffer_uv[1] >> 6, 512);
+
+                let mut result = vec![0u16; rgba10_stride * height];
+                p010_to_rgba10_std(
+                    width,
+                    height,
+                    &buffer_y,
+                    &buffer_uv,
+                    y_stride,
+                    uv_stride,
+                    &mut result,
+                    rgba10_stride,
+                    yuv_type,
+                    color_range,
+                    ByteOrder::Rgba,
+                );
+                for y in 0..height {
+                    for x in 0..width {
+                        let src = &rgba10[y * rgba10_stride + x * 4..][..3];
+                        let dst = &result[y * rgba10_stride + x * 4..][..3];
+                        for channel in 0..3 {
+                            let error = (src[channel] as i32 - dst[channel] as i32).abs();
+                            assert!(
+                                error <= 2,
+                                "{:?} {:?}: {:?} converted back to {:?}",
+                                yuv_type,
+                                color_range,
+                                src,
+                                dst
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut rgba10 = vec![0u16; 4];
+        rgba_to_rgba10_std(1, 1, &[0, 0x80, 0xFF, 0xFF], 4, &mut rgba10, 4);
+        assert_eq!(rgba10, [0, 0x202, 0x3FF, 0x3FF]);
+    }
+
+    /// Convert a random image to yuv420 with `kernel`, using padded strides
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn convert_yuv420(kernel: crate::convert::RgbaToYuvFn, width: usize, height: usize) -> Vec<u8> {
+        let (rgba_stride, y_stride, uv_stride) = (width * 4 + 12, width + 5, width.div_ceil(2) + 3);
+        let uv_height = height.div_ceil(2);
+        let rgba = gen_bytes(rgba_stride * height, 9);
+        let mut buffer_y = vec![0u8; y_stride * height];
+        let mut buffer_u = vec![0u8; uv_stride * uv_height];
+        let mut buffer_v = vec![0u8; uv_stride * uv_height];
+        kernel(
+            width,
+            height,
+            &rgba,
+            rgba_stride,
+            &mut buffer_y,
+            &mut buffer_u,
+            &mut buffer_v,
+            y_stride,
+            uv_stride,
+            uv_stride,
+            YuvType::ItuR709,
+            ColorRange::Limited,
+            ByteOrder::Rgba,
+        );
+        [buffer_y, buffer_u, buffer_v].concat()
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn test_ssse3_rayon() {
+        // Odd sizes exercise the scalar tails
+        for (width, height) in [(32, 2), (103, 37), (640, 480)] {
+            let reference = convert_yuv420(rgba_to_yuv420_std, width, height);
+            assert_eq!(
+                convert_yuv420(rgba_to_yuv420_ssse3, width, height),
+                reference
+            );
+            assert_eq!(
+                convert_yuv420(rgba_to_yuv420_ssse3_rayon, width, height),
+                reference
+            );
+        }
+    }
+
+    /// Widths around the 8, 16 and 32 pixels steps of the vector kernels,
+    /// so that each one also runs its scalar tail
+    const EQUIVALENCE_SIZES: [usize; 11] = [1, 2, 7, 15, 16, 31, 32, 33, 63, 100, 130];
+
+    /// Vector kernels round their intermediate values differently
+    const EQUIVALENCE_TOLERANCE: u8 = 1;
+
+    /// Nominal range of luma and chroma samples: out of range values overflow
+    /// the fixed point computations
+    const LUMA_RANGE: (u8, u8) = (16, 235);
+    const CHROMA_RANGE: (u8, u8) = (16, 240);
+
+    /// Value of the row padding of the output planes, which kernels must not
+    /// overwrite
+    const PADDING: u8 = 0xA5;
+
+    /// Plane of `rows` rows of `row_len` samples, followed by some padding
+    #[derive(Clone)]
+    struct Plane {
+        data: Vec<u8>,
+        row_len: usize,
+        stride: usize,
+    }
+
+    impl Plane {
+        /// Random samples in `[min, max]`
+        fn random(rows: usize, row_len: usize, seed: u32, (min, max): (u8, u8)) -> Self {
+            let stride = row_len + 13;
+            let data = gen_bytes(rows * stride, seed)
+                .into_iter()
+                .map(|sample| min + (sample as u16 * (max - min) as u16 / 255) as u8)
+                .collect();
+            Plane {
+                data,
+                row_len,
+                stride,
+            }
+        }
+
+        /// Output plane, with its samples set to `value`
+        fn blank(rows: usize, row_len: usize, value: u8) -> Self {
+            let stride = row_len + 7;
+            let mut data = vec![PADDING; rows * stride];
+            for row in data.chunks_mut(stride) {
+                row[..row_len].fill(value);
+            }
+            Plane {
+                data,
+                row_len,
+                stride,
+            }
+        }
+
+        /// Check that the samples of `self` are within tolerance of
+        /// `reference`, and that its padding has been left untouched
+        fn assert_close(&self, reference: &Plane, context: &str) {
+            let rows = self.data.chunks(self.stride);
+            for (y, (row, expected_row)) in rows.zip(reference.data.chunks(self.stride)).enumerate()
+            {
+                let (samples, padding) = row.split_at(self.row_len);
+                for (x, (&sample, &expected)) in samples.iter().zip(expected_row).enumerate() {
+                    assert!(
+                        sample.abs_diff(expected) <= EQUIVALENCE_TOLERANCE,
+                        "{}: {} instead of {} at {}x{}",
+                        context,
+                        sample,
+                        expected,
+                        x,
+                        y
+                    );
+                }
+                assert!(
+                    padding.iter().all(|&sample| sample == PADDING),
+                    "{}: padding of row {} overwritten",
+                    context,
+                    y
+                );
+            }
+        }
+    }
+
+    /// Run `check` on every image size, color matrix, range and byte order
+    fn for_each_setting(mut check: impl FnMut(usize, usize, YuvType, ColorRange, ByteOrder, &str)) {
+        for &width in EQUIVALENCE_SIZES.iter() {
+            for height in [1, 2, 5] {
+                for yuv_type in [
+                    YuvType::ItuT871,
+                    YuvType::ItuR601,
+                    YuvType::ItuR709,
+                    YuvType::ItuR2020,
+                ] {
+                    for color_range in [ColorRange::Limited, ColorRange::Full] {
+                        for byte_order in [ByteOrder::Rgba, ByteOrder::Bgra] {
+                            let context = format!(
+                                "{}x{} {:?} {:?} {:?}",
+                                width, height, yuv_type, color_range, byte_order
+                            );
+                            check(width, height, yuv_type, color_range, byte_order, &context);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_equivalence_to_planar() {
+        type Kernel = crate::convert::RgbaToYuvFn;
+        // Each kernel is compared to the first one of its subsampling
+        let families: Vec<(usize, Vec<(&str, Kernel)>)> = vec![
+            (
+                2,
+                vec![
+                    ("rgba_to_yuv420_std", rgba_to_yuv420_std),
+                    ("rgba_to_yuv420_std_rayon", rgba_to_yuv420_std_rayon),
+                    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                    ("rgba_to_yuv420_ssse3", rgba_to_yuv420_ssse3),
+                    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                    ("rgba_to_yuv420_ssse3_rayon", rgba_to_yuv420_ssse3_rayon),
+                    #[cfg(target_arch = "aarch64")]
+                    ("rgba_to_yuv420_neon", rgba_to_yuv420_neon),
+                ],
+            ),
+            (
+                1,
+                vec![
+                    ("rgba_to_yuv444_std", rgba_to_yuv444_std),
+                    ("rgba_to_yuv444_std_rayon", rgba_to_yuv444_std_rayon),
+                    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                    ("rgba_to_yuv444_ssse3", rgba_to_yuv444_ssse3),
+                ],
+            ),
+        ];
+
+        for_each_setting(
+            |width, height, yuv_type, color_range, byte_order, context| {
+                let rgba = Plane::random(
+                    height,
+                    width * 4,
+                    width as u32 * 7 + height as u32,
+                    (0, 255),
+                );
+                for (subsampling, kernels) in families.iter() {
+                    let uv_width = width.div_ceil(*subsampling);
+                    let uv_height = height.div_ceil(*subsampling);
+                    let outputs: Vec<[Plane; 3]> = kernels
+                        .iter()
+                        .map(|(_, kernel)| {
+                            let mut y = Plane::blank(height, width, 0);
+                            let mut u = Plane::blank(uv_height, uv_width, 0);
+                            let mut v = Plane::blank(uv_height, uv_width, 0);
+                            kernel(
+                                width,
+                                height,
+                                &rgba.data,
+                                rgba.stride,
+                                &mut y.data,
+                                &mut u.data,
+                                &mut v.data,
+                                y.stride,
+                                u.stride,
+                                v.stride,
+                                yuv_type,
+                                color_range,
+                                byte_order,
+                            );
+                            [y, u, v]
+                        })
+                        .collect();
+                    for ((name, _), planes) in kernels.iter().zip(outputs.iter()) {
+                        for (plane, reference) in planes.iter().zip(outputs[0].iter()) {
+                            plane.assert_close(reference, &format!("{} {}", name, context));
+                        }
+                    }
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn test_equivalence_from_planar() {
+        type Kernel = crate::convert::YuvToRgbaFn;
+        let families: Vec<(usize, Vec<(&str, Kernel)>)> = vec![
+            (
+                2,
+                vec![
+                    ("yuv420_to_rgba_std", yuv420_to_rgba_std),
+                    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                    ("yuv420_to_rgba_ssse3", yuv420_to_rgba_ssse3),
+                    #[cfg(target_arch = "aarch64")]
+                    ("yuv420_to_rgba_neon", yuv420_to_rgba_neon),
+                ],
+            ),
+            (
+                1,
+                vec![
+                    ("yuv444_to_rgba_std", yuv444_to_rgba_std),
+                    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                    ("yuv444_to_rgba_ssse3", yuv444_to_rgba_ssse3),
+                ],
+            ),
+        ];
+
+        for_each_setting(
+            |width, height, yuv_type, color_range, byte_order, context| {
+                for (subsampling, kernels) in families.iter() {
+                    let uv_width = width.div_ceil(*subsampling);
+                    let uv_height = height.div_ceil(*subsampling);
+                    let y = Plane::random(height, width, width as u32 + 1, LUMA_RANGE);
+                    let u = Plane::random(uv_height, uv_width, width as u32 + 2, CHROMA_RANGE);
+                    let v = Plane::random(uv_height, uv_width, width as u32 + 3, CHROMA_RANGE);
+                    let outputs: Vec<Plane> = kernels
+                        .iter()
+                        .map(|(_, kernel)| {
+                            let mut rgba = Plane::blank(height, width * 4, 0xFF);
+                            kernel(
+                                width,
+                                height,
+                                &y.data,
+                                &u.data,
+                                &v.data,
+                                y.stride,
+                                u.stride,
+                                v.stride,
+                                &mut rgba.data,
+                                rgba.stride,
+                                yuv_type,
+                                color_range,
+                                byte_order,
+                            );
+                            rgba
+                        })
+                        .collect();
+                    for ((name, _), rgba) in kernels.iter().zip(outputs.iter()) {
+                        rgba.assert_close(&outputs[0], &format!("{} {}", name, context));
+                    }
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn test_equivalence_semi_planar() {
+        type ToNv12 = crate::convert::RgbaToNv12Fn;
+        type FromNv12 = crate::convert::Nv12ToRgbaFn;
+        let to_nv12: Vec<Vec<(&str, ToNv12)>> = vec![
+            vec![
+                ("rgba_to_nv12_std", rgba_to_nv12_std),
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                ("rgba_to_nv12_ssse3", rgba_to_nv12_ssse3),
+            ],
+            vec![
+                ("rgba_to_nv21_std", rgba_to_nv21_std),
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                ("rgba_to_nv21_ssse3", rgba_to_nv21_ssse3),
+            ],
+        ];
+        let from_nv12: Vec<(&str, FromNv12)> = vec![
+            ("nv12_rgba_std", nv12_rgba_std),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            ("nv12_rgba_ssse3", nv12_rgba_ssse3),
+        ];
+
+        for_each_setting(
+            |width, height, yuv_type, color_range, byte_order, context| {
+                let uv_len = width.div_ceil(2) * 2;
+                let uv_height = height.div_ceil(2);
+
+                let rgba = Plane::random(
+                    height,
+                    width * 4,
+                    width as u32 * 3 + height as u32,
+                    (0, 255),
+                );
+                for kernels in to_nv12.iter() {
+                    let outputs: Vec<[Plane; 2]> = kernels
+                        .iter()
+                        .map(|(_, kernel)| {
+                            let mut y = Plane::blank(height, width, 0);
+                            let mut uv = Plane::blank(uv_height, uv_len, 0);
+                            kernel(
+                                width,
+                                height,
+                                &rgba.data,
+                                rgba.stride,
+                                &mut y.data,
+                                &mut uv.data,
+                                y.stride,
+                                uv.stride,
+                                yuv_type,
+                                color_range,
+                                byte_order,
+                            );
+                            [y, uv]
+                        })
+                        .collect();
+                    for ((name, _), planes) in kernels.iter().zip(outputs.iter()) {
+                        for (plane, reference) in planes.iter().zip(outputs[0].iter()) {
+                            plane.assert_close(reference, &format!("{} {}", name, context));
+                        }
+                    }
+                }
+
+                let y = Plane::random(height, width, width as u32 + 4, LUMA_RANGE);
+                let uv = Plane::random(uv_height, uv_len, width as u32 + 5, CHROMA_RANGE);
+                let outputs: Vec<Plane> = from_nv12
+                    .iter()
+                    .map(|(_, kernel)| {
+                        let mut rgba = Plane::blank(height, width * 4, 0xFF);
+                        kernel(
+                            width,
+                            height,
+                            &y.data,
+                            &uv.data,
+                            y.stride,
+                            uv.stride,
+                            &mut rgba.data,
+                            rgba.stride,
+                            yuv_type,
+                            color_range,
+                            byte_order,
+                        );
+                        rgba
+                    })
+                    .collect();
+                for ((name, _), rgba) in from_nv12.iter().zip(outputs.iter()) {
+                    rgba.assert_close(&outputs[0], &format!("{} {}", name, context));
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn test_equivalence_yuyv() {
+        let kernels: Vec<(&str, crate::convert::YuyvToRgbaFn)> = vec![
+            ("yuyv_to_rgba_std", yuyv_to_rgba_std),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            ("yuyv_to_rgba_ssse3", yuyv_to_rgba_ssse3),
+        ];
+
+        for_each_setting(
+            |width, height, yuv_type, color_range, byte_order, context| {
+                let yuyv =
+                    Plane::random(height, width.div_ceil(2) * 4, width as u32 + 6, LUMA_RANGE);
+                let outputs: Vec<Plane> = kernels
+                    .iter()
+                    .map(|(_, kernel)| {
+                        let mut rgba = Plane::blank(height, width * 4, 0xFF);
+                        kernel(
+                            width,
+                            height,
+                            &yuyv.data,
+                            yuyv.stride,
+                            &mut rgba.data,
+                            rgba.stride,
+                            yuv_type,
+                            color_range,
+                            byte_order,
+                        );
+                        rgba
+                    })
+                    .collect();
+                for ((name, _), rgba) in kernels.iter().zip(outputs.iter()) {
+                    rgba.assert_close(&outputs[0], &format!("{} {}", name, context));
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn test_byte_order() {
+        // Wide enough for the vector kernels
+        let (width, height) = (64, 2);
+        type ToYuv = crate::convert::RgbaToYuvFn;
+        type ToRgba = crate::convert::YuvToRgbaFn;
+        let to_yuv: Vec<(ToYuv, usize)> = vec![
+            (rgba_to_yuv420_std, 2),
+            (rgba_to_yuv420_std_rayon, 2),
+            (rgba_to_yuv444_std, 1),
+            (rgba_to_yuv444_std_rayon, 1),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            (rgba_to_yuv420_ssse3, 2),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            (rgba_to_yuv420_ssse3_rayon, 2),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            (rgba_to_yuv444_ssse3, 1),
+            #[cfg(target_arch = "aarch64")]
+            (rgba_to_yuv420_neon, 2),
+        ];
+        let to_rgba: Vec<(ToRgba, usize)> = vec![
+            (yuv420_to_rgba_std, 2),
+            (yuv444_to_rgba_std, 1),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            (yuv420_to_rgba_ssse3, 2),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            (yuv444_to_rgba_ssse3, 1),
+            #[cfg(target_arch = "aarch64")]
+            (yuv420_to_rgba_neon, 2),
+        ];
+
+        for byte_order in [ByteOrder::Rgba, ByteOrder::Bgra] {
+            let (r_offset, b_offset) = byte_order.offsets();
+            let mut red = [0u8, 0, 0, 0xFF];
+            red[r_offset] = 0xFF;
+            let rgba = red.repeat(width * height);
+
+            // Pure red: minimal Cb, maximal Cr
+            for (kernel, subsampling) in to_yuv.iter() {
+                let uv_width = width / subsampling;
+                let mut buffer_y = vec![0u8; width * height];
+                let mut buffer_u = vec![0u8; uv_width * height / subsampling];
+                let mut buffer_v = vec![0u8; uv_width * height / subsampling];
+                kernel(
+                    width,
+                    height,
+                    &rgba,
+                    width * 4,
+                    &mut buffer_y,
+                    &mut buffer_u,
+                    &mut buffer_v,
+                    width,
+                    uv_width,
+                    uv_width,
+                    YuvType::ItuR709,
+                    ColorRange::Limited,
+                    byte_order,
+                );
+                for (&y, (&u, &v)) in buffer_y.iter().zip(buffer_u.iter().zip(buffer_v.iter())) {
+                    assert!(
+                        y.abs_diff(63) <= 2 && u.abs_diff(102) <= 2 && v.abs_diff(240) <= 2,
+                        "{:?}: red converted to {} {} {}",
+                        byte_order,
+                        y,
+                        u,
+                        v
+                    );
+                }
+            }
+
+            for (kernel, subsampling) in to_rgba.iter() {
+                let uv_width = width / subsampling;
+                let mut buffer_rgba = vec![0xFFu8; width * 4 * height];
+                kernel(
+                    width,
+                    height,
+                    &vec![63; width * height],
+                    &vec![102; uv_width * height],
+                    &vec![240; uv_width * height],
+                    width,
+                    uv_width,
+                    uv_width,
+                    &mut buffer_rgba,
+                    width * 4,
+                    YuvType::ItuR709,
+                    ColorRange::Limited,
+                    byte_order,
+                );
+                for pixel in buffer_rgba.chunks_exact(4) {
+                    assert!(
+                        pixel[r_offset] >= 250 && pixel[1] <= 5 && pixel[b_offset] <= 5,
+                        "{:?}: red converted back to {:?}",
+                        byte_order,
+                        pixel
+                    );
+                }
+            }
+        }
+    }
+
+    /// Throughput of the rgba to yuv420 kernels on a 4K frame, run with
+    /// `cargo test --release -- --ignored --nocapture bench_rgba_to_yuv420`
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    #[ignore]
+    fn bench_rgba_to_yuv420() {
+        let (width, height) = (3840, 2160);
+        let kernels: [(&str, crate::convert::RgbaToYuvFn); 4] = [
+            ("std", rgba_to_yuv420_std),
+            ("std_rayon", rgba_to_yuv420_std_rayon),
+            ("ssse3", rgba_to_yuv420_ssse3),
+            ("ssse3_rayon", rgba_to_yuv420_ssse3_rayon),
+        ];
+        let rgba = gen_bytes(width * 4 * height, 10);
+        let mut buffer_y = vec![0u8; width * height];
+        let mut buffer_u = vec![0u8; width * height / 4];
+        let mut buffer_v = vec![0u8; width * height / 4];
+        let rounds = 20;
+        for (name, kernel) in kernels {
+            let start = std::time::Instant::now();
+            for _ in 0..rounds {
+                kernel(
+                    width,
+                    height,
+                    &rgba,
+                    width * 4,
+                    &mut buffer_y,
+                    &mut buffer_u,
+                    &mut buffer_v,
+                    width,
+                    width / 2,
+                    width / 2,
+                    YuvType::ItuT871,
+                    ColorRange::Full,
+                    ByteOrder::Rgba,
+                );
+            }
+            println!("{:<12} {:?} per frame", name, start.elapsed() / rounds);
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_neon_rgba_to_yuv420() {
+        // Width is not a multiple of the vector size to test the scalar tail
+        let (width, height) = (53, 6);
+        let rgba_stride = width * 4 + 12;
+        let rgba = gen_bytes(rgba_stride * height, 1);
+        let (y_stride, uv_stride) = (64, 32);
+
+        for (yuv_type, color_range) in [
+            YuvType::ItuT871,
+            YuvType::ItuR601,
+            YuvType::ItuR709,
+            YuvType::ItuR2020,
+        ]
+        .iter()
+        .flat_map(|&yuv_type| {
+            [
+                (yuv_type, ColorRange::Limited),
+                (yuv_type, ColorRange::Full),
+            ]
+        }) {
+            let mut std_yuv = (
+                vec![0u8; y_stride * height],
+                vec![0u8; uv_stride * height / 2],
+                vec![0u8; uv_stride * height / 2],
+            );
+            let mut neon_yuv = std_yuv.clone();
+            rgba_to_yuv420_std(
+                width,
+                height,
+                &rgba,
+                rgba_stride,
+                &mut std_yuv.0,
+                &mut std_yuv.1,
+                &mut std_yuv.2,
+                y_stride,
+                uv_stride,
+                uv_stride,
+                yuv_type,
+                color_range,
+                ByteOrder::Rgba,
+            );
+            rgba_to_yuv420_neon(
+                width,
+                height,
+                &rgba,
+                rgba_stride,
+                &mut neon_yuv.0,
+                &mut neon_yuv.1,
+                &mut neon_yuv.2,
+                y_stride,
+                uv_stride,
+                uv_stride,
+                yuv_type,
+                color_range,
+                ByteOrder::Rgba,
+            );
+            assert_eq!(std_yuv, neon_yuv);
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_neon_yuv420_to_rgba() {
+        let (width, height) = (53, 6);
+        let (y_stride, uv_stride) = (64, 32);
+        let buffer_y = gen_bytes(y_stride * height, 2);
+        let buffer_u = gen_bytes(uv_stride * height / 2, 3);
+        let buffer_v = gen_bytes(uv_stride * height / 2, 4);
+        let rgba_stride = width * 4 + 12;
+
+        // Alpha channel must be left untouched
+        let mut std_rgba = gen_bytes(rgba_stride * height, 5);
+        let mut neon_rgba = std_rgba.clone();
+        yuv420_to_rgba_std(
+            width,
+            height,
+            &buffer_y,
+            &buffer_u,
+            &buffer_v,
+            y_stride,
+            uv_stride,
+            uv_stride,
+            &mut std_rgba,
+            rgba_stride,
+            YuvType::ItuT871,
+            ColorRange::Full,
+            ByteOrder::Rgba,
+        );
+        yuv420_to_rgba_neon(
+            width,
+            height,
+            &buffer_y,
+            &buffer_u,
+            &buffer_v,
+            y_stride,
+            uv_stride,
+            uv_stride,
+            &mut neon_rgba,
+            rgba_stride,
+            YuvType::ItuT871,
+            ColorRange::Full,
+            ByteOrder::Rgba,
+        );
+        assert_eq!(std_rgba, neon_rgba);
+    }
+}